@@ -0,0 +1 @@
+ALTER TABLE users ADD role varchar(20) NOT NULL DEFAULT 'normal';
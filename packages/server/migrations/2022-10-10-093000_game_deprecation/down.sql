@@ -0,0 +1,2 @@
+ALTER TABLE games DROP COLUMN deprecated_at;
+ALTER TABLE games DROP COLUMN deprecation_reason;
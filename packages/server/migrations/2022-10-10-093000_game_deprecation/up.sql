@@ -0,0 +1,2 @@
+ALTER TABLE games ADD deprecated_at timestamp NULL;
+ALTER TABLE games ADD deprecation_reason text NULL;
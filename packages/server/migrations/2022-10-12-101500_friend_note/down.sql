@@ -0,0 +1,2 @@
+ALTER TABLE friends DROP COLUMN nickname;
+ALTER TABLE friends DROP COLUMN note;
@@ -0,0 +1,2 @@
+ALTER TABLE friends ADD nickname varchar(20) NULL;
+ALTER TABLE friends ADD note varchar(200) NULL;
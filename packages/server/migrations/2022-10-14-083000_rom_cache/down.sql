@@ -0,0 +1,2 @@
+ALTER TABLE games DROP COLUMN rom_hash;
+ALTER TABLE games DROP COLUMN rom_cached_at;
@@ -0,0 +1,2 @@
+ALTER TABLE games ADD rom_hash varchar(64) NULL;
+ALTER TABLE games ADD rom_cached_at timestamp NULL;
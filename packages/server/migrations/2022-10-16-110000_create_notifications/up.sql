@@ -0,0 +1,18 @@
+CREATE TABLE notifications
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ user_id    integer NOT NULL,
+ kind       varchar(20) NOT NULL,
+ payload    json NOT NULL,
+ read_at    timestamp NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_201 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_202 FOREIGN KEY ( user_id ) REFERENCES users ( "id" )
+);
+
+CREATE INDEX FK_203 ON notifications
+(
+ user_id,
+ kind,
+ read_at
+);
@@ -0,0 +1,8 @@
+CREATE TABLE core_versions
+(
+ platform   varchar(20) NOT NULL,
+ minimum    integer NOT NULL,
+ latest     integer NOT NULL,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_211 PRIMARY KEY ( platform )
+);
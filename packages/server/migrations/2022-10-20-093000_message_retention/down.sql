@@ -0,0 +1 @@
+DROP INDEX FK_221;
@@ -0,0 +1,6 @@
+CREATE INDEX FK_221 ON messages
+(
+ LEAST(user_id, target_id),
+ GREATEST(user_id, target_id),
+ "id"
+);
@@ -0,0 +1,41 @@
+CREATE TABLE webhooks
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ url        varchar(500) NOT NULL,
+ secret     varchar(200) NOT NULL,
+ events     varchar(20)[] NOT NULL,
+ fail_count integer NOT NULL DEFAULT 0,
+ failing    boolean NOT NULL DEFAULT false,
+ deleted_at timestamp NULL,
+ created_at timestamp NOT NULL,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_231 PRIMARY KEY ( "id" )
+);
+
+CREATE TABLE webhook_deliveries
+(
+ "id"            integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ webhook_id      integer NOT NULL,
+ event           varchar(20) NOT NULL,
+ payload         json NOT NULL,
+ status          varchar(20) NOT NULL,
+ attempts        integer NOT NULL DEFAULT 0,
+ response_status integer NULL,
+ error           text NULL,
+ next_attempt_at timestamp NOT NULL,
+ created_at      timestamp NOT NULL,
+ updated_at      timestamp NOT NULL,
+ CONSTRAINT PK_232 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_233 FOREIGN KEY ( webhook_id ) REFERENCES webhooks ( "id" )
+);
+
+CREATE INDEX FK_234 ON webhook_deliveries
+(
+ webhook_id
+);
+
+CREATE INDEX IX_235 ON webhook_deliveries
+(
+ status,
+ next_attempt_at
+);
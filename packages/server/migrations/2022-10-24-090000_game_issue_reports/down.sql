@@ -0,0 +1,4 @@
+DROP TABLE reports;
+
+ALTER TABLE games DROP COLUMN issue_url;
+ALTER TABLE games DROP COLUMN issue_number;
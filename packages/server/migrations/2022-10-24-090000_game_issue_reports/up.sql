@@ -0,0 +1,20 @@
+ALTER TABLE games ADD COLUMN issue_number integer NULL;
+ALTER TABLE games ADD COLUMN issue_url varchar(500) NULL;
+
+CREATE TABLE reports
+(
+ "id"        integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ user_id     integer NOT NULL,
+ game_id     integer NOT NULL,
+ description text NOT NULL,
+ created_at  timestamp NOT NULL,
+ CONSTRAINT PK_241 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_242 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ),
+ CONSTRAINT FK_243 FOREIGN KEY ( game_id ) REFERENCES games ( "id" )
+);
+
+CREATE INDEX FK_244 ON reports
+(
+ game_id,
+ created_at
+);
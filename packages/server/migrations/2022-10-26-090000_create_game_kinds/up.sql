@@ -0,0 +1,15 @@
+CREATE TABLE game_kinds
+(
+ game_id integer NOT NULL,
+ kind    varchar(20) NOT NULL,
+ CONSTRAINT PK_251 PRIMARY KEY ( game_id, kind ),
+ CONSTRAINT FK_252 FOREIGN KEY ( game_id ) REFERENCES games ( "id" )
+);
+
+CREATE INDEX IX_253 ON game_kinds
+(
+ kind
+);
+
+INSERT INTO game_kinds ( game_id, kind )
+SELECT "id", kind FROM games WHERE kind IS NOT NULL;
@@ -0,0 +1,8 @@
+CREATE TABLE retention_policies
+(
+ category   varchar(30) NOT NULL,
+ days       integer NOT NULL,
+ anonymize  boolean NOT NULL DEFAULT false,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_261 PRIMARY KEY ( category )
+);
@@ -0,0 +1 @@
+ALTER TABLE rooms ADD relayed boolean NOT NULL DEFAULT false;
@@ -0,0 +1,16 @@
+CREATE TABLE game_attachments
+(
+ "id"         integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ game_id      integer NOT NULL,
+ label        varchar(200) NOT NULL,
+ url          text NOT NULL,
+ size         bigint NULL,
+ content_type varchar(100) NULL,
+ available    boolean NOT NULL DEFAULT true,
+ cached_at    timestamp NULL,
+ created_at   timestamp NOT NULL,
+ updated_at   timestamp NOT NULL,
+ CONSTRAINT PK_271 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_272 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ),
+ CONSTRAINT Index_273 UNIQUE ( game_id, url )
+);
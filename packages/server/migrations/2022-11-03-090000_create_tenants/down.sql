@@ -0,0 +1,3 @@
+ALTER TABLE rooms DROP COLUMN tenant_id;
+ALTER TABLE users DROP COLUMN tenant_id;
+DROP TABLE tenants;
@@ -0,0 +1,20 @@
+CREATE TABLE tenants
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ slug       varchar(40) NOT NULL,
+ name       varchar(100) NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_281 PRIMARY KEY ( "id" ),
+ CONSTRAINT Index_282 UNIQUE ( slug )
+);
+
+ALTER TABLE users ADD tenant_id integer NULL;
+ALTER TABLE users ADD CONSTRAINT FK_283 FOREIGN KEY ( tenant_id ) REFERENCES tenants ( "id" );
+
+ALTER TABLE rooms ADD tenant_id integer NULL;
+ALTER TABLE rooms ADD CONSTRAINT FK_284 FOREIGN KEY ( tenant_id ) REFERENCES tenants ( "id" );
+
+CREATE INDEX FK_285 ON rooms
+(
+ tenant_id
+);
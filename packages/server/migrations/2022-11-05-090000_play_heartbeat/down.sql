@@ -0,0 +1,2 @@
+DROP TABLE play_sessions;
+ALTER TABLE records DROP COLUMN last_heartbeat_at;
@@ -0,0 +1,20 @@
+ALTER TABLE records ADD last_heartbeat_at timestamp NULL;
+
+CREATE TABLE play_sessions
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ user_id    integer NOT NULL,
+ game_id    integer NOT NULL,
+ started_at timestamp NOT NULL,
+ ended_at   timestamp NOT NULL,
+ duration   bigint NOT NULL,
+ CONSTRAINT PK_291 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_292 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ),
+ CONSTRAINT FK_293 FOREIGN KEY ( game_id ) REFERENCES games ( "id" )
+);
+
+CREATE INDEX FK_294 ON play_sessions
+(
+ user_id,
+ game_id
+);
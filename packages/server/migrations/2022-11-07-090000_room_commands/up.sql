@@ -0,0 +1 @@
+ALTER TABLE rooms ADD allow_commands boolean NOT NULL DEFAULT false;
@@ -0,0 +1,18 @@
+CREATE TABLE game_changes
+(
+ version    integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ game_id    integer NOT NULL,
+ kind       varchar(20) NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_301 PRIMARY KEY ( version ),
+ CONSTRAINT FK_302 FOREIGN KEY ( game_id ) REFERENCES games ( "id" )
+);
+
+CREATE INDEX Index_303 ON game_changes
+(
+ created_at
+);
+
+-- The existing catalog becomes the first versions
+INSERT INTO game_changes (game_id, kind, created_at)
+SELECT "id", 'created', now() FROM games WHERE deleted_at IS NULL ORDER BY "id";
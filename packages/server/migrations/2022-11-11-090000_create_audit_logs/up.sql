@@ -0,0 +1,30 @@
+CREATE TABLE audit_logs
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ actor_id   integer NOT NULL,
+ user_id    integer NOT NULL,
+ action     varchar(50) NOT NULL,
+ detail     text NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_311 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_312 FOREIGN KEY ( actor_id ) REFERENCES users ( "id" ),
+ CONSTRAINT FK_313 FOREIGN KEY ( user_id ) REFERENCES users ( "id" )
+);
+
+CREATE INDEX FK_314 ON audit_logs
+(
+ user_id
+);
+
+-- Append only, rows can't be changed or removed
+CREATE FUNCTION audit_logs_immutable() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_logs is append only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_logs_immutable BEFORE UPDATE OR DELETE ON audit_logs
+FOR EACH ROW EXECUTE FUNCTION audit_logs_immutable();
+
+CREATE TRIGGER audit_logs_immutable_truncate BEFORE TRUNCATE ON audit_logs
+FOR EACH STATEMENT EXECUTE FUNCTION audit_logs_immutable();
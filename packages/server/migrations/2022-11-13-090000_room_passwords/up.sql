@@ -0,0 +1 @@
+ALTER TABLE rooms ADD password varchar;
@@ -0,0 +1 @@
+ALTER TABLE rooms ADD spectator_policy varchar NOT NULL DEFAULT 'open';
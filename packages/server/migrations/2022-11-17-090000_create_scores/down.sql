@@ -0,0 +1,2 @@
+DROP TABLE scores;
+ALTER TABLE games DROP COLUMN score_ceiling;
@@ -0,0 +1,23 @@
+-- Scores above are held for review, no ceiling when NULL
+ALTER TABLE games ADD score_ceiling bigint NULL;
+
+CREATE TABLE scores
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ game_id    integer NOT NULL,
+ user_id    integer NOT NULL,
+ score      bigint NOT NULL,
+ proof_url  varchar NULL,
+ flagged    boolean NOT NULL DEFAULT false,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_321 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_322 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ),
+ CONSTRAINT FK_323 FOREIGN KEY ( user_id ) REFERENCES users ( "id" )
+);
+
+CREATE INDEX Index_324 ON scores
+(
+ game_id,
+ flagged,
+ score DESC
+);
@@ -0,0 +1,12 @@
+-- Issue events that kept failing, kept for replay
+CREATE TABLE github_dead_letters
+(
+ "id"         integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ issue_number integer NOT NULL,
+ action       varchar(50) NOT NULL,
+ payload      json NOT NULL,
+ error        text NOT NULL,
+ attempts     integer NOT NULL,
+ created_at   timestamp NOT NULL,
+ CONSTRAINT PK_331 PRIMARY KEY ( "id" )
+);
@@ -0,0 +1,10 @@
+-- Requests per user and hour, written in batches
+CREATE TABLE usage_counters
+(
+ user_id   integer NOT NULL,
+ hour      timestamp NOT NULL,
+ requests  integer NOT NULL DEFAULT 0,
+ mutations integer NOT NULL DEFAULT 0,
+ CONSTRAINT PK_341 PRIMARY KEY ( user_id, hour ),
+ CONSTRAINT FK_342 FOREIGN KEY ( user_id ) REFERENCES users ( "id" )
+);
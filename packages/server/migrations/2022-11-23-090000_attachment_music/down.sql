@@ -0,0 +1,3 @@
+ALTER TABLE game_attachments DROP COLUMN music_title;
+ALTER TABLE game_attachments DROP COLUMN track_count;
+ALTER TABLE game_attachments DROP COLUMN kind;
@@ -0,0 +1,4 @@
+-- `music` attachments are NSF rips, track metadata is read from the header when cached
+ALTER TABLE game_attachments ADD kind varchar(20) NOT NULL DEFAULT 'document';
+ALTER TABLE game_attachments ADD track_count integer NULL;
+ALTER TABLE game_attachments ADD music_title varchar(32) NULL;
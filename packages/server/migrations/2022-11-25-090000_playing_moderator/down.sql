@@ -0,0 +1 @@
+ALTER TABLE playing DROP COLUMN moderator;
@@ -0,0 +1,2 @@
+-- Room members the host lets kick and mute, gone when they leave the seat
+ALTER TABLE playing ADD moderator boolean NOT NULL DEFAULT false;
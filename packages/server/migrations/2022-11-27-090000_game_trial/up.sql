@@ -0,0 +1,2 @@
+-- Anonymous visitors may play these for a few minutes before registering
+ALTER TABLE games ADD trial_allowed boolean NOT NULL DEFAULT false;
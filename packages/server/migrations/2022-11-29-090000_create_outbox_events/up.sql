@@ -0,0 +1,17 @@
+-- Notify events written with the change that caused them, sent after commit
+CREATE TABLE outbox_events
+(
+ "id"         integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ kind         varchar(30) NOT NULL,
+ audience     varchar(40) NOT NULL,
+ payload      json NOT NULL,
+ created_at   timestamp NOT NULL,
+ delivered_at timestamp NULL,
+ CONSTRAINT PK_351 PRIMARY KEY ( "id" )
+);
+
+CREATE INDEX Index_352 ON outbox_events
+(
+ "id"
+)
+WHERE delivered_at IS NULL;
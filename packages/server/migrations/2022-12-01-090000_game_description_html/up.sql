@@ -0,0 +1,2 @@
+-- Sanitized render of the markdown description, filled by rerenderDescriptions
+ALTER TABLE games ADD description_html text NOT NULL DEFAULT '';
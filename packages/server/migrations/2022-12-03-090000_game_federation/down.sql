@@ -0,0 +1,3 @@
+DROP TABLE federation_syncs;
+ALTER TABLE games DROP COLUMN upstream_id;
+ALTER TABLE games DROP COLUMN source;
@@ -0,0 +1,18 @@
+-- Games pulled from CATALOG_UPSTREAM_URL are read-only locally
+ALTER TABLE games ADD source varchar(20) NOT NULL DEFAULT 'local';
+ALTER TABLE games ADD upstream_id integer NULL;
+
+CREATE UNIQUE INDEX Index_353 ON games
+(
+ upstream_id
+)
+WHERE upstream_id IS NOT NULL;
+
+-- Catalog version of the upstream applied so far
+CREATE TABLE federation_syncs
+(
+ upstream_url varchar(255) NOT NULL,
+ version      integer NOT NULL,
+ synced_at    timestamp NOT NULL,
+ CONSTRAINT PK_354 PRIMARY KEY ( upstream_url )
+);
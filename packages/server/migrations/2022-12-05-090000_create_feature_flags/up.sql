@@ -0,0 +1,12 @@
+-- Canary rollout of schema features, evaluated per request
+CREATE TABLE feature_flags
+(
+ name        varchar(50) NOT NULL,
+ -- 0 to 100, share of users bucketed by a hash of their id
+ rollout     integer NOT NULL DEFAULT 0,
+ allow_users integer[] NOT NULL DEFAULT '{}',
+ deny_users  integer[] NOT NULL DEFAULT '{}',
+ created_at  timestamp NOT NULL,
+ updated_at  timestamp NOT NULL,
+ CONSTRAINT PK_355 PRIMARY KEY ( name )
+);
@@ -0,0 +1,2 @@
+DROP INDEX Index_357;
+DROP INDEX Index_356;
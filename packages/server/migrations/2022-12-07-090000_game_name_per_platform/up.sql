@@ -0,0 +1,14 @@
+-- Webhook events find their game by issue when names are shared
+CREATE INDEX Index_356 ON games
+(
+ issue_number
+);
+
+-- Names are unique per platform from now on, older duplicates keep
+-- being told apart by their issue number
+CREATE UNIQUE INDEX Index_357 ON games
+(
+ name,
+ COALESCE(platform, '')
+)
+WHERE deleted_at IS NULL AND created_at >= '2022-12-07';
@@ -0,0 +1,27 @@
+-- Join links shared outside the app, the token is the code plus its signature
+CREATE TABLE room_links
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ code       varchar(32) NOT NULL,
+ room_id    integer NOT NULL,
+ created_by integer NOT NULL,
+ -- unlimited when null
+ max_uses   integer NULL,
+ uses       integer NOT NULL DEFAULT 0,
+ expires_at timestamp NOT NULL,
+ revoked_at timestamp NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_358 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_359 FOREIGN KEY ( room_id ) REFERENCES rooms ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT FK_360 FOREIGN KEY ( created_by ) REFERENCES users ( "id" )
+);
+
+CREATE UNIQUE INDEX Index_361 ON room_links
+(
+ code
+);
+
+CREATE INDEX FK_362 ON room_links
+(
+ room_id
+);
@@ -0,0 +1,3 @@
+DROP INDEX Index_363;
+ALTER TABLE rooms DROP COLUMN mode;
+ALTER TABLE games DROP COLUMN play_modes;
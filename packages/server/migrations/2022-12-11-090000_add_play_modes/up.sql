@@ -0,0 +1,20 @@
+-- From `game.mode.*` labels, empty is co-op
+ALTER TABLE games ADD COLUMN play_modes varchar(20)[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE rooms ADD COLUMN mode varchar(20);
+
+-- Existing rooms take the first mode of their game
+UPDATE rooms SET mode = COALESCE(games.play_modes[1], 'co_op')
+FROM games
+WHERE games.id = rooms.game_id;
+
+UPDATE rooms SET mode = 'co_op' WHERE mode IS NULL;
+
+ALTER TABLE rooms ALTER COLUMN mode SET NOT NULL;
+
+CREATE INDEX Index_363 ON rooms
+(
+ game_id,
+ mode
+)
+WHERE deleted_at IS NULL;
@@ -0,0 +1,6 @@
+DROP INDEX Index_364;
+DELETE FROM reports WHERE kind <> 'game_problem';
+ALTER TABLE reports DROP COLUMN reason;
+ALTER TABLE reports DROP COLUMN target_id;
+ALTER TABLE reports DROP COLUMN kind;
+ALTER TABLE reports ALTER COLUMN game_id SET NOT NULL;
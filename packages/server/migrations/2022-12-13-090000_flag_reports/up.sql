@@ -0,0 +1,11 @@
+-- Text flagged by moderation has no game unless it is a comment
+ALTER TABLE reports ALTER COLUMN game_id DROP NOT NULL;
+ALTER TABLE reports ADD COLUMN kind varchar(20) NOT NULL DEFAULT 'game_problem';
+ALTER TABLE reports ADD COLUMN target_id integer NULL;
+ALTER TABLE reports ADD COLUMN reason varchar(50) NULL;
+
+CREATE INDEX Index_364 ON reports
+(
+ kind,
+ created_at
+);
@@ -0,0 +1,18 @@
+-- Full snapshot of a game after each change, capped per game
+CREATE TABLE game_versions
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ game_id    integer NOT NULL,
+ kind       varchar(20) NOT NULL,
+ source     varchar(20) NOT NULL,
+ snapshot   json NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_365 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_366 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ) ON DELETE CASCADE
+);
+
+CREATE INDEX FK_367 ON game_versions
+(
+ game_id,
+ created_at
+);
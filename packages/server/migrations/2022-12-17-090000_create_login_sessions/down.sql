@@ -0,0 +1,2 @@
+DROP TABLE login_sessions;
+ALTER TABLE users DROP COLUMN session_policy;
@@ -0,0 +1,22 @@
+-- `single` revokes the other sessions at login
+ALTER TABLE users ADD COLUMN session_policy varchar(10) NOT NULL DEFAULT 'multi';
+
+-- One per issued user token, revoked ids are refused until the token expires
+CREATE TABLE login_sessions
+(
+ "id"           varchar(32) NOT NULL,
+ user_id        integer NOT NULL,
+ created_at     timestamp NOT NULL,
+ expires_at     timestamp NOT NULL,
+ revoked_at     timestamp NULL,
+ revoke_reason  varchar(20) NULL,
+ CONSTRAINT PK_368 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_369 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE
+);
+
+CREATE INDEX Index_370 ON login_sessions
+(
+ user_id,
+ expires_at
+)
+WHERE revoked_at IS NULL;
@@ -0,0 +1,35 @@
+-- Named game lists of a user besides favorites
+CREATE TABLE collections
+(
+ "id"          integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ user_id       integer NOT NULL,
+ name          varchar(50) NOT NULL,
+ public        boolean NOT NULL DEFAULT false,
+ -- entries dropped because their game was deleted
+ removed_count integer NOT NULL DEFAULT 0,
+ created_at    timestamp NOT NULL,
+ updated_at    timestamp NOT NULL,
+ CONSTRAINT PK_371 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_372 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE
+);
+
+CREATE INDEX FK_373 ON collections
+(
+ user_id
+);
+
+CREATE TABLE collection_games
+(
+ collection_id integer NOT NULL,
+ game_id       integer NOT NULL,
+ position      integer NOT NULL,
+ created_at    timestamp NOT NULL,
+ CONSTRAINT PK_374 PRIMARY KEY ( collection_id, game_id ),
+ CONSTRAINT FK_375 FOREIGN KEY ( collection_id ) REFERENCES collections ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT FK_376 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ) ON DELETE CASCADE
+);
+
+CREATE INDEX FK_377 ON collection_games
+(
+ game_id
+);
@@ -0,0 +1,2 @@
+-- Last sequence number of the room's events, continues after a restart
+ALTER TABLE rooms ADD COLUMN event_seq bigint NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE games DROP COLUMN mature;
+ALTER TABLE users DROP COLUMN birthdate;
@@ -0,0 +1,5 @@
+-- Private, only the derived minor flag leaves the server
+ALTER TABLE users ADD COLUMN birthdate date;
+
+-- Hidden from minors
+ALTER TABLE games ADD COLUMN mature boolean NOT NULL DEFAULT false;
@@ -0,0 +1,13 @@
+-- Signing keys of tokens and room links besides the SECRET env value
+CREATE TABLE secrets
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ kid        varchar(16) NOT NULL,
+ -- nonce and AES-256-GCM ciphertext under SECRET_MASTER_KEY, base64
+ sealed     text NOT NULL,
+ -- refused after this, null while it signs
+ expires_at timestamp,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_378 PRIMARY KEY ( "id" ),
+ CONSTRAINT Index_379 UNIQUE ( kid )
+);
@@ -0,0 +1,2 @@
+DROP TABLE github_deliveries;
+DROP TABLE github_repos;
@@ -0,0 +1,23 @@
+-- Repositories whose issues are games, `GITHUB_REPOS` seeds it, renames follow
+CREATE TABLE github_repos
+(
+ -- owner/name
+ full_name  varchar(200) NOT NULL,
+ created_at timestamp NOT NULL,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_380 PRIMARY KEY ( full_name )
+);
+
+-- GitHub events that changed stored issue links, besides issue content
+CREATE TABLE github_deliveries
+(
+ "id"          integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ event         varchar(50) NOT NULL,
+ action        varchar(50) NOT NULL,
+ -- owner/name the event was sent from
+ repository    varchar(200) NOT NULL,
+ affected_rows integer NOT NULL,
+ detail        text NOT NULL,
+ created_at    timestamp NOT NULL,
+ CONSTRAINT PK_381 PRIMARY KEY ( "id" )
+);
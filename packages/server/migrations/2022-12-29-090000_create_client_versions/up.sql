@@ -0,0 +1,9 @@
+-- Oldest client release still served, by the name in `clientInfo`
+CREATE TABLE client_versions
+(
+ name       varchar(50) NOT NULL,
+ -- dotted numbers, e.g. 1.4.0
+ minimum    varchar(20) NOT NULL,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_382 PRIMARY KEY ( name )
+);
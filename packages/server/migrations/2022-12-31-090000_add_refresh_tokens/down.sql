@@ -0,0 +1,3 @@
+ALTER TABLE login_sessions DROP COLUMN refreshed_at;
+ALTER TABLE login_sessions DROP COLUMN previous_refresh_hash;
+ALTER TABLE login_sessions DROP COLUMN refresh_hash;
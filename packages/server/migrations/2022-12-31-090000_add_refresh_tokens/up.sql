@@ -0,0 +1,5 @@
+-- sha256 of the refresh token secret, rotated on every refresh
+ALTER TABLE login_sessions ADD COLUMN refresh_hash varchar(64) NULL;
+-- the one before, presenting it again revokes the session
+ALTER TABLE login_sessions ADD COLUMN previous_refresh_hash varchar(64) NULL;
+ALTER TABLE login_sessions ADD COLUMN refreshed_at timestamp NULL;
@@ -0,0 +1,11 @@
+-- Single user tokens refused until they expire, whole sessions are revoked in login_sessions
+CREATE TABLE revoked_tokens
+(
+ -- `jti` claim, or derived from the token when it has none
+ jti        varchar(32) NOT NULL,
+ user_id    integer NOT NULL,
+ expires_at timestamp NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_383 PRIMARY KEY ( jti ),
+ CONSTRAINT FK_384 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE
+);
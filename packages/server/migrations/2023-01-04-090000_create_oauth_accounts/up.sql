@@ -0,0 +1,26 @@
+-- Provider accounts signing in as a user, a user may link one per provider
+CREATE TABLE oauth_accounts
+(
+ "id"       integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ user_id    integer NOT NULL,
+ -- github, google
+ provider   varchar(20) NOT NULL,
+ -- id at the provider, stable across renames
+ subject    varchar(255) NOT NULL,
+ email      varchar(255) NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_385 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_386 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX Index_387 ON oauth_accounts
+(
+ provider,
+ subject
+);
+
+CREATE UNIQUE INDEX Index_388 ON oauth_accounts
+(
+ user_id,
+ provider
+);
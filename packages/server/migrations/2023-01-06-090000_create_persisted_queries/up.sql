@@ -0,0 +1,15 @@
+-- Automatic persisted queries, documents clients refer to by their sha256
+CREATE TABLE persisted_queries
+(
+ -- lowercase hex of the sha256 of the query
+ hash         char(64) NOT NULL,
+ query        text NOT NULL,
+ created_at   timestamp NOT NULL,
+ last_used_at timestamp NOT NULL,
+ CONSTRAINT PK_389 PRIMARY KEY ( hash )
+);
+
+CREATE INDEX Index_390 ON persisted_queries
+(
+ last_used_at
+);
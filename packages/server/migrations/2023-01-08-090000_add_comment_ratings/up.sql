@@ -0,0 +1,3 @@
+-- Stars out of 5, comments written before ratings have none
+ALTER TABLE comments ADD COLUMN rating smallint NULL;
+ALTER TABLE comments ADD CONSTRAINT CK_391 CHECK ( rating BETWEEN 1 AND 5 );
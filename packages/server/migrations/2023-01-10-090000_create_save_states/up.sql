@@ -0,0 +1,18 @@
+-- Emulator states of a user, one per slot of a game
+CREATE TABLE save_states
+(
+ user_id    integer NOT NULL,
+ game_id    integer NOT NULL,
+ slot       integer NOT NULL,
+ -- zlib compressed state
+ data       bytea NOT NULL,
+ -- uncompressed bytes
+ size       integer NOT NULL,
+ -- lowercase hex sha256 of the uncompressed state
+ checksum   char(64) NOT NULL,
+ created_at timestamp NOT NULL,
+ updated_at timestamp NOT NULL,
+ CONSTRAINT PK_392 PRIMARY KEY ( user_id, game_id, slot ),
+ CONSTRAINT FK_393 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT FK_394 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ) ON DELETE CASCADE
+);
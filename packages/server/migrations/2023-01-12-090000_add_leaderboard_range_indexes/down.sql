@@ -0,0 +1,2 @@
+DROP INDEX Index_396;
+DROP INDEX Index_395;
@@ -0,0 +1,12 @@
+-- Daily and weekly leaderboards read the window of one game
+CREATE INDEX Index_395 ON scores
+(
+ game_id,
+ created_at
+);
+
+CREATE INDEX Index_396 ON play_sessions
+(
+ game_id,
+ ended_at
+);
@@ -0,0 +1,25 @@
+-- Defined per game, the game unlocks one by its key
+CREATE TABLE achievements
+(
+ "id"        integer NOT NULL GENERATED ALWAYS AS IDENTITY,
+ game_id     integer NOT NULL,
+ key         varchar NOT NULL,
+ name        varchar NOT NULL,
+ description varchar NOT NULL,
+ created_at  timestamp NOT NULL,
+ updated_at  timestamp NOT NULL,
+ CONSTRAINT PK_397 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_398 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT Index_399 UNIQUE ( game_id, key )
+);
+
+-- Unlocked achievements of a user
+CREATE TABLE user_achievements
+(
+ user_id        integer NOT NULL,
+ achievement_id integer NOT NULL,
+ unlocked_at    timestamp NOT NULL,
+ CONSTRAINT PK_400 PRIMARY KEY ( user_id, achievement_id ),
+ CONSTRAINT FK_401 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT FK_402 FOREIGN KEY ( achievement_id ) REFERENCES achievements ( "id" ) ON DELETE CASCADE
+);
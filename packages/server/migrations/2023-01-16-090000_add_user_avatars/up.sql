@@ -0,0 +1,2 @@
+-- Url served by `/avatar`, NULL until one is uploaded
+ALTER TABLE users ADD avatar varchar NULL;
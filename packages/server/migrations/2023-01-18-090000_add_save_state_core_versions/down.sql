@@ -0,0 +1 @@
+ALTER TABLE save_states DROP COLUMN core_version;
@@ -0,0 +1,2 @@
+-- Emulator core that wrote the state, NULL for states uploaded before versions
+ALTER TABLE save_states ADD COLUMN core_version integer NULL;
@@ -0,0 +1,2 @@
+DROP TABLE state_upload_chunks;
+DROP TABLE state_uploads;
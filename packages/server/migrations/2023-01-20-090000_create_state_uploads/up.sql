@@ -0,0 +1,32 @@
+-- Chunked save state uploads, purged a day after they begin
+CREATE TABLE state_uploads
+(
+ "id"         char(32) NOT NULL,
+ user_id      integer NOT NULL,
+ game_id      integer NOT NULL,
+ slot         integer NOT NULL,
+ -- uncompressed bytes of the whole state
+ total_size   integer NOT NULL,
+ -- lowercase hex sha256 the assembled state must match
+ checksum     char(64) NOT NULL,
+ core_version integer NULL,
+ created_at   timestamp NOT NULL,
+ CONSTRAINT PK_403 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_404 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ) ON DELETE CASCADE,
+ CONSTRAINT FK_405 FOREIGN KEY ( game_id ) REFERENCES games ( "id" ) ON DELETE CASCADE
+);
+
+CREATE INDEX Index_406 ON state_uploads
+(
+ created_at
+);
+
+-- Received ranges, a re-sent chunk replaces the one at its start
+CREATE TABLE state_upload_chunks
+(
+ upload_id char(32) NOT NULL,
+ start     integer NOT NULL,
+ data      bytea NOT NULL,
+ CONSTRAINT PK_407 PRIMARY KEY ( upload_id, start ),
+ CONSTRAINT FK_408 FOREIGN KEY ( upload_id ) REFERENCES state_uploads ( "id" ) ON DELETE CASCADE
+);
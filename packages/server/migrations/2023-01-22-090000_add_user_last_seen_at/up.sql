@@ -0,0 +1,12 @@
+-- Last login, token refresh or subscription, inactivity retention counts from it
+ALTER TABLE users ADD last_seen_at timestamp NULL;
+
+UPDATE users SET last_seen_at = GREATEST(
+  updated_at,
+  ( SELECT MAX(last_play_start_at) FROM records WHERE records.user_id = users."id" )
+);
+
+CREATE INDEX Index_409 ON users
+(
+ last_seen_at
+);
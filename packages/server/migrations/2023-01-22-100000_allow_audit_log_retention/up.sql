@@ -0,0 +1,13 @@
+-- Still append only, except rows past the `security_events` retention policy
+CREATE OR REPLACE FUNCTION audit_logs_immutable() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    IF OLD.created_at < timezone('UTC', NOW()) - make_interval(days => (
+      SELECT days FROM retention_policies WHERE category = 'security_events'
+    )) THEN
+      RETURN OLD;
+    END IF;
+  END IF;
+  RAISE EXCEPTION 'audit_logs is append only';
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,8 @@
+DROP TRIGGER save_states_release_blob ON save_states;
+DROP FUNCTION save_states_release_blob();
+DROP TABLE storage_deletions;
+-- States only in storage can't be kept
+DELETE FROM save_states WHERE data IS NULL;
+ALTER TABLE save_states DROP CONSTRAINT CK_410;
+ALTER TABLE save_states DROP COLUMN data_key;
+ALTER TABLE save_states ALTER COLUMN data SET NOT NULL;
@@ -0,0 +1,34 @@
+-- With a storage backend the state lives under `data_key`, `data` is kept for
+-- states written without one
+ALTER TABLE save_states ALTER COLUMN data DROP NOT NULL;
+ALTER TABLE save_states ADD COLUMN data_key varchar(128) NULL;
+ALTER TABLE save_states ADD CONSTRAINT CK_410 CHECK ( data IS NOT NULL OR data_key IS NOT NULL );
+
+-- Blobs no row points to anymore, removed from storage by the cleanup job
+CREATE TABLE storage_deletions
+(
+ "key"      varchar(128) NOT NULL,
+ created_at timestamp NOT NULL,
+ CONSTRAINT PK_411 PRIMARY KEY ( "key" )
+);
+
+-- Replaced and deleted states, cascades from users and games included
+CREATE FUNCTION save_states_release_blob() RETURNS trigger AS $$
+BEGIN
+  IF OLD.data_key IS NULL THEN
+    RETURN NULL;
+  END IF;
+  IF TG_OP = 'UPDATE' THEN
+    IF OLD.data_key IS NOT DISTINCT FROM NEW.data_key THEN
+      RETURN NULL;
+    END IF;
+  END IF;
+  INSERT INTO storage_deletions ("key", created_at)
+  VALUES (OLD.data_key, timezone('UTC', NOW()))
+  ON CONFLICT ("key") DO NOTHING;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER save_states_release_blob AFTER UPDATE OR DELETE ON save_states
+FOR EACH ROW EXECUTE FUNCTION save_states_release_blob();
@@ -0,0 +1,23 @@
+-- Messages pruned from long conversations, kept for the data export
+CREATE TABLE message_archives
+(
+ "id"        integer NOT NULL,
+ body        text NOT NULL,
+ target_id   integer NOT NULL,
+ user_id     integer NOT NULL,
+ created_at  timestamp NOT NULL,
+ archived_at timestamp NOT NULL,
+ CONSTRAINT PK_412 PRIMARY KEY ( "id" ),
+ CONSTRAINT FK_413 FOREIGN KEY ( user_id ) REFERENCES users ( "id" ),
+ CONSTRAINT FK_414 FOREIGN KEY ( target_id ) REFERENCES users ( "id" )
+);
+
+CREATE INDEX FK_415 ON message_archives
+(
+ user_id
+);
+
+CREATE INDEX FK_416 ON message_archives
+(
+ target_id
+);
@@ -0,0 +1,6 @@
+-- Set when retention anonymizes the account, accounts signed up with a
+-- provider have an empty password too
+ALTER TABLE users ADD anonymized_at timestamp NULL;
+
+UPDATE users SET anonymized_at = updated_at
+WHERE password = '' AND username = 'deleted_' || "id";
@@ -0,0 +1,111 @@
+use std::env;
+
+use crate::db::root::DB_POOL;
+use crate::net::{self, fetch_public, read_body};
+use crate::nsf::parse_nsf;
+use crate::schemas::game_attachment::{
+    get_game_attachment, is_music_attachment, set_attachment_fetched, set_music_info,
+};
+use crate::storage::get_storage;
+
+// Upstream serves rips as `application/octet-stream`
+const NSF_CONTENT_TYPE: &str = "audio/x-nsf";
+
+lazy_static! {
+    static ref ATTACHMENT_MAX_SIZE: u64 = env::var("ATTACHMENT_MAX_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(50 * 1024 * 1024);
+}
+
+pub enum FetchError {
+    // upstream says the file is gone
+    Dead(String),
+    Other(String),
+}
+
+pub struct CachedAttachment {
+    pub key: String,
+    pub content_type: String,
+}
+
+pub fn get_attachment_key(aid: i32) -> String {
+    format!("attachments/{}", aid)
+}
+
+/// The url comes from an issue, internal hosts are refused on every redirect
+fn fetch_attachment(url: &str) -> Result<(Vec<u8>, String), FetchError> {
+    let resp = fetch_public(url).map_err(|err| match err {
+        net::FetchError::Status(status @ (404 | 410)) => {
+            FetchError::Dead(format!("status {}", status))
+        }
+        err => FetchError::Other(err.to_string()),
+    })?;
+
+    let content_type = resp
+        .header("content-type")
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+    let data = read_body(resp, *ATTACHMENT_MAX_SIZE).map_err(FetchError::Other)?;
+
+    Ok((data, content_type))
+}
+
+/// Return the stored object, fetch into storage on first access.
+/// Blocking, call from `web::block` or `spawn_blocking`
+pub fn load_attachment(aid: i32) -> Option<CachedAttachment> {
+    let conn = DB_POOL.get().unwrap();
+    let attachment = get_game_attachment(&conn, aid)?;
+    let storage = get_storage()?;
+    let key = get_attachment_key(aid);
+
+    if let (Some(_), Some(content_type)) = (attachment.cached_at, attachment.content_type.clone()) {
+        if storage.exists(&key).unwrap_or_default() {
+            return Some(CachedAttachment { key, content_type });
+        }
+    }
+
+    match fetch_attachment(&attachment.url) {
+        Ok((data, content_type)) => {
+            let content_type = if is_music_attachment(&attachment) {
+                set_music_info(&conn, aid, &parse_nsf(&data));
+                NSF_CONTENT_TYPE.to_owned()
+            } else {
+                content_type
+            };
+            if let Err(err) = storage.put(&key, &data, &content_type) {
+                log::error!("Store attachment {} failed: {}", aid, err);
+                return None;
+            }
+            set_attachment_fetched(&conn, aid, Some((data.len() as i64, content_type.clone())));
+            Some(CachedAttachment { key, content_type })
+        }
+        Err(FetchError::Dead(err)) => {
+            log::info!("Attachment {} is dead: {}, {}", aid, attachment.url, err);
+            set_attachment_fetched(&conn, aid, None);
+            None
+        }
+        Err(FetchError::Other(err)) => {
+            log::error!(
+                "Fetch attachment {} failed: {}, {}",
+                aid,
+                attachment.url,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Warm the cache off the request path so dead links are flagged early
+pub fn cache_attachments(ids: Vec<i32>) {
+    if ids.is_empty() || get_storage().is_none() {
+        return;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        for aid in ids {
+            load_attachment(aid);
+        }
+    });
+}
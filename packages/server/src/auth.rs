@@ -1,13 +1,37 @@
+pub mod oauth;
+
 use std::collections::HashMap;
+use std::sync::Mutex;
 
 use actix_web::web::Query;
 use actix_web::HttpRequest;
 use chrono::Utc;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, Validation};
+use data_encoding::HEXLOWER;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, Validation};
 use jsonwebtoken::{EncodingKey, Header};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::hmac::{sign, Key, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
 
+use crate::keyring::{get_accepted_keys, get_signing_key, record_key_use, SigningKey};
 use crate::schemas::user::ScUser;
 
+/// Secret value without `PartialEq`, the only way to compare is constant time `ct_eq`
+pub struct Secret<'a>(&'a [u8]);
+
+impl<'a> Secret<'a> {
+    pub fn new(value: &'a str) -> Self {
+        Secret(value.as_bytes())
+    }
+    pub fn ct_eq(&self, other: &Secret) -> bool {
+        verify_slices_are_equal(self.0, other.0).is_ok()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserToken {
     // issued at
@@ -18,34 +42,266 @@ pub struct UserToken {
     pub user_id: i32,
     pub preferred_username: String,
     pub nickname: String,
+    // admin acting as `user_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator_id: Option<i32>,
+    // login session, none for impersonation and tokens issued before sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // token id for `logout`, none for tokens issued before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+}
+
+/// Who the request acts as, and the admin behind it when impersonated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Identity {
+    pub user_id: i32,
+    pub impersonator_id: Option<i32>,
+}
+
+/// What `logout` needs to revoke the token a request came with
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenHandle {
+    pub jti: String,
+    pub sid: Option<String>,
+    pub exp: i64,
+}
+
+/// Login sessions and their refresh tokens, also access tokens issued before refresh tokens
+pub const TOKEN_TTL_SECONDS: i64 = 60 * 60 * 24 * 7;
+
+const IMPERSONATION_TTL_SECONDS: i64 = 15 * 60;
+
+const ANON_TTL_SECONDS: i64 = 60 * 60 * 24;
+
+lazy_static! {
+    // Clients call `refreshToken` before this runs out
+    pub static ref ACCESS_TOKEN_TTL_SECONDS: i64 = env::var("ACCESS_TOKEN_TTL_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(60 * 60);
+    // anon id -> exp, tokens given up by `register`, lost on restart
+    static ref REVOKED_ANON_IDS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+    // session id -> exp, the denylist of user tokens, loaded at startup
+    static ref REVOKED_SESSIONS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+    // jti -> exp, single tokens given up by `logout`, loaded at startup
+    static ref REVOKED_TOKENS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
+/// Refuse the tokens of a session until they expire
+pub fn revoke_session(sid: &str, exp: i64) {
+    let now = Utc::now().timestamp();
+    let mut revoked = REVOKED_SESSIONS.lock().unwrap();
+    revoked.retain(|_, exp| *exp > now);
+    revoked.insert(sid.to_owned(), exp);
+}
+
+pub fn is_session_revoked(sid: &str) -> bool {
+    REVOKED_SESSIONS.lock().unwrap().contains_key(sid)
+}
+
+/// Refuse a single token until it expires
+pub fn revoke_token(jti: &str, exp: i64) {
+    let now = Utc::now().timestamp();
+    let mut revoked = REVOKED_TOKENS.lock().unwrap();
+    revoked.retain(|_, exp| *exp > now);
+    revoked.insert(jti.to_owned(), exp);
+}
+
+pub fn is_token_revoked(jti: &str) -> bool {
+    REVOKED_TOKENS.lock().unwrap().contains_key(jti)
+}
+
+fn generate_jti() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    HEXLOWER.encode(&bytes)
+}
+
+/// Tokens issued before `jti` are known by a digest of themselves
+fn get_token_jti(claims: &UserToken, token: &str) -> String {
+    claims
+        .jti
+        .clone()
+        .unwrap_or_else(|| HEXLOWER.encode(&digest(&SHA256, token.as_bytes()).as_ref()[..16]))
+}
+
+fn hash_refresh_secret(secret: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, secret.as_bytes()).as_ref())
+}
+
+/// `{sid}.{secret}` and the hash to store, the secret itself is never stored
+pub fn generate_refresh_token(sid: &str) -> (String, String) {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let secret = HEXLOWER.encode(&bytes);
+    (format!("{}.{}", sid, secret), hash_refresh_secret(&secret))
+}
+
+/// Session id and the hash of the secret
+pub fn parse_refresh_token(token: &str) -> Option<(&str, String)> {
+    let (sid, secret) = token.trim().split_once('.')?;
+    (!sid.is_empty() && secret.len() == 64).then(|| (sid, hash_refresh_secret(secret)))
+}
+
+/// Signed with `key`, its kid in the header
+fn encode_claims<T: Serialize>(key: &SigningKey, claims: &T) -> String {
+    let header = Header {
+        kid: Some(key.kid.clone()),
+        ..Header::default()
+    };
+    encode(
+        &header,
+        claims,
+        &EncodingKey::from_secret(key.secret.as_bytes()),
+    )
+    .unwrap_or_default()
+}
+
+/// Claims and kid of a token signed by one of `keys`,
+/// tokens issued before the first rotation have no kid and try each key
+fn decode_claims<T: DeserializeOwned>(keys: &[SigningKey], token: &str) -> Option<(T, String)> {
+    let kid = decode_header(token).ok().and_then(|header| header.kid);
+    let result = keys
+        .iter()
+        .filter(|key| kid.as_ref().map_or(true, |kid| *kid == key.kid))
+        .find_map(|key| {
+            decode::<T>(
+                token,
+                &DecodingKey::from_secret(key.secret.as_bytes()),
+                &Validation::new(Algorithm::HS256),
+            )
+            .ok()
+            .map(|token_data| (token_data.claims, key.kid.clone()))
+        });
+    if let (None, Some(key)) = (&result, keys.first()) {
+        // Malformed token fail before signature check, equalize the work
+        sign(
+            &Key::new(HMAC_SHA256, key.secret.as_bytes()),
+            token.as_bytes(),
+        );
+    }
+    result
+}
+
+/// Stable identity for clients that haven't registered,
+/// never accepted where a `UserToken` is expected
+#[derive(Serialize, Deserialize)]
+pub struct AnonToken {
+    pub iat: i64,
+    pub exp: i64,
+    pub anon_id: String,
+    pub anon: bool,
 }
 
 impl UserToken {
-    pub fn generate_token(secret: &str, user: &ScUser) -> String {
+    /// Short lived, the refresh token of the session gets the next one
+    pub fn generate_token(secret: &str, user: &ScUser, sid: &str) -> String {
         let now = Utc::now().timestamp();
-        let payload = UserToken {
+        UserToken {
             iat: now,
-            exp: now + 60 * 60 * 24 * 7,
+            exp: now + *ACCESS_TOKEN_TTL_SECONDS,
             user_id: user.id,
             preferred_username: user.username.to_owned(),
             nickname: user.nickname.to_owned(),
-        };
+            impersonator_id: None,
+            sid: Some(sid.to_owned()),
+            jti: Some(generate_jti()),
+        }
+        .encode(secret)
+    }
+    pub fn generate_impersonation_token(secret: &str, user: &ScUser, admin_id: i32) -> String {
+        let now = Utc::now().timestamp();
+        UserToken {
+            iat: now,
+            exp: now + IMPERSONATION_TTL_SECONDS,
+            user_id: user.id,
+            preferred_username: user.username.to_owned(),
+            nickname: user.nickname.to_owned(),
+            impersonator_id: Some(admin_id),
+            sid: None,
+            jti: Some(generate_jti()),
+        }
+        .encode(secret)
+    }
+    fn encode(&self, secret: &str) -> String {
+        encode_claims(&get_signing_key(secret), self)
+    }
+    /// Verified user id alone, revocation and key use are left to the handler
+    pub fn peek_user_id(secret: &str, token: &str) -> Option<i32> {
+        decode_claims::<UserToken>(&get_accepted_keys(secret), token)
+            .map(|(claims, _)| claims.user_id)
+    }
+    pub fn parse(secret: &str, token: &str) -> Option<Identity> {
+        UserToken::parse_session(secret, token).map(|(identity, _)| identity)
+    }
+    /// Identity and handle of a valid token, neither it nor its session revoked
+    pub fn parse_session(secret: &str, token: &str) -> Option<(Identity, TokenHandle)> {
+        decode_claims::<UserToken>(&get_accepted_keys(secret), token)
+            .filter(|(claims, _)| {
+                claims
+                    .sid
+                    .as_ref()
+                    .map_or(true, |sid| !is_session_revoked(sid))
+            })
+            .map(|(claims, kid)| (get_token_jti(&claims, token), claims, kid))
+            .filter(|(jti, _, _)| !is_token_revoked(jti))
+            .map(|(jti, claims, kid)| {
+                record_key_use(&kid);
+                let identity = Identity {
+                    user_id: claims.user_id,
+                    impersonator_id: claims.impersonator_id,
+                };
+                let handle = TokenHandle {
+                    jti,
+                    sid: claims.sid,
+                    exp: claims.exp,
+                };
+                (identity, handle)
+            })
+    }
+}
 
-        encode(
-            &Header::default(),
-            &payload,
-            &EncodingKey::from_secret(secret.as_bytes()),
-        )
-        .unwrap_or_default()
+impl AnonToken {
+    pub fn generate_token(secret: &str) -> String {
+        let mut bytes = [0u8; 16];
+        SystemRandom::new().fill(&mut bytes).unwrap();
+        let now = Utc::now().timestamp();
+        let token = AnonToken {
+            iat: now,
+            exp: now + ANON_TTL_SECONDS,
+            anon_id: HEXLOWER.encode(&bytes),
+            anon: true,
+        };
+        encode_claims(&get_signing_key(secret), &token)
     }
-    pub fn parse(secret: &str, token: &str) -> Option<i32> {
-        decode::<UserToken>(
-            token,
-            &DecodingKey::from_secret(secret.as_bytes()),
-            &Validation::new(Algorithm::HS256),
-        )
-        .map(|token_data| token_data.claims.user_id)
-        .ok()
+    fn decode(secret: &str, token: &str) -> Option<(AnonToken, String)> {
+        decode_claims::<AnonToken>(&get_accepted_keys(secret), token)
+            .filter(|(claims, _)| claims.anon)
+    }
+    /// Anon id of a valid, unrevoked token
+    pub fn parse(secret: &str, token: &str) -> Option<String> {
+        AnonToken::decode(secret, token)
+            .filter(|(claims, _)| {
+                !REVOKED_ANON_IDS
+                    .lock()
+                    .unwrap()
+                    .contains_key(&claims.anon_id)
+            })
+            .map(|(claims, kid)| {
+                record_key_use(&kid);
+                claims.anon_id
+            })
+    }
+    pub fn revoke(secret: &str, token: &str) {
+        if let Some((claims, _)) = AnonToken::decode(secret, token) {
+            let now = Utc::now().timestamp();
+            let mut revoked = REVOKED_ANON_IDS.lock().unwrap();
+            revoked.retain(|_, exp| *exp > now);
+            revoked.insert(claims.anon_id, claims.exp);
+        }
     }
 }
 
@@ -68,3 +324,180 @@ pub fn extract_token_from_req(req: &HttpRequest) -> String {
             .unwrap_or("".into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::*;
+
+    #[test]
+    fn secret_ct_eq() {
+        assert!(Secret::new("abc").ct_eq(&Secret::new("abc")));
+        assert!(!Secret::new("abc").ct_eq(&Secret::new("abd")));
+        assert!(!Secret::new("abc").ct_eq(&Secret::new("abcd")));
+        assert!(!Secret::new("").ct_eq(&Secret::new("a")));
+    }
+
+    #[test]
+    fn parse_impersonation() {
+        let now = Utc::now().timestamp();
+        let mut token = UserToken {
+            iat: now,
+            exp: now + 60,
+            user_id: 2,
+            preferred_username: "user".into(),
+            nickname: "user".into(),
+            impersonator_id: None,
+            sid: None,
+            jti: None,
+        };
+        assert_eq!(
+            UserToken::parse("secret", &token.encode("secret")),
+            Some(Identity {
+                user_id: 2,
+                impersonator_id: None
+            })
+        );
+        token.impersonator_id = Some(1);
+        assert_eq!(
+            UserToken::parse("secret", &token.encode("secret")),
+            Some(Identity {
+                user_id: 2,
+                impersonator_id: Some(1)
+            })
+        );
+        assert_eq!(UserToken::parse("other", &token.encode("secret")), None);
+    }
+
+    #[test]
+    fn revoked_session() {
+        let now = Utc::now().timestamp();
+        let token = |sid: &str| {
+            UserToken {
+                iat: now,
+                exp: now + 60,
+                user_id: 2,
+                preferred_username: "user".into(),
+                nickname: "user".into(),
+                impersonator_id: None,
+                sid: Some(sid.into()),
+                jti: None,
+            }
+            .encode("secret")
+        };
+        let (kept, replaced) = (token("revoked-session-a"), token("revoked-session-b"));
+        assert_eq!(
+            UserToken::parse_session("secret", &replaced).map(|(_, handle)| handle.sid),
+            Some(Some("revoked-session-b".into()))
+        );
+        revoke_session("revoked-session-b", now + 60);
+        assert_eq!(UserToken::parse("secret", &replaced), None);
+        assert!(UserToken::parse("secret", &kept).is_some());
+    }
+
+    #[test]
+    fn revoked_token() {
+        let now = Utc::now().timestamp();
+        let token = |jti: Option<String>| {
+            UserToken {
+                iat: now,
+                exp: now + 60,
+                user_id: 2,
+                preferred_username: "user".into(),
+                nickname: "user".into(),
+                impersonator_id: None,
+                sid: Some("revoked-token-session".into()),
+                jti,
+            }
+            .encode("secret")
+        };
+        let (revoked, kept) = (token(Some(generate_jti())), token(Some(generate_jti())));
+        let (_, handle) = UserToken::parse_session("secret", &revoked).unwrap();
+        assert_eq!(handle.jti.len(), 32);
+        revoke_token(&handle.jti, handle.exp);
+        assert_eq!(UserToken::parse("secret", &revoked), None);
+        // Other tokens of the same session stay valid
+        assert!(UserToken::parse("secret", &kept).is_some());
+
+        // Issued before `jti`, known by the digest of the token
+        let legacy = token(None);
+        let (_, handle) = UserToken::parse_session("secret", &legacy).unwrap();
+        assert_eq!(
+            UserToken::parse_session("secret", &legacy).map(|(_, handle)| handle.jti),
+            Some(handle.jti.clone())
+        );
+        revoke_token(&handle.jti, handle.exp);
+        assert_eq!(UserToken::parse("secret", &legacy), None);
+    }
+
+    #[test]
+    fn refresh_token_format() {
+        let (token, hash) = generate_refresh_token("refresh-session");
+        assert_eq!(
+            parse_refresh_token(&token),
+            Some(("refresh-session", hash.clone()))
+        );
+        assert!(!token.contains(&hash));
+        let (other, other_hash) = generate_refresh_token("refresh-session");
+        assert_ne!(other, token);
+        assert_ne!(other_hash, hash);
+        assert_eq!(parse_refresh_token("refresh-session"), None);
+        assert_eq!(parse_refresh_token("refresh-session.short"), None);
+        assert_eq!(
+            parse_refresh_token(&token[token.find('.').unwrap()..]),
+            None
+        );
+    }
+
+    #[test]
+    fn anon_token() {
+        let token = AnonToken::generate_token("secret");
+        let anon_id = AnonToken::parse("secret", &token).unwrap();
+        assert_eq!(anon_id.len(), 32);
+        assert_eq!(AnonToken::parse("other", &token), None);
+        // Subscriptions and user resolvers only take user tokens
+        assert_eq!(UserToken::parse("secret", &token), None);
+
+        let other = AnonToken::generate_token("secret");
+        AnonToken::revoke("secret", &token);
+        assert_eq!(AnonToken::parse("secret", &token), None);
+        assert!(AnonToken::parse("secret", &other).is_some());
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let now = Utc::now().timestamp();
+        let claims = AnonToken {
+            iat: now,
+            exp: now + 60,
+            anon_id: "rotated".into(),
+            anon: true,
+        };
+        let (old, new) = (SigningKey::new("old", None), SigningKey::new("new", None));
+        let decode = |keys: &[SigningKey], token: &str| {
+            decode_claims::<AnonToken>(keys, token).map(|(_, kid)| kid)
+        };
+
+        // Issued before the rotation, still accepted in the grace period
+        let token = encode_claims(&old, &claims);
+        assert_eq!(
+            decode(&[new.clone(), old.clone()], &token),
+            Some(old.kid.clone())
+        );
+        assert_eq!(decode(&[new.clone()], &token), None);
+        let token = encode_claims(&new, &claims);
+        assert_eq!(
+            decode(&[new.clone(), old.clone()], &token),
+            Some(new.kid.clone())
+        );
+        // The kid names a key, another one's signature doesn't do
+        assert_eq!(decode(&[old.clone()], &token), None);
+        // No kid from before the first rotation
+        let legacy = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"old"),
+        )
+        .unwrap();
+        assert_eq!(decode(&[new, old.clone()], &legacy), Some(old.kid));
+    }
+}
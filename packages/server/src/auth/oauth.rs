@@ -0,0 +1,308 @@
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use ring::hmac::{sign, verify, Key, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+use std::env;
+use std::time::Duration;
+use strum::{Display, EnumString};
+use url::{form_urlencoded, Url};
+
+// A login has this long to come back from the provider
+const STATE_TTL_SECONDS: i64 = 10 * 60;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    // Public url of this server, callbacks are `{OAUTH_REDIRECT_BASE}/oauth/{provider}/callback`
+    static ref REDIRECT_BASE: String = env::var("OAUTH_REDIRECT_BASE")
+        .unwrap_or_default()
+        .trim_end_matches('/')
+        .to_owned();
+    static ref GITHUB_CLIENT: Option<OauthClient> = OauthClient::from_env("GITHUB");
+    static ref GOOGLE_CLIENT: Option<OauthClient> = OauthClient::from_env("GOOGLE");
+}
+
+#[derive(Debug, Clone, Copy, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum OauthProvider {
+    Github,
+    Google,
+}
+
+struct OauthClient {
+    id: String,
+    secret: String,
+}
+
+impl OauthClient {
+    fn from_env(prefix: &str) -> Option<Self> {
+        let var = |name: &str| {
+            env::var(format!("{}_{}", prefix, name))
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        Some(OauthClient {
+            id: var("CLIENT_ID")?,
+            secret: var("CLIENT_SECRET")?,
+        })
+    }
+}
+
+/// Account at the provider, `subject` never changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct OauthProfile {
+    pub subject: String,
+    pub nickname: String,
+    // verified address only
+    pub email: Option<String>,
+}
+
+/// The `state` parameter carried through the provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct OauthState {
+    // also kept in a cookie, a callback from another browser is refused
+    pub nonce: String,
+    // signed in user linking the account, `None` to log in
+    pub link_user_id: Option<i32>,
+}
+
+impl OauthProvider {
+    fn client(&self) -> Option<&'static OauthClient> {
+        match self {
+            OauthProvider::Github => GITHUB_CLIENT.as_ref(),
+            OauthProvider::Google => GOOGLE_CLIENT.as_ref(),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.client().is_some() && !REDIRECT_BASE.is_empty()
+    }
+
+    fn redirect_uri(&self) -> String {
+        format!("{}/oauth/{}/callback", *REDIRECT_BASE, self)
+    }
+
+    /// Where the browser goes to sign in, `None` when not configured
+    pub fn authorize_url(&self, state: &str) -> Option<String> {
+        let client = self.client().filter(|_| self.is_configured())?;
+        let (base, scope) = match self {
+            OauthProvider::Github => ("https://github.com/login/oauth/authorize", "read:user"),
+            OauthProvider::Google => (
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "openid email profile",
+            ),
+        };
+        let url = Url::parse_with_params(
+            base,
+            &[
+                ("client_id", client.id.as_str()),
+                ("redirect_uri", &self.redirect_uri()),
+                ("response_type", "code"),
+                ("scope", scope),
+                ("state", state),
+            ],
+        )
+        .ok()?;
+        Some(url.into())
+    }
+
+    /// Trade the callback code for the profile, blocking
+    pub fn exchange_code(&self, code: &str) -> Result<OauthProfile, String> {
+        let client = self.client().ok_or("not configured")?;
+        let redirect_uri = self.redirect_uri();
+        let params = [
+            ("client_id", client.id.as_str()),
+            ("client_secret", client.secret.as_str()),
+            ("code", code),
+            ("redirect_uri", &redirect_uri),
+            ("grant_type", "authorization_code"),
+        ];
+        let token_url = match self {
+            OauthProvider::Github => "https://github.com/login/oauth/access_token",
+            OauthProvider::Google => "https://oauth2.googleapis.com/token",
+        };
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        let resp = attohttpc::post(token_url)
+            .timeout(TIMEOUT)
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .text(body)
+            .send()
+            .map_err(|err| err.to_string())?;
+        let token = read_json(resp)?
+            .get("access_token")
+            .and_then(|token| token.as_str())
+            .map(|token| token.to_owned())
+            .ok_or("no access token")?;
+
+        let profile_url = match self {
+            OauthProvider::Github => "https://api.github.com/user",
+            OauthProvider::Google => "https://openidconnect.googleapis.com/v1/userinfo",
+        };
+        let resp = attohttpc::get(profile_url)
+            .timeout(TIMEOUT)
+            .header("Accept", "application/json")
+            .header("User-Agent", "nesbox")
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+            .map_err(|err| err.to_string())?;
+        let profile = read_json(resp)?;
+        match self {
+            OauthProvider::Github => parse_github_profile(&profile),
+            OauthProvider::Google => parse_google_profile(&profile),
+        }
+        .ok_or_else(|| format!("unexpected profile {}", profile))
+    }
+}
+
+fn read_json(resp: attohttpc::Response) -> Result<Value, String> {
+    if !resp.is_success() {
+        return Err(format!("status {}", resp.status()));
+    }
+    let text = resp.text().map_err(|err| err.to_string())?;
+    serde_json::from_str(&text).map_err(|err| err.to_string())
+}
+
+// https://docs.github.com/en/rest/users/users#get-the-authenticated-user
+fn parse_github_profile(profile: &Value) -> Option<OauthProfile> {
+    let login = profile.get("login")?.as_str()?;
+    let name = profile
+        .get("name")
+        .and_then(|name| name.as_str())
+        .filter(|name| !name.trim().is_empty());
+    Some(OauthProfile {
+        subject: profile.get("id")?.as_i64()?.to_string(),
+        nickname: name.unwrap_or(login).trim().to_owned(),
+        // the public address is not necessarily verified
+        email: None,
+    })
+}
+
+// https://developers.google.com/identity/openid-connect/openid-connect#obtainuserinfo
+fn parse_google_profile(profile: &Value) -> Option<OauthProfile> {
+    let email = profile
+        .get("email")
+        .and_then(|email| email.as_str())
+        .filter(|_| profile.get("email_verified").and_then(|v| v.as_bool()) == Some(true));
+    let name = profile
+        .get("name")
+        .and_then(|name| name.as_str())
+        .filter(|name| !name.trim().is_empty())
+        .or_else(|| email.and_then(|email| email.split('@').next()));
+    Some(OauthProfile {
+        subject: profile.get("sub")?.as_str()?.to_owned(),
+        nickname: name.unwrap_or("google").trim().to_owned(),
+        email: email.map(|email| email.to_owned()),
+    })
+}
+
+fn sign_state(secret: &str, provider: OauthProvider, payload: &str) -> String {
+    let message = format!("{}.{}", provider, payload);
+    HEXLOWER.encode(
+        sign(
+            &Key::new(HMAC_SHA256, secret.as_bytes()),
+            message.as_bytes(),
+        )
+        .as_ref(),
+    )
+}
+
+/// `{exp}.{link_user_id}.{nonce}.{signature}`, and the nonce for the cookie
+pub fn generate_state(
+    secret: &str,
+    provider: OauthProvider,
+    link_user_id: Option<i32>,
+) -> (String, String) {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let nonce = HEXLOWER.encode(&bytes);
+    let payload = format!(
+        "{}.{}.{}",
+        Utc::now().timestamp() + STATE_TTL_SECONDS,
+        link_user_id.unwrap_or_default(),
+        nonce
+    );
+    let signature = sign_state(secret, provider, &payload);
+    (format!("{}.{}", payload, signature), nonce)
+}
+
+/// `None` when forged, expired or issued for another provider
+pub fn parse_state(secret: &str, provider: OauthProvider, state: &str) -> Option<OauthState> {
+    let (payload, signature) = state.rsplit_once('.')?;
+    let message = format!("{}.{}", provider, payload);
+    verify(
+        &Key::new(HMAC_SHA256, secret.as_bytes()),
+        message.as_bytes(),
+        &HEXLOWER.decode(signature.as_bytes()).ok()?,
+    )
+    .ok()?;
+    let mut parts = payload.splitn(3, '.');
+    let exp: i64 = parts.next()?.parse().ok()?;
+    let uid: i32 = parts.next()?.parse().ok()?;
+    let nonce = parts.next()?;
+    (exp > Utc::now().timestamp()).then(|| OauthState {
+        nonce: nonce.to_owned(),
+        link_user_id: (uid > 0).then_some(uid),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::oauth::*;
+    use serde_json::json;
+
+    #[test]
+    fn oauth_state() {
+        let (state, nonce) = generate_state("secret", OauthProvider::Github, Some(7));
+        assert_eq!(
+            parse_state("secret", OauthProvider::Github, &state),
+            Some(OauthState {
+                nonce,
+                link_user_id: Some(7)
+            })
+        );
+        assert_eq!(parse_state("other", OauthProvider::Github, &state), None);
+        // Issued for one provider, replayed on the other's callback
+        assert_eq!(parse_state("secret", OauthProvider::Google, &state), None);
+        let forged = state.replacen(".7.", ".8.", 1);
+        assert_eq!(parse_state("secret", OauthProvider::Github, &forged), None);
+
+        let (state, _) = generate_state("secret", OauthProvider::Google, None);
+        assert_eq!(
+            parse_state("secret", OauthProvider::Google, &state).map(|state| state.link_user_id),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn oauth_profiles() {
+        let github = json!({ "id": 583231, "login": "octocat", "name": null, "email": "octocat@github.com" });
+        assert_eq!(
+            parse_github_profile(&github),
+            Some(OauthProfile {
+                subject: "583231".into(),
+                nickname: "octocat".into(),
+                email: None,
+            })
+        );
+        let google = json!({
+            "sub": "110169484474386276334",
+            "email": "player@example.com",
+            "email_verified": true,
+        });
+        assert_eq!(
+            parse_google_profile(&google),
+            Some(OauthProfile {
+                subject: "110169484474386276334".into(),
+                nickname: "player".into(),
+                email: Some("player@example.com".into()),
+            })
+        );
+        let unverified =
+            json!({ "sub": "1", "name": "Player", "email": "a@b.c", "email_verified": false });
+        assert_eq!(parse_google_profile(&unverified).unwrap().email, None);
+        assert_eq!(parse_google_profile(&json!({ "email": "a@b.c" })), None);
+    }
+}
@@ -0,0 +1,91 @@
+use diesel::pg::PgConnection;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use std::env;
+use std::io::Cursor;
+
+use crate::schemas::user::update_avatar;
+use crate::screenshot::{encode, RenditionFormat};
+use crate::storage::{HashedBlobs, Storage};
+
+// Width and height of stored avatars, smaller images aren't upscaled
+const AVATAR_SIZE: u32 = 256;
+// Larger sources are refused before decoding
+const MAX_SOURCE_SIDE: u32 = 4096;
+
+lazy_static! {
+    // Body limit of `/upload/avatar`
+    pub static ref MAX_AVATAR_BYTES: usize = env::var("MAX_AVATAR_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(2 * 1024 * 1024);
+}
+
+/// Served by `/avatar/{uid}/{hash}`
+pub static AVATARS: HashedBlobs = HashedBlobs {
+    dir: "avatars",
+    route: "/avatar",
+    extension: "webp",
+    content_type: "image/webp",
+};
+
+/// Center square of the image, scaled to `AVATAR_SIZE` as webp
+pub fn render_avatar(source: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::new(Cursor::new(source))
+        .with_guessed_format()
+        .map_err(|err| err.to_string())?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_SIDE);
+    limits.max_image_height = Some(MAX_SOURCE_SIDE);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|err| err.to_string())?;
+
+    let side = AVATAR_SIZE.min(image.width()).min(image.height());
+    encode(
+        &image.resize_to_fill(side, side, FilterType::Triangle),
+        RenditionFormat::Webp,
+    )
+}
+
+/// Stores a rendered avatar on the account and deletes the replaced one, returns its url
+pub fn save_avatar(
+    conn: &PgConnection,
+    storage: &dyn Storage,
+    uid: i32,
+    avatar: &[u8],
+) -> Result<String, String> {
+    let url = AVATARS
+        .put(storage, uid, avatar)
+        .map_err(|err| err.to_string())?;
+    let previous = update_avatar(conn, uid, &url).map_err(|err| err.to_string())?;
+    if let Some(previous) = previous.filter(|previous| *previous != url) {
+        AVATARS.delete_url(storage, &previous);
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avatar::*;
+
+    // 480x300 png
+    const FIXTURE: &[u8] = include_bytes!("screenshot_fixture.png");
+
+    #[test]
+    fn avatar_upload() {
+        let avatar = image::load_from_memory(&render_avatar(FIXTURE).unwrap()).unwrap();
+        assert_eq!(
+            (avatar.width(), avatar.height()),
+            (AVATAR_SIZE, AVATAR_SIZE)
+        );
+        assert!(render_avatar(b"<svg></svg>").is_err());
+
+        // Urls already on accounts keep resolving
+        let url = AVATARS.get_url(7, "0123456789abcdef");
+        assert_eq!(url, "/avatar/7/0123456789abcdef");
+        assert_eq!(
+            AVATARS.parse_url(&url),
+            Some("avatars/7/0123456789abcdef.webp".into())
+        );
+    }
+}
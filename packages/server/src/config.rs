@@ -0,0 +1,276 @@
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use strum::{Display, EnumString};
+
+use crate::keyring::{get_kid, SigningKey};
+
+/// Reverse proxy in front of the server, `PROXY_PROFILE`
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ProxyProfile {
+    Direct,
+    // closes websockets idle for 100 seconds, and long lived ones at edge restarts
+    Cloudflare,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionConfig {
+    pub profile: ProxyProfile,
+    // graphql-ws `ka` message interval, the application level ping
+    pub keep_alive: Duration,
+    // proxy closes silent sockets after this, `None` when there's no such proxy
+    pub idle_timeout: Option<Duration>,
+    // suggested wait before the first reconnect, clients double it on failure
+    pub reconnect_backoff: Duration,
+    // permessage-deflate when the client offers it, costs ~256KB per socket at 15 bits
+    pub deflate: bool,
+    // LZ77 window of the server side, 9 to 15
+    pub deflate_window_bits: u8,
+    // reset after every message, worse ratio for small messages
+    pub deflate_no_context_takeover: bool,
+}
+
+impl SubscriptionConfig {
+    fn preset(profile: ProxyProfile) -> Self {
+        match profile {
+            ProxyProfile::Direct => SubscriptionConfig {
+                profile,
+                keep_alive: Duration::from_secs(15),
+                idle_timeout: None,
+                reconnect_backoff: Duration::from_secs(1),
+                deflate: false,
+                deflate_window_bits: 15,
+                deflate_no_context_takeover: false,
+            },
+            ProxyProfile::Cloudflare => SubscriptionConfig {
+                profile,
+                keep_alive: Duration::from_secs(30),
+                idle_timeout: Some(Duration::from_secs(100)),
+                // Spread reconnects after an edge restart drops everyone at once
+                reconnect_backoff: Duration::from_secs(2),
+                deflate: false,
+                deflate_window_bits: 15,
+                deflate_no_context_takeover: false,
+            },
+        }
+    }
+
+    /// Profile preset with `SUBSCRIPTION_*` overrides,
+    /// keep alive always stays under half the idle timeout
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let seconds = |name| {
+            var(name)
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|value| *value > 0)
+                .map(Duration::from_secs)
+        };
+
+        let profile = var("PROXY_PROFILE")
+            .and_then(|value| ProxyProfile::from_str(&value).ok())
+            .unwrap_or(ProxyProfile::Direct);
+        let mut config = SubscriptionConfig::preset(profile);
+        if let Some(keep_alive) = seconds("SUBSCRIPTION_KEEP_ALIVE_SECONDS") {
+            config.keep_alive = keep_alive;
+        }
+        if let Some(idle_timeout) = seconds("SUBSCRIPTION_IDLE_TIMEOUT_SECONDS") {
+            config.idle_timeout = Some(idle_timeout);
+        }
+        if let Some(idle_timeout) = config.idle_timeout {
+            config.keep_alive = config.keep_alive.min(idle_timeout / 2);
+        }
+        let flag = |name| var(name).map(|value: String| value == "true" || value == "1");
+        if let Some(deflate) = flag("SUBSCRIPTION_DEFLATE") {
+            config.deflate = deflate;
+        }
+        if let Some(bits) = var("SUBSCRIPTION_DEFLATE_WINDOW_BITS")
+            .and_then(|value| value.parse::<u8>().ok())
+            .filter(|bits| (9..=15).contains(bits))
+        {
+            config.deflate_window_bits = bits;
+        }
+        if let Some(reset) = flag("SUBSCRIPTION_DEFLATE_NO_CONTEXT_TAKEOVER") {
+            config.deflate_no_context_takeover = reset;
+        }
+        config
+    }
+}
+
+lazy_static! {
+    pub static ref SUBSCRIPTION_CONFIG: SubscriptionConfig =
+        SubscriptionConfig::from_vars(|name| env::var(name).ok().filter(|value| !value.is_empty()));
+    // Public proxy addresses, e.g. of a CDN, comma separated
+    pub static ref TRUSTED_PROXIES: Vec<IpAddr> = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+}
+
+/// Signing keys in the order they are tried, persisted ones newest first and
+/// ahead of `SECRET`, which is dropped once a rotation retired it
+pub fn resolve_secrets(
+    persisted: Vec<SigningKey>,
+    retired: &[String],
+    env_secret: &str,
+) -> Vec<SigningKey> {
+    let env_kid = get_kid(env_secret);
+    let mut keys = persisted;
+    keys.sort_by_key(|key| std::cmp::Reverse(key.expires_at.unwrap_or(i64::MAX)));
+    if !retired.contains(&env_kid) && keys.iter().all(|key| key.kid != env_kid) {
+        keys.push(SigningKey::new(env_secret, None));
+    }
+    keys
+}
+
+// Loopback and private peers are the proxy of a single host or cluster deployment
+fn is_trusted_proxy(ip: &IpAddr, trusted: &[IpAddr]) -> bool {
+    trusted.contains(ip)
+        || ip.is_loopback()
+        || match ip {
+            IpAddr::V4(ip) => ip.is_private(),
+            // unique local
+            IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00,
+        }
+}
+
+/// Client address that can't be spoofed with headers, `X-Forwarded-For` is only
+/// read when the peer is a trusted proxy, the nearest untrusted hop is the client
+pub fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if !is_trusted_proxy(&client, trusted) {
+        return Some(client);
+    }
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !is_trusted_proxy(&ip, trusted) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use std::collections::HashMap;
+
+    fn derive(vars: &[(&str, &str)]) -> SubscriptionConfig {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        SubscriptionConfig::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn proxy_profiles() {
+        let direct = derive(&[]);
+        assert_eq!(direct.profile, ProxyProfile::Direct);
+        assert_eq!(direct.keep_alive, Duration::from_secs(15));
+        assert_eq!(direct.idle_timeout, None);
+        assert_eq!(derive(&[("PROXY_PROFILE", "nginx")]), direct);
+
+        let cloudflare = derive(&[("PROXY_PROFILE", "cloudflare")]);
+        assert_eq!(cloudflare.profile, ProxyProfile::Cloudflare);
+        assert_eq!(cloudflare.keep_alive, Duration::from_secs(30));
+        assert_eq!(cloudflare.idle_timeout, Some(Duration::from_secs(100)));
+        assert_eq!(cloudflare.reconnect_backoff, Duration::from_secs(2));
+
+        // Overrides can't push keep alive past the proxy
+        let tuned = derive(&[
+            ("PROXY_PROFILE", "cloudflare"),
+            ("SUBSCRIPTION_KEEP_ALIVE_SECONDS", "90"),
+        ]);
+        assert_eq!(tuned.keep_alive, Duration::from_secs(50));
+        let tuned = derive(&[
+            ("SUBSCRIPTION_KEEP_ALIVE_SECONDS", "0"),
+            ("SUBSCRIPTION_IDLE_TIMEOUT_SECONDS", "20"),
+        ]);
+        assert_eq!(tuned.keep_alive, Duration::from_secs(10));
+        assert_eq!(tuned.idle_timeout, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn deflate_config() {
+        assert!(!derive(&[]).deflate);
+        let tuned = derive(&[
+            ("SUBSCRIPTION_DEFLATE", "true"),
+            ("SUBSCRIPTION_DEFLATE_WINDOW_BITS", "10"),
+            ("SUBSCRIPTION_DEFLATE_NO_CONTEXT_TAKEOVER", "1"),
+        ]);
+        assert!(tuned.deflate);
+        assert_eq!(tuned.deflate_window_bits, 10);
+        assert!(tuned.deflate_no_context_takeover);
+        // zlib can't do 8 bits raw deflate
+        let tuned = derive(&[("SUBSCRIPTION_DEFLATE_WINDOW_BITS", "8")]);
+        assert_eq!(tuned.deflate_window_bits, 15);
+    }
+
+    #[test]
+    fn client_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let cdn = [ip("203.0.113.7")];
+
+        // Direct clients can't claim another address
+        assert_eq!(
+            resolve_client_ip(Some(ip("198.51.100.1")), Some("1.1.1.1"), &cdn),
+            Some(ip("198.51.100.1"))
+        );
+        // Local proxy appends the real client, earlier hops are client supplied
+        assert_eq!(
+            resolve_client_ip(Some(ip("127.0.0.1")), Some("1.1.1.1, 198.51.100.1"), &[]),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            resolve_client_ip(
+                Some(ip("10.0.0.2")),
+                Some("1.1.1.1, 198.51.100.1, 203.0.113.7"),
+                &cdn
+            ),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            resolve_client_ip(Some(ip("10.0.0.2")), Some("junk"), &cdn),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(resolve_client_ip(None, Some("1.1.1.1"), &cdn), None);
+    }
+
+    #[test]
+    fn persisted_secrets() {
+        let kids = |keys: Vec<SigningKey>| keys.into_iter().map(|key| key.kid).collect::<Vec<_>>();
+
+        // Never rotated
+        assert_eq!(
+            kids(resolve_secrets(vec![], &[], "env")),
+            vec![get_kid("env")]
+        );
+        // Rotated twice, the env secret is persisted like the others
+        let persisted = vec![
+            SigningKey::new("env", Some(100)),
+            SigningKey::new("second", None),
+            SigningKey::new("first", Some(200)),
+        ];
+        assert_eq!(
+            kids(resolve_secrets(persisted, &[], "env")),
+            vec![get_kid("second"), get_kid("first"), get_kid("env")]
+        );
+        // Retired, not accepted again after a restart
+        assert_eq!(
+            kids(resolve_secrets(
+                vec![SigningKey::new("second", None)],
+                &[get_kid("env")],
+                "env"
+            )),
+            vec![get_kid("second")]
+        );
+    }
+}
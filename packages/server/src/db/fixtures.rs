@@ -0,0 +1,77 @@
+use diesel::connection::Connection;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Varchar};
+use std::env;
+
+#[derive(QueryableByName)]
+struct Inserted {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+#[derive(QueryableByName)]
+struct Scans {
+    #[sql_type = "BigInt"]
+    scans: i64,
+}
+
+/// A migrated `TEST_DATABASE_URL` database inside a transaction that is
+/// never committed, tests using it are `#[ignore]`d and run with `--include-ignored`
+pub fn test_conn() -> PgConnection {
+    let url = test_database_url();
+    let conn = PgConnection::establish(&url).expect("could not connect to TEST_DATABASE_URL");
+    conn.begin_test_transaction().unwrap();
+    conn
+}
+
+pub fn test_database_url() -> String {
+    env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is unset")
+}
+
+pub fn insert_user(conn: &PgConnection, username: &str) -> i32 {
+    diesel::sql_query(
+        r#"INSERT INTO users (username, password, nickname, created_at, updated_at)
+        VALUES ($1, '', $1, NOW(), NOW()) RETURNING "id""#,
+    )
+    .bind::<Varchar, _>(username)
+    .get_result::<Inserted>(conn)
+    .unwrap()
+    .id
+}
+
+pub fn insert_game(conn: &PgConnection, name: &str) -> i32 {
+    diesel::sql_query(
+        r#"INSERT INTO games (name, description, preview, created_at, updated_at, rom)
+        VALUES ($1, '', '', NOW(), NOW(), '') RETURNING "id""#,
+    )
+    .bind::<Varchar, _>(name)
+    .get_result::<Inserted>(conn)
+    .unwrap()
+    .id
+}
+
+pub fn insert_room(conn: &PgConnection, game_id: i32, host: i32) -> i32 {
+    diesel::sql_query(
+        r#"INSERT INTO rooms (game_id, "private", created_at, updated_at, host, mode)
+        VALUES ($1, false, NOW(), NOW(), $2, 'co_op') RETURNING "id""#,
+    )
+    .bind::<Integer, _>(game_id)
+    .bind::<Integer, _>(host)
+    .get_result::<Inserted>(conn)
+    .unwrap()
+    .id
+}
+
+/// Scans of `table` by this transaction so far, a per-row query shows up
+/// as one scan per row
+pub fn table_scans(conn: &PgConnection, table: &str) -> i64 {
+    diesel::sql_query(
+        r#"SELECT (COALESCE(seq_scan, 0) + COALESCE(idx_scan, 0))::bigint AS scans
+        FROM pg_stat_xact_user_tables WHERE relname = $1"#,
+    )
+    .bind::<Varchar, _>(table)
+    .get_result::<Scans>(conn)
+    .unwrap()
+    .scans
+}
@@ -1,3 +1,5 @@
+#[cfg(test)]
+pub mod fixtures;
 pub mod models;
 pub mod root;
 pub mod schema;
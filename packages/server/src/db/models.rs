@@ -1,15 +1,50 @@
+use super::schema::achievements;
+use super::schema::audit_logs;
+use super::schema::client_versions;
+use super::schema::collection_games;
+use super::schema::collections;
 use super::schema::comments;
+use super::schema::core_versions;
 use super::schema::favorites;
+use super::schema::feature_flags;
+use super::schema::federation_syncs;
 use super::schema::friends;
+use super::schema::game_attachments;
+use super::schema::game_changes;
+use super::schema::game_kinds;
+use super::schema::game_versions;
 use super::schema::games;
+use super::schema::github_dead_letters;
+use super::schema::github_deliveries;
+use super::schema::github_repos;
 use super::schema::invites;
+use super::schema::login_sessions;
 use super::schema::messages;
+use super::schema::notifications;
+use super::schema::oauth_accounts;
+use super::schema::outbox_events;
+use super::schema::persisted_queries;
+use super::schema::play_sessions;
 use super::schema::playing;
 use super::schema::records;
+use super::schema::reports;
+use super::schema::retention_policies;
+use super::schema::revoked_tokens;
+use super::schema::room_links;
 use super::schema::rooms;
+use super::schema::save_states;
+use super::schema::scores;
+use super::schema::secrets;
+use super::schema::state_upload_chunks;
+use super::schema::state_uploads;
+use super::schema::tenants;
+use super::schema::usage_counters;
+use super::schema::user_achievements;
 use super::schema::users;
+use super::schema::webhook_deliveries;
+use super::schema::webhooks;
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use serde_json::value::Value;
 
 #[derive(Queryable)]
@@ -19,6 +54,7 @@ pub struct Record {
     pub last_play_start_at: NaiveDateTime,
     pub last_play_end_at: Option<NaiveDateTime>,
     pub play_total: i64,
+    pub last_heartbeat_at: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -29,6 +65,47 @@ pub struct NewRecord {
     pub last_play_start_at: NaiveDateTime,
     pub last_play_end_at: Option<NaiveDateTime>,
     pub play_total: i64,
+    pub last_heartbeat_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable)]
+pub struct PlaySession {
+    pub id: i32,
+    pub user_id: i32,
+    pub game_id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+    pub duration: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "play_sessions"]
+pub struct NewPlaySession {
+    pub user_id: i32,
+    pub game_id: i32,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+    pub duration: i64,
+}
+
+#[derive(Queryable)]
+pub struct AuditLog {
+    pub id: i32,
+    pub actor_id: i32,
+    pub user_id: i32,
+    pub action: String,
+    pub detail: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "audit_logs"]
+pub struct NewAuditLog<'a> {
+    pub actor_id: i32,
+    pub user_id: i32,
+    pub action: &'a str,
+    pub detail: &'a str,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Queryable)]
@@ -40,6 +117,7 @@ pub struct Comment {
     pub deleted_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub rating: Option<i16>,
 }
 
 #[derive(Insertable)]
@@ -52,6 +130,7 @@ pub struct NewComment<'a> {
     pub deleted_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub rating: Option<i16>,
 }
 
 #[derive(Queryable)]
@@ -69,6 +148,19 @@ pub struct Game {
     pub series: Option<String>,
     pub kind: Option<String>,
     pub max_player: Option<i32>,
+    pub deprecated_at: Option<NaiveDateTime>,
+    pub deprecation_reason: Option<String>,
+    pub rom_hash: Option<String>,
+    pub rom_cached_at: Option<NaiveDateTime>,
+    pub issue_number: Option<i32>,
+    pub issue_url: Option<String>,
+    pub score_ceiling: Option<i64>,
+    pub trial_allowed: bool,
+    pub description_html: String,
+    pub source: String,
+    pub upstream_id: Option<i32>,
+    pub play_modes: Vec<String>,
+    pub mature: bool,
 }
 
 #[derive(Insertable)]
@@ -86,6 +178,15 @@ pub struct NewGame<'a> {
     pub series: Option<String>,
     pub kind: Option<String>,
     pub max_player: Option<i32>,
+    pub deprecated_at: Option<NaiveDateTime>,
+    pub deprecation_reason: Option<&'a str>,
+    pub rom_hash: Option<&'a str>,
+    pub issue_number: Option<i32>,
+    pub issue_url: Option<&'a str>,
+    pub score_ceiling: Option<i64>,
+    pub trial_allowed: bool,
+    pub description_html: &'a str,
+    pub play_modes: Vec<String>,
 }
 
 #[derive(Queryable)]
@@ -98,6 +199,13 @@ pub struct User {
     pub deleted_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub role: String,
+    pub tenant_id: Option<i32>,
+    pub session_policy: String,
+    pub birthdate: Option<NaiveDate>,
+    pub avatar: Option<String>,
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub anonymized_at: Option<NaiveDateTime>,
 }
 
 #[derive(Insertable)]
@@ -110,6 +218,8 @@ pub struct NewUser<'a> {
     pub deleted_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub tenant_id: Option<i32>,
+    pub birthdate: Option<NaiveDate>,
 }
 
 #[derive(Queryable)]
@@ -159,6 +269,13 @@ pub struct Room {
     pub deleted_at: Option<NaiveDateTime>,
     pub host: i32,
     pub screenshot: Option<String>,
+    pub relayed: bool,
+    pub tenant_id: Option<i32>,
+    pub allow_commands: bool,
+    pub password: Option<String>,
+    pub spectator_policy: String,
+    pub mode: String,
+    pub event_seq: i64,
 }
 
 #[derive(Insertable)]
@@ -170,6 +287,66 @@ pub struct NewRoom {
     pub updated_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
     pub host: i32,
+    pub tenant_id: Option<i32>,
+    pub allow_commands: bool,
+    pub password: Option<String>,
+    pub mode: String,
+}
+
+#[derive(Queryable)]
+pub struct Score {
+    pub id: i32,
+    pub game_id: i32,
+    pub user_id: i32,
+    pub score: i64,
+    pub proof_url: Option<String>,
+    pub flagged: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "scores"]
+pub struct NewScore<'a> {
+    pub game_id: i32,
+    pub user_id: i32,
+    pub score: i64,
+    pub proof_url: Option<&'a str>,
+    pub flagged: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Secret {
+    pub id: i32,
+    pub kid: String,
+    pub sealed: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "secrets"]
+pub struct NewSecret<'a> {
+    pub kid: &'a str,
+    pub sealed: &'a str,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Tenant {
+    pub id: i32,
+    pub slug: String,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "tenants"]
+pub struct NewTenant<'a> {
+    pub slug: &'a str,
+    pub name: &'a str,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Queryable)]
@@ -179,6 +356,8 @@ pub struct Friend {
     pub created_at: NaiveDateTime,
     pub status: String,
     pub last_read_at: NaiveDateTime,
+    pub nickname: Option<String>,
+    pub note: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -195,6 +374,7 @@ pub struct Playing {
     pub user_id: i32,
     pub room_id: i32,
     pub created_at: NaiveDateTime,
+    pub moderator: bool,
 }
 
 #[derive(Insertable)]
@@ -226,3 +406,531 @@ pub struct NewInvite {
     pub deleted_at: Option<NaiveDateTime>,
     pub updated_at: NaiveDateTime,
 }
+
+#[derive(Queryable)]
+pub struct LoginSession {
+    pub id: String,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub revoke_reason: Option<String>,
+    pub refresh_hash: Option<String>,
+    pub previous_refresh_hash: Option<String>,
+    pub refreshed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "login_sessions"]
+pub struct NewLoginSession<'a> {
+    pub id: &'a str,
+    pub user_id: i32,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub refresh_hash: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct Notification {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: String,
+    pub payload: Value,
+    pub read_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "notifications"]
+pub struct NewNotification<'a> {
+    pub user_id: i32,
+    pub kind: &'a str,
+    pub payload: &'a Value,
+    pub read_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct OauthAccount {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "oauth_accounts"]
+pub struct NewOauthAccount<'a> {
+    pub user_id: i32,
+    pub provider: &'a str,
+    pub subject: &'a str,
+    pub email: Option<&'a str>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct OutboxRecord {
+    pub id: i32,
+    pub kind: String,
+    pub audience: String,
+    pub payload: Value,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "outbox_events"]
+pub struct NewOutboxRecord<'a> {
+    pub kind: &'a str,
+    pub audience: &'a str,
+    pub payload: &'a Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "save_states"]
+pub struct NewSaveState<'a> {
+    pub user_id: i32,
+    pub game_id: i32,
+    pub slot: i32,
+    pub data: Option<&'a [u8]>,
+    pub size: i32,
+    pub checksum: &'a str,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub core_version: Option<i32>,
+    pub data_key: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct StateUpload {
+    pub id: String,
+    pub user_id: i32,
+    pub game_id: i32,
+    pub slot: i32,
+    pub total_size: i32,
+    pub checksum: String,
+    pub core_version: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "state_uploads"]
+pub struct NewStateUpload<'a> {
+    pub id: &'a str,
+    pub user_id: i32,
+    pub game_id: i32,
+    pub slot: i32,
+    pub total_size: i32,
+    pub checksum: &'a str,
+    pub core_version: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "state_upload_chunks"]
+pub struct NewStateUploadChunk<'a> {
+    pub upload_id: &'a str,
+    pub start: i32,
+    pub data: &'a [u8],
+}
+
+#[derive(Insertable)]
+#[table_name = "persisted_queries"]
+pub struct NewPersistedQuery<'a> {
+    pub hash: &'a str,
+    pub query: &'a str,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct ClientVersion {
+    pub name: String,
+    pub minimum: String,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "client_versions"]
+pub struct NewClientVersion<'a> {
+    pub name: &'a str,
+    pub minimum: &'a str,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct CoreVersion {
+    pub platform: String,
+    pub minimum: i32,
+    pub latest: i32,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "core_versions"]
+pub struct NewCoreVersion<'a> {
+    pub platform: &'a str,
+    pub minimum: i32,
+    pub latest: i32,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct RetentionPolicy {
+    pub category: String,
+    pub days: i32,
+    pub anonymize: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "retention_policies"]
+pub struct NewRetentionPolicy<'a> {
+    pub category: &'a str,
+    pub days: i32,
+    pub anonymize: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub user_id: i32,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "revoked_tokens"]
+pub struct NewRevokedToken<'a> {
+    pub jti: &'a str,
+    pub user_id: i32,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub fail_count: i32,
+    pub failing: bool,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "webhooks"]
+pub struct NewWebhook<'a> {
+    pub url: &'a str,
+    pub secret: &'a str,
+    pub events: Vec<String>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "webhook_deliveries"]
+pub struct NewWebhookDelivery<'a> {
+    pub webhook_id: i32,
+    pub event: &'a str,
+    pub payload: &'a Value,
+    pub status: &'a str,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Report {
+    pub id: i32,
+    pub user_id: i32,
+    pub game_id: Option<i32>,
+    pub description: String,
+    pub created_at: NaiveDateTime,
+    pub kind: String,
+    pub target_id: Option<i32>,
+    pub reason: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "reports"]
+pub struct NewReport<'a> {
+    pub user_id: i32,
+    pub game_id: Option<i32>,
+    pub description: &'a str,
+    pub created_at: NaiveDateTime,
+    pub kind: &'a str,
+    pub target_id: Option<i32>,
+    pub reason: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct GameAttachment {
+    pub id: i32,
+    pub game_id: i32,
+    pub label: String,
+    pub url: String,
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+    pub available: bool,
+    pub cached_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub kind: String,
+    pub track_count: Option<i32>,
+    pub music_title: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "game_attachments"]
+pub struct NewGameAttachment<'a> {
+    pub game_id: i32,
+    pub label: &'a str,
+    pub url: &'a str,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub kind: String,
+}
+
+#[derive(Queryable)]
+pub struct GameChange {
+    pub version: i32,
+    pub game_id: i32,
+    pub kind: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "game_changes"]
+pub struct NewGameChange<'a> {
+    pub game_id: i32,
+    pub kind: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct GameVersion {
+    pub id: i32,
+    pub game_id: i32,
+    pub kind: String,
+    pub source: String,
+    pub snapshot: Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "game_versions"]
+pub struct NewGameVersion<'a> {
+    pub game_id: i32,
+    pub kind: &'a str,
+    pub source: &'a str,
+    pub snapshot: &'a Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct GameKind {
+    pub game_id: i32,
+    pub kind: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "game_kinds"]
+pub struct NewGameKind<'a> {
+    pub game_id: i32,
+    pub kind: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name = "github_dead_letters"]
+pub struct NewGithubDeadLetter<'a> {
+    pub issue_number: i32,
+    pub action: &'a str,
+    pub payload: &'a Value,
+    pub error: &'a str,
+    pub attempts: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "github_repos"]
+pub struct NewGithubRepo<'a> {
+    pub full_name: &'a str,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct GithubDelivery {
+    pub id: i32,
+    pub event: String,
+    pub action: String,
+    pub repository: String,
+    pub affected_rows: i32,
+    pub detail: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "github_deliveries"]
+pub struct NewGithubDelivery<'a> {
+    pub event: &'a str,
+    pub action: &'a str,
+    pub repository: &'a str,
+    pub affected_rows: i32,
+    pub detail: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "usage_counters"]
+pub struct NewUsageCounter {
+    pub user_id: i32,
+    pub hour: NaiveDateTime,
+    pub requests: i32,
+    pub mutations: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "federation_syncs"]
+pub struct NewFederationSync<'a> {
+    pub upstream_url: &'a str,
+    pub version: i32,
+    pub synced_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub rollout: i32,
+    pub allow_users: Vec<i32>,
+    pub deny_users: Vec<i32>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "feature_flags"]
+pub struct NewFeatureFlag<'a> {
+    pub name: &'a str,
+    pub rollout: i32,
+    pub allow_users: &'a [i32],
+    pub deny_users: &'a [i32],
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Clone)]
+pub struct RoomLink {
+    pub id: i32,
+    pub code: String,
+    pub room_id: i32,
+    pub created_by: i32,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "room_links"]
+pub struct NewRoomLink<'a> {
+    pub code: &'a str,
+    pub room_id: i32,
+    pub created_by: i32,
+    pub max_uses: Option<i32>,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Collection {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub public: bool,
+    pub removed_count: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "collections"]
+pub struct NewCollection<'a> {
+    pub user_id: i32,
+    pub name: &'a str,
+    pub public: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct CollectionGame {
+    pub collection_id: i32,
+    pub game_id: i32,
+    pub position: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "collection_games"]
+pub struct NewCollectionGame {
+    pub collection_id: i32,
+    pub game_id: i32,
+    pub position: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Achievement {
+    pub id: i32,
+    pub game_id: i32,
+    pub key: String,
+    pub name: String,
+    pub description: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "achievements"]
+pub struct NewAchievement<'a> {
+    pub game_id: i32,
+    pub key: &'a str,
+    pub name: &'a str,
+    pub description: &'a str,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "user_achievements"]
+pub struct NewUserAchievement {
+    pub user_id: i32,
+    pub achievement_id: i32,
+    pub unlocked_at: NaiveDateTime,
+}
@@ -1,17 +1,124 @@
+use actix_web::web;
 use diesel::pg::PgConnection;
-use diesel::r2d2::{self, ConnectionManager};
+use diesel::r2d2::{self, ConnectionManager, PooledConnection};
 use std::env;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::profiling::{add_query, current_stats, QueryStats};
 
 type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+/// Attributes checkouts to the sampled request running on the task
+pub struct SampledPool(Pool);
+
+pub struct DbConnection {
+    conn: PooledConnection<ConnectionManager<PgConnection>>,
+    // stats of the request and when the checkout started
+    sample: Option<(Arc<Mutex<QueryStats>>, Instant)>,
+}
+
+impl SampledPool {
+    pub fn get(&self) -> Result<DbConnection, r2d2::PoolError> {
+        self.checkout(current_stats())
+    }
+
+    fn checkout(
+        &self,
+        stats: Option<Arc<Mutex<QueryStats>>>,
+    ) -> Result<DbConnection, r2d2::PoolError> {
+        let sample = stats.map(|stats| (stats, Instant::now()));
+        Ok(DbConnection {
+            conn: self.0.get()?,
+            sample,
+        })
+    }
+
+    /// Run `f` with a connection on the blocking thread pool, so the awaiting
+    /// worker keeps serving other requests meanwhile
+    pub async fn run<F, T, E>(&'static self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&PgConnection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<String> + Send + 'static,
+    {
+        // the sample is task local, it doesn't follow onto the blocking thread
+        let stats = current_stats();
+        web::block(move || {
+            let conn = self.checkout(stats).map_err(|err| err.to_string())?;
+            f(&conn)
+        })
+        .await
+        .map_err(|err| E::from(err.to_string()))?
+    }
+}
+
+impl Deref for DbConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.conn
+    }
+}
+
+impl Drop for DbConnection {
+    fn drop(&mut self) {
+        if let Some((stats, checkout)) = &self.sample {
+            add_query(stats, *checkout);
+        }
+    }
+}
+
 pub fn get_db_pool() -> Pool {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let manager = ConnectionManager::<PgConnection>::new(database_url);
+    // resolvers now check out concurrently from the blocking threads
+    let pool_size = env::var("DATABASE_POOL_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(10);
     r2d2::Pool::builder()
+        .max_size(pool_size)
         .build(manager)
         .expect("could not build connection pool")
 }
 
 lazy_static! {
-    pub static ref DB_POOL: Pool = get_db_pool();
+    pub static ref DB_POOL: SampledPool = SampledPool(get_db_pool());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::test_database_url;
+    use crate::db::root::*;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn pool_exhaustion() {
+        let url = test_database_url();
+        let timeout = Duration::from_millis(300);
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(timeout)
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .unwrap();
+        let pool: &'static SampledPool = Box::leak(Box::new(SampledPool(pool)));
+        let held = pool.get().unwrap();
+
+        // The checkout waits on a blocking thread, the worker keeps going
+        let started = Instant::now();
+        let (result, ticked) = tokio::join!(pool.run(|_| Ok::<_, String>(())), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            started.elapsed()
+        });
+        assert!(ticked < timeout);
+        // A timed out checkout fails the call instead of panicking
+        assert!(result.is_err());
+
+        drop(held);
+        assert_eq!(pool.run(|_| Ok::<_, String>(1)).await, Ok(1));
+    }
 }
@@ -1,3 +1,55 @@
+table! {
+    achievements (id) {
+        id -> Int4,
+        game_id -> Int4,
+        key -> Varchar,
+        name -> Varchar,
+        description -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    audit_logs (id) {
+        id -> Int4,
+        actor_id -> Int4,
+        user_id -> Int4,
+        action -> Varchar,
+        detail -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    client_versions (name) {
+        name -> Varchar,
+        minimum -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    collection_games (collection_id, game_id) {
+        collection_id -> Int4,
+        game_id -> Int4,
+        position -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    collections (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        public -> Bool,
+        removed_count -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     comments (user_id, game_id) {
         user_id -> Int4,
@@ -7,6 +59,16 @@ table! {
         deleted_at -> Nullable<Timestamp>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        rating -> Nullable<Int2>,
+    }
+}
+
+table! {
+    core_versions (platform) {
+        platform -> Varchar,
+        minimum -> Int4,
+        latest -> Int4,
+        updated_at -> Timestamp,
     }
 }
 
@@ -18,6 +80,25 @@ table! {
     }
 }
 
+table! {
+    feature_flags (name) {
+        name -> Varchar,
+        rollout -> Int4,
+        allow_users -> Array<Int4>,
+        deny_users -> Array<Int4>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    federation_syncs (upstream_url) {
+        upstream_url -> Varchar,
+        version -> Int4,
+        synced_at -> Timestamp,
+    }
+}
+
 table! {
     friends (user_id, target_id) {
         user_id -> Int4,
@@ -25,6 +106,53 @@ table! {
         created_at -> Timestamp,
         status -> Varchar,
         last_read_at -> Timestamp,
+        nickname -> Nullable<Varchar>,
+        note -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    game_attachments (id) {
+        id -> Int4,
+        game_id -> Int4,
+        label -> Varchar,
+        url -> Text,
+        size -> Nullable<Int8>,
+        content_type -> Nullable<Varchar>,
+        available -> Bool,
+        cached_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        kind -> Varchar,
+        track_count -> Nullable<Int4>,
+        music_title -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    game_changes (version) {
+        version -> Int4,
+        game_id -> Int4,
+        kind -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    game_kinds (game_id, kind) {
+        game_id -> Int4,
+        kind -> Varchar,
+    }
+}
+
+table! {
+    game_versions (id) {
+        id -> Int4,
+        game_id -> Int4,
+        kind -> Varchar,
+        source -> Varchar,
+        snapshot -> Json,
+        created_at -> Timestamp,
     }
 }
 
@@ -43,6 +171,51 @@ table! {
         series -> Nullable<Varchar>,
         kind -> Nullable<Varchar>,
         max_player -> Nullable<Int4>,
+        deprecated_at -> Nullable<Timestamp>,
+        deprecation_reason -> Nullable<Text>,
+        rom_hash -> Nullable<Varchar>,
+        rom_cached_at -> Nullable<Timestamp>,
+        issue_number -> Nullable<Int4>,
+        issue_url -> Nullable<Varchar>,
+        score_ceiling -> Nullable<Int8>,
+        trial_allowed -> Bool,
+        description_html -> Text,
+        source -> Varchar,
+        upstream_id -> Nullable<Int4>,
+        play_modes -> Array<Varchar>,
+        mature -> Bool,
+    }
+}
+
+table! {
+    github_dead_letters (id) {
+        id -> Int4,
+        issue_number -> Int4,
+        action -> Varchar,
+        payload -> Json,
+        error -> Text,
+        attempts -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    github_deliveries (id) {
+        id -> Int4,
+        event -> Varchar,
+        action -> Varchar,
+        repository -> Varchar,
+        affected_rows -> Int4,
+        detail -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    github_repos (full_name) {
+        full_name -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -58,6 +231,31 @@ table! {
     }
 }
 
+table! {
+    login_sessions (id) {
+        id -> Varchar,
+        user_id -> Int4,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        revoke_reason -> Nullable<Varchar>,
+        refresh_hash -> Nullable<Varchar>,
+        previous_refresh_hash -> Nullable<Varchar>,
+        refreshed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    message_archives (id) {
+        id -> Int4,
+        body -> Text,
+        target_id -> Int4,
+        user_id -> Int4,
+        created_at -> Timestamp,
+        archived_at -> Timestamp,
+    }
+}
+
 table! {
     messages (id) {
         id -> Int4,
@@ -70,11 +268,65 @@ table! {
     }
 }
 
+table! {
+    notifications (id) {
+        id -> Int4,
+        user_id -> Int4,
+        kind -> Varchar,
+        payload -> Json,
+        read_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    oauth_accounts (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    outbox_events (id) {
+        id -> Int4,
+        kind -> Varchar,
+        audience -> Varchar,
+        payload -> Json,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    persisted_queries (hash) {
+        hash -> Bpchar,
+        query -> Text,
+        created_at -> Timestamp,
+        last_used_at -> Timestamp,
+    }
+}
+
+table! {
+    play_sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        game_id -> Int4,
+        started_at -> Timestamp,
+        ended_at -> Timestamp,
+        duration -> Int8,
+    }
+}
+
 table! {
     playing (user_id, room_id) {
         user_id -> Int4,
         room_id -> Int4,
         created_at -> Timestamp,
+        moderator -> Bool,
     }
 }
 
@@ -85,6 +337,52 @@ table! {
         last_play_start_at -> Timestamp,
         last_play_end_at -> Nullable<Timestamp>,
         play_total -> Int8,
+        last_heartbeat_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    reports (id) {
+        id -> Int4,
+        user_id -> Int4,
+        game_id -> Nullable<Int4>,
+        description -> Text,
+        created_at -> Timestamp,
+        kind -> Varchar,
+        target_id -> Nullable<Int4>,
+        reason -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    retention_policies (category) {
+        category -> Varchar,
+        days -> Int4,
+        anonymize -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    revoked_tokens (jti) {
+        jti -> Varchar,
+        user_id -> Int4,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    room_links (id) {
+        id -> Int4,
+        code -> Varchar,
+        room_id -> Int4,
+        created_by -> Int4,
+        max_uses -> Nullable<Int4>,
+        uses -> Int4,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
 
@@ -98,6 +396,104 @@ table! {
         deleted_at -> Nullable<Timestamp>,
         host -> Int4,
         screenshot -> Nullable<Text>,
+        relayed -> Bool,
+        tenant_id -> Nullable<Int4>,
+        allow_commands -> Bool,
+        password -> Nullable<Varchar>,
+        spectator_policy -> Varchar,
+        mode -> Varchar,
+        event_seq -> Int8,
+    }
+}
+
+table! {
+    save_states (user_id, game_id, slot) {
+        user_id -> Int4,
+        game_id -> Int4,
+        slot -> Int4,
+        data -> Nullable<Bytea>,
+        size -> Int4,
+        checksum -> Bpchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        core_version -> Nullable<Int4>,
+        data_key -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    scores (id) {
+        id -> Int4,
+        game_id -> Int4,
+        user_id -> Int4,
+        score -> Int8,
+        proof_url -> Nullable<Varchar>,
+        flagged -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    secrets (id) {
+        id -> Int4,
+        kid -> Varchar,
+        sealed -> Text,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    state_upload_chunks (upload_id, start) {
+        upload_id -> Bpchar,
+        start -> Int4,
+        data -> Bytea,
+    }
+}
+
+table! {
+    state_uploads (id) {
+        id -> Bpchar,
+        user_id -> Int4,
+        game_id -> Int4,
+        slot -> Int4,
+        total_size -> Int4,
+        checksum -> Bpchar,
+        core_version -> Nullable<Int4>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    storage_deletions (key) {
+        key -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    tenants (id) {
+        id -> Int4,
+        slug -> Varchar,
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    usage_counters (user_id, hour) {
+        user_id -> Int4,
+        hour -> Timestamp,
+        requests -> Int4,
+        mutations -> Int4,
+    }
+}
+
+table! {
+    user_achievements (user_id, achievement_id) {
+        user_id -> Int4,
+        achievement_id -> Int4,
+        unlocked_at -> Timestamp,
     }
 }
 
@@ -111,21 +507,135 @@ table! {
         deleted_at -> Nullable<Timestamp>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        role -> Varchar,
+        tenant_id -> Nullable<Int4>,
+        session_policy -> Varchar,
+        birthdate -> Nullable<Date>,
+        avatar -> Nullable<Varchar>,
+        last_seen_at -> Nullable<Timestamp>,
+        anonymized_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Json,
+        status -> Varchar,
+        attempts -> Int4,
+        response_status -> Nullable<Int4>,
+        error -> Nullable<Text>,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Array<Varchar>,
+        fail_count -> Int4,
+        failing -> Bool,
+        deleted_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
+joinable!(achievements -> games (game_id));
+joinable!(collection_games -> collections (collection_id));
+joinable!(collection_games -> games (game_id));
+joinable!(collections -> users (user_id));
 joinable!(comments -> games (game_id));
 joinable!(comments -> users (user_id));
 joinable!(favorites -> games (game_id));
 joinable!(favorites -> users (user_id));
+joinable!(game_attachments -> games (game_id));
+joinable!(game_changes -> games (game_id));
+joinable!(game_kinds -> games (game_id));
+joinable!(game_versions -> games (game_id));
 joinable!(invites -> rooms (room_id));
+joinable!(login_sessions -> users (user_id));
+joinable!(notifications -> users (user_id));
+joinable!(oauth_accounts -> users (user_id));
+joinable!(play_sessions -> games (game_id));
+joinable!(play_sessions -> users (user_id));
 joinable!(playing -> rooms (room_id));
 joinable!(playing -> users (user_id));
 joinable!(records -> games (game_id));
 joinable!(records -> users (user_id));
+joinable!(reports -> games (game_id));
+joinable!(reports -> users (user_id));
+joinable!(revoked_tokens -> users (user_id));
+joinable!(room_links -> rooms (room_id));
+joinable!(room_links -> users (created_by));
 joinable!(rooms -> games (game_id));
+joinable!(rooms -> tenants (tenant_id));
 joinable!(rooms -> users (host));
+joinable!(save_states -> games (game_id));
+joinable!(save_states -> users (user_id));
+joinable!(scores -> games (game_id));
+joinable!(scores -> users (user_id));
+joinable!(state_upload_chunks -> state_uploads (upload_id));
+joinable!(state_uploads -> games (game_id));
+joinable!(state_uploads -> users (user_id));
+joinable!(usage_counters -> users (user_id));
+joinable!(user_achievements -> achievements (achievement_id));
+joinable!(user_achievements -> users (user_id));
+joinable!(users -> tenants (tenant_id));
+joinable!(webhook_deliveries -> webhooks (webhook_id));
 
 allow_tables_to_appear_in_same_query!(
-    comments, favorites, friends, games, invites, messages, playing, records, rooms, users,
+    achievements,
+    audit_logs,
+    client_versions,
+    collection_games,
+    collections,
+    comments,
+    core_versions,
+    favorites,
+    feature_flags,
+    federation_syncs,
+    friends,
+    game_attachments,
+    game_changes,
+    game_kinds,
+    game_versions,
+    games,
+    github_dead_letters,
+    github_deliveries,
+    github_repos,
+    invites,
+    login_sessions,
+    message_archives,
+    messages,
+    notifications,
+    oauth_accounts,
+    outbox_events,
+    persisted_queries,
+    play_sessions,
+    playing,
+    records,
+    reports,
+    retention_policies,
+    revoked_tokens,
+    room_links,
+    rooms,
+    save_states,
+    scores,
+    secrets,
+    state_upload_chunks,
+    state_uploads,
+    storage_deletions,
+    tenants,
+    usage_counters,
+    user_achievements,
+    users,
+    webhook_deliveries,
+    webhooks,
 );
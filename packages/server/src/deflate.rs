@@ -0,0 +1,520 @@
+use actix_web::error::PayloadError;
+use actix_web::web::{self, Bytes};
+use async_stream::stream;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{Stream, StreamExt};
+use std::io;
+
+use crate::config::SubscriptionConfig;
+
+// Sync flush trailer, dropped from every message (RFC 7692 7.2.1)
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const RSV1: u8 = 0x40;
+// Inflated client messages, the graphql-ws messages of a client are small
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Agreed `permessage-deflate` parameters of a socket
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeflateParams {
+    pub window_bits: u8,
+    pub no_context_takeover: bool,
+}
+
+/// First acceptable offer of `Sec-WebSocket-Extensions` and the answer header,
+/// `None` keeps the socket uncompressed
+pub fn negotiate(offers: &str, config: &SubscriptionConfig) -> Option<(DeflateParams, String)> {
+    if !config.deflate {
+        return None;
+    }
+    offers.split(',').find_map(|offer| {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next()? != "permessage-deflate" {
+            return None;
+        }
+        let mut params = DeflateParams {
+            window_bits: config.deflate_window_bits,
+            no_context_takeover: config.deflate_no_context_takeover,
+        };
+        let mut limited = false;
+        for part in parts {
+            let (name, value) = match part.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (part, None),
+            };
+            let bits = || value.and_then(|value| value.parse::<u8>().ok());
+            match name {
+                "server_no_context_takeover" => params.no_context_takeover = true,
+                // Zlib can't compress raw deflate with 8 bits, decline
+                "server_max_window_bits" => {
+                    let bits = bits().filter(|bits| (9..=15).contains(bits))?;
+                    params.window_bits = params.window_bits.min(bits);
+                    limited = true;
+                }
+                // Inflating with the full window accepts whatever the client picks
+                "client_no_context_takeover" => (),
+                "client_max_window_bits" if value.is_none() => (),
+                "client_max_window_bits" => {
+                    bits().filter(|bits| (8..=15).contains(bits))?;
+                }
+                _ => return None,
+            }
+        }
+        let mut answer = vec!["permessage-deflate".to_owned()];
+        if params.no_context_takeover {
+            answer.push("server_no_context_takeover".to_owned());
+        }
+        if limited {
+            answer.push(format!("server_max_window_bits={}", params.window_bits));
+        }
+        Some((params, answer.join("; ")))
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    // fin, rsv and opcode bits
+    first: u8,
+    mask: Option<[u8; 4]>,
+    // including the mask key
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    fn fin(&self) -> bool {
+        self.first & 0x80 != 0
+    }
+
+    fn rsv1(&self) -> bool {
+        self.first & RSV1 != 0
+    }
+
+    fn opcode(&self) -> u8 {
+        self.first & 0x0f
+    }
+
+    // text or binary, the first frame of a message
+    fn starts_message(&self) -> bool {
+        self.opcode() == 1 || self.opcode() == 2
+    }
+}
+
+fn parse_header(buf: &[u8]) -> Option<FrameHeader> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let (payload_len, mut header_len) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len) as usize, 10)
+        }
+        126 | 127 => return None,
+        len => (len as usize, 2),
+    };
+    let mask = if buf[1] & 0x80 != 0 {
+        let key = buf.get(header_len..header_len + 4)?;
+        header_len += 4;
+        Some([key[0], key[1], key[2], key[3]])
+    } else {
+        None
+    };
+    Some(FrameHeader {
+        first: buf[0],
+        mask,
+        header_len,
+        payload_len,
+    })
+}
+
+/// Complete frames with their raw bytes, a partial frame stays in `buffer`
+fn take_frames(buffer: &mut Vec<u8>) -> Vec<(FrameHeader, Vec<u8>)> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = parse_header(&buffer[offset..]) {
+        let end = offset
+            .saturating_add(header.header_len)
+            .saturating_add(header.payload_len);
+        if buffer.len() < end {
+            break;
+        }
+        frames.push((header, buffer[offset..end].to_vec()));
+        offset = end;
+    }
+    buffer.drain(..offset);
+    frames
+}
+
+fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[index % 4];
+    }
+}
+
+fn write_frame(out: &mut Vec<u8>, first: u8, mask: Option<[u8; 4]>, payload: &[u8]) {
+    out.push(first);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => out.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let start = out.len();
+    out.extend_from_slice(payload);
+    if let Some(key) = mask {
+        out.extend_from_slice(&key);
+        // Key goes before the payload
+        out[start..].rotate_right(4);
+        apply_mask(&mut out[start + 4..], key);
+    }
+}
+
+/// Compresses the server frames of a socket
+pub struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+    pending: Vec<u8>,
+}
+
+impl Deflater {
+    pub fn new(params: &DeflateParams) -> Self {
+        Deflater {
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.window_bits,
+            ),
+            no_context_takeover: params.no_context_takeover,
+            pending: Vec::new(),
+        }
+    }
+
+    fn deflate(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let read = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&payload[read..], &mut out, FlushCompress::Sync)
+                .map_err(|err| err.to_string())?;
+            // Room left means the flush completed
+            if (self.compress.total_in() - start) as usize == payload.len()
+                && out.len() < out.capacity()
+            {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(out)
+    }
+
+    /// Unfragmented text and binary messages are compressed, other frames pass
+    /// through. A frame split across chunks waits for its end
+    pub fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(chunk.len());
+        for (header, raw) in take_frames(&mut self.pending) {
+            if !(header.starts_message() && header.fin()) || header.rsv1() {
+                out.extend_from_slice(&raw);
+                continue;
+            }
+            match self.deflate(&raw[header.header_len..]) {
+                Ok(payload) => write_frame(&mut out, header.first | RSV1, None, &payload),
+                Err(err) => {
+                    log::error!("Deflate frame: {}", err);
+                    out.extend_from_slice(&raw);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Inflates the compressed client frames of a socket before actix parses them
+pub struct Inflater {
+    decompress: Decompress,
+    // the current fragmented message started with rsv1
+    compressed: bool,
+    pending: Vec<u8>,
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            compressed: false,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Inflater {
+    fn inflate(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let start = self.decompress.total_in();
+        let mut out = Vec::with_capacity(payload.len() * 2 + 64);
+        loop {
+            let read = (self.decompress.total_in() - start) as usize;
+            self.decompress
+                .decompress_vec(&payload[read..], &mut out, FlushDecompress::Sync)
+                .map_err(|err| err.to_string())?;
+            if (self.decompress.total_in() - start) as usize == payload.len()
+                && out.len() < out.capacity()
+            {
+                break;
+            }
+            if out.len() > MAX_MESSAGE_SIZE {
+                return Err(format!("message exceeds {}", MAX_MESSAGE_SIZE));
+            }
+            out.reserve(out.capacity());
+        }
+        Ok(out)
+    }
+
+    pub fn decode(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.pending.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(chunk.len());
+        for (header, raw) in take_frames(&mut self.pending) {
+            if header.starts_message() {
+                self.compressed = header.rsv1();
+            }
+            // Control frames can come between fragments
+            let data = header.opcode() <= 2;
+            if !(data && self.compressed) {
+                out.extend_from_slice(&raw);
+                continue;
+            }
+            let mut payload = raw[header.header_len..].to_vec();
+            if let Some(key) = header.mask {
+                apply_mask(&mut payload, key);
+            }
+            if header.fin() {
+                payload.extend_from_slice(&TAIL);
+                self.compressed = false;
+            }
+            let payload = self.inflate(&payload)?;
+            write_frame(&mut out, header.first & !RSV1, header.mask, &payload);
+        }
+        Ok(out)
+    }
+}
+
+/// Request body of a socket with the client frames inflated
+pub fn inflate_payload(
+    mut payload: web::Payload,
+    mut inflater: Inflater,
+) -> impl Stream<Item = Result<Bytes, PayloadError>> {
+    stream! {
+        while let Some(chunk) = payload.next().await {
+            let decoded = chunk.and_then(|chunk| {
+                inflater.decode(&chunk).map_err(|err| {
+                    PayloadError::Io(io::Error::new(io::ErrorKind::InvalidData, err))
+                })
+            });
+            match decoded {
+                Ok(data) => yield Ok(Bytes::from(data)),
+                Err(err) => {
+                    yield Err(err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::SubscriptionConfig;
+    use crate::deflate::*;
+    use serde_json::json;
+
+    fn config(deflate: bool) -> SubscriptionConfig {
+        let mut config = SubscriptionConfig::from_vars(|_| None);
+        config.deflate = deflate;
+        config
+    }
+
+    /// A browser, inflates with its own context
+    struct Client {
+        decompress: Decompress,
+        compress: Compress,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Client {
+                decompress: Decompress::new(false),
+                compress: Compress::new(Compression::default(), false),
+            }
+        }
+
+        /// (rsv1, payload) of each server frame
+        fn receive(&mut self, wire: &[u8]) -> Vec<(bool, Vec<u8>)> {
+            let mut buffer = wire.to_vec();
+            let frames = take_frames(&mut buffer);
+            assert!(buffer.is_empty());
+            frames
+                .into_iter()
+                .map(|(header, raw)| {
+                    let payload = &raw[header.header_len..];
+                    if !header.rsv1() {
+                        return (false, payload.to_vec());
+                    }
+                    let mut input = payload.to_vec();
+                    input.extend_from_slice(&TAIL);
+                    let mut out = Vec::with_capacity(1 << 20);
+                    self.decompress
+                        .decompress_vec(&input, &mut out, FlushDecompress::Sync)
+                        .unwrap();
+                    (true, out)
+                })
+                .collect()
+        }
+
+        fn send(&mut self, text: &[u8], key: [u8; 4]) -> Vec<u8> {
+            let mut payload = Vec::with_capacity(text.len() + 64);
+            self.compress
+                .compress_vec(text, &mut payload, FlushCompress::Sync)
+                .unwrap();
+            payload.truncate(payload.len() - TAIL.len());
+            let mut out = Vec::new();
+            write_frame(&mut out, 0x81 | RSV1, Some(key), &payload);
+            out
+        }
+    }
+
+    #[test]
+    fn deflate_negotiation() {
+        let enabled = config(true);
+        assert_eq!(negotiate("permessage-deflate", &config(false)), None);
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits", &enabled),
+            Some((
+                DeflateParams {
+                    window_bits: 15,
+                    no_context_takeover: false,
+                },
+                "permessage-deflate".to_owned()
+            ))
+        );
+        // The first offer asks for what zlib can't do
+        assert_eq!(
+            negotiate(
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate; server_max_window_bits=10; server_no_context_takeover",
+                &enabled
+            ),
+            Some((
+                DeflateParams {
+                    window_bits: 10,
+                    no_context_takeover: true,
+                },
+                "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+                    .to_owned()
+            ))
+        );
+        assert_eq!(negotiate("x-webkit-deflate-frame", &enabled), None);
+        assert_eq!(negotiate("permessage-deflate; unknown", &enabled), None);
+    }
+
+    #[test]
+    fn deflate_frames() {
+        // Lobby backlog, repetitive like real traffic
+        let messages: Vec<_> = (0..200)
+            .map(|index| {
+                json!({
+                    "createdAt": 1670000000000u64 + index,
+                    "userId": index % 7,
+                    "username": format!("player{}", index % 7),
+                    "text": "anyone up for contra co-op?",
+                })
+            })
+            .collect();
+        let event = json!({
+            "type": "next",
+            "id": "1",
+            "payload": { "data": { "event": { "lobbyMessages": messages } } },
+        })
+        .to_string();
+        let mut plain = Vec::new();
+        write_frame(&mut plain, 0x81, None, event.as_bytes());
+        write_frame(&mut plain, 0x89, None, b"ping");
+        write_frame(&mut plain, 0x81, None, b"{\"type\":\"ka\"}");
+
+        let (params, _) = negotiate("permessage-deflate", &config(true)).unwrap();
+        let mut deflater = Deflater::new(&params);
+        // Chunks don't line up with frames
+        let mut wire = Vec::new();
+        for chunk in plain.chunks(1000) {
+            wire.extend(deflater.encode(chunk));
+        }
+        assert!(wire.len() * 5 < plain.len());
+
+        let mut client = Client::new();
+        let received = client.receive(&wire);
+        assert_eq!(
+            received,
+            vec![
+                (true, event.as_bytes().to_vec()),
+                (false, b"ping".to_vec()),
+                (true, b"{\"type\":\"ka\"}".to_vec()),
+            ]
+        );
+
+        // Context is kept, a repeated event costs little
+        let again = deflater.encode(&plain);
+        assert!(again.len() < wire.len() / 4);
+        assert_eq!(client.receive(&again), received);
+    }
+
+    #[test]
+    fn inflate_client_frames() {
+        let key = [0x12, 0x34, 0x56, 0x78];
+        let mut client = Client::new();
+        let subscribe = br#"{"type":"subscribe","id":"1","payload":{"query":"subscription { event { lobbyMessage { text } } }"}}"#;
+        let mut wire = client.send(subscribe, key);
+        // Uncompressed messages are allowed too
+        write_frame(&mut wire, 0x81, Some(key), b"{\"type\":\"pong\"}");
+        wire.extend(client.send(subscribe, key));
+
+        let mut inflater = Inflater::default();
+        let (first, rest) = wire.split_at(7);
+        let mut decoded = inflater.decode(first).unwrap();
+        decoded.extend(inflater.decode(rest).unwrap());
+
+        let mut buffer = decoded;
+        let frames: Vec<_> = take_frames(&mut buffer)
+            .into_iter()
+            .map(|(header, raw)| {
+                assert!(!header.rsv1());
+                assert_eq!(header.mask, Some(key));
+                let mut payload = raw[header.header_len..].to_vec();
+                apply_mask(&mut payload, key);
+                payload
+            })
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                subscribe.to_vec(),
+                b"{\"type\":\"pong\"}".to_vec(),
+                subscribe.to_vec()
+            ]
+        );
+
+        let mut broken = Vec::new();
+        write_frame(&mut broken, 0x81 | RSV1, Some(key), b"\xff\xff\xff");
+        assert!(Inflater::default().decode(&broken).is_err());
+    }
+}
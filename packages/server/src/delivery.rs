@@ -0,0 +1,75 @@
+use data_encoding::HEXLOWER;
+use ring::hmac::{sign, Key, HMAC_SHA256};
+use std::time::Duration;
+
+use crate::db::root::DB_POOL;
+use crate::schemas::webhook::{get_due_deliveries, set_delivery_result};
+
+const BATCH_SIZE: i64 = 20;
+
+pub fn get_signature(secret: &str, body: &[u8]) -> String {
+    format!(
+        "sha256={}",
+        HEXLOWER.encode(sign(&Key::new(HMAC_SHA256, secret.as_bytes()), body).as_ref())
+    )
+}
+
+fn post(
+    url: &str,
+    secret: &str,
+    event: &str,
+    id: i32,
+    body: &[u8],
+) -> Result<i32, (Option<i32>, String)> {
+    let resp = attohttpc::post(url)
+        .timeout(Duration::from_secs(10))
+        .header("Content-Type", "application/json")
+        .header("X-Nesbox-Event", event)
+        .header("X-Nesbox-Delivery", id.to_string())
+        .header("X-Nesbox-Signature-256", get_signature(secret, body))
+        .bytes(body)
+        .send()
+        .map_err(|err| (None, err.to_string()))?;
+
+    let code = resp.status().as_u16() as i32;
+    if resp.is_success() {
+        Ok(code)
+    } else {
+        Err((Some(code), format!("status {}", code)))
+    }
+}
+
+/// Send due deliveries, blocking, run in background loop
+pub fn deliver_webhooks() {
+    let conn = DB_POOL.get().unwrap();
+    for (delivery, webhook) in get_due_deliveries(&conn, BATCH_SIZE) {
+        let body = delivery.payload.to_string();
+        let result = post(
+            &webhook.url,
+            &webhook.secret,
+            &delivery.event,
+            delivery.id,
+            body.as_bytes(),
+        );
+        if let Err((_, err)) = &result {
+            log::debug!("Webhook delivery {} failed: {}", delivery.id, err);
+        }
+        if let Err(err) = set_delivery_result(&conn, &delivery, result) {
+            log::error!("Save webhook delivery {}: {:?}", delivery.id, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod signature {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_hex() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            get_signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Varchar};
+use ring::hmac::{sign, verify, Key, HMAC_SHA256};
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::config::ProxyProfile;
+use crate::db::root::DB_POOL;
+use crate::github::get_token_scopes;
+use crate::storage::get_storage;
+
+// Newest directory under `migrations`, bump with every migration
+pub const LATEST_MIGRATION: &str = "20230116090000";
+
+const NUMERIC_VARS: [&str; 18] = [
+    "PORT",
+    "MAX_CONCURRENT_REQUESTS",
+    "ATTACHMENT_MAX_SIZE",
+    "DAILY_MUTATION_QUOTA",
+    "DAILY_REQUEST_QUOTA",
+    "ROM_CACHE_MAX_SIZE",
+    "IDEMPOTENCY_TTL_SECONDS",
+    "IDLE_MINUTES",
+    "MESSAGE_RETENTION_COUNT",
+    "MESSAGE_RETENTION_DAYS",
+    "NOTIFY_COALESCE_MS",
+    "REGISTER_MIN_SECONDS",
+    "ROOM_REJOIN_GRACE_SECONDS",
+    "SUBSCRIPTION_IDLE_TIMEOUT_SECONDS",
+    "SUBSCRIPTION_KEEP_ALIVE_SECONDS",
+    "TRIAL_MINUTES",
+    "TURN_CREDENTIAL_TTL_SECONDS",
+    "USAGE_FLUSH_SECONDS",
+];
+
+const S3_VARS: [&str; 4] = ["S3_ENDPOINT", "S3_BUCKET", "S3_ACCESS_KEY", "S3_SECRET_KEY"];
+
+// Either is enough to comment on game issues
+const GITHUB_SCOPES: [&str; 2] = ["repo", "public_repo"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    // Backend not configured
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let (status, detail) = match result {
+            Ok(detail) => (CheckStatus::Pass, detail),
+            Err(detail) => (CheckStatus::Fail, detail),
+        };
+        CheckResult {
+            name,
+            status,
+            detail,
+        }
+    }
+
+    fn skip(name: &'static str) -> Self {
+        CheckResult {
+            name,
+            status: CheckStatus::Skip,
+            detail: "not configured".into(),
+        }
+    }
+}
+
+/// Everything the checks touch outside the process, stubbed in tests
+pub trait Probe {
+    fn var(&self, name: &str) -> Option<String>;
+    /// Latest applied migration version
+    fn migration_version(&self) -> Result<Option<String>, String>;
+    /// `None` when no storage backend is configured
+    fn storage(&self) -> Option<Result<(), String>>;
+    /// `None` when the token doesn't report scopes, e.g. fine-grained tokens
+    fn github_scopes(&self, token: &str) -> Result<Option<Vec<String>>, String>;
+}
+
+/// Real dependencies, `pooled` reuses the server pool instead of a new connection
+pub struct LiveProbe {
+    pub pooled: bool,
+}
+
+#[derive(QueryableByName)]
+struct MigrationRow {
+    #[sql_type = "Nullable<Varchar>"]
+    version: Option<String>,
+}
+
+impl Probe for LiveProbe {
+    fn var(&self, name: &str) -> Option<String> {
+        env::var(name).ok().filter(|value| !value.is_empty())
+    }
+
+    fn migration_version(&self) -> Result<Option<String>, String> {
+        let query = |conn: &PgConnection| {
+            diesel::sql_query("SELECT MAX(version) AS version FROM __diesel_schema_migrations")
+                .get_result::<MigrationRow>(conn)
+                .map(|row| row.version)
+                .map_err(|err| err.to_string())
+        };
+        if self.pooled {
+            query(&DB_POOL.get().map_err(|err| err.to_string())?)
+        } else {
+            let url = self.var("DATABASE_URL").unwrap_or_default();
+            query(&PgConnection::establish(&url).map_err(|err| err.to_string())?)
+        }
+    }
+
+    fn storage(&self) -> Option<Result<(), String>> {
+        get_storage().map(|storage| {
+            storage
+                .exists(".doctor")
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    fn github_scopes(&self, token: &str) -> Result<Option<Vec<String>>, String> {
+        get_token_scopes(token)
+    }
+}
+
+fn check_config(probe: &dyn Probe) -> Result<String, String> {
+    let mut problems = Vec::new();
+    match probe.var("DATABASE_URL") {
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => (),
+        Some(_) => problems.push("DATABASE_URL is not a postgres url".to_owned()),
+        None => problems.push("DATABASE_URL is not set".to_owned()),
+    }
+    for name in NUMERIC_VARS {
+        if let Some(value) = probe.var(name) {
+            if value.parse::<f64>().is_err() {
+                problems.push(format!("{} is not a number", name));
+            }
+        }
+    }
+    match probe.var("STORAGE_BACKEND").as_deref() {
+        None | Some("local") => (),
+        Some("s3") => {
+            for name in S3_VARS {
+                if probe.var(name).is_none() {
+                    problems.push(format!("{} is not set", name));
+                }
+            }
+        }
+        Some(other) => problems.push(format!("unknown STORAGE_BACKEND {}", other)),
+    }
+    if let Some(profile) = probe.var("PROXY_PROFILE") {
+        if ProxyProfile::from_str(&profile).is_err() {
+            problems.push(format!("unknown PROXY_PROFILE {}", profile));
+        }
+    }
+    for proxy in probe.var("TRUSTED_PROXIES").unwrap_or_default().split(',') {
+        let proxy = proxy.trim();
+        if !proxy.is_empty() && proxy.parse::<IpAddr>().is_err() {
+            problems.push(format!("TRUSTED_PROXIES has invalid address {}", proxy));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok("ok".into())
+    } else {
+        Err(problems.join(", "))
+    }
+}
+
+/// Sign and verify, a default or empty secret makes every token forgeable
+fn check_secret(probe: &dyn Probe) -> Result<String, String> {
+    let secret = probe.var("SECRET").unwrap_or_default();
+    if secret.is_empty() || secret == "xxx" {
+        return Err("SECRET is unset or the default".into());
+    }
+    let key = Key::new(HMAC_SHA256, secret.as_bytes());
+    let tag = sign(&key, b"doctor");
+    verify(&key, b"doctor", tag.as_ref())
+        .map(|_| format!("{} byte secret", secret.len()))
+        .map_err(|_| "hmac verify failed".into())
+}
+
+fn check_database(probe: &dyn Probe) -> Result<String, String> {
+    match probe.migration_version()? {
+        Some(version) if version.as_str() >= LATEST_MIGRATION => Ok(format!("at {}", version)),
+        Some(version) => Err(format!(
+            "pending migrations, at {} expected {}",
+            version, LATEST_MIGRATION
+        )),
+        None => Err("no migrations applied".into()),
+    }
+}
+
+fn check_github(probe: &dyn Probe, token: &str) -> Result<String, String> {
+    match probe.github_scopes(token)? {
+        Some(scopes) if scopes.iter().any(|s| GITHUB_SCOPES.contains(&s.as_str())) => {
+            Ok(scopes.join(" "))
+        }
+        Some(scopes) => Err(format!(
+            "needs {} scope, has [{}]",
+            GITHUB_SCOPES.join(" or "),
+            scopes.join(" ")
+        )),
+        None => Ok("scopes not reported".into()),
+    }
+}
+
+/// Shared by `--doctor` and `/readyz`,
+/// `external` includes third party apis that readiness probes shouldn't hammer
+pub fn run_checks(probe: &dyn Probe, external: bool) -> Vec<CheckResult> {
+    let mut results = vec![
+        CheckResult::new("config", check_config(probe)),
+        CheckResult::new("secret", check_secret(probe)),
+        CheckResult::new("database", check_database(probe)),
+    ];
+    results.push(match probe.storage() {
+        Some(result) => CheckResult::new("storage", result.map(|_| "reachable".into())),
+        None => CheckResult::skip("storage"),
+    });
+    if external {
+        results.push(match probe.var("GITHUB_TOKEN") {
+            Some(token) => CheckResult::new("github", check_github(probe, &token)),
+            None => CheckResult::skip("github"),
+        });
+    }
+    results
+}
+
+pub fn is_healthy(results: &[CheckResult]) -> bool {
+    results
+        .iter()
+        .all(|result| result.status != CheckStatus::Fail)
+}
+
+pub fn render_report(results: &[CheckResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.name.len())
+        .max()
+        .unwrap_or_default();
+    results
+        .iter()
+        .map(|result| {
+            let status = match result.status {
+                CheckStatus::Pass => "PASS",
+                CheckStatus::Fail => "FAIL",
+                CheckStatus::Skip => "SKIP",
+            };
+            format!(
+                "{:width$}  {}  {}\n",
+                result.name,
+                status,
+                result.detail,
+                width = width
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doctor::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct StubProbe {
+        vars: HashMap<&'static str, &'static str>,
+        migration: Option<&'static str>,
+        storage: Option<Result<(), String>>,
+        scopes: Option<Vec<String>>,
+    }
+
+    impl Probe for StubProbe {
+        fn var(&self, name: &str) -> Option<String> {
+            self.vars.get(name).map(|value| value.to_string())
+        }
+        fn migration_version(&self) -> Result<Option<String>, String> {
+            Ok(self.migration.map(|version| version.to_owned()))
+        }
+        fn storage(&self) -> Option<Result<(), String>> {
+            self.storage.clone()
+        }
+        fn github_scopes(&self, _token: &str) -> Result<Option<Vec<String>>, String> {
+            Ok(self.scopes.clone())
+        }
+    }
+
+    fn statuses(results: &[CheckResult]) -> Vec<(&'static str, CheckStatus)> {
+        results
+            .iter()
+            .map(|result| (result.name, result.status))
+            .collect()
+    }
+
+    #[test]
+    fn doctor_report() {
+        use CheckStatus::*;
+
+        let mut probe = StubProbe {
+            vars: HashMap::from([
+                ("DATABASE_URL", "postgres://localhost/nesbox"),
+                ("SECRET", "s3cret"),
+                ("GITHUB_TOKEN", "token"),
+            ]),
+            migration: Some(LATEST_MIGRATION),
+            scopes: Some(vec!["public_repo".into()]),
+            ..Default::default()
+        };
+        let results = run_checks(&probe, true);
+        assert_eq!(
+            statuses(&results),
+            vec![
+                ("config", Pass),
+                ("secret", Pass),
+                ("database", Pass),
+                ("storage", Skip),
+                ("github", Pass),
+            ]
+        );
+        assert!(is_healthy(&results));
+        assert!(render_report(&results).contains("storage   SKIP  not configured"));
+
+        probe.vars.insert("DATABASE_URL", "mysql://localhost");
+        probe.vars.insert("PORT", "http");
+        probe.vars.insert("PROXY_PROFILE", "akamai");
+        probe.vars.insert("SECRET", "xxx");
+        probe.vars.insert("TRUSTED_PROXIES", "203.0.113.7, cdn");
+        probe.migration = Some("20221109090000");
+        probe.storage = Some(Err("403".into()));
+        probe.scopes = Some(vec!["read:user".into()]);
+        let results = run_checks(&probe, true);
+        assert!(results.iter().all(|result| result.status == Fail));
+        assert_eq!(
+            results[0].detail,
+            "DATABASE_URL is not a postgres url, PORT is not a number, unknown PROXY_PROFILE akamai, \
+             TRUSTED_PROXIES has invalid address cdn"
+        );
+        assert!(!is_healthy(&results));
+
+        // Readiness leaves third party apis out
+        assert_eq!(run_checks(&probe, false).len(), 4);
+    }
+}
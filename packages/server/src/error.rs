@@ -1,15 +1,356 @@
-use juniper::{graphql_value, Value};
+use juniper::{GraphQLEnum, Object, Value};
+use strum::{EnumIter, IntoEnumIterator};
+
+/// Stable machine readable code, clients branch on this instead of messages
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum ErrorCode {
+    Unauthorized,
+    TokenExpired,
+    PermissionDenied,
+    NotFound,
+    Conflict,
+    RateLimited,
+    // daily usage quota, `resetAt` in extensions
+    QuotaExceeded,
+    // anonymous trial used up, `resetAt` and `trialMinutes` in extensions
+    TrialExhausted,
+    // behind a feature flag the caller isn't in, `feature` in extensions
+    FeatureDisabled,
+    // client release below the minimum of its name, `minimumVersion` in extensions
+    UpgradeRequired,
+    Validation,
+    Maintenance,
+    BadRequest,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn all() -> Vec<ErrorCode> {
+        ErrorCode::iter().collect()
+    }
+
+    /// Same name as the GraphQL enum value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthorized => "UNAUTHORIZED",
+            ErrorCode::TokenExpired => "TOKEN_EXPIRED",
+            ErrorCode::PermissionDenied => "PERMISSION_DENIED",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::Conflict => "CONFLICT",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::QuotaExceeded => "QUOTA_EXCEEDED",
+            ErrorCode::TrialExhausted => "TRIAL_EXHAUSTED",
+            ErrorCode::FeatureDisabled => "FEATURE_DISABLED",
+            ErrorCode::UpgradeRequired => "UPGRADE_REQUIRED",
+            ErrorCode::Validation => "VALIDATION",
+            ErrorCode::Maintenance => "MAINTENANCE",
+            ErrorCode::BadRequest => "BAD_REQUEST",
+            ErrorCode::Internal => "INTERNAL",
+        }
+    }
+}
+
+/// Keep the numeric `code` for old clients
+fn extensions(code: i32, error_code: ErrorCode) -> Value {
+    let mut object = Object::with_capacity(2);
+    object.add_field("code", Value::scalar(code));
+    object.add_field("errorCode", Value::scalar(error_code.as_str().to_owned()));
+    Value::object(object)
+}
 
 pub struct Error;
 
 impl Error {
     pub fn register_username_exist() -> Value {
-        graphql_value!({"code": 404001})
+        extensions(404001, ErrorCode::Conflict)
     }
     pub fn username_or_password_error() -> Value {
-        graphql_value!({"code": 404002})
+        extensions(404002, ErrorCode::Unauthorized)
+    }
+    // unknown, expired, revoked or already rotated, the client logs in again
+    pub fn refresh_token_invalid() -> Value {
+        extensions(401001, ErrorCode::Unauthorized)
     }
     pub fn username_not_playing() -> Value {
-        graphql_value!({"code": 404101})
+        extensions(404101, ErrorCode::NotFound)
+    }
+    pub fn room_banned() -> Value {
+        extensions(404102, ErrorCode::PermissionDenied)
+    }
+    pub fn room_link_invalid() -> Value {
+        extensions(404103, ErrorCode::NotFound)
+    }
+    pub fn room_full() -> Value {
+        extensions(409101, ErrorCode::Conflict)
+    }
+    pub fn permission_denied() -> Value {
+        extensions(403001, ErrorCode::PermissionDenied)
+    }
+    pub fn captcha_failed() -> Value {
+        extensions(403002, ErrorCode::Validation)
+    }
+    pub fn register_honeypot() -> Value {
+        extensions(403003, ErrorCode::Validation)
+    }
+    pub fn room_commands_disabled() -> Value {
+        extensions(403004, ErrorCode::PermissionDenied)
+    }
+    pub fn room_muted() -> Value {
+        extensions(403005, ErrorCode::PermissionDenied)
+    }
+    pub fn wrong_room_password() -> Value {
+        extensions(403006, ErrorCode::PermissionDenied)
+    }
+    pub fn spectate_denied() -> Value {
+        extensions(403007, ErrorCode::PermissionDenied)
+    }
+    pub fn game_deprecated() -> Value {
+        extensions(410001, ErrorCode::NotFound)
+    }
+    pub fn room_link_expired() -> Value {
+        extensions(410002, ErrorCode::NotFound)
+    }
+    pub fn room_link_exhausted() -> Value {
+        extensions(410003, ErrorCode::NotFound)
+    }
+    pub fn room_link_revoked() -> Value {
+        extensions(410004, ErrorCode::NotFound)
+    }
+    pub fn invalid_rom_url() -> Value {
+        extensions(422001, ErrorCode::Validation)
+    }
+    pub fn unsupported_core_version() -> Value {
+        extensions(422002, ErrorCode::Validation)
+    }
+    pub fn invalid_webhook_url() -> Value {
+        extensions(422003, ErrorCode::Validation)
+    }
+    pub fn empty_report() -> Value {
+        extensions(422004, ErrorCode::Validation)
+    }
+    pub fn disposable_email() -> Value {
+        extensions(422005, ErrorCode::Validation)
+    }
+    pub fn unsupported_play_mode() -> Value {
+        extensions(422006, ErrorCode::Validation)
+    }
+    pub fn text_rejected() -> Value {
+        extensions(422007, ErrorCode::Validation)
+    }
+    pub fn collection_limit() -> Value {
+        extensions(422008, ErrorCode::Validation)
+    }
+    // deeper or larger than `MAX_QUERY_DEPTH` or `MAX_QUERY_COMPLEXITY`, the limits in extensions
+    pub fn query_too_complex(max_depth: usize, max_complexity: usize) -> Value {
+        let mut value = extensions(422009, ErrorCode::Validation);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("maxDepth", Value::scalar(max_depth as i32));
+            object.add_field("maxComplexity", Value::scalar(max_complexity as i32));
+        }
+        value
+    }
+    // hash of an automatic persisted query never stored, the client resends the query
+    pub fn persisted_query_not_found() -> Value {
+        extensions(404201, ErrorCode::NotFound)
+    }
+    pub fn register_too_fast() -> Value {
+        extensions(429001, ErrorCode::RateLimited)
+    }
+    pub fn room_command_too_fast() -> Value {
+        extensions(429002, ErrorCode::RateLimited)
+    }
+    pub fn room_join_locked() -> Value {
+        extensions(429003, ErrorCode::RateLimited)
+    }
+    pub fn report_too_fast() -> Value {
+        extensions(429006, ErrorCode::RateLimited)
+    }
+    // request rate of the user or address, `retryAfter` seconds in extensions
+    pub fn rate_limited(retry_after: i32) -> Value {
+        let mut value = extensions(429005, ErrorCode::RateLimited);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("retryAfter", Value::scalar(retry_after));
+        }
+        value
+    }
+    /// `reset_at` is milliseconds since epoch
+    pub fn quota_exceeded(reset_at: f64) -> Value {
+        let mut value = extensions(429004, ErrorCode::QuotaExceeded);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("resetAt", Value::scalar(reset_at));
+        }
+        value
+    }
+    /// Clients prompt registration, `reset_at` is milliseconds since epoch
+    pub fn trial_exhausted(reset_at: f64, trial_minutes: i32) -> Value {
+        let mut value = extensions(403008, ErrorCode::TrialExhausted);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("resetAt", Value::scalar(reset_at));
+            object.add_field("trialMinutes", Value::scalar(trial_minutes));
+            object.add_field("register", Value::scalar(true));
+        }
+        value
+    }
+    pub fn feature_disabled(feature: &str) -> Value {
+        let mut value = extensions(403009, ErrorCode::FeatureDisabled);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("feature", Value::scalar(feature.to_string()));
+        }
+        value
+    }
+    // account of a minor, `restriction` in extensions
+    pub fn restricted(restriction: &str) -> Value {
+        let mut value = extensions(403010, ErrorCode::PermissionDenied);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("restriction", Value::scalar(restriction.to_string()));
+        }
+        value
+    }
+    pub fn upgrade_required(minimum: &str) -> Value {
+        let mut value = extensions(426001, ErrorCode::UpgradeRequired);
+        if let Some(object) = value.as_mut_object_value() {
+            object.add_field("minimumVersion", Value::scalar(minimum.to_string()));
+        }
+        value
+    }
+    pub fn relay_not_configured() -> Value {
+        extensions(503001, ErrorCode::Maintenance)
+    }
+    pub fn validation() -> Value {
+        extensions(422000, ErrorCode::Validation)
+    }
+    pub fn not_found() -> Value {
+        extensions(404000, ErrorCode::NotFound)
+    }
+    pub fn conflict() -> Value {
+        extensions(409000, ErrorCode::Conflict)
+    }
+    pub fn bad_request() -> Value {
+        extensions(400000, ErrorCode::BadRequest)
+    }
+    pub fn internal() -> Value {
+        extensions(500000, ErrorCode::Internal)
+    }
+}
+
+/// Message and extensions for a resolver error that carries no code,
+/// e.g. a diesel error propagated with `?`
+fn classify_uncoded(message: &str) -> (&'static str, Value) {
+    if message == diesel::result::Error::NotFound.to_string() {
+        ("not found", Error::not_found())
+    } else if message.starts_with("duplicate key value violates unique constraint") {
+        ("already exists", Error::conflict())
+    } else {
+        log::error!("Uncoded resolver error: {}", message);
+        ("internal error", Error::internal())
+    }
+}
+
+/// Give every execution error a code and never leak raw database text,
+/// request errors (parse, validation) have no path and are left as is
+pub fn sanitize_errors(response: &mut serde_json::Value) {
+    let errors = match response
+        .get_mut("errors")
+        .and_then(|errors| errors.as_array_mut())
+    {
+        Some(errors) => errors,
+        None => return,
+    };
+
+    for error in errors.iter_mut() {
+        let coded = error
+            .pointer("/extensions/errorCode")
+            .map_or(false, |code| code.is_string());
+        if coded || error.get("path").is_none() {
+            continue;
+        }
+        let message = error
+            .get("message")
+            .and_then(|message| message.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let (message, extensions) = classify_uncoded(&message);
+        error["message"] = message.into();
+        error["extensions"] = serde_json::to_value(&extensions).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::*;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use juniper::FieldError;
+    use serde_json::json;
+
+    fn execution_error(err: FieldError, path: &str) -> serde_json::Value {
+        let mut error = json!({
+            "message": err.message(),
+            "locations": [{ "line": 1, "column": 3 }],
+            "path": [path],
+        });
+        if !err.extensions().is_null() {
+            error["extensions"] = serde_json::to_value(err.extensions()).unwrap();
+        }
+        error
+    }
+
+    #[test]
+    fn representative_failures() {
+        let unique = DieselError::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            Box::new(r#"duplicate key value violates unique constraint "Index_7""#.to_owned()),
+        );
+        let foreign_key = DieselError::DatabaseError(
+            DatabaseErrorKind::ForeignKeyViolation,
+            Box::new(
+                r#"insert or update on table "favorites" violates foreign key constraint "FK_52""#
+                    .to_owned(),
+            ),
+        );
+        let mut response = json!({
+            "data": null,
+            "errors": [
+                execution_error(DieselError::NotFound.into(), "account"),
+                execution_error(unique.into(), "register"),
+                execution_error(foreign_key.into(), "favoriteGame"),
+                execution_error(
+                    FieldError::new("admin only", Error::permission_denied()),
+                    "webhooks",
+                ),
+                { "message": "Unknown field \"foo\"", "locations": [{ "line": 1, "column": 3 }] },
+            ],
+        });
+
+        sanitize_errors(&mut response);
+
+        let errors = response["errors"].as_array().unwrap();
+        let codes: Vec<_> = errors
+            .iter()
+            .map(|error| error.pointer("/extensions/errorCode").cloned())
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                Some(json!("NOT_FOUND")),
+                Some(json!("CONFLICT")),
+                Some(json!("INTERNAL")),
+                Some(json!("PERMISSION_DENIED")),
+                None,
+            ]
+        );
+        assert_eq!(errors[3]["extensions"]["code"], json!(403001));
+        for error in errors {
+            let message = error["message"].as_str().unwrap();
+            assert!(!message.contains("constraint"), "{}", message);
+            assert!(!message.contains("favorites"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn code_names_match_schema() {
+        for code in ErrorCode::all() {
+            let value = juniper::ToInputValue::<juniper::DefaultScalarValue>::to_input_value(&code);
+            assert_eq!(value.as_enum_value(), Some(code.as_str()));
+        }
     }
 }
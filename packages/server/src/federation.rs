@@ -0,0 +1,770 @@
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, GraphQLObject};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use url::Url;
+
+use crate::db::models::NewFederationSync;
+use crate::db::root::DB_POOL;
+use crate::db::schema::{federation_syncs, games};
+use crate::outbox::{wake_outbox, write_outbox_event, OutboxEvent};
+use crate::rom::cache_rom;
+use crate::schemas::{
+    game::{
+        create_game, delete_federated_game, get_games_by_ids, set_federated, update_game,
+        ScGameSource, ScNewGame,
+    },
+    game_kind::get_game_kinds,
+    game_version::ScGameVersionSource,
+    webhook::{enqueue_webhook_event, ScWebhookEvent},
+};
+
+const GAME_FIELDS: &str = "id name description preview rom screenshots platform series kind \
+    kinds maxPlayer deprecationReason romHash issueUrl trialAllowed";
+
+lazy_static! {
+    // Guest graphql endpoint of another instance, e.g. `https://nesbox.example/guestgraphql`
+    pub static ref CATALOG_UPSTREAM_URL: Option<String> = env::var("CATALOG_UPSTREAM_URL")
+        .ok()
+        .map(|url| url.trim().to_owned())
+        .filter(|url| !url.is_empty());
+    pub static ref CATALOG_SYNC_SECONDS: u64 = env::var("CATALOG_SYNC_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(5 * 60);
+    static ref STATUS: Mutex<FederationStatus> = Mutex::new(FederationStatus::default());
+}
+
+/// Catalog fields that travel, enums in their graphql spelling until `normalize`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamGame {
+    pub id: i32,
+    pub name: String,
+    pub description: String,
+    pub preview: String,
+    pub rom: String,
+    pub screenshots: Vec<String>,
+    pub platform: Option<String>,
+    pub series: Option<String>,
+    pub kind: Option<String>,
+    pub kinds: Vec<String>,
+    pub max_player: Option<i32>,
+    pub deprecation_reason: Option<String>,
+    pub rom_hash: Option<String>,
+    pub issue_url: Option<String>,
+    pub trial_allowed: bool,
+}
+
+impl UpstreamGame {
+    /// Local spelling of enums, relative roms point at the upstream
+    fn normalize(mut self, upstream_url: &str) -> Self {
+        let snake = |value: String| value.to_lowercase();
+        self.platform = self.platform.map(snake);
+        self.series = self.series.map(snake);
+        self.kind = self.kind.map(snake);
+        self.kinds = self.kinds.into_iter().map(snake).collect();
+        if self.rom.starts_with('/') {
+            if let Ok(rom) = Url::parse(upstream_url).and_then(|url| url.join(&self.rom)) {
+                self.rom = rom.to_string();
+            }
+        }
+        self
+    }
+
+    /// Same game as far as players can tell
+    fn same_content(&self, other: &UpstreamGame) -> bool {
+        let mut kinds = self.kinds.clone();
+        let mut other_kinds = other.kinds.clone();
+        kinds.sort();
+        other_kinds.sort();
+        (
+            &self.description,
+            &self.preview,
+            &self.rom,
+            &self.screenshots,
+            &self.series,
+            &self.kind,
+            kinds,
+            self.max_player,
+            &self.deprecation_reason,
+            self.trial_allowed,
+        ) == (
+            &other.description,
+            &other.preview,
+            &other.rom,
+            &other.screenshots,
+            &other.series,
+            &other.kind,
+            other_kinds,
+            other.max_player,
+            &other.deprecation_reason,
+            other.trial_allowed,
+        )
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamDelta {
+    pub created: Vec<UpstreamGame>,
+    pub updated: Vec<UpstreamGame>,
+    pub deleted_ids: Vec<i32>,
+    pub version: i32,
+    pub full_resync: bool,
+}
+
+/// A local game with the name and platform of an upstream one, the local one is kept
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScFederationConflict {
+    pub upstream_id: i32,
+    pub local_id: i32,
+    pub name: String,
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FederationStatus {
+    // upstream catalog version applied
+    pub version: Option<i32>,
+    pub upstream_version: Option<i32>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+    pub conflicts: Vec<ScFederationConflict>,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScFederationStatus {
+    // not federated when `None`
+    upstream_url: Option<String>,
+    version: Option<i32>,
+    upstream_version: Option<i32>,
+    // upstream catalog versions not applied yet
+    drift: i32,
+    last_synced_at: Option<f64>,
+    last_error: Option<String>,
+    last_error_at: Option<f64>,
+    federated_games: i32,
+    conflicts: Vec<ScFederationConflict>,
+}
+
+pub trait Upstream {
+    fn delta(&self, since: i32) -> Result<UpstreamDelta, String>;
+    fn games(&self) -> Result<Vec<UpstreamGame>, String>;
+}
+
+/// The local catalog as the sync sees it
+pub trait FederationStore {
+    /// Local id of a federated game
+    fn federated_id(&self, upstream_id: i32) -> Option<i32>;
+    fn federated_upstream_ids(&self) -> Vec<i32>;
+    /// Local-only game with the name and platform
+    fn local_game(&self, name: &str, platform: Option<&str>) -> Option<(i32, UpstreamGame)>;
+    /// Creates when `local_id` is `None`
+    fn upsert(&mut self, local_id: Option<i32>, game: &UpstreamGame) -> Result<(), String>;
+    fn remove(&mut self, local_id: i32) -> Result<(), String>;
+}
+
+/// Applies upstream games, `full` also removes federated games missing from `games`
+pub fn apply_upstream_games(
+    store: &mut impl FederationStore,
+    games: &[UpstreamGame],
+    deleted_ids: &[i32],
+    full: bool,
+) -> Result<Vec<ScFederationConflict>, String> {
+    let mut conflicts = vec![];
+    for game in games {
+        if let Some(local_id) = store.federated_id(game.id) {
+            store.upsert(Some(local_id), game)?;
+            continue;
+        }
+        match store.local_game(&game.name, game.platform.as_deref()) {
+            // Already here, e.g. the upstream federates from this instance
+            Some((_, local)) if local.same_content(game) => (),
+            Some((local_id, _)) => conflicts.push(ScFederationConflict {
+                upstream_id: game.id,
+                local_id,
+                name: game.name.clone(),
+                platform: game.platform.clone(),
+            }),
+            None => store.upsert(None, game)?,
+        }
+    }
+
+    let mut removed: Vec<i32> = deleted_ids.to_vec();
+    if full {
+        let listed: HashSet<i32> = games.iter().map(|game| game.id).collect();
+        removed.extend(
+            store
+                .federated_upstream_ids()
+                .into_iter()
+                .filter(|upstream_id| !listed.contains(upstream_id)),
+        );
+    }
+    for upstream_id in removed {
+        if let Some(local_id) = store.federated_id(upstream_id) {
+            store.remove(local_id)?;
+        }
+    }
+    Ok(conflicts)
+}
+
+/// One round from `cursor`, returns the version reached, the full list is
+/// loaded on the first sync and when the upstream journal was pruned past it
+pub fn sync_catalog(
+    store: &mut impl FederationStore,
+    upstream: &impl Upstream,
+    cursor: Option<i32>,
+    status: &mut FederationStatus,
+) -> Result<i32, String> {
+    let delta = upstream.delta(cursor.unwrap_or_default())?;
+    status.upstream_version = Some(delta.version);
+    status.conflicts = if cursor.is_none() || delta.full_resync {
+        // Changes after `delta.version` are replayed next round
+        apply_upstream_games(store, &upstream.games()?, &[], true)?
+    } else {
+        let games: Vec<_> = delta
+            .created
+            .into_iter()
+            .chain(delta.updated.into_iter())
+            .collect();
+        let mut conflicts = apply_upstream_games(store, &games, &delta.deleted_ids, false)?;
+        // Reported until resolved, not only in the round that saw them
+        conflicts.extend(
+            status
+                .conflicts
+                .drain(..)
+                .filter(|old| {
+                    !conflicts
+                        .iter()
+                        .any(|new| new.upstream_id == old.upstream_id)
+                })
+                .filter(|old| store.federated_id(old.upstream_id).is_none())
+                .collect::<Vec<_>>(),
+        );
+        conflicts
+    };
+    status.version = Some(delta.version);
+    Ok(delta.version)
+}
+
+/// Guest graphql of the upstream instance
+pub struct HttpUpstream<'a> {
+    pub url: &'a str,
+}
+
+impl HttpUpstream<'_> {
+    fn query(&self, query: &str, variables: Value) -> Result<Value, String> {
+        let body = json!({ "query": query, "variables": variables }).to_string();
+        let resp = attohttpc::post(self.url)
+            .timeout(Duration::from_secs(30))
+            .header("Content-Type", "application/json")
+            .bytes(body.as_bytes())
+            .send()
+            .map_err(|err| err.to_string())?;
+        let status = resp.status().as_u16();
+        let bytes = resp.bytes().map_err(|err| err.to_string())?;
+        let mut body: Value =
+            serde_json::from_slice(&bytes).map_err(|err| format!("status {}: {}", status, err))?;
+        if let Some(errors) = body.get("errors") {
+            return Err(format!("status {}: {}", status, errors));
+        }
+        Ok(body["data"].take())
+    }
+}
+
+impl Upstream for HttpUpstream<'_> {
+    fn delta(&self, since: i32) -> Result<UpstreamDelta, String> {
+        let query = format!(
+            "query GamesDelta($since: Int!) {{ gamesDelta(input: {{ sinceVersion: $since }}) \
+            {{ created {{ {0} }} updated {{ {0} }} deletedIds version fullResync }} }}",
+            GAME_FIELDS
+        );
+        let mut data = self.query(&query, json!({ "since": since }))?;
+        let mut delta: UpstreamDelta =
+            serde_json::from_value(data["gamesDelta"].take()).map_err(|err| err.to_string())?;
+        delta.created = delta
+            .created
+            .into_iter()
+            .map(|game| game.normalize(self.url))
+            .collect();
+        delta.updated = delta
+            .updated
+            .into_iter()
+            .map(|game| game.normalize(self.url))
+            .collect();
+        Ok(delta)
+    }
+
+    fn games(&self) -> Result<Vec<UpstreamGame>, String> {
+        let query = format!("query Games {{ games {{ {} }} }}", GAME_FIELDS);
+        let mut data = self.query(&query, json!({}))?;
+        let games: Vec<UpstreamGame> =
+            serde_json::from_value(data["games"].take()).map_err(|err| err.to_string())?;
+        Ok(games
+            .into_iter()
+            .map(|game| game.normalize(self.url))
+            .collect())
+    }
+}
+
+fn parse_enum<T: FromStr>(value: &Option<String>) -> Option<T> {
+    value.as_deref().and_then(|value| T::from_str(value).ok())
+}
+
+fn to_sc_new_game(game: &UpstreamGame) -> ScNewGame {
+    ScNewGame {
+        name: game.name.clone(),
+        description: game.description.clone(),
+        preview: game.preview.clone(),
+        rom: game.rom.clone(),
+        screenshots: game.screenshots.clone(),
+        platform: parse_enum(&game.platform),
+        series: parse_enum(&game.series),
+        kind: parse_enum(&game.kind),
+        kinds: Some(
+            game.kinds
+                .iter()
+                .filter_map(|kind| FromStr::from_str(kind).ok())
+                .collect(),
+        ),
+        max_player: game.max_player,
+        deprecation_reason: game.deprecation_reason.clone(),
+        rom_hash: game.rom_hash.clone(),
+        issue_number: None,
+        issue_url: game.issue_url.clone(),
+        attachments: None,
+        score_ceiling: None,
+        trial_allowed: Some(game.trial_allowed),
+        // not part of the upstream query yet
+        play_modes: None,
+    }
+}
+
+pub struct PgFederationStore<'a> {
+    pub conn: &'a PgConnection,
+}
+
+impl FederationStore for PgFederationStore<'_> {
+    fn federated_id(&self, upstream: i32) -> Option<i32> {
+        use self::games::dsl::*;
+
+        games
+            .select(id)
+            .filter(deleted_at.is_null())
+            .filter(upstream_id.eq(upstream))
+            .get_result::<i32>(self.conn)
+            .ok()
+    }
+
+    fn federated_upstream_ids(&self) -> Vec<i32> {
+        use self::games::dsl::*;
+
+        games
+            .select(upstream_id)
+            .filter(deleted_at.is_null())
+            .filter(source.eq(ScGameSource::Federated.to_string()))
+            .load::<Option<i32>>(self.conn)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn local_game(
+        &self,
+        game_name: &str,
+        game_platform: Option<&str>,
+    ) -> Option<(i32, UpstreamGame)> {
+        use self::games::dsl::*;
+
+        let ids = games
+            .select(id)
+            .filter(deleted_at.is_null())
+            .filter(source.eq(ScGameSource::Local.to_string()))
+            .filter(name.eq(game_name))
+            .load::<i32>(self.conn)
+            .ok()?;
+        let game = get_games_by_ids(self.conn, ids)
+            .ok()?
+            .into_iter()
+            .map(|game| serde_json::to_value(&game).unwrap_or_default())
+            .filter_map(|value| serde_json::from_value::<UpstreamGame>(value).ok())
+            .find(|game| game.platform.as_deref() == game_platform)?;
+        let kinds = get_game_kinds(self.conn, game.id)
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect();
+        Some((game.id, UpstreamGame { kinds, ..game }))
+    }
+
+    fn upsert(&mut self, local_id: Option<i32>, game: &UpstreamGame) -> Result<(), String> {
+        let req = to_sc_new_game(game);
+        let conn = self.conn;
+        let saved = conn
+            .transaction(|| {
+                let saved = match local_id {
+                    Some(gid) => {
+                        let old_deprecated = get_games_by_ids(conn, vec![gid])?
+                            .first()
+                            .map_or(false, |old| old.deprecation_reason.is_some());
+                        let saved = update_game(conn, gid, &req, ScGameVersionSource::Sync)?;
+                        enqueue_webhook_event(conn, ScWebhookEvent::GameUpdated, &json!(saved))?;
+                        write_outbox_event(conn, &OutboxEvent::GameUpdated { game_id: gid })?;
+                        if !old_deprecated && saved.deprecation_reason.is_some() {
+                            write_outbox_event(conn, &OutboxEvent::DeprecateGame { game_id: gid })?;
+                        }
+                        saved
+                    }
+                    None => {
+                        let saved = create_game(conn, &req, ScGameVersionSource::Sync)?;
+                        set_federated(conn, saved.id, game.id)?;
+                        enqueue_webhook_event(conn, ScWebhookEvent::GameCreated, &json!(saved))?;
+                        write_outbox_event(conn, &OutboxEvent::GameCreated { game_id: saved.id })?;
+                        saved
+                    }
+                };
+                Ok::<_, FieldError>(saved)
+            })
+            .map_err(|err| format!("{}: {}", game.name, err.message()))?;
+        wake_outbox();
+        if !saved.rom_ready {
+            cache_rom(saved.id, saved.rom.clone(), req.rom_hash);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, local_id: i32) -> Result<(), String> {
+        delete_federated_game(self.conn, local_id).map_err(|err| err.to_string())
+    }
+}
+
+fn get_cursor(conn: &PgConnection, url: &str) -> QueryResult<Option<i32>> {
+    use self::federation_syncs::dsl::*;
+
+    federation_syncs
+        .select(version)
+        .filter(upstream_url.eq(url))
+        .get_result::<i32>(conn)
+        .optional()
+}
+
+fn set_cursor(conn: &PgConnection, url: &str, reached: i32) -> QueryResult<()> {
+    use self::federation_syncs::dsl::*;
+
+    let new_sync = NewFederationSync {
+        upstream_url: url,
+        version: reached,
+        synced_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(federation_syncs)
+        .values(&new_sync)
+        .on_conflict(upstream_url)
+        .do_update()
+        .set((version.eq(reached), synced_at.eq(new_sync.synced_at)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Blocking, run in the background loop when `CATALOG_UPSTREAM_URL` is set
+pub fn sync_federation() {
+    let url = match CATALOG_UPSTREAM_URL.as_deref() {
+        Some(url) => url,
+        None => return,
+    };
+    let mut status = STATUS.lock().unwrap().clone();
+    let result = DB_POOL
+        .get()
+        .map_err(|err| err.to_string())
+        .and_then(|conn| {
+            let cursor = get_cursor(&conn, url).map_err(|err| err.to_string())?;
+            let mut store = PgFederationStore { conn: &conn };
+            let reached = sync_catalog(&mut store, &HttpUpstream { url }, cursor, &mut status)?;
+            set_cursor(&conn, url, reached).map_err(|err| err.to_string())
+        });
+    match result {
+        Ok(()) => status.last_synced_at = Some(Utc::now()),
+        Err(err) => {
+            log::error!("Catalog sync from {}: {}", url, err);
+            status.last_error = Some(err);
+            status.last_error_at = Some(Utc::now());
+        }
+    }
+    for conflict in &status.conflicts {
+        log::warn!(
+            "Catalog conflict, local game {} kept over upstream {}",
+            conflict.local_id,
+            conflict.upstream_id
+        );
+    }
+    *STATUS.lock().unwrap() = status;
+}
+
+pub fn get_federation_status(conn: &PgConnection) -> QueryResult<ScFederationStatus> {
+    use self::games::dsl::*;
+
+    let federated_games = games
+        .filter(deleted_at.is_null())
+        .filter(source.eq(ScGameSource::Federated.to_string()))
+        .count()
+        .get_result::<i64>(conn)?;
+    let status = STATUS.lock().unwrap().clone();
+    let millis = |time: Option<DateTime<Utc>>| time.map(|time| time.timestamp_millis() as f64);
+    Ok(ScFederationStatus {
+        upstream_url: CATALOG_UPSTREAM_URL.clone(),
+        version: status.version,
+        upstream_version: status.upstream_version,
+        drift: status.upstream_version.unwrap_or_default() - status.version.unwrap_or_default(),
+        last_synced_at: millis(status.last_synced_at),
+        last_error: status.last_error,
+        last_error_at: millis(status.last_error_at),
+        federated_games: federated_games as i32,
+        conflicts: status.conflicts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::federation::*;
+    use crate::schemas::game_change::{reduce_game_changes, GameChangeKind};
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    struct Row {
+        game: UpstreamGame,
+        upstream_id: Option<i32>,
+    }
+
+    /// An instance with its catalog journal, serving `gamesDelta` like the guest schema
+    #[derive(Default)]
+    struct Instance {
+        rows: BTreeMap<i32, Row>,
+        journal: Vec<(i32, GameChangeKind)>,
+        next_id: i32,
+    }
+
+    impl Instance {
+        fn new(first_id: i32) -> Self {
+            Instance {
+                next_id: first_id,
+                ..Default::default()
+            }
+        }
+
+        fn publish(&mut self, name: &str, rom: &str) -> i32 {
+            let gid = self.next_id;
+            self.next_id += 1;
+            let game = game(gid, name, rom);
+            self.rows.insert(
+                gid,
+                Row {
+                    game,
+                    upstream_id: None,
+                },
+            );
+            self.journal.push((gid, GameChangeKind::Created));
+            gid
+        }
+
+        fn edit(&mut self, gid: i32, rom: &str) {
+            self.rows.get_mut(&gid).unwrap().game.rom = rom.into();
+            self.journal.push((gid, GameChangeKind::Updated));
+        }
+
+        fn delete(&mut self, gid: i32) {
+            self.rows.remove(&gid);
+            self.journal.push((gid, GameChangeKind::Deleted));
+        }
+
+        fn catalog(&self) -> BTreeMap<String, String> {
+            self.rows
+                .values()
+                .map(|row| (row.game.name.clone(), row.game.rom.clone()))
+                .collect()
+        }
+    }
+
+    fn game(gid: i32, name: &str, rom: &str) -> UpstreamGame {
+        UpstreamGame {
+            id: gid,
+            name: name.into(),
+            description: String::new(),
+            preview: String::new(),
+            rom: rom.into(),
+            screenshots: vec![],
+            platform: Some("nes".into()),
+            series: None,
+            kind: None,
+            kinds: vec![],
+            max_player: Some(2),
+            deprecation_reason: None,
+            rom_hash: None,
+            issue_url: None,
+            trial_allowed: false,
+        }
+    }
+
+    impl Upstream for RefCell<Instance> {
+        fn delta(&self, since: i32) -> Result<UpstreamDelta, String> {
+            let instance = self.borrow();
+            let version = instance.journal.len() as i32;
+            let (created, updated, deleted_ids) =
+                reduce_game_changes(&instance.journal[since as usize..]);
+            let load = |ids: Vec<i32>| {
+                ids.iter()
+                    .filter_map(|gid| instance.rows.get(gid).map(|row| row.game.clone()))
+                    .collect()
+            };
+            Ok(UpstreamDelta {
+                created: load(created),
+                updated: load(updated),
+                deleted_ids,
+                version,
+                full_resync: false,
+            })
+        }
+
+        fn games(&self) -> Result<Vec<UpstreamGame>, String> {
+            Ok(self
+                .borrow()
+                .rows
+                .values()
+                .map(|row| row.game.clone())
+                .collect())
+        }
+    }
+
+    impl FederationStore for RefCell<Instance> {
+        fn federated_id(&self, upstream_id: i32) -> Option<i32> {
+            self.borrow()
+                .rows
+                .iter()
+                .find(|(_, row)| row.upstream_id == Some(upstream_id))
+                .map(|(gid, _)| *gid)
+        }
+
+        fn federated_upstream_ids(&self) -> Vec<i32> {
+            self.borrow()
+                .rows
+                .values()
+                .filter_map(|row| row.upstream_id)
+                .collect()
+        }
+
+        fn local_game(&self, name: &str, platform: Option<&str>) -> Option<(i32, UpstreamGame)> {
+            self.borrow()
+                .rows
+                .iter()
+                .find(|(_, row)| {
+                    row.upstream_id.is_none()
+                        && row.game.name == name
+                        && row.game.platform.as_deref() == platform
+                })
+                .map(|(gid, row)| (*gid, row.game.clone()))
+        }
+
+        fn upsert(&mut self, local_id: Option<i32>, game: &UpstreamGame) -> Result<(), String> {
+            let mut instance = self.borrow_mut();
+            match local_id {
+                Some(gid) => {
+                    let row = instance.rows.get_mut(&gid).unwrap();
+                    row.game = UpstreamGame {
+                        id: gid,
+                        ..game.clone()
+                    };
+                    instance.journal.push((gid, GameChangeKind::Updated));
+                }
+                None => {
+                    let gid = instance.publish(&game.name, &game.rom);
+                    instance.rows.get_mut(&gid).unwrap().upstream_id = Some(game.id);
+                }
+            }
+            Ok(())
+        }
+
+        fn remove(&mut self, local_id: i32) -> Result<(), String> {
+            self.get_mut().delete(local_id);
+            Ok(())
+        }
+    }
+
+    fn sync(
+        local: &mut RefCell<Instance>,
+        upstream: &RefCell<Instance>,
+        cursor: &mut Option<i32>,
+        status: &mut FederationStatus,
+    ) {
+        *cursor = Some(sync_catalog(local, upstream, *cursor, status).unwrap());
+    }
+
+    #[test]
+    fn federation_between_instances() {
+        let mut a = RefCell::new(Instance::new(1));
+        let mut b = RefCell::new(Instance::new(100));
+        let (mut b_cursor, mut b_status) = (None, FederationStatus::default());
+        let (mut a_cursor, mut a_status) = (None, FederationStatus::default());
+
+        let contra = a
+            .get_mut()
+            .publish("Contra", "https://roms.example/contra.zip");
+        a.get_mut()
+            .publish("Tetris", "https://roms.example/tetris.zip");
+        // Local to b, differs from a
+        let local_tetris = b
+            .get_mut()
+            .publish("Tetris", "https://mirror.example/tetris.zip");
+        b.get_mut()
+            .publish("Snake", "https://mirror.example/snake.zip");
+
+        // First sync loads the full list
+        sync(&mut b, &a, &mut b_cursor, &mut b_status);
+        assert_eq!(
+            b.borrow().catalog(),
+            BTreeMap::from([
+                ("Contra".into(), "https://roms.example/contra.zip".into()),
+                ("Snake".into(), "https://mirror.example/snake.zip".into()),
+                ("Tetris".into(), "https://mirror.example/tetris.zip".into()),
+            ])
+        );
+        assert_eq!(b_status.conflicts.len(), 1);
+        assert_eq!(b_status.conflicts[0].local_id, local_tetris);
+
+        // Deltas after that, the conflict is still reported
+        a.get_mut()
+            .edit(contra, "https://roms.example/contra-v2.zip");
+        a.get_mut()
+            .publish("Mario", "https://roms.example/mario.zip");
+        sync(&mut b, &a, &mut b_cursor, &mut b_status);
+        assert_eq!(b_cursor, Some(a.borrow().journal.len() as i32));
+        assert_eq!(b_status.conflicts.len(), 1);
+        assert_eq!(
+            b.borrow().catalog()["Contra"],
+            "https://roms.example/contra-v2.zip"
+        );
+
+        // The other way around, a gets b's own games but not its copies of a's
+        sync(&mut a, &b, &mut a_cursor, &mut a_status);
+        assert_eq!(a.borrow().rows.len(), 4);
+        assert!(a.borrow().catalog().contains_key("Snake"));
+        assert_eq!(
+            a.borrow().catalog()["Tetris"],
+            "https://roms.example/tetris.zip"
+        );
+        assert_eq!(a_status.conflicts.len(), 1);
+
+        // Deletes propagate, local games stay untouched
+        a.get_mut().delete(contra);
+        sync(&mut b, &a, &mut b_cursor, &mut b_status);
+        assert!(!b.borrow().catalog().contains_key("Contra"));
+        assert!(b.borrow().rows.contains_key(&local_tetris));
+        sync(&mut b, &a, &mut b_cursor, &mut b_status);
+        assert_eq!(b_status.version, b_status.upstream_version);
+    }
+}
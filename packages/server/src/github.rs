@@ -1,9 +1,17 @@
 use actix_web::HttpRequest;
+use chrono::{DateTime, Duration, Utc};
 use data_encoding::HEXLOWER;
 use pulldown_cmark::{Event, Options, Parser, Tag};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
 use std::str::FromStr;
+use std::sync::Mutex;
+use url::Url;
 
+use crate::net::check_public_url;
 use crate::schemas::game::*;
+use crate::schemas::game_attachment::{get_attachment_kind, ScNewGameAttachment};
 use ring::hmac::{verify, Key, HMAC_SHA256};
 
 pub fn validate(req: &HttpRequest, secret: &str, data: &[u8]) -> bool {
@@ -12,7 +20,7 @@ pub fn validate(req: &HttpRequest, secret: &str, data: &[u8]) -> bool {
         .get("X-Hub-Signature-256")
         .map(|signature| signature.to_str().unwrap_or_default())
         .unwrap_or_default();
-    let tag = &signature[7..signature.len()];
+    let tag = signature.strip_prefix("sha256=").unwrap_or_default();
 
     let is_ok = verify(
         &Key::new(HMAC_SHA256, secret.as_bytes()),
@@ -33,11 +41,17 @@ pub struct GithubUser {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct GithubLabel {
     pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 // https://docs.github.com/en/rest/issues/issues#get-an-issue
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GithubIssue {
+    #[serde(default)]
+    pub number: i32,
+    #[serde(default)]
+    pub html_url: String,
     pub title: String,
     pub body: String,
     pub state: String,
@@ -47,6 +61,16 @@ pub struct GithubIssue {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GithubRepo {
     pub owner: GithubUser,
+    // owner/name
+    #[serde(default)]
+    pub full_name: String,
+}
+
+/// Where a transferred issue lives now
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GithubIssueLink {
+    pub number: i32,
+    pub html_url: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,6 +81,9 @@ pub struct GithubChangeTitle {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GithubChanges {
     pub title: Option<GithubChangeTitle>,
+    // `transferred`
+    pub new_issue: Option<GithubIssueLink>,
+    pub new_repository: Option<GithubRepo>,
 }
 
 // https://docs.github.com/en/developers/webhooks-and-events/webhooks/webhook-events-and-payloads#issues
@@ -73,14 +100,108 @@ impl GithubPayload {
     pub fn is_owner(self: &Self) -> bool {
         self.sender.login == self.repository.owner.login
     }
+
+    /// (number, url, repository) of a `transferred` issue in its new place
+    pub fn get_transfer(&self) -> Option<(i32, String, String)> {
+        if self.action != "transferred" {
+            return None;
+        }
+        let changes = self.changes.as_ref()?;
+        let issue = changes.new_issue.as_ref()?;
+        let repository = changes.new_repository.as_ref()?;
+        Some((
+            issue.number,
+            issue.html_url.clone(),
+            repository.full_name.clone(),
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GithubRepoNameChange {
+    pub name: GithubChangeTitle,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GithubRepoChanges {
+    pub repository: Option<GithubRepoNameChange>,
+}
+
+// https://docs.github.com/en/developers/webhooks-and-events/webhooks/webhook-events-and-payloads#repository
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GithubRepoPayload {
+    pub action: String,
+    pub repository: GithubRepo,
+    pub sender: GithubUser,
+    pub changes: Option<GithubRepoChanges>,
+}
+
+impl GithubRepoPayload {
+    pub fn is_owner(&self) -> bool {
+        self.sender.login == self.repository.owner.login
+    }
+
+    /// (old, new) full names of a `renamed` repository, the owner stays
+    pub fn get_rename(&self) -> Option<(String, String)> {
+        if self.action != "renamed" {
+            return None;
+        }
+        let from = &self.changes.as_ref()?.repository.as_ref()?.name.from;
+        Some((
+            format!("{}/{}", self.repository.owner.login, from),
+            self.repository.full_name.clone(),
+        ))
+    }
+}
+
+/// Issue url moved from repository `old` to `new`, `None` for other repositories.
+/// GitHub names are case insensitive
+pub fn rename_issue_url(url: &str, old: &str, new: &str) -> Option<String> {
+    let path = url.strip_prefix("https://github.com/")?;
+    let (repository, rest) = path.split_at(path.find("/issues/")?);
+    repository
+        .eq_ignore_ascii_case(old)
+        .then(|| format!("https://github.com/{}{}", new, rest))
+}
+
+/// Manuals, control sheets and NSF music, images are screenshots and zip is the rom.
+/// Only public hosts, the server fetches them
+fn is_attachment_url(url: &str) -> bool {
+    Url::parse(url).map_or(false, |parsed| check_public_url(&parsed).is_ok())
+        && get_attachment_kind(url).is_some()
+}
+
+/// `key: value` lines fenced by `---` at the top of the body, and the rest of the body
+fn split_front_matter(body: &str) -> (HashMap<String, String>, &str) {
+    let mut matter = HashMap::new();
+    let mut offset = 0;
+    for (index, line) in body.split_inclusive('\n').enumerate() {
+        offset += line.len();
+        let line = line.trim_end();
+        if index == 0 {
+            if line != "---" {
+                break;
+            }
+        } else if line == "---" {
+            return (matter, &body[offset..]);
+        } else if let Some((key, value)) = line.split_once(':') {
+            matter.insert(key.trim().to_lowercase(), value.trim().to_owned());
+        }
+    }
+    (HashMap::new(), body)
 }
 
 pub fn get_sc_game(payload: &GithubPayload) -> (String, ScNewGame) {
-    let parser = Parser::new_ext(&payload.issue.body, Options::all());
+    let (front_matter, body) = split_front_matter(&payload.issue.body);
+    let parser = Parser::new_ext(body, Options::all());
 
     let mut preview = String::new();
     let mut screenshots = Vec::new();
     let mut rom = String::new();
+    let mut rom_hash = None;
+    // (url, label) of the link being parsed
+    let mut link = None;
+    let mut attachments: Vec<ScNewGameAttachment> = Vec::new();
     for event in parser {
         match event {
             Event::Start(Tag::Image(_, url, _)) => {
@@ -90,27 +211,70 @@ pub fn get_sc_game(payload: &GithubPayload) -> (String, ScNewGame) {
                     screenshots.push(url.into_string());
                 }
             }
-            Event::Start(Tag::Link(_, link, _)) => {
-                if rom.is_empty() && link.ends_with(".zip") {
-                    rom.push_str(&link);
+            Event::Start(Tag::Link(_, url, _)) => {
+                if rom.is_empty() && url.ends_with(".zip") {
+                    rom.push_str(&url);
+                }
+                link = Some((url.into_string(), String::new()));
+            }
+            Event::End(Tag::Link(..)) => {
+                if let Some((url, label)) = link.take() {
+                    if is_attachment_url(&url) && !attachments.iter().any(|a| a.url == url) {
+                        let label = label.trim();
+                        attachments.push(ScNewGameAttachment {
+                            label: if label.is_empty() {
+                                url.rsplit('/').next().unwrap_or_default().to_owned()
+                            } else {
+                                label.chars().take(200).collect()
+                            },
+                            url,
+                        });
+                    }
+                }
+            }
+            // e.g. `sha256:<hex>`
+            Event::Code(text) | Event::Text(text) => {
+                if let Some((_, label)) = link.as_mut() {
+                    label.push_str(&text);
+                }
+                if let Some(hash) = text.trim().strip_prefix("sha256:") {
+                    let hash = hash.trim();
+                    if rom_hash.is_none()
+                        && hash.len() == 64
+                        && hash.chars().all(|c| c.is_ascii_hexdigit())
+                    {
+                        rom_hash = Some(hash.to_lowercase());
+                    }
                 }
             }
             _ => (),
         }
     }
+    let kinds: Vec<ScGameKind> = payload
+        .issue
+        .labels
+        .iter()
+        .filter(|label| label.name.starts_with("game.kind."))
+        .filter_map(|label| label.name.split_terminator(".").last())
+        .filter_map(|s| ScGameKind::from_str(s).ok())
+        .collect();
+    let play_modes: Vec<ScPlayMode> = payload
+        .issue
+        .labels
+        .iter()
+        .filter(|label| label.name.starts_with("game.mode."))
+        .filter_map(|label| label.name.split_terminator(".").last())
+        .filter_map(|s| ScPlayMode::from_str(s).ok())
+        .collect();
     let game = ScNewGame {
         name: payload.issue.title.clone(),
-        description: payload.issue.body.clone(),
+        description: body.to_owned(),
         preview,
         rom,
+        rom_hash,
         screenshots,
-        kind: payload
-            .issue
-            .labels
-            .iter()
-            .find(|label| label.name.starts_with("game.kind."))
-            .and_then(|label| label.name.split_terminator(".").last())
-            .and_then(|s| ScGameKind::from_str(s).ok()),
+        kind: kinds.first().cloned(),
+        kinds: Some(kinds),
         max_player: payload
             .issue
             .labels
@@ -132,6 +296,27 @@ pub fn get_sc_game(payload: &GithubPayload) -> (String, ScNewGame) {
             .find(|label| label.name.starts_with("game.series."))
             .and_then(|label| label.name.split_terminator(".").last())
             .and_then(|s| ScGameSeries::from_str(s).ok()),
+        deprecation_reason: payload
+            .issue
+            .labels
+            .iter()
+            .find(|label| label.name == "deprecated")
+            .map(|label| label.description.clone().unwrap_or_default()),
+        issue_number: Some(payload.issue.number).filter(|number| *number > 0),
+        issue_url: Some(payload.issue.html_url.clone()).filter(|url| !url.is_empty()),
+        attachments: Some(attachments),
+        score_ceiling: front_matter
+            .get("score_ceiling")
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|ceiling| *ceiling >= 0.0),
+        trial_allowed: Some(
+            payload
+                .issue
+                .labels
+                .iter()
+                .any(|label| label.name == "trial"),
+        ),
+        play_modes: Some(play_modes),
     };
     (
         payload
@@ -143,6 +328,219 @@ pub fn get_sc_game(payload: &GithubPayload) -> (String, ScNewGame) {
     )
 }
 
+struct ReportComment {
+    // `None` while the comment is being created
+    id: Option<u64>,
+    created_at: DateTime<Utc>,
+    descriptions: Vec<String>,
+    // body of the latest report
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct GithubComment {
+    id: u64,
+}
+
+lazy_static! {
+    // Disabled when not set
+    static ref GITHUB_TOKEN: Option<String> = env::var("GITHUB_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    // game id -> report comment created in the last hour
+    static ref REPORT_COMMENTS: Mutex<HashMap<i32, ReportComment>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+}
+
+/// `https://github.com/<owner>/<repo>/issues/<number>` to api url
+fn get_issue_api_url(issue_url: &str) -> Option<String> {
+    issue_url
+        .strip_prefix("https://github.com/")
+        .filter(|path| path.contains("/issues/"))
+        .map(|path| format!("https://api.github.com/repos/{}", path))
+}
+
+/// Player text in a fence longer than any backtick run in it,
+/// mentions, links and images inside aren't rendered
+fn fence_text(text: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or_default();
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}text\n{text}\n{fence}", fence = fence, text = text)
+}
+
+fn get_report_comment_body(count: i64, descriptions: &[String]) -> String {
+    let mut body = format!(
+        "**{}** player(s) reported a problem in the last hour:\n",
+        count
+    );
+    for description in descriptions {
+        body.push_str(&format!("\n{}\n", fence_text(description)));
+    }
+    body
+}
+
+fn send_comment(method: &str, url: &str, token: &str, body: &str) -> Result<u64, String> {
+    let request = match method {
+        "PATCH" => attohttpc::patch(url),
+        _ => attohttpc::post(url),
+    };
+    let resp = request
+        .header("Authorization", format!("token {}", token))
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", "nesbox")
+        .header("Content-Type", "application/json")
+        .text(json!({ "body": body }).to_string())
+        .send()
+        .map_err(|err| err.to_string())?;
+
+    if !resp.is_success() {
+        return Err(format!("status {}", resp.status()));
+    }
+
+    let text = resp.text().map_err(|err| err.to_string())?;
+    serde_json::from_str::<GithubComment>(&text)
+        .map(|comment| comment.id)
+        .map_err(|err| err.to_string())
+}
+
+/// Classic tokens list their scopes in `X-OAuth-Scopes`, fine-grained tokens don't
+pub fn get_token_scopes(token: &str) -> Result<Option<Vec<String>>, String> {
+    let resp = attohttpc::get("https://api.github.com/user")
+        .header("Authorization", format!("token {}", token))
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", "nesbox")
+        .send()
+        .map_err(|err| err.to_string())?;
+
+    if !resp.is_success() {
+        return Err(format!("status {}", resp.status()));
+    }
+
+    Ok(resp
+        .headers()
+        .get("X-OAuth-Scopes")
+        .and_then(|value| value.to_str().ok())
+        .map(|scopes| {
+            scopes
+                .split(',')
+                .map(|scope| scope.trim().to_owned())
+                .filter(|scope| !scope.is_empty())
+                .collect()
+        }))
+}
+
+/// Record a report, return the comment to create (`None`) or update and its body,
+/// nothing while the comment is still being created
+fn queue_report(
+    comments: &mut HashMap<i32, ReportComment>,
+    gid: i32,
+    description: String,
+    count: i64,
+    now: DateTime<Utc>,
+) -> Option<(Option<u64>, String)> {
+    comments.retain(|_, comment| now - comment.created_at < Duration::hours(1));
+
+    let comment = comments.entry(gid).or_insert_with(|| ReportComment {
+        id: None,
+        created_at: now,
+        descriptions: vec![],
+        body: String::new(),
+    });
+    let creating = comment.body.is_empty();
+    comment.descriptions.push(description);
+    let skip = comment.descriptions.len().saturating_sub(10);
+    comment.descriptions.drain(..skip);
+    comment.body = get_report_comment_body(count, &comment.descriptions);
+
+    match comment.id {
+        Some(id) => Some((Some(id), comment.body.clone())),
+        None if creating => Some((None, comment.body.clone())),
+        None => None,
+    }
+}
+
+/// Store the created comment, return the newer body of reports queued meanwhile.
+/// A failed creation is forgotten so the next report tries again
+fn finish_report(
+    comments: &mut HashMap<i32, ReportComment>,
+    gid: i32,
+    created: Option<u64>,
+    sent: &str,
+) -> Option<(u64, String)> {
+    let comment = comments.get_mut(&gid)?;
+    match created {
+        Some(id) => {
+            comment.id = Some(id);
+            (comment.body != sent).then(|| (id, comment.body.clone()))
+        }
+        None => {
+            comments.remove(&gid);
+            None
+        }
+    }
+}
+
+/// Comment on the game issue, reports within an hour update the same comment
+pub fn comment_game_report(gid: i32, issue_url: &str, description: String, count: i64) {
+    let token = match GITHUB_TOKEN.as_ref() {
+        Some(token) => token.clone(),
+        None => return,
+    };
+    let api_url = match get_issue_api_url(issue_url) {
+        Some(url) => url,
+        None => return,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let issues_url = api_url.rsplitn(2, '/').last().unwrap_or_default();
+        let update = |id: u64, body: &str| {
+            send_comment(
+                "PATCH",
+                &format!("{}/comments/{}", issues_url, id),
+                &token,
+                body,
+            )
+            .map(|_| ())
+        };
+
+        // The lock only guards the map, requests are sent after releasing it
+        let queued = queue_report(
+            &mut REPORT_COMMENTS.lock().unwrap(),
+            gid,
+            description,
+            count,
+            Utc::now(),
+        );
+        let result = match queued {
+            Some((Some(id), body)) => update(id, &body),
+            Some((None, body)) => {
+                let created = send_comment("POST", &format!("{}/comments", api_url), &token, &body);
+                let newer = finish_report(
+                    &mut REPORT_COMMENTS.lock().unwrap(),
+                    gid,
+                    created.as_ref().ok().copied(),
+                    &body,
+                );
+                match (created, newer) {
+                    (Ok(_), Some((id, body))) => update(id, &body),
+                    (created, _) => created.map(|_| ()),
+                }
+            }
+            None => Ok(()),
+        };
+
+        if let Err(err) = result {
+            log::error!("Comment game {} report failed: {}", gid, err);
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use crate::github::*;
@@ -152,37 +550,189 @@ mod tests {
         let payload = GithubPayload {
             action: "closed".into(),
             issue: GithubIssue {
+                number: 42,
+                html_url: "https://github.com/mantou132/nesbox/issues/42".into(),
                 labels: vec![
-                    GithubLabel {name: "game.kind.act".into()}, 
-                    GithubLabel {name: "game.max_player.1".into()}, 
-                    GithubLabel {name: "game.platform.nes".into()},
-                    GithubLabel {name: "game.series.tmnt".into()},
+                    GithubLabel {name: "game.kind.act".into(), description: None}, 
+                    GithubLabel {name: "game.kind.stg".into(), description: None}, 
+                    GithubLabel {name: "game.max_player.1".into(), description: None}, 
+                    GithubLabel {name: "game.platform.nes".into(), description: None},
+                    GithubLabel {name: "game.series.tmnt".into(), description: None},
+                    GithubLabel {name: "game.mode.versus".into(), description: None},
+                    GithubLabel {name: "game.mode.co_op".into(), description: None},
+                    GithubLabel {name: "game.mode.hotseat".into(), description: None},
+                    GithubLabel {name: "deprecated".into(), description: Some("license".into())},
                 ],
                 state: "open".into(),
                 title: "name".into(),
-                body: "![NekketsuKakutouDensetsu_frontcover](https://user-images.githubusercontent.com/3841872/168952574-26de855e-b7cd-43fe-ab94-093a2903832d.png)\r\n\r\nゲームモードは、ストーリーにそって闘いを進めていく「ストーリーモード」と最高4人でどたばたと闘い合う「バトルモード」の2種類のモードがあるぞ！\r\n![ABUIABACGAAg9eiD9gUo_I7-uQYwmgM4mgM](https://user-images.githubusercontent.com/3841872/168967700-44131eb9-6e33-48d0-9f3d-e71e9fcdb51b.jpg)\r\n[legend.nes.zip](https://github.com/mantou132/nesbox/files/8713065/legend.nes.zip)\r\n".into(),
+                body: "![NekketsuKakutouDensetsu_frontcover](https://user-images.githubusercontent.com/3841872/168952574-26de855e-b7cd-43fe-ab94-093a2903832d.png)\r\n\r\nゲームモードは、ストーリーにそって闘いを進めていく「ストーリーモード」と最高4人でどたばたと闘い合う「バトルモード」の2種類のモードがあるぞ！\r\n![ABUIABACGAAg9eiD9gUo_I7-uQYwmgM4mgM](https://user-images.githubusercontent.com/3841872/168967700-44131eb9-6e33-48d0-9f3d-e71e9fcdb51b.jpg)\r\n[legend.nes.zip](https://github.com/mantou132/nesbox/files/8713065/legend.nes.zip)\r\n[Manual](https://github.com/mantou132/nesbox/files/8713066/manual.pdf)\r\n".into(),
             },
             repository: GithubRepo {
                 owner: GithubUser { login: "".into() },
+                full_name: "".into(),
             },
             sender: GithubUser { login: "".into() },
             changes: Some(GithubChanges {
-                title: Some(GithubChangeTitle { from: "old_name".into() })
+                title: Some(GithubChangeTitle { from: "old_name".into() }),
+                new_issue: None,
+                new_repository: None,
             }),
         };
         assert_eq!(
             get_sc_game(&payload),
             ("old_name".into(), ScNewGame {
                 name: "name".into(),
-                description: "![NekketsuKakutouDensetsu_frontcover](https://user-images.githubusercontent.com/3841872/168952574-26de855e-b7cd-43fe-ab94-093a2903832d.png)\r\n\r\nゲームモードは、ストーリーにそって闘いを進めていく「ストーリーモード」と最高4人でどたばたと闘い合う「バトルモード」の2種類のモードがあるぞ！\r\n![ABUIABACGAAg9eiD9gUo_I7-uQYwmgM4mgM](https://user-images.githubusercontent.com/3841872/168967700-44131eb9-6e33-48d0-9f3d-e71e9fcdb51b.jpg)\r\n[legend.nes.zip](https://github.com/mantou132/nesbox/files/8713065/legend.nes.zip)\r\n".into(),
+                description: "![NekketsuKakutouDensetsu_frontcover](https://user-images.githubusercontent.com/3841872/168952574-26de855e-b7cd-43fe-ab94-093a2903832d.png)\r\n\r\nゲームモードは、ストーリーにそって闘いを進めていく「ストーリーモード」と最高4人でどたばたと闘い合う「バトルモード」の2種類のモードがあるぞ！\r\n![ABUIABACGAAg9eiD9gUo_I7-uQYwmgM4mgM](https://user-images.githubusercontent.com/3841872/168967700-44131eb9-6e33-48d0-9f3d-e71e9fcdb51b.jpg)\r\n[legend.nes.zip](https://github.com/mantou132/nesbox/files/8713065/legend.nes.zip)\r\n[Manual](https://github.com/mantou132/nesbox/files/8713066/manual.pdf)\r\n".into(),
                 preview: "https://user-images.githubusercontent.com/3841872/168952574-26de855e-b7cd-43fe-ab94-093a2903832d.png".into(),
                 rom: "https://github.com/mantou132/nesbox/files/8713065/legend.nes.zip".into(),
                 screenshots: vec!["https://user-images.githubusercontent.com/3841872/168967700-44131eb9-6e33-48d0-9f3d-e71e9fcdb51b.jpg".into()],
                 kind: Some(ScGameKind::Act),
+                kinds: Some(vec![ScGameKind::Act, ScGameKind::Stg]),
                 max_player: Some(1),
                 platform: Some(ScGamePlatform::Nes),
                 series: Some(ScGameSeries::Tmnt),
+                deprecation_reason: Some("license".into()),
+                rom_hash: None,
+                issue_number: Some(42),
+                issue_url: Some("https://github.com/mantou132/nesbox/issues/42".into()),
+                attachments: Some(vec![ScNewGameAttachment {
+                    label: "Manual".into(),
+                    url: "https://github.com/mantou132/nesbox/files/8713066/manual.pdf".into(),
+                }]),
+                score_ceiling: None,
+                trial_allowed: Some(false),
+                play_modes: Some(vec![ScPlayMode::Versus, ScPlayMode::CoOp]),
             })
         );
     }
+
+    #[test]
+    fn attachment_urls() {
+        assert!(is_attachment_url("https://github.com/files/1/manual.PDF"));
+        assert!(is_attachment_url(
+            "https://github.com/files/2/ost.nsf?raw=1"
+        ));
+        assert!(!is_attachment_url(
+            "https://github.com/files/3/legend.nes.zip"
+        ));
+        assert!(!is_attachment_url("ftp://example.com/ost.nsf"));
+        assert!(!is_attachment_url("http://169.254.169.254/manual.pdf"));
+        assert!(!is_attachment_url("http://localhost:8080/ost.nsf"));
+        assert!(!is_attachment_url(
+            "https://user@github.com/files/1/manual.pdf"
+        ));
+    }
+
+    #[test]
+    fn front_matter() {
+        let (matter, body) =
+            split_front_matter("---\r\nscore_ceiling: 999990\r\n---\r\n![cover](a.png)");
+        assert_eq!(
+            matter.get("score_ceiling").map(String::as_str),
+            Some("999990")
+        );
+        assert_eq!(body, "![cover](a.png)");
+
+        // Unclosed fence is markdown
+        let (matter, body) = split_front_matter("---\nscore_ceiling: 1\n");
+        assert!(matter.is_empty());
+        assert_eq!(body, "---\nscore_ceiling: 1\n");
+        assert!(split_front_matter("text\n---\na: b\n---\n").0.is_empty());
+    }
+
+    #[test]
+    fn report_comments() {
+        let mut comments = HashMap::new();
+        let now = Utc::now();
+
+        let (id, body) = queue_report(&mut comments, 1, "black screen".into(), 1, now).unwrap();
+        assert_eq!(id, None);
+        // Reports while the comment is created don't create another one
+        assert!(queue_report(&mut comments, 1, "no sound".into(), 2, now).is_none());
+        let (id, newer) = finish_report(&mut comments, 1, Some(7), &body).unwrap();
+        assert_eq!(id, 7);
+        assert!(newer.starts_with("**2**"));
+        assert!(newer.contains("```text\nblack screen\n```") && newer.contains("no sound"));
+
+        let (id, body) = queue_report(&mut comments, 1, "crash".into(), 3, now).unwrap();
+        assert_eq!(id, Some(7));
+        assert!(body.starts_with("**3**"));
+
+        // Failed creation, the next report tries again
+        let (_, body) = queue_report(&mut comments, 2, "slow".into(), 1, now).unwrap();
+        assert!(finish_report(&mut comments, 2, None, &body).is_none());
+        assert_eq!(
+            queue_report(&mut comments, 2, "slow".into(), 2, now).map(|(id, _)| id),
+            Some(None)
+        );
+
+        // A new comment an hour later
+        let later = now + Duration::hours(1);
+        assert_eq!(
+            queue_report(&mut comments, 1, "crash".into(), 1, later).map(|(id, _)| id),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn report_fences() {
+        assert_eq!(fence_text("@owner"), "```text\n@owner\n```");
+        assert_eq!(
+            fence_text("```\n[x](https://a.b) @owner\n``"),
+            "````text\n```\n[x](https://a.b) @owner\n``\n````"
+        );
+    }
+
+    #[test]
+    fn issue_api_url() {
+        assert_eq!(
+            get_issue_api_url("https://github.com/mantou132/nesbox/issues/42"),
+            Some("https://api.github.com/repos/mantou132/nesbox/issues/42".into())
+        );
+        assert_eq!(get_issue_api_url("https://example.com/issues/42"), None);
+    }
+
+    #[test]
+    fn transferred_issue_payload() {
+        let payload: GithubPayload =
+            serde_json::from_slice(include_bytes!("github_transferred_fixture.json")).unwrap();
+        assert!(payload.is_owner());
+        assert_eq!(payload.repository.full_name, "mantou132/nesbox");
+        assert_eq!(
+            payload.get_transfer(),
+            Some((
+                7,
+                "https://github.com/mantou132/nesbox-games/issues/7".into(),
+                "mantou132/nesbox-games".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn renamed_repository_payload() {
+        let payload: GithubRepoPayload =
+            serde_json::from_slice(include_bytes!("github_renamed_fixture.json")).unwrap();
+        assert!(payload.is_owner());
+        let (old, new) = payload.get_rename().unwrap();
+        assert_eq!(old, "mantou132/nesbox");
+        assert_eq!(new, "mantou132/nesbox-games");
+
+        assert_eq!(
+            rename_issue_url("https://github.com/Mantou132/NESBox/issues/42", &old, &new),
+            Some("https://github.com/mantou132/nesbox-games/issues/42".into())
+        );
+        // Another repository with the same prefix
+        assert_eq!(
+            rename_issue_url(
+                "https://github.com/mantou132/nesbox-app/issues/42",
+                &old,
+                &new
+            ),
+            None
+        );
+        assert_eq!(
+            rename_issue_url("https://example.com/issues/42", &old, &new),
+            None
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use chrono::Utc;
+use juniper::{FieldError, FieldResult};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::time::Duration;
+use url::form_urlencoded;
+
+use crate::error::Error;
+use crate::schemas::user::ScRegisterReq;
+
+const BUNDLED_DISPOSABLE_DOMAINS: &str = include_str!("disposable_domains.txt");
+
+fn env_flag(key: &str, default: bool) -> bool {
+    env::var(key)
+        .ok()
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(default)
+}
+
+fn parse_domains(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+lazy_static! {
+    // Disabled when not set, hCaptcha or Turnstile
+    static ref CAPTCHA_SECRET: Option<String> = env::var("CAPTCHA_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty());
+    static ref CAPTCHA_VERIFY_URL: String = env::var("CAPTCHA_VERIFY_URL")
+        .unwrap_or("https://challenges.cloudflare.com/turnstile/v0/siteverify".to_owned());
+    static ref REGISTER_HONEYPOT: bool = env_flag("REGISTER_HONEYPOT", true);
+    static ref REGISTER_BLOCK_DISPOSABLE_EMAIL: bool =
+        env_flag("REGISTER_BLOCK_DISPOSABLE_EMAIL", true);
+    // Minimum seconds between form shown and submit, 0 is disabled
+    static ref REGISTER_MIN_SECONDS: i64 = env::var("REGISTER_MIN_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(0);
+    static ref DISPOSABLE_DOMAINS: HashSet<String> = {
+        let mut set: HashSet<String> = parse_domains(BUNDLED_DISPOSABLE_DOMAINS).collect();
+        if let Ok(file) = env::var("DISPOSABLE_DOMAINS_FILE") {
+            match fs::read_to_string(&file) {
+                Ok(text) => set.extend(parse_domains(&text)),
+                Err(err) => log::error!("Read disposable domains {}: {}", file, err),
+            }
+        }
+        set
+    };
+}
+
+pub fn is_disposable_email(email: &str) -> bool {
+    email
+        .trim()
+        .rsplit_once('@')
+        .map(|(_, domain)| {
+            let domain = domain.to_lowercase();
+            // subdomains of listed domains too
+            let mut parts = domain.as_str();
+            loop {
+                if DISPOSABLE_DOMAINS.contains(parts) {
+                    return true;
+                }
+                match parts.split_once('.') {
+                    Some((_, rest)) if rest.contains('.') => parts = rest,
+                    _ => return false,
+                }
+            }
+        })
+        .unwrap_or(false)
+}
+
+fn verify_captcha_with(url: &str, secret: &str, token: &str) -> Result<bool, String> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .append_pair("secret", secret)
+        .append_pair("response", token)
+        .finish();
+    let resp = attohttpc::post(url)
+        .timeout(Duration::from_secs(10))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .text(body)
+        .send()
+        .map_err(|err| err.to_string())?;
+    let text = resp.text().map_err(|err| err.to_string())?;
+    let result: Value = serde_json::from_str(&text).map_err(|err| err.to_string())?;
+    Ok(result
+        .get("success")
+        .and_then(|success| success.as_bool())
+        .unwrap_or(false))
+}
+
+/// Cheap checks first, captcha round-trip last, skip disabled defenses
+pub async fn check_register(req: &ScRegisterReq) -> FieldResult<()> {
+    if *REGISTER_HONEYPOT && req.website.as_ref().map_or(false, |s| !s.is_empty()) {
+        return Err(FieldError::new("bot detected", Error::register_honeypot()));
+    }
+
+    if *REGISTER_MIN_SECONDS > 0 {
+        let elapsed = req
+            .form_started_at
+            .map(|at| (Utc::now().timestamp_millis() as f64 - at) / 1000.0)
+            .unwrap_or(0.0);
+        if elapsed < *REGISTER_MIN_SECONDS as f64 {
+            return Err(FieldError::new(
+                "submitted too fast",
+                Error::register_too_fast(),
+            ));
+        }
+    }
+
+    if *REGISTER_BLOCK_DISPOSABLE_EMAIL && req.email.as_deref().map_or(false, is_disposable_email) {
+        return Err(FieldError::new(
+            "disposable email is not allowed",
+            Error::disposable_email(),
+        ));
+    }
+
+    if let Some(secret) = CAPTCHA_SECRET.as_ref() {
+        let secret = secret.clone();
+        let token = req.captcha_token.clone().unwrap_or_default();
+        let url = CAPTCHA_VERIFY_URL.clone();
+        let passed =
+            tokio::task::spawn_blocking(move || verify_captcha_with(&url, &secret, &token))
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|result| result);
+        match passed {
+            Ok(true) => (),
+            Ok(false) => {
+                return Err(FieldError::new(
+                    "captcha verification failed",
+                    Error::captcha_failed(),
+                ))
+            }
+            Err(err) => {
+                log::error!("Verify captcha: {}", err);
+                return Err(FieldError::new(
+                    "captcha verification unavailable",
+                    Error::captcha_failed(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::guard::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn mock_captcha_server(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            stream.read(&mut buf).ok();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .ok();
+        });
+        format!("http://{}/siteverify", addr)
+    }
+
+    #[test]
+    fn captcha_verification() {
+        let url = mock_captcha_server(r#"{"success":true}"#);
+        assert_eq!(verify_captcha_with(&url, "secret", "token"), Ok(true));
+
+        let url =
+            mock_captcha_server(r#"{"success":false,"error-codes":["invalid-input-response"]}"#);
+        assert_eq!(verify_captcha_with(&url, "secret", "token"), Ok(false));
+    }
+
+    #[test]
+    fn disposable_email() {
+        assert!(is_disposable_email("bot@mailinator.com"));
+        assert!(is_disposable_email("bot@MAILINATOR.com"));
+        assert!(is_disposable_email("bot@eu.yopmail.com"));
+        assert!(!is_disposable_email("player@gmail.com"));
+        assert!(!is_disposable_email("not-an-email"));
+    }
+}
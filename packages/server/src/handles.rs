@@ -1,23 +1,198 @@
-use actix_web::{error, web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    cookie::{Cookie, SameSite},
+    dev, error,
+    http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS},
+    http::StatusCode,
+    web::{self, Bytes},
+    Error, FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use chrono::Utc;
+use futures::Stream;
 use juniper::{
     http::{GraphQLRequest, GraphQLResponse},
     introspect, DefaultScalarValue, InputValue, IntrospectionFormat, Variables,
 };
 use juniper_actix::subscriptions::subscriptions_handler;
 use juniper_graphql_ws::ConnectionConfig;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::env;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{self, Poll};
 use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::{
-    auth::{extract_token_from_req, extract_token_from_str, UserToken},
+    attachment::load_attachment,
+    auth::oauth::{generate_state, parse_state, OauthProvider},
+    auth::{
+        extract_token_from_req, extract_token_from_str, AnonToken, Identity, Secret, UserToken,
+    },
+    avatar::{render_avatar, save_avatar},
+    config::{resolve_client_ip, SUBSCRIPTION_CONFIG, TRUSTED_PROXIES},
     db::root::DB_POOL,
-    github::{get_sc_game, validate, GithubPayload},
+    deflate::{inflate_payload, negotiate, Deflater, Inflater},
+    doctor::{is_healthy, render_report, run_checks, LiveProbe},
+    error::{sanitize_errors, Error as ApiError},
+    github::{validate, GithubPayload, GithubRepoPayload},
+    idempotency::{
+        execute_once, is_mutation, parse_idempotency_key, OperationInfo, StoredResponse,
+    },
+    issue_queue::{process_repository_rename, ISSUE_QUEUE},
+    metrics::{inc_counter, render},
+    profiling::sample_request,
+    query_cost::{measure_query, QUERY_LIMITS},
+    quota::{consume_quota, is_usage_loaded, load_usage, set_loaded_usage, DAILY_LIMITS},
+    request::{
+        bad_request_response, error_envelope, get_mime, parse_content_range, parse_graphql_request,
+        parse_range, read_multipart_file,
+    },
+    rom::{check_rom, get_rom_key, get_upload_key, is_rom_hash, store_uploaded_rom},
     schemas::root::{Context, GuestContext, GuestSchema, Schema},
     schemas::{
-        game::{create_game, get_game_from_name, update_game},
-        notify::{notify_all, ScNotifyMessageBuilder},
+        anonymous::is_allowed_for_anonymous,
+        audit::{is_allowed_when_impersonated, write_audit_log},
+        client_info::{
+            get_cached_client_versions, get_required_upgrade, load_client_versions,
+            parse_client_info, ScClientInfo,
+        },
+        connection::{close_connection, open_connection, SubscriptionConnection},
+        feature_flag::{get_cached_flags, load_flags, resolve_flags},
+        game::{get_games_after, ScGame},
+        oauth_account::oauth_login,
+        persisted_query::{
+            get_cached_query, get_persisted_query, read_persisted_query, save_persisted_query,
+            PersistedQuery, MAX_PERSISTED_QUERY_BYTES,
+        },
+        restriction::{get_cached_birthdate, load_birthdate, RESTRICTION_CONFIG},
+        save_state::put_state_chunk,
+        tenant::{get_tenant_slug, get_user_tenant, resolve_tenant},
+        user::{is_admin, touch_last_seen},
     },
+    screenshot::load_screenshot,
+    storage::{get_storage, is_blob_hash, read_all, HashedBlobs},
+    stream::{stream_json_array, ERROR_SENTINEL},
+    transfer::{export_personal_data, import_personal_data, parse_personal_data},
 };
 
+lazy_static! {
+    // In-flight graphql requests, shed load instead of exhausting db connections
+    static ref GRAPHQL_SEMAPHORE: Semaphore = Semaphore::new(
+        env::var("MAX_CONCURRENT_REQUESTS")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(64),
+    );
+}
+
+fn acquire_graphql_permit() -> Result<SemaphorePermit<'static>, HttpResponse> {
+    GRAPHQL_SEMAPHORE.try_acquire().map_err(|_| {
+        log::warn!("Too many concurrent graphql requests");
+        HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", "1"))
+            .finish()
+    })
+}
+
+/// Tenant from header or subdomain, the user must belong to it
+async fn get_request_tenant(
+    req: &HttpRequest,
+    user_id: Option<i32>,
+) -> Result<Option<i32>, HttpResponse> {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    let slug = get_tenant_slug(header("X-Tenant").as_deref(), header("host").as_deref());
+    let status = web::block(move || {
+        let conn = DB_POOL.get().unwrap();
+        let tenant = resolve_tenant(&conn, slug.as_deref()).ok_or(StatusCode::NOT_FOUND)?;
+        match user_id {
+            Some(uid) if get_user_tenant(&conn, uid) != tenant => Err(StatusCode::UNAUTHORIZED),
+            _ => Ok(tenant),
+        }
+    })
+    .await
+    .unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR));
+    status.map_err(HttpResponse::new)
+}
+
+/// Flags of the caller, rows are reloaded once the cache expired
+async fn get_request_features(user_id: i32) -> Result<BTreeMap<String, bool>, HttpResponse> {
+    let flags = match get_cached_flags() {
+        Some(flags) => flags,
+        None => web::block(|| load_flags(&DB_POOL.get().unwrap()))
+            .await
+            .ok()
+            .and_then(|result| result.ok())
+            .ok_or_else(|| HttpResponse::InternalServerError().finish())?,
+    };
+    Ok(resolve_flags(&flags, user_id))
+}
+
+/// Anonymous sessions and users without a birthdate are not minors
+async fn get_request_minor(user_id: i32) -> Result<bool, HttpResponse> {
+    if user_id == 0 {
+        return Ok(false);
+    }
+    let birthdate = match get_cached_birthdate(user_id) {
+        Some(birthdate) => birthdate,
+        None => web::block(move || load_birthdate(&DB_POOL.get().unwrap(), user_id))
+            .await
+            .ok()
+            .and_then(|result| result.ok())
+            .ok_or_else(|| HttpResponse::InternalServerError().finish())?,
+    };
+    Ok(RESTRICTION_CONFIG.is_minor(birthdate, Utc::now()))
+}
+
+/// Minimum version when the client is older than its name allows,
+/// clients that don't declare themselves are never refused
+async fn get_required_client_upgrade(
+    client: Option<&ScClientInfo>,
+) -> Result<Option<String>, HttpResponse> {
+    let client = match client {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    let versions = match get_cached_client_versions() {
+        Some(versions) => versions,
+        None => web::block(|| load_client_versions(&DB_POOL.get().unwrap()))
+            .await
+            .ok()
+            .and_then(|result| result.ok())
+            .ok_or_else(|| HttpResponse::InternalServerError().finish())?,
+    };
+    Ok(get_required_upgrade(&versions, client))
+}
+
+/// Optional `X-Client-Info` header, JSON like the `clientInfo` connection parameter
+fn get_client_info(req: &HttpRequest) -> Result<Option<ScClientInfo>, HttpResponse> {
+    match req.headers().get("X-Client-Info") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|value| serde_json::from_str(value).ok())
+            .and_then(parse_client_info)
+            .map(Some)
+            .ok_or_else(|| bad_request_response("invalid X-Client-Info header")),
+        None => Ok(None),
+    }
+}
+
+fn upgrade_required_response(minimum: &str) -> HttpResponse {
+    HttpResponse::build(StatusCode::UPGRADE_REQUIRED).json(error_envelope(
+        &format!("upgrade required, minimum version {}", minimum),
+        ApiError::upgrade_required(minimum),
+    ))
+}
+
 pub async fn subscriptions(
     req: HttpRequest,
     schema: web::Data<Schema>,
@@ -25,82 +200,1120 @@ pub async fn subscriptions(
     stream: web::Payload,
 ) -> Result<HttpResponse, Error> {
     let schema = schema.into_inner();
-    subscriptions_handler(req, stream, schema, |params: Variables| async move {
+    let ip = get_client_ip(&req);
+    let connection = open_connection();
+    let counted = connection.clone();
+    // Clients that don't offer permessage-deflate get the plain frames
+    let deflate = req
+        .headers()
+        .get(SEC_WEBSOCKET_EXTENSIONS)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|offers| negotiate(offers, &SUBSCRIPTION_CONFIG));
+    let stream = match &deflate {
+        Some(_) => {
+            let inflated: Pin<Box<dyn Stream<Item = Result<Bytes, error::PayloadError>>>> =
+                Box::pin(inflate_payload(stream, Inflater::default()));
+            web::Payload::from_request(&req, &mut dev::Payload::from(inflated)).await?
+        }
+        None => stream,
+    };
+    let mut res = subscriptions_handler(req, stream, schema, |params: Variables| async move {
+        // Refused before anything else, ancient clients can't parse the protocol
+        let client = match params.get("clientInfo") {
+            Some(InputValue::Null) | None => None,
+            Some(value) => Some(
+                serde_json::to_value(value)
+                    .ok()
+                    .and_then(parse_client_info)
+                    .ok_or_else(|| error::ErrorBadRequest("Invalid clientInfo"))?,
+            ),
+        };
+        let minimum = get_required_client_upgrade(client.as_ref())
+            .await
+            .map_err(|_| error::ErrorInternalServerError("Client versions unavailable"))?;
+        if let Some(minimum) = minimum {
+            return Err(error::InternalError::new(
+                format!("Upgrade required, minimum version {}", minimum),
+                StatusCode::UPGRADE_REQUIRED,
+            )
+            .into());
+        }
         let authorization = params
             .get("authorization")
             .unwrap_or(params.get("Authorization").unwrap_or(&InputValue::Null));
         let user = match authorization {
             InputValue::Scalar(DefaultScalarValue::String(auth_string)) => {
-                UserToken::parse(&secret, extract_token_from_str(&auth_string))
+                UserToken::parse_session(&secret, extract_token_from_str(&auth_string))
             }
             _ => None,
         };
-        let user_id = match user {
-            Some(id) => id,
+        let (
+            Identity {
+                user_id,
+                impersonator_id,
+            },
+            handle,
+        ) = match user {
+            Some(identity) => identity,
             None => return Err(error::ErrorUnauthorized("Unauthorized")),
         };
-        let ctx = Context { user_id };
-        let config = ConnectionConfig::new(ctx).with_keep_alive_interval(Duration::from_secs(15));
+        // Events follow the tenant of the user
+        let tenant_id = web::block(move || {
+            let conn = DB_POOL.get().unwrap();
+            match impersonator_id {
+                Some(admin_id) => {
+                    write_audit_log(&conn, admin_id, user_id, "subscribe", "").map_err(|_| ())?;
+                }
+                // an admin watching doesn't keep the account active
+                None => {
+                    touch_last_seen(&conn, user_id).map_err(|_| ())?;
+                }
+            }
+            Ok(get_user_tenant(&conn, user_id))
+        })
+        .await
+        .ok()
+        .and_then(|result: Result<Option<i32>, ()>| result.ok())
+        .ok_or_else(|| error::ErrorInternalServerError("Tenant unavailable"))?;
+        // Low-bandwidth clients refetch details of update-type events lazily
+        let compact = matches!(
+            params.get("compact"),
+            Some(InputValue::Scalar(DefaultScalarValue::Boolean(true)))
+        );
+        let features = get_request_features(user_id)
+            .await
+            .map_err(|_| error::ErrorInternalServerError("Features unavailable"))?;
+        let is_minor = get_request_minor(user_id)
+            .await
+            .map_err(|_| error::ErrorInternalServerError("Birthdate unavailable"))?;
+        connection.init(user_id, compact);
+        connection.set_session_id(handle.sid.clone());
+        connection.set_client(client);
+        let ctx = Context {
+            user_id,
+            tenant_id,
+            impersonator_id,
+            secret: secret.to_string(),
+            anon_id: None,
+            ip,
+            connection: Some(connection),
+            features,
+            is_minor,
+            token: Some(handle),
+        };
+        let config =
+            ConnectionConfig::new(ctx).with_keep_alive_interval(SUBSCRIPTION_CONFIG.keep_alive);
         Ok(config) as Result<ConnectionConfig<Context>, Error>
     })
     .await
+    .map_err(|err| {
+        close_connection(counted.id);
+        err
+    })?;
+    let deflater = match deflate {
+        Some((params, answer)) if res.status() == StatusCode::SWITCHING_PROTOCOLS => {
+            res.headers_mut().insert(
+                SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_str(&answer).unwrap(),
+            );
+            counted.set_deflate(true);
+            Some(Deflater::new(&params))
+        }
+        _ => None,
+    };
+    Ok(res
+        .map_body(|_, body| CountedBody {
+            body,
+            connection: counted,
+            deflater,
+        })
+        .map_into_boxed_body())
+}
+
+/// Socket frames of a subscription, unregisters the connection when dropped
+struct CountedBody {
+    body: BoxBody,
+    connection: Arc<SubscriptionConnection>,
+    deflater: Option<Deflater>,
+}
+
+impl MessageBody for CountedBody {
+    type Error = <BoxBody as MessageBody>::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        let this = self.get_mut();
+        match (poll, &mut this.deflater) {
+            (Poll::Ready(Some(Ok(bytes))), Some(deflater)) => {
+                let encoded = deflater.encode(&bytes);
+                this.connection.add_frames(encoded.len(), bytes.len());
+                Poll::Ready(Some(Ok(Bytes::from(encoded))))
+            }
+            (Poll::Ready(Some(Ok(bytes))), None) => {
+                this.connection.add_bytes(bytes.len());
+                Poll::Ready(Some(Ok(bytes)))
+            }
+            (poll, _) => poll,
+        }
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        close_connection(self.connection.id);
+    }
+}
+
+/// Honors `X-Forwarded-For` from trusted reverse proxies only
+pub fn get_client_ip(req: &HttpRequest) -> Option<String> {
+    let forwarded_for = req
+        .headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    resolve_client_ip(
+        req.peer_addr().map(|addr| addr.ip()),
+        forwarded_for,
+        &TRUSTED_PROXIES,
+    )
+    .map(|ip| ip.to_string())
+}
+
+fn get_response_json(res: &GraphQLResponse) -> serde_json::Value {
+    let mut value = serde_json::to_value(res).unwrap_or_default();
+    sanitize_errors(&mut value);
+    value
+}
+
+fn get_content_type(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Every impersonated request is audited, mutations outside the whitelist are refused
+async fn audit_impersonated_request(
+    admin_id: i32,
+    user_id: i32,
+    schema: &Schema,
+    op: &OperationInfo,
+) -> Result<(), HttpResponse> {
+    let allowed =
+        is_allowed_when_impersonated(&schema.schema, &op.query, op.operation_name.as_deref());
+    let detail = json!({
+        "operationName": op.operation_name,
+        "query": op.query,
+        "allowed": allowed,
+    })
+    .to_string();
+    let logged = web::block(move || {
+        let conn = DB_POOL.get().unwrap();
+        write_audit_log(&conn, admin_id, user_id, "request", &detail)
+    })
+    .await;
+    if !matches!(logged, Ok(Ok(()))) {
+        return Err(HttpResponse::ServiceUnavailable().finish());
+    }
+    if !allowed {
+        return Err(HttpResponse::Forbidden().json(error_envelope(
+            "read only while impersonating",
+            ApiError::permission_denied(),
+        )));
+    }
+    Ok(())
+}
+
+/// Routes outside graphql that take an impersonation token are audited too,
+/// with the method and path as the detail
+async fn audit_impersonated_route(
+    req: &HttpRequest,
+    identity: &Identity,
+) -> Result<(), HttpResponse> {
+    let (admin_id, user_id) = match identity.impersonator_id {
+        Some(admin_id) => (admin_id, identity.user_id),
+        None => return Ok(()),
+    };
+    let detail = json!({
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "allowed": true,
+    })
+    .to_string();
+    let logged = web::block(move || {
+        let conn = DB_POOL.get().unwrap();
+        write_audit_log(&conn, admin_id, user_id, "request", &detail)
+    })
+    .await;
+    if !matches!(logged, Ok(Ok(()))) {
+        return Err(HttpResponse::ServiceUnavailable().finish());
+    }
+    Ok(())
+}
+
+/// A malformed key is rejected rather than running the mutation unguarded
+fn get_idempotency_key(req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+    let value = req.headers().get("Idempotency-Key");
+    parse_idempotency_key(value.map(|value| value.as_bytes())).map_err(bad_request_response)
 }
 
 pub async fn graphql(
     req: HttpRequest,
     schema: web::Data<Schema>,
     secret: web::Data<String>,
-    data: web::Json<GraphQLRequest>,
+    body: web::Bytes,
 ) -> impl Responder {
-    let user_id = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
-        Some(id) => id,
-        None => return HttpResponse::Unauthorized().finish(),
+    let _permit = match acquire_graphql_permit() {
+        Ok(permit) => permit,
+        Err(resp) => return resp,
+    };
+    let token = extract_token_from_req(&req);
+    let (
+        Identity {
+            user_id,
+            impersonator_id,
+        },
+        handle,
+        anon_id,
+    ) = match UserToken::parse_session(&secret, &token) {
+        Some((identity, handle)) => (identity, Some(handle), None),
+        None => match AnonToken::parse(&secret, &token) {
+            Some(anon_id) => (
+                Identity {
+                    user_id: 0,
+                    impersonator_id: None,
+                },
+                None,
+                Some(anon_id),
+            ),
+            None => return HttpResponse::Unauthorized().finish(),
+        },
+    };
+    let data = match read_graphql_request(&req, &body).await {
+        Ok(data) => data,
+        Err(resp) => return resp,
+    };
+    let tenant_id = match get_request_tenant(&req, anon_id.is_none().then_some(user_id)).await {
+        Ok(tenant_id) => tenant_id,
+        Err(resp) => return resp,
     };
-    let ctx = Context { user_id };
-    let res = data.execute(&schema, &ctx).await;
+    let client = match get_client_info(&req) {
+        Ok(client) => client,
+        Err(resp) => return resp,
+    };
+    match get_required_client_upgrade(client.as_ref()).await {
+        Ok(Some(minimum)) => return upgrade_required_response(&minimum),
+        Ok(None) => (),
+        Err(resp) => return resp,
+    }
+    let op = serde_json::to_value(&data)
+        .and_then(serde_json::from_value::<OperationInfo>)
+        .unwrap_or_default();
+    if let Err(resp) = check_query_cost(&op.query) {
+        return resp;
+    }
+    let mutation = is_mutation(&op.query, op.operation_name.as_deref());
+
+    if anon_id.is_some()
+        && !is_allowed_for_anonymous(&schema.schema, &op.query, op.operation_name.as_deref())
+    {
+        return HttpResponse::Forbidden().json(error_envelope(
+            "register to continue",
+            ApiError::permission_denied(),
+        ));
+    }
+    if let Some(admin_id) = impersonator_id {
+        if let Err(resp) = audit_impersonated_request(admin_id, user_id, &schema, &op).await {
+            return resp;
+        }
+    }
+    if anon_id.is_none() {
+        if let Err(resp) = check_quota(user_id, mutation).await {
+            return resp;
+        }
+    }
+    let features = match get_request_features(user_id).await {
+        Ok(features) => features,
+        Err(resp) => return resp,
+    };
+    let is_minor = match get_request_minor(user_id).await {
+        Ok(is_minor) => is_minor,
+        Err(resp) => return resp,
+    };
+    let ctx = Context {
+        user_id,
+        tenant_id,
+        impersonator_id,
+        secret: secret.to_string(),
+        anon_id,
+        ip: get_client_ip(&req),
+        connection: None,
+        features,
+        is_minor,
+        token: handle,
+    };
+
+    // Only mutations participate, replays return the stored response
+    let mutation_key = match get_idempotency_key(&req) {
+        Ok(key) => key.filter(|_| mutation),
+        Err(resp) => return resp,
+    };
+    if let Some(key) = mutation_key {
+        let (data, schema, ctx, op) = (&data, &schema, &ctx, &op);
+        let (stored, replayed) = execute_once(user_id, &key, || async move {
+            let res = sample_request(&schema.schema, op, data.execute(schema, ctx)).await;
+            StoredResponse {
+                ok: res.is_ok(),
+                body: get_response_json(&res).to_string(),
+            }
+        })
+        .await;
+        let mut builder = if stored.ok {
+            HttpResponse::Ok()
+        } else {
+            HttpResponse::BadRequest()
+        };
+        return builder
+            .content_type("application/json")
+            .insert_header(("Idempotent-Replayed", replayed.to_string()))
+            .body(stored.body);
+    }
+
+    let res = sample_request(&schema.schema, &op, data.execute(&schema, &ctx)).await;
     if res.is_ok() {
-        HttpResponse::Ok().json(res)
+        HttpResponse::Ok().json(get_response_json(&res))
     } else {
-        HttpResponse::BadRequest().json(res)
+        HttpResponse::BadRequest().json(get_response_json(&res))
+    }
+}
+
+/// Parse the body, a persisted query hash is swapped for the stored query
+/// and a query sent along with its hash is stored
+async fn read_graphql_request(
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<GraphQLRequest, HttpResponse> {
+    let content_type = get_content_type(req);
+    let parse =
+        || parse_graphql_request(content_type, body).map_err(|hint| bad_request_response(&hint));
+    // Most requests carry their query, spare them a second parse
+    let key = b"persistedQuery";
+    if get_mime(content_type) != "application/json"
+        || !body.windows(key.len()).any(|window| window == key)
+    {
+        return parse();
+    }
+    let mut request = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(request) => request,
+        Err(_) => return parse(),
+    };
+    match read_persisted_query(&request).map_err(|hint| bad_request_response(&hint))? {
+        Some(PersistedQuery::Lookup(hash)) => {
+            let query = match get_cached_query(&hash) {
+                Some(query) => Some(query),
+                None => web::block(move || get_persisted_query(&DB_POOL.get().unwrap(), &hash))
+                    .await
+                    .ok()
+                    .and_then(|result| result.ok())
+                    .ok_or_else(|| HttpResponse::InternalServerError().finish())?,
+            };
+            let query = match query {
+                Some(query) => query,
+                None => {
+                    inc_counter("nesbox_persisted_query_total", "miss");
+                    // Apollo clients match the message, then resend with the query
+                    return Err(HttpResponse::Ok().json(error_envelope(
+                        "PersistedQueryNotFound",
+                        ApiError::persisted_query_not_found(),
+                    )));
+                }
+            };
+            inc_counter("nesbox_persisted_query_total", "hit");
+            request["query"] = query.into();
+            serde_json::from_value(request).map_err(|err| bad_request_response(&err.to_string()))
+        }
+        Some(PersistedQuery::Register(hash, query)) => {
+            // Oversized or over the limits documents run, or fail, without being stored
+            let storable = query.len() <= *MAX_PERSISTED_QUERY_BYTES
+                && measure_query(&query).map_or(false, |cost| cost.is_within(&QUERY_LIMITS));
+            if storable && get_cached_query(&hash).is_none() {
+                inc_counter("nesbox_persisted_query_total", "register");
+                let saved = web::block(move || {
+                    save_persisted_query(&DB_POOL.get().unwrap(), &hash, &query)
+                })
+                .await;
+                if let Ok(Err(err)) = saved {
+                    log::warn!("Save persisted query: {}", err);
+                }
+            }
+            parse()
+        }
+        None => parse(),
+    }
+}
+
+/// Refuse documents over the depth or complexity budget before they run
+fn check_query_cost(query: &str) -> Result<(), HttpResponse> {
+    match measure_query(query) {
+        Some(cost) if !cost.is_within(&QUERY_LIMITS) => {
+            inc_counter("nesbox_query_rejected_total", "cost");
+            Err(HttpResponse::BadRequest().json(error_envelope(
+                &format!(
+                    "query too complex, depth {} of {}, complexity {} of {}",
+                    cost.depth, QUERY_LIMITS.depth, cost.complexity, QUERY_LIMITS.complexity
+                ),
+                ApiError::query_too_complex(QUERY_LIMITS.depth, QUERY_LIMITS.complexity),
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Count the request against the daily quota of the user
+async fn check_quota(user_id: i32, mutation: bool) -> Result<(), HttpResponse> {
+    let now = Utc::now();
+    if !is_usage_loaded(user_id, now) {
+        let usage = web::block(move || load_usage(&DB_POOL.get().unwrap(), user_id, now))
+            .await
+            .ok()
+            .and_then(|result| result.ok())
+            .ok_or_else(|| HttpResponse::InternalServerError().finish())?;
+        set_loaded_usage(user_id, now, usage);
     }
+    consume_quota(user_id, mutation, now, *DAILY_LIMITS)
+        .map(|_| ())
+        .map_err(|reset_at| {
+            HttpResponse::TooManyRequests()
+                .insert_header((
+                    "Retry-After",
+                    (reset_at - now).num_seconds().max(1).to_string(),
+                ))
+                .json(error_envelope(
+                    "daily quota exceeded",
+                    ApiError::quota_exceeded(reset_at.timestamp_millis() as f64),
+                ))
+        })
 }
 
 pub async fn graphqlschema(schema: web::Data<Schema>) -> impl Responder {
-    let ctx = Context { user_id: 0 };
+    let ctx = Context {
+        user_id: 0,
+        tenant_id: None,
+        impersonator_id: None,
+        secret: String::new(),
+        anon_id: None,
+        ip: None,
+        connection: None,
+        features: BTreeMap::new(),
+        is_minor: false,
+        token: None,
+    };
     let result = introspect(&schema, &ctx, IntrospectionFormat::default());
     HttpResponse::Ok().json(GraphQLResponse::from_result(result))
 }
 
 pub async fn guestgraphql(
+    req: HttpRequest,
     schema: web::Data<GuestSchema>,
     secret: web::Data<String>,
-    data: web::Json<GraphQLRequest>,
+    body: web::Bytes,
 ) -> impl Responder {
+    let _permit = match acquire_graphql_permit() {
+        Ok(permit) => permit,
+        Err(resp) => return resp,
+    };
+    let data = match read_graphql_request(&req, &body).await {
+        Ok(data) => data,
+        Err(resp) => return resp,
+    };
+    let tenant_id = match get_request_tenant(&req, None).await {
+        Ok(tenant_id) => tenant_id,
+        Err(resp) => return resp,
+    };
+    let token = extract_token_from_req(&req);
     let ctx = GuestContext {
         secret: secret.to_string(),
+        tenant_id,
+        anon_token: (!token.is_empty()).then_some(token),
     };
-    let res = data.execute(&schema, &ctx).await;
+    let op = serde_json::to_value(&data)
+        .and_then(serde_json::from_value::<OperationInfo>)
+        .unwrap_or_default();
+    if let Err(resp) = check_query_cost(&op.query) {
+        return resp;
+    }
+    let res = sample_request(&schema.schema, &op, data.execute(&schema, &ctx)).await;
     if res.is_ok() {
-        HttpResponse::Ok().json(res)
+        HttpResponse::Ok().json(get_response_json(&res))
     } else {
-        HttpResponse::BadRequest().json(res)
+        HttpResponse::BadRequest().json(get_response_json(&res))
     }
 }
 
 pub async fn guestgraphqlschema(schema: web::Data<GuestSchema>) -> impl Responder {
     let ctx = GuestContext {
         secret: String::new(),
+        tenant_id: None,
+        anon_token: None,
     };
     let result = introspect(&schema, &ctx, IntrospectionFormat::default());
     HttpResponse::Ok().json(GraphQLResponse::from_result(result))
 }
 
+// Nonce of the state, ties the callback to the browser that started it
+const OAUTH_COOKIE: &str = "oauth_state";
+
+#[derive(Deserialize)]
+pub struct OauthCallback {
+    code: Option<String>,
+    state: Option<String>,
+    // the user declined, or the provider failed
+    error: Option<String>,
+}
+
+fn get_oauth_provider(name: &str) -> Option<OauthProvider> {
+    OauthProvider::from_str(name)
+        .ok()
+        .filter(|provider| provider.is_configured())
+}
+
+/// Redirect to the provider, a user token in `?token=` links the account to that user
+pub async fn oauth_authorize(
+    req: HttpRequest,
+    path: web::Path<String>,
+    secret: web::Data<String>,
+) -> impl Responder {
+    let provider = match get_oauth_provider(&path) {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let link_user_id = UserToken::parse(&secret, &extract_token_from_req(&req))
+        .filter(|identity| identity.impersonator_id.is_none())
+        .map(|identity| identity.user_id);
+    let (state, nonce) = generate_state(&secret, provider, link_user_id);
+    match provider.authorize_url(&state) {
+        Some(url) => HttpResponse::Found()
+            .insert_header(("Location", url))
+            .cookie(
+                Cookie::build(OAUTH_COOKIE, nonce)
+                    .path("/oauth")
+                    .http_only(true)
+                    .secure(req.connection_info().scheme() == "https")
+                    .same_site(SameSite::Lax)
+                    .finish(),
+            )
+            .finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Tokens of the user signed in by the provider, the same as `login`
+pub async fn oauth_callback(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<OauthCallback>,
+    secret: web::Data<String>,
+) -> impl Responder {
+    let provider = match get_oauth_provider(&path) {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if let Some(error) = &query.error {
+        return bad_request_response(&format!("{} sign in failed: {}", provider, error));
+    }
+    let cookie = req.cookie(OAUTH_COOKIE);
+    let state = query
+        .state
+        .as_deref()
+        .and_then(|state| parse_state(&secret, provider, state))
+        .filter(|state| {
+            cookie.as_ref().map_or(false, |cookie| {
+                Secret::new(cookie.value()).ct_eq(&Secret::new(&state.nonce))
+            })
+        });
+    let (state, code) = match (state, query.code.clone()) {
+        (Some(state), Some(code)) => (state, code),
+        _ => return bad_request_response("invalid or expired oauth state"),
+    };
+    let tenant_id = match get_request_tenant(&req, state.link_user_id).await {
+        Ok(tenant_id) => tenant_id,
+        Err(resp) => return resp,
+    };
+    let secret = secret.to_string();
+    let result = web::block(move || {
+        let profile = provider.exchange_code(&code).map_err(|err| {
+            log::warn!("OAuth {}: {}", provider, err);
+            StatusCode::BAD_GATEWAY
+        })?;
+        let conn = DB_POOL.get().unwrap();
+        Ok(oauth_login(
+            &conn,
+            provider,
+            &profile,
+            state.link_user_id,
+            tenant_id,
+            &secret,
+        )
+        .map(|login| login.to_json())
+        .map_err(|err| {
+            error_envelope(
+                err.message(),
+                serde_json::to_value(err.extensions()).unwrap_or_default(),
+            )
+        }))
+    })
+    .await
+    .unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR));
+    let mut resp = match result {
+        Ok(Ok(login)) => HttpResponse::Ok().json(login),
+        Ok(Err(envelope)) => HttpResponse::BadRequest().json(envelope),
+        Err(status) => HttpResponse::new(status),
+    };
+    if let Some(cookie) = cookie {
+        // Spent, a second callback in this browser is refused
+        let _ = resp.add_removal_cookie(&cookie);
+    }
+    resp
+}
+
+/// Same checks as `--doctor` without third party apis
+pub async fn readyz() -> impl Responder {
+    let results = web::block(|| run_checks(&LiveProbe { pooled: true }, false)).await;
+    match results {
+        Ok(results) if is_healthy(&results) => HttpResponse::Ok().body(render_report(&results)),
+        Ok(results) => HttpResponse::ServiceUnavailable().body(render_report(&results)),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+pub async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
+
+pub async fn dump_games(req: HttpRequest, secret: web::Data<String>) -> impl Responder {
+    let identity = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) => identity,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let user_id = identity.user_id;
+    let admin = web::block(move || {
+        let conn = DB_POOL.get().unwrap();
+        is_admin(&conn, user_id)
+    })
+    .await
+    .unwrap_or_default();
+    if !admin {
+        return HttpResponse::Forbidden().finish();
+    }
+    if let Err(resp) = audit_impersonated_route(&req, &identity).await {
+        return resp;
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header(("X-Stream-Error-Sentinel", ERROR_SENTINEL))
+        .streaming(stream_json_array(
+            |cursor| {
+                let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+                get_games_after(&conn, cursor.unwrap_or(0), 500).map_err(|err| err.to_string())
+            },
+            |game: &ScGame| game.id,
+        ))
+}
+
+pub async fn export_data(req: HttpRequest, secret: web::Data<String>) -> impl Responder {
+    let identity = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) => identity,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(resp) = audit_impersonated_route(&req, &identity).await {
+        return resp;
+    }
+    let user_id = identity.user_id;
+    let data = web::block(move || export_personal_data(&DB_POOL.get().unwrap(), user_id)).await;
+    match data {
+        Ok(Ok(data)) => HttpResponse::Ok()
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"nesbox.json\"",
+            ))
+            .json(data),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportQuery {
+    #[serde(default)]
+    dry_run: bool,
+    // playtime is added to the local records unless skipped
+    #[serde(default)]
+    skip_records: bool,
+}
+
+/// Merges an export of another instance into the account, the body limit is `MAX_IMPORT_BYTES`
+pub async fn import_data(
+    req: HttpRequest,
+    query: web::Query<ImportQuery>,
+    secret: web::Data<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let identity = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) => identity,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if identity.impersonator_id.is_some() && !query.dry_run {
+        return HttpResponse::Forbidden().json(error_envelope(
+            "read only while impersonating",
+            ApiError::permission_denied(),
+        ));
+    }
+    if let Err(resp) = audit_impersonated_route(&req, &identity).await {
+        return resp;
+    }
+    let user_id = identity.user_id;
+    let data = match parse_personal_data(&body) {
+        Ok(data) => data,
+        Err(hint) => return bad_request_response(&hint),
+    };
+    let ImportQuery {
+        dry_run,
+        skip_records,
+    } = query.into_inner();
+    let report = web::block(move || {
+        let conn = DB_POOL.get().unwrap();
+        import_personal_data(&conn, user_id, &data, skip_records, dry_run)
+    })
+    .await;
+    match report {
+        Ok(Ok(report)) => HttpResponse::Ok().json(report),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Admin only, `multipart/form-data` with the zipped rom as a file part.
+/// The returned `rom` and `romHash` go into `createGame`
+pub async fn upload_rom(
+    req: HttpRequest,
+    secret: web::Data<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let user_id = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) if identity.impersonator_id.is_none() => identity.user_id,
+        Some(_) => {
+            return HttpResponse::Forbidden().json(error_envelope(
+                "read only while impersonating",
+                ApiError::permission_denied(),
+            ))
+        }
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let admin = web::block(move || DB_POOL.get().map_or(false, |conn| is_admin(&conn, user_id)))
+        .await
+        .unwrap_or_default();
+    if !admin {
+        return HttpResponse::Forbidden()
+            .json(error_envelope("admin only", ApiError::permission_denied()));
+    }
+    let storage = match get_storage() {
+        Some(storage) => storage,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let data = match read_multipart_file(&content_type, &body)
+        .and_then(|data| check_rom(&data).map(|_| data))
+    {
+        Ok(data) => data,
+        Err(hint) => return bad_request_response(&hint),
+    };
+    let size = data.len();
+    match web::block(move || store_uploaded_rom(storage, &data)).await {
+        Ok(Ok((url, hash, duplicate))) => {
+            inc_counter(
+                "nesbox_rom_upload_total",
+                if duplicate { "duplicate" } else { "stored" },
+            );
+            HttpResponse::Ok().json(json!({
+                "rom": url,
+                "romHash": hash,
+                "size": size,
+                "duplicate": duplicate,
+            }))
+        }
+        Ok(Err(err)) => {
+            log::error!("Store rom of {}: {}", user_id, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn uploaded_rom(path: web::Path<String>) -> impl Responder {
+    let hash = path.into_inner();
+    if !is_rom_hash(&hash) {
+        return HttpResponse::NotFound().finish();
+    }
+    serve_stored(get_upload_key(&hash), "application/zip").await
+}
+
+/// Objects never change under their key, a changed file gets a new url
+async fn serve_stored(key: String, content_type: &'static str) -> HttpResponse {
+    if let Some(resp) = redirect_to_storage(&key) {
+        return resp;
+    }
+    match web::block(move || get_storage().and_then(|storage| read_all(storage, &key).ok())).await {
+        Ok(Some(data)) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("Cache-Control", "public, max-age=31536000"))
+            .body(data),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Large downloads go straight to the storage backend when it can presign
+fn redirect_to_storage(key: &str) -> Option<HttpResponse> {
+    let url = get_storage()?.presigned_url(key, Duration::from_secs(5 * 60))?;
+    Some(
+        HttpResponse::Found()
+            .insert_header(("Location", url))
+            .finish(),
+    )
+}
+
+pub async fn rom(path: web::Path<i32>) -> impl Responder {
+    serve_stored(get_rom_key(path.into_inner()), "application/zip").await
+}
+
+pub async fn screenshot(
+    req: HttpRequest,
+    path: web::Path<(i32, String, String)>,
+) -> impl Responder {
+    let (gid, hash, size) = path.into_inner();
+    let webp = req
+        .headers()
+        .get("accept")
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |accept| accept.contains("image/webp"));
+    let (key, content_type) =
+        match web::block(move || load_screenshot(gid, &hash, &size, webp)).await {
+            Ok(Some(cached)) => cached,
+            _ => return HttpResponse::NotFound().finish(),
+        };
+    if let Some(resp) = redirect_to_storage(&key) {
+        return resp;
+    }
+    match web::block(move || get_storage().and_then(|storage| read_all(storage, &key).ok())).await {
+        // The hash in the path changes with the upstream url
+        Ok(Some(data)) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("Cache-Control", "public, max-age=31536000"))
+            .insert_header(("Vary", "Accept"))
+            .body(data),
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// `multipart/form-data` with the image as a file part, the body limit is `MAX_AVATAR_BYTES`
+pub async fn upload_avatar(
+    req: HttpRequest,
+    secret: web::Data<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let user_id = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) if identity.impersonator_id.is_none() => identity.user_id,
+        Some(_) => {
+            return HttpResponse::Forbidden().json(error_envelope(
+                "read only while impersonating",
+                ApiError::permission_denied(),
+            ))
+        }
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let storage = match get_storage() {
+        Some(storage) => storage,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let avatar = match web::block(move || {
+        render_avatar(&read_multipart_file(&content_type, &body)?)
+    })
+    .await
+    {
+        Ok(Ok(avatar)) => avatar,
+        Ok(Err(hint)) => return bad_request_response(&hint),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let saved = web::block(move || {
+        let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+        save_avatar(&conn, storage, user_id, &avatar)
+    })
+    .await;
+    match saved {
+        Ok(Ok(url)) => HttpResponse::Ok().json(json!({ "avatar": url })),
+        Ok(Err(err)) => {
+            log::error!("Save avatar {}: {}", user_id, err);
+            HttpResponse::InternalServerError().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// One chunk of a `beginStateUpload` upload, `Content-Range` says where it goes
+pub async fn upload_state_chunk(
+    req: HttpRequest,
+    path: web::Path<String>,
+    secret: web::Data<String>,
+    body: web::Bytes,
+) -> impl Responder {
+    let user_id = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) if identity.impersonator_id.is_none() => identity.user_id,
+        Some(_) => {
+            return HttpResponse::Forbidden().json(error_envelope(
+                "read only while impersonating",
+                ApiError::permission_denied(),
+            ))
+        }
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let range = req
+        .headers()
+        .get("content-range")
+        .and_then(|value| value.to_str().ok());
+    let (start, total) = match parse_content_range(range, body.len() as u64) {
+        Some(range) => range,
+        None => {
+            return bad_request_response(
+                "expected `Content-Range: bytes <start>-<end>/<total>` matching the body",
+            )
+        }
+    };
+    let upload_id = path.into_inner();
+    let uploaded = web::block(move || {
+        DB_POOL
+            .get()
+            .map(|conn| put_state_chunk(&conn, user_id, &upload_id, start, total, &body))
+    })
+    .await;
+    let err = match uploaded {
+        Ok(Ok(Ok(upload))) => return HttpResponse::Ok().json(upload),
+        Ok(Ok(Err(err))) => err,
+        _ => return HttpResponse::InternalServerError().finish(),
+    };
+    let extensions = err.extensions().to_owned();
+    if extensions.is_null() {
+        log::error!("Upload state chunk of {}: {}", user_id, err.message());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut resp = if extensions == ApiError::not_found() {
+        HttpResponse::NotFound()
+    } else {
+        HttpResponse::BadRequest()
+    };
+    resp.json(error_envelope(err.message(), extensions))
+}
+
+/// Avatars and room screenshots, the route's app data picks which
+pub async fn hashed_blob(
+    path: web::Path<(i32, String)>,
+    blobs: web::Data<&'static HashedBlobs>,
+) -> impl Responder {
+    let (owner, hash) = path.into_inner();
+    if !is_blob_hash(&hash) {
+        return HttpResponse::NotFound().finish();
+    }
+    serve_stored(blobs.get_key(owner, &hash), blobs.content_type).await
+}
+
+pub async fn attachment(
+    req: HttpRequest,
+    path: web::Path<i32>,
+    secret: web::Data<String>,
+) -> impl Responder {
+    let identity = match UserToken::parse(&secret, &extract_token_from_req(&req)) {
+        Some(identity) => identity,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(resp) = audit_impersonated_route(&req, &identity).await {
+        return resp;
+    }
+    let aid = path.into_inner();
+    let cached = match web::block(move || load_attachment(aid)).await {
+        Ok(Some(cached)) => cached,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    if let Some(resp) = redirect_to_storage(&cached.key) {
+        return resp;
+    }
+    let data = web::block(move || {
+        get_storage()
+            .and_then(|storage| read_all(storage, &cached.key).ok())
+            .map(|data| (data, cached.content_type))
+    })
+    .await;
+    let (data, content_type) = match data {
+        Ok(Some(data)) => data,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    // Seeking in the audio player
+    let len = data.len() as u64;
+    let range = req
+        .headers()
+        .get("range")
+        .and_then(|value| value.to_str().ok());
+    match parse_range(range, len) {
+        Ok(Some((start, end))) => HttpResponse::PartialContent()
+            .content_type(content_type)
+            .insert_header(("Cache-Control", "private, max-age=86400"))
+            .insert_header(("Accept-Ranges", "bytes"))
+            .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, len)))
+            .body(data[start as usize..=end as usize].to_vec()),
+        Ok(None) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header(("Cache-Control", "private, max-age=86400"))
+            .insert_header(("Accept-Ranges", "bytes"))
+            .body(data),
+        Err(()) => HttpResponse::RangeNotSatisfiable()
+            .insert_header(("Content-Range", format!("bytes */{}", len)))
+            .finish(),
+    }
+}
+
 pub async fn webhook(
     req: HttpRequest,
     body: web::Bytes,
     secret: web::Data<String>,
 ) -> impl Responder {
-    let payload: GithubPayload = serde_json::from_slice(&body).unwrap();
+    let event = req
+        .headers()
+        .get("X-GitHub-Event")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("issues");
+    if event == "repository" {
+        return webhook_repository(&req, &secret, &body).await;
+    }
+
+    let payload: GithubPayload = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
 
     log::debug!("Webhook payload: {:?}", payload);
 
@@ -108,44 +1321,34 @@ pub async fn webhook(
         return HttpResponse::Unauthorized().finish();
     }
 
-    let conn = DB_POOL.get().unwrap();
+    // Processed in delivery order per issue, GitHub only needs the receipt
+    let response = HttpResponse::Accepted().json(&payload);
+    ISSUE_QUEUE.enqueue(payload.issue.number, payload);
+    response
+}
 
-    let action = payload.action.as_str();
-    let state = payload.issue.state.as_str();
-    let closed = action == "closed";
-    let edited = action == "edited" && state == "closed";
-    let labeled = action == "labeled" && state == "closed";
-    if payload
-        .issue
-        .labels
-        .iter()
-        .find(|label| label.name == "duplicate")
-        .is_none()
-    {
-        if closed || edited || labeled {
-            let (old_name, sc_game) = get_sc_game(&payload);
-            if sc_game.rom.is_empty() {
-                log::debug!("Not rom");
-            } else {
-                match get_game_from_name(&conn, &old_name) {
-                    Some(game) => {
-                        update_game(&conn, game.id, &sc_game).ok();
-                    }
-                    None => {
-                        if closed {
-                            if let Ok(game) = create_game(&conn, &sc_game) {
-                                notify_all(
-                                    ScNotifyMessageBuilder::default()
-                                        .new_game(game)
-                                        .build()
-                                        .unwrap(),
-                                );
-                            }
-                        }
-                    }
-                };
+async fn webhook_repository(req: &HttpRequest, secret: &str, body: &[u8]) -> HttpResponse {
+    let payload: GithubRepoPayload = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    if !validate(req, secret, body) || !payload.is_owner() {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if let Some((old, new)) = payload.get_rename() {
+        let result = web::block(move || process_repository_rename(&old, &new))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|result| result);
+        match result {
+            Ok(count) => log::info!("Webhook rename relinked {} games", count),
+            // GitHub redelivers on failure
+            Err(err) => {
+                log::error!("Webhook rename: {}", err);
+                return HttpResponse::InternalServerError().finish();
             }
         }
     }
-    HttpResponse::Ok().json(payload)
+    HttpResponse::Accepted().finish()
 }
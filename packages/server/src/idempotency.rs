@@ -0,0 +1,410 @@
+use juniper::parser::parse_document_source;
+use juniper::{DefaultScalarValue, Definition, OperationType, SchemaType, Selection};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredResponse {
+    pub ok: bool,
+    pub body: String,
+}
+
+struct Slot {
+    // serialize concurrent first requests with the same key
+    response: Arc<tokio::sync::Mutex<Option<StoredResponse>>>,
+    expires_at: Instant,
+}
+
+lazy_static! {
+    static ref IDEMPOTENCY_TTL: Duration = Duration::from_secs(
+        env::var("IDEMPOTENCY_TTL_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(600)
+    );
+    // (user id, key) -> response
+    static ref SLOTS: Mutex<HashMap<(i32, String), Slot>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+}
+
+/// A missing header is `None`, a present one must be 1 to 255 visible
+/// ASCII characters
+pub fn parse_idempotency_key(value: Option<&[u8]>) -> Result<Option<String>, &'static str> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let key = std::str::from_utf8(value)
+        .map_err(|_| "Idempotency-Key must be ASCII")?
+        .trim();
+    if key.is_empty() || key.len() > 255 {
+        return Err("Idempotency-Key must be 1 to 255 characters");
+    }
+    if !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return Err("Idempotency-Key must be visible ASCII");
+    }
+    Ok(Some(key.to_owned()))
+}
+
+#[derive(Deserialize, Default)]
+pub struct OperationInfo {
+    #[serde(default)]
+    pub query: String,
+    #[serde(rename = "operationName")]
+    pub operation_name: Option<String>,
+}
+
+/// End of the string starting at `start`, just past its opening quote
+fn skip_string(query: &str, start: usize) -> usize {
+    let mut chars = query[start..].char_indices();
+    while let Some((n, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => return start + n + 1,
+            _ => (),
+        }
+    }
+    query.len()
+}
+
+/// End of the block string starting at `start`, just past its opening `"""`,
+/// `\"""` is its only escape
+fn skip_block_string(query: &str, start: usize) -> usize {
+    let mut chars = query[start..].char_indices();
+    while let Some((n, c)) = chars.next() {
+        match c {
+            '\\' if query[start + n + 1..].starts_with(r#"""""#) => {
+                chars.nth(2);
+            }
+            '"' if query[start + n..].starts_with(r#"""""#) => return start + n + 3,
+            _ => (),
+        }
+    }
+    query.len()
+}
+
+/// Skip ignored tokens (whitespace, commas, comments and the byte order mark) and
+/// strings, return next name or punctuator with its end
+pub fn next_token(query: &str, start: usize) -> Option<(&str, usize)> {
+    let mut i = start;
+    while let Some(c) = query[i..].chars().next() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => i += c.len_utf8(),
+            '#' => i = query[i..].find('\n').map_or(query.len(), |n| i + n),
+            '"' if query[i..].starts_with(r#"""""#) => i = skip_block_string(query, i + 3),
+            '"' => i = skip_string(query, i + 1),
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let end = query[i..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .map_or(query.len(), |n| i + n);
+                return Some((&query[i..end], end));
+            }
+            c => return Some((&query[i..i + c.len_utf8()], i + c.len_utf8())),
+        }
+    }
+    None
+}
+
+/// Whether the selected operation of the document is a mutation
+pub fn is_mutation(query: &str, operation_name: Option<&str>) -> bool {
+    let mut depth = 0;
+    let mut in_definition = false;
+    let mut pos = 0;
+    while let Some((token, end)) = next_token(query, pos) {
+        pos = end;
+        match token {
+            // anonymous query shorthand
+            "{" if depth == 0 && !in_definition && operation_name.is_none() => return false,
+            "{" | "(" => depth += 1,
+            "}" | ")" => {
+                depth -= 1;
+                if depth == 0 && token == "}" {
+                    in_definition = false;
+                }
+            }
+            "fragment" if depth == 0 => in_definition = true,
+            "query" | "mutation" | "subscription" if depth == 0 && !in_definition => {
+                in_definition = true;
+                let name = next_token(query, pos)
+                    .map(|(name, _)| name)
+                    .filter(|name| is_name(name));
+                if operation_name.is_none() || operation_name == name {
+                    return token == "mutation";
+                }
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+pub fn is_name(token: &str) -> bool {
+    token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Type and top level field names of the operation juniper would run, read by
+/// its own parser with fragments expanded. `None` when the document doesn't
+/// parse, the operation can't be told or a fragment is unknown or cyclic
+pub fn get_root_fields(
+    schema: &SchemaType<DefaultScalarValue>,
+    query: &str,
+    operation_name: Option<&str>,
+) -> Option<(OperationType, Vec<String>)> {
+    let document = parse_document_source(query, schema).ok()?;
+    let mut operations = document.iter().filter_map(|definition| match definition {
+        Definition::Operation(operation) => Some(&operation.item),
+        Definition::Fragment(_) => None,
+    });
+    let operation = match operation_name {
+        Some(name) => operations
+            .find(|operation| operation.name.as_ref().map(|name| name.item) == Some(name))?,
+        // Several operations need a name
+        None => match (operations.next(), operations.next()) {
+            (Some(operation), None) => operation,
+            _ => return None,
+        },
+    };
+    let fragments = document
+        .iter()
+        .filter_map(|definition| match definition {
+            Definition::Fragment(fragment) => Some((
+                fragment.item.name.item,
+                fragment.item.selection_set.as_slice(),
+            )),
+            Definition::Operation(_) => None,
+        })
+        .collect();
+
+    let mut fields = Vec::new();
+    collect_root_fields(
+        &operation.selection_set,
+        &fragments,
+        &mut Vec::new(),
+        &mut fields,
+    )?;
+    Some((operation.operation_type, fields))
+}
+
+fn collect_root_fields<'a, 'b>(
+    selections: &'b [Selection<'a, DefaultScalarValue>],
+    fragments: &HashMap<&'a str, &'b [Selection<'a, DefaultScalarValue>]>,
+    // fragments being expanded
+    spreading: &mut Vec<&'a str>,
+    fields: &mut Vec<String>,
+) -> Option<()> {
+    for selection in selections {
+        match selection {
+            Selection::Field(field) => fields.push(field.item.name.item.to_owned()),
+            Selection::InlineFragment(fragment) => {
+                collect_root_fields(&fragment.item.selection_set, fragments, spreading, fields)?
+            }
+            Selection::FragmentSpread(spread) => {
+                let name = spread.item.name.item;
+                if spreading.contains(&name) {
+                    return None;
+                }
+                spreading.push(name);
+                let fragment = fragments.get(name).copied()?;
+                collect_root_fields(fragment, fragments, spreading, fields)?;
+                spreading.pop();
+            }
+        }
+    }
+    Some(())
+}
+
+fn get_slot(user_id: i32, key: &str) -> Arc<tokio::sync::Mutex<Option<StoredResponse>>> {
+    let now = Instant::now();
+    let mut slots = SLOTS.lock().unwrap();
+    slots.retain(|_, slot| slot.expires_at > now);
+    slots
+        .entry((user_id, key.to_owned()))
+        .or_insert_with(|| Slot {
+            response: Arc::new(tokio::sync::Mutex::new(None)),
+            expires_at: now + *IDEMPOTENCY_TTL,
+        })
+        .response
+        .clone()
+}
+
+/// Execute once per user and key within ttl, replays return the stored response.
+/// Return the response and whether it is a replay
+pub async fn execute_once<F, Fut>(user_id: i32, key: &str, execute: F) -> (StoredResponse, bool)
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = StoredResponse>,
+{
+    let slot = get_slot(user_id, key);
+    let mut stored = slot.lock().await;
+    if let Some(response) = stored.as_ref() {
+        return (response.clone(), true);
+    }
+    let response = execute().await;
+    *stored = Some(response.clone());
+    (response, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::*;
+    use crate::schemas::root::create_schema;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn detect_mutation() {
+        assert!(is_mutation("mutation { leaveRoom }", None));
+        assert!(is_mutation(
+            "# comment\n mutation Leave { leaveRoom }",
+            None
+        ));
+        assert!(!is_mutation("{ account { id } }", None));
+        assert!(!is_mutation("query Q { account { id } }", None));
+        let doc = "query Q { account { id } } mutation M { leaveRoom }";
+        assert!(is_mutation(doc, Some("M")));
+        assert!(!is_mutation(doc, Some("Q")));
+    }
+
+    #[test]
+    fn non_ascii_tokens() {
+        let tokens = |query| {
+            let mut tokens = Vec::new();
+            let mut pos = 0;
+            while let Some((token, end)) = next_token(query, pos) {
+                tokens.push(token);
+                pos = end;
+            }
+            tokens
+        };
+        assert_eq!(
+            tokens("\u{feff}{ games(name: \"游戏\") }"),
+            ["{", "games", "(", "name", ":", ")", "}"]
+        );
+        assert_eq!(tokens("{ é ∑ }"), ["{", "é", "∑", "}"]);
+        assert_eq!(tokens("# 注释\n{ a }"), ["{", "a", "}"]);
+        assert!(is_mutation("\u{feff}mutation { leaveRoom }", None));
+        // A block string hides what looks like an operation
+        assert_eq!(
+            tokens(r#"{ a(t: """ \""" } mutation { b } """) }"#),
+            ["{", "a", "(", "t", ":", ")", "}"]
+        );
+        assert!(!is_mutation(
+            r#"{ a(t: """ " } mutation { b } """) }"#,
+            None
+        ));
+        assert_eq!(tokens("{ a(t: \"unterminated"), ["{", "a", "(", "t", ":"]);
+    }
+
+    #[test]
+    fn root_fields() {
+        let schema = create_schema();
+        let fields = |query, name| {
+            get_root_fields(&schema.schema, query, name).map(|(operation_type, fields)| {
+                let fields: Vec<_> = fields.iter().map(String::as_str).collect();
+                (operation_type, fields.join(","))
+            })
+        };
+        let mutation = |fields: &str| Some((OperationType::Mutation, fields.to_owned()));
+        assert_eq!(
+            fields("mutation { activityPing }", None),
+            mutation("activityPing")
+        );
+        assert_eq!(
+            fields(
+                "mutation M($id: Int) { a: leaveRoom b: playHeartbeat(input: { gameId: $id }) { x } }",
+                None
+            ),
+            mutation("leaveRoom,playHeartbeat")
+        );
+        assert_eq!(
+            fields(
+                "query Q { account { id } } mutation M { leaveRoom @skip(if: false) }",
+                Some("M")
+            ),
+            mutation("leaveRoom")
+        );
+        assert_eq!(
+            fields("{ account { id } }", None),
+            Some((OperationType::Query, "account".to_owned()))
+        );
+        // Fragments are expanded
+        assert_eq!(
+            fields(
+                "mutation { ...F ... on MutationRoot { leaveRoom } } fragment F on MutationRoot { ...G } fragment G on MutationRoot { activityPing }",
+                None
+            ),
+            mutation("activityPing,leaveRoom")
+        );
+        assert_eq!(fields("mutation { ...F }", None), None);
+        assert_eq!(
+            fields(
+                "{ ...F } fragment F on QueryRoot { ...G } fragment G on QueryRoot { ...F }",
+                None
+            ),
+            None
+        );
+        // Read like the executor does
+        assert_eq!(
+            fields("\u{feff}mutation { deleteAccount }", None),
+            mutation("deleteAccount")
+        );
+        assert_eq!(fields("query A { a } query B { b }", None), None);
+        assert_eq!(fields("mutation {", None), None);
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        assert_eq!(parse_idempotency_key(None), Ok(None));
+        assert_eq!(
+            parse_idempotency_key(Some(b" abc-123 ")),
+            Ok(Some("abc-123".to_owned()))
+        );
+        assert!(parse_idempotency_key(Some(b"")).is_err());
+        assert!(parse_idempotency_key(Some("a".repeat(256).as_bytes())).is_err());
+        assert!(parse_idempotency_key(Some(b"a b")).is_err());
+        assert!(parse_idempotency_key(Some(&[0xff])).is_err());
+    }
+
+    #[actix_web::test]
+    async fn replay_returns_same_body() {
+        let count = &AtomicUsize::new(0);
+        let execute = || async move {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            StoredResponse {
+                ok: true,
+                body: format!("body-{}", n),
+            }
+        };
+
+        let (first, replayed) = execute_once(1, "replay-key", execute).await;
+        assert!(!replayed);
+        let (second, replayed) = execute_once(1, "replay-key", execute).await;
+        assert!(replayed);
+        assert_eq!(first, second);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn same_key_different_users() {
+        let count = &AtomicUsize::new(0);
+        let execute = || async move {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            StoredResponse {
+                ok: true,
+                body: format!("body-{}", n),
+            }
+        };
+
+        let (first, _) = execute_once(2, "shared-key", execute).await;
+        let (second, replayed) = execute_once(3, "shared-key", execute).await;
+        assert!(!replayed);
+        assert_ne!(first, second);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,395 @@
+use diesel::Connection;
+use juniper::FieldError;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::root::DB_POOL;
+use crate::github::{get_sc_game, GithubPayload};
+use crate::metrics;
+use crate::outbox::{wake_outbox, write_outbox_event, OutboxEvent};
+use crate::rom::cache_rom;
+use crate::schemas::{
+    game::{
+        create_game, get_game_from_name, update_game, validate_rom_url, GameLookupError,
+        ScGameSource,
+    },
+    game_version::ScGameVersionSource,
+    github_repo::{
+        get_issue_game_ids, is_configured_repo, relink_issue, rename_repo, write_github_delivery,
+    },
+    webhook::{enqueue_webhook_event, write_github_dead_letter, ScWebhookEvent},
+};
+
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Items of a key are processed one by one in enqueue order, keys run in parallel
+pub struct KeyedQueue<T> {
+    // metrics label
+    name: &'static str,
+    // the front item is in progress, a key is present while its worker runs
+    pending: Mutex<HashMap<i32, VecDeque<(Arc<T>, Instant)>>>,
+    process: fn(&T) -> Result<(), String>,
+    // called after the last failed attempt
+    dead_letter: fn(&T, &str, u32),
+    // doubled on each retry
+    retry_delay: Duration,
+}
+
+impl<T: Send + Sync + 'static> KeyedQueue<T> {
+    pub fn new(
+        name: &'static str,
+        process: fn(&T) -> Result<(), String>,
+        dead_letter: fn(&T, &str, u32),
+        retry_delay: Duration,
+    ) -> Self {
+        KeyedQueue {
+            name,
+            pending: Mutex::new(HashMap::new()),
+            process,
+            dead_letter,
+            retry_delay,
+        }
+    }
+
+    fn set_depth(&self, pending: &HashMap<i32, VecDeque<(Arc<T>, Instant)>>) {
+        let depth: usize = pending.values().map(|queue| queue.len()).sum();
+        metrics::set_gauge("nesbox_issue_queue_depth", self.name, depth as f64);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|queue| queue.len())
+            .sum()
+    }
+
+    pub fn enqueue(&'static self, key: i32, item: T) {
+        let mut pending = self.pending.lock().unwrap();
+        let queue = pending.entry(key).or_default();
+        queue.push_back((Arc::new(item), Instant::now()));
+        // Otherwise the running worker picks it up
+        if queue.len() == 1 {
+            tokio::spawn(self.drain(key));
+        }
+        self.set_depth(&pending);
+    }
+
+    async fn drain(&'static self, key: i32) {
+        loop {
+            let front = self
+                .pending
+                .lock()
+                .unwrap()
+                .get(&key)
+                .and_then(|queue| queue.front().cloned());
+            let (item, enqueued_at) = match front {
+                Some(front) => front,
+                None => return,
+            };
+            metrics::observe(
+                "nesbox_issue_queue_lag_seconds",
+                enqueued_at.elapsed().as_secs_f64(),
+            );
+            self.run(item).await;
+
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(queue) = pending.get_mut(&key) {
+                queue.pop_front();
+                if queue.is_empty() {
+                    pending.remove(&key);
+                }
+            }
+            self.set_depth(&pending);
+        }
+    }
+
+    async fn run(&self, item: Arc<T>) {
+        let process = self.process;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let target = item.clone();
+            let result = tokio::task::spawn_blocking(move || process(&target))
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()));
+            match result {
+                Ok(()) => {
+                    metrics::inc_counter("nesbox_issue_queue_processed_total", self.name);
+                    return;
+                }
+                Err(err) if attempts < MAX_ATTEMPTS => {
+                    log::warn!("Queue {} attempt {}: {}", self.name, attempts, err);
+                    tokio::time::sleep(self.retry_delay * 2u32.pow(attempts - 1)).await;
+                }
+                Err(err) => {
+                    log::error!("Queue {} gave up: {}", self.name, err);
+                    metrics::inc_counter("nesbox_issue_queue_dead_total", self.name);
+                    let dead_letter = self.dead_letter;
+                    if let Err(err) =
+                        tokio::task::spawn_blocking(move || dead_letter(&item, &err, attempts))
+                            .await
+                    {
+                        log::error!("Queue {} dead letter: {:?}", self.name, err);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    // Keyed by issue number, a `reopened` never overtakes its earlier `closed`
+    pub static ref ISSUE_QUEUE: KeyedQueue<GithubPayload> = KeyedQueue::new(
+        "github",
+        process_issue_event,
+        dead_letter_issue_event,
+        Duration::from_secs(2),
+    );
+}
+
+/// Create or update the game of a closed issue, `Err` is retried
+fn process_issue_event(payload: &GithubPayload) -> Result<(), String> {
+    if payload.action == "transferred" {
+        return process_issue_transfer(payload);
+    }
+    let action = payload.action.as_str();
+    let state = payload.issue.state.as_str();
+    let closed = action == "closed";
+    let edited = action == "edited" && state == "closed";
+    let labeled = (action == "labeled" || action == "unlabeled") && state == "closed";
+    let duplicate = payload
+        .issue
+        .labels
+        .iter()
+        .any(|label| label.name == "duplicate");
+    if duplicate || !(closed || edited || labeled) {
+        return Ok(());
+    }
+
+    let (old_name, sc_game) = get_sc_game(payload);
+    if sc_game.rom.is_empty() {
+        log::debug!("Not rom");
+        return Ok(());
+    }
+    if let Err(err) = validate_rom_url(&sc_game.rom) {
+        // Retrying doesn't fix the issue body
+        log::error!("Webhook rom error: {}", err.message());
+        return Ok(());
+    }
+
+    let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+    // A title change is looked up by the previous title
+    match get_game_from_name(&conn, &old_name, sc_game.issue_number) {
+        // Owned by the upstream catalog
+        Ok(game) if game.source == ScGameSource::Federated => {
+            log::info!("Skip federated game {}", game.id);
+        }
+        Ok(game) => {
+            let new_game = conn
+                .transaction(|| {
+                    let new_game =
+                        update_game(&conn, game.id, &sc_game, ScGameVersionSource::Webhook)?;
+                    enqueue_webhook_event(&conn, ScWebhookEvent::GameUpdated, &json!(new_game))?;
+                    let game_id = new_game.id;
+                    write_outbox_event(&conn, &OutboxEvent::GameUpdated { game_id })?;
+                    if game.deprecation_reason.is_none() && new_game.deprecation_reason.is_some() {
+                        write_outbox_event(&conn, &OutboxEvent::DeprecateGame { game_id })?;
+                    }
+                    Ok::<_, FieldError>(new_game)
+                })
+                .map_err(|err| err.message().to_owned())?;
+            wake_outbox();
+            if !new_game.rom_ready {
+                cache_rom(new_game.id, new_game.rom.clone(), sc_game.rom_hash);
+            }
+        }
+        Err(GameLookupError::NotFound) if closed => {
+            let game = conn
+                .transaction(|| {
+                    let game = create_game(&conn, &sc_game, ScGameVersionSource::Webhook)?;
+                    enqueue_webhook_event(&conn, ScWebhookEvent::GameCreated, &json!(game))?;
+                    write_outbox_event(&conn, &OutboxEvent::GameCreated { game_id: game.id })?;
+                    Ok::<_, FieldError>(game)
+                })
+                .map_err(|err| err.message().to_owned())?;
+            wake_outbox();
+            cache_rom(game.id, game.rom.clone(), sc_game.rom_hash);
+        }
+        Err(GameLookupError::NotFound) => (),
+        // Retrying doesn't tell the games apart, an admin links the issue
+        Err(GameLookupError::Ambiguous(ids)) => {
+            let err = format!("games {:?} are named {}", ids, old_name);
+            log::error!("Webhook ambiguous name: {}", err);
+            dead_letter_issue_event(payload, &err, 1);
+        }
+        Err(GameLookupError::Database(err)) => return Err(err),
+    }
+    Ok(())
+}
+
+/// Games follow the issue into a configured repository, a transfer anywhere
+/// else is left for an admin so the games don't point to an unknown place
+fn process_issue_transfer(payload: &GithubPayload) -> Result<(), String> {
+    let (number, url, repository) = match payload.get_transfer() {
+        Some(transfer) => transfer,
+        None => return Ok(()),
+    };
+    let old_url = &payload.issue.html_url;
+    let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+    if !is_configured_repo(&conn, &repository).map_err(|err| err.to_string())? {
+        let ids = get_issue_game_ids(&conn, old_url).map_err(|err| err.to_string())?;
+        let err = format!(
+            "issue {} of games {:?} transferred to unconfigured {}",
+            payload.issue.number, ids, repository
+        );
+        log::error!("Webhook transfer: {}", err);
+        dead_letter_issue_event(payload, &err, 1);
+        write_github_delivery(&conn, "issues", "transferred", &repository, 0, &err)
+            .map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+
+    let ids = conn
+        .transaction(|| {
+            let ids = relink_issue(&conn, old_url, number, &url)?;
+            for game_id in &ids {
+                write_outbox_event(&conn, &OutboxEvent::GameUpdated { game_id: *game_id })?;
+            }
+            let detail = format!("{} -> {}, games {:?}", old_url, url, ids);
+            write_github_delivery(
+                &conn,
+                "issues",
+                "transferred",
+                &repository,
+                ids.len(),
+                &detail,
+            )?;
+            Ok::<_, diesel::result::Error>(ids)
+        })
+        .map_err(|err| err.to_string())?;
+    if !ids.is_empty() {
+        wake_outbox();
+    }
+    Ok(())
+}
+
+/// Issue urls of a `renamed` repository, GitHub redirects the old ones until
+/// the name is reused
+pub fn process_repository_rename(old: &str, new: &str) -> Result<usize, String> {
+    let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+    let ids = conn
+        .transaction(|| {
+            let ids = rename_repo(&conn, old, new)?;
+            for game_id in &ids {
+                write_outbox_event(&conn, &OutboxEvent::GameUpdated { game_id: *game_id })?;
+            }
+            Ok::<_, diesel::result::Error>(ids)
+        })
+        .map_err(|err| err.to_string())?;
+    if !ids.is_empty() {
+        wake_outbox();
+    }
+    Ok(ids.len())
+}
+
+fn dead_letter_issue_event(payload: &GithubPayload, err: &str, attempts: u32) {
+    let result = DB_POOL
+        .get()
+        .map_err(|err| err.to_string())
+        .and_then(|conn| {
+            write_github_dead_letter(
+                &conn,
+                payload.issue.number,
+                &payload.action,
+                &json!(payload),
+                err,
+                attempts as i32,
+            )
+            .map_err(|err| err.to_string())
+        });
+    if let Err(err) = result {
+        log::error!("Dead letter issue {}: {}", payload.issue.number, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::issue_queue::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // (issue, action, processing time in ms)
+    type Event = (i32, &'static str, u64);
+
+    lazy_static! {
+        // issue -> actions in the order they were applied
+        static ref APPLIED: Mutex<HashMap<i32, Vec<&'static str>>> = Mutex::new(HashMap::new());
+        static ref DEAD: Mutex<Vec<(Event, u32)>> = Mutex::new(Vec::new());
+        static ref QUEUE: KeyedQueue<Event> =
+            KeyedQueue::new("test", apply, dead_letter, Duration::from_millis(5));
+    }
+
+    static FLAKY_ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+
+    fn apply(event: &Event) -> Result<(), String> {
+        std::thread::sleep(Duration::from_millis(event.2));
+        match event.1 {
+            "broken" => return Err("database is down".into()),
+            "flaky" if FLAKY_ATTEMPTS.fetch_add(1, Ordering::SeqCst) == 0 => {
+                return Err("timeout".into())
+            }
+            _ => (),
+        }
+        APPLIED
+            .lock()
+            .unwrap()
+            .entry(event.0)
+            .or_default()
+            .push(event.1);
+        Ok(())
+    }
+
+    fn dead_letter(event: &Event, _err: &str, attempts: u32) {
+        DEAD.lock().unwrap().push((*event, attempts));
+    }
+
+    #[actix_web::test]
+    async fn issue_events_keep_order() {
+        // A slow `closed` is delivered before a fast `reopened` of the same issue
+        let burst: [Event; 7] = [
+            (1, "closed", 40),
+            (2, "closed", 0),
+            (1, "reopened", 0),
+            (3, "broken", 0),
+            (2, "flaky", 10),
+            (1, "closed", 10),
+            (2, "reopened", 0),
+        ];
+        for event in burst {
+            QUEUE.enqueue(event.0, event);
+        }
+        assert_eq!(QUEUE.depth(), 7);
+
+        for _ in 0..100 {
+            if QUEUE.depth() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(QUEUE.depth(), 0);
+
+        let applied = APPLIED.lock().unwrap();
+        assert_eq!(applied[&1], vec!["closed", "reopened", "closed"]);
+        // Retried before the next event of the issue
+        assert_eq!(applied[&2], vec!["closed", "flaky", "reopened"]);
+        assert!(applied.get(&3).is_none());
+        assert_eq!(
+            *DEAD.lock().unwrap(),
+            vec![((3, "broken", 0), MAX_ATTEMPTS)]
+        );
+    }
+}
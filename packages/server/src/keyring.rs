@@ -0,0 +1,286 @@
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::{BASE64, HEXLOWER};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLObject};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::env;
+use std::sync::RwLock;
+
+use crate::auth::TOKEN_TTL_SECONDS;
+use crate::config::resolve_secrets;
+use crate::db::models::{NewSecret, Secret};
+use crate::db::schema::secrets;
+use crate::error::Error;
+use crate::metrics;
+
+lazy_static! {
+    // Newest first, empty until `load_keyring`, then the env secret is the only key
+    static ref KEYRING: RwLock<Keyring> = RwLock::new(Keyring::default());
+    // Old keys stay accepted this long after a rotation, tokens live a week
+    static ref SECRET_GRACE_SECONDS: i64 = env::var("SECRET_GRACE_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(TOKEN_TTL_SECONDS);
+    // Encrypts the persisted keys, rotation is refused without it
+    static ref SECRET_MASTER_KEY: Option<String> = env::var("SECRET_MASTER_KEY")
+        .ok()
+        .filter(|key| !key.is_empty());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SigningKey {
+    pub kid: String,
+    pub secret: String,
+    // unix seconds, `None` while it signs
+    pub expires_at: Option<i64>,
+}
+
+impl SigningKey {
+    pub fn new(secret: &str, expires_at: Option<i64>) -> Self {
+        SigningKey {
+            kid: get_kid(secret),
+            secret: secret.to_owned(),
+            expires_at,
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScSigningKey {
+    pub kid: String,
+    // null for the key that signs new tokens
+    pub expires_at: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Keyring {
+    keys: Vec<SigningKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<SigningKey>) -> Self {
+        Keyring { keys }
+    }
+
+    /// Newest key, `fallback` is the env secret before anything is loaded
+    pub fn signing(&self, fallback: &str) -> SigningKey {
+        self.keys
+            .first()
+            .cloned()
+            .unwrap_or_else(|| SigningKey::new(fallback, None))
+    }
+
+    /// Unexpired keys, newest first
+    pub fn accepted(&self, fallback: &str, now: i64) -> Vec<SigningKey> {
+        if self.keys.is_empty() {
+            return vec![SigningKey::new(fallback, None)];
+        }
+        self.keys
+            .iter()
+            .filter(|key| key.expires_at.map_or(true, |at| at > now))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Public name of a key, in token headers and metrics
+pub fn get_kid(secret: &str) -> String {
+    HEXLOWER.encode(&digest(&SHA256, secret.as_bytes()).as_ref()[..8])
+}
+
+pub fn get_signing_key(fallback: &str) -> SigningKey {
+    KEYRING.read().unwrap().signing(fallback)
+}
+
+pub fn get_accepted_keys(fallback: &str) -> Vec<SigningKey> {
+    KEYRING
+        .read()
+        .unwrap()
+        .accepted(fallback, Utc::now().timestamp())
+}
+
+/// Which key verified recently seen tokens, the fleet converged once
+/// only the new kid keeps moving
+pub fn record_key_use(kid: &str) {
+    metrics::inc_counter("nesbox_token_key_verified_total", kid);
+    metrics::set_gauge(
+        "nesbox_token_key_last_seen_seconds",
+        kid,
+        Utc::now().timestamp() as f64,
+    );
+}
+
+fn get_cipher(master: &str) -> LessSafeKey {
+    let key = digest(&SHA256, master.as_bytes());
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key.as_ref()).unwrap())
+}
+
+/// base64 of nonce and ciphertext, bound to the kid
+pub fn seal(master: &str, key: &SigningKey) -> String {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    let mut sealed = key.secret.as_bytes().to_vec();
+    get_cipher(master)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(key.kid.as_bytes()),
+            &mut sealed,
+        )
+        .unwrap();
+    BASE64.encode(&[&nonce[..], &sealed].concat())
+}
+
+pub fn unseal(master: &str, kid: &str, sealed: &str) -> Option<String> {
+    let bytes = BASE64.decode(sealed.as_bytes()).ok()?;
+    if bytes.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+    let mut sealed = sealed.to_vec();
+    let secret = get_cipher(master)
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).ok()?,
+            Aad::from(kid.as_bytes()),
+            &mut sealed,
+        )
+        .ok()?;
+    String::from_utf8(secret.to_vec()).ok()
+}
+
+fn to_sc_signing_keys(keys: &[SigningKey]) -> Vec<ScSigningKey> {
+    keys.iter()
+        .map(|key| ScSigningKey {
+            kid: key.kid.clone(),
+            expires_at: key.expires_at.map(|at| (at * 1000) as f64),
+        })
+        .collect()
+}
+
+/// Persisted keys ahead of the env secret, at startup and every minute so
+/// every instance picks up a rotation. Expired keys lose their secret and
+/// keep the row, a retired env secret stays retired across restarts
+pub fn load_keyring(conn: &PgConnection, env_secret: &str) -> QueryResult<Vec<ScSigningKey>> {
+    use self::secrets::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    diesel::update(secrets.filter(expires_at.le(now)).filter(sealed.ne("")))
+        .set(sealed.eq(""))
+        .execute(conn)?;
+
+    let rows = secrets.order(id.desc()).load::<Secret>(conn)?;
+    let retired = rows
+        .iter()
+        .filter(|row| row.sealed.is_empty())
+        .map(|row| row.kid.clone())
+        .collect::<Vec<_>>();
+    let persisted = rows
+        .iter()
+        .filter(|row| !row.sealed.is_empty())
+        .filter_map(|row| {
+            let secret = SECRET_MASTER_KEY
+                .as_ref()
+                .and_then(|master| unseal(master, &row.kid, &row.sealed));
+            if secret.is_none() {
+                log::error!("Unseal secret {}", row.kid);
+            }
+            Some(SigningKey {
+                kid: row.kid.clone(),
+                secret: secret?,
+                expires_at: row.expires_at.map(|at| at.timestamp()),
+            })
+        })
+        .collect();
+
+    let keys = resolve_secrets(persisted, &retired, env_secret);
+    let list = to_sc_signing_keys(&keys);
+    *KEYRING.write().unwrap() = Keyring::new(keys);
+    Ok(list)
+}
+
+/// New signing key at once, the others are accepted for the grace period
+pub fn rotate_secret(conn: &PgConnection, env_secret: &str) -> FieldResult<Vec<ScSigningKey>> {
+    use self::secrets::dsl::*;
+
+    let master = SECRET_MASTER_KEY.as_ref().ok_or_else(|| {
+        FieldError::new("SECRET_MASTER_KEY is not configured", Error::bad_request())
+    })?;
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let new_key = SigningKey::new(&HEXLOWER.encode(&bytes), None);
+    let env_key = SigningKey::new(env_secret, None);
+    let now = Utc::now().naive_utc();
+    let grace = NaiveDateTime::from_timestamp_opt(now.timestamp() + *SECRET_GRACE_SECONDS, 0);
+
+    conn.transaction(|| {
+        // The env secret gets a row the first time, so its expiry is persisted
+        diesel::insert_into(secrets)
+            .values(&NewSecret {
+                kid: &env_key.kid,
+                sealed: &seal(master, &env_key),
+                expires_at: None,
+                created_at: now,
+            })
+            .on_conflict(kid)
+            .do_nothing()
+            .execute(conn)?;
+        diesel::update(secrets.filter(expires_at.is_null()))
+            .set(expires_at.eq(grace))
+            .execute(conn)?;
+        diesel::insert_into(secrets)
+            .values(&NewSecret {
+                kid: &new_key.kid,
+                sealed: &seal(master, &new_key),
+                expires_at: None,
+                created_at: now,
+            })
+            .execute(conn)
+    })?;
+
+    Ok(load_keyring(conn, env_secret)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keyring::*;
+
+    #[test]
+    fn keyring_accepted_keys() {
+        // Nothing loaded, the env secret alone
+        let keyring = Keyring::default();
+        assert_eq!(keyring.signing("env"), SigningKey::new("env", None));
+        assert_eq!(
+            keyring.accepted("env", 0),
+            vec![SigningKey::new("env", None)]
+        );
+
+        let keyring = Keyring::new(vec![
+            SigningKey::new("new", None),
+            SigningKey::new("old", Some(100)),
+        ]);
+        assert_eq!(keyring.signing("env").kid, get_kid("new"));
+        assert_eq!(keyring.accepted("env", 99).len(), 2);
+        assert_eq!(
+            keyring.accepted("env", 100),
+            vec![SigningKey::new("new", None)]
+        );
+    }
+
+    #[test]
+    fn keyring_seal() {
+        let key = SigningKey::new("signing secret", None);
+        let sealed = seal("master", &key);
+        assert!(!sealed.contains("signing"));
+        assert_ne!(sealed, seal("master", &key));
+        assert_eq!(
+            unseal("master", &key.kid, &sealed).as_deref(),
+            Some("signing secret")
+        );
+        // Wrong master key, or moved to another kid
+        assert_eq!(unseal("other", &key.kid, &sealed), None);
+        assert_eq!(unseal("master", &get_kid("other"), &sealed), None);
+        assert_eq!(unseal("master", &key.kid, "AAAA"), None);
+    }
+}
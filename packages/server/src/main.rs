@@ -14,44 +14,215 @@ use std::{env, io, sync::Arc, time::Duration};
 use actix_cors::Cors;
 use actix_web::{
     middleware,
-    web::{self, Data},
+    web::{self, Data, PayloadConfig},
     App, HttpServer,
 };
 use actix_web_lab::respond::Html;
+use chrono::Utc;
 use juniper::http::playground::playground_source;
 use tokio::time;
 
 use crate::{
+    avatar::{AVATARS, MAX_AVATAR_BYTES},
     db::root::DB_POOL,
+    delivery::deliver_webhooks,
+    doctor::{is_healthy, render_report, run_checks, LiveProbe},
     error::Error,
+    federation::{sync_federation, CATALOG_SYNC_SECONDS, CATALOG_UPSTREAM_URL},
     handles::*,
+    keyring::load_keyring,
+    outbox::{dispatch_outbox, prune_outbox, wait_outbox},
+    quota::{flush_usage, USAGE_FLUSH_SECONDS},
+    rate_limit::{RateLimiter, RateScope},
+    rom::MAX_ROM_UPLOAD_BYTES,
     schemas::{
+        friend::get_friend_ids,
+        game_change::prune_game_changes,
+        github_repo::seed_github_repos,
+        invite::prune_invites,
+        login_session::{
+            load_revoked_sessions, load_revoked_tokens, prune_login_sessions, prune_revoked_tokens,
+        },
+        message::prune_messages,
+        notify::{notify_ids, take_seats_to_free, ScNotifyMessageBuilder},
+        persisted_query::prune_persisted_queries,
+        presence::update_idle_presence,
+        record::close_stale_sessions,
+        retention::apply_retention_policies,
         room::delete_room,
         room::get_outdated_rooms,
-        root::{create_guest_schema, create_schema, leave_room_and_notify},
+        root::{create_guest_schema, create_schema, free_seat_and_notify, leave_room_and_notify},
+        save_state::{prune_state_uploads, release_state_blobs, MAX_STATE_CHUNK_BYTES},
+        user::get_user_basic,
     },
+    screenshot::LIVE_SCREENSHOTS,
+    seed::{seed_demo_data, SEED_DEMO_DATA, SEED_FORCE},
+    storage::get_storage,
+    transfer::MAX_IMPORT_BYTES,
 };
 
+mod attachment;
 mod auth;
+mod avatar;
+mod config;
 mod db;
+mod deflate;
+mod delivery;
+mod doctor;
 mod error;
+mod federation;
 mod github;
+mod guard;
 mod handles;
+mod idempotency;
+mod issue_queue;
+mod keyring;
+mod markdown;
+mod metrics;
+mod moderation;
+mod net;
+mod nsf;
+mod outbox;
+mod profiling;
+mod query_cost;
+mod quota;
+mod rate_limit;
+mod request;
+mod rom;
 mod schemas;
+mod screenshot;
+mod seed;
+mod storage;
+mod stream;
+mod transfer;
 mod voice;
 
+/// Hourly on the blocking pool, outdated rooms and expired data
+fn clean_up() {
+    let conn = DB_POOL.get().unwrap();
+    let mut rooms = get_outdated_rooms(&conn);
+    rooms.truncate(100);
+    rooms.iter().for_each(|room| {
+        if let Err(err) = leave_room_and_notify(&conn, room.host) {
+            if err.extensions().to_owned() == Error::username_not_playing() {
+                delete_room(&conn, room.id);
+            } else {
+                log::error!("{:?}", err);
+            }
+        }
+    });
+    log::debug!("Clean outdated rooms: {:?}", rooms);
+    log::debug!("Prune messages: {}", prune_messages(&conn));
+    log::debug!("Prune game changes: {:?}", prune_game_changes(&conn));
+    log::debug!("Prune outbox: {:?}", prune_outbox(&conn));
+    log::debug!("Prune login sessions: {:?}", prune_login_sessions(&conn));
+    log::debug!("Prune revoked tokens: {:?}", prune_revoked_tokens(&conn));
+    log::debug!("Prune invites: {:?}", prune_invites(&conn));
+    log::debug!("Prune state uploads: {:?}", prune_state_uploads(&conn));
+    if let Some(storage) = get_storage() {
+        log::debug!(
+            "Release state blobs: {:?}",
+            release_state_blobs(&conn, storage)
+        );
+    }
+    log::debug!(
+        "Prune persisted queries: {:?}",
+        prune_persisted_queries(&conn)
+    );
+    log::debug!("Apply retention: {:?}", apply_retention_policies(&conn));
+}
+
+/// Every minute on the blocking pool
+fn refresh_idle(keyring_secret: &str) {
+    let conn = DB_POOL.get().unwrap();
+    // Rotations of other instances, and expired keys dropped
+    if let Err(err) = load_keyring(&conn, keyring_secret) {
+        log::error!("Reload signing keys: {}", err);
+    }
+    log::debug!("Close stale play: {}", close_stale_sessions(&conn));
+    for user_id in update_idle_presence() {
+        if let Ok(user) = get_user_basic(&conn, user_id) {
+            notify_ids(
+                get_friend_ids(&conn, user_id),
+                ScNotifyMessageBuilder::default()
+                    .update_user(user)
+                    .build()
+                    .unwrap(),
+            );
+        }
+    }
+}
+
+fn free_seats() {
+    let seats = take_seats_to_free(Utc::now());
+    if seats.is_empty() {
+        return;
+    }
+    let conn = DB_POOL.get().unwrap();
+    for user_id in seats {
+        if let Err(err) = free_seat_and_notify(&conn, user_id) {
+            log::debug!("{:?}", err);
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     openssl_probe::init_ssl_cert_env_vars();
 
     dotenv().ok();
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    // Validate the deployment without serving
+    if env::args().any(|arg| arg == "--doctor") {
+        let results = run_checks(&LiveProbe { pooled: false }, true);
+        print!("{}", render_report(&results));
+        std::process::exit(if is_healthy(&results) { 0 } else { 1 });
+    }
+
     let port = env::var("PORT")
         .unwrap_or_default()
         .parse::<u16>()
         .unwrap_or(8080);
     let secret = env::var("SECRET").unwrap_or("xxx".to_owned());
 
+    // Demo data for a fresh database
+    if env::args().any(|arg| arg == "--seed") {
+        let force = *SEED_FORCE || env::args().any(|arg| arg == "--force");
+        match seed_demo_data(&DB_POOL.get().unwrap(), &secret, force) {
+            Ok(summary) => println!("{}", summary),
+            Err(err) => {
+                eprintln!("Seed failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if *SEED_DEMO_DATA {
+        if let Err(err) = seed_demo_data(&DB_POOL.get().unwrap(), &secret, *SEED_FORCE) {
+            log::warn!("Seed skipped: {}", err);
+        }
+    }
+    // Rotated keys sign ahead of the env value
+    match load_keyring(&DB_POOL.get().unwrap(), &secret) {
+        Ok(keys) => log::info!("Signing keys: {}", keys.len()),
+        Err(err) => log::error!("Load signing keys: {}", err),
+    }
+    // Transferred issues only follow into these, renames update them
+    match seed_github_repos(&DB_POOL.get().unwrap()) {
+        Ok(count) => log::info!("New GitHub repositories: {}", count),
+        Err(err) => log::error!("Seed GitHub repositories: {}", err),
+    }
+    // Revoked tokens stay refused across restarts
+    match load_revoked_sessions(&DB_POOL.get().unwrap()) {
+        Ok(count) => log::info!("Revoked sessions: {}", count),
+        Err(err) => log::error!("Load revoked sessions: {}", err),
+    }
+    match load_revoked_tokens(&DB_POOL.get().unwrap()) {
+        Ok(count) => log::info!("Revoked tokens: {}", count),
+        Err(err) => log::error!("Load revoked tokens: {}", err),
+    }
+
     let schema = Arc::new(create_schema());
     let guestschema = Arc::new(create_guest_schema());
 
@@ -62,19 +233,80 @@ async fn main() -> io::Result<()> {
         let mut interval = time::interval(Duration::from_secs(60 * 60));
         loop {
             interval.tick().await;
-            let conn = DB_POOL.get().unwrap();
-            let mut rooms = get_outdated_rooms(&conn);
-            rooms.truncate(100);
-            rooms.iter().for_each(|room| {
-                if let Err(err) = leave_room_and_notify(room.host) {
-                    if err.extensions().to_owned() == Error::username_not_playing() {
-                        delete_room(&conn, room.id);
-                    } else {
-                        log::error!("{:?}", err);
-                    }
+            if let Err(err) = tokio::task::spawn_blocking(clean_up).await {
+                log::error!("Clean up: {:?}", err);
+            }
+        }
+    });
+
+    let keyring_secret = secret.clone();
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let keyring_secret = keyring_secret.clone();
+            if let Err(err) =
+                tokio::task::spawn_blocking(move || refresh_idle(&keyring_secret)).await
+            {
+                log::error!("Refresh idle: {:?}", err);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            if let Err(err) = tokio::task::spawn_blocking(free_seats).await {
+                log::error!("Free seats: {:?}", err);
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            if let Err(err) = tokio::task::spawn_blocking(deliver_webhooks).await {
+                log::error!("Deliver webhooks: {:?}", err);
+            }
+        }
+    });
+
+    // One dispatcher, woken after each commit, the interval retries failures
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(5));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = wait_outbox() => (),
+            }
+            match tokio::task::spawn_blocking(dispatch_outbox).await {
+                Ok(Ok(_)) => (),
+                result => log::error!("Dispatch outbox: {:?}", result),
+            }
+        }
+    });
+
+    if CATALOG_UPSTREAM_URL.is_some() {
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(*CATALOG_SYNC_SECONDS));
+            loop {
+                interval.tick().await;
+                if let Err(err) = tokio::task::spawn_blocking(sync_federation).await {
+                    log::error!("Sync catalog: {:?}", err);
                 }
-            });
-            log::debug!("Clean outdated rooms: {:?}", rooms);
+            }
+        });
+    }
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(*USAGE_FLUSH_SECONDS));
+        loop {
+            interval.tick().await;
+            match tokio::task::spawn_blocking(|| flush_usage(&DB_POOL.get().unwrap())).await {
+                Ok(Ok(count)) => log::debug!("Flush usage: {}", count),
+                result => log::error!("Flush usage: {:?}", result),
+            }
         }
     });
 
@@ -90,6 +322,7 @@ async fn main() -> io::Result<()> {
                 web::resource("/graphql")
                     .app_data(Data::from(schema.clone()))
                     .app_data(Data::new(secret.clone()))
+                    .wrap(RateLimiter::new(RateScope::User, &secret))
                     .route(web::post().to(graphql)),
             )
             .service(
@@ -108,6 +341,7 @@ async fn main() -> io::Result<()> {
                 web::resource("/guestgraphql")
                     .app_data(Data::new(secret.clone()))
                     .app_data(Data::from(guestschema.clone()))
+                    .wrap(RateLimiter::new(RateScope::Guest, &secret))
                     .route(web::post().to(guestgraphql)),
             )
             .service(
@@ -120,11 +354,77 @@ async fn main() -> io::Result<()> {
                     web::get().to(|| async { Html(playground_source("/guestgraphql", None)) }),
                 ),
             )
+            .service(
+                web::resource("/admin/games")
+                    .app_data(Data::new(secret.clone()))
+                    .route(web::get().to(dump_games)),
+            )
+            .service(
+                web::resource("/export")
+                    .app_data(Data::new(secret.clone()))
+                    .route(web::get().to(export_data)),
+            )
+            .service(
+                web::resource("/import")
+                    .app_data(Data::new(secret.clone()))
+                    .app_data(PayloadConfig::new(*MAX_IMPORT_BYTES))
+                    .route(web::post().to(import_data)),
+            )
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(web::resource("/readyz").route(web::get().to(readyz)))
+            .service(web::resource("/rom/{id}").route(web::get().to(rom)))
+            .service(
+                web::resource("/upload/rom")
+                    .app_data(Data::new(secret.clone()))
+                    .app_data(PayloadConfig::new(*MAX_ROM_UPLOAD_BYTES))
+                    .route(web::post().to(upload_rom)),
+            )
+            .service(web::resource("/rom/upload/{hash}").route(web::get().to(uploaded_rom)))
+            .service(
+                web::resource("/upload/avatar")
+                    .app_data(Data::new(secret.clone()))
+                    .app_data(PayloadConfig::new(*MAX_AVATAR_BYTES))
+                    .route(web::post().to(upload_avatar)),
+            )
+            .service(
+                web::resource("/avatar/{id}/{hash}")
+                    .app_data(Data::new(&AVATARS))
+                    .route(web::get().to(hashed_blob)),
+            )
+            .service(
+                web::resource("/upload/state/{id}")
+                    .app_data(Data::new(secret.clone()))
+                    .app_data(PayloadConfig::new(*MAX_STATE_CHUNK_BYTES))
+                    .route(web::put().to(upload_state_chunk)),
+            )
+            .service(
+                web::resource("/screenshot/{id}/{hash}/{size}").route(web::get().to(screenshot)),
+            )
+            .service(
+                web::resource("/room-screenshot/{id}/{hash}")
+                    .app_data(Data::new(&LIVE_SCREENSHOTS))
+                    .route(web::get().to(hashed_blob)),
+            )
+            .service(
+                web::resource("/attachment/{id}")
+                    .app_data(Data::new(secret.clone()))
+                    .route(web::get().to(attachment)),
+            )
             .service(
                 web::resource("/webhook")
                     .app_data(Data::new(secret.clone()))
                     .route(web::post().to(webhook)),
             )
+            .service(
+                web::resource("/oauth/{provider}")
+                    .app_data(Data::new(secret.clone()))
+                    .route(web::get().to(oauth_authorize)),
+            )
+            .service(
+                web::resource("/oauth/{provider}/callback")
+                    .app_data(Data::new(secret.clone()))
+                    .route(web::get().to(oauth_callback)),
+            )
             .wrap(Cors::permissive())
             .wrap(middleware::Logger::default())
     })
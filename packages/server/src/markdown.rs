@@ -0,0 +1,122 @@
+use ammonia::{Builder, UrlRelative};
+use pulldown_cmark::{html, Options, Parser};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use url::Url;
+
+lazy_static! {
+    // Comma separated, where issue uploads live by default
+    pub static ref IMAGE_HOSTS: Vec<String> = env::var("IMAGE_HOSTS")
+        .unwrap_or("user-images.githubusercontent.com".into())
+        .split(',')
+        .map(|host| host.trim().to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect();
+}
+
+const TAGS: [&str; 14] = [
+    "a",
+    "blockquote",
+    "br",
+    "code",
+    "del",
+    "em",
+    "hr",
+    "img",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "strong",
+    "ul",
+];
+
+fn is_image_host(src: &str, image_hosts: &[String]) -> bool {
+    Url::parse(src).map_or(false, |url| {
+        url.scheme() == "https"
+            && url
+                .host_str()
+                .map_or(false, |host| image_hosts.iter().any(|allow| allow == host))
+    })
+}
+
+/// Markdown of a game issue to html that is safe to inject into the page,
+/// images from other hosts lose their `src`
+pub fn render_markdown(markdown: &str, image_hosts: &[String]) -> String {
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, Options::all()));
+
+    let image_hosts = image_hosts.to_vec();
+    let mut builder = Builder::default();
+    builder
+        .tags(HashSet::from(TAGS))
+        .generic_attributes(HashSet::new())
+        .tag_attributes(HashMap::from([
+            ("a", HashSet::from(["href", "title"])),
+            ("img", HashSet::from(["src", "alt", "title"])),
+        ]))
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .attribute_filter(move |element, attribute, value| {
+            if element == "img" && attribute == "src" && !is_image_host(value, &image_hosts) {
+                None
+            } else {
+                Some(Cow::Borrowed(value))
+            }
+        });
+    builder.clean(&unsafe_html).to_string()
+}
+
+pub fn render_description(markdown: &str) -> String {
+    render_markdown(markdown, &IMAGE_HOSTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::*;
+
+    const INJECTION: &str = r#"**Contra** for [two players](https://example.com/manual "manual")
+
+<script>alert(document.cookie)</script>
+
+<img src="https://user-images.githubusercontent.com/1/a.png" onerror="alert(1)">
+
+![tracker](https://evil.example/track.png)
+
+[click](javascript:alert(1)) <a href="data:text/html,x" onclick="alert(1)">data</a>
+
+<iframe src="https://evil.example"></iframe><style>body{display:none}</style>
+
+- one
+- two
+"#;
+
+    #[test]
+    fn description_sanitized() {
+        let hosts = vec!["user-images.githubusercontent.com".to_owned()];
+        let rendered = render_markdown(INJECTION, &hosts);
+
+        for needle in [
+            "<script",
+            "alert",
+            "onerror",
+            "onclick",
+            "javascript:",
+            "data:",
+            "<iframe",
+            "<style",
+            "evil.example",
+        ] {
+            assert!(!rendered.contains(needle), "{} in {}", needle, rendered);
+        }
+        assert!(rendered.contains("<strong>Contra</strong>"));
+        assert!(rendered.contains(r#"href="https://example.com/manual""#));
+        assert!(rendered.contains(r#"rel="noopener noreferrer nofollow""#));
+        assert!(
+            rendered.contains(r#"<img src="https://user-images.githubusercontent.com/1/a.png">"#)
+        );
+        assert!(rendered.contains("<li>two</li>"));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+const BUCKETS: [f64; 8] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0];
+
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+lazy_static! {
+    // (metric name, label value) -> count
+    static ref COUNTERS: Mutex<BTreeMap<(&'static str, String), u64>> = {
+        let m = BTreeMap::new();
+        Mutex::new(m)
+    };
+    // (metric name, label value or empty) -> histogram
+    static ref HISTOGRAMS: Mutex<BTreeMap<(&'static str, String), Histogram>> = {
+        let m = BTreeMap::new();
+        Mutex::new(m)
+    };
+    // (metric name, label value) -> last value
+    static ref GAUGES: Mutex<BTreeMap<(&'static str, String), f64>> = {
+        let m = BTreeMap::new();
+        Mutex::new(m)
+    };
+}
+
+pub fn inc_counter(name: &'static str, kind: &str) {
+    add_counter(name, kind, 1);
+}
+
+pub fn add_counter(name: &'static str, kind: &str, value: u64) {
+    *COUNTERS
+        .lock()
+        .unwrap()
+        .entry((name, kind.to_owned()))
+        .or_insert(0) += value;
+}
+
+pub fn get_counter(name: &'static str, kind: &str) -> u64 {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .get(&(name, kind.to_owned()))
+        .copied()
+        .unwrap_or_default()
+}
+
+pub fn set_gauge(name: &'static str, kind: &str, value: f64) {
+    GAUGES
+        .lock()
+        .unwrap()
+        .insert((name, kind.to_owned()), value);
+}
+
+pub fn observe(name: &'static str, value: f64) {
+    observe_kind(name, "", value);
+}
+
+pub fn observe_kind(name: &'static str, kind: &str, value: f64) {
+    let mut map = HISTOGRAMS.lock().unwrap();
+    let histogram = map.entry((name, kind.to_owned())).or_insert(Histogram {
+        buckets: [0; BUCKETS.len()],
+        count: 0,
+        sum: 0.0,
+    });
+    for (i, le) in BUCKETS.iter().enumerate() {
+        if value <= *le {
+            histogram.buckets[i] += 1;
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += value;
+}
+
+/// Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut last_name = "";
+    for ((name, kind), value) in COUNTERS.lock().unwrap().iter() {
+        if *name != last_name {
+            writeln!(out, "# TYPE {} counter", name).ok();
+            last_name = *name;
+        }
+        writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, value).ok();
+    }
+
+    let mut last_name = "";
+    for ((name, kind), value) in GAUGES.lock().unwrap().iter() {
+        if *name != last_name {
+            writeln!(out, "# TYPE {} gauge", name).ok();
+            last_name = *name;
+        }
+        writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, value).ok();
+    }
+
+    let mut last_name = "";
+    for ((name, kind), histogram) in HISTOGRAMS.lock().unwrap().iter() {
+        if *name != last_name {
+            writeln!(out, "# TYPE {} histogram", name).ok();
+            last_name = *name;
+        }
+        let (label, labels) = if kind.is_empty() {
+            (String::new(), String::new())
+        } else {
+            (
+                format!("kind=\"{}\",", kind),
+                format!("{{kind=\"{}\"}}", kind),
+            )
+        };
+        for (i, le) in BUCKETS.iter().enumerate() {
+            writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, label, le, histogram.buckets[i]
+            )
+            .ok();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, label, histogram.count
+        )
+        .ok();
+        writeln!(out, "{}_sum{} {}", name, labels, histogram.sum).ok();
+        writeln!(out, "{}_count{} {}", name, labels, histogram.count).ok();
+    }
+
+    out
+}
@@ -0,0 +1,496 @@
+use juniper::{FieldError, FieldResult, GraphQLEnum};
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use strum::{Display, EnumString};
+use url::Url;
+
+use crate::error::Error;
+use crate::metrics;
+
+lazy_static! {
+    // Links to other hosts are flagged, usernames can't contain any
+    static ref ALLOWED_LINK_DOMAINS: Vec<String> = env::var("MODERATION_ALLOWED_DOMAINS")
+        .unwrap_or("nesbox.xianqiao.wang,github.com".to_owned())
+        .split(',')
+        .map(|domain| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect();
+    static ref MAX_REPEAT: usize = env::var("MODERATION_MAX_REPEAT")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(10);
+    static ref BLOCKED_WORDS_FILE: Option<String> = env::var("BLOCKED_WORDS_FILE").ok();
+    static ref BLOCKED_WORDS: RwLock<BlockedWords> = RwLock::new(
+        BLOCKED_WORDS_FILE
+            .as_ref()
+            .map(|file| {
+                read_blocked_words(file).unwrap_or_else(|err| {
+                    log::error!("Read blocked words {}: {}", file, err);
+                    BlockedWords::default()
+                })
+            })
+            .unwrap_or_default()
+    );
+    static ref CLASSIFIER_URL: Option<String> = env::var("MODERATION_CLASSIFIER_URL").ok();
+    static ref CLASSIFIER_TIMEOUT: Duration = Duration::from_millis(
+        env::var("MODERATION_CLASSIFIER_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(800)
+    );
+    static ref FILTERS: Vec<Box<dyn TextFilter>> = default_filters();
+}
+
+/// Where the text comes from, also the `kind` of the report it is flagged to
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScTextKind {
+    Comment,
+    Message,
+    Lobby,
+    RoomCommand,
+    // nicknames too
+    Username,
+    CollectionName,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Allow,
+    // stored, and listed by `moderationReports`
+    Flag,
+    Reject,
+}
+
+pub trait TextFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, kind: ScTextKind, text: &str) -> Verdict;
+}
+
+/// Cheap filters first, the classifier is skipped once something rejects
+fn default_filters() -> Vec<Box<dyn TextFilter>> {
+    let mut filters: Vec<Box<dyn TextFilter>> = vec![
+        Box::new(FloodFilter {
+            max_repeat: *MAX_REPEAT,
+        }),
+        Box::new(LinkFilter {
+            allowed: ALLOWED_LINK_DOMAINS.clone(),
+        }),
+        Box::new(BlockedWordFilter {
+            words: &BLOCKED_WORDS,
+        }),
+    ];
+    if let Some(url) = CLASSIFIER_URL.as_ref() {
+        filters.push(Box::new(ClassifierFilter {
+            url: url.clone(),
+            timeout: *CLASSIFIER_TIMEOUT,
+        }));
+    }
+    filters
+}
+
+/// Most severe verdict and the filter that gave it
+pub fn run_filters(
+    filters: &[Box<dyn TextFilter>],
+    kind: ScTextKind,
+    text: &str,
+) -> (Verdict, Option<&'static str>) {
+    let mut result = (Verdict::Allow, None);
+    for filter in filters {
+        let start = Instant::now();
+        let verdict = filter.check(kind, text);
+        metrics::observe_kind(
+            "nesbox_moderation_ms",
+            filter.name(),
+            start.elapsed().as_secs_f64() * 1000.0,
+        );
+        match verdict {
+            Verdict::Allow => continue,
+            Verdict::Flag => metrics::inc_counter("nesbox_moderation_flagged_total", filter.name()),
+            Verdict::Reject => {
+                metrics::inc_counter("nesbox_moderation_rejected_total", filter.name())
+            }
+        }
+        if verdict > result.0 {
+            result = (verdict, Some(filter.name()));
+        }
+        if verdict == Verdict::Reject {
+            break;
+        }
+    }
+    result
+}
+
+/// Fails when rejected, returns the filter to put in the report when flagged
+pub fn check_text(kind: ScTextKind, text: &str) -> FieldResult<Option<&'static str>> {
+    match run_filters(&FILTERS, kind, text) {
+        (Verdict::Reject, filter) => Err(FieldError::new(
+            format!("{} rejected by {}", kind, filter.unwrap_or_default()),
+            Error::text_rejected(),
+        )),
+        (Verdict::Flag, filter) => Ok(filter),
+        _ => Ok(None),
+    }
+}
+
+/// Same character over and over, e.g. "!!!!!!!!!!!!" or "哈哈哈哈哈哈哈哈哈哈哈"
+pub struct FloodFilter {
+    pub max_repeat: usize,
+}
+
+impl TextFilter for FloodFilter {
+    fn name(&self) -> &'static str {
+        "flood"
+    }
+
+    fn check(&self, _kind: ScTextKind, text: &str) -> Verdict {
+        let mut last = None;
+        let mut run = 0;
+        for c in text.chars().filter(|c| !c.is_whitespace()) {
+            if Some(c) == last {
+                run += 1;
+            } else {
+                last = Some(c);
+                run = 1;
+            }
+            if run > self.max_repeat {
+                return Verdict::Reject;
+            }
+        }
+        Verdict::Allow
+    }
+}
+
+pub struct LinkFilter {
+    pub allowed: Vec<String>,
+}
+
+/// Links may follow other text without a space, e.g. "看这里https://..."
+fn get_link_host(word: &str) -> Option<String> {
+    let word = word.to_lowercase();
+    let start = ["http://", "https://", "www."]
+        .iter()
+        .filter_map(|prefix| word.find(prefix))
+        .min()?;
+    let link = word[start..].trim_end_matches(|c: char| !c.is_alphanumeric() && c != '/');
+    let url = if link.starts_with("www.") {
+        Url::parse(&format!("http://{}", link)).ok()?
+    } else {
+        Url::parse(link).ok()?
+    };
+    url.host_str().map(|host| host.to_owned())
+}
+
+impl TextFilter for LinkFilter {
+    fn name(&self) -> &'static str {
+        "link"
+    }
+
+    fn check(&self, kind: ScTextKind, text: &str) -> Verdict {
+        for host in text.split_whitespace().filter_map(get_link_host) {
+            if kind == ScTextKind::Username {
+                return Verdict::Reject;
+            }
+            let allowed = self
+                .allowed
+                .iter()
+                .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)));
+            if !allowed {
+                return Verdict::Flag;
+            }
+        }
+        Verdict::Allow
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BlockedWords {
+    reject: Vec<String>,
+    flag: Vec<String>,
+}
+
+/// Scripts written without spaces, their words match anywhere in the text
+fn is_unspaced(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // kana
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{0e00}'..='\u{0e7f}' // thai
+    )
+}
+
+fn get_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// One entry per line, `#` starts a comment, `~word` flags instead of rejecting
+pub fn parse_blocked_words(text: &str) -> BlockedWords {
+    let mut words = BlockedWords::default();
+    for line in text.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.strip_prefix('~') {
+            Some(word) => words.flag.push(word.trim().to_lowercase()),
+            None => words.reject.push(line.to_lowercase()),
+        }
+    }
+    words
+}
+
+fn read_blocked_words(file: &str) -> Result<BlockedWords, String> {
+    fs::read_to_string(file)
+        .map(|text| parse_blocked_words(&text))
+        .map_err(|err| err.to_string())
+}
+
+/// Reads `BLOCKED_WORDS_FILE` again, the old list stays when it can't be read
+pub fn reload_blocked_words() -> Result<i32, String> {
+    let file = BLOCKED_WORDS_FILE
+        .as_ref()
+        .ok_or("BLOCKED_WORDS_FILE is not set")?;
+    let words = read_blocked_words(file)?;
+    let count = (words.reject.len() + words.flag.len()) as i32;
+    *BLOCKED_WORDS.write().unwrap() = words;
+    Ok(count)
+}
+
+/// Whole words, so "class" doesn't match "ass", except in unspaced scripts
+fn contains_word(lower: &str, words: &[String], entry: &str) -> bool {
+    if entry.chars().any(is_unspaced) {
+        return lower.contains(entry);
+    }
+    let entry = get_words(entry);
+    !entry.is_empty()
+        && words
+            .windows(entry.len())
+            .any(|window| window == entry.as_slice())
+}
+
+impl BlockedWords {
+    pub fn check(&self, text: &str) -> Verdict {
+        let lower = text.to_lowercase();
+        let words = get_words(text);
+        if self
+            .reject
+            .iter()
+            .any(|entry| contains_word(&lower, &words, entry))
+        {
+            Verdict::Reject
+        } else if self
+            .flag
+            .iter()
+            .any(|entry| contains_word(&lower, &words, entry))
+        {
+            Verdict::Flag
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+pub struct BlockedWordFilter {
+    pub words: &'static RwLock<BlockedWords>,
+}
+
+impl TextFilter for BlockedWordFilter {
+    fn name(&self) -> &'static str {
+        "blocked_word"
+    }
+
+    fn check(&self, _kind: ScTextKind, text: &str) -> Verdict {
+        self.words.read().unwrap().check(text)
+    }
+}
+
+/// External service, posted `{ kind, text }` and answering `{ verdict }`,
+/// anything but a timely answer allows the text
+pub struct ClassifierFilter {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+fn classify_with(
+    url: &str,
+    timeout: Duration,
+    kind: ScTextKind,
+    text: &str,
+) -> Result<Verdict, String> {
+    let resp = attohttpc::post(url)
+        .timeout(timeout)
+        .header("Content-Type", "application/json")
+        .text(json!({ "kind": kind.to_string(), "text": text }).to_string())
+        .send()
+        .map_err(|err| err.to_string())?;
+    if !resp.is_success() {
+        return Err(format!("status {}", resp.status()));
+    }
+    let text = resp.text().map_err(|err| err.to_string())?;
+    let result: Value = serde_json::from_str(&text).map_err(|err| err.to_string())?;
+    match result.get("verdict").and_then(|verdict| verdict.as_str()) {
+        Some("allow") => Ok(Verdict::Allow),
+        Some("flag") => Ok(Verdict::Flag),
+        Some("reject") => Ok(Verdict::Reject),
+        _ => Err(format!("unexpected answer {}", result)),
+    }
+}
+
+impl TextFilter for ClassifierFilter {
+    fn name(&self) -> &'static str {
+        "classifier"
+    }
+
+    fn check(&self, kind: ScTextKind, text: &str) -> Verdict {
+        classify_with(&self.url, self.timeout, kind, text).unwrap_or_else(|err| {
+            log::warn!("Moderation classifier: {}", err);
+            metrics::inc_counter("nesbox_moderation_errors_total", self.name());
+            Verdict::Allow
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::moderation::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn mock_classifier(body: &'static str, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            stream.read(&mut buf).ok();
+            thread::sleep(delay);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .ok();
+        });
+        format!("http://{}/classify", addr)
+    }
+
+    #[test]
+    fn flood_filter() {
+        let filter = FloodFilter { max_repeat: 5 };
+        let check = |text| filter.check(ScTextKind::Comment, text);
+        assert_eq!(check("good game!!!"), Verdict::Allow);
+        assert_eq!(check("2333333"), Verdict::Reject);
+        assert_eq!(check("哈哈哈哈哈"), Verdict::Allow);
+        assert_eq!(check("哈哈哈哈哈哈"), Verdict::Reject);
+        assert_eq!(check("ああああ ああ"), Verdict::Reject);
+        assert_eq!(check("Ураааааа"), Verdict::Reject);
+        assert_eq!(check("¡Vamos! ¡Otra vez!"), Verdict::Allow);
+    }
+
+    #[test]
+    fn link_filter() {
+        let filter = LinkFilter {
+            allowed: vec!["github.com".into()],
+        };
+        let check = |kind, text| filter.check(kind, text);
+        let comment = ScTextKind::Comment;
+        assert_eq!(check(comment, "see https://github.com/foo"), Verdict::Allow);
+        assert_eq!(
+            check(comment, "看这里 https://gist.github.com/x。"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            check(comment, "free coins: www.spam.example"),
+            Verdict::Flag
+        );
+        assert_eq!(
+            check(comment, "бесплатно (HTTP://spam.example/ru)"),
+            Verdict::Flag
+        );
+        assert_eq!(
+            check(comment, "看这里https://github.com.spam.example"),
+            Verdict::Flag
+        );
+        assert_eq!(check(comment, "https://notgithub.com"), Verdict::Flag);
+        assert_eq!(check(comment, "mario 3 es el mejor"), Verdict::Allow);
+        assert_eq!(
+            check(ScTextKind::Username, "https://github.com/me"),
+            Verdict::Reject
+        );
+    }
+
+    #[test]
+    fn blocked_word_filter() {
+        let words = parse_blocked_words(
+            "# reject\nScam\nfree gold\n傻瓜\nдурак\n\n# flag\n~cheat\n~バカ\n~tonto",
+        );
+        assert_eq!(words.reject, vec!["scam", "free gold", "傻瓜", "дурак"]);
+        assert_eq!(words.flag, vec!["cheat", "バカ", "tonto"]);
+
+        assert_eq!(words.check("this is a SCAM!"), Verdict::Reject);
+        assert_eq!(words.check("Scampi is tasty"), Verdict::Allow);
+        assert_eq!(words.check("get Free   gold now"), Verdict::Reject);
+        assert_eq!(words.check("free, then gold"), Verdict::Allow);
+        assert_eq!(words.check("你真是个傻瓜"), Verdict::Reject);
+        assert_eq!(words.check("Ты Дурак"), Verdict::Reject);
+        assert_eq!(words.check("дураков нет"), Verdict::Allow);
+        assert_eq!(words.check("any cheat codes?"), Verdict::Flag);
+        assert_eq!(words.check("おまえはバカだ"), Verdict::Flag);
+        assert_eq!(words.check("¡Qué tonto!"), Verdict::Flag);
+        assert_eq!(words.check("cheat scam"), Verdict::Reject);
+        assert_eq!(words.check("很好玩"), Verdict::Allow);
+    }
+
+    #[test]
+    fn classifier_filter() {
+        let check = |body, delay| {
+            ClassifierFilter {
+                url: mock_classifier(body, delay),
+                timeout: Duration::from_millis(200),
+            }
+            .check(ScTextKind::Message, "こんにちは")
+        };
+        assert_eq!(
+            check(r#"{"verdict":"flag"}"#, Duration::ZERO),
+            Verdict::Flag
+        );
+        assert_eq!(
+            check(r#"{"verdict":"reject"}"#, Duration::ZERO),
+            Verdict::Reject
+        );
+        // Fail open
+        assert_eq!(check(r#"{"error":"busy"}"#, Duration::ZERO), Verdict::Allow);
+        assert_eq!(
+            check(r#"{"verdict":"reject"}"#, Duration::from_millis(1000)),
+            Verdict::Allow
+        );
+        let unreachable = ClassifierFilter {
+            url: "http://127.0.0.1:1/classify".into(),
+            timeout: Duration::from_millis(200),
+        };
+        assert_eq!(unreachable.check(ScTextKind::Lobby, "hi"), Verdict::Allow);
+    }
+
+    #[test]
+    fn filter_chain() {
+        let filters: Vec<Box<dyn TextFilter>> = vec![
+            Box::new(FloodFilter { max_repeat: 5 }),
+            Box::new(LinkFilter { allowed: vec![] }),
+        ];
+        let run = |text| run_filters(&filters, ScTextKind::Lobby, text);
+        assert_eq!(run("gg"), (Verdict::Allow, None));
+        assert_eq!(run("www.spam.example"), (Verdict::Flag, Some("link")));
+        assert_eq!(
+            run("www.spam.example !!!!!!!!"),
+            (Verdict::Reject, Some("flood"))
+        );
+    }
+}
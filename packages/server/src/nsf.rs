@@ -0,0 +1,71 @@
+const MAGIC: &[u8] = b"NESM\x1a";
+const HEADER_SIZE: usize = 0x80;
+
+/// Tracks of an NSF rip, what the jukebox needs from the header
+#[derive(Debug, Clone, PartialEq)]
+pub struct NsfInfo {
+    pub track_count: i32,
+    pub title: Option<String>,
+}
+
+impl Default for NsfInfo {
+    // Still playable from the first song
+    fn default() -> Self {
+        NsfInfo {
+            track_count: 1,
+            title: None,
+        }
+    }
+}
+
+// Null padded, `<?>` is the convention for unknown
+fn read_text(field: &[u8]) -> Option<String> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    let text = String::from_utf8_lossy(&field[..end]).trim().to_owned();
+    if text.is_empty() || text == "<?>" {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Header of an NSF file, corrupt headers read as a single unnamed track
+pub fn parse_nsf(data: &[u8]) -> NsfInfo {
+    if data.len() < HEADER_SIZE || !data.starts_with(MAGIC) || data[6] == 0 {
+        return NsfInfo::default();
+    }
+    NsfInfo {
+        track_count: data[6] as i32,
+        title: read_text(&data[0x0E..0x2E]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nsf::*;
+
+    const FIXTURE: &[u8] = include_bytes!("nsf_fixture.nsf");
+
+    #[test]
+    fn nsf_header() {
+        assert_eq!(
+            parse_nsf(FIXTURE),
+            NsfInfo {
+                track_count: 12,
+                title: Some("Nesbox Jukebox".into()),
+            }
+        );
+
+        let mut unknown = FIXTURE.to_vec();
+        unknown[0x0E..0x11].copy_from_slice(b"<?>");
+        unknown[0x11] = 0;
+        assert_eq!(parse_nsf(&unknown).title, None);
+
+        // Corrupt files still get a track
+        assert_eq!(parse_nsf(&FIXTURE[..0x40]), NsfInfo::default());
+        assert_eq!(parse_nsf(b"PK\x03\x04"), NsfInfo::default());
+        let mut empty = FIXTURE.to_vec();
+        empty[6] = 0;
+        assert_eq!(parse_nsf(&empty), NsfInfo::default());
+    }
+}
@@ -0,0 +1,425 @@
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::collections::HashSet;
+use tokio::sync::Notify;
+
+use crate::db::models::{NewOutboxRecord, OutboxRecord};
+use crate::db::root::DB_POOL;
+use crate::db::schema::outbox_events;
+use crate::metrics;
+use crate::schemas::{
+    favorite::get_favorite_user_ids,
+    friend::get_friend,
+    game::get_games_by_ids,
+    invite::get_invite,
+    notify::{notify, notify_game_change, notify_ids, GameChange, ScNotifyMessageBuilder},
+};
+
+const BATCH_SIZE: i64 = 100;
+const RETENTION_DAYS: i64 = 7;
+
+lazy_static! {
+    // Committed events are waiting, the sweep in main catches missed wakeups
+    static ref WAKE: Notify = Notify::new();
+}
+
+/// Written in the transaction of the change, ids only: messages are built from
+/// current rows on delivery, so a late redelivery never sends stale data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutboxEvent {
+    NewInvite { invite_id: i32, target_id: i32 },
+    DeleteInvite { invite_id: i32, target_id: i32 },
+    ApplyFriend { user_id: i32, target_id: i32 },
+    AcceptFriend { user_id: i32, target_id: i32 },
+    DeleteFriend { user_id: i32, target_id: i32 },
+    GameCreated { game_id: i32 },
+    GameUpdated { game_id: i32 },
+    DeprecateGame { game_id: i32 },
+}
+
+impl OutboxEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboxEvent::NewInvite { .. } => "new_invite",
+            OutboxEvent::DeleteInvite { .. } => "delete_invite",
+            OutboxEvent::ApplyFriend { .. } => "apply_friend",
+            OutboxEvent::AcceptFriend { .. } => "accept_friend",
+            OutboxEvent::DeleteFriend { .. } => "delete_friend",
+            OutboxEvent::GameCreated { .. } => "game_created",
+            OutboxEvent::GameUpdated { .. } => "game_updated",
+            OutboxEvent::DeprecateGame { .. } => "deprecate_game",
+        }
+    }
+
+    /// Events of one audience are delivered in commit order
+    pub fn audience(&self) -> String {
+        match self {
+            OutboxEvent::NewInvite { target_id, .. }
+            | OutboxEvent::DeleteInvite { target_id, .. }
+            | OutboxEvent::ApplyFriend { target_id, .. }
+            | OutboxEvent::AcceptFriend { target_id, .. }
+            | OutboxEvent::DeleteFriend { target_id, .. } => format!("user:{}", target_id),
+            OutboxEvent::GameCreated { .. }
+            | OutboxEvent::GameUpdated { .. }
+            | OutboxEvent::DeprecateGame { .. } => "games".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxRow {
+    pub id: i32,
+    pub event: OutboxEvent,
+}
+
+/// Committed events, stubbed in tests
+pub trait OutboxStore {
+    /// Undelivered rows in id order
+    fn load_pending(&self, limit: i64) -> Result<Vec<OutboxRow>, String>;
+    fn mark_delivered(&self, id: i32) -> Result<(), String>;
+}
+
+/// Deliver then mark, a crash in between redelivers: at least once.
+/// A failed event holds back the rest of its audience until the next run
+pub fn dispatch_pending(
+    store: &dyn OutboxStore,
+    limit: i64,
+    deliver: &mut dyn FnMut(&OutboxEvent) -> Result<(), String>,
+) -> Result<usize, String> {
+    let mut blocked = HashSet::new();
+    let mut count = 0;
+    for row in store.load_pending(limit)? {
+        let audience = row.event.audience();
+        if blocked.contains(&audience) {
+            continue;
+        }
+        match deliver(&row.event) {
+            Ok(()) => {
+                store.mark_delivered(row.id)?;
+                metrics::inc_counter("nesbox_outbox_delivered_total", row.event.kind());
+                count += 1;
+            }
+            Err(err) => {
+                log::warn!("Outbox event {} failed: {}", row.id, err);
+                metrics::inc_counter("nesbox_outbox_failed_total", row.event.kind());
+                blocked.insert(audience);
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Call inside the transaction of the change, then `wake_outbox` after commit
+pub fn write_outbox_event(conn: &PgConnection, event: &OutboxEvent) -> QueryResult<()> {
+    let payload = serde_json::to_value(event).unwrap();
+    let new_record = NewOutboxRecord {
+        kind: event.kind(),
+        audience: &event.audience(),
+        payload: &payload,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(outbox_events::table)
+        .values(&new_record)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn wake_outbox() {
+    WAKE.notify_one();
+}
+
+pub async fn wait_outbox() {
+    WAKE.notified().await;
+}
+
+struct PgOutbox<'a> {
+    conn: &'a PgConnection,
+}
+
+impl OutboxStore for PgOutbox<'_> {
+    fn load_pending(&self, limit: i64) -> Result<Vec<OutboxRow>, String> {
+        use self::outbox_events::dsl::*;
+
+        let records = outbox_events
+            .filter(delivered_at.is_null())
+            .order(id.asc())
+            .limit(limit)
+            .load::<OutboxRecord>(self.conn)
+            .map_err(|err| err.to_string())?;
+
+        let mut rows = Vec::new();
+        for record in records {
+            match serde_json::from_value::<OutboxEvent>(record.payload) {
+                Ok(event) => rows.push(OutboxRow {
+                    id: record.id,
+                    event,
+                }),
+                // Written by a newer build, retrying won't help
+                Err(err) => {
+                    log::error!("Outbox event {} unreadable: {}", record.id, err);
+                    self.mark_delivered(record.id)?;
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    fn mark_delivered(&self, eid: i32) -> Result<(), String> {
+        use self::outbox_events::dsl::*;
+
+        diesel::update(outbox_events.filter(id.eq(eid)))
+            .set(delivered_at.eq(Utc::now().naive_utc()))
+            .execute(self.conn)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+// Rows gone since the commit are skipped, a later event covers them
+fn deliver_event(conn: &PgConnection, event: &OutboxEvent) -> Result<(), String> {
+    match *event {
+        OutboxEvent::NewInvite {
+            invite_id,
+            target_id,
+        } => match get_invite(conn, target_id, invite_id) {
+            Ok(invite) => notify(
+                target_id,
+                ScNotifyMessageBuilder::default()
+                    .new_invite(invite)
+                    .build()
+                    .unwrap(),
+            ),
+            Err(_) => log::debug!("Outbox skip {:?}", event),
+        },
+        OutboxEvent::DeleteInvite {
+            invite_id,
+            target_id,
+        } => notify(
+            target_id,
+            ScNotifyMessageBuilder::default()
+                .delete_invite(invite_id)
+                .build()
+                .unwrap(),
+        ),
+        OutboxEvent::ApplyFriend { user_id, target_id } => {
+            match get_friend(conn, target_id, user_id) {
+                Ok(friend) => notify(
+                    target_id,
+                    ScNotifyMessageBuilder::default()
+                        .apply_friend(friend)
+                        .build()
+                        .unwrap(),
+                ),
+                Err(_) => log::debug!("Outbox skip {:?}", event),
+            }
+        }
+        OutboxEvent::AcceptFriend { user_id, target_id } => {
+            match get_friend(conn, target_id, user_id) {
+                Ok(friend) => notify(
+                    target_id,
+                    ScNotifyMessageBuilder::default()
+                        .accept_friend(friend)
+                        .build()
+                        .unwrap(),
+                ),
+                Err(_) => log::debug!("Outbox skip {:?}", event),
+            }
+        }
+        OutboxEvent::DeleteFriend { user_id, target_id } => notify(
+            target_id,
+            ScNotifyMessageBuilder::default()
+                .delete_friend(user_id)
+                .build()
+                .unwrap(),
+        ),
+        OutboxEvent::GameCreated { game_id } => {
+            let games = get_games_by_ids(conn, vec![game_id]).map_err(|err| err.to_string())?;
+            if let Some(game) = games.into_iter().next() {
+                if let Err(err) = notify_game_change(GameChange::Created(Box::new(game))) {
+                    log::error!("Notify new game: {:?}", err);
+                }
+            }
+        }
+        OutboxEvent::GameUpdated { game_id } => {
+            if let Err(err) = notify_game_change(GameChange::Updated(game_id)) {
+                log::error!("Notify update game: {:?}", err);
+            }
+        }
+        OutboxEvent::DeprecateGame { game_id } => {
+            let games = get_games_by_ids(conn, vec![game_id]).map_err(|err| err.to_string())?;
+            if let Some(game) = games.into_iter().next() {
+                notify_ids(
+                    get_favorite_user_ids(conn, game_id),
+                    ScNotifyMessageBuilder::default()
+                        .deprecate_game(game)
+                        .build()
+                        .unwrap(),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send committed events, blocking, run after wakeups and on an interval
+pub fn dispatch_outbox() -> Result<usize, String> {
+    let conn = DB_POOL.get().map_err(|err| err.to_string())?;
+    let store = PgOutbox { conn: &conn };
+    dispatch_pending(&store, BATCH_SIZE, &mut |event| deliver_event(&conn, event))
+}
+
+pub fn prune_outbox(conn: &PgConnection) -> QueryResult<usize> {
+    use self::outbox_events::dsl::*;
+
+    let deadline = Utc::now().naive_utc() - Duration::days(RETENTION_DAYS);
+    diesel::delete(outbox_events.filter(delivered_at.lt(deadline))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::*;
+    use std::cell::{Cell, RefCell};
+
+    // Table rows and outbox rows commit or roll back together, like one transaction
+    #[derive(Default)]
+    struct MemoryDb {
+        invites: RefCell<Vec<i32>>,
+        outbox: RefCell<Vec<(OutboxRow, bool)>>,
+        // marking fails from this call on, the process "crashes"
+        crash_on_mark: Cell<Option<usize>>,
+        marks: Cell<usize>,
+    }
+
+    impl MemoryDb {
+        fn create_invite(&self, invite_id: i32, target_id: i32, fail: bool) -> Result<(), String> {
+            let staged = OutboxEvent::NewInvite {
+                invite_id,
+                target_id,
+            };
+            if fail {
+                return Err("constraint violation".into());
+            }
+            self.invites.borrow_mut().push(invite_id);
+            let id = self.outbox.borrow().len() as i32 + 1;
+            self.outbox
+                .borrow_mut()
+                .push((OutboxRow { id, event: staged }, false));
+            Ok(())
+        }
+    }
+
+    impl OutboxStore for MemoryDb {
+        fn load_pending(&self, limit: i64) -> Result<Vec<OutboxRow>, String> {
+            Ok(self
+                .outbox
+                .borrow()
+                .iter()
+                .filter(|(_, delivered)| !delivered)
+                .map(|(row, _)| row.clone())
+                .take(limit as usize)
+                .collect())
+        }
+
+        fn mark_delivered(&self, id: i32) -> Result<(), String> {
+            if Some(self.marks.get()) == self.crash_on_mark.get() {
+                return Err("connection reset".into());
+            }
+            self.marks.set(self.marks.get() + 1);
+            let mut outbox = self.outbox.borrow_mut();
+            outbox.iter_mut().find(|(row, _)| row.id == id).unwrap().1 = true;
+            Ok(())
+        }
+    }
+
+    fn invite_ids(events: &[OutboxEvent], target: i32) -> Vec<i32> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                OutboxEvent::NewInvite {
+                    invite_id,
+                    target_id,
+                } if *target_id == target => Some(*invite_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outbox_crash_recovery() {
+        let db = MemoryDb::default();
+        let mut sent = Vec::new();
+
+        db.create_invite(1, 10, false).unwrap();
+        // Rolled back, no phantom event
+        assert!(db.create_invite(2, 10, true).is_err());
+        db.create_invite(3, 10, false).unwrap();
+        db.create_invite(4, 20, false).unwrap();
+        // Crash before any dispatch, the events are committed and wait
+
+        // Crash after sending the first event but before marking it
+        db.crash_on_mark.set(Some(0));
+        let mut record = |event: &OutboxEvent| {
+            sent.push(event.clone());
+            Ok(())
+        };
+        assert!(dispatch_pending(&db, 10, &mut record).is_err());
+        db.crash_on_mark.set(None);
+
+        // Restart, the unmarked event is sent again rather than lost
+        let mut record = |event: &OutboxEvent| {
+            sent.push(event.clone());
+            Ok(())
+        };
+        assert_eq!(dispatch_pending(&db, 10, &mut record), Ok(3));
+        assert_eq!(invite_ids(&sent, 10), vec![1, 1, 3]);
+        assert_eq!(invite_ids(&sent, 20), vec![4]);
+        assert_eq!(*db.invites.borrow(), vec![1, 3, 4]);
+        assert_eq!(dispatch_pending(&db, 10, &mut |_| Ok(())), Ok(0));
+    }
+
+    #[test]
+    fn outbox_audience_order() {
+        let db = MemoryDb::default();
+        db.create_invite(1, 10, false).unwrap();
+        db.create_invite(2, 20, false).unwrap();
+        db.create_invite(3, 10, false).unwrap();
+        db.create_invite(4, 20, false).unwrap();
+
+        // A failure holds back its audience only
+        let mut sent = Vec::new();
+        let mut flaky = |event: &OutboxEvent| {
+            if event.audience() == "user:20" {
+                return Err("closed".into());
+            }
+            sent.push(event.clone());
+            Ok(())
+        };
+        assert_eq!(dispatch_pending(&db, 10, &mut flaky), Ok(2));
+        assert_eq!(invite_ids(&sent, 10), vec![1, 3]);
+
+        let mut record = |event: &OutboxEvent| {
+            sent.push(event.clone());
+            Ok(())
+        };
+        assert_eq!(dispatch_pending(&db, 10, &mut record), Ok(2));
+        assert_eq!(invite_ids(&sent, 20), vec![2, 4]);
+    }
+
+    #[test]
+    fn outbox_payload() {
+        let event = OutboxEvent::ApplyFriend {
+            user_id: 1,
+            target_id: 2,
+        };
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"type": "apply_friend", "user_id": 1, "target_id": 2})
+        );
+        assert_eq!(serde_json::from_value::<OutboxEvent>(value).unwrap(), event);
+        assert_eq!(OutboxEvent::GameUpdated { game_id: 1 }.audience(), "games");
+    }
+}
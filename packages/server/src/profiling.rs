@@ -0,0 +1,256 @@
+use chrono::{DateTime, Duration, Utc};
+use juniper::{DefaultScalarValue, GraphQLObject, SchemaType};
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::idempotency::{get_root_fields, OperationInfo};
+use crate::metrics;
+
+// Samples kept for `slowResolvers`
+const WINDOW_MINUTES: i64 = 60;
+const MAX_SAMPLES: usize = 10_000;
+const MAX_EXAMPLES: usize = 3;
+
+lazy_static! {
+    // 1 in N graphql requests is sampled, 0 is off
+    pub static ref QUERY_SAMPLE_EVERY: u64 = env::var("QUERY_SAMPLE_EVERY")
+        .ok()
+        .and_then(|every| every.parse().ok())
+        .unwrap_or(100);
+    static ref SAMPLES: Mutex<VecDeque<ResolverSample>> = Mutex::new(VecDeque::new());
+}
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    // Set for the execution of a sampled request, read when a connection is returned
+    static CURRENT: Arc<Mutex<QueryStats>>;
+}
+
+/// Database use of one sampled request
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct QueryStats {
+    // connection checkouts, diesel has no hook for single statements
+    pub queries: u32,
+    // waiting for the pool and holding the connection
+    pub db_ms: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolverSample {
+    pub at: DateTime<Utc>,
+    pub resolver: String,
+    pub operation: Option<String>,
+    pub duration_ms: f64,
+    pub stats: QueryStats,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScSlowResolver {
+    // root fields of the operation, joined by comma
+    pub resolver: String,
+    pub samples: i32,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+    pub avg_db_ms: f64,
+    pub avg_queries: f64,
+    // operation names seen for this resolver
+    pub operations: Vec<String>,
+}
+
+/// Stats of the sampled request running on this task, `None` when sampling is off
+pub fn current_stats() -> Option<Arc<Mutex<QueryStats>>> {
+    if *QUERY_SAMPLE_EVERY == 0 {
+        return None;
+    }
+    CURRENT.try_with(|stats| stats.clone()).ok()
+}
+
+pub fn add_query(stats: &Mutex<QueryStats>, checkout: Instant) {
+    let mut stats = stats.lock().unwrap();
+    stats.queries += 1;
+    stats.db_ms += checkout.elapsed().as_secs_f64() * 1000.0;
+}
+
+/// Name that requests are grouped by, the root fields can't be told apart
+/// because they share the connection checkouts of the request
+pub fn get_resolver_name(schema: &SchemaType<DefaultScalarValue>, op: &OperationInfo) -> String {
+    match get_root_fields(schema, &op.query, op.operation_name.as_deref()) {
+        Some((_, mut fields)) if !fields.is_empty() => {
+            fields.sort();
+            fields.dedup();
+            fields.join(",")
+        }
+        _ => "unknown".to_owned(),
+    }
+}
+
+/// Runs the execution of a graphql request, 1 in `QUERY_SAMPLE_EVERY` is measured
+pub async fn sample_request<F: Future>(
+    schema: &SchemaType<'_, DefaultScalarValue>,
+    op: &OperationInfo,
+    fut: F,
+) -> F::Output {
+    if *QUERY_SAMPLE_EVERY == 0
+        || NEXT_REQUEST.fetch_add(1, Ordering::Relaxed) % *QUERY_SAMPLE_EVERY != 0
+    {
+        return fut.await;
+    }
+    let stats = Arc::new(Mutex::new(QueryStats::default()));
+    let start = Instant::now();
+    let output = CURRENT.scope(stats.clone(), fut).await;
+    let stats = *stats.lock().unwrap();
+    record_sample(ResolverSample {
+        at: Utc::now(),
+        resolver: get_resolver_name(schema, op),
+        operation: op.operation_name.clone(),
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+        stats,
+    });
+    output
+}
+
+fn record_sample(sample: ResolverSample) {
+    metrics::observe_kind(
+        "nesbox_resolver_duration_ms",
+        &sample.resolver,
+        sample.duration_ms,
+    );
+    metrics::observe_kind(
+        "nesbox_resolver_db_ms",
+        &sample.resolver,
+        sample.stats.db_ms,
+    );
+    metrics::observe_kind(
+        "nesbox_resolver_queries",
+        &sample.resolver,
+        sample.stats.queries as f64,
+    );
+
+    let mut samples = SAMPLES.lock().unwrap();
+    let deadline = sample.at - Duration::minutes(WINDOW_MINUTES);
+    while samples.front().map_or(false, |oldest| oldest.at < deadline) {
+        samples.pop_front();
+    }
+    if samples.len() >= MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Nearest rank
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p * sorted.len() as f64).ceil() as usize).max(1);
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+/// Worst p99 first over the last hour
+pub fn summarize_samples<'a>(
+    samples: impl Iterator<Item = &'a ResolverSample>,
+    now: DateTime<Utc>,
+    limit: usize,
+) -> Vec<ScSlowResolver> {
+    let deadline = now - Duration::minutes(WINDOW_MINUTES);
+    let mut groups: BTreeMap<&str, Vec<&ResolverSample>> = BTreeMap::new();
+    for sample in samples.filter(|sample| sample.at >= deadline) {
+        groups.entry(&sample.resolver).or_default().push(sample);
+    }
+    let mut list: Vec<ScSlowResolver> = groups
+        .into_iter()
+        .map(|(resolver, samples)| {
+            let count = samples.len() as f64;
+            let mut durations: Vec<f64> = samples.iter().map(|s| s.duration_ms).collect();
+            durations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let mut operations: Vec<String> = Vec::new();
+            // Slowest examples are the useful ones
+            let mut slowest = samples.clone();
+            slowest.sort_by(|a, b| b.duration_ms.partial_cmp(&a.duration_ms).unwrap());
+            for name in slowest.iter().filter_map(|s| s.operation.as_ref()) {
+                if operations.len() < MAX_EXAMPLES && !operations.contains(name) {
+                    operations.push(name.clone());
+                }
+            }
+            ScSlowResolver {
+                resolver: resolver.to_owned(),
+                samples: samples.len() as i32,
+                p99_ms: percentile(&durations, 0.99),
+                max_ms: *durations.last().unwrap(),
+                avg_db_ms: samples.iter().map(|s| s.stats.db_ms).sum::<f64>() / count,
+                avg_queries: samples.iter().map(|s| s.stats.queries as f64).sum::<f64>() / count,
+                operations,
+            }
+        })
+        .collect();
+    list.sort_by(|a, b| b.p99_ms.partial_cmp(&a.p99_ms).unwrap());
+    list.truncate(limit);
+    list
+}
+
+pub fn get_slow_resolvers(limit: usize) -> Vec<ScSlowResolver> {
+    summarize_samples(SAMPLES.lock().unwrap().iter(), Utc::now(), limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiling::*;
+    use crate::schemas::root::create_schema;
+
+    fn sample(
+        resolver: &str,
+        operation: &str,
+        duration_ms: f64,
+        minutes_ago: i64,
+    ) -> ResolverSample {
+        ResolverSample {
+            at: Utc::now() - Duration::minutes(minutes_ago),
+            resolver: resolver.into(),
+            operation: Some(operation.into()),
+            duration_ms,
+            stats: QueryStats {
+                queries: 2,
+                db_ms: duration_ms / 2.0,
+            },
+        }
+    }
+
+    #[test]
+    fn slow_resolvers() {
+        let schema = create_schema();
+        let name = |query: &str| {
+            let op = OperationInfo {
+                query: query.into(),
+                operation_name: None,
+            };
+            get_resolver_name(&schema.schema, &op)
+        };
+        assert_eq!(name("{ games { id } }"), "games");
+        assert_eq!(
+            name("{ friends { id } games { id } friends { id } }"),
+            "friends,games"
+        );
+        assert_eq!(name("{ ...Fields }"), "unknown");
+
+        let mut samples: Vec<_> = (1..=100)
+            .map(|ms| sample("games", "Games", ms as f64, 1))
+            .collect();
+        samples.push(sample("games", "GamesPage", 500.0, 1));
+        samples.push(sample("comments", "Comments", 30.0, 1));
+        // Out of the window
+        samples.push(sample("friends", "Friends", 9000.0, 61));
+
+        let list = summarize_samples(samples.iter(), Utc::now(), 10);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].resolver, "games");
+        assert_eq!(list[0].samples, 101);
+        assert_eq!(list[0].p99_ms, 100.0);
+        assert_eq!(list[0].max_ms, 500.0);
+        assert_eq!(list[0].avg_queries, 2.0);
+        assert_eq!(list[0].operations, vec!["GamesPage", "Games"]);
+        assert_eq!(list[1].resolver, "comments");
+        assert_eq!(summarize_samples(samples.iter(), Utc::now(), 1).len(), 1);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::env;
+
+use crate::idempotency::{is_name, next_token};
+
+// Bounds the recursion, anything this deep is over any sensible limit
+const MAX_NESTING: usize = 256;
+
+fn var_or(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    // The playground's introspection query nests 13 levels deep
+    pub static ref QUERY_LIMITS: QueryCost = QueryCost {
+        depth: var_or("MAX_QUERY_DEPTH", 15),
+        complexity: var_or("MAX_QUERY_COMPLEXITY", 1000),
+    };
+}
+
+/// Deepest field nesting and number of fields once fragments are expanded
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueryCost {
+    pub depth: usize,
+    pub complexity: usize,
+}
+
+impl QueryCost {
+    /// Both within `limits`, a zero limit is no limit
+    pub fn is_within(&self, limits: &QueryCost) -> bool {
+        (limits.depth == 0 || self.depth <= limits.depth)
+            && (limits.complexity == 0 || self.complexity <= limits.complexity)
+    }
+
+    fn add(self, other: QueryCost) -> QueryCost {
+        QueryCost {
+            depth: self.depth.max(other.depth),
+            complexity: self.complexity.saturating_add(other.complexity),
+        }
+    }
+}
+
+enum Selection<'a> {
+    Field(Vec<Selection<'a>>),
+    Spread(&'a str),
+    Inline(Vec<Selection<'a>>),
+    // nested beyond `MAX_NESTING`, not parsed
+    TooDeep,
+}
+
+/// Skip a balanced `(...)`, `[...]` or `{...}` starting at `pos`
+fn skip_group(tokens: &[&str], pos: &mut usize) -> Option<()> {
+    let mut depth = 0;
+    loop {
+        match *tokens.get(*pos)? {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            _ => (),
+        }
+        *pos += 1;
+        if depth == 0 {
+            return Some(());
+        }
+    }
+}
+
+fn skip_directives(tokens: &[&str], pos: &mut usize) -> Option<()> {
+    while tokens.get(*pos) == Some(&"@") {
+        *pos += 2;
+        if tokens.get(*pos) == Some(&"(") {
+            skip_group(tokens, pos)?;
+        }
+    }
+    Some(())
+}
+
+/// Selections up to the closing `}`, `pos` is just past the opening one
+fn parse_selections<'a>(
+    tokens: &[&'a str],
+    pos: &mut usize,
+    nesting: usize,
+) -> Option<Vec<Selection<'a>>> {
+    if nesting > MAX_NESTING {
+        *pos -= 1;
+        skip_group(tokens, pos)?;
+        return Some(vec![Selection::TooDeep]);
+    }
+    let mut selections = Vec::new();
+    loop {
+        match *tokens.get(*pos)? {
+            "}" => {
+                *pos += 1;
+                return Some(selections);
+            }
+            "." => {
+                *pos += 3;
+                match *tokens.get(*pos)? {
+                    "on" | "@" | "{" => {
+                        if tokens[*pos] == "on" {
+                            *pos += 2;
+                        }
+                        skip_directives(tokens, pos)?;
+                        (tokens.get(*pos)? == &"{").then_some(())?;
+                        *pos += 1;
+                        let children = parse_selections(tokens, pos, nesting + 1)?;
+                        selections.push(Selection::Inline(children));
+                    }
+                    name if is_name(name) => {
+                        *pos += 1;
+                        skip_directives(tokens, pos)?;
+                        selections.push(Selection::Spread(name));
+                    }
+                    _ => return None,
+                }
+            }
+            name if is_name(name) => {
+                *pos += 1;
+                // `alias: field`
+                if tokens.get(*pos) == Some(&":") {
+                    *pos += 2;
+                }
+                if tokens.get(*pos) == Some(&"(") {
+                    skip_group(tokens, pos)?;
+                }
+                skip_directives(tokens, pos)?;
+                let children = if tokens.get(*pos) == Some(&"{") {
+                    *pos += 1;
+                    parse_selections(tokens, pos, nesting + 1)?
+                } else {
+                    Vec::new()
+                };
+                selections.push(Selection::Field(children));
+            }
+            _ => return None,
+        }
+    }
+}
+
+struct Document<'a> {
+    operations: Vec<Vec<Selection<'a>>>,
+    fragments: HashMap<&'a str, Vec<Selection<'a>>>,
+}
+
+fn parse_document(query: &str) -> Option<Document> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some((token, end)) = next_token(query, pos) {
+        tokens.push(token);
+        pos = end;
+    }
+
+    let mut document = Document {
+        operations: Vec::new(),
+        fragments: HashMap::new(),
+    };
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let fragment = match tokens[pos] {
+            "fragment" => {
+                // `fragment Name on Type`
+                pos += 4;
+                Some(*tokens.get(pos - 3)?)
+            }
+            "query" | "mutation" | "subscription" => {
+                pos += 1;
+                if tokens.get(pos).map_or(false, |name| is_name(name)) {
+                    pos += 1;
+                }
+                if tokens.get(pos) == Some(&"(") {
+                    skip_group(&tokens, &mut pos)?;
+                }
+                None
+            }
+            "{" => None,
+            _ => return None,
+        };
+        skip_directives(&tokens, &mut pos)?;
+        (tokens.get(pos)? == &"{").then_some(())?;
+        pos += 1;
+        let selections = parse_selections(&tokens, &mut pos, 0)?;
+        match fragment {
+            Some(name) => {
+                document.fragments.insert(name, selections);
+            }
+            None => document.operations.push(selections),
+        }
+    }
+    Some(document)
+}
+
+/// Fragments are measured once, a cycle or a nesting beyond
+/// `MAX_NESTING` makes the document too costly
+fn measure_selections<'a>(
+    document: &Document<'a>,
+    selections: &[Selection<'a>],
+    fragment_costs: &mut HashMap<&'a str, Option<QueryCost>>,
+    nesting: usize,
+) -> Option<QueryCost> {
+    if nesting > MAX_NESTING {
+        return None;
+    }
+    let mut cost = QueryCost::default();
+    for selection in selections {
+        let selection_cost = match selection {
+            Selection::Field(children) => {
+                let children = measure_selections(document, children, fragment_costs, nesting + 1)?;
+                QueryCost {
+                    depth: children.depth + 1,
+                    complexity: children.complexity.saturating_add(1),
+                }
+            }
+            Selection::Inline(children) => {
+                measure_selections(document, children, fragment_costs, nesting + 1)?
+            }
+            Selection::TooDeep => return None,
+            Selection::Spread(name) => match fragment_costs.get(name) {
+                Some(known) => (*known)?,
+                None => {
+                    // In progress, seen again only through a cycle
+                    fragment_costs.insert(*name, None);
+                    // Unknown fragments are left to the executor to report
+                    let known = match document.fragments.get(name) {
+                        Some(fragment) => {
+                            measure_selections(document, fragment, fragment_costs, nesting + 1)?
+                        }
+                        None => QueryCost::default(),
+                    };
+                    fragment_costs.insert(*name, Some(known));
+                    known
+                }
+            },
+        };
+        cost = cost.add(selection_cost);
+    }
+    Some(cost)
+}
+
+/// Cost of the costliest operation of the document, `None` when it can't be
+/// read, left to the executor to report
+pub fn measure_query(query: &str) -> Option<QueryCost> {
+    let document = parse_document(query)?;
+    let mut fragment_costs = HashMap::new();
+    let mut cost = QueryCost::default();
+    for operation in &document.operations {
+        let operation_cost = measure_selections(&document, operation, &mut fragment_costs, 0)
+            .unwrap_or(QueryCost {
+                depth: usize::MAX,
+                complexity: usize::MAX,
+            });
+        cost = QueryCost {
+            depth: cost.depth.max(operation_cost.depth),
+            complexity: cost.complexity.max(operation_cost.complexity),
+        };
+    }
+    Some(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query_cost::*;
+
+    #[test]
+    fn query_depth_and_complexity() {
+        let cost = |query| measure_query(query).unwrap();
+        assert_eq!(
+            cost("{ account { id nickname } }"),
+            QueryCost {
+                depth: 2,
+                complexity: 3
+            }
+        );
+        assert_eq!(
+            cost(
+                r#"query Q($id: Int!) { a: game(id: $id) @include(if: true) { id comments(input: { text: "{" }) { id } } }"#
+            ),
+            QueryCost {
+                depth: 3,
+                complexity: 4
+            }
+        );
+        // Fragments count where they are spread
+        assert_eq!(
+            cost(
+                "{ friends { ...F ... on ScFriend { user { id } } } } fragment F on ScFriend { user { ...U } } fragment U on ScUserBasic { id nickname }"
+            ),
+            QueryCost {
+                depth: 3,
+                complexity: 6
+            }
+        );
+        // Each operation is measured alone
+        assert_eq!(
+            cost("query A { a { b { c } } } mutation B { d e f g }"),
+            QueryCost {
+                depth: 3,
+                complexity: 4
+            }
+        );
+
+        let nested = format!("{}{}", "{ friends ".repeat(20), "}".repeat(20));
+        assert!(!cost(&nested).is_within(&QueryCost {
+            depth: 15,
+            complexity: 1000
+        }));
+        let cyclic =
+            "{ ...A } fragment A on QueryRoot { a { ...B } } fragment B on Q { b { ...A } }";
+        assert_eq!(cost(cyclic).complexity, usize::MAX);
+        // Rejected without recursing all the way down
+        let deep = format!("{}{}", "{ a ".repeat(100_000), "}".repeat(100_000));
+        assert_eq!(cost(&deep).depth, usize::MAX);
+        assert_eq!(measure_query("{ account {"), None);
+    }
+}
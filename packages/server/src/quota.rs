@@ -0,0 +1,265 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Timelike, Utc};
+use diesel::dsl::sum;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::GraphQLObject;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use crate::db::models::NewUsageCounter;
+use crate::db::schema::usage_counters;
+
+fn var_or(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    // Interactive sessions, far above what a person clicking around reaches
+    pub static ref DAILY_LIMITS: Usage = Usage {
+        requests: var_or("DAILY_REQUEST_QUOTA", 200_000),
+        mutations: var_or("DAILY_MUTATION_QUOTA", 20_000),
+    };
+    pub static ref USAGE_FLUSH_SECONDS: u64 = var_or("USAGE_FLUSH_SECONDS", 30).max(1) as u64;
+    // user_id -> usage of the current day, seeded from the table
+    static ref DAILY: Mutex<HashMap<i32, (NaiveDate, Usage)>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+    // (user_id, hour) -> counted but not yet written
+    static ref UNFLUSHED: Mutex<HashMap<(i32, NaiveDateTime), Usage>> = {
+        let m = HashMap::new();
+        Mutex::new(m)
+    };
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub requests: i64,
+    pub mutations: i64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.requests += other.requests;
+        self.mutations += other.mutations;
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScUsage {
+    requests: f64,
+    mutations: f64,
+    request_limit: f64,
+    mutation_limit: f64,
+    // start of the next period, counters are per UTC day
+    reset_at: f64,
+}
+
+fn get_hour(now: DateTime<Utc>) -> NaiveDateTime {
+    now.date_naive().and_hms_opt(now.hour(), 0, 0).unwrap()
+}
+
+pub fn get_reset_at(now: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_utc(
+        (now.date_naive() + Duration::days(1))
+            .and_hms_opt(0, 0, 0)
+            .unwrap(),
+        Utc,
+    )
+}
+
+/// Whether today's usage of the user is in memory, otherwise `load_usage` first
+pub fn is_usage_loaded(uid: i32, now: DateTime<Utc>) -> bool {
+    DAILY
+        .lock()
+        .unwrap()
+        .get(&uid)
+        .map_or(false, |(day, _)| *day == now.date_naive())
+}
+
+/// Written usage of the day, unwritten counts are in memory already
+pub fn load_usage(conn: &PgConnection, uid: i32, now: DateTime<Utc>) -> QueryResult<Usage> {
+    use self::usage_counters::dsl::*;
+
+    let start = now.date_naive().and_hms_opt(0, 0, 0).unwrap();
+    let (r, m) = usage_counters
+        .select((sum(requests), sum(mutations)))
+        .filter(user_id.eq(uid))
+        .filter(hour.ge(start))
+        .get_result::<(Option<i64>, Option<i64>)>(conn)?;
+    Ok(Usage {
+        requests: r.unwrap_or_default(),
+        mutations: m.unwrap_or_default(),
+    })
+}
+
+pub fn set_loaded_usage(uid: i32, now: DateTime<Utc>, usage: Usage) {
+    let mut daily = DAILY.lock().unwrap();
+    let entry = daily
+        .entry(uid)
+        .or_insert((now.date_naive(), Usage::default()));
+    if entry.0 != now.date_naive() {
+        *entry = (now.date_naive(), Usage::default());
+    }
+    // Requests counted while loading
+    entry.1.add(usage);
+}
+
+/// Count the request, `Err` is when the quota resets
+pub fn consume_quota(
+    uid: i32,
+    mutation: bool,
+    now: DateTime<Utc>,
+    limits: Usage,
+) -> Result<Usage, DateTime<Utc>> {
+    let cost = Usage {
+        requests: 1,
+        mutations: mutation as i64,
+    };
+    let used = {
+        let mut daily = DAILY.lock().unwrap();
+        let entry = daily
+            .entry(uid)
+            .or_insert((now.date_naive(), Usage::default()));
+        if entry.0 != now.date_naive() {
+            *entry = (now.date_naive(), Usage::default());
+        }
+        if entry.1.requests + cost.requests > limits.requests
+            || entry.1.mutations + cost.mutations > limits.mutations
+        {
+            return Err(get_reset_at(now));
+        }
+        entry.1.add(cost);
+        entry.1
+    };
+    UNFLUSHED
+        .lock()
+        .unwrap()
+        .entry((uid, get_hour(now)))
+        .or_default()
+        .add(cost);
+    Ok(used)
+}
+
+pub fn get_usage(uid: i32, now: DateTime<Utc>) -> ScUsage {
+    let used = DAILY
+        .lock()
+        .unwrap()
+        .get(&uid)
+        .filter(|(day, _)| *day == now.date_naive())
+        .map(|(_, usage)| *usage)
+        .unwrap_or_default();
+    ScUsage {
+        requests: used.requests as f64,
+        mutations: used.mutations as f64,
+        request_limit: DAILY_LIMITS.requests as f64,
+        mutation_limit: DAILY_LIMITS.mutations as f64,
+        reset_at: get_reset_at(now).timestamp_millis() as f64,
+    }
+}
+
+fn take_unflushed() -> Vec<NewUsageCounter> {
+    std::mem::take(&mut *UNFLUSHED.lock().unwrap())
+        .into_iter()
+        .map(|((uid, h), usage)| NewUsageCounter {
+            user_id: uid,
+            hour: h,
+            requests: usage.requests as i32,
+            mutations: usage.mutations as i32,
+        })
+        .collect()
+}
+
+fn restore_unflushed(rows: Vec<NewUsageCounter>) {
+    let mut unflushed = UNFLUSHED.lock().unwrap();
+    for row in rows {
+        unflushed
+            .entry((row.user_id, row.hour))
+            .or_default()
+            .add(Usage {
+                requests: row.requests as i64,
+                mutations: row.mutations as i64,
+            });
+    }
+}
+
+/// One upsert for everything counted since the last flush
+pub fn flush_usage(conn: &PgConnection) -> QueryResult<usize> {
+    use self::usage_counters::dsl::*;
+
+    let rows = take_unflushed();
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let result = diesel::insert_into(usage_counters)
+        .values(&rows)
+        .on_conflict((user_id, hour))
+        .do_update()
+        .set((
+            requests.eq(requests + excluded(requests)),
+            mutations.eq(mutations + excluded(mutations)),
+        ))
+        .execute(conn);
+    if result.is_err() {
+        // Try again next time
+        restore_unflushed(rows);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quota::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn daily_quota() {
+        let limits = Usage {
+            requests: 3,
+            mutations: 1,
+        };
+        let now = Utc.with_ymd_and_hms(2022, 11, 21, 23, 30, 0).unwrap();
+        let reset_at = Utc.with_ymd_and_hms(2022, 11, 22, 0, 0, 0).unwrap();
+
+        // Written by another instance earlier today
+        set_loaded_usage(
+            -1,
+            now,
+            Usage {
+                requests: 1,
+                mutations: 0,
+            },
+        );
+        assert!(is_usage_loaded(-1, now));
+        assert_eq!(
+            consume_quota(-1, true, now, limits),
+            Ok(Usage {
+                requests: 2,
+                mutations: 1
+            })
+        );
+        assert_eq!(consume_quota(-1, true, now, limits), Err(reset_at));
+        assert!(consume_quota(-1, false, now, limits).is_ok());
+        assert_eq!(consume_quota(-1, false, now, limits), Err(reset_at));
+
+        let tomorrow = now + Duration::hours(1);
+        assert!(!is_usage_loaded(-1, tomorrow));
+        assert!(consume_quota(-1, true, tomorrow, limits).is_ok());
+        assert_eq!(get_usage(-1, tomorrow).requests, 1.0);
+
+        // Only accepted requests are written, per hour
+        let rows: Vec<_> = take_unflushed()
+            .into_iter()
+            .filter(|row| row.user_id == -1)
+            .map(|row| (row.hour, row.requests, row.mutations))
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.contains(&(get_hour(now), 2, 1)));
+        assert!(rows.contains(&(get_hour(tomorrow), 1, 1)));
+    }
+}
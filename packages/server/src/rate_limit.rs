@@ -0,0 +1,234 @@
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::auth::{extract_token_from_req, UserToken};
+use crate::error::Error as ApiError;
+use crate::handles::get_client_ip;
+use crate::metrics;
+use crate::request::error_envelope;
+
+// Idle buckets are dropped once this many keys are tracked
+const MAX_BUCKETS: usize = 10_000;
+
+fn var_or(name: &str, default: f64) -> f64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value: &f64| *value >= 0.0)
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    // Signed in users, by user id
+    static ref USER_LIMIT: RateLimit = RateLimit {
+        per_second: var_or("RATE_LIMIT_PER_SECOND", 10.0),
+        burst: var_or("RATE_LIMIT_BURST", 40.0),
+    };
+    // Guests and anonymous sessions, by client address
+    static ref GUEST_LIMIT: RateLimit = RateLimit {
+        per_second: var_or("GUEST_RATE_LIMIT_PER_SECOND", 2.0),
+        burst: var_or("GUEST_RATE_LIMIT_BURST", 10.0),
+    };
+    static ref USER_BUCKETS: Mutex<HashMap<String, Bucket>> = Mutex::new(HashMap::new());
+    static ref GUEST_BUCKETS: Mutex<HashMap<String, Bucket>> = Mutex::new(HashMap::new());
+}
+
+/// Token bucket refilled at `per_second` up to `burst`, a zero rate disables it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimit {
+    fn is_disabled(&self) -> bool {
+        self.per_second == 0.0
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst);
+        bucket.updated_at = now;
+    }
+
+    /// Take a token of `key`, `Err` with the seconds until the next one
+    fn take(
+        &self,
+        buckets: &mut HashMap<String, Bucket>,
+        key: &str,
+        now: Instant,
+    ) -> Result<(), u64> {
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < self.burst
+            });
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.per_second).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// Whom a request counts against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateScope {
+    // the user of the token, the client address without one
+    User,
+    // the client address
+    Guest,
+}
+
+/// Refuses requests over the limit of their scope with 429 and `Retry-After`
+pub struct RateLimiter {
+    scope: RateScope,
+    secret: String,
+}
+
+impl RateLimiter {
+    pub fn new(scope: RateScope, secret: &str) -> Self {
+        RateLimiter {
+            scope,
+            secret: secret.to_owned(),
+        }
+    }
+
+    fn check(&self, req: &ServiceRequest) -> Result<(), u64> {
+        let user_id = match self.scope {
+            RateScope::User => {
+                UserToken::peek_user_id(&self.secret, &extract_token_from_req(req.request()))
+            }
+            RateScope::Guest => None,
+        };
+        let (limit, buckets, key) = match user_id {
+            Some(uid) => (&*USER_LIMIT, &*USER_BUCKETS, uid.to_string()),
+            None => (
+                &*GUEST_LIMIT,
+                &*GUEST_BUCKETS,
+                get_client_ip(req.request()).unwrap_or_default(),
+            ),
+        };
+        if limit.is_disabled() {
+            return Ok(());
+        }
+        limit.take(&mut buckets.lock().unwrap(), &key, Instant::now())
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service,
+            limiter: RateLimiter::new(self.scope, &self.secret),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: S,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if let Err(retry_after) = self.limiter.check(&req) {
+            metrics::inc_counter(
+                "nesbox_rate_limited_total",
+                match self.limiter.scope {
+                    RateScope::User => "user",
+                    RateScope::Guest => "guest",
+                },
+            );
+            let resp = HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", retry_after.to_string()))
+                .json(error_envelope(
+                    "too many requests",
+                    ApiError::rate_limited(retry_after as i32),
+                ));
+            return Box::pin(ready(Ok(req.into_response(resp).map_into_right_body())));
+        }
+        let fut = self.service.call(req);
+        Box::pin(async move { fut.await.map(|res| res.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit {
+            per_second: 2.0,
+            burst: 3.0,
+        };
+        let mut buckets = HashMap::new();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limit.take(&mut buckets, "1", start), Ok(()));
+        }
+        assert_eq!(limit.take(&mut buckets, "1", start), Err(1));
+        // Others have their own bucket
+        assert_eq!(limit.take(&mut buckets, "2", start), Ok(()));
+        // Half a second refills one token
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limit.take(&mut buckets, "1", later), Ok(()));
+        assert_eq!(limit.take(&mut buckets, "1", later), Err(1));
+        // Never above the burst however long the wait
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limit.take(&mut buckets, "1", much_later), Ok(()));
+        }
+        assert!(limit.take(&mut buckets, "1", much_later).is_err());
+
+        let slow = RateLimit {
+            per_second: 0.5,
+            burst: 1.0,
+        };
+        assert_eq!(slow.take(&mut buckets, "10.0.0.1", start), Ok(()));
+        assert_eq!(slow.take(&mut buckets, "10.0.0.1", start), Err(2));
+    }
+}
@@ -0,0 +1,238 @@
+use actix_web::HttpResponse;
+use juniper::{http::GraphQLRequest, Value};
+use serde_json::json;
+
+use crate::error::Error;
+
+/// Read the GraphQL request from a json body or a raw `application/graphql` query,
+/// the error is a hint for the client
+/// Media type without parameters, json when unset
+pub fn get_mime(content_type: Option<&str>) -> String {
+    content_type
+        .unwrap_or("application/json")
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+pub fn parse_graphql_request(
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<GraphQLRequest, String> {
+    let mime = get_mime(content_type);
+
+    if body.iter().all(|b| b.is_ascii_whitespace()) {
+        return Err("empty request body, expected a json object with a `query` field".into());
+    }
+
+    match mime.as_str() {
+        "application/json" => serde_json::from_slice::<GraphQLRequest>(body).map_err(|err| {
+            format!(
+                "invalid json at line {}, column {}: {}",
+                err.line(),
+                err.column(),
+                err
+            )
+        }),
+        "application/graphql" => std::str::from_utf8(body)
+            .map(|query| GraphQLRequest::new(query.to_owned(), None, None))
+            .map_err(|_| "query is not valid utf-8".into()),
+        _ => Err(format!(
+            "unsupported content type `{}`, expected `application/json` or `application/graphql`",
+            mime
+        )),
+    }
+}
+
+/// Same envelope as resolver errors, for requests refused before execution
+pub fn error_envelope(message: &str, extensions: Value) -> serde_json::Value {
+    json!({
+        "data": null,
+        "errors": [{
+            "message": message,
+            "extensions": extensions,
+        }],
+    })
+}
+
+/// Inclusive byte range of a single range `Range` header, `Err` when unsatisfiable.
+/// Multiple ranges and other units are served whole
+pub fn parse_range(header: Option<&str>, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match header.and_then(|value| value.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        // Suffix, the last `end` bytes
+        (None, Some(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        (Some(start), None) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Some(start), Some(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+    if len == 0 || range.0 >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+/// Start and total size of a `Content-Range: bytes start-end/total` upload,
+/// the range must be `len` bytes inside the total
+pub fn parse_content_range(header: Option<&str>, len: u64) -> Option<(u64, u64)> {
+    let (range, total) = header?.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end, total) = (
+        start.parse::<u64>().ok()?,
+        end.parse::<u64>().ok()?,
+        total.parse::<u64>().ok()?,
+    );
+    (start <= end && end < total && end - start + 1 == len).then(|| (start, total))
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|index| from + index)
+}
+
+/// Content of the first file part of a `multipart/form-data` body
+pub fn read_multipart_file(content_type: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params
+        .next()
+        .unwrap_or_default()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return Err("expected `multipart/form-data`".into());
+    }
+    let boundary = params
+        .find_map(|param| param.strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+        .ok_or("multipart boundary is missing")?;
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+    // The first delimiter may start the body without a line break
+    let mut start =
+        find(body, &delimiter[2..], 0).ok_or("multipart body is malformed")? + delimiter.len() - 2;
+    loop {
+        if body.get(start..start + 2) == Some(b"--") {
+            return Err("no file in the form".into());
+        }
+        let headers_end = find(body, b"\r\n\r\n", start).ok_or("multipart body is malformed")?;
+        let content = headers_end + 4;
+        let end = find(body, &delimiter, content).ok_or("multipart body is malformed")?;
+        let headers = String::from_utf8_lossy(&body[start..headers_end]).to_lowercase();
+        let is_file = headers
+            .lines()
+            .any(|line| line.starts_with("content-disposition:") && line.contains("filename="));
+        if is_file {
+            return Ok(body[content..end].to_vec());
+        }
+        start = end + delimiter.len();
+    }
+}
+
+/// Clients show the hint
+pub fn bad_request_response(hint: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(error_envelope(hint, Error::bad_request()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request::*;
+
+    #[test]
+    fn wrong_content_type() {
+        let err = parse_graphql_request(Some("text/plain"), b"{\"query\":\"{ a }\"}")
+            .err()
+            .unwrap();
+        assert!(err.contains("text/plain"));
+    }
+
+    #[test]
+    fn invalid_json() {
+        let err = parse_graphql_request(Some("application/json"), b"{\"query\":\"{ a }\",}")
+            .err()
+            .unwrap();
+        assert!(err.starts_with("invalid json at line 1"));
+    }
+
+    #[test]
+    fn empty_body() {
+        assert!(parse_graphql_request(Some("application/json"), b" \n")
+            .err()
+            .unwrap()
+            .starts_with("empty request body"));
+    }
+
+    #[test]
+    fn raw_query() {
+        let req =
+            parse_graphql_request(Some("application/graphql; charset=utf-8"), b"{ a }").unwrap();
+        assert_eq!(serde_json::to_value(req).unwrap()["query"], "{ a }");
+        assert!(parse_graphql_request(None, b"{\"query\":\"{ a }\"}").is_ok());
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_range(None, 100), Ok(None));
+        assert_eq!(parse_range(Some("bytes=0-"), 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range(Some("bytes=10-19"), 100), Ok(Some((10, 19))));
+        assert_eq!(parse_range(Some("bytes=90-200"), 100), Ok(Some((90, 99))));
+        assert_eq!(parse_range(Some("bytes=-30"), 100), Ok(Some((70, 99))));
+        assert_eq!(parse_range(Some("bytes=-300"), 100), Ok(Some((0, 99))));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Err(()));
+        // Served whole
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Ok(None));
+        assert_eq!(parse_range(Some("items=0-1"), 100), Ok(None));
+        assert_eq!(parse_range(Some("bytes=5-1"), 100), Ok(None));
+    }
+
+    #[test]
+    fn upload_ranges() {
+        assert_eq!(
+            parse_content_range(Some("bytes 0-9/100"), 10),
+            Some((0, 100))
+        );
+        assert_eq!(
+            parse_content_range(Some("bytes 90-99/100"), 10),
+            Some((90, 100))
+        );
+        // Body length differs from the range
+        assert_eq!(parse_content_range(Some("bytes 0-9/100"), 9), None);
+        assert_eq!(parse_content_range(Some("bytes 95-104/100"), 10), None);
+        assert_eq!(parse_content_range(Some("bytes */100"), 0), None);
+        assert_eq!(parse_content_range(None, 10), None);
+    }
+
+    #[test]
+    fn bad_request_envelope() {
+        let resp = bad_request_response("hint");
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[test]
+    fn multipart_file() {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--XyZ\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n".to_vec();
+        body.extend_from_slice(b"\x89PNG");
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+        let source = read_multipart_file("multipart/form-data; boundary=XyZ", &body).unwrap();
+        assert_eq!(source, b"\x89PNG");
+        assert!(read_multipart_file("image/png", b"\x89PNG").is_err());
+        assert!(read_multipart_file(
+            "multipart/form-data; boundary=XyZ",
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--XyZ--\r\n"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use data_encoding::HEXLOWER;
+use ring::digest::{digest, SHA256};
+use std::env;
+
+use crate::db::root::DB_POOL;
+use crate::net::{fetch_public, read_body};
+use crate::schemas::game::set_rom_cached;
+use crate::storage::{get_storage, read_all, Storage};
+
+const ALLOW_CONTENT_TYPES: [&str; 4] = [
+    "application/zip",
+    "application/x-zip-compressed",
+    "application/octet-stream",
+    "binary/octet-stream",
+];
+
+// Local file header of a zip archive
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+lazy_static! {
+    static ref ROM_CACHE_MAX_SIZE: u64 = env::var("ROM_CACHE_MAX_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(10 * 1024 * 1024);
+    // Body limit of `/upload/rom`, with room for the multipart framing
+    pub static ref MAX_ROM_UPLOAD_BYTES: usize = *ROM_CACHE_MAX_SIZE as usize + 64 * 1024;
+}
+
+/// Same name as the old cache dir layout so cached roms keep working
+pub fn get_rom_key(gid: i32) -> String {
+    format!("{}.zip", gid)
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    HEXLOWER.encode(digest(&SHA256, data).as_ref())
+}
+
+/// Uploads are stored by content, the same rom is kept once
+pub fn get_upload_key(hash: &str) -> String {
+    format!("roms/uploads/{}.zip", hash)
+}
+
+/// Served by `/rom/upload/{hash}`, used as the `rom` of a game
+pub fn get_upload_url(hash: &str) -> String {
+    format!("/rom/upload/{}", hash)
+}
+
+pub fn is_rom_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+fn parse_upload_url(url: &str) -> Option<&str> {
+    url.strip_prefix("/rom/upload/")
+        .filter(|hash| is_rom_hash(hash))
+}
+
+/// Zipped like the roms linked from issues
+pub fn check_rom(data: &[u8]) -> Result<(), String> {
+    if data.len() as u64 > *ROM_CACHE_MAX_SIZE {
+        return Err(format!("size exceeds {}", *ROM_CACHE_MAX_SIZE));
+    }
+    if !data.starts_with(ZIP_MAGIC) {
+        return Err("rom must be a zip file".into());
+    }
+    Ok(())
+}
+
+/// Url and hash of the stored rom, `true` when it was uploaded before
+pub fn store_uploaded_rom(
+    storage: &dyn Storage,
+    data: &[u8],
+) -> Result<(String, String, bool), String> {
+    let hash = sha256_hex(data);
+    let key = get_upload_key(&hash);
+    let duplicate = storage.exists(&key).map_err(|err| err.to_string())?;
+    if !duplicate {
+        storage
+            .put(&key, data, "application/zip")
+            .map_err(|err| err.to_string())?;
+    }
+    Ok((get_upload_url(&hash), hash, duplicate))
+}
+
+/// Redirects are followed, every hop must resolve to public addresses
+fn fetch_rom(url: &str, expected_hash: Option<&str>) -> Result<(Vec<u8>, String), String> {
+    let resp = fetch_public(url).map_err(|err| err.to_string())?;
+
+    let content_type = resp
+        .header("content-type")
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if !ALLOW_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(format!("content type {}", content_type));
+    }
+
+    let data = read_body(resp, *ROM_CACHE_MAX_SIZE)?;
+
+    let hash = sha256_hex(&data);
+    if let Some(expected) = expected_hash {
+        if !expected.eq_ignore_ascii_case(&hash) {
+            return Err(format!(
+                "hash mismatch, expected {}, got {}",
+                expected, hash
+            ));
+        }
+    }
+
+    Ok((data, hash))
+}
+
+/// Download rom to storage off the request path, an uploaded rom is copied,
+/// other relative rom paths are skipped
+pub fn cache_rom(gid: i32, url: String, expected_hash: Option<String>) {
+    let storage = match get_storage() {
+        Some(storage) => storage,
+        None => return,
+    };
+    let uploaded = parse_upload_url(&url).map(str::to_owned);
+    if uploaded.is_none() && !url.starts_with("http") {
+        return;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let loaded = match uploaded {
+            Some(hash) => read_all(storage, &get_upload_key(&hash))
+                .map(|data| (data, hash))
+                .map_err(|err| err.to_string()),
+            None => fetch_rom(&url, expected_hash.as_deref()),
+        };
+        let result = loaded.and_then(|(data, hash)| {
+            storage
+                .put(&get_rom_key(gid), &data, "application/zip")
+                .map_err(|err| err.to_string())?;
+            Ok(hash)
+        });
+
+        match result {
+            Ok(hash) => {
+                let conn = DB_POOL.get().unwrap();
+                set_rom_cached(&conn, gid, &hash);
+                log::info!("Cached rom {}: {}", gid, url);
+            }
+            Err(err) => log::error!("Cache rom {} failed: {}, {}", gid, url, err),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rom::*;
+
+    #[test]
+    fn rom_uploads() {
+        assert!(check_rom(b"PK\x03\x04rest of the archive").is_ok());
+        assert!(check_rom(b"NES\x1a").is_err());
+        assert!(check_rom(b"").is_err());
+
+        let hash = sha256_hex(b"PK\x03\x04");
+        assert!(is_rom_hash(&hash));
+        assert!(!is_rom_hash(&hash.to_uppercase()));
+        assert_eq!(
+            parse_upload_url(&get_upload_url(&hash)),
+            Some(hash.as_str())
+        );
+        assert_eq!(parse_upload_url("/rom/upload/../1.zip"), None);
+        assert_eq!(parse_upload_url("/roms/demo/a.nes.zip"), None);
+        assert_eq!(get_upload_key(&hash), format!("roms/uploads/{}.zip", hash));
+    }
+}
@@ -0,0 +1,280 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+
+use crate::db::models::{Achievement, NewAchievement, NewUserAchievement};
+use crate::db::schema::{achievements, games, user_achievements};
+use crate::error::Error;
+
+use super::playing::get_playing;
+use super::visibility::visible_games;
+
+const MAX_KEY_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 500;
+// Definitions per game
+const MAX_ACHIEVEMENTS: usize = 200;
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScAchievement {
+    id: i32,
+    game_id: i32,
+    // what the game sends to unlock it
+    pub key: String,
+    name: String,
+    description: String,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScUserAchievement {
+    pub user_id: i32,
+    achievement: ScAchievement,
+    unlocked_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewAchievement {
+    pub key: String,
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScSetAchievementsReq {
+    pub game_id: i32,
+    // added or updated by key, missing ones are kept
+    pub achievements: Vec<ScNewAchievement>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScGrantAchievementReq {
+    pub game_id: i32,
+    pub key: String,
+}
+
+fn convert_to_sc_achievement(achievement: &Achievement) -> ScAchievement {
+    ScAchievement {
+        id: achievement.id,
+        game_id: achievement.game_id,
+        key: achievement.key.clone(),
+        name: achievement.name.clone(),
+        description: achievement.description.clone(),
+    }
+}
+
+fn check_key(key: &str) -> FieldResult<()> {
+    let valid = !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(FieldError::new(
+            format!("key is 1 to {} letters, digits, `_` or `-`", MAX_KEY_LEN),
+            Error::validation(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_new_achievement(req: &ScNewAchievement) -> FieldResult<()> {
+    check_key(&req.key)?;
+    let name_len = req.name.trim().chars().count();
+    if name_len == 0 || name_len > MAX_NAME_LEN {
+        return Err(FieldError::new(
+            format!("name is 1 to {} characters", MAX_NAME_LEN),
+            Error::validation(),
+        ));
+    }
+    if req
+        .description
+        .as_deref()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        > MAX_DESCRIPTION_LEN
+    {
+        return Err(FieldError::new(
+            format!("description is at most {} characters", MAX_DESCRIPTION_LEN),
+            Error::validation(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_game_achievements(conn: &PgConnection, gid: i32) -> FieldResult<Vec<ScAchievement>> {
+    use self::achievements::dsl::*;
+
+    Ok(achievements
+        .filter(game_id.eq(gid))
+        .order(id.asc())
+        .load::<Achievement>(conn)?
+        .iter()
+        .map(convert_to_sc_achievement)
+        .collect())
+}
+
+/// Newest first, of every game without `gid`
+pub fn get_user_achievements(
+    conn: &PgConnection,
+    uid: i32,
+    gid: Option<i32>,
+) -> FieldResult<Vec<ScUserAchievement>> {
+    let mut query = user_achievements::table
+        .inner_join(achievements::table)
+        .select((user_achievements::unlocked_at, achievements::all_columns))
+        .filter(user_achievements::user_id.eq(uid))
+        .order(user_achievements::unlocked_at.desc())
+        .into_boxed();
+    if let Some(gid) = gid {
+        query = query.filter(achievements::game_id.eq(gid));
+    }
+
+    Ok(query
+        .load::<(NaiveDateTime, Achievement)>(conn)?
+        .iter()
+        .map(|(unlocked_at, achievement)| ScUserAchievement {
+            user_id: uid,
+            achievement: convert_to_sc_achievement(achievement),
+            unlocked_at: unlocked_at.timestamp_millis() as f64,
+        })
+        .collect())
+}
+
+/// Definitions of the game after the update
+pub fn set_achievements(
+    conn: &PgConnection,
+    req: &ScSetAchievementsReq,
+) -> FieldResult<Vec<ScAchievement>> {
+    use self::achievements::dsl::*;
+
+    for achievement in &req.achievements {
+        check_new_achievement(achievement)?;
+    }
+    let exists = visible_games()
+        .filter(games::id.eq(req.game_id))
+        .count()
+        .get_result::<i64>(conn)?
+        > 0;
+    if !exists {
+        return Err(FieldError::new("game not found", Error::not_found()));
+    }
+
+    let now = Utc::now().naive_utc();
+    conn.transaction(|| {
+        for achievement in &req.achievements {
+            diesel::insert_into(achievements)
+                .values(&NewAchievement {
+                    game_id: req.game_id,
+                    key: &achievement.key,
+                    name: achievement.name.trim(),
+                    description: achievement
+                        .description
+                        .as_deref()
+                        .unwrap_or_default()
+                        .trim(),
+                    created_at: now,
+                    updated_at: now,
+                })
+                .on_conflict((game_id, key))
+                .do_update()
+                .set((
+                    name.eq(excluded(name)),
+                    description.eq(excluded(description)),
+                    updated_at.eq(excluded(updated_at)),
+                ))
+                .execute(conn)?;
+        }
+        let count = achievements
+            .filter(game_id.eq(req.game_id))
+            .count()
+            .get_result::<i64>(conn)?;
+        if count as usize > MAX_ACHIEVEMENTS {
+            return Err(FieldError::new(
+                format!("a game has at most {} achievements", MAX_ACHIEVEMENTS),
+                Error::validation(),
+            ));
+        }
+        Ok(())
+    })?;
+
+    get_game_achievements(conn, req.game_id)
+}
+
+/// Unlocks while the user plays the game, `true` when it wasn't unlocked before
+pub fn grant_achievement(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScGrantAchievementReq,
+) -> FieldResult<(ScUserAchievement, bool)> {
+    use self::user_achievements::dsl::*;
+
+    if get_playing(conn, uid).map(|room| room.game_id) != Some(req.game_id) {
+        return Err(FieldError::new(
+            "not playing the game",
+            Error::permission_denied(),
+        ));
+    }
+    let achievement = achievements::table
+        .filter(achievements::game_id.eq(req.game_id))
+        .filter(achievements::key.eq(&req.key))
+        .get_result::<Achievement>(conn)
+        .optional()?
+        .ok_or_else(|| FieldError::new("achievement not found", Error::not_found()))?;
+
+    let inserted = diesel::insert_into(user_achievements)
+        .values(&NewUserAchievement {
+            user_id: uid,
+            achievement_id: achievement.id,
+            unlocked_at: Utc::now().naive_utc(),
+        })
+        .on_conflict_do_nothing()
+        .returning(unlocked_at)
+        .get_result::<NaiveDateTime>(conn)
+        .optional()?;
+    let time = match inserted {
+        Some(time) => time,
+        None => user_achievements
+            .select(unlocked_at)
+            .filter(user_id.eq(uid))
+            .filter(achievement_id.eq(achievement.id))
+            .get_result(conn)?,
+    };
+
+    Ok((
+        ScUserAchievement {
+            user_id: uid,
+            achievement: convert_to_sc_achievement(&achievement),
+            unlocked_at: time.timestamp_millis() as f64,
+        },
+        inserted.is_some(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::achievement::*;
+
+    #[test]
+    fn achievement_definitions() {
+        assert!(check_key("first_clear").is_ok());
+        assert!(check_key("no-damage-1").is_ok());
+        assert!(check_key("").is_err());
+        assert!(check_key("first clear").is_err());
+        assert!(check_key(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+
+        let mut req = ScNewAchievement {
+            key: "first_clear".into(),
+            name: " First Clear ".into(),
+            description: None,
+        };
+        assert!(check_new_achievement(&req).is_ok());
+        req.name = "  ".into();
+        assert!(check_new_achievement(&req).is_err());
+        req.name = "First Clear".into();
+        req.description = Some("d".repeat(MAX_DESCRIPTION_LEN + 1));
+        assert!(check_new_achievement(&req).is_err());
+    }
+}
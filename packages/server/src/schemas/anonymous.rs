@@ -0,0 +1,69 @@
+use juniper::{DefaultScalarValue, OperationType, SchemaType};
+
+use crate::idempotency::get_root_fields;
+
+/// Public catalog, the only fields an anonymous session may read
+pub const ANONYMOUS_ALLOWED_QUERIES: [&str; 6] = [
+    "games",
+    "gamesDelta",
+    "kinds",
+    "topGames",
+    "comments",
+    "errorCodes",
+];
+
+/// Trial play only, no saves, rooms or records
+pub const ANONYMOUS_ALLOWED_MUTATIONS: [&str; 2] = ["startTrialPlay", "endTrialPlay"];
+
+/// Anonymous sessions read the catalog and try games, nothing else
+pub fn is_allowed_for_anonymous(
+    schema: &SchemaType<DefaultScalarValue>,
+    query: &str,
+    operation_name: Option<&str>,
+) -> bool {
+    let (allowed, fields) = match get_root_fields(schema, query, operation_name) {
+        Some((OperationType::Query, fields)) => (&ANONYMOUS_ALLOWED_QUERIES[..], fields),
+        Some((OperationType::Mutation, fields)) => (&ANONYMOUS_ALLOWED_MUTATIONS[..], fields),
+        _ => return false,
+    };
+    fields.iter().all(|field| allowed.contains(&field.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::anonymous::*;
+    use crate::schemas::root::create_schema;
+
+    #[test]
+    fn anonymous_whitelist() {
+        let schema = create_schema();
+        let allowed = |query, name| is_allowed_for_anonymous(&schema.schema, query, name);
+        assert!(allowed("{ games { id } }", None));
+        assert!(allowed("query Q { kinds { kind } topGames }", Some("Q")));
+        assert!(!allowed("{ games { id } favorites }", None));
+        assert!(!allowed(
+            "mutation { favoriteGame(input: { gameId: 1, favorite: true }) }",
+            None
+        ));
+        assert!(allowed(
+            "mutation { startTrialPlay(gameId: 1) { expiresAt } }",
+            None
+        ));
+        assert!(!allowed(
+            "mutation { startTrialPlay(gameId: 1) { expiresAt } createRoom(input: { gameId: 1, private: false }) { id } }",
+            None
+        ));
+        assert!(!allowed("{ ...F }", None));
+        assert!(allowed(
+            "{ ...F } fragment F on QueryRoot { games { id } }",
+            None
+        ));
+        assert!(!allowed(
+            "{ ... on QueryRoot { games { id } favorites } }",
+            None
+        ));
+        // Only query and mutation whitelists apply
+        assert!(!allowed("subscription { games }", None));
+        assert!(!allowed("\u{feff}mutation { favoriteGame }", None));
+    }
+}
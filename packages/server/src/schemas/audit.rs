@@ -0,0 +1,171 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{
+    DefaultScalarValue, FieldError, FieldResult, GraphQLInputObject, GraphQLObject, OperationType,
+    SchemaType,
+};
+use serde_json::json;
+use std::env;
+
+use crate::auth::UserToken;
+use crate::db::models::{AuditLog, NewAuditLog};
+use crate::db::schema::audit_logs;
+use crate::error::Error;
+use crate::idempotency::get_root_fields;
+
+use super::notification::{create_notification, ScNotificationKind};
+use super::user::{get_account, is_admin};
+
+/// Mutations an admin may run while seeing what the user sees
+pub const IMPERSONATION_ALLOWED_MUTATIONS: [&str; 2] = ["activityPing", "playHeartbeat"];
+
+const MAX_AUDIT_DETAIL_LEN: usize = 2000;
+
+lazy_static! {
+    // The user is not told by default, support sessions are read only
+    static ref IMPERSONATION_NOTIFY_USER: bool = env::var("IMPERSONATION_NOTIFY_USER")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or_default();
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScImpersonateReq {
+    pub user_id: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScAuditLogsReq {
+    pub user_id: Option<i32>,
+    pub first: Option<i32>,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScAuditLog {
+    id: i32,
+    actor_id: i32,
+    user_id: i32,
+    action: String,
+    detail: String,
+    created_at: f64,
+}
+
+fn convert_to_sc_audit_log(log: &AuditLog) -> ScAuditLog {
+    ScAuditLog {
+        id: log.id,
+        actor_id: log.actor_id,
+        user_id: log.user_id,
+        action: log.action.clone(),
+        detail: log.detail.clone(),
+        created_at: log.created_at.timestamp_millis() as f64,
+    }
+}
+
+/// Rows are append only, the table rejects updates and deletes
+pub fn write_audit_log(
+    conn: &PgConnection,
+    actor: i32,
+    uid: i32,
+    act: &str,
+    text: &str,
+) -> QueryResult<()> {
+    let text: String = text.chars().take(MAX_AUDIT_DETAIL_LEN).collect();
+    let new_log = NewAuditLog {
+        actor_id: actor,
+        user_id: uid,
+        action: act,
+        detail: &text,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(audit_logs::table)
+        .values(&new_log)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_audit_logs(conn: &PgConnection, req: &ScAuditLogsReq) -> FieldResult<Vec<ScAuditLog>> {
+    use self::audit_logs::dsl::*;
+
+    let mut query = audit_logs
+        .order(id.desc())
+        .limit(req.first.unwrap_or(50).clamp(1, 500).into())
+        .into_boxed();
+    if let Some(uid) = req.user_id {
+        query = query.filter(user_id.eq(uid));
+    }
+
+    Ok(query
+        .load::<AuditLog>(conn)?
+        .iter()
+        .map(|log| convert_to_sc_audit_log(log))
+        .collect())
+}
+
+/// Short lived token acting as the user, admins can't be impersonated
+pub fn impersonate(
+    conn: &PgConnection,
+    secret: &str,
+    admin_id: i32,
+    uid: i32,
+) -> FieldResult<String> {
+    if is_admin(conn, uid) {
+        return Err(FieldError::new(
+            "can't impersonate an admin",
+            Error::permission_denied(),
+        ));
+    }
+    let user = get_account(conn, uid)?;
+    write_audit_log(conn, admin_id, uid, "impersonate", "")?;
+
+    if *IMPERSONATION_NOTIFY_USER {
+        create_notification(
+            conn,
+            uid,
+            ScNotificationKind::Announcement,
+            &json!({ "message": "Support viewed your account" }),
+        )?;
+    }
+
+    Ok(UserToken::generate_impersonation_token(
+        secret, &user, admin_id,
+    ))
+}
+
+/// Only whitelisted mutations run during impersonation, queries always do.
+/// A document juniper can't read is refused, whatever it looks like
+pub fn is_allowed_when_impersonated(
+    schema: &SchemaType<DefaultScalarValue>,
+    query: &str,
+    operation_name: Option<&str>,
+) -> bool {
+    match get_root_fields(schema, query, operation_name) {
+        Some((OperationType::Mutation, fields)) => fields
+            .iter()
+            .all(|field| IMPERSONATION_ALLOWED_MUTATIONS.contains(&field.as_str())),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::audit::*;
+    use crate::schemas::root::create_schema;
+
+    #[test]
+    fn impersonation_whitelist() {
+        let schema = create_schema();
+        let allowed = |query| is_allowed_when_impersonated(&schema.schema, query, None);
+        assert!(allowed("{ favorites }"));
+        assert!(allowed("mutation { activityPing }"));
+        assert!(!allowed("mutation { activityPing leaveRoom }"));
+        assert!(!allowed("mutation { p: activityPing ...F }"));
+        assert!(!allowed(
+            "mutation { ...F } fragment F on MutationRoot { leaveRoom }"
+        ));
+        // Not told apart by a scan of the text
+        assert!(!allowed("\u{feff}mutation { leaveRoom }"));
+        assert!(!allowed(""));
+    }
+}
@@ -0,0 +1,193 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::error::Error;
+
+use super::playing::{get_playing, get_room_user_ids};
+use super::room::get_room;
+
+// Samples older than this don't describe the current network
+const STATS_TTL_SECONDS: i64 = 60;
+
+lazy_static! {
+    // room_id -> (lower user id, higher user id) -> latest rtt in ms and when it arrived
+    static ref ROOM_RTT: Mutex<HashMap<i32, HashMap<(i32, i32), (f64, DateTime<Utc>)>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // room_id -> member running the core, the host when not set
+    static ref ROOM_AUTHORITY: Mutex<HashMap<i32, i32>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScRttSample {
+    // the other member
+    pub user_id: i32,
+    // round trip in milliseconds
+    pub rtt: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScRoomStatsReq {
+    pub room_id: i32,
+    pub samples: Vec<ScRttSample>,
+}
+
+/// Clients re-negotiate who runs the core
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScAuthorityChanged {
+    pub room_id: i32,
+    pub user_id: i32,
+}
+
+fn pair(a: i32, b: i32) -> (i32, i32) {
+    (a.min(b), a.max(b))
+}
+
+/// The player whose worst round trip to the other players is smallest.
+/// Stale or missing pairs disqualify a candidate, the current authority wins ties
+pub fn suggest_authority(
+    players: &[i32],
+    rtt: &HashMap<(i32, i32), (f64, DateTime<Utc>)>,
+    current: i32,
+    now: DateTime<Utc>,
+) -> Option<i32> {
+    if players.len() < 2 {
+        return None;
+    }
+    let deadline = now - Duration::seconds(STATS_TTL_SECONDS);
+    players
+        .iter()
+        .filter_map(|candidate| {
+            let worst = players
+                .iter()
+                .filter(|player| *player != candidate)
+                .map(|player| {
+                    rtt.get(&pair(*candidate, *player))
+                        .filter(|(_, at)| *at >= deadline)
+                        .map(|(ms, _)| *ms)
+                })
+                .try_fold(0.0_f64, |worst, ms| ms.map(|ms| worst.max(ms)))?;
+            Some((*candidate, worst))
+        })
+        .min_by(|(a, a_worst), (b, b_worst)| {
+            a_worst
+                .partial_cmp(b_worst)
+                .unwrap()
+                .then((*a != current).cmp(&(*b != current)))
+                .then(a.cmp(b))
+        })
+        .map(|(candidate, _)| candidate)
+}
+
+/// Current authority, back to the host when it left the seats
+pub fn get_authority(rid: i32, host: i32, players: &[i32]) -> i32 {
+    ROOM_AUTHORITY
+        .lock()
+        .unwrap()
+        .get(&rid)
+        .copied()
+        .filter(|uid| players.contains(uid))
+        .unwrap_or(host)
+}
+
+pub fn get_suggested_authority(rid: i32, authority: i32, players: &[i32]) -> Option<i32> {
+    let mut map = ROOM_RTT.lock().unwrap();
+    let rtt = map.entry(rid).or_default();
+    let now = Utc::now();
+    rtt.retain(|_, (_, at)| *at >= now - Duration::seconds(STATS_TTL_SECONDS));
+    suggest_authority(players, rtt, authority, now)
+}
+
+/// Samples from spectators or about them are dropped, `true` when the suggestion changed
+pub fn report_room_stats(conn: &PgConnection, uid: i32, req: &ScRoomStatsReq) -> FieldResult<bool> {
+    let room = get_room(conn, req.room_id)?;
+    if get_playing(conn, uid).map(|room| room.id) != Some(room.id) {
+        return Err(FieldError::new(
+            "not in the room",
+            Error::permission_denied(),
+        ));
+    }
+    let players = get_room_user_ids(conn, room.id);
+    let authority = get_authority(room.id, room.host, &players);
+    let before = get_suggested_authority(room.id, authority, &players);
+
+    let now = Utc::now();
+    let mut map = ROOM_RTT.lock().unwrap();
+    let rtt = map.entry(room.id).or_default();
+    for sample in &req.samples {
+        if sample.user_id != uid && players.contains(&sample.user_id) && sample.rtt >= 0.0 {
+            rtt.insert(pair(uid, sample.user_id), (sample.rtt, now));
+        }
+    }
+    drop(map);
+
+    Ok(get_suggested_authority(room.id, authority, &players) != before)
+}
+
+pub fn set_authority(
+    conn: &PgConnection,
+    uid: i32,
+    rid: i32,
+    target: i32,
+) -> FieldResult<ScAuthorityChanged> {
+    let room = get_room(conn, rid)?;
+    if room.host != uid {
+        return Err(FieldError::new("host only", Error::permission_denied()));
+    }
+    if !get_room_user_ids(conn, room.id).contains(&target) {
+        return Err(FieldError::new(
+            format!("{} is not playing in room {}", target, room.id),
+            Error::username_not_playing(),
+        ));
+    }
+    ROOM_AUTHORITY.lock().unwrap().insert(room.id, target);
+    Ok(ScAuthorityChanged {
+        room_id: room.id,
+        user_id: target,
+    })
+}
+
+pub fn clear_room_authority(rid: i32) {
+    ROOM_AUTHORITY.lock().unwrap().remove(&rid);
+    ROOM_RTT.lock().unwrap().remove(&rid);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::authority::*;
+
+    #[test]
+    fn authority_suggestion() {
+        let now = Utc::now();
+        let fresh = now - Duration::seconds(5);
+        let stale = now - Duration::seconds(61);
+        let mut rtt = HashMap::from([
+            ((1, 2), (40.0, fresh)),
+            ((1, 3), (120.0, fresh)),
+            ((2, 3), (60.0, fresh)),
+        ]);
+
+        // 2 is in the middle: worst 60 against 120 for 1 and 3
+        assert_eq!(suggest_authority(&[1, 2, 3], &rtt, 1, now), Some(2));
+        // Two players see the same rtt, keep the current one
+        assert_eq!(suggest_authority(&[1, 2], &rtt, 2, now), Some(2));
+        assert_eq!(suggest_authority(&[1, 2], &rtt, 1, now), Some(1));
+        // The authority left its seat, lower id wins
+        assert_eq!(suggest_authority(&[1, 2], &rtt, 9, now), Some(1));
+        // Alone there is nothing to choose
+        assert_eq!(suggest_authority(&[1], &rtt, 1, now), None);
+
+        // Stale pairs disqualify, 2 can't be measured against 3 anymore
+        rtt.insert((2, 3), (10.0, stale));
+        assert_eq!(suggest_authority(&[1, 2, 3], &rtt, 2, now), Some(1));
+        rtt.insert((1, 3), (120.0, stale));
+        assert_eq!(suggest_authority(&[1, 2, 3], &rtt, 2, now), None);
+    }
+}
@@ -0,0 +1,231 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::sync::Mutex;
+
+use crate::db::models::{ClientVersion, NewClientVersion};
+use crate::db::schema::client_versions;
+use crate::error::Error;
+
+// Minimum version changes reach every instance within this delay
+const CACHE_SECONDS: i64 = 10;
+const MAX_NAME_LEN: usize = 50;
+const MAX_VERSION_LEN: usize = 20;
+const MAX_CAPABILITIES: usize = 64;
+
+lazy_static! {
+    static ref CACHE: Mutex<Option<(DateTime<Utc>, Vec<ClientVersion>)>> = Mutex::new(None);
+}
+
+/// `clientInfo` connection parameter, or the `X-Client-Info` header as JSON
+#[derive(GraphQLObject, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScClientInfo {
+    pub name: String,
+    pub version: String,
+    // snake case event kinds the client parses, e.g. `room_member`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl ScClientInfo {
+    pub fn supports(&self, kind: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|capability| capability == kind)
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScClientVersion {
+    pub name: String,
+    pub minimum: String,
+    pub updated_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScUpdateClientVersion {
+    pub name: String,
+    // older clients are refused at connect, null lifts the limit
+    pub minimum: Option<String>,
+}
+
+impl From<ClientVersion> for ScClientVersion {
+    fn from(version: ClientVersion) -> Self {
+        Self {
+            name: version.name,
+            minimum: version.minimum,
+            updated_at: version.updated_at.timestamp_millis() as f64,
+        }
+    }
+}
+
+/// `None` when the shape or the lengths are wrong
+pub fn parse_client_info(value: Value) -> Option<ScClientInfo> {
+    serde_json::from_value::<ScClientInfo>(value)
+        .ok()
+        .filter(|info| !info.name.is_empty() && info.name.len() <= MAX_NAME_LEN)
+        .filter(|info| info.version.len() <= MAX_VERSION_LEN)
+        .filter(|info| info.capabilities.len() <= MAX_CAPABILITIES)
+}
+
+/// Dotted numbers, a pre-release suffix of the last part is ignored
+pub fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split(['-', '+'])
+        .next()?
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect()
+}
+
+pub fn compare_versions(a: &[u32], b: &[u32]) -> Ordering {
+    let len = a.len().max(b.len());
+    let pad = |version: &[u32]| {
+        let mut version = version.to_vec();
+        version.resize(len, 0);
+        version
+    };
+    pad(a).cmp(&pad(b))
+}
+
+/// Minimum of the client's name when it is older, unreadable versions are older
+pub fn get_required_upgrade(versions: &[ClientVersion], client: &ScClientInfo) -> Option<String> {
+    let required = versions
+        .iter()
+        .find(|version| version.name == client.name)?;
+    let minimum = parse_version(&required.minimum)?;
+    let outdated = parse_version(&client.version).map_or(true, |version| {
+        compare_versions(&version, &minimum) == Ordering::Less
+    });
+    outdated.then(|| required.minimum.clone())
+}
+
+/// `None` once the cache expired, then `load_client_versions` on a blocking thread
+pub fn get_cached_client_versions() -> Option<Vec<ClientVersion>> {
+    CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(at, _)| Utc::now() - *at < Duration::seconds(CACHE_SECONDS))
+        .map(|(_, versions)| versions.clone())
+}
+
+pub fn load_client_versions(conn: &PgConnection) -> QueryResult<Vec<ClientVersion>> {
+    use self::client_versions::dsl::*;
+
+    let versions = client_versions
+        .order(name.asc())
+        .load::<ClientVersion>(conn)?;
+    *CACHE.lock().unwrap() = Some((Utc::now(), versions.clone()));
+    Ok(versions)
+}
+
+pub fn get_client_versions(conn: &PgConnection) -> FieldResult<Vec<ScClientVersion>> {
+    Ok(load_client_versions(conn)?
+        .into_iter()
+        .map(|version| version.into())
+        .collect())
+}
+
+pub fn update_client_version(
+    conn: &PgConnection,
+    req: &ScUpdateClientVersion,
+) -> FieldResult<Vec<ScClientVersion>> {
+    use self::client_versions::dsl::*;
+
+    let client_name = req.name.trim();
+    if client_name.is_empty() || client_name.len() > MAX_NAME_LEN {
+        return Err(FieldError::new(
+            format!("name must be 1 to {} characters", MAX_NAME_LEN),
+            Error::validation(),
+        ));
+    }
+    match req.minimum.as_deref().map(str::trim) {
+        Some(version) => {
+            if version.len() > MAX_VERSION_LEN || parse_version(version).is_none() {
+                return Err(FieldError::new("invalid version", Error::validation()));
+            }
+            let new_version = NewClientVersion {
+                name: client_name,
+                minimum: version,
+                updated_at: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(client_versions)
+                .values(&new_version)
+                .on_conflict(name)
+                .do_update()
+                .set((
+                    minimum.eq(new_version.minimum),
+                    updated_at.eq(new_version.updated_at),
+                ))
+                .execute(conn)?;
+        }
+        None => {
+            diesel::delete(client_versions.filter(name.eq(client_name))).execute(conn)?;
+        }
+    }
+
+    // Refreshes this process at once, other instances on their next expiry
+    get_client_versions(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::client_info::*;
+    use serde_json::json;
+
+    #[test]
+    fn client_info_params() {
+        let info = parse_client_info(json!({
+            "name": "nesbox-flutter",
+            "version": "1.2.0",
+            "capabilities": ["room_member"],
+        }))
+        .unwrap();
+        assert!(info.supports("room_member"));
+        assert!(!info.supports("authority_changed"));
+        // Capabilities are optional, a name is not
+        assert!(parse_client_info(json!({ "name": "web", "version": "2.0.0" })).is_some());
+        assert!(parse_client_info(json!({ "name": "", "version": "2.0.0" })).is_none());
+        assert!(parse_client_info(json!("nesbox-flutter/1.2.0")).is_none());
+    }
+
+    #[test]
+    fn client_minimum_version() {
+        assert_eq!(parse_version("v1.10.2-beta.1"), Some(vec![1, 10, 2]));
+        assert_eq!(parse_version("1.x"), None);
+        assert_eq!(compare_versions(&[1, 2], &[1, 2, 0]), Ordering::Equal);
+
+        let versions = vec![ClientVersion {
+            name: "nesbox-flutter".into(),
+            minimum: "1.10.0".into(),
+            updated_at: Utc::now().naive_utc(),
+        }];
+        let client = |name: &str, version: &str| ScClientInfo {
+            name: name.into(),
+            version: version.into(),
+            capabilities: vec![],
+        };
+        assert_eq!(
+            get_required_upgrade(&versions, &client("nesbox-flutter", "1.9.9")),
+            Some("1.10.0".into())
+        );
+        assert_eq!(
+            get_required_upgrade(&versions, &client("nesbox-flutter", "unknown")),
+            Some("1.10.0".into())
+        );
+        assert_eq!(
+            get_required_upgrade(&versions, &client("nesbox-flutter", "1.10")),
+            None
+        );
+        assert_eq!(
+            get_required_upgrade(&versions, &client("web", "0.1.0")),
+            None
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Mutex;
+
+use crate::metrics;
+
+use super::notify::{NotifyCoalesce, NotifyError};
+
+/// Changes merged while a window is open
+pub trait Batch: Default + Send + 'static {
+    type Change;
+
+    fn push(&mut self, change: &Self::Change);
+
+    fn is_empty(&self) -> bool;
+}
+
+/// The first change of a window is sent by the caller right away, the rest
+/// are merged and flushed once per window of the class, a window without
+/// changes closes it
+pub struct Coalescer<B> {
+    class: NotifyCoalesce,
+    // `Some` while a window is open
+    pending: Mutex<Option<B>>,
+    flush: fn(B),
+}
+
+impl<B: Batch> Coalescer<B> {
+    pub fn new(class: NotifyCoalesce, flush: fn(B)) -> Self {
+        Coalescer {
+            class,
+            pending: Mutex::new(None),
+            flush,
+        }
+    }
+
+    /// `true` when the caller sends the change itself
+    pub fn offer(&'static self, change: &B::Change) -> Result<bool, NotifyError> {
+        let mut pending = self.pending.lock().map_err(|_| NotifyError::Unavailable)?;
+        if let Some(batch) = pending.as_mut() {
+            metrics::inc_counter("nesbox_notify_coalesced_total", &self.class.to_string());
+            batch.push(change);
+            return Ok(false);
+        }
+        // Without a runtime there is nobody to flush, send every change
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            *pending = Some(B::default());
+            handle.spawn(self.run());
+        }
+        Ok(true)
+    }
+
+    async fn run(&'static self) {
+        loop {
+            tokio::time::sleep(self.class.window()).await;
+            let batch = {
+                let mut pending = match self.pending.lock() {
+                    Ok(pending) => pending,
+                    Err(_) => return,
+                };
+                match pending.as_mut() {
+                    Some(batch) if !batch.is_empty() => std::mem::take(batch),
+                    // Quiet for a whole window, close it
+                    _ => {
+                        *pending = None;
+                        return;
+                    }
+                }
+            };
+            // flushing may need the database
+            let flush = self.flush;
+            if let Err(err) = tokio::task::spawn_blocking(move || flush(batch)).await {
+                log::error!("Flush {}: {:?}", self.class, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::coalesce::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Ids(Vec<i32>);
+
+    impl Batch for Ids {
+        type Change = i32;
+
+        fn push(&mut self, change: &i32) {
+            self.0.push(*change);
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    lazy_static! {
+        static ref FLUSHED: Mutex<Vec<Vec<i32>>> = Mutex::new(Vec::new());
+        static ref IDS: Coalescer<Ids> = Coalescer::new(NotifyCoalesce::GameBurst, |batch| {
+            FLUSHED.lock().unwrap().push(batch.0)
+        });
+    }
+
+    #[actix_web::test]
+    async fn coalesced_emission() {
+        let window = NotifyCoalesce::GameBurst.window();
+
+        // Opens the window, sent by the caller
+        assert!(IDS.offer(&1).unwrap());
+        assert!(!IDS.offer(&2).unwrap());
+        assert!(!IDS.offer(&3).unwrap());
+        tokio::time::sleep(window + window / 2).await;
+        assert_eq!(*FLUSHED.lock().unwrap(), vec![vec![2, 3]]);
+
+        // Still open, merged into the next flush
+        assert!(!IDS.offer(&4).unwrap());
+        tokio::time::sleep(window).await;
+        assert_eq!(FLUSHED.lock().unwrap().last(), Some(&vec![4]));
+
+        // A quiet window closes it, the next change is sent right away
+        tokio::time::sleep(window * 2).await;
+        assert!(IDS.offer(&5).unwrap());
+        assert_eq!(FLUSHED.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,377 @@
+use chrono::Utc;
+use diesel::dsl::max;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::env;
+
+use super::friend::{is_blocked, sanitize_text};
+use super::report::flag_text;
+use super::visibility::{visible_games, visible_users};
+use crate::db::models::{Collection, NewCollection, NewCollectionGame};
+use crate::db::schema::{collection_games, collections, games, users};
+use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
+
+const MAX_NAME_LEN: usize = 50;
+
+lazy_static! {
+    static ref MAX_COLLECTIONS: i64 = env::var("MAX_COLLECTIONS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(50);
+    // per collection
+    static ref MAX_COLLECTION_GAMES: i64 = env::var("MAX_COLLECTION_GAMES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(200);
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewCollection {
+    pub name: String,
+    // private when null
+    pub public: Option<bool>,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScCollection {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub public: bool,
+    // in the owner's order
+    pub game_ids: Vec<i32>,
+    // entries dropped because their game was deleted
+    pub removed_count: i32,
+    created_at: f64,
+    updated_at: f64,
+}
+
+/// `requested` must list every current entry once
+pub fn check_order(current: &[i32], requested: &[i32]) -> bool {
+    let mut current = current.to_vec();
+    let mut requested = requested.to_vec();
+    current.sort_unstable();
+    requested.sort_unstable();
+    current == requested
+}
+
+fn get_entries(conn: &PgConnection, ids: Vec<i32>) -> HashMap<i32, Vec<i32>> {
+    use self::collection_games::dsl::*;
+
+    let mut map: HashMap<i32, Vec<i32>> = HashMap::new();
+    collection_games
+        .select((collection_id, game_id))
+        .filter(collection_id.eq_any(ids))
+        .order(position.asc())
+        .load::<(i32, i32)>(conn)
+        .unwrap_or_default()
+        .into_iter()
+        .for_each(|(cid, gid)| map.entry(cid).or_default().push(gid));
+    map
+}
+
+fn convert_to_sc_collections(conn: &PgConnection, list: Vec<Collection>) -> Vec<ScCollection> {
+    let mut entries = get_entries(conn, list.iter().map(|collection| collection.id).collect());
+    list.into_iter()
+        .map(|collection| ScCollection {
+            id: collection.id,
+            user_id: collection.user_id,
+            game_ids: entries.remove(&collection.id).unwrap_or_default(),
+            name: collection.name,
+            public: collection.public,
+            removed_count: collection.removed_count,
+            created_at: collection.created_at.timestamp_millis() as f64,
+            updated_at: collection.updated_at.timestamp_millis() as f64,
+        })
+        .collect()
+}
+
+fn convert_to_sc_collection(conn: &PgConnection, collection: Collection) -> ScCollection {
+    convert_to_sc_collections(conn, vec![collection]).remove(0)
+}
+
+/// Other users' collections are not found
+fn get_own_collection(conn: &PgConnection, uid: i32, cid: i32) -> FieldResult<Collection> {
+    use self::collections::dsl::*;
+
+    collections
+        .filter(id.eq(cid))
+        .filter(user_id.eq(uid))
+        .get_result::<Collection>(conn)
+        .optional()?
+        .ok_or_else(|| FieldError::new("collection not found", Error::not_found()))
+}
+
+/// Sanitized name and the filter that flagged it
+fn check_name(name: &str) -> FieldResult<(String, Option<&'static str>)> {
+    let name = sanitize_text(&Some(name.to_owned()), MAX_NAME_LEN)
+        .ok_or_else(|| FieldError::new("name is empty", Error::validation()))?;
+    let filter = check_text(ScTextKind::CollectionName, &name)?;
+    Ok((name, filter))
+}
+
+fn flag_name(conn: &PgConnection, collection: &Collection, filter: Option<&str>) {
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            collection.user_id,
+            ScTextKind::CollectionName,
+            &collection.name,
+            filter,
+            None,
+            Some(collection.id),
+        );
+    }
+}
+
+fn touch_collection(conn: &PgConnection, cid: i32) -> QueryResult<Collection> {
+    use self::collections::dsl::*;
+
+    diesel::update(collections.filter(id.eq(cid)))
+        .set(updated_at.eq(Utc::now().naive_utc()))
+        .get_result::<Collection>(conn)
+}
+
+pub fn get_collections(conn: &PgConnection, uid: i32) -> FieldResult<Vec<ScCollection>> {
+    use self::collections::dsl::*;
+
+    let list = collections
+        .filter(user_id.eq(uid))
+        .order(created_at.asc())
+        .load::<Collection>(conn)?;
+    Ok(convert_to_sc_collections(conn, list))
+}
+
+/// Public ones, empty for banned accounts and when either user blocked the other
+pub fn get_user_collections(
+    conn: &PgConnection,
+    viewer: i32,
+    uid: i32,
+) -> FieldResult<Vec<ScCollection>> {
+    use self::collections::dsl::*;
+
+    if viewer == uid {
+        return get_collections(conn, uid);
+    }
+    let visible = visible_users()
+        .filter(users::id.eq(uid))
+        .count()
+        .get_result::<i64>(conn)?
+        > 0;
+    if !visible || is_blocked(conn, viewer, uid) {
+        return Ok(Vec::new());
+    }
+    let list = collections
+        .filter(user_id.eq(uid))
+        .filter(public.eq(true))
+        .order(created_at.asc())
+        .load::<Collection>(conn)?;
+    Ok(convert_to_sc_collections(conn, list))
+}
+
+pub fn create_collection(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScNewCollection,
+) -> FieldResult<ScCollection> {
+    use self::collections::dsl::*;
+
+    let count = collections
+        .filter(user_id.eq(uid))
+        .count()
+        .get_result::<i64>(conn)?;
+    if count >= *MAX_COLLECTIONS {
+        return Err(FieldError::new(
+            format!("at most {} collections", *MAX_COLLECTIONS),
+            Error::collection_limit(),
+        ));
+    }
+    let (checked_name, filter) = check_name(&req.name)?;
+    let new_collection = NewCollection {
+        user_id: uid,
+        name: &checked_name,
+        public: req.public.unwrap_or_default(),
+        created_at: Utc::now().naive_utc(),
+        updated_at: Utc::now().naive_utc(),
+    };
+    let collection = diesel::insert_into(collections)
+        .values(&new_collection)
+        .get_result::<Collection>(conn)?;
+    flag_name(conn, &collection, filter);
+
+    Ok(convert_to_sc_collection(conn, collection))
+}
+
+pub fn rename_collection(
+    conn: &PgConnection,
+    uid: i32,
+    cid: i32,
+    new_name: &str,
+) -> FieldResult<ScCollection> {
+    use self::collections::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    let (checked_name, filter) = check_name(new_name)?;
+    let collection = diesel::update(collections.filter(id.eq(cid)))
+        .set((name.eq(checked_name), updated_at.eq(Utc::now().naive_utc())))
+        .get_result::<Collection>(conn)?;
+    flag_name(conn, &collection, filter);
+
+    Ok(convert_to_sc_collection(conn, collection))
+}
+
+pub fn set_collection_public(
+    conn: &PgConnection,
+    uid: i32,
+    cid: i32,
+    is_public: bool,
+) -> FieldResult<ScCollection> {
+    use self::collections::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    let collection = diesel::update(collections.filter(id.eq(cid)))
+        .set((public.eq(is_public), updated_at.eq(Utc::now().naive_utc())))
+        .get_result::<Collection>(conn)?;
+
+    Ok(convert_to_sc_collection(conn, collection))
+}
+
+pub fn delete_collection(conn: &PgConnection, uid: i32, cid: i32) -> FieldResult<i32> {
+    use self::collections::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    diesel::delete(collections.filter(id.eq(cid))).execute(conn)?;
+    Ok(cid)
+}
+
+/// Appended at the end, adding a game twice keeps its place
+pub fn add_to_collection(
+    conn: &PgConnection,
+    uid: i32,
+    cid: i32,
+    gid: i32,
+) -> FieldResult<ScCollection> {
+    use self::collection_games::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    let exists = visible_games()
+        .filter(games::id.eq(gid))
+        .count()
+        .get_result::<i64>(conn)?
+        > 0;
+    if !exists {
+        return Err(FieldError::new("game not found", Error::not_found()));
+    }
+    let count = collection_games
+        .filter(collection_id.eq(cid))
+        .count()
+        .get_result::<i64>(conn)?;
+    if count >= *MAX_COLLECTION_GAMES {
+        return Err(FieldError::new(
+            format!("at most {} games in a collection", *MAX_COLLECTION_GAMES),
+            Error::collection_limit(),
+        ));
+    }
+    let last = collection_games
+        .select(max(position))
+        .filter(collection_id.eq(cid))
+        .get_result::<Option<i32>>(conn)?;
+    let new_entry = NewCollectionGame {
+        collection_id: cid,
+        game_id: gid,
+        position: last.map_or(0, |last| last + 1),
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(collection_games)
+        .values(&new_entry)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    Ok(convert_to_sc_collection(conn, touch_collection(conn, cid)?))
+}
+
+pub fn remove_from_collection(
+    conn: &PgConnection,
+    uid: i32,
+    cid: i32,
+    gid: i32,
+) -> FieldResult<ScCollection> {
+    use self::collection_games::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    diesel::delete(
+        collection_games
+            .filter(collection_id.eq(cid))
+            .filter(game_id.eq(gid)),
+    )
+    .execute(conn)?;
+
+    Ok(convert_to_sc_collection(conn, touch_collection(conn, cid)?))
+}
+
+/// `gids` is the new order of every entry
+pub fn reorder_collection(
+    conn: &PgConnection,
+    uid: i32,
+    cid: i32,
+    gids: &[i32],
+) -> FieldResult<ScCollection> {
+    use self::collection_games::dsl::*;
+
+    get_own_collection(conn, uid, cid)?;
+    let current = get_entries(conn, vec![cid])
+        .remove(&cid)
+        .unwrap_or_default();
+    if !check_order(&current, gids) {
+        return Err(FieldError::new(
+            "order must list every game of the collection once",
+            Error::validation(),
+        ));
+    }
+    let collection = conn.transaction(|| {
+        for (index, gid) in gids.iter().enumerate() {
+            diesel::update(
+                collection_games
+                    .filter(collection_id.eq(cid))
+                    .filter(game_id.eq(gid)),
+            )
+            .set(position.eq(index as i32))
+            .execute(conn)?;
+        }
+        touch_collection(conn, cid)
+    })?;
+
+    Ok(convert_to_sc_collection(conn, collection))
+}
+
+/// Called when a game is deleted, the collections keep a count of what was removed
+pub fn remove_game_from_collections(conn: &PgConnection, gid: i32) -> QueryResult<usize> {
+    use self::collections::dsl::*;
+
+    let cids = collection_games::table
+        .select(collection_games::collection_id)
+        .filter(collection_games::game_id.eq(gid))
+        .load::<i32>(conn)?;
+    diesel::update(collections.filter(id.eq_any(&cids)))
+        .set(removed_count.eq(removed_count + 1))
+        .execute(conn)?;
+    diesel::delete(collection_games::table.filter(collection_games::game_id.eq(gid))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::collection::*;
+
+    #[test]
+    fn collection_order() {
+        assert!(check_order(&[3, 1, 2], &[1, 2, 3]));
+        assert!(check_order(&[], &[]));
+        // Missing, unknown or repeated entries
+        assert!(!check_order(&[3, 1, 2], &[1, 2]));
+        assert!(!check_order(&[3, 1, 2], &[1, 2, 4]));
+        assert!(!check_order(&[1, 2], &[1, 1, 2]));
+    }
+}
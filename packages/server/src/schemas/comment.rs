@@ -1,12 +1,18 @@
 use chrono::{NaiveDateTime, Utc};
+use diesel::dsl::any;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use juniper::{FieldResult, GraphQLInputObject, GraphQLObject};
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
 
 use crate::db::models::{Comment, NewComment};
-use crate::db::schema::comments;
+use crate::db::schema::{comments, users};
+use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
 
+use super::report::flag_text;
 use super::user::*;
+use super::visibility::{hidden_comments, visible_comments, visible_users};
 
 #[derive(GraphQLObject)]
 pub struct ScComment {
@@ -14,15 +20,25 @@ pub struct ScComment {
     game_id: i32,
     body: String,
     like: bool,
+    // 1 to 5 stars, `None` for comments written before ratings
+    rating: Option<i32>,
     created_at: f64,
     updated_at: f64,
 }
 
+#[derive(GraphQLObject)]
+pub struct ScHiddenComment {
+    comment: ScComment,
+    deleted_at: f64,
+}
+
 #[derive(GraphQLInputObject)]
 pub struct ScNewComment {
-    game_id: i32,
-    body: String,
-    like: bool,
+    pub game_id: i32,
+    pub body: String,
+    pub like: bool,
+    // 1 to 5 stars
+    pub rating: Option<i32>,
 }
 
 #[derive(GraphQLInputObject)]
@@ -36,16 +52,17 @@ fn convert_to_sc_comment(conn: &PgConnection, comment: &Comment) -> ScComment {
         game_id: comment.game_id,
         body: comment.body.clone(),
         like: comment.like,
+        rating: comment.rating.map(|stars| stars.into()),
         created_at: comment.created_at.timestamp_millis() as f64,
         updated_at: comment.updated_at.timestamp_millis() as f64,
     }
 }
 
-pub fn get_comments(conn: &PgConnection, gid: i32) -> Vec<ScComment> {
+/// Without a viewer only comments visible to everyone
+pub fn get_comments(conn: &PgConnection, gid: i32, viewer: Option<i32>) -> Vec<ScComment> {
     use self::comments::dsl::*;
 
-    comments
-        .filter(deleted_at.is_null())
+    visible_comments(viewer)
         .filter(game_id.eq(gid))
         .order(updated_at.desc())
         .load::<Comment>(conn)
@@ -55,9 +72,79 @@ pub fn get_comments(conn: &PgConnection, gid: i32) -> Vec<ScComment> {
         .collect()
 }
 
+/// Visible comments of a game
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CommentStats {
+    pub comments: i32,
+    pub likes: i32,
+    pub ratings: i32,
+    rating_sum: i32,
+}
+
+impl CommentStats {
+    /// Mean stars of the rated comments, `None` before the first rating
+    pub fn average_rating(&self) -> Option<f64> {
+        (self.ratings > 0).then(|| self.rating_sum as f64 / self.ratings as f64)
+    }
+
+    fn add(&mut self, liked: bool, stars: Option<i16>) {
+        self.comments += 1;
+        self.likes += liked as i32;
+        if let Some(stars) = stars {
+            self.ratings += 1;
+            self.rating_sum += stars as i32;
+        }
+    }
+}
+
+/// game_id -> stats of `ids`, one query regardless of game count,
+/// counted here because boxed visibility queries can't be grouped
+pub fn get_comment_stats(conn: &PgConnection, ids: Vec<i32>) -> HashMap<i32, CommentStats> {
+    use self::comments::dsl::*;
+
+    let mut map = HashMap::new();
+
+    visible_comments(None)
+        .filter(game_id.eq(any(ids)))
+        .select((game_id, like, rating))
+        .load::<(i32, bool, Option<i16>)>(conn)
+        .unwrap()
+        .into_iter()
+        .for_each(|(gid, liked, stars)| {
+            map.entry(gid)
+                .or_insert_with(CommentStats::default)
+                .add(liked, stars);
+        });
+
+    map
+}
+
+fn check_rating(rating: Option<i32>) -> FieldResult<Option<i16>> {
+    match rating {
+        Some(stars) if !(1..=5).contains(&stars) => Err(FieldError::new(
+            "rating is 1 to 5 stars",
+            Error::validation(),
+        )),
+        _ => Ok(rating.map(|stars| stars as i16)),
+    }
+}
+
 pub fn create_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> FieldResult<ScComment> {
     use self::comments::dsl::*;
 
+    let stars = check_rating(req.rating)?;
+    if let Some(filter) = check_text(ScTextKind::Comment, &req.body)? {
+        flag_text(
+            conn,
+            uid,
+            ScTextKind::Comment,
+            &req.body,
+            filter,
+            Some(req.game_id),
+            None,
+        );
+    }
+
     let c = comments
         .filter(user_id.eq(uid))
         .filter(game_id.eq(req.game_id))
@@ -72,6 +159,7 @@ pub fn create_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> Fiel
         game_id: req.game_id,
         body: &req.body,
         like: req.like,
+        rating: stars,
         deleted_at: None,
         created_at: Utc::now().naive_utc(),
         updated_at: Utc::now().naive_utc(),
@@ -87,6 +175,7 @@ pub fn create_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> Fiel
 pub fn update_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> FieldResult<ScComment> {
     use self::comments::dsl::*;
 
+    let stars = check_rating(req.rating)?;
     let comment = diesel::update(
         comments
             .filter(deleted_at.is_null())
@@ -96,6 +185,7 @@ pub fn update_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> Fiel
     .set((
         body.eq(req.body.clone()),
         like.eq(req.like),
+        rating.eq(stars),
         updated_at.eq(Utc::now().naive_utc()),
         deleted_at.eq(None::<NaiveDateTime>),
     ))
@@ -103,3 +193,89 @@ pub fn update_comment(conn: &PgConnection, uid: i32, req: &ScNewComment) -> Fiel
 
     Ok(convert_to_sc_comment(conn, &comment))
 }
+
+/// By the author, gone for good unlike a hidden comment
+pub fn delete_comment(conn: &PgConnection, uid: i32, gid: i32) -> FieldResult<ScComment> {
+    use self::comments::dsl::*;
+
+    let comment = diesel::delete(
+        comments
+            .filter(deleted_at.is_null())
+            .filter(game_id.eq(gid))
+            .filter(user_id.eq(uid)),
+    )
+    .get_result::<Comment>(conn)?;
+
+    Ok(convert_to_sc_comment(conn, &comment))
+}
+
+/// Admin only, the author still sees it
+pub fn hide_comment(conn: &PgConnection, uid: i32, gid: i32) -> FieldResult<ScComment> {
+    use self::comments::dsl::*;
+
+    let comment = diesel::update(
+        comments
+            .filter(deleted_at.is_null())
+            .filter(game_id.eq(gid))
+            .filter(user_id.eq(uid)),
+    )
+    .set(deleted_at.eq(Some(Utc::now().naive_utc())))
+    .get_result::<Comment>(conn)?;
+
+    Ok(convert_to_sc_comment(conn, &comment))
+}
+
+pub fn restore_comment(conn: &PgConnection, uid: i32, gid: i32) -> FieldResult<ScComment> {
+    use self::comments::dsl::*;
+
+    let comment = diesel::update(
+        comments
+            .filter(deleted_at.is_not_null())
+            .filter(game_id.eq(gid))
+            .filter(user_id.eq(uid)),
+    )
+    .set(deleted_at.eq(None::<NaiveDateTime>))
+    .get_result::<Comment>(conn)?;
+
+    Ok(convert_to_sc_comment(conn, &comment))
+}
+
+/// Comments of banned accounts are listed with the account
+pub fn get_hidden_comments(conn: &PgConnection) -> FieldResult<Vec<ScHiddenComment>> {
+    use self::comments::dsl::*;
+
+    Ok(hidden_comments()
+        .filter(user_id.eq_any(visible_users().select(users::id)))
+        .order(deleted_at.desc())
+        .load::<Comment>(conn)?
+        .iter()
+        .map(|comment| ScHiddenComment {
+            comment: convert_to_sc_comment(conn, comment),
+            deleted_at: comment
+                .deleted_at
+                .map_or(0.0, |at| at.timestamp_millis() as f64),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::comment::*;
+
+    #[test]
+    fn comment_ratings() {
+        assert_eq!(check_rating(Some(5)).unwrap(), Some(5));
+        assert_eq!(check_rating(None).unwrap(), None);
+        assert!(check_rating(Some(0)).is_err());
+        assert!(check_rating(Some(6)).is_err());
+
+        let mut stats = CommentStats::default();
+        assert_eq!(stats.average_rating(), None);
+        stats.add(true, Some(5));
+        stats.add(false, Some(2));
+        // Unrated comments count but don't weigh on the average
+        stats.add(true, None);
+        assert_eq!((stats.comments, stats.likes, stats.ratings), (3, 2, 2));
+        assert_eq!(stats.average_rating(), Some(3.5));
+    }
+}
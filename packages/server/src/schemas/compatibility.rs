@@ -0,0 +1,103 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::str::FromStr;
+use std::string::ToString;
+
+use crate::db::models::{CoreVersion, NewCoreVersion};
+use crate::db::schema::core_versions;
+use crate::error::Error;
+
+use super::game::ScGamePlatform;
+
+#[derive(GraphQLObject)]
+pub struct ScCompatibility {
+    platform: ScGamePlatform,
+    minimum_supported_core_version: i32,
+    latest_core_version: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScUpdateCoreVersion {
+    pub platform: ScGamePlatform,
+    pub minimum: i32,
+    pub latest: i32,
+}
+
+fn convert_to_sc_compatibility(version: &CoreVersion) -> Option<ScCompatibility> {
+    Some(ScCompatibility {
+        platform: ScGamePlatform::from_str(&version.platform).ok()?,
+        minimum_supported_core_version: version.minimum,
+        latest_core_version: version.latest,
+    })
+}
+
+pub fn get_compatibility(conn: &PgConnection) -> Vec<ScCompatibility> {
+    use self::core_versions::dsl::*;
+
+    core_versions
+        .order(platform.asc())
+        .load::<CoreVersion>(conn)
+        .unwrap()
+        .iter()
+        .filter_map(|version| convert_to_sc_compatibility(version))
+        .collect()
+}
+
+/// `None` until an admin configures the platform
+pub fn get_core_version(
+    conn: &PgConnection,
+    p: &ScGamePlatform,
+) -> QueryResult<Option<CoreVersion>> {
+    use self::core_versions::dsl::*;
+
+    core_versions
+        .filter(platform.eq(p.to_string()))
+        .get_result::<CoreVersion>(conn)
+        .optional()
+}
+
+/// Return `true` when the version is below minimum, error when the server doesn't know it yet
+pub fn check_core_version(
+    conn: &PgConnection,
+    p: &ScGamePlatform,
+    version: i32,
+) -> FieldResult<bool> {
+    match get_core_version(conn, p)? {
+        Some(known) if version > known.latest => Err(FieldError::new(
+            format!("unknown {} core version {}", p, version),
+            Error::unsupported_core_version(),
+        )),
+        Some(known) => Ok(version < known.minimum),
+        None => Ok(false),
+    }
+}
+
+pub fn update_core_version(
+    conn: &PgConnection,
+    req: &ScUpdateCoreVersion,
+) -> FieldResult<ScCompatibility> {
+    use self::core_versions::dsl::*;
+
+    let new_version = NewCoreVersion {
+        platform: &req.platform.to_string(),
+        minimum: req.minimum,
+        latest: req.latest.max(req.minimum),
+        updated_at: Utc::now().naive_utc(),
+    };
+
+    let version = diesel::insert_into(core_versions)
+        .values(&new_version)
+        .on_conflict(platform)
+        .do_update()
+        .set((
+            minimum.eq(new_version.minimum),
+            latest.eq(new_version.latest),
+            updated_at.eq(new_version.updated_at),
+        ))
+        .get_result::<CoreVersion>(conn)?;
+
+    convert_to_sc_compatibility(&version)
+        .ok_or_else(|| FieldError::new("invalid platform", Error::validation()))
+}
@@ -0,0 +1,359 @@
+use chrono::{DateTime, Utc};
+use juniper::{FieldError, FieldResult, GraphQLObject};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::error::Error;
+use crate::metrics;
+
+use super::client_info::ScClientInfo;
+use super::notify::{NotifyCategory, ScNotifyMessage};
+
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+
+lazy_static! {
+    static ref CONNECTIONS: RwLock<HashMap<i32, Arc<SubscriptionConnection>>> = {
+        let map = HashMap::new();
+        RwLock::new(map)
+    };
+}
+
+/// A subscription socket, registered from upgrade until its response body is dropped
+pub struct SubscriptionConnection {
+    pub id: i32,
+    connected_at: DateTime<Utc>,
+    // 0 until the init payload is accepted
+    user_id: AtomicI32,
+    // `compact: true` connection parameter
+    compact: AtomicBool,
+    // permessage-deflate negotiated on upgrade
+    deflate: AtomicBool,
+    // websocket frames including keep alives, as sent on the wire
+    bytes_sent: AtomicU64,
+    // the same frames before compression
+    uncompressed_bytes: AtomicU64,
+    events_sent: AtomicU64,
+    // categories the mounted UI shows, `None` is every category
+    interests: RwLock<Option<Vec<NotifyCategory>>>,
+    // login session of the token, `None` for tokens issued before sessions
+    session_id: RwLock<Option<String>>,
+    // `clientInfo` connection parameter, `None` gets every event kind
+    client: RwLock<Option<ScClientInfo>>,
+}
+
+impl SubscriptionConnection {
+    pub fn init(&self, uid: i32, compact: bool) {
+        self.user_id.store(uid, Ordering::Relaxed);
+        self.compact.store(compact, Ordering::Relaxed);
+    }
+
+    pub fn set_session_id(&self, sid: Option<String>) {
+        *self.session_id.write().unwrap() = sid;
+    }
+
+    pub fn session_id(&self) -> Option<String> {
+        self.session_id.read().unwrap().clone()
+    }
+
+    pub fn set_client(&self, client: Option<ScClientInfo>) {
+        *self.client.write().unwrap() = client;
+    }
+
+    /// Kinds the client didn't declare become `refreshHint`
+    pub fn downgrade(&self, msg: ScNotifyMessage) -> ScNotifyMessage {
+        msg.downgrade(self.client.read().unwrap().as_ref())
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.compact.load(Ordering::Relaxed)
+    }
+
+    pub fn set_deflate(&self, deflate: bool) {
+        self.deflate.store(deflate, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, count: usize) {
+        self.add_frames(count, count);
+    }
+
+    /// `sent` after compression, `uncompressed` as produced by the handler
+    pub fn add_frames(&self, sent: usize, uncompressed: usize) {
+        self.bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+        self.uncompressed_bytes
+            .fetch_add(uncompressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_event(&self) {
+        self.events_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_interests(&self, categories: Vec<NotifyCategory>) {
+        *self.interests.write().unwrap() = Some(categories);
+    }
+
+    /// Skipped on this socket only, persisted events are replayed from notifications
+    pub fn accepts(&self, msg: &ScNotifyMessage) -> bool {
+        let route = match msg.route() {
+            Some(route) => route,
+            None => return true,
+        };
+        let accepted = route.critical
+            || self
+                .interests
+                .read()
+                .unwrap()
+                .as_ref()
+                .map_or(true, |interests| interests.contains(&route.category));
+        if !accepted {
+            metrics::inc_counter("nesbox_notify_filtered_total", msg.kind());
+        }
+        accepted
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScConnection {
+    pub id: i32,
+    pub user_id: i32,
+    pub compact: bool,
+    // null when every category is delivered
+    pub interests: Option<Vec<NotifyCategory>>,
+    pub deflate: bool,
+    pub client: Option<ScClientInfo>,
+    connected_at: f64,
+    bytes_sent: f64,
+    // equals `bytes_sent` without deflate
+    uncompressed_bytes: f64,
+    events_sent: f64,
+}
+
+fn convert_to_sc_connection(connection: &SubscriptionConnection) -> ScConnection {
+    ScConnection {
+        id: connection.id,
+        user_id: connection.user_id.load(Ordering::Relaxed),
+        compact: connection.is_compact(),
+        interests: connection.interests.read().unwrap().clone(),
+        deflate: connection.deflate.load(Ordering::Relaxed),
+        client: connection.client.read().unwrap().clone(),
+        connected_at: connection.connected_at.timestamp_millis() as f64,
+        bytes_sent: connection.bytes_sent.load(Ordering::Relaxed) as f64,
+        uncompressed_bytes: connection.uncompressed_bytes.load(Ordering::Relaxed) as f64,
+        events_sent: connection.events_sent.load(Ordering::Relaxed) as f64,
+    }
+}
+
+pub fn open_connection() -> Arc<SubscriptionConnection> {
+    let connection = Arc::new(SubscriptionConnection {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        connected_at: Utc::now(),
+        user_id: AtomicI32::new(0),
+        compact: AtomicBool::new(false),
+        deflate: AtomicBool::new(false),
+        bytes_sent: AtomicU64::new(0),
+        uncompressed_bytes: AtomicU64::new(0),
+        events_sent: AtomicU64::new(0),
+        interests: RwLock::new(None),
+        session_id: RwLock::new(None),
+        client: RwLock::new(None),
+    });
+    CONNECTIONS
+        .write()
+        .unwrap()
+        .insert(connection.id, connection.clone());
+    connection
+}
+
+pub fn close_connection(cid: i32) {
+    CONNECTIONS.write().unwrap().remove(&cid);
+}
+
+/// Open sockets of this process, oldest first
+pub fn get_connections() -> Vec<ScConnection> {
+    let mut connections: Vec<_> = CONNECTIONS
+        .read()
+        .unwrap()
+        .values()
+        .map(|connection| convert_to_sc_connection(connection))
+        .collect();
+    connections.sort_by_key(|connection| connection.id);
+    connections
+}
+
+/// The socket the mutation came over, otherwise the caller's sockets on this
+/// process, all of them or the one given
+pub fn update_interests(
+    uid: i32,
+    current: Option<&Arc<SubscriptionConnection>>,
+    cid: Option<i32>,
+    categories: Vec<NotifyCategory>,
+) -> FieldResult<Vec<ScConnection>> {
+    let targets: Vec<_> = match current {
+        Some(connection) => vec![connection.clone()],
+        None => CONNECTIONS
+            .read()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.user_id.load(Ordering::Relaxed) == uid)
+            .filter(|connection| cid.map_or(true, |cid| connection.id == cid))
+            .cloned()
+            .collect(),
+    };
+    if targets.is_empty() && cid.is_some() {
+        return Err(FieldError::new("connection not found", Error::not_found()));
+    }
+    let mut updated: Vec<_> = targets
+        .iter()
+        .map(|connection| {
+            connection.set_interests(categories.clone());
+            convert_to_sc_connection(connection)
+        })
+        .collect();
+    updated.sort_by_key(|connection| connection.id);
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::connection::*;
+    use crate::schemas::notify::NoyifyReceiver;
+
+    #[test]
+    fn connection_counters() {
+        let connection = open_connection();
+        connection.init(-31, true);
+        connection.add_bytes(120);
+        connection.add_bytes(30);
+        connection.add_frames(40, 400);
+        connection.add_event();
+
+        let listed = get_connections()
+            .into_iter()
+            .find(|listed| listed.id == connection.id)
+            .unwrap();
+        assert_eq!(listed.user_id, -31);
+        assert!(listed.compact);
+        assert_eq!((listed.bytes_sent, listed.events_sent), (190.0, 1.0));
+        assert_eq!(listed.uncompressed_bytes, 550.0);
+
+        close_connection(connection.id);
+        assert!(get_connections()
+            .iter()
+            .all(|listed| listed.id != connection.id));
+    }
+
+    #[test]
+    fn connection_interests() {
+        use crate::schemas::lobby::ScLobbyMessage;
+        use crate::schemas::notify::{get_receiver, notify, ScNotifyMessageBuilder};
+
+        // Fullscreen game and a second tab of the same user
+        let playing = open_connection();
+        let browsing = open_connection();
+        playing.init(-41, false);
+        browsing.init(-41, false);
+        let mut playing_rx = get_receiver(-41);
+        let mut browsing_rx = get_receiver(-41);
+
+        let updated = update_interests(
+            -41,
+            None,
+            Some(playing.id),
+            vec![NotifyCategory::Rooms, NotifyCategory::Signaling],
+        )
+        .unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(
+            updated[0].interests,
+            Some(vec![NotifyCategory::Rooms, NotifyCategory::Signaling])
+        );
+        assert!(update_interests(-41, None, Some(-1), vec![]).is_err());
+
+        let lobby = ScNotifyMessageBuilder::default()
+            .lobby_message(ScLobbyMessage {
+                created_at: 0.0,
+                user_id: 1,
+                username: "nes".into(),
+                nickname: "nes".into(),
+                text: "gg".into(),
+            })
+            .build()
+            .unwrap();
+        let kicked = ScNotifyMessageBuilder::default()
+            .kicked_room(3)
+            .build()
+            .unwrap();
+        notify(-41, lobby);
+        notify(-41, kicked);
+
+        let received = |rx: &mut NoyifyReceiver, connection: &SubscriptionConnection| {
+            let mut kinds = Vec::new();
+            while let Ok(msg) = rx.0.try_recv() {
+                if connection.accepts(&msg) {
+                    kinds.push(msg.kind());
+                }
+            }
+            kinds
+        };
+        // Critical kinds can't be filtered out
+        assert_eq!(received(&mut playing_rx, &playing), vec!["kicked_room"]);
+        assert_eq!(
+            received(&mut browsing_rx, &browsing),
+            vec!["lobby_message", "kicked_room"]
+        );
+
+        close_connection(playing.id);
+        close_connection(browsing.id);
+        playing_rx.detach();
+        browsing_rx.detach();
+    }
+
+    #[test]
+    fn connection_client_capabilities() {
+        use crate::schemas::notify::{NotifyKind, ScNotifyMessageBuilder};
+        use crate::schemas::room_event::{ScRoomMemberAction, ScRoomMemberChange};
+
+        let client = |version: &str, capabilities: &[&str]| ScClientInfo {
+            name: "nesbox".into(),
+            version: version.into(),
+            capabilities: capabilities.iter().map(|kind| kind.to_string()).collect(),
+        };
+        let old = open_connection();
+        let new = open_connection();
+        let undeclared = open_connection();
+        old.set_client(Some(client("1.0.0", &[])));
+        new.set_client(Some(client("2.0.0", &["room_member"])));
+
+        let member = ScNotifyMessageBuilder::default()
+            .room_member(ScRoomMemberChange {
+                room_id: 3,
+                seq: 1.0,
+                user_id: 1,
+                action: ScRoomMemberAction::Join,
+            })
+            .build()
+            .unwrap();
+        let downgraded = old.downgrade(member.clone());
+        assert_eq!(downgraded.kind(), "refresh_hint");
+        assert_eq!(downgraded.route().unwrap().kind, NotifyKind::RefreshHint);
+        assert_eq!(new.downgrade(member.clone()).kind(), "room_member");
+        assert_eq!(undeclared.downgrade(member).kind(), "room_member");
+
+        // Kinds every supported client parses are never downgraded
+        let kicked = ScNotifyMessageBuilder::default()
+            .kicked_room(3)
+            .build()
+            .unwrap();
+        assert_eq!(old.downgrade(kicked).kind(), "kicked_room");
+
+        let listed = get_connections()
+            .into_iter()
+            .find(|listed| listed.id == old.id)
+            .unwrap();
+        assert_eq!(listed.client.unwrap().version, "1.0.0");
+
+        close_connection(old.id);
+        close_connection(new.id);
+        close_connection(undeclared.id);
+    }
+}
@@ -7,6 +7,8 @@ use juniper::{FieldResult, GraphQLInputObject};
 use crate::db::models::{Favorite, NewFavorite};
 use crate::db::schema::favorites;
 
+use super::game::get_deprecated_ids;
+
 #[derive(GraphQLInputObject)]
 pub struct ScNewFavorite {
     pub game_id: i32,
@@ -29,10 +31,25 @@ pub fn get_favorites(conn: &PgConnection, uid: i32) -> Vec<i32> {
 pub fn get_top_ids(conn: &PgConnection) -> Vec<i32> {
     use self::favorites::dsl::*;
 
+    let deprecated_ids = get_deprecated_ids(conn);
+
     favorites
         .group_by(game_id)
         .select(game_id)
         .order(count(game_id).desc())
+        .load::<i32>(conn)
+        .unwrap()
+        .into_iter()
+        .filter(|gid| !deprecated_ids.contains(gid))
+        .collect()
+}
+
+pub fn get_favorite_user_ids(conn: &PgConnection, gid: i32) -> Vec<i32> {
+    use self::favorites::dsl::*;
+
+    favorites
+        .select(user_id)
+        .filter(game_id.eq(gid))
         .load(conn)
         .unwrap()
 }
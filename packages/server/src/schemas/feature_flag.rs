@@ -0,0 +1,224 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use ring::digest::{digest, SHA256};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::db::models::{FeatureFlag, NewFeatureFlag};
+use crate::db::schema::feature_flags;
+use crate::error::Error;
+
+// Rollout changes reach every instance within this delay
+const CACHE_SECONDS: i64 = 10;
+const MAX_NAME_LEN: usize = 50;
+
+lazy_static! {
+    static ref CACHE: Mutex<Option<(DateTime<Utc>, Vec<FeatureFlag>)>> = Mutex::new(None);
+}
+
+/// Resolved for the caller
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScFeatureFlag {
+    pub name: String,
+    pub enabled: bool,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScFeatureFlagConfig {
+    pub name: String,
+    pub rollout: i32,
+    pub allow_users: Vec<i32>,
+    pub deny_users: Vec<i32>,
+    pub updated_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScUpdateFeatureFlag {
+    pub name: String,
+    // 0 to 100, unchanged when null
+    pub rollout: Option<i32>,
+    // always enabled, unchanged when null
+    pub allow_users: Option<Vec<i32>>,
+    // never enabled, wins over the allow list, unchanged when null
+    pub deny_users: Option<Vec<i32>>,
+}
+
+impl From<FeatureFlag> for ScFeatureFlagConfig {
+    fn from(flag: FeatureFlag) -> Self {
+        Self {
+            name: flag.name,
+            rollout: flag.rollout,
+            allow_users: flag.allow_users,
+            deny_users: flag.deny_users,
+            updated_at: flag.updated_at.timestamp_millis() as f64,
+        }
+    }
+}
+
+/// 0 to 99, stable across processes and releases, hashed with the flag name
+/// so the same users aren't the canary of every feature
+pub fn get_bucket(flag: &str, uid: i32) -> u32 {
+    let hash = digest(&SHA256, format!("{}:{}", flag, uid).as_bytes());
+    let bytes = hash.as_ref();
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) % 100
+}
+
+/// Anonymous sessions share user id 0, they only see fully rolled out flags
+pub fn is_enabled(flag: &FeatureFlag, uid: i32) -> bool {
+    if flag.deny_users.contains(&uid) {
+        return false;
+    }
+    if flag.allow_users.contains(&uid) {
+        return true;
+    }
+    if uid == 0 {
+        return flag.rollout >= 100;
+    }
+    (get_bucket(&flag.name, uid) as i32) < flag.rollout
+}
+
+/// Every defined flag, evaluated once per request
+pub fn resolve_flags(flags: &[FeatureFlag], uid: i32) -> BTreeMap<String, bool> {
+    flags
+        .iter()
+        .map(|flag| (flag.name.clone(), is_enabled(flag, uid)))
+        .collect()
+}
+
+/// `None` once the cache expired, then `load_flags` on a blocking thread
+pub fn get_cached_flags() -> Option<Vec<FeatureFlag>> {
+    CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(at, _)| Utc::now() - *at < Duration::seconds(CACHE_SECONDS))
+        .map(|(_, flags)| flags.clone())
+}
+
+pub fn load_flags(conn: &PgConnection) -> QueryResult<Vec<FeatureFlag>> {
+    use self::feature_flags::dsl::*;
+
+    let flags = feature_flags.order(name.asc()).load::<FeatureFlag>(conn)?;
+    *CACHE.lock().unwrap() = Some((Utc::now(), flags.clone()));
+    Ok(flags)
+}
+
+pub fn get_feature_flag_configs(conn: &PgConnection) -> FieldResult<Vec<ScFeatureFlagConfig>> {
+    Ok(load_flags(conn)?
+        .into_iter()
+        .map(|flag| flag.into())
+        .collect())
+}
+
+pub fn update_feature_flag(
+    conn: &PgConnection,
+    req: &ScUpdateFeatureFlag,
+) -> FieldResult<Vec<ScFeatureFlagConfig>> {
+    use self::feature_flags::dsl::*;
+
+    let flag_name = req.name.trim();
+    if flag_name.is_empty() || flag_name.len() > MAX_NAME_LEN {
+        return Err(FieldError::new(
+            format!("name must be 1 to {} characters", MAX_NAME_LEN),
+            Error::validation(),
+        ));
+    }
+    let current = feature_flags
+        .filter(name.eq(flag_name))
+        .first::<FeatureFlag>(conn)
+        .optional()?;
+    let now = Utc::now().naive_utc();
+    let new_rollout = req
+        .rollout
+        .or(current.as_ref().map(|flag| flag.rollout))
+        .unwrap_or_default()
+        .clamp(0, 100);
+    let new_allow = req
+        .allow_users
+        .clone()
+        .or(current.as_ref().map(|flag| flag.allow_users.clone()))
+        .unwrap_or_default();
+    let new_deny = req
+        .deny_users
+        .clone()
+        .or(current.as_ref().map(|flag| flag.deny_users.clone()))
+        .unwrap_or_default();
+    let new_flag = NewFeatureFlag {
+        name: flag_name,
+        rollout: new_rollout,
+        allow_users: &new_allow,
+        deny_users: &new_deny,
+        created_at: now,
+        updated_at: now,
+    };
+    diesel::insert_into(feature_flags)
+        .values(&new_flag)
+        .on_conflict(name)
+        .do_update()
+        .set((
+            rollout.eq(new_flag.rollout),
+            allow_users.eq(new_flag.allow_users),
+            deny_users.eq(new_flag.deny_users),
+            updated_at.eq(new_flag.updated_at),
+        ))
+        .execute(conn)?;
+
+    // Refreshes this process at once, other instances on their next expiry
+    get_feature_flag_configs(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::feature_flag::*;
+
+    fn flag(rollout: i32, allow_users: Vec<i32>, deny_users: Vec<i32>) -> FeatureFlag {
+        let now = Utc::now().naive_utc();
+        FeatureFlag {
+            name: "netplay-v2".into(),
+            rollout,
+            allow_users,
+            deny_users,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn feature_flag_rollout() {
+        // Same user same bucket, the name spreads canaries across flags
+        assert_eq!(get_bucket("netplay-v2", 42), get_bucket("netplay-v2", 42));
+        assert!((1..1000).any(|uid| get_bucket("netplay-v2", uid) != get_bucket("chat", uid)));
+
+        let enabled = |rollout| {
+            (1..=10000)
+                .filter(|uid| is_enabled(&flag(rollout, vec![], vec![]), *uid))
+                .collect::<Vec<_>>()
+        };
+        assert!(enabled(0).is_empty());
+        assert_eq!(enabled(100).len(), 10000);
+        let ten = enabled(10);
+        let thirty = enabled(30);
+        assert!((800..1200).contains(&ten.len()));
+        assert!((2700..3300).contains(&thirty.len()));
+        // Raising the rollout never takes the feature away
+        assert!(ten.iter().all(|uid| thirty.contains(uid)));
+
+        // Lists win over the rollout, deny over allow
+        let listed = flag(100, vec![7, 8], vec![8, 9]);
+        assert!(is_enabled(&listed, 7));
+        assert!(!is_enabled(&listed, 8));
+        assert!(!is_enabled(&listed, 9));
+        assert!(is_enabled(&flag(0, vec![7], vec![]), 7));
+
+        // Anonymous only after full rollout
+        assert!(!is_enabled(&flag(99, vec![], vec![]), 0));
+        assert!(is_enabled(&flag(100, vec![], vec![]), 0));
+
+        assert_eq!(
+            resolve_flags(&[flag(0, vec![3], vec![])], 3),
+            BTreeMap::from([("netplay-v2".to_string(), true)])
+        );
+    }
+}
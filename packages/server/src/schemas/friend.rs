@@ -31,6 +31,18 @@ pub struct ScUpdateFriend {
     pub accept: bool,
 }
 
+#[derive(GraphQLInputObject)]
+pub struct ScUpdateFriendInfo {
+    pub user_id: i32,
+    pub nickname: Option<String>,
+    pub note: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScFriendsReq {
+    pub search: Option<String>,
+}
+
 #[derive(GraphQLInputObject)]
 pub struct ScReadMessage {
     pub target_id: i32,
@@ -42,6 +54,23 @@ pub struct ScFriend {
     created_at: f64,
     status: ScFriendStatus,
     unread_message_count: i32,
+    // only visible to the owner
+    nickname: Option<String>,
+    note: Option<String>,
+}
+
+/// Remove control characters and limit length, empty string is `None`
+pub fn sanitize_text(text: &Option<String>, max_len: usize) -> Option<String> {
+    text.as_ref()
+        .map(|s| {
+            s.chars()
+                .filter(|c| !c.is_control())
+                .take(max_len)
+                .collect::<String>()
+                .trim()
+                .to_owned()
+        })
+        .filter(|s| !s.is_empty())
 }
 
 fn convert_to_sc_friend(conn: &PgConnection, friend: &Friend) -> ScFriend {
@@ -55,6 +84,8 @@ fn convert_to_sc_friend(conn: &PgConnection, friend: &Friend) -> ScFriend {
             friend.target_id,
             friend.last_read_at,
         ),
+        nickname: friend.nickname.clone(),
+        note: friend.note.clone(),
     }
 }
 
@@ -71,6 +102,25 @@ pub fn get_friends(conn: &PgConnection, uid: i32) -> Vec<ScFriend> {
         .collect()
 }
 
+/// `search` is lowercase, the owner's nickname and note count as well
+fn matches_search(friend: &ScFriend, search: &str) -> bool {
+    let matches = |s: &str| s.to_lowercase().contains(search);
+
+    matches(&friend.user.username)
+        || matches(&friend.user.nickname)
+        || friend.nickname.as_deref().map_or(false, matches)
+        || friend.note.as_deref().map_or(false, matches)
+}
+
+pub fn search_friends(conn: &PgConnection, uid: i32, search: &str) -> Vec<ScFriend> {
+    let search = search.trim().to_lowercase();
+
+    get_friends(conn, uid)
+        .into_iter()
+        .filter(|friend| matches_search(friend, &search))
+        .collect()
+}
+
 pub fn get_friend_ids(conn: &PgConnection, uid: i32) -> Vec<i32> {
     use self::friends::dsl::*;
 
@@ -84,6 +134,46 @@ pub fn get_friend_ids(conn: &PgConnection, uid: i32) -> Vec<i32> {
         .collect()
 }
 
+pub fn is_friend(conn: &PgConnection, uid: i32, tid: i32) -> bool {
+    use self::friends::dsl::*;
+
+    friends
+        .filter(user_id.eq(uid))
+        .filter(target_id.eq(tid))
+        .filter(status.eq(ScFriendStatus::Accept.to_string()))
+        .count()
+        .get_result::<i64>(conn)
+        .map_or(false, |count| count > 0)
+}
+
+/// A `deny` row either way, the users don't see each other's public content
+pub fn is_blocked(conn: &PgConnection, uid: i32, tid: i32) -> bool {
+    use self::friends::dsl::*;
+
+    friends
+        .filter(
+            user_id
+                .eq(uid)
+                .and(target_id.eq(tid))
+                .or(user_id.eq(tid).and(target_id.eq(uid))),
+        )
+        .filter(status.eq(ScFriendStatus::Deny.to_string()))
+        .count()
+        .get_result::<i64>(conn)
+        .map_or(false, |count| count > 0)
+}
+
+pub fn get_friend(conn: &PgConnection, uid: i32, tid: i32) -> FieldResult<ScFriend> {
+    use self::friends::dsl::*;
+
+    let friend = friends
+        .filter(user_id.eq(uid))
+        .filter(target_id.eq(tid))
+        .get_result::<Friend>(conn)?;
+
+    Ok(convert_to_sc_friend(conn, &friend))
+}
+
 pub fn apply_friend(conn: &PgConnection, uid: i32, tid: i32) -> FieldResult<ScFriend> {
     let new_friend = NewFriend {
         user_id: tid,
@@ -124,6 +214,27 @@ pub fn accept_friend(conn: &PgConnection, uid: i32, tid: i32) -> FieldResult<ScF
     Ok(convert_to_sc_friend(conn, &friend))
 }
 
+pub fn update_friend_info(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScUpdateFriendInfo,
+) -> FieldResult<ScFriend> {
+    use self::friends::dsl::*;
+
+    let friend = diesel::update(
+        friends
+            .filter(user_id.eq(uid))
+            .filter(target_id.eq(req.user_id)),
+    )
+    .set((
+        nickname.eq(sanitize_text(&req.nickname, 20)),
+        note.eq(sanitize_text(&req.note, 200)),
+    ))
+    .get_result::<Friend>(conn)?;
+
+    Ok(convert_to_sc_friend(conn, &friend))
+}
+
 pub fn read_message(conn: &PgConnection, uid: i32, tid: i32) -> FieldResult<ScFriend> {
     use self::friends::dsl::*;
 
@@ -145,3 +256,44 @@ pub fn delete_friend(conn: &PgConnection, uid: i32, tid: i32) {
         .execute(conn)
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::friend::*;
+    use crate::schemas::user::{ScUserBasic, ScUserStatus};
+
+    #[test]
+    fn friend_notes() {
+        assert_eq!(
+            sanitize_text(&Some(" Dave\u{7} from work\n".into()), 20),
+            Some("Dave from work".into())
+        );
+        assert_eq!(sanitize_text(&Some("\t \n".into()), 20), None);
+        assert_eq!(
+            sanitize_text(&Some("x".repeat(30)), 20),
+            Some("x".repeat(20))
+        );
+        assert_eq!(sanitize_text(&None, 20), None);
+
+        let friend = ScFriend {
+            user: ScUserBasic {
+                id: 7,
+                username: "nes".into(),
+                nickname: "Famicom".into(),
+                status: ScUserStatus::Online,
+                playing: None,
+                avatar: None,
+            },
+            created_at: 0.0,
+            status: ScFriendStatus::Accept,
+            unread_message_count: 0,
+            nickname: Some("Dave from work".into()),
+            note: Some("plays Contra".into()),
+        };
+        assert!(matches_search(&friend, "dave"));
+        assert!(matches_search(&friend, "contra"));
+        assert!(matches_search(&friend, "famicom"));
+        assert!(matches_search(&friend, "nes"));
+        assert!(!matches_search(&friend, "mario"));
+    }
+}
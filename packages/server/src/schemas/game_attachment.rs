@@ -0,0 +1,260 @@
+use chrono::Utc;
+use diesel::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::str::FromStr;
+use strum::{Display, EnumString};
+
+use crate::db::models::{GameAttachment, NewGameAttachment};
+use crate::db::schema::game_attachments;
+use crate::nsf::NsfInfo;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq, Serialize)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScAttachmentKind {
+    // manuals and control sheets
+    Document,
+    // NSF rips, played on the jukebox page
+    Music,
+}
+
+#[derive(GraphQLObject, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScGameAttachment {
+    id: i32,
+    label: String,
+    // upstream url, download through `/attachment/{id}`
+    url: String,
+    size: Option<f64>,
+    content_type: Option<String>,
+    // false when the upstream link is dead
+    available: bool,
+    kind: ScAttachmentKind,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScMusicTrack {
+    // play bytes of `/attachment/{id}`, supports range requests
+    attachment_id: i32,
+    label: String,
+    // song number inside the NSF, starts at 1
+    track: i32,
+    track_count: i32,
+    // from the NSF header, `None` until cached or when not present
+    title: Option<String>,
+}
+
+#[derive(GraphQLInputObject, Debug, Clone, PartialEq)]
+pub struct ScNewGameAttachment {
+    pub label: String,
+    pub url: String,
+}
+
+/// Kind by the file extension of the url
+pub fn get_attachment_kind(url: &str) -> Option<ScAttachmentKind> {
+    let path = url
+        .split(|c| c == '?' || c == '#')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if path.ends_with(".pdf") {
+        Some(ScAttachmentKind::Document)
+    } else if path.ends_with(".nsf") {
+        Some(ScAttachmentKind::Music)
+    } else {
+        None
+    }
+}
+
+fn get_kind(attachment: &GameAttachment) -> ScAttachmentKind {
+    ScAttachmentKind::from_str(&attachment.kind).unwrap_or(ScAttachmentKind::Document)
+}
+
+fn convert_to_sc_game_attachment(attachment: &GameAttachment) -> ScGameAttachment {
+    ScGameAttachment {
+        id: attachment.id,
+        label: attachment.label.clone(),
+        url: attachment.url.clone(),
+        size: attachment.size.map(|size| size as f64),
+        content_type: attachment.content_type.clone(),
+        available: attachment.available,
+        kind: get_kind(attachment),
+    }
+}
+
+fn convert_to_sc_music_tracks(attachment: &GameAttachment) -> Vec<ScMusicTrack> {
+    // Uncached files and corrupt headers are a single unnamed track
+    let info = NsfInfo::default();
+    let track_count = attachment.track_count.unwrap_or(info.track_count).max(1);
+    (1..=track_count)
+        .map(|track| ScMusicTrack {
+            attachment_id: attachment.id,
+            label: attachment.label.clone(),
+            track,
+            track_count,
+            title: attachment.music_title.clone(),
+        })
+        .collect()
+}
+
+/// game_id -> attachments, all games when `ids` is `None`
+pub fn get_game_attachments_map(
+    conn: &PgConnection,
+    ids: Option<Vec<i32>>,
+) -> HashMap<i32, Vec<ScGameAttachment>> {
+    use self::game_attachments::dsl::*;
+
+    let mut query = game_attachments.order(id.asc()).into_boxed();
+    if let Some(ids) = ids {
+        query = query.filter(game_id.eq(any(ids)));
+    }
+
+    let mut map: HashMap<i32, Vec<ScGameAttachment>> = HashMap::new();
+    query
+        .load::<GameAttachment>(conn)
+        .unwrap()
+        .iter()
+        .for_each(|attachment| {
+            map.entry(attachment.game_id)
+                .or_default()
+                .push(convert_to_sc_game_attachment(attachment));
+        });
+    map
+}
+
+pub fn get_game_attachments(conn: &PgConnection, gid: i32) -> Vec<ScGameAttachment> {
+    get_game_attachments_map(conn, Some(vec![gid]))
+        .remove(&gid)
+        .unwrap_or_default()
+}
+
+pub fn get_game_attachment(conn: &PgConnection, aid: i32) -> Option<GameAttachment> {
+    use self::game_attachments::dsl::*;
+
+    game_attachments
+        .filter(id.eq(aid))
+        .get_result::<GameAttachment>(conn)
+        .ok()
+}
+
+/// Tracks of the available music attachments of the game
+pub fn get_music_tracks(conn: &PgConnection, gid: i32) -> Vec<ScMusicTrack> {
+    use self::game_attachments::dsl::*;
+
+    game_attachments
+        .filter(game_id.eq(gid))
+        .filter(kind.eq(ScAttachmentKind::Music.to_string()))
+        .filter(available.eq(true))
+        .order(id.asc())
+        .load::<GameAttachment>(conn)
+        .unwrap_or_default()
+        .iter()
+        .flat_map(convert_to_sc_music_tracks)
+        .collect()
+}
+
+/// Reconcile attachments of the game by url, return ids of added attachments
+pub fn set_game_attachments(
+    conn: &PgConnection,
+    gid: i32,
+    attachments: &[ScNewGameAttachment],
+) -> QueryResult<Vec<i32>> {
+    use self::game_attachments::dsl::*;
+
+    let urls: Vec<String> = attachments.iter().map(|a| a.url.clone()).collect();
+
+    conn.transaction(|| {
+        diesel::delete(
+            game_attachments
+                .filter(game_id.eq(gid))
+                .filter(diesel::dsl::not(url.eq(any(urls.clone())))),
+        )
+        .execute(conn)?;
+
+        let existing: Vec<String> = game_attachments
+            .select(url)
+            .filter(game_id.eq(gid))
+            .load::<String>(conn)?;
+
+        let mut added = Vec::new();
+        for attachment in attachments {
+            if existing.contains(&attachment.url) {
+                diesel::update(
+                    game_attachments
+                        .filter(game_id.eq(gid))
+                        .filter(url.eq(&attachment.url)),
+                )
+                .set(label.eq(&attachment.label))
+                .execute(conn)?;
+            } else {
+                let new_attachment = NewGameAttachment {
+                    game_id: gid,
+                    label: &attachment.label,
+                    url: &attachment.url,
+                    created_at: Utc::now().naive_utc(),
+                    updated_at: Utc::now().naive_utc(),
+                    kind: get_attachment_kind(&attachment.url)
+                        .unwrap_or(ScAttachmentKind::Document)
+                        .to_string(),
+                };
+                let attachment = diesel::insert_into(game_attachments)
+                    .values(&new_attachment)
+                    .on_conflict_do_nothing()
+                    .get_result::<GameAttachment>(conn)
+                    .optional()?;
+                added.extend(attachment.map(|a| a.id));
+            }
+        }
+
+        Ok(added)
+    })
+}
+
+/// Record a fetch result, `None` marks the upstream link dead
+pub fn set_attachment_fetched(conn: &PgConnection, aid: i32, fetched: Option<(i64, String)>) {
+    use self::game_attachments::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let result = match fetched {
+        Some((s, t)) => diesel::update(game_attachments.filter(id.eq(aid)))
+            .set((
+                size.eq(Some(s)),
+                content_type.eq(Some(t)),
+                available.eq(true),
+                cached_at.eq(Some(now)),
+                updated_at.eq(now),
+            ))
+            .execute(conn),
+        None => diesel::update(game_attachments.filter(id.eq(aid)))
+            .set((
+                available.eq(false),
+                cached_at.eq(None::<chrono::NaiveDateTime>),
+                updated_at.eq(now),
+            ))
+            .execute(conn),
+    };
+    if let Err(err) = result {
+        log::error!("Update attachment {}: {:?}", aid, err);
+    }
+}
+
+pub fn is_music_attachment(attachment: &GameAttachment) -> bool {
+    get_kind(attachment) == ScAttachmentKind::Music
+}
+
+/// Record track metadata read from the cached NSF
+pub fn set_music_info(conn: &PgConnection, aid: i32, info: &NsfInfo) {
+    use self::game_attachments::dsl::*;
+
+    let result = diesel::update(game_attachments.filter(id.eq(aid)))
+        .set((
+            track_count.eq(Some(info.track_count)),
+            music_title.eq(info.title.clone()),
+        ))
+        .execute(conn);
+    if let Err(err) = result {
+        log::error!("Update music attachment {}: {:?}", aid, err);
+    }
+}
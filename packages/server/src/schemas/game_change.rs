@@ -0,0 +1,249 @@
+use chrono::{Duration, Utc};
+use diesel::dsl::{max, min};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::string::ToString;
+use strum::{Display, EnumString};
+
+use crate::db::models::{GameChange, NewGameChange};
+use crate::db::schema::game_changes;
+
+use super::game::{get_games_by_ids, ScGame};
+
+const GAME_CHANGE_RETENTION_DAYS: i64 = 90;
+
+#[derive(Debug, Clone, Copy, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum GameChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScGamesDeltaReq {
+    // `version` of the last delta, 0 for an empty local catalog
+    pub since_version: i32,
+}
+
+#[derive(GraphQLObject)]
+pub struct ScGamesDelta {
+    created: Vec<ScGame>,
+    updated: Vec<ScGame>,
+    deleted_ids: Vec<i32>,
+    // current catalog version
+    version: i32,
+    // journal no longer covers `sinceVersion`, reload the full list
+    full_resync: bool,
+}
+
+impl ScGamesDelta {
+    /// Hidden games read as deleted, clients drop what they cached
+    pub fn hide_games(mut self, hidden: impl Fn(&ScGame) -> bool) -> Self {
+        for list in [&mut self.created, &mut self.updated] {
+            list.retain(|game| {
+                if hidden(game) {
+                    self.deleted_ids.push(game.id);
+                }
+                !hidden(game)
+            });
+        }
+        self
+    }
+}
+
+/// Journal every catalog change, `gamesDelta` is built from it
+pub fn record_game_change(conn: &PgConnection, gid: i32, kind: GameChangeKind) -> QueryResult<()> {
+    let new_change = NewGameChange {
+        game_id: gid,
+        kind: &kind.to_string(),
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(game_changes::table)
+        .values(&new_change)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Net effect of changes in version order: (created, updated, deleted) ids,
+/// a game both created and deleted in the range never reached the client
+pub fn reduce_game_changes(changes: &[(i32, GameChangeKind)]) -> (Vec<i32>, Vec<i32>, Vec<i32>) {
+    let mut order = Vec::new();
+    let mut spans: HashMap<i32, (GameChangeKind, GameChangeKind)> = HashMap::new();
+    for (gid, kind) in changes {
+        spans
+            .entry(*gid)
+            .and_modify(|span| span.1 = *kind)
+            .or_insert_with(|| {
+                order.push(*gid);
+                (*kind, *kind)
+            });
+    }
+
+    let (mut created, mut updated, mut deleted) = (Vec::new(), Vec::new(), Vec::new());
+    for gid in order {
+        match spans[&gid] {
+            (GameChangeKind::Created, GameChangeKind::Deleted) => (),
+            (_, GameChangeKind::Deleted) => deleted.push(gid),
+            (GameChangeKind::Created, _) => created.push(gid),
+            _ => updated.push(gid),
+        }
+    }
+    (created, updated, deleted)
+}
+
+pub fn get_games_delta(conn: &PgConnection, since: i32) -> FieldResult<ScGamesDelta> {
+    use self::game_changes::dsl::*;
+
+    let (horizon, current) = game_changes
+        .select((min(version), max(version)))
+        .get_result::<(Option<i32>, Option<i32>)>(conn)?;
+    let current = current.unwrap_or_default();
+    let mut delta = ScGamesDelta {
+        created: vec![],
+        updated: vec![],
+        deleted_ids: vec![],
+        version: current,
+        full_resync: false,
+    };
+
+    // Pruned past the client, or the client is ahead of a restored database
+    if since < horizon.unwrap_or(1) - 1 || since > current {
+        delta.full_resync = true;
+        return Ok(delta);
+    }
+
+    let changes: Vec<(i32, GameChangeKind)> = game_changes
+        .filter(version.gt(since))
+        .order(version.asc())
+        .load::<GameChange>(conn)?
+        .iter()
+        .filter_map(|change| {
+            GameChangeKind::from_str(&change.kind)
+                .ok()
+                .map(|k| (change.game_id, k))
+        })
+        .collect();
+    let (created_ids, updated_ids, deleted_ids) = reduce_game_changes(&changes);
+
+    let mut rows: HashMap<i32, ScGame> = get_games_by_ids(
+        conn,
+        created_ids
+            .iter()
+            .chain(updated_ids.iter())
+            .copied()
+            .collect(),
+    )?
+    .into_iter()
+    .map(|game| (game.id, game))
+    .collect();
+    delta.created = created_ids
+        .iter()
+        .filter_map(|gid| rows.remove(gid))
+        .collect();
+    delta.updated = updated_ids
+        .iter()
+        .filter_map(|gid| rows.remove(gid))
+        .collect();
+    // Soft deleted outside the journal
+    delta.deleted_ids = updated_ids
+        .into_iter()
+        .filter(|gid| !delta.updated.iter().any(|game| game.id == *gid))
+        .chain(deleted_ids)
+        .collect();
+
+    Ok(delta)
+}
+
+/// Keep the latest change so the current version survives pruning
+pub fn prune_game_changes(conn: &PgConnection) -> QueryResult<usize> {
+    use self::game_changes::dsl::*;
+
+    let latest = game_changes
+        .select(max(version))
+        .get_result::<Option<i32>>(conn)?
+        .unwrap_or_default();
+    let deadline = Utc::now().naive_utc() - Duration::days(GAME_CHANGE_RETENTION_DAYS);
+
+    diesel::delete(
+        game_changes
+            .filter(created_at.lt(deadline))
+            .filter(version.lt(latest)),
+    )
+    .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::game_change::*;
+    use std::collections::BTreeSet;
+
+    /// Server catalog with its journal, driven like webhook events
+    #[derive(Default)]
+    struct Catalog {
+        games: BTreeSet<i32>,
+        journal: Vec<(i32, GameChangeKind)>,
+    }
+
+    impl Catalog {
+        fn apply(&mut self, gid: i32, kind: GameChangeKind) {
+            match kind {
+                GameChangeKind::Deleted => self.games.remove(&gid),
+                _ => self.games.insert(gid),
+            };
+            self.journal.push((gid, kind));
+        }
+
+        fn version(&self) -> usize {
+            self.journal.len()
+        }
+
+        fn sync(&self, local: &mut BTreeSet<i32>, since: usize) {
+            let (created, updated, deleted) = reduce_game_changes(&self.journal[since..]);
+            for gid in created.iter().chain(updated.iter()) {
+                local.insert(*gid);
+            }
+            for gid in deleted {
+                local.remove(&gid);
+            }
+        }
+    }
+
+    #[test]
+    fn delta_matches_full_list() {
+        use GameChangeKind::*;
+
+        let mut catalog = Catalog::default();
+        let mut local = BTreeSet::new();
+        for gid in 1..=3 {
+            catalog.apply(gid, Created);
+        }
+        catalog.sync(&mut local, 0);
+        assert_eq!(local, catalog.games);
+
+        let since = catalog.version();
+        catalog.apply(2, Updated);
+        catalog.apply(3, Deleted);
+        catalog.apply(4, Created);
+        catalog.apply(4, Updated);
+        catalog.apply(5, Created);
+        catalog.apply(5, Deleted);
+        catalog.apply(1, Updated);
+
+        assert_eq!(
+            reduce_game_changes(&catalog.journal[since..]),
+            (vec![4], vec![2, 1], vec![3])
+        );
+        catalog.sync(&mut local, since);
+        assert_eq!(local, catalog.games);
+
+        // Nothing changed
+        let since = catalog.version();
+        catalog.sync(&mut local, since);
+        assert_eq!(local, catalog.games);
+    }
+}
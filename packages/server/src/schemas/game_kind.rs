@@ -0,0 +1,214 @@
+use diesel::dsl::{any, count};
+use diesel::pg::Pg;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::GraphQLObject;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::string::ToString;
+
+use crate::db::models::{GameKind, NewGameKind};
+use crate::db::schema::{game_kinds, games};
+
+use super::game::ScGameKind;
+use super::visibility::visible_games;
+
+#[derive(GraphQLObject)]
+pub struct ScGameKindCount {
+    kind: ScGameKind,
+    count: i32,
+}
+
+/// game_id -> kinds, all games when `ids` is `None`
+pub fn get_game_kinds_map(
+    conn: &PgConnection,
+    ids: Option<Vec<i32>>,
+) -> HashMap<i32, Vec<ScGameKind>> {
+    use self::game_kinds::dsl::*;
+
+    let mut query = game_kinds.order(kind.asc()).into_boxed();
+    if let Some(ids) = ids {
+        query = query.filter(game_id.eq(any(ids)));
+    }
+
+    let mut map: HashMap<i32, Vec<ScGameKind>> = HashMap::new();
+    query
+        .load::<GameKind>(conn)
+        .unwrap()
+        .iter()
+        .for_each(|game_kind| {
+            if let Ok(k) = ScGameKind::from_str(&game_kind.kind) {
+                map.entry(game_kind.game_id).or_default().push(k);
+            }
+        });
+    map
+}
+
+pub fn get_game_kinds(conn: &PgConnection, gid: i32) -> Vec<ScGameKind> {
+    use self::game_kinds::dsl::*;
+
+    game_kinds
+        .filter(game_id.eq(gid))
+        .order(kind.asc())
+        .load::<GameKind>(conn)
+        .unwrap()
+        .iter()
+        .filter_map(|game_kind| ScGameKind::from_str(&game_kind.kind).ok())
+        .collect()
+}
+
+/// Reconcile kinds of the game, remove missing and insert new
+pub fn set_game_kinds(conn: &PgConnection, gid: i32, kinds: &[ScGameKind]) -> QueryResult<()> {
+    use self::game_kinds::dsl::*;
+
+    let names: Vec<String> = kinds.iter().map(|k| k.to_string()).collect();
+
+    conn.transaction(|| {
+        diesel::delete(
+            game_kinds
+                .filter(game_id.eq(gid))
+                .filter(diesel::dsl::not(kind.eq(any(names.clone())))),
+        )
+        .execute(conn)?;
+
+        let new_kinds: Vec<_> = names
+            .iter()
+            .map(|name| NewGameKind {
+                game_id: gid,
+                kind: name,
+            })
+            .collect();
+        if !new_kinds.is_empty() {
+            diesel::insert_into(game_kinds)
+                .values(&new_kinds)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Count of visible games per kind
+pub fn get_kind_counts(conn: &PgConnection) -> Vec<ScGameKindCount> {
+    use self::game_kinds::dsl::*;
+
+    let listed = visible_games()
+        .select(games::id)
+        .filter(games::deprecated_at.is_null());
+    game_kinds
+        .filter(game_id.eq_any(listed))
+        .group_by(kind)
+        .select((kind, count(game_id)))
+        .order(kind.asc())
+        .load::<(String, i64)>(conn)
+        .unwrap()
+        .into_iter()
+        .filter_map(|(k, total)| {
+            ScGameKind::from_str(&k).ok().map(|k| ScGameKindCount {
+                kind: k,
+                count: total as i32,
+            })
+        })
+        .collect()
+}
+
+/// Keep games having any of `kinds`, or all of them with `match_all`,
+/// no or empty `kinds` keeps every game
+pub fn filter_games_by_kinds<'a>(
+    query: games::BoxedQuery<'a, Pg>,
+    kinds: Option<Vec<ScGameKind>>,
+    match_all: bool,
+) -> games::BoxedQuery<'a, Pg> {
+    use self::game_kinds::dsl::*;
+
+    let mut names: Vec<String> = kinds
+        .unwrap_or_default()
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        return query;
+    }
+
+    if match_all {
+        names.into_iter().fold(query, |query, name| {
+            query.filter(games::id.eq_any(game_kinds.select(game_id).filter(kind.eq(name))))
+        })
+    } else {
+        query.filter(games::id.eq_any(game_kinds.select(game_id).filter(kind.eq(any(names)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::*;
+    use crate::schemas::game::*;
+    use crate::schemas::game_kind::*;
+
+    fn count_of(conn: &PgConnection, k: &ScGameKind) -> i32 {
+        get_kind_counts(conn)
+            .into_iter()
+            .find(|c| &c.kind == k)
+            .map(|c| c.count)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn filter_by_kinds() {
+        let conn = test_conn();
+        let act = insert_game(&conn, "kinds-act");
+        let both = insert_game(&conn, "kinds-act-stg");
+        let rpg = insert_game(&conn, "kinds-rpg");
+        set_game_kinds(&conn, act, &[ScGameKind::Act]).unwrap();
+        set_game_kinds(&conn, both, &[ScGameKind::Act, ScGameKind::Stg]).unwrap();
+        set_game_kinds(&conn, rpg, &[ScGameKind::Rpg]).unwrap();
+
+        let ids = |kinds: Option<Vec<ScGameKind>>, match_all| {
+            let mut ids: Vec<i32> = get_games(&conn, kinds, match_all)
+                .iter()
+                .map(|game| game.id)
+                .filter(|id| [act, both, rpg].contains(id))
+                .collect();
+            ids.sort();
+            ids
+        };
+        let any = ids(Some(vec![ScGameKind::Act, ScGameKind::Stg]), false);
+        assert_eq!(any, vec![act, both]);
+        let all = ids(Some(vec![ScGameKind::Act, ScGameKind::Stg]), true);
+        assert_eq!(all, vec![both]);
+        let all = ids(Some(vec![ScGameKind::Stg, ScGameKind::Stg]), true);
+        assert_eq!(all, vec![both]);
+        assert_eq!(ids(Some(vec![]), true), vec![act, both, rpg]);
+        assert_eq!(ids(None, false), vec![act, both, rpg]);
+
+        // the webhook dropped a label
+        set_game_kinds(&conn, both, &[ScGameKind::Stg]).unwrap();
+        assert_eq!(ids(Some(vec![ScGameKind::Act]), false), vec![act]);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn kind_counts() {
+        let conn = test_conn();
+        let act_before = count_of(&conn, &ScGameKind::Act);
+        let stg_before = count_of(&conn, &ScGameKind::Stg);
+
+        let act = insert_game(&conn, "counts-act");
+        let both = insert_game(&conn, "counts-act-stg");
+        let deprecated = insert_game(&conn, "counts-deprecated");
+        set_game_kinds(&conn, act, &[ScGameKind::Act]).unwrap();
+        set_game_kinds(&conn, both, &[ScGameKind::Act, ScGameKind::Stg]).unwrap();
+        set_game_kinds(&conn, deprecated, &[ScGameKind::Stg]).unwrap();
+        diesel::update(games::table.find(deprecated))
+            .set(games::deprecated_at.eq(diesel::dsl::now))
+            .execute(&conn)
+            .unwrap();
+
+        assert_eq!(count_of(&conn, &ScGameKind::Act), act_before + 2);
+        assert_eq!(count_of(&conn, &ScGameKind::Stg), stg_before + 1);
+    }
+}
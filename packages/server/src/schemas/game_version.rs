@@ -0,0 +1,380 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldResult, GraphQLEnum, GraphQLObject};
+use serde_json::Value;
+use std::env;
+use std::str::FromStr;
+use std::string::ToString;
+use strum::{Display, EnumString};
+
+use crate::db::models::{Game, GameVersion, NewGameVersion};
+use crate::db::schema::game_versions;
+
+use super::game::{convert_to_sc_game, ScGame, ScGameKind};
+use super::game_kind::get_game_kinds;
+
+lazy_static! {
+    // Versions kept per game, the oldest are pruned
+    static ref GAME_VERSIONS_KEEP: i64 = env::var("GAME_VERSIONS_KEEP")
+        .ok()
+        .and_then(|keep| keep.parse().ok())
+        .unwrap_or(50)
+        .max(1);
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScGameVersionKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScGameVersionSource {
+    // issue events
+    Webhook,
+    Admin,
+    // `CATALOG_UPSTREAM_URL`
+    Sync,
+    Seed,
+    // hash of the downloaded rom
+    RomCache,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScGameFieldChange {
+    pub field: String,
+    // json, null when the field was empty
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScGameVersion {
+    pub id: i32,
+    pub kind: ScGameVersionKind,
+    pub source: ScGameVersionSource,
+    pub created_at: f64,
+    pub game: ScGame,
+    // against the previous version, every field for the oldest one kept
+    pub changes: Vec<ScGameFieldChange>,
+}
+
+/// Columns of the row plus the kinds, timestamps in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameSnapshot {
+    pub name: String,
+    pub description: String,
+    pub description_html: String,
+    pub preview: String,
+    pub rom: String,
+    pub rom_hash: Option<String>,
+    pub rom_cached_at: Option<i64>,
+    pub screenshots: Option<String>,
+    pub platform: Option<String>,
+    pub series: Option<String>,
+    pub kind: Option<String>,
+    pub kinds: Vec<String>,
+    pub max_player: Option<i32>,
+    pub play_modes: Vec<String>,
+    pub deprecated_at: Option<i64>,
+    pub deprecation_reason: Option<String>,
+    pub issue_number: Option<i32>,
+    pub issue_url: Option<String>,
+    pub score_ceiling: Option<i64>,
+    pub trial_allowed: bool,
+    // missing in versions from before ratings
+    #[serde(default)]
+    pub mature: bool,
+    pub source: String,
+    pub upstream_id: Option<i32>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub deleted_at: Option<i64>,
+}
+
+fn from_millis(ms: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(
+        ms.div_euclid(1000),
+        (ms.rem_euclid(1000) * 1_000_000) as u32,
+    )
+}
+
+impl GameSnapshot {
+    pub fn new(game: &Game, kinds: &[ScGameKind]) -> Self {
+        GameSnapshot {
+            name: game.name.clone(),
+            description: game.description.clone(),
+            description_html: game.description_html.clone(),
+            preview: game.preview.clone(),
+            rom: game.rom.clone(),
+            rom_hash: game.rom_hash.clone(),
+            rom_cached_at: game.rom_cached_at.map(|at| at.timestamp_millis()),
+            screenshots: game.screenshots.clone(),
+            platform: game.platform.clone(),
+            series: game.series.clone(),
+            kind: game.kind.clone(),
+            kinds: kinds.iter().map(|k| k.to_string()).collect(),
+            max_player: game.max_player,
+            play_modes: game.play_modes.clone(),
+            deprecated_at: game.deprecated_at.map(|at| at.timestamp_millis()),
+            deprecation_reason: game.deprecation_reason.clone(),
+            issue_number: game.issue_number,
+            issue_url: game.issue_url.clone(),
+            score_ceiling: game.score_ceiling,
+            trial_allowed: game.trial_allowed,
+            mature: game.mature,
+            source: game.source.clone(),
+            upstream_id: game.upstream_id,
+            created_at: game.created_at.timestamp_millis(),
+            updated_at: game.updated_at.timestamp_millis(),
+            deleted_at: game.deleted_at.map(|at| at.timestamp_millis()),
+        }
+    }
+
+    /// The game as it was, without comment counts and attachments
+    pub fn to_sc_game(&self, gid: i32) -> ScGame {
+        let game = Game {
+            id: gid,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            preview: self.preview.clone(),
+            deleted_at: self.deleted_at.map(from_millis),
+            created_at: from_millis(self.created_at),
+            updated_at: from_millis(self.updated_at),
+            rom: self.rom.clone(),
+            screenshots: self.screenshots.clone(),
+            platform: self.platform.clone(),
+            series: self.series.clone(),
+            kind: self.kind.clone(),
+            max_player: self.max_player,
+            deprecated_at: self.deprecated_at.map(from_millis),
+            deprecation_reason: self.deprecation_reason.clone(),
+            rom_hash: self.rom_hash.clone(),
+            rom_cached_at: self.rom_cached_at.map(from_millis),
+            issue_number: self.issue_number,
+            issue_url: self.issue_url.clone(),
+            score_ceiling: self.score_ceiling,
+            trial_allowed: self.trial_allowed,
+            description_html: self.description_html.clone(),
+            source: self.source.clone(),
+            upstream_id: self.upstream_id,
+            play_modes: self.play_modes.clone(),
+            mature: self.mature,
+        };
+        let mut sc_game = convert_to_sc_game(&game);
+        sc_game.kinds = self
+            .kinds
+            .iter()
+            .filter_map(|k| ScGameKind::from_str(k).ok())
+            .collect();
+        sc_game
+    }
+}
+
+/// Snapshot the game as stored now, called after each catalog change
+pub fn record_game_version(
+    conn: &PgConnection,
+    game: &Game,
+    version_kind: ScGameVersionKind,
+    version_source: ScGameVersionSource,
+) -> QueryResult<()> {
+    use self::game_versions::dsl::*;
+
+    let value =
+        serde_json::to_value(GameSnapshot::new(game, &get_game_kinds(conn, game.id))).unwrap();
+    let new_version = NewGameVersion {
+        game_id: game.id,
+        kind: &version_kind.to_string(),
+        source: &version_source.to_string(),
+        snapshot: &value,
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(game_versions)
+        .values(&new_version)
+        .execute(conn)?;
+
+    // Oldest kept version
+    let oldest = game_versions
+        .select(id)
+        .filter(game_id.eq(game.id))
+        .order(id.desc())
+        .offset(*GAME_VERSIONS_KEEP - 1)
+        .first::<i32>(conn)
+        .optional()?;
+    if let Some(oldest) = oldest {
+        diesel::delete(
+            game_versions
+                .filter(game_id.eq(game.id))
+                .filter(id.lt(oldest)),
+        )
+        .execute(conn)?;
+    }
+    Ok(())
+}
+
+fn get_field_value(snapshot: Option<&Value>, field: &str) -> Option<String> {
+    snapshot
+        .and_then(|snapshot| snapshot.get(field))
+        .filter(|value| !value.is_null())
+        .map(|value| value.to_string())
+}
+
+/// Fields that differ, in name order, `updated_at` changes every time
+pub fn diff_snapshots(old: Option<&Value>, new: &Value) -> Vec<ScGameFieldChange> {
+    let mut fields: Vec<&String> = new
+        .as_object()
+        .into_iter()
+        .chain(old.and_then(|old| old.as_object()))
+        .flat_map(|map| map.keys())
+        .filter(|field| *field != "updated_at")
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let old_value = get_field_value(old, field);
+            let new_value = get_field_value(Some(new), field);
+            (old_value != new_value).then(|| ScGameFieldChange {
+                field: field.clone(),
+                old_value,
+                new_value,
+            })
+        })
+        .collect()
+}
+
+fn convert_to_sc_game_version(version: &GameVersion, previous: Option<&Value>) -> ScGameVersion {
+    ScGameVersion {
+        id: version.id,
+        kind: ScGameVersionKind::from_str(&version.kind).unwrap_or(ScGameVersionKind::Updated),
+        source: ScGameVersionSource::from_str(&version.source)
+            .unwrap_or(ScGameVersionSource::Admin),
+        created_at: version.created_at.timestamp_millis() as f64,
+        game: serde_json::from_value::<GameSnapshot>(version.snapshot.clone())
+            .map(|snapshot| snapshot.to_sc_game(version.game_id))
+            .unwrap(),
+        changes: diff_snapshots(previous, &version.snapshot),
+    }
+}
+
+/// Latest first, trashed games included
+pub fn get_game_history(conn: &PgConnection, gid: i32) -> FieldResult<Vec<ScGameVersion>> {
+    use self::game_versions::dsl::*;
+
+    let list = game_versions
+        .filter(game_id.eq(gid))
+        .order(id.asc())
+        .load::<GameVersion>(conn)?;
+    let mut history: Vec<ScGameVersion> = list
+        .iter()
+        .enumerate()
+        .map(|(index, version)| {
+            let previous = index.checked_sub(1).map(|prev| &list[prev].snapshot);
+            convert_to_sc_game_version(version, previous)
+        })
+        .collect();
+    history.reverse();
+    Ok(history)
+}
+
+/// The game at that time, `None` before it was created, while it was deleted
+/// or when the versions of that time were pruned
+pub fn get_game_as_of(
+    conn: &PgConnection,
+    gid: i32,
+    at: NaiveDateTime,
+) -> FieldResult<Option<ScGame>> {
+    use self::game_versions::dsl::*;
+
+    let version = game_versions
+        .filter(game_id.eq(gid))
+        .filter(created_at.le(at))
+        .order(id.desc())
+        .first::<GameVersion>(conn)
+        .optional()?;
+    Ok(version
+        .filter(|version| version.kind != ScGameVersionKind::Deleted.to_string())
+        .and_then(|version| serde_json::from_value::<GameSnapshot>(version.snapshot).ok())
+        .map(|snapshot| snapshot.to_sc_game(gid)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::game_version::*;
+    use serde_json::json;
+
+    #[test]
+    fn game_snapshots() {
+        let now = from_millis(Utc::now().timestamp_millis());
+        let game = Game {
+            id: 7,
+            name: "Contra".into(),
+            description: "run and gun".into(),
+            preview: "https://example.com/contra.png".into(),
+            deleted_at: None,
+            created_at: now,
+            updated_at: now,
+            rom: "/roms/contra.nes.zip".into(),
+            screenshots: Some("a.png,b.png".into()),
+            platform: Some("nes".into()),
+            series: Some("contra".into()),
+            kind: Some("stg".into()),
+            max_player: Some(2),
+            deprecated_at: None,
+            deprecation_reason: None,
+            rom_hash: Some("abc".into()),
+            rom_cached_at: Some(now),
+            issue_number: Some(12),
+            issue_url: None,
+            score_ceiling: None,
+            trial_allowed: true,
+            description_html: "<p>run and gun</p>".into(),
+            source: "local".into(),
+            upstream_id: None,
+            play_modes: vec!["co_op".into()],
+            mature: false,
+        };
+        let snapshot = GameSnapshot::new(&game, &[ScGameKind::Stg, ScGameKind::Act]);
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_value::<GameSnapshot>(value.clone()).unwrap(),
+            snapshot
+        );
+        let sc_game = snapshot.to_sc_game(7);
+        assert_eq!(sc_game.id, 7);
+        assert_eq!(sc_game.kinds, vec![ScGameKind::Stg, ScGameKind::Act]);
+        assert!(sc_game.rom_ready);
+
+        assert_eq!(diff_snapshots(Some(&value), &value), vec![]);
+        let mut changed = value.clone();
+        changed["rom_hash"] = json!("def");
+        changed["deprecation_reason"] = json!("broken dump");
+        changed["updated_at"] = json!(0);
+        assert_eq!(
+            diff_snapshots(Some(&value), &changed),
+            vec![
+                ScGameFieldChange {
+                    field: "deprecation_reason".into(),
+                    old_value: None,
+                    new_value: Some(r#""broken dump""#.into()),
+                },
+                ScGameFieldChange {
+                    field: "rom_hash".into(),
+                    old_value: Some(r#""abc""#.into()),
+                    new_value: Some(r#""def""#.into()),
+                },
+            ]
+        );
+        // Oldest version kept
+        let all = diff_snapshots(None, &value);
+        assert!(all.iter().all(|change| change.old_value.is_none()));
+        assert!(all.iter().any(|change| change.field == "name"));
+        assert!(!all.iter().any(|change| change.field == "deleted_at"));
+    }
+}
@@ -0,0 +1,194 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldResult, GraphQLObject};
+use std::env;
+
+use super::game_change::{record_game_change, GameChangeKind};
+use crate::db::models::{GithubDelivery, NewGithubDelivery, NewGithubRepo};
+use crate::db::schema::{games, github_deliveries, github_repos};
+use crate::github::rename_issue_url;
+
+lazy_static! {
+    // owner/name, comma separated, added to `github_repos` at startup
+    static ref GITHUB_REPOS: Vec<String> = env::var("GITHUB_REPOS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|repo| repo.trim().to_owned())
+        .filter(|repo| repo.contains('/'))
+        .collect();
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScGithubDelivery {
+    pub id: i32,
+    pub event: String,
+    pub action: String,
+    pub repository: String,
+    pub affected_rows: i32,
+    pub detail: String,
+    pub created_at: f64,
+}
+
+pub fn seed_github_repos(conn: &PgConnection) -> QueryResult<usize> {
+    let now = Utc::now().naive_utc();
+    let new_repos: Vec<_> = GITHUB_REPOS
+        .iter()
+        .map(|repo| NewGithubRepo {
+            full_name: repo,
+            created_at: now,
+            updated_at: now,
+        })
+        .collect();
+
+    diesel::insert_into(github_repos::table)
+        .values(&new_repos)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+/// Case insensitive like GitHub
+pub fn is_configured_repo(conn: &PgConnection, repo: &str) -> QueryResult<bool> {
+    use self::github_repos::dsl::*;
+
+    Ok(github_repos
+        .select(full_name)
+        .load::<String>(conn)?
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(repo)))
+}
+
+pub fn write_github_delivery(
+    conn: &PgConnection,
+    event_name: &str,
+    act: &str,
+    repo: &str,
+    affected: usize,
+    text: &str,
+) -> QueryResult<()> {
+    let new_delivery = NewGithubDelivery {
+        event: event_name,
+        action: act,
+        repository: repo,
+        affected_rows: affected as i32,
+        detail: text,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(github_deliveries::table)
+        .values(&new_delivery)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_github_deliveries(
+    conn: &PgConnection,
+    first: Option<i32>,
+) -> FieldResult<Vec<ScGithubDelivery>> {
+    use self::github_deliveries::dsl::*;
+
+    Ok(github_deliveries
+        .order(id.desc())
+        .limit(first.unwrap_or(20).clamp(1, 100).into())
+        .load::<GithubDelivery>(conn)?
+        .into_iter()
+        .map(|delivery| ScGithubDelivery {
+            id: delivery.id,
+            event: delivery.event,
+            action: delivery.action,
+            repository: delivery.repository,
+            affected_rows: delivery.affected_rows,
+            detail: delivery.detail,
+            created_at: delivery.created_at.timestamp_millis() as f64,
+        })
+        .collect())
+}
+
+/// Games of the issue at `old_url`
+pub fn get_issue_game_ids(conn: &PgConnection, old_url: &str) -> QueryResult<Vec<i32>> {
+    use self::games::dsl::*;
+
+    games
+        .select(id)
+        .filter(issue_url.eq(old_url))
+        .load::<i32>(conn)
+}
+
+/// Point the games of a transferred issue to its new place, return their ids
+pub fn relink_issue(
+    conn: &PgConnection,
+    old_url: &str,
+    number: i32,
+    new_url: &str,
+) -> QueryResult<Vec<i32>> {
+    use self::games::dsl::*;
+
+    let ids = diesel::update(games.filter(issue_url.eq(old_url)))
+        .set((
+            issue_number.eq(number),
+            issue_url.eq(new_url),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(id)
+        .get_results::<i32>(conn)?;
+    for gid in &ids {
+        record_game_change(conn, *gid, GameChangeKind::Updated)?;
+    }
+    Ok(ids)
+}
+
+/// Issue urls and the configured repository follow a rename,
+/// return the ids of games relinked
+pub fn rename_repo(conn: &PgConnection, old: &str, new: &str) -> QueryResult<Vec<i32>> {
+    let links = games::table
+        .select((games::id, games::issue_url))
+        .filter(games::issue_url.is_not_null())
+        .load::<(i32, Option<String>)>(conn)?;
+    let mut ids = Vec::new();
+    for (gid, url) in links {
+        let new_url = match url.and_then(|url| rename_issue_url(&url, old, new)) {
+            Some(new_url) => new_url,
+            None => continue,
+        };
+        diesel::update(games::table.filter(games::id.eq(gid)))
+            .set((
+                games::issue_url.eq(new_url),
+                games::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+        record_game_change(conn, gid, GameChangeKind::Updated)?;
+        ids.push(gid);
+    }
+
+    let renamed = github_repos::table
+        .select(github_repos::full_name)
+        .load::<String>(conn)?
+        .into_iter()
+        .filter(|name| name.eq_ignore_ascii_case(old))
+        .collect::<Vec<_>>();
+    diesel::update(github_repos::table.filter(github_repos::full_name.eq_any(&renamed)))
+        .set((
+            github_repos::full_name.eq(new),
+            github_repos::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+
+    write_github_delivery(
+        conn,
+        "repository",
+        "renamed",
+        new,
+        ids.len(),
+        &format!(
+            "{} -> {}, configured repository {}",
+            old,
+            new,
+            if renamed.is_empty() {
+                "unchanged"
+            } else {
+                "renamed"
+            }
+        ),
+    )?;
+    Ok(ids)
+}
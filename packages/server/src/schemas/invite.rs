@@ -1,7 +1,8 @@
-use chrono::Utc;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use juniper::{FieldResult, GraphQLInputObject, GraphQLObject};
+use std::env;
 
 use crate::db::models::{Invite, NewInvite};
 use crate::db::schema::invites;
@@ -9,6 +10,15 @@ use crate::schemas::user::get_user_by_username;
 
 use super::room::{get_room, ScRoomBasic};
 
+lazy_static! {
+    // Unanswered invites lapse after this, the room has likely moved on
+    static ref INVITE_TTL_SECONDS: i64 = env::var("INVITE_TTL_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|seconds: &i64| *seconds > 0)
+        .unwrap_or(30 * 60);
+}
+
 #[derive(GraphQLObject, Debug, Clone)]
 pub struct ScInvite {
     pub id: i32,
@@ -17,6 +27,7 @@ pub struct ScInvite {
     user_id: i32,
     created_at: f64,
     updated_at: f64,
+    expires_at: f64,
 }
 
 #[derive(GraphQLInputObject)]
@@ -40,14 +51,22 @@ fn convert_to_sc_invite(conn: &PgConnection, invite: &Invite) -> ScInvite {
         user_id: invite.user_id,
         created_at: invite.created_at.timestamp_millis() as f64,
         updated_at: invite.updated_at.timestamp_millis() as f64,
+        expires_at: (invite.created_at + Duration::seconds(*INVITE_TTL_SECONDS)).timestamp_millis()
+            as f64,
     }
 }
 
+/// Invites created before this are expired
+fn get_expired_before() -> NaiveDateTime {
+    Utc::now().naive_utc() - Duration::seconds(*INVITE_TTL_SECONDS)
+}
+
 pub fn get_invites(conn: &PgConnection, uid: i32) -> Vec<ScInvite> {
     use self::invites::dsl::*;
 
     invites
         .filter(deleted_at.is_null())
+        .filter(created_at.gt(get_expired_before()))
         .filter(target_id.eq(uid))
         .load::<Invite>(conn)
         .unwrap()
@@ -61,6 +80,7 @@ pub fn get_invites_with(conn: &PgConnection, uid: i32) -> Vec<ScInvite> {
 
     invites
         .filter(deleted_at.is_null())
+        .filter(created_at.gt(get_expired_before()))
         .filter(user_id.eq(uid))
         .load::<Invite>(conn)
         .unwrap()
@@ -74,6 +94,7 @@ pub fn get_invite(conn: &PgConnection, uid: i32, iid: i32) -> FieldResult<ScInvi
 
     let invite = invites
         .filter(deleted_at.is_null())
+        .filter(created_at.gt(get_expired_before()))
         .filter(target_id.eq(uid))
         .filter(id.eq(iid))
         .get_result::<Invite>(conn)?;
@@ -136,3 +157,57 @@ pub fn delete_invite(conn: &PgConnection, uid: i32, all: bool) {
             .unwrap();
     }
 }
+
+pub fn prune_invites(conn: &PgConnection) -> QueryResult<usize> {
+    use self::invites::dsl::*;
+
+    diesel::delete(invites.filter(created_at.lt(get_expired_before()))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::*;
+    use crate::schemas::invite::*;
+
+    fn insert_invite(conn: &PgConnection, rid: i32, uid: i32, tid: i32, at: NaiveDateTime) -> i32 {
+        diesel::insert_into(invites::table)
+            .values(&NewInvite {
+                user_id: uid,
+                room_id: rid,
+                target_id: tid,
+                deleted_at: None,
+                created_at: at,
+                updated_at: at,
+            })
+            .get_result::<Invite>(conn)
+            .unwrap()
+            .id
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn expired_invites() {
+        let conn = test_conn();
+        let host = insert_user(&conn, "invite-host");
+        let late = insert_user(&conn, "invite-late");
+        let early = insert_user(&conn, "invite-early");
+        let rid = insert_room(&conn, insert_game(&conn, "invites"), host);
+        let now = Utc::now().naive_utc();
+        let ttl = Duration::seconds(*INVITE_TTL_SECONDS);
+        let expired = insert_invite(&conn, rid, host, late, now - ttl - Duration::seconds(1));
+        let fresh = insert_invite(&conn, rid, host, early, now - ttl + Duration::seconds(60));
+
+        // Accepting looks the invite up first
+        assert!(get_invite(&conn, late, expired).is_err());
+        assert!(get_invites(&conn, late).is_empty());
+        assert_eq!(get_invite(&conn, early, fresh).unwrap().id, fresh);
+
+        assert_eq!(prune_invites(&conn).unwrap(), 1);
+        let left: Vec<i32> = invites::table
+            .select(invites::id)
+            .filter(invites::room_id.eq(rid))
+            .load(&conn)
+            .unwrap();
+        assert_eq!(left, vec![fresh]);
+    }
+}
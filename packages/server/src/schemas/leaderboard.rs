@@ -0,0 +1,198 @@
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Varchar};
+use juniper::{FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+
+use super::friend::ScFriendStatus;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScLeaderboardKind {
+    PlayTime,
+    // best reviewed score per user
+    HighScore,
+}
+
+// Windows start at midnight UTC, weeks on Monday
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScLeaderboardRange {
+    Day,
+    Week,
+    AllTime,
+}
+
+impl ScLeaderboardRange {
+    /// Start of the current window as SQL, `None` for all time
+    fn since(&self) -> Option<&'static str> {
+        match self {
+            ScLeaderboardRange::Day => Some("date_trunc('day', timezone('UTC', now()))"),
+            ScLeaderboardRange::Week => Some("date_trunc('week', timezone('UTC', now()))"),
+            ScLeaderboardRange::AllTime => None,
+        }
+    }
+}
+
+impl ScLeaderboardKind {
+    /// Relation joined as `source`, with `user_id`, `score` and `proof_url`.
+    /// Binds: $1 game id
+    fn source(&self, range: ScLeaderboardRange) -> String {
+        match (self, range.since()) {
+            (ScLeaderboardKind::PlayTime, None) => {
+                "SELECT user_id, play_total AS score, NULL::varchar AS proof_url \
+                FROM records WHERE game_id = $1"
+                    .into()
+            }
+            // Sessions ended in the window
+            (ScLeaderboardKind::PlayTime, Some(since)) => format!(
+                "SELECT user_id, SUM(duration)::bigint AS score, NULL::varchar AS proof_url \
+                FROM play_sessions WHERE game_id = $1 AND ended_at >= {} \
+                GROUP BY user_id",
+                since
+            ),
+            (ScLeaderboardKind::HighScore, since) => format!(
+                "SELECT DISTINCT ON (user_id) user_id, score, proof_url \
+                FROM scores WHERE game_id = $1 AND NOT flagged {} \
+                ORDER BY user_id, score DESC, id",
+                since
+                    .map(|since| format!("AND created_at >= {}", since))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScLeaderboardReq {
+    pub game_id: i32,
+    pub kind: ScLeaderboardKind,
+    // all time when null
+    pub range: Option<ScLeaderboardRange>,
+    pub first: Option<i32>,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScLeaderboardItem {
+    // null when no record
+    rank: Option<i32>,
+    user_id: i32,
+    nickname: String,
+    score: Option<f64>,
+    // screenshot attached to the ranked score
+    proof_url: Option<String>,
+    is_me: bool,
+}
+
+#[derive(QueryableByName)]
+struct LeaderboardRow {
+    #[sql_type = "Integer"]
+    user_id: i32,
+    #[sql_type = "Varchar"]
+    nickname: String,
+    #[sql_type = "Nullable<BigInt>"]
+    score: Option<i64>,
+    #[sql_type = "Nullable<Varchar>"]
+    proof_url: Option<String>,
+    #[sql_type = "BigInt"]
+    rank: i64,
+}
+
+/// Rank `members` (subquery selecting `user_id`) on the game,
+/// members without record are kept at the bottom.
+/// Binds: $1 game id, $2 limit
+fn ranking_sql(req: &ScLeaderboardReq, members: &str) -> String {
+    format!(
+        r#"
+SELECT members.user_id, users.nickname, source.score, source.proof_url,
+    RANK() OVER (ORDER BY source.score DESC NULLS LAST) AS rank
+FROM ({members}) AS members
+INNER JOIN users ON users."id" = members.user_id AND users.deleted_at IS NULL
+LEFT JOIN ({source}) AS source ON source.user_id = members.user_id
+ORDER BY rank, members.user_id
+LIMIT $2
+"#,
+        source = req.kind.source(get_range(req)),
+        members = members,
+    )
+}
+
+fn get_range(req: &ScLeaderboardReq) -> ScLeaderboardRange {
+    req.range.unwrap_or(ScLeaderboardRange::AllTime)
+}
+
+fn convert_to_sc_leaderboard_item(row: &LeaderboardRow, uid: i32) -> ScLeaderboardItem {
+    ScLeaderboardItem {
+        rank: row.score.map(|_| row.rank as i32),
+        user_id: row.user_id,
+        nickname: row.nickname.clone(),
+        score: row.score.map(|score| score as f64),
+        proof_url: row.proof_url.clone(),
+        is_me: row.user_id == uid,
+    }
+}
+
+pub fn get_leaderboard(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScLeaderboardReq,
+) -> FieldResult<Vec<ScLeaderboardItem>> {
+    let members = format!(
+        "SELECT user_id FROM ({}) AS ranked",
+        req.kind.source(get_range(req))
+    );
+    let sql = ranking_sql(req, &members);
+
+    let rows = diesel::sql_query(sql)
+        .bind::<Integer, _>(req.game_id)
+        .bind::<BigInt, _>(req.first.unwrap_or(100).clamp(1, 100) as i64)
+        .load::<LeaderboardRow>(conn)?;
+
+    Ok(rows
+        .iter()
+        .map(|row| convert_to_sc_leaderboard_item(row, uid))
+        .collect())
+}
+
+/// Caller and accepted friends, including friends without record
+pub fn get_friends_leaderboard(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScLeaderboardReq,
+) -> FieldResult<Vec<ScLeaderboardItem>> {
+    let sql = ranking_sql(
+        req,
+        r#"SELECT $3::integer AS user_id
+        UNION SELECT target_id FROM friends WHERE user_id = $3 AND status = $4"#,
+    );
+
+    let rows = diesel::sql_query(sql)
+        .bind::<Integer, _>(req.game_id)
+        .bind::<BigInt, _>(req.first.unwrap_or(100).clamp(1, 100) as i64)
+        .bind::<Integer, _>(uid)
+        .bind::<Varchar, _>(ScFriendStatus::Accept.to_string())
+        .load::<LeaderboardRow>(conn)?;
+
+    Ok(rows
+        .iter()
+        .map(|row| convert_to_sc_leaderboard_item(row, uid))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::leaderboard::*;
+
+    #[test]
+    fn leaderboard_ranges() {
+        let all_time = ScLeaderboardKind::HighScore.source(ScLeaderboardRange::AllTime);
+        assert!(!all_time.contains("created_at"));
+        let week = ScLeaderboardKind::HighScore.source(ScLeaderboardRange::Week);
+        assert!(week.contains("created_at >= date_trunc('week'"));
+        assert!(week.ends_with("ORDER BY user_id, score DESC, id"));
+
+        assert!(ScLeaderboardKind::PlayTime
+            .source(ScLeaderboardRange::AllTime)
+            .contains("FROM records"));
+        let day = ScLeaderboardKind::PlayTime.source(ScLeaderboardRange::Day);
+        assert!(day.contains("FROM play_sessions"));
+        assert!(day.contains("ended_at >= date_trunc('day'"));
+    }
+}
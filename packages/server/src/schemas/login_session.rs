@@ -0,0 +1,373 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use data_encoding::HEXLOWER;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLEnum};
+use ring::rand::{SecureRandom, SystemRandom};
+use strum::{Display, EnumString};
+
+use super::notify::{notify, ScNotifyMessageBuilder};
+use crate::auth::{
+    generate_refresh_token, parse_refresh_token, revoke_session, revoke_token, TokenHandle,
+    TOKEN_TTL_SECONDS,
+};
+use crate::db::models::{LoginSession, NewLoginSession, NewRevokedToken, RevokedToken};
+use crate::db::schema::{login_sessions, revoked_tokens};
+use crate::error::Error;
+
+// Tabs of one browser may refresh at once, the slower one is refused without revoking
+const REFRESH_REUSE_GRACE_SECONDS: i64 = 10;
+
+/// What a login does to the other sessions of the account
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScSessionPolicy {
+    // keep them
+    Multi,
+    // revoke them, e.g. in an internet café
+    Single,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScSessionCloseReason {
+    // a login with the `SINGLE` policy
+    SessionReplaced,
+    // `revokeRefreshToken` or `logout`
+    SignedOut,
+    // a rotated refresh token came back, it may have leaked
+    RefreshReused,
+    // the account was anonymized by retention
+    AccountAnonymized,
+}
+
+fn get_active_sessions(conn: &PgConnection, uid: i32) -> QueryResult<Vec<LoginSession>> {
+    use self::login_sessions::dsl::*;
+
+    login_sessions
+        .filter(user_id.eq(uid))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .load::<LoginSession>(conn)
+}
+
+/// Denylist the tokens and end the subscriptions of the sessions,
+/// sockets of the kept session ignore the event
+pub fn close_sessions(uid: i32, revoked: &[(String, i64)], reason: ScSessionCloseReason) {
+    for (sid, exp) in revoked {
+        revoke_session(sid, *exp);
+    }
+    notify(
+        uid,
+        ScNotifyMessageBuilder::default()
+            .session_closed(reason)
+            .build()
+            .unwrap(),
+    );
+}
+
+/// Id and refresh token of the new session, and the number of other active
+/// sessions, revoked under `SINGLE`. Sockets opened with tokens from before
+/// sessions existed are closed too, their tokens expire on their own
+pub fn start_login_session(
+    conn: &PgConnection,
+    uid: i32,
+    policy: ScSessionPolicy,
+) -> QueryResult<(String, String, i32)> {
+    use self::login_sessions::dsl::*;
+
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let sid = HEXLOWER.encode(&bytes);
+    let (refresh_token, hash) = generate_refresh_token(&sid);
+    let others = get_active_sessions(conn, uid)?;
+    let now = Utc::now().naive_utc();
+    let new_session = NewLoginSession {
+        id: &sid,
+        user_id: uid,
+        created_at: now,
+        expires_at: now + Duration::seconds(TOKEN_TTL_SECONDS),
+        refresh_hash: Some(&hash),
+    };
+    diesel::insert_into(login_sessions)
+        .values(&new_session)
+        .execute(conn)?;
+
+    if policy == ScSessionPolicy::Single {
+        let reason = ScSessionCloseReason::SessionReplaced;
+        let ids: Vec<&str> = others.iter().map(|session| session.id.as_str()).collect();
+        diesel::update(login_sessions.filter(id.eq_any(ids)))
+            .set((
+                revoked_at.eq(Some(now)),
+                revoke_reason.eq(Some(reason.to_string())),
+            ))
+            .execute(conn)?;
+        let revoked: Vec<(String, i64)> = others
+            .iter()
+            .map(|session| (session.id.clone(), session.expires_at.timestamp()))
+            .collect();
+        close_sessions(uid, &revoked, reason);
+    }
+
+    Ok((sid, refresh_token, others.len() as i32))
+}
+
+fn revoke_login_session(
+    conn: &PgConnection,
+    session: &LoginSession,
+    reason: ScSessionCloseReason,
+) -> QueryResult<()> {
+    use self::login_sessions::dsl::*;
+
+    diesel::update(login_sessions.filter(id.eq(&session.id)))
+        .set((
+            revoked_at.eq(Some(Utc::now().naive_utc())),
+            revoke_reason.eq(Some(reason.to_string())),
+        ))
+        .execute(conn)?;
+    close_sessions(
+        session.user_id,
+        &[(session.id.clone(), session.expires_at.timestamp())],
+        reason,
+    );
+    Ok(())
+}
+
+fn get_refreshable_session(conn: &PgConnection, sid: &str) -> QueryResult<Option<LoginSession>> {
+    use self::login_sessions::dsl::*;
+
+    login_sessions
+        .filter(id.eq(sid))
+        .filter(revoked_at.is_null())
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .get_result::<LoginSession>(conn)
+        .optional()
+}
+
+/// The session and its next refresh token, each token works once and the
+/// session lasts `TOKEN_TTL_SECONDS` from the last refresh
+pub fn refresh_login_session(
+    conn: &PgConnection,
+    token: &str,
+) -> FieldResult<(LoginSession, String)> {
+    use self::login_sessions::dsl::*;
+
+    let invalid = || FieldError::new("invalid refresh token", Error::refresh_token_invalid());
+    let (sid, hash) = parse_refresh_token(token).ok_or_else(invalid)?;
+    let session = get_refreshable_session(conn, sid)?.ok_or_else(invalid)?;
+    let now = Utc::now().naive_utc();
+    if session.refresh_hash.as_deref() != Some(hash.as_str()) {
+        let reused = session.previous_refresh_hash.as_deref() == Some(hash.as_str());
+        let raced = session.refreshed_at.map_or(false, |at| {
+            now - at < Duration::seconds(REFRESH_REUSE_GRACE_SECONDS)
+        });
+        if reused && !raced {
+            log::warn!("Refresh token reused, revoke session {}", session.id);
+            revoke_login_session(conn, &session, ScSessionCloseReason::RefreshReused)?;
+        }
+        return Err(invalid());
+    }
+
+    let (new_token, new_hash) = generate_refresh_token(sid);
+    // Conditional, of two concurrent refreshes only one rotates
+    let session = diesel::update(
+        login_sessions
+            .filter(id.eq(sid))
+            .filter(refresh_hash.eq(hash.as_str())),
+    )
+    .set((
+        refresh_hash.eq(Some(new_hash.as_str())),
+        previous_refresh_hash.eq(Some(hash.as_str())),
+        refreshed_at.eq(Some(now)),
+        expires_at.eq(now + Duration::seconds(TOKEN_TTL_SECONDS)),
+    ))
+    .get_result::<LoginSession>(conn)
+    .optional()?
+    .ok_or_else(invalid)?;
+    Ok((session, new_token))
+}
+
+/// Ends the session of the token, `false` when it was not valid
+pub fn revoke_refresh_token(conn: &PgConnection, token: &str) -> QueryResult<bool> {
+    let (sid, hash) = match parse_refresh_token(token) {
+        Some(parsed) => parsed,
+        None => return Ok(false),
+    };
+    match get_refreshable_session(conn, sid)? {
+        Some(session) if session.refresh_hash.as_deref() == Some(hash.as_str()) => {
+            revoke_login_session(conn, &session, ScSessionCloseReason::SignedOut)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Persist the token in the denylist, other instances pick it up on restart
+pub fn revoke_user_token(conn: &PgConnection, uid: i32, handle: &TokenHandle) -> QueryResult<()> {
+    let new_token = NewRevokedToken {
+        jti: &handle.jti,
+        user_id: uid,
+        expires_at: NaiveDateTime::from_timestamp_opt(handle.exp, 0).unwrap_or_default(),
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(revoked_tokens::table)
+        .values(&new_token)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    revoke_token(&handle.jti, handle.exp);
+    Ok(())
+}
+
+/// Revoke the token and its session, or every session of the user
+pub fn logout(
+    conn: &PgConnection,
+    uid: i32,
+    handle: &TokenHandle,
+    all_sessions: bool,
+) -> QueryResult<()> {
+    use self::login_sessions::dsl::*;
+
+    revoke_user_token(conn, uid, handle)?;
+    let reason = ScSessionCloseReason::SignedOut;
+    let sessions = if all_sessions {
+        get_active_sessions(conn, uid)?
+    } else {
+        match &handle.sid {
+            Some(sid) => get_refreshable_session(conn, sid)?.into_iter().collect(),
+            None => vec![],
+        }
+    };
+    if sessions.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<&str> = sessions.iter().map(|session| session.id.as_str()).collect();
+    diesel::update(login_sessions.filter(id.eq_any(ids)))
+        .set((
+            revoked_at.eq(Some(Utc::now().naive_utc())),
+            revoke_reason.eq(Some(reason.to_string())),
+        ))
+        .execute(conn)?;
+    let revoked: Vec<(String, i64)> = sessions
+        .iter()
+        .map(|session| (session.id.clone(), session.expires_at.timestamp()))
+        .collect();
+    close_sessions(uid, &revoked, reason);
+    Ok(())
+}
+
+/// Revoke every session of the users with their refresh tokens, return the
+/// user, id and expiry of each to pass to `close_sessions` once committed
+pub fn revoke_users_sessions(
+    conn: &PgConnection,
+    uids: &[i32],
+    reason: ScSessionCloseReason,
+) -> QueryResult<Vec<(i32, String, NaiveDateTime)>> {
+    use self::login_sessions::dsl::*;
+
+    diesel::update(
+        login_sessions
+            .filter(user_id.eq_any(uids))
+            .filter(revoked_at.is_null()),
+    )
+    .set((
+        revoked_at.eq(Some(Utc::now().naive_utc())),
+        revoke_reason.eq(Some(reason.to_string())),
+        refresh_hash.eq(None::<String>),
+        previous_refresh_hash.eq(None::<String>),
+    ))
+    .returning((user_id, id, expires_at))
+    .get_results(conn)
+}
+
+/// Fill the token denylist at startup, returns the count
+pub fn load_revoked_tokens(conn: &PgConnection) -> QueryResult<usize> {
+    use self::revoked_tokens::dsl::*;
+
+    let list = revoked_tokens
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .load::<RevokedToken>(conn)?;
+    for token in &list {
+        revoke_token(&token.jti, token.expires_at.timestamp());
+    }
+    Ok(list.len())
+}
+
+/// Fill the denylist at startup, returns the count
+pub fn load_revoked_sessions(conn: &PgConnection) -> QueryResult<usize> {
+    use self::login_sessions::dsl::*;
+
+    let list = login_sessions
+        .filter(revoked_at.is_not_null())
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .load::<LoginSession>(conn)?;
+    for session in &list {
+        revoke_session(&session.id, session.expires_at.timestamp());
+    }
+    Ok(list.len())
+}
+
+/// Expired tokens are refused anyway
+pub fn prune_login_sessions(conn: &PgConnection) -> QueryResult<usize> {
+    use self::login_sessions::dsl::*;
+
+    diesel::delete(login_sessions.filter(expires_at.lt(Utc::now().naive_utc()))).execute(conn)
+}
+
+pub fn prune_revoked_tokens(conn: &PgConnection) -> QueryResult<usize> {
+    use self::revoked_tokens::dsl::*;
+
+    diesel::delete(revoked_tokens.filter(expires_at.lt(Utc::now().naive_utc()))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::is_session_revoked;
+    use crate::schemas::connection::{close_connection, open_connection};
+    use crate::schemas::login_session::*;
+    use crate::schemas::notify::get_receiver;
+    use std::time::Duration as StdDuration;
+
+    #[actix_web::test]
+    async fn session_replaced() {
+        // Café computer, then the phone logs in with `SINGLE`
+        let cafe = open_connection();
+        let phone = open_connection();
+        cafe.init(-51, false);
+        phone.init(-51, false);
+        cafe.set_session_id(Some("session-replaced-cafe".into()));
+        phone.set_session_id(Some("session-replaced-phone".into()));
+        let mut cafe_rx = get_receiver(-51);
+        let mut phone_rx = get_receiver(-51);
+
+        let exp = Utc::now().timestamp() + 60;
+        close_sessions(
+            -51,
+            &[("session-replaced-cafe".into(), exp)],
+            ScSessionCloseReason::SessionReplaced,
+        );
+        assert!(is_session_revoked("session-replaced-cafe"));
+        assert!(!is_session_revoked("session-replaced-phone"));
+
+        let msg = tokio::time::timeout(StdDuration::from_secs(3), cafe_rx.0.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.closes_session(cafe.session_id().as_deref()), Some(true));
+        let msg = tokio::time::timeout(StdDuration::from_secs(3), phone_rx.0.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            msg.closes_session(phone.session_id().as_deref()),
+            Some(false)
+        );
+        // Socket of a token without session
+        assert_eq!(msg.closes_session(None), Some(true));
+
+        close_connection(cafe.id);
+        close_connection(phone.id);
+        cafe_rx.detach();
+        phone_rx.detach();
+    }
+}
@@ -1,12 +1,20 @@
 use chrono::NaiveDateTime;
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use diesel::dsl::*;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use juniper::{FieldResult, GraphQLInputObject, GraphQLObject};
+use diesel::sql_types::{BigInt, Timestamp};
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::env;
 
 use crate::db::models::{Message, NewMessage};
 use crate::db::schema::messages;
+use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
+
+use super::friend::is_friend;
+use super::report::flag_text;
+use super::visibility::visible_messages;
 
 #[derive(GraphQLObject, Debug, Clone)]
 pub struct ScMessage {
@@ -27,6 +35,72 @@ pub struct ScNewMessage {
 #[derive(GraphQLInputObject)]
 pub struct ScMessagesReq {
     pub target_id: i32,
+    // message id, pruned id also works
+    pub before: Option<i32>,
+    pub first: Option<i32>,
+}
+
+lazy_static! {
+    static ref MESSAGE_RETENTION_COUNT: i64 = env::var("MESSAGE_RETENTION_COUNT")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(2000);
+    static ref MESSAGE_RETENTION_DAYS: i64 = env::var("MESSAGE_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(365);
+}
+
+const PRUNE_BATCH_SIZE: i64 = 1000;
+
+// Keep latest N messages or messages within retention days per conversation,
+// only conversations over N messages with an expired one are ranked.
+// Pruned messages move to `message_archives` so the data export still has them,
+// ones their author deleted aren't kept
+const PRUNE_CONVERSATION_SQL: &str = r#"
+WITH pruned AS (
+DELETE FROM messages WHERE "id" IN (
+    SELECT "id" FROM (
+        SELECT "id", created_at, ROW_NUMBER() OVER (
+            PARTITION BY LEAST(user_id, target_id), GREATEST(user_id, target_id)
+            ORDER BY "id" DESC
+        ) AS n
+        FROM messages
+        WHERE (LEAST(user_id, target_id), GREATEST(user_id, target_id)) IN (
+            SELECT LEAST(user_id, target_id), GREATEST(user_id, target_id)
+            FROM messages
+            GROUP BY 1, 2
+            HAVING COUNT(*) > $1 AND MIN(created_at) < $2
+        )
+    ) AS ranked
+    WHERE n > $1 AND created_at < $2
+    LIMIT $3
+)
+RETURNING *
+), archived AS (
+    INSERT INTO message_archives ("id", body, target_id, user_id, created_at, archived_at)
+    SELECT "id", body, target_id, user_id, created_at, $4 FROM pruned
+    WHERE deleted_at IS NULL
+)
+SELECT "id" FROM pruned
+"#;
+
+// User settings `messageAutoDeleteDays`, only messages authored by the user,
+// archived ones included
+fn get_prune_auto_delete_sql(table: &str) -> String {
+    format!(
+        r#"
+DELETE FROM {table} WHERE "id" IN (
+    SELECT {table}."id" FROM {table}
+    INNER JOIN users ON users."id" = {table}.user_id
+    WHERE users.settings->>'messageAutoDeleteDays' ~ '^[1-9][0-9]{{0,4}}$'
+    AND {table}.created_at < $1
+        - (users.settings->>'messageAutoDeleteDays')::integer * INTERVAL '1 day'
+    LIMIT $2
+)
+"#,
+        table = table
+    )
 }
 
 pub fn convert_to_sc_message(message: &Message) -> ScMessage {
@@ -40,17 +114,25 @@ pub fn convert_to_sc_message(message: &Message) -> ScMessage {
     }
 }
 
-pub fn get_messages(conn: &PgConnection, uid: i32, tid: i32) -> Vec<ScMessage> {
+pub fn get_messages(conn: &PgConnection, uid: i32, req: &ScMessagesReq) -> Vec<ScMessage> {
     use self::messages::dsl::*;
 
-    messages
-        .filter(deleted_at.is_null())
+    let tid = req.target_id;
+    let mut query = visible_messages()
         .filter(user_id.eq(any(vec![uid, tid])))
         .filter(target_id.eq(any(vec![uid, tid])))
-        .limit(100)
+        .order(id.desc())
+        .limit(req.first.unwrap_or(100).clamp(1, 100).into());
+
+    if let Some(cursor) = req.before {
+        query = query.filter(id.lt(cursor));
+    }
+
+    query
         .load::<Message>(conn)
         .unwrap()
         .iter()
+        .rev()
         .map(|message| convert_to_sc_message(message))
         .collect()
 }
@@ -58,8 +140,7 @@ pub fn get_messages(conn: &PgConnection, uid: i32, tid: i32) -> Vec<ScMessage> {
 pub fn get_messages_count(conn: &PgConnection, uid: i32, tid: i32, at: NaiveDateTime) -> i32 {
     use self::messages::dsl::*;
 
-    messages
-        .filter(deleted_at.is_null())
+    visible_messages()
         .filter(user_id.eq(tid))
         .filter(target_id.eq(uid))
         .filter(created_at.gt(at))
@@ -74,6 +155,11 @@ pub fn create_message(
     user_id: i32,
     req: &ScNewMessage,
 ) -> FieldResult<ScMessage> {
+    // Direct messages are between friends only
+    if !is_friend(conn, user_id, req.target_id) {
+        return Err(FieldError::new("not friends", Error::permission_denied()));
+    }
+    let filter = check_text(ScTextKind::Message, &req.body)?;
     let new_message = NewMessage {
         user_id,
         target_id: req.target_id,
@@ -87,5 +173,201 @@ pub fn create_message(
         .values(&new_message)
         .get_result::<Message>(conn)?;
 
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            user_id,
+            ScTextKind::Message,
+            &message.body,
+            filter,
+            None,
+            Some(message.id),
+        );
+    }
+
     Ok(convert_to_sc_message(&message))
 }
+
+fn prune_in_batches<F>(name: &str, mut delete_batch: F) -> usize
+where
+    F: FnMut() -> QueryResult<usize>,
+{
+    let mut total = 0;
+    loop {
+        match delete_batch() {
+            Ok(count) => {
+                total += count;
+                if (count as i64) < PRUNE_BATCH_SIZE {
+                    break;
+                }
+            }
+            Err(err) => {
+                log::error!("Prune {} messages: {:?}", name, err);
+                break;
+            }
+        }
+    }
+    total
+}
+
+fn prune_conversations(conn: &PgConnection, keep: i64, deadline: NaiveDateTime) -> usize {
+    let now = Utc::now().naive_utc();
+    prune_in_batches("conversation", || {
+        diesel::sql_query(PRUNE_CONVERSATION_SQL)
+            .bind::<BigInt, _>(keep)
+            .bind::<Timestamp, _>(deadline)
+            .bind::<BigInt, _>(PRUNE_BATCH_SIZE)
+            .bind::<Timestamp, _>(now)
+            .execute(conn)
+    })
+}
+
+/// Delete expired messages in small batches so the table is not locked for long,
+/// return deleted count
+pub fn prune_messages(conn: &PgConnection) -> usize {
+    let now = Utc::now().naive_utc();
+    let deadline = now - Duration::days(*MESSAGE_RETENTION_DAYS);
+
+    let conversation = prune_conversations(conn, *MESSAGE_RETENTION_COUNT, deadline);
+
+    let mut auto_delete = 0;
+    for table in ["messages", "message_archives"] {
+        let sql = get_prune_auto_delete_sql(table);
+        auto_delete += prune_in_batches("auto delete", || {
+            diesel::sql_query(&sql)
+                .bind::<Timestamp, _>(now)
+                .bind::<BigInt, _>(PRUNE_BATCH_SIZE)
+                .execute(conn)
+        });
+    }
+
+    conversation + auto_delete
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::{insert_user, test_conn};
+    use crate::db::schema::message_archives;
+    use crate::schemas::message::*;
+    use crate::transfer::load_messages;
+
+    fn insert_message(conn: &PgConnection, uid: i32, tid: i32, days_ago: i64) -> i32 {
+        let at = Utc::now().naive_utc() - Duration::days(days_ago);
+        diesel::insert_into(messages::table)
+            .values((
+                messages::body.eq(""),
+                messages::user_id.eq(uid),
+                messages::target_id.eq(tid),
+                messages::created_at.eq(at),
+                messages::updated_at.eq(at),
+            ))
+            .returning(messages::id)
+            .get_result(conn)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn prune_conversation_history() {
+        let conn = test_conn();
+        let a = insert_user(&conn, "prune_a");
+        let b = insert_user(&conn, "prune_b");
+        let c = insert_user(&conn, "prune_c");
+        // Over the cap with expired messages, both directions are one conversation
+        let old: Vec<i32> = (0..4)
+            .map(|i| match i % 2 {
+                0 => insert_message(&conn, a, b, 400),
+                _ => insert_message(&conn, b, a, 400),
+            })
+            .collect();
+        let new = insert_message(&conn, a, b, 1);
+        // Expired but within the cap
+        let small: Vec<i32> = (0..2).map(|_| insert_message(&conn, a, c, 400)).collect();
+        // Over the cap but recent
+        let recent: Vec<i32> = (0..3).map(|_| insert_message(&conn, b, c, 1)).collect();
+
+        let deadline = Utc::now().naive_utc() - Duration::days(365);
+        assert_eq!(prune_conversations(&conn, 2, deadline), 3);
+        let mut archived: Vec<i32> = message_archives::table
+            .select(message_archives::id)
+            .filter(message_archives::user_id.eq(any(vec![a, b, c])))
+            .load(&conn)
+            .unwrap();
+        archived.sort_unstable();
+        assert_eq!(archived, old[..3].to_vec());
+        // Still exported
+        let exported = load_messages(&conn, a).unwrap();
+        assert_eq!(exported.len(), 7);
+        assert_eq!(
+            exported.iter().filter(|message| message.archived).count(),
+            3
+        );
+
+        let mut kept: Vec<i32> = messages::table
+            .select(messages::id)
+            .filter(messages::user_id.eq(any(vec![a, b, c])))
+            .load(&conn)
+            .unwrap();
+        kept.sort_unstable();
+        let mut expected = vec![old[3], new];
+        expected.extend(small);
+        expected.extend(recent);
+        expected.sort_unstable();
+        assert_eq!(kept, expected);
+
+        // Nothing left to prune
+        assert_eq!(prune_conversations(&conn, 2, deadline), 0);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn friends_only() {
+        use crate::db::schema::friends;
+        use crate::schemas::friend::ScFriendStatus;
+
+        let conn = test_conn();
+        let a = insert_user(&conn, "message_a");
+        let friend = insert_user(&conn, "message_friend");
+        let pending = insert_user(&conn, "message_pending");
+        let stranger = insert_user(&conn, "message_stranger");
+        for (tid, state) in [
+            (friend, ScFriendStatus::Accept),
+            (pending, ScFriendStatus::Pending),
+        ] {
+            let now = Utc::now().naive_utc();
+            diesel::insert_into(friends::table)
+                .values((
+                    friends::user_id.eq(a),
+                    friends::target_id.eq(tid),
+                    friends::created_at.eq(now),
+                    friends::status.eq(state.to_string()),
+                    friends::last_read_at.eq(now),
+                ))
+                .execute(&conn)
+                .unwrap();
+        }
+
+        let send = |tid| {
+            create_message(
+                &conn,
+                a,
+                &ScNewMessage {
+                    body: "gg".into(),
+                    target_id: tid,
+                },
+            )
+        };
+        for tid in [stranger, pending] {
+            let err = send(tid).unwrap_err();
+            assert_eq!(err.extensions().to_owned(), Error::permission_denied());
+        }
+        let sent = messages::table
+            .filter(messages::user_id.eq(a))
+            .count()
+            .get_result::<i64>(&conn)
+            .unwrap();
+        assert_eq!(sent, 0);
+
+        assert!(send(friend).is_ok());
+    }
+}
@@ -1,13 +1,49 @@
+pub mod achievement;
+pub mod anonymous;
+pub mod audit;
+pub mod authority;
+pub mod client_info;
+pub mod coalesce;
+pub mod collection;
 pub mod comment;
+pub mod compatibility;
+pub mod connection;
 pub mod favorite;
+pub mod feature_flag;
 pub mod friend;
 pub mod game;
+pub mod game_attachment;
+pub mod game_change;
+pub mod game_kind;
+pub mod game_version;
+pub mod github_repo;
 pub mod invite;
+pub mod leaderboard;
 pub mod lobby;
+pub mod login_session;
 pub mod message;
+pub mod notification;
 pub mod notify;
+pub mod oauth_account;
+pub mod persisted_query;
 pub mod playing;
+pub mod presence;
+pub mod recommendation;
 pub mod record;
+pub mod relay;
+pub mod report;
+pub mod restriction;
+pub mod retention;
 pub mod room;
+pub mod room_event;
+pub mod room_join;
+pub mod room_link;
 pub mod root;
+pub mod save_state;
+pub mod score;
+pub mod spectator;
+pub mod tenant;
+pub mod trial;
 pub mod user;
+pub mod visibility;
+pub mod webhook;
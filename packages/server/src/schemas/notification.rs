@@ -0,0 +1,312 @@
+use chrono::Utc;
+use diesel::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::string::ToString;
+use strum::{Display, EnumString};
+
+use crate::db::models::{NewNotification, Notification};
+use crate::db::schema::{friends, invites, notifications};
+
+#[derive(GraphQLEnum, Debug, Clone, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScNotificationKind {
+    FriendRequest,
+    Invite,
+    Announcement,
+    Achievement,
+    ScoreInvalidated,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScNotification {
+    id: i32,
+    kind: ScNotificationKind,
+    // JSON
+    payload: String,
+    user_id: Option<i32>,
+    room_id: Option<i32>,
+    invite_id: Option<i32>,
+    // underlying invite/friend request is gone, payload is empty
+    tombstone: bool,
+    read: bool,
+    created_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNotificationsReq {
+    pub kind: Option<ScNotificationKind>,
+    pub unread_only: Option<bool>,
+    pub first: Option<i32>,
+    // notification id, stable when new notifications arrive
+    pub after: Option<i32>,
+    pub offset: Option<i32>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScMarkNotificationsReadReq {
+    pub up_to_id: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScMarkNotificationReadReq {
+    pub id: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScUnreadNotificationReq {
+    pub kind: Option<ScNotificationKind>,
+}
+
+fn get_payload_id(payload: &Value, key: &str) -> Option<i32> {
+    payload
+        .get(key)
+        .and_then(|value| value.as_i64())
+        .map(|value| value as i32)
+}
+
+fn convert_to_sc_notification(notification: &Notification) -> ScNotification {
+    let payload = &notification.payload;
+    ScNotification {
+        id: notification.id,
+        kind: ScNotificationKind::from_str(&notification.kind).unwrap(),
+        payload: payload.to_string(),
+        user_id: get_payload_id(payload, "userId"),
+        room_id: get_payload_id(payload, "roomId"),
+        invite_id: get_payload_id(payload, "inviteId"),
+        tombstone: false,
+        read: notification.read_at.is_some(),
+        created_at: notification.created_at.timestamp_millis() as f64,
+    }
+}
+
+/// Mark notifications whose invite/friend request was deleted, two queries per page
+fn fill_tombstones(
+    conn: &PgConnection,
+    uid: i32,
+    list: Vec<ScNotification>,
+) -> Vec<ScNotification> {
+    let invite_ids: Vec<i32> = list
+        .iter()
+        .filter(|n| n.kind == ScNotificationKind::Invite)
+        .filter_map(|n| n.invite_id)
+        .collect();
+    let live_invites: HashSet<i32> = invites::table
+        .select(invites::id)
+        .filter(invites::deleted_at.is_null())
+        .filter(invites::id.eq(any(invite_ids)))
+        .load::<i32>(conn)
+        .unwrap()
+        .into_iter()
+        .collect();
+
+    let applicant_ids: Vec<i32> = list
+        .iter()
+        .filter(|n| n.kind == ScNotificationKind::FriendRequest)
+        .filter_map(|n| n.user_id)
+        .collect();
+    let live_applicants: HashSet<i32> = friends::table
+        .select(friends::target_id)
+        .filter(friends::user_id.eq(uid))
+        .filter(friends::target_id.eq(any(applicant_ids)))
+        .load::<i32>(conn)
+        .unwrap()
+        .into_iter()
+        .collect();
+
+    list.into_iter()
+        .map(|mut n| {
+            n.tombstone = match n.kind {
+                ScNotificationKind::Invite => {
+                    !n.invite_id.map_or(false, |iid| live_invites.contains(&iid))
+                }
+                ScNotificationKind::FriendRequest => !n
+                    .user_id
+                    .map_or(false, |tid| live_applicants.contains(&tid)),
+                _ => false,
+            };
+            if n.tombstone {
+                n.payload = "{}".into();
+            }
+            n
+        })
+        .collect()
+}
+
+pub fn get_notifications(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScNotificationsReq,
+) -> Vec<ScNotification> {
+    use self::notifications::dsl::*;
+
+    let mut query = notifications
+        .filter(user_id.eq(uid))
+        .order(id.desc())
+        .limit(req.first.unwrap_or(20).clamp(1, 100).into())
+        .offset(req.offset.unwrap_or(0).max(0).into())
+        .into_boxed();
+
+    if let Some(k) = &req.kind {
+        query = query.filter(kind.eq(k.to_string()));
+    }
+
+    if req.unread_only.unwrap_or_default() {
+        query = query.filter(read_at.is_null());
+    }
+
+    if let Some(cursor) = req.after {
+        query = query.filter(id.lt(cursor));
+    }
+
+    let list = query
+        .load::<Notification>(conn)
+        .unwrap()
+        .iter()
+        .map(|notification| convert_to_sc_notification(notification))
+        .collect();
+
+    fill_tombstones(conn, uid, list)
+}
+
+pub fn get_unread_notification_count(
+    conn: &PgConnection,
+    uid: i32,
+    k: Option<ScNotificationKind>,
+) -> i32 {
+    use self::notifications::dsl::*;
+
+    let mut query = notifications
+        .filter(user_id.eq(uid))
+        .filter(read_at.is_null())
+        .into_boxed();
+
+    if let Some(k) = k {
+        query = query.filter(kind.eq(k.to_string()));
+    }
+
+    query
+        .count()
+        .get_result::<i64>(conn)
+        .map(|x| x as i32)
+        .unwrap()
+}
+
+pub fn create_notification(
+    conn: &PgConnection,
+    uid: i32,
+    k: ScNotificationKind,
+    data: &Value,
+) -> FieldResult<ScNotification> {
+    let new_notification = NewNotification {
+        user_id: uid,
+        kind: &k.to_string(),
+        payload: data,
+        read_at: None,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let notification = diesel::insert_into(notifications::table)
+        .values(&new_notification)
+        .get_result::<Notification>(conn)?;
+
+    Ok(convert_to_sc_notification(&notification))
+}
+
+pub fn mark_notifications_read(conn: &PgConnection, uid: i32, up_to_id: i32) -> FieldResult<()> {
+    use self::notifications::dsl::*;
+
+    diesel::update(
+        notifications
+            .filter(user_id.eq(uid))
+            .filter(id.le(up_to_id))
+            .filter(read_at.is_null()),
+    )
+    .set(read_at.eq(Some(Utc::now().naive_utc())))
+    .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn mark_notification_read(conn: &PgConnection, uid: i32, nid: i32) -> FieldResult<()> {
+    use self::notifications::dsl::*;
+
+    diesel::update(
+        notifications
+            .filter(user_id.eq(uid))
+            .filter(id.eq(nid))
+            .filter(read_at.is_null()),
+    )
+    .set(read_at.eq(Some(Utc::now().naive_utc())))
+    .execute(conn)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::*;
+    use crate::schemas::notification::*;
+    use serde_json::json;
+
+    fn page(conn: &PgConnection, uid: i32, after: Option<i32>) -> Vec<ScNotification> {
+        get_notifications(
+            conn,
+            uid,
+            &ScNotificationsReq {
+                kind: None,
+                unread_only: None,
+                first: Some(2),
+                after,
+                offset: None,
+            },
+        )
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn cursor_across_deleted_rows() {
+        let conn = test_conn();
+        let uid = insert_user(&conn, "notification-pager");
+        let applicant = insert_user(&conn, "notification-applicant");
+        let gone = insert_user(&conn, "notification-gone");
+        diesel::sql_query(
+            r#"INSERT INTO friends (user_id, target_id, created_at, status, last_read_at)
+            VALUES ($1, $2, NOW(), 'pending', NOW())"#,
+        )
+        .bind::<diesel::sql_types::Integer, _>(uid)
+        .bind::<diesel::sql_types::Integer, _>(applicant)
+        .execute(&conn)
+        .unwrap();
+
+        let create = |k, data| create_notification(&conn, uid, k, &data).unwrap().id;
+        let first = create(ScNotificationKind::Announcement, json!({}));
+        let live = create(
+            ScNotificationKind::FriendRequest,
+            json!({ "userId": applicant }),
+        );
+        let deleted = create(ScNotificationKind::Announcement, json!({}));
+        let withdrawn = create(ScNotificationKind::FriendRequest, json!({ "userId": gone }));
+        let expired = create(ScNotificationKind::Invite, json!({ "inviteId": 0 }));
+
+        let ids = |list: &[ScNotification]| list.iter().map(|n| n.id).collect::<Vec<_>>();
+        let one = page(&conn, uid, None);
+        assert_eq!(ids(&one), vec![expired, withdrawn]);
+        assert!(one.iter().all(|n| n.tombstone && n.payload == "{}"));
+
+        // Rows removed and added between pages neither shift nor repeat the next one
+        diesel::delete(notifications::table.find(deleted))
+            .execute(&conn)
+            .unwrap();
+        create(ScNotificationKind::Announcement, json!({}));
+        let two = page(&conn, uid, one.last().map(|n| n.id));
+        assert_eq!(ids(&two), vec![live, first]);
+        assert!(!two[0].tombstone);
+        assert_eq!(two[0].user_id, Some(applicant));
+        assert!(page(&conn, uid, Some(first)).is_empty());
+    }
+}
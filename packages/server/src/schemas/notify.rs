@@ -1,14 +1,29 @@
-use crate::{db::root::DB_POOL, schemas::lobby::leave_lobby};
+use crate::{
+    auth::is_session_revoked, config::SUBSCRIPTION_CONFIG, db::root::DB_POOL, metrics,
+    schemas::lobby::leave_lobby,
+};
 use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
 
 use super::{
-    friend::get_friend_ids, friend::ScFriend, game::ScGame, invite::ScInvite,
-    lobby::ScLobbyMessage, message::ScMessage, record::pause_game, room::ScRoomBasic,
-    user::get_user_basic, user::ScUserBasic,
+    achievement::ScUserAchievement, authority::ScAuthorityChanged, client_info::ScClientInfo,
+    coalesce::Batch, coalesce::Coalescer, friend::get_friend_ids, friend::ScFriend, game::ScGame,
+    invite::ScInvite, lobby::ScLobbyMessage, login_session::ScSessionCloseReason,
+    message::ScMessage, notification::create_notification, notification::ScNotificationKind,
+    playing::mark_disconnected, playing::mark_reconnected, playing::take_expired_disconnected,
+    playing::REJOIN_GRACE, presence::is_dnd, presence::remove_presence, presence::reset_presence,
+    record::pause_game, room::ScRoomBasic, room::ScRoomCommand, room_event::ScRoomMemberChange,
+    room_join::ScRoomJoinAlert, score::ScScoreInvalidated, spectator::remove_spectator,
+    spectator::ScSpectatorCount, user::get_user_basic, user::ScUserBasic,
 };
-use juniper::{GraphQLInputObject, GraphQLObject};
-use std::collections::HashMap;
-use std::sync::RwLock;
+use juniper::{FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use strum::{Display, EnumIter, IntoEnumIterator, IntoStaticStr};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::broadcast::{self, Receiver, Sender};
 
 #[derive(GraphQLObject, Debug, Clone, Default, Builder)]
@@ -30,6 +45,523 @@ pub struct ScNotifyMessage {
     send_signal: Option<ScSignal>,
     login: Option<bool>,
     voice_signal: Option<ScVoiceSignal>,
+    kicked_room: Option<i32>,
+    deprecate_game: Option<ScGame>,
+    announcement: Option<String>,
+    // unread notification count
+    unread_changed: Option<i32>,
+    // bursts of game changes, single creation is still `new_game`
+    games_changed: Option<ScGamesChanged>,
+    // only sent to the room host
+    room_command: Option<ScRoomCommand>,
+    // only sent to the room host
+    room_join_failures: Option<ScRoomJoinAlert>,
+    // to members and spectators, at most once per window
+    spectator_count: Option<ScSpectatorCount>,
+    // room id, spectating was closed by the host
+    spectate_closed: Option<i32>,
+    // removed from the leaderboard by a moderator
+    score_invalidated: Option<ScScoreInvalidated>,
+    // first event of every subscription
+    reconnect_hint: Option<ScReconnectHint>,
+    // compact connections get this instead of update-type payloads
+    changed: Option<ScNotifyChanged>,
+    // set by the room host, clients re-negotiate who runs the core
+    authority_changed: Option<ScAuthorityChanged>,
+    // the socket's login session was revoked, the client goes back to login
+    session_closed: Option<ScSessionCloseReason>,
+    // in `seq` order per room, a gap means `roomSnapshot` is due
+    room_member: Option<ScRoomMemberChange>,
+    // instead of a kind the connection's `clientInfo` doesn't list
+    refresh_hint: Option<ScRefreshHint>,
+    // to the user's friends and other sessions, clients show a toast
+    achievement_unlocked: Option<ScUserAchievement>,
+    // delivered during do-not-disturb, shown without a popup or sound
+    silent: Option<bool>,
+}
+
+/// One per `ScNotifyMessage` field, the snake case name is the metrics label
+#[derive(
+    GraphQLEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Display, EnumIter, IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyKind {
+    NewMessage,
+    LobbyMessage,
+    NewGame,
+    UpdateRoom,
+    DeleteRoom,
+    NewInvite,
+    DeleteInvite,
+    ApplyFriend,
+    AcceptFriend,
+    DeleteFriend,
+    Favorite,
+    DeleteFavorite,
+    UpdateUser,
+    SendSignal,
+    Login,
+    VoiceSignal,
+    KickedRoom,
+    DeprecateGame,
+    Announcement,
+    UnreadChanged,
+    GamesChanged,
+    RoomCommand,
+    RoomJoinFailures,
+    SpectatorCount,
+    SpectateClosed,
+    ScoreInvalidated,
+    ReconnectHint,
+    Changed,
+    AuthorityChanged,
+    SessionClosed,
+    RoomMember,
+    RefreshHint,
+    AchievementUnlocked,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyAudience {
+    // every online user
+    Global,
+    // online users of one tenant
+    Tenant,
+    // members of a room or the lobby
+    Room,
+    // one user, or users related to someone, e.g. friends
+    User,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyCategory {
+    Social,
+    Invites,
+    Rooms,
+    Lobby,
+    Games,
+    Presence,
+    // realtime plumbing, never user facing
+    Signaling,
+    System,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyCoalesce {
+    Immediate,
+    // merged per window by `notify_game_change`
+    GameBurst,
+    // merged per window by `notify_spectator_count`
+    SpectatorWindow,
+}
+
+impl NotifyCoalesce {
+    /// How often merged changes of the class are flushed
+    pub fn window(self) -> Duration {
+        match self {
+            NotifyCoalesce::Immediate => Duration::ZERO,
+            NotifyCoalesce::GameBurst => *COALESCE_WINDOW,
+            // a live count changes on every join, members see it at most this often
+            NotifyCoalesce::SpectatorWindow => Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScNotifyRoute {
+    pub kind: NotifyKind,
+    pub audience: NotifyAudience,
+    // also stored in notifications, counts as unread
+    pub persist: Option<ScNotificationKind>,
+    // worth an interruption: silenced in DND, eligible for Web Push
+    pub push: bool,
+    // preference the event falls under
+    pub category: NotifyCategory,
+    pub coalesce: NotifyCoalesce,
+    // sent as `changed` to compact connections, the client refetches lazily
+    pub compact: bool,
+    // delivered whatever interests the connection declared
+    pub critical: bool,
+    // newer than the oldest supported client, clients that declare capabilities
+    // without this kind get `refreshHint` instead
+    pub gated: bool,
+}
+
+impl NotifyKind {
+    /// The routing table, a kind without a route fails to compile
+    pub fn route(self) -> ScNotifyRoute {
+        use NotifyAudience::*;
+        use NotifyCategory::*;
+        use NotifyCoalesce::*;
+
+        let (audience, persist, push, category, coalesce) = match self {
+            NotifyKind::NewMessage => (User, None, true, Social, Immediate),
+            NotifyKind::LobbyMessage => (Room, None, false, Lobby, Immediate),
+            NotifyKind::NewGame => (Global, None, false, Games, GameBurst),
+            NotifyKind::UpdateRoom => (Room, None, false, Rooms, Immediate),
+            NotifyKind::DeleteRoom => (Tenant, None, false, Rooms, Immediate),
+            NotifyKind::NewInvite => (
+                User,
+                Some(ScNotificationKind::Invite),
+                true,
+                Invites,
+                Immediate,
+            ),
+            NotifyKind::DeleteInvite => (User, None, false, Invites, Immediate),
+            NotifyKind::ApplyFriend => (
+                User,
+                Some(ScNotificationKind::FriendRequest),
+                true,
+                Social,
+                Immediate,
+            ),
+            NotifyKind::AcceptFriend => (User, None, true, Social, Immediate),
+            NotifyKind::DeleteFriend => (User, None, false, Social, Immediate),
+            NotifyKind::Favorite => (User, None, false, Games, Immediate),
+            NotifyKind::DeleteFavorite => (User, None, false, Games, Immediate),
+            NotifyKind::UpdateUser => (User, None, false, Presence, Immediate),
+            NotifyKind::SendSignal => (User, None, false, Signaling, Immediate),
+            NotifyKind::Login => (User, None, false, System, Immediate),
+            NotifyKind::VoiceSignal => (Room, None, false, Signaling, Immediate),
+            NotifyKind::KickedRoom => (User, None, false, Rooms, Immediate),
+            NotifyKind::DeprecateGame => (User, None, true, Games, Immediate),
+            NotifyKind::Announcement => (Global, None, false, System, Immediate),
+            NotifyKind::UnreadChanged => (User, None, false, System, Immediate),
+            NotifyKind::GamesChanged => (Global, None, false, Games, GameBurst),
+            NotifyKind::RoomCommand => (User, None, false, Rooms, Immediate),
+            NotifyKind::RoomJoinFailures => (User, None, true, Rooms, Immediate),
+            NotifyKind::SpectatorCount => (Room, None, false, Rooms, SpectatorWindow),
+            NotifyKind::SpectateClosed => (User, None, false, Rooms, Immediate),
+            NotifyKind::ScoreInvalidated => (
+                User,
+                Some(ScNotificationKind::ScoreInvalidated),
+                true,
+                Games,
+                Immediate,
+            ),
+            NotifyKind::ReconnectHint => (User, None, false, System, Immediate),
+            NotifyKind::Changed => (User, None, false, System, Immediate),
+            NotifyKind::AuthorityChanged => (Room, None, false, Rooms, Immediate),
+            NotifyKind::SessionClosed => (User, None, false, System, Immediate),
+            NotifyKind::RoomMember => (Room, None, false, Rooms, Immediate),
+            NotifyKind::RefreshHint => (User, None, false, System, Immediate),
+            NotifyKind::AchievementUnlocked => (User, None, true, Social, Immediate),
+        };
+        let compact = matches!(
+            self,
+            NotifyKind::NewGame | NotifyKind::UpdateRoom | NotifyKind::UpdateUser
+        );
+        let critical = matches!(
+            self,
+            NotifyKind::NewInvite
+                | NotifyKind::KickedRoom
+                | NotifyKind::Announcement
+                | NotifyKind::SessionClosed
+        );
+        let gated = matches!(
+            self,
+            NotifyKind::GamesChanged
+                | NotifyKind::RoomCommand
+                | NotifyKind::RoomJoinFailures
+                | NotifyKind::SpectatorCount
+                | NotifyKind::SpectateClosed
+                | NotifyKind::ScoreInvalidated
+                | NotifyKind::ReconnectHint
+                | NotifyKind::Changed
+                | NotifyKind::AuthorityChanged
+                | NotifyKind::SessionClosed
+                | NotifyKind::RoomMember
+                | NotifyKind::AchievementUnlocked
+        );
+        ScNotifyRoute {
+            kind: self,
+            audience,
+            persist,
+            push,
+            category,
+            coalesce,
+            compact,
+            critical,
+            gated,
+        }
+    }
+}
+
+pub fn get_notify_routes() -> Vec<ScNotifyRoute> {
+    NotifyKind::iter().map(NotifyKind::route).collect()
+}
+
+impl ScNotifyMessage {
+    /// Every field must be listed here, a new message type without a route fails to compile
+    pub fn notify_kind(&self) -> Option<NotifyKind> {
+        let ScNotifyMessage {
+            new_message,
+            lobby_message,
+            new_game,
+            update_room,
+            delete_room,
+            new_invite,
+            delete_invite,
+            apply_friend,
+            accept_friend,
+            delete_friend,
+            favorite,
+            delete_favorite,
+            update_user,
+            send_signal,
+            login,
+            voice_signal,
+            kicked_room,
+            deprecate_game,
+            announcement,
+            unread_changed,
+            games_changed,
+            room_command,
+            room_join_failures,
+            spectator_count,
+            spectate_closed,
+            score_invalidated,
+            reconnect_hint,
+            changed,
+            authority_changed,
+            session_closed,
+            room_member,
+            refresh_hint,
+            achievement_unlocked,
+            silent: _,
+        } = self;
+
+        [
+            (new_message.is_some(), NotifyKind::NewMessage),
+            (lobby_message.is_some(), NotifyKind::LobbyMessage),
+            (new_game.is_some(), NotifyKind::NewGame),
+            (update_room.is_some(), NotifyKind::UpdateRoom),
+            (delete_room.is_some(), NotifyKind::DeleteRoom),
+            (new_invite.is_some(), NotifyKind::NewInvite),
+            (delete_invite.is_some(), NotifyKind::DeleteInvite),
+            (apply_friend.is_some(), NotifyKind::ApplyFriend),
+            (accept_friend.is_some(), NotifyKind::AcceptFriend),
+            (delete_friend.is_some(), NotifyKind::DeleteFriend),
+            (favorite.is_some(), NotifyKind::Favorite),
+            (delete_favorite.is_some(), NotifyKind::DeleteFavorite),
+            (update_user.is_some(), NotifyKind::UpdateUser),
+            (send_signal.is_some(), NotifyKind::SendSignal),
+            (login.is_some(), NotifyKind::Login),
+            (voice_signal.is_some(), NotifyKind::VoiceSignal),
+            (kicked_room.is_some(), NotifyKind::KickedRoom),
+            (deprecate_game.is_some(), NotifyKind::DeprecateGame),
+            (announcement.is_some(), NotifyKind::Announcement),
+            (unread_changed.is_some(), NotifyKind::UnreadChanged),
+            (games_changed.is_some(), NotifyKind::GamesChanged),
+            (room_command.is_some(), NotifyKind::RoomCommand),
+            (room_join_failures.is_some(), NotifyKind::RoomJoinFailures),
+            (spectator_count.is_some(), NotifyKind::SpectatorCount),
+            (spectate_closed.is_some(), NotifyKind::SpectateClosed),
+            (score_invalidated.is_some(), NotifyKind::ScoreInvalidated),
+            (reconnect_hint.is_some(), NotifyKind::ReconnectHint),
+            (changed.is_some(), NotifyKind::Changed),
+            (authority_changed.is_some(), NotifyKind::AuthorityChanged),
+            (session_closed.is_some(), NotifyKind::SessionClosed),
+            (room_member.is_some(), NotifyKind::RoomMember),
+            (refresh_hint.is_some(), NotifyKind::RefreshHint),
+            (
+                achievement_unlocked.is_some(),
+                NotifyKind::AchievementUnlocked,
+            ),
+        ]
+        .iter()
+        .find(|(some, _)| *some)
+        .map(|(_, kind)| *kind)
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.notify_kind()
+            .map(|kind| kind.into())
+            .unwrap_or("empty")
+    }
+
+    pub fn route(&self) -> Option<ScNotifyRoute> {
+        self.notify_kind().map(NotifyKind::route)
+    }
+
+    /// No push in DND, the client still sees them in unread counts
+    pub fn is_silenceable(&self) -> bool {
+        self.route().map(|route| route.push).unwrap_or_default()
+    }
+
+    /// Silenceable kinds still delivered in DND, marked `silent` instead of dropped
+    pub fn is_quiet_in_dnd(&self) -> bool {
+        matches!(
+            self.notify_kind(),
+            Some(NotifyKind::NewMessage | NotifyKind::NewInvite)
+        )
+    }
+
+    pub fn room_member_change(&self) -> Option<&ScRoomMemberChange> {
+        self.room_member.as_ref()
+    }
+
+    /// The event as `client` can parse it, `None` is a client that declared nothing
+    pub fn downgrade(self, client: Option<&ScClientInfo>) -> ScNotifyMessage {
+        let route = match (self.route(), client) {
+            (Some(route), Some(client)) if route.gated && !client.supports(self.kind()) => route,
+            _ => return self,
+        };
+        metrics::inc_counter("nesbox_notify_downgraded_total", self.kind());
+        ScNotifyMessageBuilder::default()
+            .refresh_hint(ScRefreshHint {
+                kind: self.kind().to_owned(),
+                category: route.category,
+            })
+            .build()
+            .unwrap()
+    }
+
+    /// `Some(true)` when a socket of the session must end after this event,
+    /// the other sockets of the user skip it
+    pub fn closes_session(&self, sid: Option<&str>) -> Option<bool> {
+        self.session_closed
+            .map(|_| sid.map_or(true, is_session_revoked))
+    }
+
+    /// Like `closes_session`, kicked users also lose every socket and
+    /// subscribe again outside the room
+    pub fn ends_subscription(&self, sid: Option<&str>) -> Option<bool> {
+        if self.kicked_room.is_some() {
+            return Some(true);
+        }
+        self.closes_session(sid)
+    }
+}
+
+/// How to come back after the proxy drops the socket
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScReconnectHint {
+    // the server sends `ka` this often, silence for twice as long is a dead socket
+    pub keep_alive_ms: i32,
+    // wait before the first reconnect, double on each failure
+    pub backoff_ms: i32,
+    // the room seat is kept this long, reconnect in time to resume the game
+    pub resume_within_seconds: i32,
+}
+
+pub fn get_reconnect_hint() -> ScReconnectHint {
+    ScReconnectHint {
+        keep_alive_ms: SUBSCRIPTION_CONFIG.keep_alive.as_millis() as i32,
+        backoff_ms: SUBSCRIPTION_CONFIG.reconnect_backoff.as_millis() as i32,
+        resume_within_seconds: REJOIN_GRACE.num_seconds() as i32,
+    }
+}
+
+/// Something the client can't parse happened, it refetches what the category covers
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScRefreshHint {
+    // snake case name of the withheld kind, a string so old parsers never see a new enum value
+    pub kind: String,
+    pub category: NotifyCategory,
+}
+
+/// Update-type event reduced to what changed, for connections on metered links
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScNotifyChanged {
+    pub kind: NotifyKind,
+    pub id: i32,
+    // top level fields that differ from what this connection saw last, all when unseen
+    pub fields: Vec<String>,
+}
+
+// Objects remembered per compact connection
+const COMPACT_CACHE_SIZE: usize = 256;
+
+/// Renders the compact shape of an event, owned by one compact connection so
+/// full connections never pay for it
+#[derive(Default)]
+pub struct CompactRenderer {
+    seen: HashMap<(NotifyKind, i32), Value>,
+}
+
+/// Top level fields of `current` that differ from `previous`
+pub fn get_changed_fields(previous: Option<&Value>, current: &Value) -> Vec<String> {
+    current
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter(|(name, value)| previous.and_then(|prev| prev.get(name)) != Some(value))
+                .map(|(name, _)| name.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl CompactRenderer {
+    /// `None` when nothing changed since the last event about the object
+    pub fn render(&mut self, msg: ScNotifyMessage) -> Option<ScNotifyMessage> {
+        let kind = match msg.route() {
+            Some(route) if route.compact => route.kind,
+            _ => return Some(msg),
+        };
+        let (id, value) = match (&msg.new_game, &msg.update_room, &msg.update_user) {
+            (Some(game), _, _) => (game.id, serde_json::to_value(game)),
+            (_, Some(room), _) => (room.id, serde_json::to_value(room)),
+            (_, _, Some(user)) => (user.id, serde_json::to_value(user)),
+            _ => return Some(msg),
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+
+        let key = (kind, id);
+        let previous = self.seen.get(&key);
+        let fields = get_changed_fields(previous, &value);
+        if previous.is_some() && fields.is_empty() {
+            metrics::inc_counter("nesbox_notify_compact_skipped_total", msg.kind());
+            return None;
+        }
+        if self.seen.len() >= COMPACT_CACHE_SIZE && previous.is_none() {
+            self.seen.clear();
+        }
+        self.seen.insert(key, value);
+        Some(
+            ScNotifyMessageBuilder::default()
+                .changed(ScNotifyChanged { kind, id, fields })
+                .build()
+                .unwrap(),
+        )
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone, Default, PartialEq)]
+pub struct ScGamesChanged {
+    pub created_ids: Vec<i32>,
+    pub updated_ids: Vec<i32>,
+    pub deleted_ids: Vec<i32>,
+}
+
+impl Batch for ScGamesChanged {
+    type Change = GameChange;
+
+    fn push(&mut self, change: &GameChange) {
+        match change {
+            GameChange::Created(game) => self.created_ids.push(game.id),
+            GameChange::Updated(id) => self.updated_ids.push(*id),
+            GameChange::Deleted(id) => self.deleted_ids.push(*id),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.created_ids.is_empty() && self.updated_ids.is_empty() && self.deleted_ids.is_empty()
+    }
+}
+
+pub enum GameChange {
+    Created(Box<ScGame>),
+    Updated(i32),
+    Deleted(i32),
 }
 
 #[derive(GraphQLObject, Debug, Clone)]
@@ -49,37 +581,233 @@ pub struct ScNewSignal {
     pub json: String,
 }
 
+// Sends remembered per user to tell which kinds a lagging receiver lost
+const SENT_LOG_LEN: usize = 64;
+
+#[derive(Default)]
+struct SentLog {
+    // position of the next send, the same as the channel's
+    next: u64,
+    kinds: VecDeque<&'static str>,
+}
+
+impl SentLog {
+    fn push(&mut self, kind: &'static str) {
+        if self.kinds.len() == SENT_LOG_LEN {
+            self.kinds.pop_front();
+        }
+        self.kinds.push_back(kind);
+        self.next += 1;
+    }
+
+    /// Kinds sent at `from..from + count`, `unknown` once out of the log
+    fn kinds(&self, from: u64, count: u64) -> Vec<&'static str> {
+        let first = self.next - self.kinds.len() as u64;
+        (from..from + count)
+            .map(|position| {
+                position
+                    .checked_sub(first)
+                    .and_then(|index| self.kinds.get(index as usize))
+                    .copied()
+                    .unwrap_or("unknown")
+            })
+            .collect()
+    }
+}
+
+struct NotifyChannel {
+    sender: Sender<ScNotifyMessage>,
+    online_at: DateTime<Utc>,
+    // locked around every send so positions match the channel
+    sent: Mutex<SentLog>,
+}
+
 lazy_static! {
-    static ref NOTIFY_MAP: RwLock<HashMap<i32, (Sender<ScNotifyMessage>, DateTime<Utc>)>> = {
+    static ref NOTIFY_MAP: RwLock<HashMap<i32, NotifyChannel>> = {
+        let m = HashMap::new();
+        RwLock::new(m)
+    };
+    // Online user -> tenant, missing is the default tenant
+    static ref TENANT_MAP: RwLock<HashMap<i32, i32>> = {
         let m = HashMap::new();
         RwLock::new(m)
     };
+    static ref COALESCE_WINDOW: Duration = Duration::from_millis(
+        env::var("NOTIFY_COALESCE_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(500)
+    );
+    static ref GAME_CHANGES: Coalescer<ScGamesChanged> =
+        Coalescer::new(NotifyCoalesce::GameBurst, flush_game_changes);
+}
+
+fn send_to_user(user_id: i32, channel: &NotifyChannel, mut msg: ScNotifyMessage) {
+    if msg.is_silenceable() && is_dnd(user_id) {
+        if !msg.is_quiet_in_dnd() {
+            metrics::inc_counter("nesbox_notify_suppressed_total", msg.kind());
+            return;
+        }
+        metrics::inc_counter("nesbox_notify_silenced_total", msg.kind());
+        msg.silent = Some(true);
+    }
+    send(channel, msg);
+}
+
+/// Overflow is counted as `dropped` by the lagging receiver
+fn send(channel: &NotifyChannel, msg: ScNotifyMessage) -> bool {
+    let kind = msg.kind();
+    let mut sent = channel.sent.lock().unwrap();
+    if channel.sender.send(msg).is_ok() {
+        sent.push(kind);
+        metrics::inc_counter("nesbox_notify_delivered_total", kind);
+        true
+    } else {
+        // every receiver is gone
+        metrics::inc_counter("nesbox_notify_unreceived_total", kind);
+        false
+    }
 }
 
 pub fn notify(user_id: i32, msg: ScNotifyMessage) {
+    metrics::inc_counter("nesbox_notify_emitted_total", msg.kind());
+    metrics::observe("nesbox_notify_fanout", 1.0);
     let map = NOTIFY_MAP.read().unwrap();
-    map.get(&user_id).and_then(|sender| sender.0.send(msg).ok());
+    if let Some(channel) = map.get(&user_id) {
+        send_to_user(user_id, channel, msg);
+    }
 }
 
 pub fn notify_ids(ids: Vec<i32>, msg: ScNotifyMessage) {
+    metrics::inc_counter("nesbox_notify_emitted_total", msg.kind());
+    metrics::observe("nesbox_notify_fanout", ids.len() as f64);
     let map = NOTIFY_MAP.read().unwrap();
     for user_id in ids {
-        map.get(&user_id)
-            .and_then(|sender| sender.0.send(msg.clone()).ok());
+        if let Some(channel) = map.get(&user_id) {
+            send_to_user(user_id, channel, msg.clone());
+        }
     }
 }
 
-pub fn notify_all(msg: ScNotifyMessage) {
-    let map = NOTIFY_MAP.read().unwrap();
-    for (user_id, _) in map.iter() {
-        map.get(&user_id)
-            .and_then(|sender| sender.0.send(msg.clone()).ok());
+/// Stores what the routing table persists for the kind, inside the caller's transaction
+pub fn persist_notification(
+    conn: &PgConnection,
+    user_id: i32,
+    kind: NotifyKind,
+    payload: &Value,
+) -> FieldResult<()> {
+    if let Some(persist) = kind.route().persist {
+        create_notification(conn, user_id, persist, payload)?;
+        metrics::inc_counter("nesbox_notify_persisted_total", kind.into());
     }
+    Ok(())
+}
+
+/// Stores what the routing table persists, then notifies
+pub fn notify_persisted(conn: &PgConnection, user_id: i32, msg: ScNotifyMessage, payload: &Value) {
+    if let Some(kind) = msg.notify_kind() {
+        if let Err(err) = persist_notification(conn, user_id, kind, payload) {
+            log::error!("Persist {}: {:?}", msg.kind(), err);
+        }
+    }
+    notify(user_id, msg);
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    // Lock poisoned by a panicked thread
+    Unavailable,
+    // Count of channels without receiver, e.g. shutting down
+    Closed(usize),
+    // Not a broadcast event per the routing table, nothing was sent
+    Misrouted,
+}
+
+/// Private events must never reach every online user
+fn check_broadcast(msg: &ScNotifyMessage) -> Result<(), NotifyError> {
+    match msg.route().map(|route| route.audience) {
+        Some(NotifyAudience::Global | NotifyAudience::Tenant) => Ok(()),
+        _ => {
+            metrics::inc_counter("nesbox_notify_misrouted_total", msg.kind());
+            Err(NotifyError::Misrouted)
+        }
+    }
+}
+
+pub fn notify_all(msg: ScNotifyMessage) -> Result<(), NotifyError> {
+    metrics::inc_counter("nesbox_notify_emitted_total", msg.kind());
+    check_broadcast(&msg)?;
+    let map = NOTIFY_MAP.read().map_err(|_| NotifyError::Unavailable)?;
+    let closed = map
+        .values()
+        .filter(|channel| !send(channel, msg.clone()))
+        .count();
+    if closed > 0 {
+        return Err(NotifyError::Closed(closed));
+    }
+    Ok(())
+}
+
+/// Game changes go to everyone, the first one in a window is sent right away
+/// and the rest are merged into one `games_changed` per window,
+/// latency sensitive messages never go through here
+pub fn notify_game_change(change: GameChange) -> Result<(), NotifyError> {
+    if !GAME_CHANGES.offer(&change)? {
+        return Ok(());
+    }
+
+    let msg = match change {
+        GameChange::Created(game) => ScNotifyMessageBuilder::default().new_game(*game).build(),
+        change => {
+            let mut batch = ScGamesChanged::default();
+            batch.push(&change);
+            ScNotifyMessageBuilder::default()
+                .games_changed(batch)
+                .build()
+        }
+    };
+    notify_all(msg.unwrap())
+}
+
+fn flush_game_changes(batch: ScGamesChanged) {
+    if let Err(err) = notify_all(
+        ScNotifyMessageBuilder::default()
+            .games_changed(batch)
+            .build()
+            .unwrap(),
+    ) {
+        log::error!("Notify games changed: {:?}", err);
+    }
+}
+
+/// Broadcast to online users of the tenant only
+pub fn notify_tenant(tenant_id: Option<i32>, msg: ScNotifyMessage) -> Result<(), NotifyError> {
+    metrics::inc_counter("nesbox_notify_emitted_total", msg.kind());
+    check_broadcast(&msg)?;
+    let map = NOTIFY_MAP.read().map_err(|_| NotifyError::Unavailable)?;
+    let tenants = TENANT_MAP.read().map_err(|_| NotifyError::Unavailable)?;
+    let closed = map
+        .iter()
+        .filter(|(user_id, _)| tenants.get(user_id).copied() == tenant_id)
+        .filter(|(_, channel)| !send(channel, msg.clone()))
+        .count();
+    if closed > 0 {
+        return Err(NotifyError::Closed(closed));
+    }
+    Ok(())
+}
+
+pub fn set_notify_tenant(user_id: i32, tenant_id: Option<i32>) {
+    let mut map = TENANT_MAP.write().unwrap();
+    match tenant_id {
+        Some(tenant_id) => map.insert(user_id, tenant_id),
+        None => map.remove(&user_id),
+    };
 }
 
 pub fn get_online_time(user_id: i32) -> Option<DateTime<Utc>> {
     let map = NOTIFY_MAP.read().unwrap();
-    map.get(&user_id).map(|sender| sender.1)
+    map.get(&user_id).map(|channel| channel.online_at)
 }
 
 pub fn get_online_count() -> i32 {
@@ -91,31 +819,84 @@ pub fn has_user(user_id: i32) -> bool {
     map.contains_key(&user_id)
 }
 
-pub struct NoyifyReceiver(pub Receiver<ScNotifyMessage>, pub i32);
+/// Disconnected longer than the grace period and not back on any socket
+pub fn take_seats_to_free(now: DateTime<Utc>) -> Vec<i32> {
+    take_expired_disconnected(now, *REJOIN_GRACE)
+        .into_iter()
+        .filter(|user_id| !has_user(*user_id))
+        .collect()
+}
+
+/// Receiver of a user's channel and its position in the channel
+pub struct NoyifyReceiver(pub Receiver<ScNotifyMessage>, pub i32, u64);
 
 pub fn get_receiver(user_id: i32) -> NoyifyReceiver {
-    NoyifyReceiver(
-        NOTIFY_MAP
-            .write()
+    let mut map = NOTIFY_MAP.write().unwrap();
+    let channel = map.entry(user_id).or_insert_with(|| {
+        log::debug!("{} is online", user_id);
+        reset_presence(user_id);
+        mark_reconnected(user_id);
+        NotifyChannel {
+            sender: broadcast::channel(5).0,
+            online_at: Utc::now(),
+            sent: Mutex::new(SentLog::default()),
+        }
+    });
+    let sent = channel.sent.lock().unwrap();
+    NoyifyReceiver(channel.sender.subscribe(), user_id, sent.next)
+}
+
+impl NoyifyReceiver {
+    pub async fn recv(&mut self) -> Result<ScNotifyMessage, RecvError> {
+        let result = self.0.recv().await;
+        match &result {
+            Ok(_) => self.2 += 1,
+            Err(RecvError::Lagged(count)) => self.lagged(*count),
+            Err(RecvError::Closed) => (),
+        }
+        result
+    }
+
+    pub fn try_recv(&mut self) -> Result<ScNotifyMessage, TryRecvError> {
+        let result = self.0.try_recv();
+        match &result {
+            Ok(_) => self.2 += 1,
+            Err(TryRecvError::Lagged(count)) => self.lagged(*count),
+            Err(_) => (),
+        }
+        result
+    }
+
+    /// The channel overwrote `count` events before this receiver got them
+    fn lagged(&mut self, count: u64) {
+        let kinds = NOTIFY_MAP
+            .read()
             .unwrap()
-            .entry(user_id)
-            .or_insert_with(|| {
-                log::debug!("{} is online", user_id);
-                (broadcast::channel(5).0, Utc::now())
-            })
-            .0
-            .subscribe(),
-        user_id,
-    )
+            .get(&self.1)
+            .map(|channel| channel.sent.lock().unwrap().kinds(self.2, count))
+            .unwrap_or_else(|| vec!["unknown"; count as usize]);
+        for kind in kinds {
+            metrics::inc_counter("nesbox_notify_dropped_total", kind);
+        }
+        self.2 += count;
+    }
+
+    /// Closes the user's channel without the offline bookkeeping of `drop`,
+    /// which needs the database
+    #[cfg(test)]
+    pub fn detach(self) {
+        NOTIFY_MAP.write().unwrap().remove(&self.1);
+        std::mem::forget(self);
+    }
 }
 
 impl Drop for NoyifyReceiver {
     fn drop(&mut self) {
         let user_id = self.1;
 
-        let online_time = NOTIFY_MAP.read().unwrap().get(&self.1).and_then(|sender| {
-            if sender.0.receiver_count() <= 1 {
-                Some(sender.1)
+        let online_time = NOTIFY_MAP.read().unwrap().get(&self.1).and_then(|channel| {
+            if channel.sender.receiver_count() <= 1 {
+                Some(channel.online_at)
             } else {
                 None
             }
@@ -125,23 +906,362 @@ impl Drop for NoyifyReceiver {
             log::debug!("{} is offline", user_id);
             // Temporary value release write lock
             NOTIFY_MAP.write().unwrap().remove(&user_id);
+            TENANT_MAP.write().unwrap().remove(&user_id);
 
             leave_lobby(user_id);
+            remove_presence(user_id);
 
-            let conn = DB_POOL.get().unwrap();
-            if let Ok(user) = get_user_basic(&conn, user_id) {
-                notify_ids(
-                    get_friend_ids(&conn, user_id),
-                    ScNotifyMessageBuilder::default()
-                        .update_user(user.clone())
-                        .build()
-                        .unwrap(),
-                );
-
-                if let Some(playing) = user.playing {
-                    pause_game(&conn, user_id, playing.game_id, time);
+            // dropped on a worker when the socket closes, keep the database off it
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    handle.spawn_blocking(move || go_offline(user_id, time));
                 }
+                Err(_) => go_offline(user_id, time),
             }
         }
     }
 }
+
+fn go_offline(user_id: i32, online_at: DateTime<Utc>) {
+    remove_spectator(user_id);
+
+    let conn = match DB_POOL.get() {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::error!("Offline {}: {}", user_id, err);
+            return;
+        }
+    };
+    if let Ok(user) = get_user_basic(&conn, user_id) {
+        notify_ids(
+            get_friend_ids(&conn, user_id),
+            ScNotifyMessageBuilder::default()
+                .update_user(user.clone())
+                .build()
+                .unwrap(),
+        );
+
+        if let Some(playing) = user.playing {
+            pause_game(&conn, user_id, playing.game_id, online_at);
+            mark_disconnected(user_id, Utc::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::get_counter;
+    use crate::schemas::notify::*;
+
+    #[test]
+    fn notify_metrics_two_subscribers() {
+        // Counters are process wide, other tests move them too
+        let names = [
+            "nesbox_notify_emitted_total",
+            "nesbox_notify_delivered_total",
+            "nesbox_notify_dropped_total",
+            "nesbox_notify_unreceived_total",
+        ];
+        let snapshot = || names.map(|name| get_counter(name, "delete_friend"));
+        let before = snapshot();
+        let mut reader = get_receiver(-1);
+        let mut idle = get_receiver(-2);
+
+        for _ in 0..10 {
+            notify_ids(
+                vec![-1, -2, -3],
+                ScNotifyMessageBuilder::default()
+                    .delete_friend(0)
+                    .build()
+                    .unwrap(),
+            );
+            assert!(reader.try_recv().is_ok());
+        }
+        // The channel keeps 5, the idle receiver lost the first 5
+        assert_eq!(idle.try_recv().unwrap_err(), TryRecvError::Lagged(5));
+        let mut received = 0;
+        while idle.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 5);
+
+        let delta: Vec<u64> = snapshot()
+            .iter()
+            .zip(before)
+            .map(|(after, before)| after - before)
+            .collect();
+        assert_eq!(delta, vec![10, 20, 5, 0]);
+
+        reader.detach();
+        idle.detach();
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn persisted_metrics() {
+        use crate::db::fixtures::{insert_user, test_conn};
+
+        let conn = test_conn();
+        let uid = insert_user(&conn, "persisted-metrics");
+        let persisted =
+            |kind: NotifyKind| get_counter("nesbox_notify_persisted_total", kind.into());
+        let before = (
+            persisted(NotifyKind::ApplyFriend),
+            persisted(NotifyKind::AcceptFriend),
+        );
+
+        let payload = json!({ "userId": 1 });
+        persist_notification(&conn, uid, NotifyKind::ApplyFriend, &payload).unwrap();
+        persist_notification(&conn, uid, NotifyKind::ApplyFriend, &payload).unwrap();
+        // Not kept for replay
+        persist_notification(&conn, uid, NotifyKind::AcceptFriend, &payload).unwrap();
+
+        assert_eq!(
+            (
+                persisted(NotifyKind::ApplyFriend) - before.0,
+                persisted(NotifyKind::AcceptFriend) - before.1,
+            ),
+            (2, 0)
+        );
+    }
+
+    #[test]
+    fn kick_ends_subscription() {
+        let mut receiver = get_receiver(-31);
+        notify(
+            -31,
+            ScNotifyMessageBuilder::default()
+                .kicked_room(3)
+                .build()
+                .unwrap(),
+        );
+        let msg = receiver.try_recv().unwrap();
+        assert_eq!(msg.kicked_room, Some(3));
+        assert_eq!(msg.ends_subscription(Some("session")), Some(true));
+        assert_eq!(msg.ends_subscription(None), Some(true));
+
+        let update = ScNotifyMessageBuilder::default()
+            .delete_room(3)
+            .build()
+            .unwrap();
+        assert_eq!(update.ends_subscription(None), None);
+
+        receiver.detach();
+    }
+
+    #[test]
+    fn dnd_delivery() {
+        use crate::db::models::Message;
+        use crate::schemas::message::convert_to_sc_message;
+        use crate::schemas::presence::{set_presence, ScPresenceStatus};
+
+        let message = || {
+            let now = Utc::now().naive_utc();
+            ScNotifyMessageBuilder::default()
+                .new_message(convert_to_sc_message(&Message {
+                    id: 1,
+                    body: "gg".into(),
+                    target_id: -32,
+                    user_id: 7,
+                    deleted_at: None,
+                    created_at: now,
+                    updated_at: now,
+                }))
+                .build()
+                .unwrap()
+        };
+        let alert = || {
+            ScNotifyMessageBuilder::default()
+                .room_join_failures(ScRoomJoinAlert {
+                    room_id: 3,
+                    attempts: 5,
+                })
+                .build()
+                .unwrap()
+        };
+        let mut receiver = get_receiver(-32);
+
+        notify(-32, message());
+        assert_eq!(receiver.try_recv().unwrap().silent, None);
+
+        // Messages arrive without a popup, other push kinds wait for the user
+        set_presence(-32, ScPresenceStatus::Dnd);
+        notify(-32, alert());
+        notify(-32, message());
+        let msg = receiver.try_recv().unwrap();
+        assert_eq!(msg.kind(), "new_message");
+        assert_eq!(msg.silent, Some(true));
+        assert!(receiver.try_recv().is_err());
+
+        remove_presence(-32);
+        notify(-32, alert());
+        assert_eq!(receiver.try_recv().unwrap().silent, None);
+
+        receiver.detach();
+    }
+
+    #[test]
+    fn rejoin_keeps_seat() {
+        let now = Utc::now();
+        // Both dropped out mid-game, only -33 is back before the grace ends
+        mark_disconnected(-33, now);
+        mark_disconnected(-34, now);
+        let receiver = get_receiver(-33);
+
+        let freed = take_seats_to_free(now + *REJOIN_GRACE);
+        assert!(!freed.contains(&-33));
+        assert!(freed.contains(&-34));
+
+        // Nothing left to release once the rejoined user leaves for good
+        receiver.detach();
+        assert!(!take_seats_to_free(now + *REJOIN_GRACE * 2).contains(&-33));
+    }
+
+    #[test]
+    fn sent_log() {
+        let mut log = SentLog::default();
+        for _ in 0..SENT_LOG_LEN {
+            log.push("new_game");
+        }
+        log.push("delete_room");
+        assert_eq!(log.next, SENT_LOG_LEN as u64 + 1);
+        assert_eq!(
+            log.kinds(SENT_LOG_LEN as u64 - 1, 3),
+            vec!["new_game", "delete_room", "unknown"]
+        );
+        // Fell out of the log
+        assert_eq!(log.kinds(0, 1), vec!["unknown"]);
+    }
+
+    #[test]
+    fn notify_tenant_isolation() {
+        let mut tenant_a = get_receiver(-11);
+        let mut tenant_b = get_receiver(-12);
+        let mut public = get_receiver(-13);
+        set_notify_tenant(-11, Some(1));
+        set_notify_tenant(-12, Some(2));
+
+        notify_tenant(
+            Some(2),
+            ScNotifyMessageBuilder::default()
+                .delete_room(42)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert!(tenant_a.0.try_recv().is_err());
+        assert!(public.0.try_recv().is_err());
+        assert_eq!(tenant_b.0.try_recv().unwrap().delete_room, Some(42));
+
+        tenant_a.detach();
+        tenant_b.detach();
+        public.detach();
+    }
+
+    #[test]
+    fn notify_routes_golden() {
+        let routes: String = get_notify_routes()
+            .iter()
+            .map(|route| {
+                format!(
+                    "{} audience={} persist={} push={} category={} coalesce={} compact={} critical={} gated={}\n",
+                    route.kind,
+                    route.audience,
+                    route
+                        .persist
+                        .as_ref()
+                        .map(|kind| kind.to_string())
+                        .unwrap_or_else(|| "-".into()),
+                    route.push,
+                    route.category,
+                    route.coalesce,
+                    route.compact,
+                    route.critical,
+                    route.gated,
+                )
+            })
+            .collect();
+        assert_eq!(routes, include_str!("notify_routes.golden"));
+
+        // Private events are refused by broadcasts
+        let msg = ScNotifyMessageBuilder::default()
+            .delete_friend(0)
+            .build()
+            .unwrap();
+        assert_eq!(msg.route().unwrap().kind, NotifyKind::DeleteFriend);
+        assert!(matches!(notify_all(msg), Err(NotifyError::Misrouted)));
+    }
+
+    #[actix_web::test]
+    async fn game_changes_coalesce() {
+        let mut receiver = get_receiver(-21);
+
+        // e.g. a bulk import
+        for id in 0..200 {
+            notify_game_change(GameChange::Updated(-1000 - id)).unwrap();
+        }
+        tokio::time::sleep(*COALESCE_WINDOW * 3).await;
+
+        let mut batches = Vec::new();
+        while let Ok(msg) = receiver.0.try_recv() {
+            batches.extend(msg.games_changed);
+        }
+        assert!(batches.len() <= 5);
+        assert_eq!(batches[0].updated_ids, vec![-1000]);
+        let updated: Vec<i32> = batches
+            .iter()
+            .flat_map(|batch| batch.updated_ids.clone())
+            .collect();
+        assert_eq!(updated, (0..200).map(|id| -1000 - id).collect::<Vec<_>>());
+
+        receiver.detach();
+    }
+
+    #[test]
+    fn compact_render() {
+        use crate::schemas::user::ScUserStatus;
+
+        let user = ScUserBasic {
+            id: 7,
+            username: "nes".into(),
+            nickname: "nes".into(),
+            status: ScUserStatus::Online,
+            playing: None,
+            avatar: None,
+        };
+        let update = |user: &ScUserBasic| {
+            ScNotifyMessageBuilder::default()
+                .update_user(user.clone())
+                .build()
+                .unwrap()
+        };
+        let mut renderer = CompactRenderer::default();
+
+        // Unseen objects list every field
+        let changed = renderer.render(update(&user)).unwrap().changed.unwrap();
+        assert_eq!((changed.kind, changed.id), (NotifyKind::UpdateUser, 7));
+        let mut fields = changed.fields;
+        fields.sort();
+        assert_eq!(
+            fields,
+            vec!["avatar", "id", "nickname", "playing", "status", "username"]
+        );
+
+        // Nothing changed, nothing sent
+        assert!(renderer.render(update(&user)).is_none());
+        let idle = ScUserBasic {
+            status: ScUserStatus::Idle,
+            ..user.clone()
+        };
+        let changed = renderer.render(update(&idle)).unwrap().changed.unwrap();
+        assert_eq!(changed.fields, vec!["status"]);
+
+        // Critical payloads stay complete
+        let msg = ScNotifyMessageBuilder::default()
+            .delete_invite(3)
+            .build()
+            .unwrap();
+        assert_eq!(renderer.render(msg).unwrap().delete_invite, Some(3));
+    }
+}
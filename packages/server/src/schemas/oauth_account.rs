@@ -0,0 +1,137 @@
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::report::flag_text;
+use super::user::{issue_login, ScLoginResp};
+use crate::auth::oauth::{OauthProfile, OauthProvider};
+use crate::db::models::{NewOauthAccount, NewUser, OauthAccount, User};
+use crate::db::schema::{oauth_accounts, users};
+use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
+
+const MAX_NICKNAME_LEN: usize = 20;
+
+fn link_account(
+    conn: &PgConnection,
+    uid: i32,
+    provider: OauthProvider,
+    profile: &OauthProfile,
+) -> FieldResult<()> {
+    let new_account = NewOauthAccount {
+        user_id: uid,
+        provider: &provider.to_string(),
+        subject: &profile.subject,
+        email: profile.email.as_deref(),
+        created_at: Utc::now().naive_utc(),
+    };
+    diesel::insert_into(oauth_accounts::table)
+        .values(&new_account)
+        .execute(conn)
+        .map_err(|_| {
+            FieldError::new(
+                format!("another {} account is linked", provider),
+                Error::conflict(),
+            )
+        })?;
+    Ok(())
+}
+
+/// Without a password, only the provider signs it in
+fn create_oauth_user(
+    conn: &PgConnection,
+    provider: OauthProvider,
+    profile: &OauthProfile,
+    tenant: Option<i32>,
+) -> FieldResult<User> {
+    let mut bytes = [0u8; 6];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let username = format!("{}_{}", provider, HEXLOWER.encode(&bytes));
+    let nickname: String = profile.nickname.chars().take(MAX_NICKNAME_LEN).collect();
+    // A rejected name falls back to the generated one
+    let (nickname, filter) = match check_text(ScTextKind::Username, &nickname) {
+        Ok(filter) if !nickname.is_empty() => (nickname, filter),
+        _ => (username.clone(), None),
+    };
+    let new_user = NewUser {
+        username: &username,
+        password: "",
+        nickname: &nickname,
+        settings: None,
+        deleted_at: None,
+        created_at: Utc::now().naive_utc(),
+        updated_at: Utc::now().naive_utc(),
+        tenant_id: tenant,
+        birthdate: None,
+    };
+    let user = diesel::insert_into(users::table)
+        .values(&new_user)
+        .get_result::<User>(conn)
+        .map_err(|error| FieldError::new(error, Error::register_username_exist()))?;
+    link_account(conn, user.id, provider, profile)?;
+
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            user.id,
+            ScTextKind::Username,
+            &user.nickname,
+            filter,
+            None,
+            Some(user.id),
+        );
+    }
+    Ok(user)
+}
+
+/// Log in the user of the provider account, created on its first sign in,
+/// or link the account to `link_user_id` first
+pub fn oauth_login(
+    conn: &PgConnection,
+    provider: OauthProvider,
+    profile: &OauthProfile,
+    link_user_id: Option<i32>,
+    tenant: Option<i32>,
+    secret: &str,
+) -> FieldResult<ScLoginResp> {
+    let linked = oauth_accounts::table
+        .filter(oauth_accounts::provider.eq(provider.to_string()))
+        .filter(oauth_accounts::subject.eq(&profile.subject))
+        .get_result::<OauthAccount>(conn)
+        .optional()?;
+    let uid = match (linked, link_user_id) {
+        (Some(account), Some(uid)) if account.user_id != uid => {
+            return Err(FieldError::new(
+                format!("the {} account belongs to another user", provider),
+                Error::conflict(),
+            ))
+        }
+        (Some(account), _) => account.user_id,
+        (None, Some(uid)) => {
+            link_account(conn, uid, provider, profile)?;
+            uid
+        }
+        (None, None) => {
+            conn.transaction(|| create_oauth_user(conn, provider, profile, tenant))?
+                .id
+        }
+    };
+
+    let user = users::table
+        .filter(users::deleted_at.is_null())
+        .filter(users::anonymized_at.is_null())
+        .filter(users::id.eq(uid))
+        .get_result::<User>(conn)
+        .optional()?
+        .filter(|user| user.tenant_id == tenant)
+        .ok_or_else(|| {
+            FieldError::new(
+                "username or password error",
+                Error::username_or_password_error(),
+            )
+        })?;
+    issue_login(conn, &user, secret)
+}
@@ -0,0 +1,171 @@
+use chrono::{Duration, Utc};
+use data_encoding::HEXLOWER;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ring::digest::{digest, SHA256};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use crate::db::models::NewPersistedQuery;
+use crate::db::schema::persisted_queries;
+
+// Queries kept in memory by each instance, the table has them all
+const MAX_CACHED: usize = 10_000;
+
+lazy_static! {
+    // Longest query stored, longer ones run but are sent in full every time
+    pub static ref MAX_PERSISTED_QUERY_BYTES: usize = env::var("MAX_PERSISTED_QUERY_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(64 * 1024);
+    // Stored queries unused this long are pruned, clients register them again
+    static ref PERSISTED_QUERY_TTL_DAYS: i64 = env::var("PERSISTED_QUERY_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30);
+    static ref CACHE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// `extensions.persistedQuery` of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum PersistedQuery {
+    // the hash alone, the query is looked up
+    Lookup(String),
+    // the hash along with its query, stored for later requests
+    Register(String, String),
+}
+
+pub fn get_query_hash(query: &str) -> String {
+    HEXLOWER.encode(digest(&SHA256, query.as_bytes()).as_ref())
+}
+
+/// `None` without the extension, `Err` with a hint when it can't be used
+pub fn read_persisted_query(request: &Value) -> Result<Option<PersistedQuery>, String> {
+    let extension = match request
+        .get("extensions")
+        .and_then(|extensions| extensions.get("persistedQuery"))
+    {
+        Some(extension) => extension,
+        None => return Ok(None),
+    };
+    if extension
+        .get("version")
+        .and_then(|version| version.as_i64())
+        != Some(1)
+    {
+        return Err("unsupported persisted query version, expected 1".into());
+    }
+    let hash = extension
+        .get("sha256Hash")
+        .and_then(|hash| hash.as_str())
+        .filter(|hash| hash.len() == 64 && HEXLOWER.decode(hash.as_bytes()).is_ok())
+        .ok_or("persisted query `sha256Hash` is not a lowercase hex sha256")?;
+    match request.get("query").and_then(|query| query.as_str()) {
+        Some(query) if get_query_hash(query) != hash => {
+            Err("provided sha does not match query".into())
+        }
+        Some(query) => Ok(Some(PersistedQuery::Register(
+            hash.to_owned(),
+            query.to_owned(),
+        ))),
+        None => Ok(Some(PersistedQuery::Lookup(hash.to_owned()))),
+    }
+}
+
+pub fn get_cached_query(hash: &str) -> Option<String> {
+    CACHE.lock().unwrap().get(hash).cloned()
+}
+
+fn cache_query(hash: &str, query: &str) {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED && !cache.contains_key(hash) {
+        if let Some(evicted) = cache.keys().next().cloned() {
+            cache.remove(&evicted);
+        }
+    }
+    cache.insert(hash.to_owned(), query.to_owned());
+}
+
+/// Stored query of `hash`, marked used so it outlives the pruning
+pub fn get_persisted_query(conn: &PgConnection, hash_value: &str) -> QueryResult<Option<String>> {
+    use self::persisted_queries::dsl::*;
+
+    let stored = diesel::update(persisted_queries.filter(hash.eq(hash_value)))
+        .set(last_used_at.eq(Utc::now().naive_utc()))
+        .returning(query)
+        .get_result::<String>(conn)
+        .optional()?;
+    if let Some(stored) = &stored {
+        cache_query(hash_value, stored);
+    }
+    Ok(stored)
+}
+
+/// Store a query the hash of which was checked, a known one is kept as is
+pub fn save_persisted_query(
+    conn: &PgConnection,
+    hash_value: &str,
+    query_value: &str,
+) -> QueryResult<usize> {
+    use self::persisted_queries::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let count = diesel::insert_into(persisted_queries)
+        .values(&NewPersistedQuery {
+            hash: hash_value,
+            query: query_value,
+            created_at: now,
+            last_used_at: now,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    cache_query(hash_value, query_value);
+    Ok(count)
+}
+
+pub fn prune_persisted_queries(conn: &PgConnection) -> QueryResult<usize> {
+    use self::persisted_queries::dsl::*;
+
+    let before = Utc::now().naive_utc() - Duration::days(*PERSISTED_QUERY_TTL_DAYS);
+    diesel::delete(persisted_queries.filter(last_used_at.lt(before))).execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::persisted_query::*;
+    use serde_json::json;
+
+    #[test]
+    fn persisted_query_extension() {
+        let query = "{ account { id } }";
+        let hash = get_query_hash(query);
+        assert_eq!(hash.len(), 64);
+        let extensions = json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+
+        assert_eq!(read_persisted_query(&json!({ "query": query })), Ok(None));
+        assert_eq!(
+            read_persisted_query(&json!({ "extensions": extensions })),
+            Ok(Some(PersistedQuery::Lookup(hash.clone())))
+        );
+        assert_eq!(
+            read_persisted_query(&json!({ "query": query, "extensions": extensions })),
+            Ok(Some(PersistedQuery::Register(hash.clone(), query.into())))
+        );
+        // Registering another document under the hash
+        assert!(
+            read_persisted_query(&json!({ "query": "{ a }", "extensions": extensions }))
+                .unwrap_err()
+                .contains("does not match")
+        );
+        assert!(read_persisted_query(
+            &json!({ "extensions": { "persistedQuery": { "version": 2, "sha256Hash": hash } } })
+        )
+        .is_err());
+        assert!(read_persisted_query(
+            &json!({ "extensions": { "persistedQuery": { "version": 1, "sha256Hash": hash.to_uppercase() } } })
+        )
+        .is_err());
+    }
+}
@@ -1,7 +1,10 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use juniper::{FieldResult, GraphQLInputObject};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
 
 use super::room::*;
 use crate::db::models::{NewPlaying, Playing};
@@ -10,6 +13,47 @@ use crate::db::schema::playing;
 #[derive(GraphQLInputObject)]
 pub struct ScUpdatePlaying {
     pub room_id: i32,
+    // required by private rooms with a password
+    pub password: Option<String>,
+    // watch without a seat, subject to the room's spectator policy
+    pub spectate: Option<bool>,
+}
+
+lazy_static! {
+    // user_id -> disconnected time
+    static ref DISCONNECTED: Mutex<HashMap<i32, DateTime<Utc>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    pub static ref REJOIN_GRACE: Duration = Duration::seconds(
+        env::var("ROOM_REJOIN_GRACE_SECONDS")
+            .ok()
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(60),
+    );
+}
+
+/// Seat is kept until grace period expires
+pub fn mark_disconnected(uid: i32, at: DateTime<Utc>) {
+    DISCONNECTED.lock().unwrap().insert(uid, at);
+}
+
+pub fn mark_reconnected(uid: i32) {
+    DISCONNECTED.lock().unwrap().remove(&uid);
+}
+
+/// Return users whose seat should be freed
+pub fn take_expired_disconnected(now: DateTime<Utc>, grace: Duration) -> Vec<i32> {
+    let mut map = DISCONNECTED.lock().unwrap();
+    let ids: Vec<i32> = map
+        .iter()
+        .filter(|(_, at)| **at + grace <= now)
+        .map(|(uid, _)| *uid)
+        .collect();
+    for uid in ids.iter() {
+        map.remove(uid);
+    }
+    ids
 }
 
 pub fn get_playing(conn: &PgConnection, uid: i32) -> Option<ScRoomBasic> {
@@ -23,6 +67,17 @@ pub fn get_playing(conn: &PgConnection, uid: i32) -> Option<ScRoomBasic> {
         .map(|row| get_room(conn, row.room_id).unwrap())
 }
 
+pub fn get_playing_room_id(conn: &PgConnection, uid: i32) -> Option<i32> {
+    use self::playing::dsl::*;
+
+    playing
+        .select(room_id)
+        .filter(user_id.eq(uid))
+        .get_result::<i32>(conn)
+        .optional()
+        .unwrap()
+}
+
 pub fn get_room_user_ids(conn: &PgConnection, rid: i32) -> Vec<i32> {
     use self::playing::dsl::*;
 
@@ -33,6 +88,43 @@ pub fn get_room_user_ids(conn: &PgConnection, rid: i32) -> Vec<i32> {
         .unwrap()
 }
 
+/// Members in join order
+pub fn get_room_members(conn: &PgConnection, rid: i32) -> Vec<Playing> {
+    use self::playing::dsl::*;
+
+    playing
+        .filter(room_id.eq(rid))
+        .order(created_at.asc())
+        .load::<Playing>(conn)
+        .unwrap()
+}
+
+pub fn get_room_moderator_ids(conn: &PgConnection, rid: i32) -> Vec<i32> {
+    use self::playing::dsl::*;
+
+    playing
+        .select(user_id)
+        .filter(room_id.eq(rid))
+        .filter(moderator.eq(true))
+        .load(conn)
+        .unwrap()
+}
+
+pub fn is_room_moderator(conn: &PgConnection, rid: i32, uid: i32) -> bool {
+    get_room_moderator_ids(conn, rid).contains(&uid)
+}
+
+/// Return false when the user is not in the room
+pub fn set_room_moderator(conn: &PgConnection, rid: i32, uid: i32, enabled: bool) -> bool {
+    use self::playing::dsl::*;
+
+    diesel::update(playing.filter(room_id.eq(rid)).filter(user_id.eq(uid)))
+        .set(moderator.eq(enabled))
+        .execute(conn)
+        .unwrap()
+        > 0
+}
+
 pub fn create_playing(conn: &PgConnection, uid: i32, rid: i32) -> FieldResult<i32> {
     let new_playing = NewPlaying {
         room_id: rid,
@@ -62,3 +154,38 @@ pub fn delete_playing_with_room(conn: &PgConnection, rid: i32) {
         .execute(conn)
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::playing::*;
+
+    #[test]
+    fn rejoin_within_grace() {
+        let now = Utc::now();
+        mark_disconnected(-10, now);
+        mark_reconnected(-10);
+        assert!(
+            !take_expired_disconnected(now + Duration::seconds(120), Duration::seconds(60))
+                .contains(&-10)
+        );
+    }
+
+    #[test]
+    fn rejoin_after_seat_freed() {
+        let now = Utc::now();
+        mark_disconnected(-11, now);
+        assert!(
+            !take_expired_disconnected(now + Duration::seconds(30), Duration::seconds(60))
+                .contains(&-11)
+        );
+        assert!(
+            take_expired_disconnected(now + Duration::seconds(60), Duration::seconds(60))
+                .contains(&-11)
+        );
+        // seat freed only once
+        assert!(
+            !take_expired_disconnected(now + Duration::seconds(90), Duration::seconds(60))
+                .contains(&-11)
+        );
+    }
+}
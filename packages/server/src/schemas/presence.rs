@@ -0,0 +1,115 @@
+use chrono::{DateTime, Duration, Utc};
+use juniper::{GraphQLEnum, GraphQLInputObject};
+use std::collections::HashMap;
+use std::env;
+use std::sync::RwLock;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScPresenceStatus {
+    Online,
+    Idle,
+    Dnd,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScSetStatusReq {
+    pub status: ScPresenceStatus,
+}
+
+struct Presence {
+    status: ScPresenceStatus,
+    last_active_at: DateTime<Utc>,
+    // pinned DND survive reconnect
+    pinned: bool,
+}
+
+lazy_static! {
+    static ref PRESENCE_MAP: RwLock<HashMap<i32, Presence>> = {
+        let m = HashMap::new();
+        RwLock::new(m)
+    };
+    static ref IDLE_MINUTES: i64 = env::var("IDLE_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(5);
+}
+
+pub fn get_presence(uid: i32) -> ScPresenceStatus {
+    PRESENCE_MAP
+        .read()
+        .unwrap()
+        .get(&uid)
+        .map(|presence| presence.status)
+        .unwrap_or(ScPresenceStatus::Online)
+}
+
+pub fn is_dnd(uid: i32) -> bool {
+    get_presence(uid) == ScPresenceStatus::Dnd
+}
+
+/// Call when user online
+pub fn reset_presence(uid: i32) {
+    PRESENCE_MAP
+        .write()
+        .unwrap()
+        .entry(uid)
+        .and_modify(|presence| {
+            if !presence.pinned {
+                presence.status = ScPresenceStatus::Online;
+            }
+            presence.last_active_at = Utc::now();
+        })
+        .or_insert(Presence {
+            status: ScPresenceStatus::Online,
+            last_active_at: Utc::now(),
+            pinned: false,
+        });
+}
+
+/// Call when user offline
+pub fn remove_presence(uid: i32) {
+    let mut map = PRESENCE_MAP.write().unwrap();
+    if !map.get(&uid).map_or(false, |presence| presence.pinned) {
+        map.remove(&uid);
+    }
+}
+
+pub fn set_presence(uid: i32, status: ScPresenceStatus) {
+    PRESENCE_MAP.write().unwrap().insert(
+        uid,
+        Presence {
+            status,
+            last_active_at: Utc::now(),
+            pinned: status == ScPresenceStatus::Dnd,
+        },
+    );
+}
+
+/// Return true if status changed
+pub fn ping_presence(uid: i32) -> bool {
+    let mut map = PRESENCE_MAP.write().unwrap();
+    match map.get_mut(&uid) {
+        Some(presence) => {
+            presence.last_active_at = Utc::now();
+            if presence.status == ScPresenceStatus::Idle {
+                presence.status = ScPresenceStatus::Online;
+                return true;
+            }
+            false
+        }
+        None => false,
+    }
+}
+
+/// Mark inactive online user as idle, return changed user ids
+pub fn update_idle_presence() -> Vec<i32> {
+    let deadline = Utc::now() - Duration::minutes(*IDLE_MINUTES);
+    let mut ids = Vec::new();
+    for (uid, presence) in PRESENCE_MAP.write().unwrap().iter_mut() {
+        if presence.status == ScPresenceStatus::Online && presence.last_active_at < deadline {
+            presence.status = ScPresenceStatus::Idle;
+            ids.push(*uid);
+        }
+    }
+    ids
+}
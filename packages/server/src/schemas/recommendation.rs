@@ -0,0 +1,304 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::dsl::not;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Timestamp, Varchar};
+use juniper::{FieldResult, GraphQLEnum, GraphQLObject};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::db::schema::{games, records};
+
+use super::friend::ScFriendStatus;
+use super::game_kind::get_game_kinds_map;
+use super::visibility::visible_games;
+
+const CACHE_MINUTES: i64 = 60;
+const TRENDING_DAYS: i64 = 14;
+const MAX_RECOMMENDATIONS: usize = 100;
+
+// Weights of the normalized signals
+const FRIENDS_PLAYTIME_WEIGHT: f64 = 0.4;
+const FRIENDS_FAVORITE_WEIGHT: f64 = 0.2;
+const KIND_WEIGHT: f64 = 0.25;
+const TRENDING_WEIGHT: f64 = 0.15;
+
+lazy_static! {
+    // user id -> ranking, recomputed an hour after
+    static ref CACHE: Mutex<HashMap<i32, (DateTime<Utc>, Vec<ScRecommendation>)>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScRecommendationReason {
+    // friends played or favorited it lately
+    FriendsPlaying,
+    // shares a kind with what the caller plays most
+    SimilarKind,
+    Trending,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScRecommendation {
+    pub game_id: i32,
+    // 0 to 1
+    pub score: f64,
+    pub reason: ScRecommendationReason,
+}
+
+/// Everything the ranking needs, loaded by a fixed number of aggregate queries
+#[derive(Default)]
+pub struct RecommendationSignals {
+    // playable games the caller has no record of
+    pub candidates: Vec<i32>,
+    pub game_kinds: HashMap<i32, Vec<String>>,
+    // recent seconds played by friends
+    pub friends_playtime: HashMap<i32, i64>,
+    pub friends_favorites: HashMap<i32, i64>,
+    // recent distinct players
+    pub trending: HashMap<i32, i64>,
+    // seconds the caller played per kind
+    pub kind_playtime: HashMap<String, i64>,
+}
+
+#[derive(QueryableByName)]
+struct GameValueRow {
+    #[sql_type = "Integer"]
+    game_id: i32,
+    #[sql_type = "BigInt"]
+    value: i64,
+}
+
+#[derive(QueryableByName)]
+struct KindValueRow {
+    #[sql_type = "Varchar"]
+    kind: String,
+    #[sql_type = "BigInt"]
+    value: i64,
+}
+
+// Binds: $1 user id, $2 accepted status
+const FRIEND_IDS_SQL: &str = "SELECT target_id FROM friends WHERE user_id = $1 AND status = $2";
+
+fn normalize(map: &HashMap<i32, i64>, gid: i32) -> f64 {
+    let max = map.values().copied().max().unwrap_or_default();
+    if max <= 0 {
+        return 0.0;
+    }
+    map.get(&gid).copied().unwrap_or_default().max(0) as f64 / max as f64
+}
+
+/// Deterministic for fixed signals: score, then game id
+pub fn rank_games(signals: &RecommendationSignals) -> Vec<ScRecommendation> {
+    let max_kind = signals
+        .kind_playtime
+        .values()
+        .copied()
+        .max()
+        .unwrap_or_default();
+    let mut ranked: Vec<_> = signals
+        .candidates
+        .iter()
+        .filter_map(|gid| {
+            let friends = FRIENDS_PLAYTIME_WEIGHT * normalize(&signals.friends_playtime, *gid)
+                + FRIENDS_FAVORITE_WEIGHT * normalize(&signals.friends_favorites, *gid);
+            let kind = if max_kind > 0 {
+                let best = signals
+                    .game_kinds
+                    .get(gid)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|kind| signals.kind_playtime.get(kind))
+                    .copied()
+                    .max()
+                    .unwrap_or_default();
+                KIND_WEIGHT * best as f64 / max_kind as f64
+            } else {
+                0.0
+            };
+            let trending = TRENDING_WEIGHT * normalize(&signals.trending, *gid);
+
+            let score = friends + kind + trending;
+            if score <= 0.0 {
+                return None;
+            }
+            let reason = if friends >= kind && friends >= trending {
+                ScRecommendationReason::FriendsPlaying
+            } else if kind >= trending {
+                ScRecommendationReason::SimilarKind
+            } else {
+                ScRecommendationReason::Trending
+            };
+            Some(ScRecommendation {
+                game_id: *gid,
+                score,
+                reason,
+            })
+        })
+        .collect();
+    ranked.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap()
+            .then(a.game_id.cmp(&b.game_id))
+    });
+    ranked.truncate(MAX_RECOMMENDATIONS);
+    ranked
+}
+
+fn to_map(rows: Vec<GameValueRow>) -> HashMap<i32, i64> {
+    rows.into_iter()
+        .map(|row| (row.game_id, row.value))
+        .collect()
+}
+
+fn load_signals(conn: &PgConnection, uid: i32) -> QueryResult<RecommendationSignals> {
+    let since = Utc::now().naive_utc() - Duration::days(TRENDING_DAYS);
+    let accept = ScFriendStatus::Accept.to_string();
+
+    let friends_playtime = to_map(
+        diesel::sql_query(format!(
+            "SELECT game_id, SUM(duration)::bigint AS value FROM play_sessions \
+            WHERE user_id IN ({}) AND started_at > $3 GROUP BY game_id",
+            FRIEND_IDS_SQL
+        ))
+        .bind::<Integer, _>(uid)
+        .bind::<Varchar, _>(&accept)
+        .bind::<Timestamp, _>(since)
+        .load::<GameValueRow>(conn)?,
+    );
+    let friends_favorites = to_map(
+        diesel::sql_query(format!(
+            "SELECT game_id, COUNT(*) AS value FROM favorites \
+            WHERE user_id IN ({}) GROUP BY game_id",
+            FRIEND_IDS_SQL
+        ))
+        .bind::<Integer, _>(uid)
+        .bind::<Varchar, _>(&accept)
+        .load::<GameValueRow>(conn)?,
+    );
+    let trending = to_map(
+        diesel::sql_query(
+            "SELECT game_id, COUNT(DISTINCT user_id) AS value FROM play_sessions \
+            WHERE started_at > $1 GROUP BY game_id",
+        )
+        .bind::<Timestamp, _>(since)
+        .load::<GameValueRow>(conn)?,
+    );
+    let played = records::table
+        .select(records::game_id)
+        .filter(records::user_id.eq(uid));
+    let candidates = visible_games()
+        .select(games::id)
+        .filter(games::deprecated_at.is_null())
+        .filter(not(games::id.eq_any(played)))
+        .order(games::id.asc())
+        .load::<i32>(conn)?;
+
+    let kind_playtime = diesel::sql_query(
+        "SELECT game_kinds.kind, SUM(records.play_total)::bigint AS value FROM records \
+        INNER JOIN game_kinds ON game_kinds.game_id = records.game_id \
+        WHERE records.user_id = $1 GROUP BY game_kinds.kind",
+    )
+    .bind::<Integer, _>(uid)
+    .load::<KindValueRow>(conn)?
+    .into_iter()
+    .map(|row| (row.kind, row.value))
+    .collect();
+
+    let game_kinds = get_game_kinds_map(conn, Some(candidates.clone()))
+        .into_iter()
+        .map(|(gid, kinds)| (gid, kinds.iter().map(|k| k.to_string()).collect()))
+        .collect();
+
+    Ok(RecommendationSignals {
+        candidates,
+        game_kinds,
+        friends_playtime,
+        friends_favorites,
+        trending,
+        kind_playtime,
+    })
+}
+
+/// Unplayed games, cached per user for an hour
+pub fn get_recommended_games(
+    conn: &PgConnection,
+    uid: i32,
+    first: Option<i32>,
+) -> FieldResult<Vec<ScRecommendation>> {
+    let first = first.unwrap_or(20).clamp(1, MAX_RECOMMENDATIONS as i32) as usize;
+    let now = Utc::now();
+    let cached = CACHE
+        .lock()
+        .unwrap()
+        .get(&uid)
+        .filter(|(at, _)| now - *at < Duration::minutes(CACHE_MINUTES))
+        .map(|(_, ranked)| ranked.clone());
+    let ranked = match cached {
+        Some(ranked) => ranked,
+        None => {
+            let ranked = rank_games(&load_signals(conn, uid)?);
+            let mut cache = CACHE.lock().unwrap();
+            cache.retain(|_, (at, _)| now - *at < Duration::minutes(CACHE_MINUTES));
+            cache.insert(uid, (now, ranked.clone()));
+            ranked
+        }
+    };
+    Ok(ranked.into_iter().take(first).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::recommendation::*;
+
+    fn ids(ranked: &[ScRecommendation]) -> Vec<(i32, ScRecommendationReason)> {
+        ranked
+            .iter()
+            .map(|item| (item.game_id, item.reason))
+            .collect()
+    }
+
+    #[test]
+    fn recommendation_ranking() {
+        use ScRecommendationReason::*;
+
+        let signals = RecommendationSignals {
+            candidates: vec![1, 2, 3, 4, 5, 6],
+            game_kinds: HashMap::from([
+                (2, vec!["stg".into()]),
+                (3, vec!["act".into(), "rpg".into()]),
+                (6, vec!["act".into()]),
+            ]),
+            friends_playtime: HashMap::from([(1, 3600), (4, 600)]),
+            friends_favorites: HashMap::from([(1, 2), (5, 1)]),
+            trending: HashMap::from([(2, 10), (4, 30), (5, 2)]),
+            kind_playtime: HashMap::from([("act".into(), 7200), ("stg".into(), 100)]),
+        };
+        let ranked = rank_games(&signals);
+        assert_eq!(
+            ids(&ranked),
+            vec![
+                (1, FriendsPlaying),
+                (3, SimilarKind),
+                (6, SimilarKind),
+                (4, Trending),
+                (5, FriendsPlaying),
+                (2, Trending),
+            ]
+        );
+        // Ties keep id order, same data same ranking
+        assert_eq!(ranked[1].score, ranked[2].score);
+        assert_eq!(rank_games(&signals), ranked);
+
+        // No friends or history, trending only
+        let fresh = RecommendationSignals {
+            candidates: vec![1, 2, 3],
+            trending: HashMap::from([(2, 5), (3, 5)]),
+            ..Default::default()
+        };
+        assert_eq!(ids(&rank_games(&fresh)), vec![(2, Trending), (3, Trending)]);
+    }
+}
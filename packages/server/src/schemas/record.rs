@@ -1,17 +1,27 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Nullable, Timestamp};
 
-use crate::db::models::{NewRecord, Record};
-use crate::db::schema::records;
+use crate::db::models::{NewPlaySession, NewRecord, Record};
+use crate::db::schema::{play_sessions, records};
+use crate::error::Error;
 use crate::schemas::notify::get_online_time;
-use juniper::{GraphQLInputObject, GraphQLObject};
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+
+const HEARTBEAT_TIMEOUT_SECONDS: i64 = 5 * 60;
 
 #[derive(GraphQLInputObject)]
 pub struct ScRecordReq {
     pub game_id: i32,
 }
 
+#[derive(GraphQLInputObject)]
+pub struct ScRepairPlaytimeReq {
+    // records whose total exceeds this are treated as corrupt
+    pub max_hours: f64,
+}
+
 #[derive(GraphQLObject, Debug, Clone)]
 pub struct ScRecord {
     play_total: f64,
@@ -19,6 +29,33 @@ pub struct ScRecord {
     last_play_end_at: Option<f64>,
 }
 
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScGameStats {
+    game_id: i32,
+    // milliseconds, every player together
+    play_total: f64,
+    players: i32,
+    // `None` before anyone played
+    last_played_at: Option<f64>,
+}
+
+#[derive(QueryableByName)]
+struct GameStatsRow {
+    #[sql_type = "BigInt"]
+    play_total: i64,
+    #[sql_type = "BigInt"]
+    players: i64,
+    #[sql_type = "Nullable<Timestamp>"]
+    last_played_at: Option<NaiveDateTime>,
+}
+
+// A session in progress counts as played at its start
+const GAME_STATS_SQL: &str = r#"
+SELECT COALESCE(SUM(play_total), 0)::bigint AS play_total, COUNT(*) AS players,
+    MAX(GREATEST(last_play_start_at, last_play_end_at)) AS last_played_at
+FROM records WHERE game_id = $1
+"#;
+
 fn convert_to_sc_record(record: &Record) -> ScRecord {
     ScRecord {
         last_play_end_at: record
@@ -43,6 +80,7 @@ pub fn start_game(conn: &PgConnection, uid: i32, gid: i32) {
             .set((
                 last_play_start_at.eq(Utc::now().naive_utc()),
                 last_play_end_at.eq(None::<NaiveDateTime>),
+                last_heartbeat_at.eq(Some(Utc::now().naive_utc())),
             ))
             .execute(conn)
             .ok();
@@ -56,6 +94,7 @@ pub fn start_game(conn: &PgConnection, uid: i32, gid: i32) {
         play_total: 0,
         last_play_end_at: None,
         last_play_start_at: Utc::now().naive_utc(),
+        last_heartbeat_at: Some(Utc::now().naive_utc()),
     };
 
     diesel::insert_into(records)
@@ -64,55 +103,175 @@ pub fn start_game(conn: &PgConnection, uid: i32, gid: i32) {
         .ok();
 }
 
-pub fn end_game(conn: &PgConnection, uid: i32, gid: i32) {
-    use self::records::dsl::*;
+/// Milliseconds played from `from` (not before `start`) to `end`,
+/// never more than the session itself to protect against clock skew
+pub fn get_session_duration(start: i64, from: i64, end: i64) -> i64 {
+    (end - std::cmp::max(start, from)).clamp(0, std::cmp::max(end - start, 0))
+}
 
-    if let Some(online_time) = get_online_time(uid) {
-        let r = records
-            .filter(user_id.eq(uid))
-            .filter(game_id.eq(gid))
-            .get_result::<Record>(conn);
+fn add_session(
+    conn: &PgConnection,
+    record: &Record,
+    from: NaiveDateTime,
+    end: NaiveDateTime,
+    closed: bool,
+) -> QueryResult<i64> {
+    use self::records::dsl::*;
 
-        if let Ok(record) = r {
-            let duration = Utc::now().timestamp_millis()
-                - std::cmp::max(
-                    record.last_play_start_at.timestamp_millis(),
-                    online_time.timestamp_millis(),
-                );
+    let duration = get_session_duration(
+        record.last_play_start_at.timestamp_millis(),
+        from.timestamp_millis(),
+        end.timestamp_millis(),
+    );
 
-            diesel::update(records.filter(game_id.eq(gid)).filter(user_id.eq(uid)))
+    conn.transaction(|| {
+        let target = records
+            .filter(user_id.eq(record.user_id))
+            .filter(game_id.eq(record.game_id));
+        if closed {
+            diesel::update(target)
+                .set((
+                    last_play_end_at.eq(Some(end)),
+                    play_total.eq(record.play_total + duration),
+                    last_heartbeat_at.eq(None::<NaiveDateTime>),
+                ))
+                .execute(conn)?;
+        } else {
+            diesel::update(target)
                 .set((
-                    last_play_end_at.eq(Utc::now().naive_utc()),
                     play_total.eq(record.play_total + duration),
+                    last_heartbeat_at.eq(None::<NaiveDateTime>),
                 ))
-                .execute(conn)
-                .ok();
+                .execute(conn)?;
         }
-    }
+
+        if duration > 0 {
+            let new_session = NewPlaySession {
+                user_id: record.user_id,
+                game_id: record.game_id,
+                started_at: std::cmp::max(record.last_play_start_at, from),
+                ended_at: end,
+                duration,
+            };
+            diesel::insert_into(play_sessions::table)
+                .values(&new_session)
+                .execute(conn)?;
+        }
+
+        Ok(duration)
+    })
 }
 
-pub fn pause_game(conn: &PgConnection, uid: i32, gid: i32, online_time: DateTime<Utc>) {
+fn get_open_record(conn: &PgConnection, uid: i32, gid: i32) -> Option<Record> {
     use self::records::dsl::*;
 
-    let r = records
+    records
         .filter(user_id.eq(uid))
         .filter(game_id.eq(gid))
-        .get_result::<Record>(conn);
-
-    if let Ok(record) = r {
-        let duration = Utc::now().timestamp_millis()
-            - std::cmp::max(
-                record.last_play_start_at.timestamp_millis(),
-                online_time.timestamp_millis(),
-            );
+        .filter(last_play_end_at.is_null())
+        .get_result::<Record>(conn)
+        .ok()
+}
 
-        diesel::update(records.filter(game_id.eq(gid)).filter(user_id.eq(uid)))
-            .set((play_total.eq(record.play_total + duration),))
-            .execute(conn)
+pub fn end_game(conn: &PgConnection, uid: i32, gid: i32) {
+    if let Some(online_time) = get_online_time(uid) {
+        // Already closed by the heartbeat cleanup
+        if let Some(record) = get_open_record(conn, uid, gid) {
+            add_session(
+                conn,
+                &record,
+                online_time.naive_utc(),
+                Utc::now().naive_utc(),
+                true,
+            )
             .ok();
+        }
+    }
+}
+
+pub fn pause_game(conn: &PgConnection, uid: i32, gid: i32, online_time: DateTime<Utc>) {
+    if let Some(record) = get_open_record(conn, uid, gid) {
+        add_session(
+            conn,
+            &record,
+            online_time.naive_utc(),
+            Utc::now().naive_utc(),
+            false,
+        )
+        .ok();
     }
 }
 
+/// Client calls every minute while the game is running
+pub fn play_heartbeat(conn: &PgConnection, uid: i32, gid: i32) -> FieldResult<bool> {
+    use self::records::dsl::*;
+
+    let count = diesel::update(
+        records
+            .filter(user_id.eq(uid))
+            .filter(game_id.eq(gid))
+            .filter(last_play_end_at.is_null()),
+    )
+    .set(last_heartbeat_at.eq(Some(Utc::now().naive_utc())))
+    .execute(conn)?;
+
+    Ok(count > 0)
+}
+
+/// Close sessions abandoned without `endPlay`, the last heartbeat is the effective end
+pub fn close_stale_sessions(conn: &PgConnection) -> usize {
+    use self::records::dsl::*;
+
+    let deadline = Utc::now().naive_utc() - Duration::seconds(HEARTBEAT_TIMEOUT_SECONDS);
+    let stale = records
+        .filter(last_play_end_at.is_null())
+        .filter(last_heartbeat_at.lt(deadline))
+        .load::<Record>(conn)
+        .unwrap_or_default();
+
+    stale
+        .iter()
+        .filter(|record| {
+            let from = get_online_time(record.user_id)
+                .map(|time| time.naive_utc())
+                .unwrap_or(record.last_play_start_at);
+            let end = record.last_heartbeat_at.unwrap_or(deadline);
+            match add_session(conn, record, from, end, true) {
+                Ok(_) => true,
+                Err(err) => {
+                    log::error!(
+                        "Close play {:?}: {:?}",
+                        (record.user_id, record.game_id),
+                        err
+                    );
+                    false
+                }
+            }
+        })
+        .count()
+}
+
+/// Re-derive totals above `max_hours` from session rows, records without sessions are kept
+pub fn repair_playtime(conn: &PgConnection, max_hours: f64) -> FieldResult<i32> {
+    if !max_hours.is_finite() || max_hours <= 0.0 {
+        return Err(FieldError::new("invalid max hours", Error::validation()));
+    }
+
+    let count = diesel::sql_query(
+        r#"UPDATE records SET play_total = s.total
+        FROM (
+            SELECT user_id, game_id, SUM(duration)::bigint AS total
+            FROM play_sessions GROUP BY user_id, game_id
+        ) s
+        WHERE records.user_id = s.user_id AND records.game_id = s.game_id
+        AND records.play_total > $1 AND records.play_total > s.total"#,
+    )
+    .bind::<BigInt, _>((max_hours * 3600.0 * 1000.0) as i64)
+    .execute(conn)?;
+
+    Ok(count as i32)
+}
+
 pub fn get_recent_ids(conn: &PgConnection, uid: i32) -> Vec<i32> {
     use self::records::dsl::*;
 
@@ -126,6 +285,40 @@ pub fn get_recent_ids(conn: &PgConnection, uid: i32) -> Vec<i32> {
         .collect()
 }
 
+/// A finished session in the past, goes through the same accounting as real play
+pub fn add_past_session(
+    conn: &PgConnection,
+    uid: i32,
+    gid: i32,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> QueryResult<i64> {
+    use self::records::dsl::*;
+
+    start_game(conn, uid, gid);
+    let target = records.filter(user_id.eq(uid)).filter(game_id.eq(gid));
+    let record = diesel::update(target)
+        .set(last_play_start_at.eq(start))
+        .get_result::<Record>(conn)?;
+    add_session(conn, &record, start, end, true)
+}
+
+/// Totals over the records of every player of the game
+pub fn get_game_stats(conn: &PgConnection, gid: i32) -> FieldResult<ScGameStats> {
+    let row = diesel::sql_query(GAME_STATS_SQL)
+        .bind::<Integer, _>(gid)
+        .get_result::<GameStatsRow>(conn)?;
+
+    Ok(ScGameStats {
+        game_id: gid,
+        play_total: row.play_total as f64,
+        players: row.players as i32,
+        last_played_at: row
+            .last_played_at
+            .map(|time| time.timestamp_millis() as f64),
+    })
+}
+
 pub fn get_record(conn: &PgConnection, uid: i32, gid: i32) -> Option<ScRecord> {
     use self::records::dsl::*;
 
@@ -136,3 +329,62 @@ pub fn get_record(conn: &PgConnection, uid: i32, gid: i32) -> Option<ScRecord> {
         .ok()
         .map(|record| convert_to_sc_record(&record))
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::record::*;
+
+    #[test]
+    fn session_duration() {
+        assert_eq!(get_session_duration(1000, 0, 5000), 4000);
+        assert_eq!(get_session_duration(1000, 3000, 5000), 2000);
+        // clock skew
+        assert_eq!(get_session_duration(6000, 0, 5000), 0);
+        assert_eq!(get_session_duration(1000, 7000, 5000), 0);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn game_stats() {
+        use crate::db::fixtures::*;
+        use chrono::NaiveDate;
+
+        let conn = test_conn();
+        let at = |hour| {
+            NaiveDate::from_ymd_opt(2023, 1, 1)
+                .and_then(|date| date.and_hms_opt(hour, 0, 0))
+                .unwrap()
+        };
+        let insert = |uid, gid, total, start, end| {
+            diesel::insert_into(records::table)
+                .values(&NewRecord {
+                    user_id: uid,
+                    game_id: gid,
+                    play_total: total,
+                    last_play_start_at: start,
+                    last_play_end_at: end,
+                    last_heartbeat_at: None,
+                })
+                .execute(&conn)
+                .unwrap();
+        };
+        let gid = insert_game(&conn, "stats");
+        let other = insert_game(&conn, "stats-other");
+        let a = insert_user(&conn, "stats-a");
+        let b = insert_user(&conn, "stats-b");
+        insert(a, gid, 1000, at(1), Some(at(5)));
+        // still playing, started after `a` ended
+        insert(b, gid, 2500, at(3), None);
+        insert(a, other, 9000, at(8), Some(at(9)));
+
+        let stats = get_game_stats(&conn, gid).unwrap();
+        assert_eq!(stats.play_total, 3500.0);
+        assert_eq!(stats.players, 2);
+        assert_eq!(stats.last_played_at, Some(at(5).timestamp_millis() as f64));
+
+        let unplayed = get_game_stats(&conn, insert_game(&conn, "stats-unplayed")).unwrap();
+        assert_eq!(unplayed.play_total, 0.0);
+        assert_eq!(unplayed.players, 0);
+        assert_eq!(unplayed.last_played_at, None);
+    }
+}
@@ -0,0 +1,134 @@
+use chrono::Utc;
+use data_encoding::BASE64;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use ring::hmac::{sign, Key, HMAC_SHA1_FOR_LEGACY_USE_ONLY};
+use std::env;
+
+use crate::db::schema::rooms;
+use crate::error::Error;
+use crate::metrics;
+
+use super::playing::get_playing;
+
+lazy_static! {
+    // Shared secret of coturn `static-auth-secret`, relay is disabled when not set
+    static ref TURN_SECRET: Option<String> = env::var("TURN_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty());
+    // Comma separated, e.g. `turn:turn.example.com:3478?transport=udp`
+    static ref TURN_URLS: Vec<String> = env::var("TURN_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|url| url.trim().to_owned())
+        .filter(|url| !url.is_empty())
+        .collect();
+    // Short lived so credentials stop working soon after leaving the room,
+    // members refresh before expiry
+    static ref TURN_CREDENTIAL_TTL: i64 = env::var("TURN_CREDENTIAL_TTL_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(600);
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScRelayCredentialsReq {
+    pub room_id: i32,
+}
+
+#[derive(GraphQLObject)]
+pub struct ScRelayCredentials {
+    urls: Vec<String>,
+    username: String,
+    credential: String,
+    expires_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScReportConnectionReq {
+    pub room_id: i32,
+    pub relayed: bool,
+}
+
+/// coturn REST API credential: base64(HMAC-SHA1(secret, username))
+fn get_turn_credential(secret: &str, username: &str) -> String {
+    let key = Key::new(HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    BASE64.encode(sign(&key, username.as_bytes()).as_ref())
+}
+
+fn check_room_member(conn: &PgConnection, uid: i32, rid: i32) -> FieldResult<()> {
+    if get_playing(conn, uid).map(|room| room.id) != Some(rid) {
+        return Err(FieldError::new(
+            "not in the room",
+            Error::permission_denied(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_relay_credentials(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScRelayCredentialsReq,
+) -> FieldResult<ScRelayCredentials> {
+    check_room_member(conn, uid, req.room_id)?;
+
+    let secret = match TURN_SECRET.as_ref() {
+        Some(secret) if !TURN_URLS.is_empty() => secret,
+        _ => {
+            return Err(FieldError::new(
+                "relay is not configured",
+                Error::relay_not_configured(),
+            ))
+        }
+    };
+
+    let expires_at = Utc::now().timestamp() + *TURN_CREDENTIAL_TTL;
+    let username = format!("{}:{}", expires_at, uid);
+
+    Ok(ScRelayCredentials {
+        urls: TURN_URLS.clone(),
+        credential: get_turn_credential(secret, &username),
+        username,
+        expires_at: (expires_at * 1000) as f64,
+    })
+}
+
+/// Record the connection type a member settled on
+pub fn report_connection(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScReportConnectionReq,
+) -> FieldResult<()> {
+    use self::rooms::dsl::*;
+
+    check_room_member(conn, uid, req.room_id)?;
+
+    if req.relayed {
+        diesel::update(rooms.filter(id.eq(req.room_id)))
+            .set(relayed.eq(true))
+            .execute(conn)?;
+    }
+
+    metrics::inc_counter(
+        "nesbox_webrtc_sessions_total",
+        if req.relayed { "relay" } else { "direct" },
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::relay::get_turn_credential;
+
+    #[test]
+    fn turn_credential() {
+        // RFC 2202 test case 2
+        assert_eq!(
+            get_turn_credential("Jefe", "what do ya want for nothing?"),
+            "7/zfauXrL6LSdBbV8YTfnCWafHk="
+        );
+    }
+}
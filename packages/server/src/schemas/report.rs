@@ -0,0 +1,203 @@
+use chrono::{Duration, Utc};
+use diesel::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::env;
+use std::str::FromStr;
+
+use crate::db::models::{NewReport, Report};
+use crate::db::schema::reports;
+use crate::error::Error;
+use crate::moderation::ScTextKind;
+
+use super::friend::sanitize_text;
+use super::user::{get_user_basic, ScUserBasic};
+
+const GAME_PROBLEM: &str = "game_problem";
+
+lazy_static! {
+    // Problem reports of a user in an hour, every one updates the issue comment
+    static ref MAX_REPORTS_PER_HOUR: i64 = env::var("MAX_REPORTS_PER_HOUR")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(5);
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScReportGameProblem {
+    pub game_id: i32,
+    pub description: String,
+}
+
+/// Text flagged by moderation, waiting for review
+#[derive(GraphQLObject)]
+pub struct ScModerationReport {
+    pub id: i32,
+    pub user: ScUserBasic,
+    pub kind: ScTextKind,
+    // the comment's game
+    pub game_id: Option<i32>,
+    // message id, room id of a command, user id of a username
+    pub target_id: Option<i32>,
+    pub text: String,
+    // filter that flagged it
+    pub reason: Option<String>,
+    pub created_at: f64,
+}
+
+/// Players who reported the game in the last hour
+pub fn get_recent_report_count(conn: &PgConnection, gid: i32) -> i64 {
+    use self::reports::dsl::*;
+
+    reports
+        .select(sql::<BigInt>("COUNT(DISTINCT user_id)"))
+        .filter(game_id.eq(gid))
+        .filter(kind.eq(GAME_PROBLEM))
+        .filter(created_at.gt(Utc::now().naive_utc() - Duration::hours(1)))
+        .get_result::<i64>(conn)
+        .unwrap_or_default()
+}
+
+/// Return the sanitized description, `None` when the user already reported
+/// the game in the last hour so the issue comment isn't touched again
+pub fn create_report(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScReportGameProblem,
+) -> FieldResult<Option<String>> {
+    use self::reports::dsl::*;
+
+    let text = sanitize_text(&Some(req.description.clone()), 1000).ok_or(FieldError::new(
+        "description is empty",
+        Error::empty_report(),
+    ))?;
+
+    let recent = reports
+        .select(game_id)
+        .filter(user_id.eq(uid))
+        .filter(kind.eq(GAME_PROBLEM))
+        .filter(created_at.gt(Utc::now().naive_utc() - Duration::hours(1)))
+        .get_results::<Option<i32>>(conn)?;
+    if recent.contains(&Some(req.game_id)) {
+        return Ok(None);
+    }
+    if recent.len() as i64 >= *MAX_REPORTS_PER_HOUR {
+        return Err(FieldError::new(
+            "too many reports",
+            Error::report_too_fast(),
+        ));
+    }
+
+    let new_report = NewReport {
+        user_id: uid,
+        game_id: Some(req.game_id),
+        description: &text,
+        created_at: Utc::now().naive_utc(),
+        kind: GAME_PROBLEM,
+        target_id: None,
+        reason: None,
+    };
+
+    diesel::insert_into(reports)
+        .values(&new_report)
+        .execute(conn)?;
+
+    Ok(Some(text))
+}
+
+/// The text is already stored or sent, so a failure is only logged
+pub fn flag_text(
+    conn: &PgConnection,
+    uid: i32,
+    text_kind: ScTextKind,
+    text: &str,
+    filter: &str,
+    gid: Option<i32>,
+    target: Option<i32>,
+) {
+    let text_kind_name = text_kind.to_string();
+    let new_report = NewReport {
+        user_id: uid,
+        game_id: gid,
+        description: text,
+        created_at: Utc::now().naive_utc(),
+        kind: &text_kind_name,
+        target_id: target,
+        reason: Some(filter),
+    };
+
+    if let Err(err) = diesel::insert_into(reports::table)
+        .values(&new_report)
+        .execute(conn)
+    {
+        log::error!("Flag {} of user {}: {}", text_kind, uid, err);
+    }
+}
+
+/// Latest first, reports of banned users are left out
+pub fn get_moderation_reports(
+    conn: &PgConnection,
+    limit: i64,
+) -> FieldResult<Vec<ScModerationReport>> {
+    use self::reports::dsl::*;
+
+    let list = reports
+        .filter(kind.ne(GAME_PROBLEM))
+        .order(created_at.desc())
+        .limit(limit)
+        .get_results::<Report>(conn)?;
+
+    Ok(list
+        .iter()
+        .filter_map(|report| {
+            Some(ScModerationReport {
+                id: report.id,
+                user: get_user_basic(conn, report.user_id).ok()?,
+                kind: ScTextKind::from_str(&report.kind).ok()?,
+                game_id: report.game_id,
+                target_id: report.target_id,
+                text: report.description.clone(),
+                reason: report.reason.clone(),
+                created_at: report.created_at.timestamp_millis() as f64,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::{insert_game, insert_user, test_conn};
+    use crate::schemas::report::*;
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn report_limits() {
+        let conn = test_conn();
+        let a = insert_user(&conn, "report_a");
+        let b = insert_user(&conn, "report_b");
+        let games: Vec<i32> = (0..=*MAX_REPORTS_PER_HOUR)
+            .map(|i| insert_game(&conn, &format!("report_{}", i)))
+            .collect();
+        let report = |uid: i32, gid: i32| {
+            let req = ScReportGameProblem {
+                game_id: gid,
+                description: "black screen".into(),
+            };
+            create_report(&conn, uid, &req)
+        };
+
+        assert!(report(a, games[0]).unwrap().is_some());
+        // Again within the hour is dropped and not counted twice
+        assert!(report(a, games[0]).unwrap().is_none());
+        assert!(report(b, games[0]).unwrap().is_some());
+        assert_eq!(get_recent_report_count(&conn, games[0]), 2);
+
+        for gid in &games[1..games.len() - 1] {
+            assert!(report(a, *gid).unwrap().is_some());
+        }
+        let err = report(a, games[games.len() - 1]).unwrap_err();
+        assert_eq!(err.extensions().to_owned(), Error::report_too_fast());
+    }
+}
@@ -0,0 +1,253 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Timelike, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLEnum};
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::Mutex;
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+
+use crate::db::schema::{games, users};
+use crate::error::Error;
+
+/// What accounts of minors can't do, `MINOR_RESTRICTIONS`
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Display, EnumString, EnumIter)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScRestriction {
+    // no public rooms during the night hours
+    NightHosting,
+    // the lobby chat can be read but not written
+    LobbyReadOnly,
+    // games marked mature are hidden and can't be played
+    MatureGames,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestrictionConfig {
+    // younger users are minors
+    pub minor_age: u32,
+    pub restrictions: Vec<ScRestriction>,
+    // local hours, the night wraps around midnight when start > end
+    pub night_start: u32,
+    pub night_end: u32,
+    // there's no timezone per user, one for the whole deployment
+    pub utc_offset: FixedOffset,
+}
+
+impl RestrictionConfig {
+    /// `MINOR_*` variables, every restriction applies by default,
+    /// an empty `MINOR_RESTRICTIONS` lifts them all
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let hour = |name, default| {
+            var(name)
+                .and_then(|value| value.parse::<u32>().ok())
+                .filter(|value| *value < 24)
+                .unwrap_or(default)
+        };
+
+        RestrictionConfig {
+            minor_age: var("MINOR_AGE")
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(18),
+            restrictions: match var("MINOR_RESTRICTIONS") {
+                Some(value) => value
+                    .split(',')
+                    .filter_map(|name| ScRestriction::from_str(name.trim()).ok())
+                    .collect(),
+                None => ScRestriction::iter().collect(),
+            },
+            night_start: hour("MINOR_NIGHT_START", 22),
+            night_end: hour("MINOR_NIGHT_END", 6),
+            utc_offset: var("MINOR_UTC_OFFSET_MINUTES")
+                .and_then(|value| value.parse::<i32>().ok())
+                .and_then(|minutes| FixedOffset::east_opt(minutes * 60))
+                .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap()),
+        }
+    }
+
+    pub fn is_night(&self, now: DateTime<Utc>) -> bool {
+        let hour = now.with_timezone(&self.utc_offset).hour();
+        if self.night_start <= self.night_end {
+            (self.night_start..self.night_end).contains(&hour)
+        } else {
+            hour >= self.night_start || hour < self.night_end
+        }
+    }
+
+    pub fn today(&self, now: DateTime<Utc>) -> NaiveDate {
+        now.with_timezone(&self.utc_offset).date_naive()
+    }
+
+    pub fn is_minor(&self, birth: Option<NaiveDate>, now: DateTime<Utc>) -> bool {
+        birth.map_or(false, |birth| {
+            get_age(birth, self.today(now)) < self.minor_age as i32
+        })
+    }
+
+    /// Adults and users without a birthdate are never restricted
+    pub fn applies(&self, restriction: ScRestriction, is_minor: bool, now: DateTime<Utc>) -> bool {
+        if !is_minor || !self.restrictions.contains(&restriction) {
+            return false;
+        }
+        match restriction {
+            ScRestriction::NightHosting => self.is_night(now),
+            ScRestriction::LobbyReadOnly | ScRestriction::MatureGames => true,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref RESTRICTION_CONFIG: RestrictionConfig =
+        RestrictionConfig::from_vars(|name| env::var(name).ok());
+    // user_id -> birthdate, it is set once so it never goes stale
+    static ref BIRTHDATES: Mutex<HashMap<i32, Option<NaiveDate>>> = Mutex::new(HashMap::new());
+}
+
+/// Full years
+pub fn get_age(birth: NaiveDate, today: NaiveDate) -> i32 {
+    let mut age = today.year() - birth.year();
+    if (today.month(), today.day()) < (birth.month(), birth.day()) {
+        age -= 1;
+    }
+    age
+}
+
+/// `YYYY-MM-DD`, not in the future
+pub fn parse_birthdate(value: &str, today: NaiveDate) -> FieldResult<NaiveDate> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d")
+        .ok()
+        .filter(|birth| *birth <= today && birth.year() >= 1900)
+        .ok_or_else(|| FieldError::new("invalid birthdate", Error::validation()))
+}
+
+/// `None` when not loaded yet, then `load_birthdate` on a blocking thread
+pub fn get_cached_birthdate(uid: i32) -> Option<Option<NaiveDate>> {
+    BIRTHDATES.lock().unwrap().get(&uid).copied()
+}
+
+pub fn load_birthdate(conn: &PgConnection, uid: i32) -> QueryResult<Option<NaiveDate>> {
+    use self::users::dsl::*;
+
+    let birth = users
+        .select(birthdate)
+        .filter(id.eq(uid))
+        .get_result::<Option<NaiveDate>>(conn)
+        .optional()?
+        .flatten();
+    BIRTHDATES.lock().unwrap().insert(uid, birth);
+    Ok(birth)
+}
+
+/// Only once, a minor can't become an adult by changing it
+pub fn set_birthdate(conn: &PgConnection, uid: i32, value: &str) -> FieldResult<()> {
+    use self::users::dsl::*;
+
+    let birth = parse_birthdate(value, RESTRICTION_CONFIG.today(Utc::now()))?;
+    let count = diesel::update(users.filter(id.eq(uid)).filter(birthdate.is_null()))
+        .set(birthdate.eq(birth))
+        .execute(conn)?;
+    if count == 0 {
+        return Err(FieldError::new("birthdate already set", Error::conflict()));
+    }
+    BIRTHDATES.lock().unwrap().insert(uid, Some(birth));
+    Ok(())
+}
+
+pub fn get_mature_game_ids(conn: &PgConnection) -> Vec<i32> {
+    use self::games::dsl::*;
+
+    games
+        .select(id)
+        .filter(mature.eq(true))
+        .load::<i32>(conn)
+        .unwrap_or_default()
+}
+
+pub fn is_mature_game(conn: &PgConnection, gid: i32) -> bool {
+    use self::games::dsl::*;
+
+    games
+        .select(mature)
+        .filter(id.eq(gid))
+        .get_result::<bool>(conn)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::restriction::*;
+    use chrono::TimeZone;
+
+    fn derive(vars: &[(&str, &str)]) -> RestrictionConfig {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        RestrictionConfig::from_vars(|name| vars.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    fn minor_restrictions() {
+        let config = derive(&[]);
+        let noon = Utc.with_ymd_and_hms(2022, 12, 23, 12, 0, 0).unwrap();
+        let night = Utc.with_ymd_and_hms(2022, 12, 23, 23, 0, 0).unwrap();
+        for restriction in ScRestriction::iter() {
+            assert!(!config.applies(restriction, false, noon));
+            assert!(!config.applies(restriction, false, night));
+            assert!(config.applies(restriction, true, night));
+        }
+        // Hosting is only restricted at night
+        assert!(!config.applies(ScRestriction::NightHosting, true, noon));
+        assert!(config.applies(ScRestriction::LobbyReadOnly, true, noon));
+        assert!(config.applies(ScRestriction::MatureGames, true, noon));
+
+        // Turned off one by one
+        let config = derive(&[("MINOR_RESTRICTIONS", "mature_games, unknown")]);
+        assert!(!config.applies(ScRestriction::NightHosting, true, night));
+        assert!(!config.applies(ScRestriction::LobbyReadOnly, true, night));
+        assert!(config.applies(ScRestriction::MatureGames, true, night));
+        let config = derive(&[("MINOR_RESTRICTIONS", "")]);
+        assert!(ScRestriction::iter().all(|r| !config.applies(r, true, night)));
+    }
+
+    #[test]
+    fn minor_night_hours() {
+        let at = |hour| Utc.with_ymd_and_hms(2022, 12, 23, hour, 30, 0).unwrap();
+        let config = derive(&[]);
+        assert!(config.is_night(at(22)));
+        assert!(config.is_night(at(5)));
+        assert!(!config.is_night(at(6)));
+        assert!(!config.is_night(at(21)));
+        // Local hours, 23:30 UTC is 07:30 at UTC+8
+        let config = derive(&[("MINOR_UTC_OFFSET_MINUTES", "480")]);
+        assert!(!config.is_night(at(23)));
+        assert!(config.is_night(at(14)));
+        // Not wrapping
+        let config = derive(&[("MINOR_NIGHT_START", "1"), ("MINOR_NIGHT_END", "5")]);
+        assert!(config.is_night(at(1)));
+        assert!(!config.is_night(at(23)));
+    }
+
+    #[test]
+    fn minor_age() {
+        let birth = NaiveDate::from_ymd_opt(2004, 12, 24).unwrap();
+        assert_eq!(
+            get_age(birth, NaiveDate::from_ymd_opt(2022, 12, 23).unwrap()),
+            17
+        );
+        assert_eq!(
+            get_age(birth, NaiveDate::from_ymd_opt(2022, 12, 24).unwrap()),
+            18
+        );
+
+        let config = derive(&[]);
+        let now = Utc.with_ymd_and_hms(2022, 12, 23, 12, 0, 0).unwrap();
+        assert!(config.is_minor(Some(birth), now));
+        assert!(!config.is_minor(None, now));
+        assert!(!derive(&[("MINOR_AGE", "16")]).is_minor(Some(birth), now));
+
+        let today = NaiveDate::from_ymd_opt(2022, 12, 23).unwrap();
+        assert_eq!(parse_birthdate(" 2004-12-24 ", today).unwrap(), birth);
+        assert!(parse_birthdate("2022-12-24", today).is_err());
+        assert!(parse_birthdate("1899-12-31", today).is_err());
+        assert!(parse_birthdate("24/12/2004", today).is_err());
+    }
+}
@@ -0,0 +1,451 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Integer, Nullable, Timestamp};
+use juniper::{FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use std::str::FromStr;
+use std::string::ToString;
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+
+use crate::db::models::{NewRetentionPolicy, RetentionPolicy};
+use crate::db::schema::retention_policies;
+
+use super::login_session::{close_sessions, revoke_users_sessions, ScSessionCloseReason};
+
+const RETENTION_BATCH_SIZE: i64 = 1000;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, EnumIter, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScRetentionCategory {
+    Messages,
+    Notifications,
+    WebhookDeliveries,
+    Reports,
+    // admin audit log, its trigger refuses deletes the policy doesn't cover
+    SecurityEvents,
+    // revoked login sessions, expired ones are pruned regardless
+    SessionsHistory,
+    InactiveAccounts,
+}
+
+impl ScRetentionCategory {
+    /// Table, age expression and extra filter of the rows the policy applies to
+    fn source(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            ScRetentionCategory::Messages => ("messages", "messages.created_at", ""),
+            ScRetentionCategory::Notifications => ("notifications", "notifications.created_at", ""),
+            // pending deliveries are still owned by the delivery worker
+            ScRetentionCategory::WebhookDeliveries => (
+                "webhook_deliveries",
+                "webhook_deliveries.updated_at",
+                "AND webhook_deliveries.status <> 'pending'",
+            ),
+            ScRetentionCategory::Reports => ("reports", "reports.created_at", ""),
+            ScRetentionCategory::SecurityEvents => ("audit_logs", "audit_logs.created_at", ""),
+            ScRetentionCategory::SessionsHistory => (
+                "login_sessions",
+                "login_sessions.revoked_at",
+                "AND login_sessions.revoked_at IS NOT NULL",
+            ),
+            // never seen is counted from sign up
+            ScRetentionCategory::InactiveAccounts => (
+                "users",
+                "COALESCE(users.last_seen_at, users.created_at)",
+                "AND users.deleted_at IS NULL AND users.anonymized_at IS NULL AND users.role <> 'admin'",
+            ),
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRetentionStatus {
+    category: ScRetentionCategory,
+    // null when kept forever
+    days: Option<i32>,
+    anonymize: bool,
+    row_count: f64,
+    oldest_at: Option<f64>,
+    updated_at: Option<f64>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScUpdateRetentionPolicy {
+    pub category: ScRetentionCategory,
+    // null to keep forever
+    pub days: Option<i32>,
+    // only for inactive accounts, delete their content when false
+    pub anonymize: Option<bool>,
+}
+
+#[derive(QueryableByName)]
+struct RetentionCountRow {
+    #[sql_type = "BigInt"]
+    count: i64,
+    #[sql_type = "Nullable<Timestamp>"]
+    oldest: Option<NaiveDateTime>,
+}
+
+#[derive(QueryableByName)]
+struct RetentionIdRow {
+    #[sql_type = "Integer"]
+    id: i32,
+}
+
+fn get_policies(conn: &PgConnection) -> Vec<RetentionPolicy> {
+    use self::retention_policies::dsl::*;
+
+    retention_policies.load::<RetentionPolicy>(conn).unwrap()
+}
+
+fn get_category_status(
+    conn: &PgConnection,
+    category: ScRetentionCategory,
+) -> QueryResult<RetentionCountRow> {
+    let (table, age, filter) = category.source();
+    let sql = format!(
+        "SELECT COUNT(*) AS count, MIN({age}) AS oldest FROM {table} WHERE TRUE {filter}",
+        age = age,
+        table = table,
+        filter = filter,
+    );
+
+    diesel::sql_query(sql).get_result::<RetentionCountRow>(conn)
+}
+
+pub fn get_retention_status(conn: &PgConnection) -> FieldResult<Vec<ScRetentionStatus>> {
+    let policies = get_policies(conn);
+
+    ScRetentionCategory::iter()
+        .map(|category| {
+            let policy = policies
+                .iter()
+                .find(|policy| policy.category == category.to_string());
+            let row = get_category_status(conn, category)?;
+            Ok(ScRetentionStatus {
+                category,
+                days: policy.map(|policy| policy.days),
+                anonymize: policy.map_or(false, |policy| policy.anonymize),
+                row_count: row.count as f64,
+                oldest_at: row.oldest.map(|at| at.timestamp_millis() as f64),
+                updated_at: policy.map(|policy| policy.updated_at.timestamp_millis() as f64),
+            })
+        })
+        .collect()
+}
+
+pub fn update_retention_policy(
+    conn: &PgConnection,
+    req: &ScUpdateRetentionPolicy,
+) -> FieldResult<Vec<ScRetentionStatus>> {
+    use self::retention_policies::dsl::*;
+
+    let name = req.category.to_string();
+    match req.days {
+        Some(d) => {
+            let new_policy = NewRetentionPolicy {
+                category: &name,
+                days: d.max(1),
+                anonymize: req.anonymize.unwrap_or(true)
+                    && req.category == ScRetentionCategory::InactiveAccounts,
+                updated_at: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(retention_policies)
+                .values(&new_policy)
+                .on_conflict(category)
+                .do_update()
+                .set((
+                    days.eq(new_policy.days),
+                    anonymize.eq(new_policy.anonymize),
+                    updated_at.eq(new_policy.updated_at),
+                ))
+                .execute(conn)?;
+        }
+        None => {
+            diesel::delete(retention_policies.filter(category.eq(&name))).execute(conn)?;
+        }
+    }
+
+    get_retention_status(conn)
+}
+
+/// Delete content owned by the accounts, then scrub credentials, provider links
+/// and profile and end their sessions, rows stay so remaining references show
+/// "deleted user"
+fn anonymize_accounts(conn: &PgConnection, ids: &[i32], delete_content: bool) -> QueryResult<()> {
+    let reason = ScSessionCloseReason::AccountAnonymized;
+    let revoked = conn.transaction(|| {
+        if delete_content {
+            for sql in [
+                "DELETE FROM comments WHERE user_id = ANY($1)",
+                "DELETE FROM favorites WHERE user_id = ANY($1)",
+                "DELETE FROM friends WHERE user_id = ANY($1) OR target_id = ANY($1)",
+                "DELETE FROM messages WHERE user_id = ANY($1) OR target_id = ANY($1)",
+                "DELETE FROM message_archives WHERE user_id = ANY($1) OR target_id = ANY($1)",
+                "DELETE FROM notifications WHERE user_id = ANY($1)",
+                "DELETE FROM records WHERE user_id = ANY($1)",
+                "DELETE FROM reports WHERE user_id = ANY($1)",
+                "DELETE FROM save_states WHERE user_id = ANY($1)",
+                "DELETE FROM state_uploads WHERE user_id = ANY($1)",
+                "DELETE FROM user_achievements WHERE user_id = ANY($1)",
+            ] {
+                diesel::sql_query(sql)
+                    .bind::<Array<Integer>, _>(ids)
+                    .execute(conn)?;
+            }
+        }
+        diesel::sql_query("DELETE FROM oauth_accounts WHERE user_id = ANY($1)")
+            .bind::<Array<Integer>, _>(ids)
+            .execute(conn)?;
+        let revoked = revoke_users_sessions(conn, ids, reason)?;
+        diesel::sql_query(
+            r#"UPDATE users SET username = 'deleted_' || "id", nickname = 'deleted user',
+            password = '', settings = NULL, avatar = NULL, updated_at = $2, anonymized_at = $2
+            WHERE "id" = ANY($1)"#,
+        )
+        .bind::<Array<Integer>, _>(ids)
+        .bind::<Timestamp, _>(Utc::now().naive_utc())
+        .execute(conn)?;
+        Ok(revoked)
+    })?;
+
+    for (uid, sid, expires_at) in revoked {
+        close_sessions(uid, &[(sid, expires_at.timestamp())], reason);
+    }
+    Ok(())
+}
+
+fn get_inactive_account_ids(
+    conn: &PgConnection,
+    deadline: NaiveDateTime,
+    limit: i64,
+) -> QueryResult<Vec<i32>> {
+    let (table, age, filter) = ScRetentionCategory::InactiveAccounts.source();
+    let sql = format!(
+        r#"SELECT users."id" FROM {table} WHERE {age} < $1 {filter} LIMIT $2"#,
+        table = table,
+        age = age,
+        filter = filter,
+    );
+    Ok(diesel::sql_query(sql)
+        .bind::<Timestamp, _>(deadline)
+        .bind::<BigInt, _>(limit)
+        .load::<RetentionIdRow>(conn)?
+        .iter()
+        .map(|row| row.id)
+        .collect())
+}
+
+fn apply_policy_batch(
+    conn: &PgConnection,
+    policy: &RetentionPolicy,
+    category: ScRetentionCategory,
+) -> QueryResult<usize> {
+    let (table, age, filter) = category.source();
+    let deadline = Utc::now().naive_utc() - Duration::days(policy.days as i64);
+
+    if category == ScRetentionCategory::InactiveAccounts {
+        let ids = get_inactive_account_ids(conn, deadline, RETENTION_BATCH_SIZE)?;
+        if !ids.is_empty() {
+            anonymize_accounts(conn, &ids, !policy.anonymize)?;
+        }
+        return Ok(ids.len());
+    }
+
+    let sql = format!(
+        r#"DELETE FROM {table} WHERE "id" IN (
+            SELECT "id" FROM {table} WHERE {age} < $1 {filter} LIMIT $2
+        )"#,
+        table = table,
+        age = age,
+        filter = filter,
+    );
+    diesel::sql_query(sql)
+        .bind::<Timestamp, _>(deadline)
+        .bind::<BigInt, _>(RETENTION_BATCH_SIZE)
+        .execute(conn)
+}
+
+/// Enforce every configured policy in small batches, return affected rows per category
+pub fn apply_retention_policies(conn: &PgConnection) -> Vec<(ScRetentionCategory, usize)> {
+    get_policies(conn)
+        .iter()
+        .filter_map(|policy| {
+            let category = ScRetentionCategory::from_str(&policy.category).ok()?;
+            let mut total = 0;
+            loop {
+                match apply_policy_batch(conn, policy, category) {
+                    Ok(count) => {
+                        total += count;
+                        if (count as i64) < RETENTION_BATCH_SIZE {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Apply retention {}: {:?}", category, err);
+                        break;
+                    }
+                }
+            }
+            Some((category, total))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::fixtures::*;
+    use crate::schemas::retention::*;
+
+    /// Account with a password, signed up 30 days ago and seen `seen` days ago
+    fn account(conn: &PgConnection, name: &str, seen: Option<i64>, extra: &str) -> i32 {
+        let uid = insert_user(conn, name);
+        let now = Utc::now().naive_utc();
+        diesel::sql_query(format!(
+            r#"UPDATE users SET password = 'hash', created_at = $2, updated_at = $3,
+            last_seen_at = $4 {} WHERE "id" = $1"#,
+            extra
+        ))
+        .bind::<Integer, _>(uid)
+        .bind::<Timestamp, _>(now - Duration::days(30))
+        // profile edits don't count as activity
+        .bind::<Timestamp, _>(now)
+        .bind::<Nullable<Timestamp>, _>(seen.map(|days| now - Duration::days(days)))
+        .execute(conn)
+        .unwrap();
+        uid
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn inactive_account_selection() {
+        let conn = test_conn();
+        let stale = account(&conn, "retention-stale", Some(10), "");
+        let recent = account(&conn, "retention-recent", Some(2), "");
+        let never_seen = account(&conn, "retention-never-seen", None, "");
+        let admin = account(&conn, "retention-admin", Some(10), ", role = 'admin'");
+        let banned = account(&conn, "retention-banned", Some(10), ", deleted_at = NOW()");
+        let anonymized = account(
+            &conn,
+            "retention-anonymized",
+            Some(10),
+            ", anonymized_at = NOW()",
+        );
+        // Signed up with a provider
+        let oauth = account(&conn, "retention-oauth", Some(10), ", password = ''");
+
+        let selected = |days| {
+            let deadline = Utc::now().naive_utc() - Duration::days(days);
+            get_inactive_account_ids(&conn, deadline, i64::MAX).unwrap()
+        };
+        let ids = selected(5);
+        assert!(ids.contains(&stale));
+        assert!(ids.contains(&never_seen));
+        assert!(ids.contains(&oauth));
+        for uid in [recent, admin, banned, anonymized] {
+            assert!(!ids.contains(&uid));
+        }
+        let ids = selected(20);
+        assert!(!ids.contains(&stale));
+        assert!(ids.contains(&never_seen));
+
+        // Anonymized in the first run, not selected again
+        anonymize_accounts(&conn, &[stale], false).unwrap();
+        assert!(!selected(5).contains(&stale));
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn anonymized_oauth_login() {
+        use crate::auth::oauth::{OauthProfile, OauthProvider};
+        use crate::error::Error;
+        use crate::schemas::login_session::refresh_login_session;
+        use crate::schemas::oauth_account::oauth_login;
+
+        let conn = test_conn();
+        let profile = OauthProfile {
+            subject: "retention-oauth-subject".into(),
+            nickname: "retention".into(),
+            email: None,
+        };
+        let login = || oauth_login(&conn, OauthProvider::Github, &profile, None, None, "secret");
+        let first = login().unwrap().to_json();
+        let uid = first["userId"].as_i64().unwrap() as i32;
+        anonymize_accounts(&conn, &[uid], false).unwrap();
+
+        // The session can't be refreshed and the provider signs in a new account
+        let refresh_token = first["refreshToken"].as_str().unwrap();
+        assert!(refresh_login_session(&conn, refresh_token).is_err());
+        let again = login().unwrap().to_json();
+        assert_ne!(again["userId"], first["userId"]);
+
+        // A link left behind still doesn't sign in the anonymized account
+        diesel::sql_query(
+            r#"INSERT INTO oauth_accounts (user_id, provider, subject, created_at)
+            VALUES ($1, 'google', 'retention-oauth-subject', NOW())"#,
+        )
+        .bind::<Integer, _>(uid)
+        .execute(&conn)
+        .unwrap();
+        let err =
+            oauth_login(&conn, OauthProvider::Google, &profile, None, None, "secret").unwrap_err();
+        assert_eq!(
+            err.extensions().to_owned(),
+            Error::username_or_password_error()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn security_events_retention() {
+        use crate::schemas::audit::write_audit_log;
+
+        let conn = test_conn();
+        let admin = account(&conn, "retention-auditor", Some(0), "");
+        let uid = account(&conn, "retention-audited", Some(0), "");
+        write_audit_log(&conn, admin, uid, "request", "").unwrap();
+        diesel::sql_query(
+            r#"INSERT INTO audit_logs (actor_id, user_id, action, detail, created_at)
+            VALUES ($1, $2, 'request', '', $3)"#,
+        )
+        .bind::<Integer, _>(admin)
+        .bind::<Integer, _>(uid)
+        .bind::<Timestamp, _>(Utc::now().naive_utc() - Duration::days(40))
+        .execute(&conn)
+        .unwrap();
+        let logged = |conn: &PgConnection| {
+            diesel::sql_query(
+                r#"SELECT COUNT(*) AS count, MIN(created_at) AS oldest FROM audit_logs
+                WHERE user_id = $1"#,
+            )
+            .bind::<Integer, _>(uid)
+            .get_result::<RetentionCountRow>(conn)
+            .unwrap()
+            .count
+        };
+
+        // Without a policy the log stays append only
+        let delete_all = r#"DELETE FROM audit_logs WHERE user_id = $1"#;
+        let refused = conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::sql_query(delete_all)
+                .bind::<Integer, _>(uid)
+                .execute(&conn)
+        });
+        assert!(refused.is_err());
+        assert_eq!(logged(&conn), 2);
+
+        update_retention_policy(
+            &conn,
+            &ScUpdateRetentionPolicy {
+                category: ScRetentionCategory::SecurityEvents,
+                days: Some(30),
+                anonymize: None,
+            },
+        )
+        .unwrap();
+        let applied = apply_retention_policies(&conn);
+        assert!(applied.iter().any(|(category, count)| *category
+            == ScRetentionCategory::SecurityEvents
+            && *count >= 1));
+        assert_eq!(logged(&conn), 1);
+    }
+}
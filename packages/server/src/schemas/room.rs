@@ -1,23 +1,52 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use diesel::dsl::*;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use juniper::{FieldResult, GraphQLInputObject, GraphQLObject};
-
+use diesel::sql_types::{Integer, Nullable, Text, Timestamp};
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+
+use super::authority::{clear_room_authority, get_authority, get_suggested_authority};
+use super::game::{check_game_deprecated, check_play_mode, ScPlayMode};
 use super::invite::*;
 use super::notify::*;
 use super::playing::*;
 use super::record::*;
+use super::report::flag_text;
+use super::room_event::{change_room_members, clear_room_seq, ScRoomMemberAction};
+use super::room_join::{clear_room_joins, log_room_action, ScRoomLogAction};
+use super::spectator::*;
 use super::user::*;
-use crate::db::models::{NewRoom, Room};
+use crate::db::models::{NewRoom, Playing, Room};
 use crate::db::schema::rooms;
+use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
+use crate::screenshot::{decode_data_url, render_live_screenshot, LIVE_SCREENSHOTS};
+use crate::storage::{get_blob_hash, get_storage, Storage};
 
-#[derive(GraphQLObject, Debug, Clone)]
+#[derive(GraphQLObject, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ScRoomBasic {
     pub id: i32,
     pub game_id: i32,
     pub private: bool,
     pub host: i32,
+    pub allow_commands: bool,
+    pub has_password: bool,
+    pub spectator_policy: ScSpectatorPolicy,
+    pub spectator_count: i32,
+    // members who may kick and mute
+    pub moderators: Vec<i32>,
+    // member running the core, the host unless `setAuthority` chose another
+    pub authority: i32,
+    // lowest worst-case rtt among players, from recent `reportRoomStats`
+    pub suggested_authority: Option<i32>,
+    pub mode: ScPlayMode,
+    // seq of the room's last event
+    pub seq: f64,
     created_at: f64,
     updated_at: f64,
 }
@@ -32,12 +61,28 @@ pub struct ScRoom {
     updated_at: f64,
     users: Vec<ScUserBasic>,
     screenshot: Option<String>,
+    // any member fell back to the TURN relay
+    relayed: bool,
+    allow_commands: bool,
+    has_password: bool,
+    spectator_policy: ScSpectatorPolicy,
+    spectator_count: i32,
+    moderators: Vec<i32>,
+    authority: i32,
+    suggested_authority: Option<i32>,
+    pub mode: ScPlayMode,
 }
 
 #[derive(GraphQLInputObject)]
 pub struct ScNewRoom {
     pub game_id: i32,
     pub private: bool,
+    // one of the game's `playModes`
+    pub mode: ScPlayMode,
+    // members may send `sendRoomCommand` to the host
+    pub allow_commands: Option<bool>,
+    // private rooms only, lets anyone with it join without an invite
+    pub password: Option<String>,
 }
 
 #[derive(GraphQLInputObject)]
@@ -46,35 +91,138 @@ pub struct ScUpdateRoom {
     pub game_id: i32,
     pub private: bool,
     pub host: i32,
+    // unchanged when omitted, `CLOSED` evicts current spectators
+    pub spectator_policy: Option<ScSpectatorPolicy>,
 }
 
 #[derive(GraphQLInputObject)]
 pub struct ScUpdateRoomScreenshot {
     pub id: i32,
+    // base64 png or its data url, stored as a `/room-screenshot` thumbnail
     pub screenshot: String,
 }
 
-pub fn convert_to_sc_room_basic(room: &Room) -> ScRoomBasic {
+#[derive(GraphQLInputObject)]
+pub struct ScKickFromRoom {
+    pub room_id: i32,
+    pub user_id: i32,
+    pub ban_minutes: Option<i32>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScRoomCommandReq {
+    pub room_id: i32,
+    pub command: String,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRoomCommand {
+    pub room_id: i32,
+    pub user_id: i32,
+    pub command: String,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScMuteRoomUser {
+    pub room_id: i32,
+    pub user_id: i32,
+    pub muted: bool,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScSetRoomModerator {
+    pub room_id: i32,
+    pub user_id: i32,
+    pub enabled: bool,
+}
+
+const MAX_ROOM_COMMAND_LEN: usize = 1024;
+const ROOM_COMMANDS_PER_SECOND: usize = 5;
+
+lazy_static! {
+    // (room_id, user_id) -> ban expiration time
+    static ref ROOM_BANS: Mutex<HashMap<(i32, i32), DateTime<Utc>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // (room_id, user_id) muted by the host or a moderator, commands only
+    static ref ROOM_MUTES: Mutex<HashSet<(i32, i32)>> = {
+        let set = HashSet::new();
+        Mutex::new(set)
+    };
+    // (room_id, user_id) -> recent command times
+    static ref ROOM_COMMAND_TIMES: Mutex<HashMap<(i32, i32), VecDeque<Instant>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+pub fn is_banned_from_room(rid: i32, uid: i32) -> bool {
+    let mut map = ROOM_BANS.lock().unwrap();
+    map.retain(|_, expires| *expires > Utc::now());
+    map.contains_key(&(rid, uid))
+}
+
+pub fn check_room_ban(rid: i32, uid: i32) -> FieldResult<()> {
+    if is_banned_from_room(rid, uid) {
+        return Err(FieldError::new(
+            format!("{} banned from room {}", uid, rid),
+            Error::room_banned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Unknown values can only come from a newer release, show them as co-op
+fn get_room_mode(room: &Room) -> ScPlayMode {
+    ScPlayMode::from_str(&room.mode).unwrap_or(ScPlayMode::CoOp)
+}
+
+pub fn convert_to_sc_room_basic(conn: &PgConnection, room: &Room) -> ScRoomBasic {
+    let players = get_room_user_ids(conn, room.id);
+    let authority = get_authority(room.id, room.host, &players);
     ScRoomBasic {
         id: room.id,
         host: room.host,
         private: room.private,
         game_id: room.game_id,
+        allow_commands: room.allow_commands,
+        has_password: room.password.is_some(),
+        spectator_policy: ScSpectatorPolicy::from_str(&room.spectator_policy)
+            .unwrap_or(ScSpectatorPolicy::Open),
+        spectator_count: get_spectator_count(room.id),
+        moderators: get_room_moderator_ids(conn, room.id),
+        authority,
+        suggested_authority: get_suggested_authority(room.id, authority, &players),
+        mode: get_room_mode(room),
+        seq: room.event_seq as f64,
         created_at: room.created_at.timestamp_millis() as f64,
         updated_at: room.updated_at.timestamp_millis() as f64,
     }
 }
 
 pub fn convert_to_sc_room(conn: &PgConnection, room: &Room) -> ScRoom {
+    let players = get_room_user_ids(conn, room.id);
+    let authority = get_authority(room.id, room.host, &players);
     ScRoom {
         id: room.id,
         host: room.host,
         private: room.private,
         game_id: room.game_id,
         screenshot: room.screenshot.clone(),
+        relayed: room.relayed,
+        allow_commands: room.allow_commands,
+        has_password: room.password.is_some(),
+        spectator_policy: ScSpectatorPolicy::from_str(&room.spectator_policy)
+            .unwrap_or(ScSpectatorPolicy::Open),
+        spectator_count: get_spectator_count(room.id),
+        moderators: get_room_moderator_ids(conn, room.id),
+        authority,
+        suggested_authority: get_suggested_authority(room.id, authority, &players),
+        mode: get_room_mode(room),
         created_at: room.created_at.timestamp_millis() as f64,
         updated_at: room.updated_at.timestamp_millis() as f64,
-        users: get_room_user_ids(conn, room.id)
+        users: players
             .into_iter()
             .filter(|user_id| has_user(*user_id))
             .map(|user_id| get_user_basic(conn, user_id).unwrap())
@@ -87,7 +235,20 @@ pub fn get_room(conn: &PgConnection, rid: i32) -> FieldResult<ScRoomBasic> {
 
     let room = rooms.filter(id.eq(rid)).get_result::<Room>(conn)?;
 
-    Ok(convert_to_sc_room_basic(&room))
+    Ok(convert_to_sc_room_basic(conn, &room))
+}
+
+/// Constant time, rooms without a password never match
+pub fn check_room_password(conn: &PgConnection, rid: i32, input: &str) -> bool {
+    use self::rooms::dsl::*;
+
+    let stored = rooms
+        .filter(id.eq(rid))
+        .select(password)
+        .get_result::<Option<String>>(conn)
+        .ok()
+        .flatten();
+    verify_password(stored.as_deref(), input)
 }
 
 pub fn get_outdated_rooms(conn: &PgConnection) -> Vec<ScRoomBasic> {
@@ -99,18 +260,36 @@ pub fn get_outdated_rooms(conn: &PgConnection) -> Vec<ScRoomBasic> {
         .load::<Room>(conn)
         .unwrap()
         .iter()
-        .map(|room| convert_to_sc_room_basic(&room))
+        .map(|room| convert_to_sc_room_basic(conn, &room))
         .filter(|room| !has_user(room.host))
         .collect()
 }
 
-pub fn get_rooms(conn: &PgConnection) -> Vec<ScRoom> {
+pub fn get_rooms(
+    conn: &PgConnection,
+    tenant: Option<i32>,
+    gid: Option<i32>,
+    play_mode: Option<ScPlayMode>,
+) -> Vec<ScRoom> {
     use self::rooms::dsl::*;
 
-    rooms
+    let mut query = rooms
         .filter(deleted_at.is_null())
         .filter(private.eq(false))
         .order(created_at.desc())
+        .into_boxed();
+    query = match tenant {
+        Some(tid) => query.filter(tenant_id.eq(tid)),
+        None => query.filter(tenant_id.is_null()),
+    };
+    if let Some(gid) = gid {
+        query = query.filter(game_id.eq(gid));
+    }
+    if let Some(play_mode) = play_mode {
+        query = query.filter(mode.eq(play_mode.to_string()));
+    }
+
+    query
         .load::<Room>(conn)
         .unwrap()
         .iter()
@@ -119,7 +298,15 @@ pub fn get_rooms(conn: &PgConnection) -> Vec<ScRoom> {
         .collect()
 }
 
-pub fn create_room(conn: &PgConnection, uid: i32, req: &ScNewRoom) -> FieldResult<ScRoomBasic> {
+pub fn create_room(
+    conn: &PgConnection,
+    uid: i32,
+    tenant: Option<i32>,
+    req: &ScNewRoom,
+) -> FieldResult<ScRoomBasic> {
+    check_game_deprecated(conn, req.game_id)?;
+    check_play_mode(conn, req.game_id, req.mode)?;
+
     start_game(conn, uid, req.game_id);
 
     let new_room = NewRoom {
@@ -129,6 +316,14 @@ pub fn create_room(conn: &PgConnection, uid: i32, req: &ScNewRoom) -> FieldResul
         created_at: Utc::now().naive_utc(),
         updated_at: Utc::now().naive_utc(),
         host: uid,
+        tenant_id: tenant,
+        allow_commands: req.allow_commands.unwrap_or_default(),
+        password: req
+            .password
+            .as_deref()
+            .filter(|password| req.private && !password.is_empty())
+            .map(hash_password),
+        mode: req.mode.to_string(),
     };
 
     let room = diesel::insert_into(rooms::table)
@@ -137,7 +332,7 @@ pub fn create_room(conn: &PgConnection, uid: i32, req: &ScNewRoom) -> FieldResul
 
     enter_room(conn, uid, room.id);
 
-    Ok(convert_to_sc_room_basic(&room))
+    Ok(convert_to_sc_room_basic(conn, &room))
 }
 
 pub fn update_room(conn: &PgConnection, uid: i32, req: &ScUpdateRoom) -> FieldResult<ScRoomBasic> {
@@ -150,6 +345,8 @@ pub fn update_room(conn: &PgConnection, uid: i32, req: &ScUpdateRoom) -> FieldRe
         .get_result::<Room>(conn)?;
 
     if r.game_id != req.game_id {
+        check_game_deprecated(conn, req.game_id)?;
+        check_play_mode(conn, req.game_id, get_room_mode(&r))?;
         end_game(conn, uid, r.game_id);
         start_game(conn, uid, req.game_id);
     }
@@ -164,11 +361,21 @@ pub fn update_room(conn: &PgConnection, uid: i32, req: &ScUpdateRoom) -> FieldRe
         game_id.eq(req.game_id),
         host.eq(req.host),
         private.eq(req.private),
+        spectator_policy.eq(req
+            .spectator_policy
+            .map(|policy| policy.to_string())
+            .unwrap_or(r.spectator_policy)),
         updated_at.eq(Utc::now().naive_utc()),
     ))
     .get_result::<Room>(conn)?;
 
-    Ok(convert_to_sc_room_basic(&room))
+    Ok(convert_to_sc_room_basic(conn, &room))
+}
+
+#[derive(QueryableByName)]
+struct ReplacedScreenshotRow {
+    #[sql_type = "Nullable<Text>"]
+    screenshot: Option<String>,
 }
 
 pub fn update_room_screenshot(
@@ -176,21 +383,79 @@ pub fn update_room_screenshot(
     uid: i32,
     req: &ScUpdateRoomScreenshot,
 ) -> FieldResult<ScRoomBasic> {
-    use self::rooms::dsl::*;
+    replace_room_screenshot(conn, get_storage(), uid, req)
+}
 
-    let room = diesel::update(
-        rooms
-            .filter(deleted_at.is_null())
-            .filter(id.eq(req.id))
-            .filter(host.eq(uid)),
-    )
-    .set((
-        screenshot.eq(req.screenshot.clone()),
-        updated_at.eq(Utc::now().naive_utc()),
-    ))
-    .get_result::<Room>(conn)?;
+fn replace_room_screenshot(
+    conn: &PgConnection,
+    storage: Option<&dyn Storage>,
+    uid: i32,
+    req: &ScUpdateRoomScreenshot,
+) -> FieldResult<ScRoomBasic> {
+    // Without storage the value is kept as sent
+    let (value, data) = match storage {
+        Some(_) => {
+            let data = decode_data_url(&req.screenshot)
+                .and_then(|source| render_live_screenshot(&source))
+                .map_err(|err| FieldError::new(err, Error::validation()))?;
+            (
+                LIVE_SCREENSHOTS.get_url(req.id, &get_blob_hash(&data)),
+                Some(data),
+            )
+        }
+        None => (req.screenshot.clone(), None),
+    };
 
-    Ok(convert_to_sc_room_basic(&room))
+    let previous = conn.transaction(|| {
+        // The locked row gives the value being replaced
+        let replaced = diesel::sql_query(
+            r#"UPDATE rooms SET screenshot = $1, updated_at = $2
+            FROM (SELECT "id", screenshot FROM rooms WHERE "id" = $3 FOR UPDATE) previous
+            WHERE rooms."id" = previous."id" AND rooms.host = $4 AND rooms.deleted_at IS NULL
+            RETURNING previous.screenshot"#,
+        )
+        .bind::<Text, _>(&value)
+        .bind::<Timestamp, _>(Utc::now().naive_utc())
+        .bind::<Integer, _>(req.id)
+        .bind::<Integer, _>(uid)
+        .get_result::<ReplacedScreenshotRow>(conn)
+        .optional()?
+        .ok_or_else(|| {
+            FieldError::new(
+                format!("{} not host of room {}", uid, req.id),
+                Error::permission_denied(),
+            )
+        })?;
+        // A failed upload leaves the room as it was
+        if let (Some(storage), Some(data)) = (storage, &data) {
+            LIVE_SCREENSHOTS
+                .put(storage, req.id, data)
+                .map_err(|err| FieldError::new(err.to_string(), Error::internal()))?;
+        }
+        Ok::<_, FieldError>(replaced.screenshot)
+    })?;
+    if let (Some(storage), Some(previous)) = (storage, previous.filter(|p| *p != value)) {
+        LIVE_SCREENSHOTS.delete_url(storage, &previous);
+    }
+
+    get_room(conn, req.id)
+}
+
+/// Newest thumbnail of a public room per game, for lobby previews
+pub fn get_live_screenshots(conn: &PgConnection) -> HashMap<i32, String> {
+    use self::rooms::dsl::*;
+
+    rooms
+        .select((game_id, screenshot))
+        .filter(deleted_at.is_null())
+        .filter(private.eq(false))
+        .filter(screenshot.like(format!("{}/%", LIVE_SCREENSHOTS.route)))
+        .order(updated_at.asc())
+        .load::<(i32, Option<String>)>(conn)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(gid, url)| Some((gid, url?)))
+        .collect()
 }
 
 pub fn delete_room(conn: &PgConnection, rid: i32) {
@@ -200,9 +465,16 @@ pub fn delete_room(conn: &PgConnection, rid: i32) {
         for user_id in get_room_user_ids(conn, rid) {
             end_game(conn, user_id, room.game_id);
         }
+        if let (Some(storage), Some(url)) = (get_storage(), &room.screenshot) {
+            LIVE_SCREENSHOTS.delete_url(storage, url);
+        }
     }
 
     delete_playing_with_room(conn, rid);
+    clear_room_joins(rid);
+    close_spectators(rid);
+    clear_room_authority(rid);
+    clear_room_seq(rid);
 
     diesel::delete(rooms.filter(id.eq(rid)))
         .execute(conn)
@@ -216,8 +488,17 @@ pub fn enter_room(conn: &PgConnection, uid: i32, rid: i32) {
         start_game(conn, uid, room.game_id);
     }
 
-    delete_playing(conn, uid);
-    create_playing(conn, uid, rid).ok();
+    remove_spectator(uid);
+    // A seat in another room is given up first
+    if let Some(previous) = get_playing_room_id(conn, uid).filter(|previous| *previous != rid) {
+        change_room_members(conn, previous, uid, ScRoomMemberAction::Leave, || {
+            delete_playing(conn, uid)
+        });
+    }
+    change_room_members(conn, rid, uid, ScRoomMemberAction::Join, || {
+        delete_playing(conn, uid);
+        create_playing(conn, uid, rid).ok();
+    });
     delete_invite(conn, uid, true);
 }
 
@@ -228,6 +509,396 @@ pub fn leave_room(conn: &PgConnection, uid: i32, rid: i32) {
         end_game(conn, uid, room.game_id);
     }
 
-    delete_playing(conn, uid);
+    change_room_members(conn, rid, uid, ScRoomMemberAction::Leave, || {
+        delete_playing(conn, uid)
+    });
     delete_invite(conn, uid, false);
 }
+
+/// Moderators act on members, not on the host or each other
+fn check_room_moderator(
+    conn: &PgConnection,
+    room: &ScRoomBasic,
+    uid: i32,
+    target: i32,
+) -> FieldResult<()> {
+    if !is_room_moderator(conn, room.id, uid) {
+        return Err(FieldError::new(
+            format!("{} not room host or moderator", uid),
+            Error::permission_denied(),
+        ));
+    }
+    if target == room.host || is_room_moderator(conn, room.id, target) {
+        return Err(FieldError::new(
+            "can't moderate room host or moderator",
+            Error::permission_denied(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn kick_from_room(conn: &PgConnection, uid: i32, req: &ScKickFromRoom) -> FieldResult<()> {
+    let room = get_room(conn, req.room_id)?;
+
+    if req.user_id == room.host {
+        return Err(FieldError::new(
+            "can't kick room host",
+            Error::permission_denied(),
+        ));
+    }
+
+    if room.host != uid && !is_admin(conn, uid) {
+        check_room_moderator(conn, &room, uid, req.user_id)?;
+    }
+
+    if !get_room_user_ids(conn, room.id).contains(&req.user_id) {
+        return Err(FieldError::new(
+            format!("{} not playing", req.user_id),
+            Error::username_not_playing(),
+        ));
+    }
+
+    leave_room(conn, req.user_id, room.id);
+
+    if let Some(minutes) = req.ban_minutes.filter(|minutes| *minutes > 0) {
+        ROOM_BANS.lock().unwrap().insert(
+            (room.id, req.user_id),
+            Utc::now() + Duration::minutes(minutes.into()),
+        );
+    }
+
+    log_room_action(room.id, uid, req.user_id, ScRoomLogAction::Kick, Utc::now());
+
+    Ok(())
+}
+
+/// Sliding window over the last second, drop times that fell out of it
+fn allow_room_command(times: &mut VecDeque<Instant>, now: Instant) -> bool {
+    while let Some(time) = times.front() {
+        if now.duration_since(*time) < StdDuration::from_secs(1) {
+            break;
+        }
+        times.pop_front();
+    }
+    if times.len() >= ROOM_COMMANDS_PER_SECOND {
+        return false;
+    }
+    times.push_back(now);
+    true
+}
+
+pub fn mute_room_user(conn: &PgConnection, uid: i32, req: &ScMuteRoomUser) -> FieldResult<()> {
+    let room = get_room(conn, req.room_id)?;
+
+    if room.host != uid {
+        check_room_moderator(conn, &room, uid, req.user_id)?;
+    }
+
+    let mut set = ROOM_MUTES.lock().unwrap();
+    let action = if req.muted {
+        set.insert((room.id, req.user_id));
+        ScRoomLogAction::Mute
+    } else {
+        set.remove(&(room.id, req.user_id));
+        ScRoomLogAction::Unmute
+    };
+    log_room_action(room.id, uid, req.user_id, action, Utc::now());
+
+    Ok(())
+}
+
+/// Host only, the flag is cleared when the member leaves
+pub fn update_room_moderator(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScSetRoomModerator,
+) -> FieldResult<()> {
+    let room = get_room(conn, req.room_id)?;
+
+    if room.host != uid {
+        return Err(FieldError::new(
+            format!("{} not room host", uid),
+            Error::permission_denied(),
+        ));
+    }
+
+    if req.user_id == room.host || !set_room_moderator(conn, room.id, req.user_id, req.enabled) {
+        return Err(FieldError::new(
+            format!("{} not playing", req.user_id),
+            Error::username_not_playing(),
+        ));
+    }
+
+    let action = if req.enabled {
+        ScRoomLogAction::AddModerator
+    } else {
+        ScRoomLogAction::RemoveModerator
+    };
+    log_room_action(room.id, uid, req.user_id, action, Utc::now());
+
+    Ok(())
+}
+
+/// Successor of a leaving host among the remaining members,
+/// online moderators first, then the member who joined earliest
+pub fn pick_next_host(members: &[Playing], is_online: impl Fn(i32) -> bool) -> Option<i32> {
+    members
+        .iter()
+        .filter(|member| is_online(member.user_id))
+        .min_by_key(|member| (!member.moderator, member.created_at))
+        .map(|member| member.user_id)
+}
+
+/// Hand the room over, the new host is no longer listed as a moderator
+pub fn transfer_room_host(conn: &PgConnection, rid: i32, uid: i32) -> FieldResult<ScRoomBasic> {
+    use self::rooms::dsl::*;
+
+    set_room_moderator(conn, rid, uid, false);
+    let room = diesel::update(rooms.filter(deleted_at.is_null()).filter(id.eq(rid)))
+        .set((host.eq(uid), updated_at.eq(Utc::now().naive_utc())))
+        .get_result::<Room>(conn)?;
+
+    Ok(convert_to_sc_room_basic(conn, &room))
+}
+
+/// Validate a command for the room host, the host's client decides what it means
+pub fn check_room_command(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScRoomCommandReq,
+) -> FieldResult<ScRoomCommand> {
+    let room = get_room(conn, req.room_id)?;
+
+    if !room.allow_commands {
+        return Err(FieldError::new(
+            format!("room {} does not accept commands", room.id),
+            Error::room_commands_disabled(),
+        ));
+    }
+
+    if !get_room_user_ids(conn, room.id).contains(&uid) {
+        return Err(FieldError::new(
+            format!("{} not playing", uid),
+            Error::username_not_playing(),
+        ));
+    }
+
+    if ROOM_MUTES.lock().unwrap().contains(&(room.id, uid)) {
+        return Err(FieldError::new(
+            format!("{} muted in room {}", uid, room.id),
+            Error::room_muted(),
+        ));
+    }
+
+    if req.command.is_empty() || req.command.len() > MAX_ROOM_COMMAND_LEN {
+        return Err(FieldError::new(
+            format!("command must be 1 to {} bytes", MAX_ROOM_COMMAND_LEN),
+            Error::validation(),
+        ));
+    }
+
+    let filter = check_text(ScTextKind::RoomCommand, &req.command)?;
+
+    let allowed = allow_room_command(
+        ROOM_COMMAND_TIMES
+            .lock()
+            .unwrap()
+            .entry((room.id, uid))
+            .or_default(),
+        Instant::now(),
+    );
+    if !allowed {
+        return Err(FieldError::new(
+            "too many commands",
+            Error::room_command_too_fast(),
+        ));
+    }
+
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            uid,
+            ScTextKind::RoomCommand,
+            &req.command,
+            filter,
+            Some(room.game_id),
+            Some(room.id),
+        );
+    }
+
+    Ok(ScRoomCommand {
+        room_id: room.id,
+        user_id: uid,
+        command: req.command.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::room::*;
+
+    #[test]
+    fn room_command_rate() {
+        let mut times = VecDeque::new();
+        let now = Instant::now();
+        for _ in 0..ROOM_COMMANDS_PER_SECOND {
+            assert!(allow_room_command(&mut times, now));
+        }
+        assert!(!allow_room_command(&mut times, now));
+        let later = now + StdDuration::from_millis(1001);
+        assert!(allow_room_command(&mut times, later));
+        assert_eq!(times.len(), 1);
+    }
+
+    #[test]
+    fn next_host() {
+        let now = Utc::now().naive_utc();
+        let member = |user_id, minutes, moderator| Playing {
+            user_id,
+            room_id: -1,
+            created_at: now + Duration::minutes(minutes),
+            moderator,
+        };
+        let members = vec![
+            member(1, 0, false),
+            member(2, 1, true),
+            member(3, 2, true),
+            member(4, 3, false),
+        ];
+        assert_eq!(pick_next_host(&members, |_| true), Some(2));
+        // Offline moderators are skipped
+        assert_eq!(pick_next_host(&members, |uid| uid != 2), Some(3));
+        assert_eq!(
+            pick_next_host(&members, |uid| uid == 1 || uid == 4),
+            Some(1)
+        );
+        assert_eq!(pick_next_host(&members, |_| false), None);
+        assert_eq!(pick_next_host(&[], |_| true), None);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn moderator_limits() {
+        use crate::db::fixtures::*;
+
+        let conn = test_conn();
+        let host = insert_user(&conn, "moderation-host");
+        let moderator = insert_user(&conn, "moderation-moderator");
+        let other = insert_user(&conn, "moderation-other-moderator");
+        let member = insert_user(&conn, "moderation-member");
+        let rid = insert_room(&conn, insert_game(&conn, "moderation"), host);
+        for uid in [host, moderator, other, member] {
+            create_playing(&conn, uid, rid).unwrap();
+        }
+        set_room_moderator(&conn, rid, moderator, true);
+        set_room_moderator(&conn, rid, other, true);
+
+        let kick = |uid, target| {
+            kick_from_room(
+                &conn,
+                uid,
+                &ScKickFromRoom {
+                    room_id: rid,
+                    user_id: target,
+                    ban_minutes: None,
+                },
+            )
+        };
+        let mute = |uid, target| {
+            mute_room_user(
+                &conn,
+                uid,
+                &ScMuteRoomUser {
+                    room_id: rid,
+                    user_id: target,
+                    muted: true,
+                },
+            )
+        };
+        let denied = |result: FieldResult<()>| {
+            result.unwrap_err().extensions().to_owned() == Error::permission_denied()
+        };
+
+        assert!(denied(kick(moderator, host)));
+        assert!(denied(kick(moderator, other)));
+        assert!(denied(mute(moderator, host)));
+        assert!(denied(mute(moderator, other)));
+        // Members can't moderate at all
+        assert!(denied(mute(member, moderator)));
+        assert!(get_room_user_ids(&conn, rid).contains(&other));
+
+        assert!(mute(moderator, member).is_ok());
+        assert!(kick(moderator, member).is_ok());
+        assert!(!get_room_user_ids(&conn, rid).contains(&member));
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn room_screenshot_host() {
+        use crate::db::fixtures::*;
+        use crate::storage::LocalStorage;
+        use data_encoding::BASE64;
+
+        let conn = test_conn();
+        let dir = std::env::temp_dir().join(format!("nesbox-rooms-{}", std::process::id()));
+        let storage = LocalStorage::new(&dir);
+        let host = insert_user(&conn, "screenshot-host");
+        let other = insert_user(&conn, "screenshot-other");
+        let rid = insert_room(&conn, insert_game(&conn, "screenshot"), host);
+        let frame = BASE64.encode(include_bytes!("../screenshot_fixture.png"));
+        let replace = |uid, storage: Option<&dyn Storage>| {
+            replace_room_screenshot(
+                &conn,
+                storage,
+                uid,
+                &ScUpdateRoomScreenshot {
+                    id: rid,
+                    screenshot: frame.clone(),
+                },
+            )
+        };
+        let stored = || {
+            rooms::table
+                .select(rooms::screenshot)
+                .filter(rooms::id.eq(rid))
+                .get_result::<Option<String>>(&conn)
+                .unwrap()
+        };
+
+        let denied = replace(other, Some(&storage)).unwrap_err();
+        assert_eq!(denied.extensions().to_owned(), Error::permission_denied());
+        assert_eq!(stored(), None);
+
+        replace(host, Some(&storage)).unwrap();
+        let url = stored().unwrap();
+        let key = LIVE_SCREENSHOTS.parse_url(&url).unwrap();
+        assert!(storage.exists(&key).unwrap());
+
+        // The same frame again keeps the blob, another one deletes the replaced blob
+        replace(host, Some(&storage)).unwrap();
+        assert!(storage.exists(&key).unwrap());
+        let older = LIVE_SCREENSHOTS.put(&storage, rid, b"older").unwrap();
+        diesel::update(rooms::table.filter(rooms::id.eq(rid)))
+            .set(rooms::screenshot.eq(&older))
+            .execute(&conn)
+            .unwrap();
+        replace(host, Some(&storage)).unwrap();
+        assert_eq!(stored(), Some(url.clone()));
+        assert!(!storage
+            .exists(&LIVE_SCREENSHOTS.parse_url(&older).unwrap())
+            .unwrap());
+
+        // Kept as sent without storage
+        replace(host, None).unwrap();
+        assert_eq!(stored(), Some(frame.clone()));
+
+        // Closed rooms keep their last frame
+        diesel::update(rooms::table.filter(rooms::id.eq(rid)))
+            .set(rooms::deleted_at.eq(Utc::now().naive_utc()))
+            .execute(&conn)
+            .unwrap();
+        assert!(replace(host, Some(&storage)).is_err());
+        assert_eq!(stored(), Some(frame));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
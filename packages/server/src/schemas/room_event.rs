@@ -0,0 +1,285 @@
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldResult, GraphQLEnum, GraphQLObject};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::notify::*;
+use super::playing::get_room_user_ids;
+use super::room::convert_to_sc_room_basic;
+use crate::db::models::Room;
+use crate::db::schema::rooms;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScRoomMemberAction {
+    Join,
+    Leave,
+}
+
+/// A seat taken or freed, apply in `seq` order
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScRoomMemberChange {
+    pub room_id: i32,
+    pub seq: f64,
+    pub user_id: i32,
+    pub action: ScRoomMemberAction,
+}
+
+/// Authoritative members, events up to `seq` are included
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScRoomSnapshot {
+    pub room_id: i32,
+    pub seq: f64,
+    pub host: i32,
+    pub members: Vec<i32>,
+}
+
+lazy_static! {
+    // room_id -> last seq, `None` until read from the room row
+    static ref ROOM_SEQS: Mutex<HashMap<i32, Arc<Mutex<Option<i64>>>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+fn get_room_seq(rid: i32) -> Arc<Mutex<Option<i64>>> {
+    ROOM_SEQS.lock().unwrap().entry(rid).or_default().clone()
+}
+
+/// Runs `f` with the last seq of the room while no other event of the room
+/// can be stamped or sent. Changes and their events happen inside, so the
+/// order of the events is the order of the changes and every subscriber
+/// channel receives them in seq order, nothing to reorder downstream
+pub fn with_room_seq<T>(rid: i32, load: impl FnOnce() -> i64, f: impl FnOnce(&mut i64) -> T) -> T {
+    let room_seq = get_room_seq(rid);
+    let mut guard = room_seq.lock().unwrap();
+    let seq = guard.get_or_insert_with(load);
+    f(seq)
+}
+
+/// Forget the counter of a deleted room
+pub fn clear_room_seq(rid: i32) {
+    ROOM_SEQS.lock().unwrap().remove(&rid);
+}
+
+fn load_room_seq(conn: &PgConnection, rid: i32) -> i64 {
+    use self::rooms::dsl::*;
+
+    rooms
+        .select(event_seq)
+        .filter(id.eq(rid))
+        .get_result::<i64>(conn)
+        .unwrap_or_default()
+}
+
+/// Next seq of the room, stored on the row before the event goes out
+fn stamp(conn: &PgConnection, rid: i32, seq: &mut i64) -> i64 {
+    use self::rooms::dsl::*;
+
+    *seq += 1;
+    if let Err(err) = diesel::update(rooms.filter(id.eq(rid)))
+        .set(event_seq.eq(*seq))
+        .execute(conn)
+    {
+        log::error!("Store room {} seq: {:?}", rid, err);
+    }
+    *seq
+}
+
+/// Apply `change` to the seats of the room and tell the members, the user
+/// who left included
+pub fn change_room_members(
+    conn: &PgConnection,
+    rid: i32,
+    uid: i32,
+    action: ScRoomMemberAction,
+    change: impl FnOnce(),
+) {
+    with_room_seq(
+        rid,
+        || load_room_seq(conn, rid),
+        |seq| {
+            change();
+            let seq = stamp(conn, rid, seq);
+            let mut ids = get_room_user_ids(conn, rid);
+            if !ids.contains(&uid) {
+                ids.push(uid);
+            }
+            notify_ids(
+                ids,
+                ScNotifyMessageBuilder::default()
+                    .room_member(ScRoomMemberChange {
+                        room_id: rid,
+                        seq: seq as f64,
+                        user_id: uid,
+                        action,
+                    })
+                    .build()
+                    .unwrap(),
+            );
+        },
+    );
+}
+
+/// `update_room` to the members, stamped like membership changes
+pub fn notify_room_update(conn: &PgConnection, rid: i32) -> FieldResult<()> {
+    use self::rooms::dsl::*;
+
+    with_room_seq(
+        rid,
+        || load_room_seq(conn, rid),
+        |seq| {
+            stamp(conn, rid, seq);
+            let room = rooms.filter(id.eq(rid)).get_result::<Room>(conn)?;
+            notify_ids(
+                get_room_user_ids(conn, rid),
+                ScNotifyMessageBuilder::default()
+                    .update_room(convert_to_sc_room_basic(conn, &room))
+                    .build()
+                    .unwrap(),
+            );
+            Ok(())
+        },
+    )
+}
+
+/// For clients that found a gap in the seq of a room
+pub fn get_room_snapshot(conn: &PgConnection, rid: i32) -> FieldResult<ScRoomSnapshot> {
+    use self::rooms::dsl::*;
+
+    with_room_seq(
+        rid,
+        || load_room_seq(conn, rid),
+        |seq| {
+            let room_host = rooms
+                .select(host)
+                .filter(id.eq(rid))
+                .get_result::<i32>(conn)?;
+            Ok(ScRoomSnapshot {
+                room_id: rid,
+                seq: *seq as f64,
+                host: room_host,
+                members: get_room_user_ids(conn, rid),
+            })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::notify::{get_receiver, notify_ids, ScNotifyMessageBuilder};
+    use crate::schemas::room_event::*;
+    use std::collections::BTreeSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[test]
+    fn room_member_order() {
+        const ROOM: i32 = -61;
+        // Subscribers, members play the seats in memory
+        const SUBSCRIBERS: [i32; 3] = [-62, -63, -64];
+        let seats: Arc<Mutex<BTreeSet<i32>>> = Default::default();
+        let receivers: Vec<_> = SUBSCRIBERS.iter().map(|uid| get_receiver(*uid)).collect();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = receivers
+            .into_iter()
+            .map(|mut rx| {
+                let seats = seats.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let (mut seq, mut members) = (0.0, BTreeSet::new());
+                    loop {
+                        // Read before, an empty channel after the last send is drained
+                        let finished = done.load(Ordering::SeqCst);
+                        match rx.0.try_recv() {
+                            Ok(msg) => {
+                                let change = msg.room_member_change().unwrap().clone();
+                                // Covered by the snapshot
+                                if change.seq <= seq {
+                                    continue;
+                                }
+                                assert_eq!(change.seq, seq + 1.0);
+                                seq = change.seq;
+                                match change.action {
+                                    ScRoomMemberAction::Join => members.insert(change.user_id),
+                                    ScRoomMemberAction::Leave => members.remove(&change.user_id),
+                                };
+                            }
+                            // Slow subscriber, the channel dropped events
+                            Err(TryRecvError::Lagged(_)) => {
+                                with_room_seq(
+                                    ROOM,
+                                    || 0,
+                                    |last| {
+                                        seq = *last as f64;
+                                        members = seats.lock().unwrap().clone();
+                                    },
+                                );
+                            }
+                            Err(TryRecvError::Empty) if finished => break,
+                            Err(TryRecvError::Empty) => thread::yield_now(),
+                            Err(TryRecvError::Closed) => break,
+                        }
+                    }
+                    rx.detach();
+                    members
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let seats = seats.clone();
+                thread::spawn(move || {
+                    for round in 0..50 {
+                        let uid = 1000 + (writer * 7 + round) % 12;
+                        let action = if round % 3 == 2 {
+                            ScRoomMemberAction::Leave
+                        } else {
+                            ScRoomMemberAction::Join
+                        };
+                        with_room_seq(
+                            ROOM,
+                            || 0,
+                            |seq| {
+                                {
+                                    let mut seats = seats.lock().unwrap();
+                                    match action {
+                                        ScRoomMemberAction::Join => seats.insert(uid),
+                                        ScRoomMemberAction::Leave => seats.remove(&uid),
+                                    };
+                                }
+                                *seq += 1;
+                                notify_ids(
+                                    SUBSCRIBERS.to_vec(),
+                                    ScNotifyMessageBuilder::default()
+                                        .room_member(ScRoomMemberChange {
+                                            room_id: ROOM,
+                                            seq: *seq as f64,
+                                            user_id: uid,
+                                            action,
+                                        })
+                                        .build()
+                                        .unwrap(),
+                                );
+                            },
+                        );
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+
+        let expected = seats.lock().unwrap().clone();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), expected);
+        }
+        with_room_seq(ROOM, || 0, |seq| assert_eq!(*seq, 400));
+        clear_room_seq(ROOM);
+    }
+}
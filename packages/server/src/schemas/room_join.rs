@@ -0,0 +1,247 @@
+use chrono::{DateTime, Duration, Utc};
+use juniper::{FieldError, FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::error::Error;
+
+const FAILURES_PER_LOCK: i32 = 3;
+// Escalating lock after every `FAILURES_PER_LOCK` wrong passwords
+const LOCK_MINUTES: [i64; 3] = [1, 5, 30];
+// Host is told every this many failures in the room
+const HOST_ALERT_FAILURES: i32 = 10;
+const MAX_JOIN_LOG_LEN: usize = 500;
+
+lazy_static! {
+    // Idle counters are forgotten after this, rooms are short lived
+    static ref FAILURE_TTL: Duration = Duration::hours(1);
+    // (room_id, user_id) -> wrong passwords
+    static ref USER_FAILURES: Mutex<HashMap<(i32, i32), Failures>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // room_id -> wrong passwords from anyone
+    static ref ROOM_FAILURES: Mutex<HashMap<i32, Failures>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // room_id -> join attempts and moderation, kept for the room's lifetime
+    static ref JOIN_LOGS: Mutex<HashMap<i32, Vec<ScRoomJoinAttempt>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+struct Failures {
+    count: i32,
+    locked_until: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+}
+
+impl Failures {
+    fn new(now: DateTime<Utc>) -> Self {
+        Failures {
+            count: 0,
+            locked_until: None,
+            updated_at: now,
+        }
+    }
+
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.updated_at + *FAILURE_TTL > now || self.locked_until.map_or(false, |t| t > now)
+    }
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScRoomJoinLogReq {
+    pub room_id: i32,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ScRoomLogAction {
+    Join,
+    Kick,
+    Mute,
+    Unmute,
+    AddModerator,
+    RemoveModerator,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRoomJoinAttempt {
+    // joining user, or the target of the action
+    user_id: i32,
+    ip: Option<String>,
+    success: bool,
+    created_at: f64,
+    action: ScRoomLogAction,
+    // host or moderator, `None` for joins
+    actor_id: Option<i32>,
+}
+
+/// Sent to the host after repeated wrong passwords
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRoomJoinAlert {
+    pub room_id: i32,
+    // wrong passwords so far
+    pub attempts: i32,
+}
+
+pub fn check_room_join_lock(rid: i32, uid: i32, now: DateTime<Utc>) -> FieldResult<()> {
+    let mut map = USER_FAILURES.lock().unwrap();
+    map.retain(|_, failures| failures.is_live(now));
+    if let Some(until) = map
+        .get(&(rid, uid))
+        .and_then(|failures| failures.locked_until)
+        .filter(|until| *until > now)
+    {
+        return Err(FieldError::new(
+            format!(
+                "too many wrong passwords, retry in {}s",
+                (until - now).num_seconds()
+            ),
+            Error::room_join_locked(),
+        ));
+    }
+    Ok(())
+}
+
+/// Count a wrong password, returns an alert for the host every `HOST_ALERT_FAILURES`
+pub fn record_join_failure(rid: i32, uid: i32, now: DateTime<Utc>) -> Option<ScRoomJoinAlert> {
+    {
+        let mut map = USER_FAILURES.lock().unwrap();
+        let failures = map.entry((rid, uid)).or_insert_with(|| Failures::new(now));
+        failures.count += 1;
+        failures.updated_at = now;
+        if failures.count % FAILURES_PER_LOCK == 0 {
+            let level = (failures.count / FAILURES_PER_LOCK - 1) as usize;
+            let minutes = LOCK_MINUTES[level.min(LOCK_MINUTES.len() - 1)];
+            failures.locked_until = Some(now + Duration::minutes(minutes));
+        }
+    }
+
+    let mut map = ROOM_FAILURES.lock().unwrap();
+    map.retain(|_, failures| failures.is_live(now));
+    let failures = map.entry(rid).or_insert_with(|| Failures::new(now));
+    failures.count += 1;
+    failures.updated_at = now;
+    (failures.count % HOST_ALERT_FAILURES == 0).then_some(ScRoomJoinAlert {
+        room_id: rid,
+        attempts: failures.count,
+    })
+}
+
+pub fn record_join_success(rid: i32, uid: i32) {
+    USER_FAILURES.lock().unwrap().remove(&(rid, uid));
+}
+
+fn push_room_log(rid: i32, entry: ScRoomJoinAttempt) {
+    let mut map = JOIN_LOGS.lock().unwrap();
+    let log = map.entry(rid).or_default();
+    if log.len() >= MAX_JOIN_LOG_LEN {
+        log.remove(0);
+    }
+    log.push(entry);
+}
+
+pub fn log_join_attempt(rid: i32, uid: i32, ip: Option<String>, success: bool, now: DateTime<Utc>) {
+    push_room_log(
+        rid,
+        ScRoomJoinAttempt {
+            user_id: uid,
+            ip,
+            success,
+            created_at: now.timestamp_millis() as f64,
+            action: ScRoomLogAction::Join,
+            actor_id: None,
+        },
+    );
+}
+
+/// Kick, mute and moderator changes, shown to the host with joins
+pub fn log_room_action(
+    rid: i32,
+    actor: i32,
+    target: i32,
+    action: ScRoomLogAction,
+    now: DateTime<Utc>,
+) {
+    push_room_log(
+        rid,
+        ScRoomJoinAttempt {
+            user_id: target,
+            ip: None,
+            success: true,
+            created_at: now.timestamp_millis() as f64,
+            action,
+            actor_id: Some(actor),
+        },
+    );
+}
+
+/// Newest first
+pub fn get_join_log(rid: i32) -> Vec<ScRoomJoinAttempt> {
+    let map = JOIN_LOGS.lock().unwrap();
+    map.get(&rid)
+        .map(|log| log.iter().rev().cloned().collect())
+        .unwrap_or_default()
+}
+
+pub fn clear_room_joins(rid: i32) {
+    JOIN_LOGS.lock().unwrap().remove(&rid);
+    ROOM_FAILURES.lock().unwrap().remove(&rid);
+    USER_FAILURES
+        .lock()
+        .unwrap()
+        .retain(|(room_id, _), _| *room_id != rid);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::room_join::*;
+
+    fn fail(rid: i32, uid: i32, now: DateTime<Utc>, times: i32) -> Vec<ScRoomJoinAlert> {
+        (0..times)
+            .filter_map(|_| record_join_failure(rid, uid, now))
+            .collect()
+    }
+
+    #[test]
+    fn join_lock_escalates() {
+        let (rid, uid) = (-1, -2);
+        let now = Utc::now();
+
+        fail(rid, uid, now, 2);
+        assert!(check_room_join_lock(rid, uid, now).is_ok());
+        fail(rid, uid, now, 1);
+        assert!(check_room_join_lock(rid, uid, now).is_err());
+        assert!(check_room_join_lock(rid, uid, now + Duration::minutes(1)).is_ok());
+
+        // Another user is not affected
+        assert!(check_room_join_lock(rid, -3, now).is_ok());
+
+        let now = now + Duration::minutes(1);
+        fail(rid, uid, now, 3);
+        assert!(check_room_join_lock(rid, uid, now + Duration::minutes(4)).is_err());
+        assert!(check_room_join_lock(rid, uid, now + Duration::minutes(5)).is_ok());
+
+        let now = now + Duration::minutes(5);
+        let alerts = fail(rid, uid, now, 3);
+        assert!(check_room_join_lock(rid, uid, now + Duration::minutes(29)).is_err());
+        assert_eq!(alerts.len(), 0);
+        let alerts = fail(rid, -3, now, 1);
+        assert_eq!(alerts[0].attempts, 10);
+
+        record_join_success(rid, uid);
+        assert!(check_room_join_lock(rid, uid, now).is_ok());
+
+        log_join_attempt(rid, uid, Some("127.0.0.1".into()), true, now);
+        log_room_action(rid, -3, uid, ScRoomLogAction::Mute, now);
+        let log = get_join_log(rid);
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].action, ScRoomLogAction::Mute);
+        assert_eq!(log[0].actor_id, Some(-3));
+        clear_room_joins(rid);
+        assert!(get_join_log(rid).is_empty());
+    }
+}
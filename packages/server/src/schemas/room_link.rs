@@ -0,0 +1,313 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use data_encoding::HEXLOWER;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use ring::constant_time::verify_slices_are_equal;
+use ring::hmac::{sign, Key, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::env;
+
+use super::game::get_game_max_player;
+use super::playing::get_room_user_ids;
+use super::room::{check_room_ban, get_room, ScRoomBasic};
+use super::tenant::{check_same_tenant, get_room_tenant};
+use super::user::{get_user_basic, ScUserBasic};
+use crate::db::models::{NewRoomLink, RoomLink};
+use crate::db::schema::room_links;
+use crate::error::Error;
+use crate::keyring::{get_accepted_keys, get_signing_key};
+
+const DEFAULT_EXPIRES_IN_SECONDS: i32 = 24 * 60 * 60;
+const MAX_EXPIRES_IN_SECONDS: i32 = 7 * 24 * 60 * 60;
+const MAX_USES: i32 = 100;
+// hex chars of the signature kept in the token
+const SIGNATURE_LEN: usize = 16;
+
+lazy_static! {
+    // Landing page of the links, it renders `roomLinkInfo` and calls `joinByLink`
+    static ref WEBAPP_URL: String = env::var("WEBAPP_URL")
+        .unwrap_or("https://nesbox.xianqiao.wang".to_owned())
+        .trim_end_matches('/')
+        .to_owned();
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewRoomLink {
+    pub room_id: i32,
+    // unlimited when null
+    pub max_uses: Option<i32>,
+    // seconds, one day when null and at most a week
+    pub expires_in: Option<i32>,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRoomLink {
+    pub id: i32,
+    pub room_id: i32,
+    pub token: String,
+    pub url: String,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: f64,
+    pub revoked: bool,
+}
+
+/// What the landing page shows before login, without the room id
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScRoomLinkInfo {
+    pub game_id: i32,
+    pub host: ScUserBasic,
+    pub player_count: i32,
+    // null when the game sets no limit
+    pub max_player: Option<i32>,
+    pub expires_at: f64,
+}
+
+fn sign_code(secret: &str, code: &str) -> String {
+    let signature = sign(&Key::new(HMAC_SHA256, secret.as_bytes()), code.as_bytes());
+    HEXLOWER.encode(signature.as_ref())[..SIGNATURE_LEN].to_owned()
+}
+
+/// Signed with the newest key, links shared before a rotation keep
+/// working for the grace period
+pub fn get_link_token(secret: &str, code: &str) -> String {
+    format!(
+        "{}.{}",
+        code,
+        sign_code(&get_signing_key(secret).secret, code)
+    )
+}
+
+/// Code of a token signed with an accepted key, forged tokens never reach the database
+pub fn parse_link_token(secret: &str, token: &str) -> Option<String> {
+    let (code, signature) = token.trim().split_once('.')?;
+    get_accepted_keys(secret)
+        .iter()
+        .any(|key| {
+            verify_slices_are_equal(
+                sign_code(&key.secret, code).as_bytes(),
+                signature.as_bytes(),
+            )
+            .is_ok()
+        })
+        .then(|| code.to_owned())
+}
+
+/// Revoked first, a revoked link never comes back
+pub fn check_link(link: &RoomLink, now: NaiveDateTime) -> FieldResult<()> {
+    if link.revoked_at.is_some() {
+        return Err(FieldError::new(
+            "link was revoked",
+            Error::room_link_revoked(),
+        ));
+    }
+    if link.expires_at <= now {
+        return Err(FieldError::new("link expired", Error::room_link_expired()));
+    }
+    if link.max_uses.map_or(false, |max| link.uses >= max) {
+        return Err(FieldError::new(
+            "link has no uses left",
+            Error::room_link_exhausted(),
+        ));
+    }
+    Ok(())
+}
+
+fn convert_to_sc_room_link(secret: &str, link: &RoomLink) -> ScRoomLink {
+    let token = get_link_token(secret, &link.code);
+    ScRoomLink {
+        id: link.id,
+        room_id: link.room_id,
+        url: format!("{}/join/{}", *WEBAPP_URL, token),
+        token,
+        max_uses: link.max_uses,
+        uses: link.uses,
+        expires_at: link.expires_at.timestamp_millis() as f64,
+        revoked: link.revoked_at.is_some(),
+    }
+}
+
+fn get_link(conn: &PgConnection, secret: &str, token: &str) -> FieldResult<RoomLink> {
+    use self::room_links::dsl::*;
+
+    let invalid = || FieldError::new("invalid link", Error::room_link_invalid());
+    let link_code = parse_link_token(secret, token).ok_or_else(invalid)?;
+    room_links
+        .filter(code.eq(link_code))
+        .get_result::<RoomLink>(conn)
+        .optional()?
+        .ok_or_else(invalid)
+}
+
+pub fn create_room_link(
+    conn: &PgConnection,
+    uid: i32,
+    secret: &str,
+    req: &ScNewRoomLink,
+) -> FieldResult<ScRoomLink> {
+    let room = get_room(conn, req.room_id)?;
+    if room.host != uid {
+        return Err(FieldError::new("host only", Error::permission_denied()));
+    }
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| FieldError::new("random unavailable", Error::internal()))?;
+    let link_code = HEXLOWER.encode(&bytes);
+    let expires_in = req
+        .expires_in
+        .unwrap_or(DEFAULT_EXPIRES_IN_SECONDS)
+        .clamp(60, MAX_EXPIRES_IN_SECONDS);
+    let now = Utc::now().naive_utc();
+    let new_link = NewRoomLink {
+        code: &link_code,
+        room_id: room.id,
+        created_by: uid,
+        max_uses: req.max_uses.map(|max| max.clamp(1, MAX_USES)),
+        expires_at: now + Duration::seconds(expires_in as i64),
+        created_at: now,
+    };
+    let link = diesel::insert_into(room_links::table)
+        .values(&new_link)
+        .get_result::<RoomLink>(conn)?;
+    Ok(convert_to_sc_room_link(secret, &link))
+}
+
+pub fn get_room_link_info(
+    conn: &PgConnection,
+    secret: &str,
+    token: &str,
+) -> FieldResult<ScRoomLinkInfo> {
+    let link = get_link(conn, secret, token)?;
+    check_link(&link, Utc::now().naive_utc())?;
+    let room = get_room(conn, link.room_id)?;
+    Ok(ScRoomLinkInfo {
+        game_id: room.game_id,
+        host: get_user_basic(conn, room.host)?,
+        player_count: get_room_user_ids(conn, room.id).len() as i32,
+        max_player: get_game_max_player(conn, room.game_id),
+        expires_at: link.expires_at.timestamp_millis() as f64,
+    })
+}
+
+/// Consumes a use and returns the room to enter, members don't use it up.
+/// `check` runs the caller's restrictions before the use is counted, the caller
+/// enters the room in the same transaction so a refused join keeps the use
+pub fn redeem_room_link(
+    conn: &PgConnection,
+    uid: i32,
+    tenant: Option<i32>,
+    secret: &str,
+    token: &str,
+    check: impl FnOnce(&ScRoomBasic) -> FieldResult<()>,
+) -> FieldResult<i32> {
+    use self::room_links::dsl::*;
+
+    let link = get_link(conn, secret, token)?;
+    conn.transaction(|| {
+        // Concurrent redemptions of the last use
+        let link = room_links
+            .filter(id.eq(link.id))
+            .for_update()
+            .get_result::<RoomLink>(conn)?;
+        check_link(&link, Utc::now().naive_utc())?;
+        let room = get_room(conn, link.room_id)?;
+        let players = get_room_user_ids(conn, room.id);
+        if players.contains(&uid) {
+            return Ok(room.id);
+        }
+        check_room_ban(room.id, uid)?;
+        check_same_tenant(get_room_tenant(conn, room.id), tenant)?;
+        if let Some(max) = get_game_max_player(conn, room.game_id) {
+            if players.len() as i32 >= max {
+                return Err(FieldError::new("room is full", Error::room_full()));
+            }
+        }
+        check(&room)?;
+        diesel::update(room_links.filter(id.eq(link.id)))
+            .set(uses.eq(uses + 1))
+            .execute(conn)?;
+        Ok(room.id)
+    })
+}
+
+/// Host of the room, used links stay listed as revoked
+pub fn revoke_room_link(
+    conn: &PgConnection,
+    uid: i32,
+    secret: &str,
+    link_id: i32,
+) -> FieldResult<ScRoomLink> {
+    use self::room_links::dsl::*;
+
+    let link = room_links
+        .filter(id.eq(link_id))
+        .get_result::<RoomLink>(conn)?;
+    if get_room(conn, link.room_id)?.host != uid {
+        return Err(FieldError::new("host only", Error::permission_denied()));
+    }
+    let link = diesel::update(room_links.filter(id.eq(link_id)))
+        .set(revoked_at.eq(link.revoked_at.or(Some(Utc::now().naive_utc()))))
+        .get_result::<RoomLink>(conn)?;
+    Ok(convert_to_sc_room_link(secret, &link))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::room_link::*;
+
+    #[test]
+    fn room_link_tokens() {
+        let token = get_link_token("secret", "0a1b2c");
+        assert_eq!(parse_link_token("secret", &token), Some("0a1b2c".into()));
+        // Signed with another secret, tampered, or a guessed room id
+        assert_eq!(parse_link_token("other", &token), None);
+        assert_eq!(
+            parse_link_token("secret", &token.replace("0a1b2c", "0a1b2d")),
+            None
+        );
+        assert_eq!(parse_link_token("secret", "42"), None);
+        assert_eq!(parse_link_token("secret", ""), None);
+
+        let now = Utc::now().naive_utc();
+        let link = RoomLink {
+            id: 1,
+            code: "0a1b2c".into(),
+            room_id: 1,
+            created_by: 1,
+            max_uses: Some(2),
+            uses: 1,
+            expires_at: now + Duration::hours(1),
+            revoked_at: None,
+            created_at: now,
+        };
+        let code = |link: &RoomLink| {
+            check_link(link, now)
+                .err()
+                .map(|err| err.extensions().clone())
+        };
+        assert_eq!(code(&link), None);
+        let exhausted = RoomLink {
+            uses: 2,
+            ..link.clone()
+        };
+        assert_eq!(code(&exhausted), Some(Error::room_link_exhausted()));
+        let unlimited = RoomLink {
+            max_uses: None,
+            uses: 1000,
+            ..link.clone()
+        };
+        assert_eq!(code(&unlimited), None);
+        let expired = RoomLink {
+            expires_at: now,
+            ..link.clone()
+        };
+        assert_eq!(code(&expired), Some(Error::room_link_expired()));
+        let revoked = RoomLink {
+            revoked_at: Some(now),
+            ..expired
+        };
+        assert_eq!(code(&revoked), Some(Error::room_link_revoked()));
+    }
+}
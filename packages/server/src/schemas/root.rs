@@ -1,75 +1,613 @@
+use crate::auth::{AnonToken, TokenHandle};
 use crate::db::root::DB_POOL;
-use crate::error::Error;
+use crate::error::{Error, ErrorCode};
+use crate::federation::{get_federation_status, ScFederationStatus};
+use crate::github::comment_game_report;
+use crate::guard::check_register;
+use crate::keyring::{rotate_secret, ScSigningKey};
+use crate::moderation::{check_text, reload_blocked_words, ScTextKind};
+use crate::outbox::{wake_outbox, write_outbox_event, OutboxEvent};
+use crate::profiling::{get_slow_resolvers, ScSlowResolver};
+use crate::quota::{get_usage, ScUsage};
+use crate::rom::cache_rom;
+use crate::screenshot::purge_screenshot;
 
+use super::achievement::*;
+use super::audit::*;
+use super::authority::*;
+use super::client_info::*;
+use super::collection::*;
 use super::comment::*;
+use super::compatibility::*;
+use super::connection::*;
 use super::favorite::*;
+use super::feature_flag::*;
 use super::friend::*;
 use super::game::*;
+use super::game_attachment::*;
+use super::game_change::*;
+use super::game_kind::*;
+use super::game_version::*;
+use super::github_repo::*;
 use super::invite::*;
+use super::leaderboard::*;
 use super::lobby::*;
+use super::login_session::*;
 use super::message::*;
+use super::notification::*;
 use super::notify::*;
 use super::playing::*;
+use super::presence::*;
+use super::recommendation::*;
 use super::record::*;
+use super::relay::*;
+use super::report::*;
+use super::restriction::*;
+use super::retention::*;
 use super::room::*;
+use super::room_event::*;
+use super::room_join::*;
+use super::room_link::*;
+use super::save_state::*;
+use super::score::*;
+use super::spectator::*;
+use super::tenant::*;
+use super::trial::*;
 use super::user::*;
+use super::webhook::*;
 use crate::voice::*;
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::Connection;
 use futures::Stream;
 use juniper::{graphql_subscription, EmptySubscription, FieldError, FieldResult, RootNode};
+use serde_json::json;
+use std::collections::BTreeMap;
 use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 
 pub struct QueryRoot;
 
 #[juniper::graphql_object(Context = Context)]
 impl QueryRoot {
+    // `kinds` matches any of them, all of them with `matchAll`
     #[deprecated]
-    fn games(_context: &Context) -> FieldResult<Vec<ScGame>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_games(&conn))
+    async fn games(
+        context: &Context,
+        kinds: Option<Vec<ScGameKind>>,
+        match_all: Option<bool>,
+    ) -> FieldResult<Vec<ScGame>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let match_all = match_all.unwrap_or_default();
+                let mut list = get_games_with_user(&conn, context.user_id, kinds, match_all);
+                if context.is_restricted(ScRestriction::MatureGames) {
+                    list.retain(|game| !game.mature);
+                }
+                Ok(list)
+            })
+            .await
+    }
+    // `asOf` is admin only, the game as it was then
+    async fn game(context: &Context, id: i32, as_of: Option<f64>) -> FieldResult<Option<ScGame>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if context.is_restricted(ScRestriction::MatureGames) && is_mature_game(&conn, id) {
+                    return Ok(None);
+                }
+                match as_of {
+                    Some(as_of) => {
+                        if !is_admin(&conn, context.user_id) {
+                            return Err(FieldError::new("admin only", Error::permission_denied()));
+                        }
+                        let ms = as_of as i64;
+                        let at = NaiveDateTime::from_timestamp_opt(
+                            ms.div_euclid(1000),
+                            (ms.rem_euclid(1000) * 1_000_000) as u32,
+                        )
+                        .ok_or_else(|| FieldError::new("invalid asOf", Error::validation()))?;
+                        get_game_as_of(&conn, id, at)
+                    }
+                    None => Ok(get_games_by_ids(&conn, vec![id])?.pop()),
+                }
+            })
+            .await
+    }
+    async fn game_history(context: &Context, game_id: i32) -> FieldResult<Vec<ScGameVersion>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_game_history(&conn, game_id)
+            })
+            .await
     }
-    fn recent_games(context: &Context) -> FieldResult<Vec<i32>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_recent_ids(&conn, context.user_id))
+    async fn games_delta(context: &Context, input: ScGamesDeltaReq) -> FieldResult<ScGamesDelta> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let delta = get_games_delta(&conn, input.since_version)?;
+                if context.is_restricted(ScRestriction::MatureGames) {
+                    return Ok(delta.hide_games(|game| game.mature));
+                }
+                Ok(delta)
+            })
+            .await
+    }
+    async fn kinds(_context: &Context) -> FieldResult<Vec<ScGameKindCount>> {
+        DB_POOL.run(move |conn| Ok(get_kind_counts(&conn))).await
     }
-    fn top_games(_context: &Context) -> FieldResult<Vec<i32>> {
+    async fn recent_games(context: &Context) -> FieldResult<Vec<i32>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_recent_ids(&conn, context.user_id)))
+            .await
+    }
+    async fn top_games(context: &Context) -> FieldResult<Vec<i32>> {
         // TODO: 个性化推荐
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_top_ids(&conn))
+        let restricted = context.is_restricted(ScRestriction::MatureGames);
+        DB_POOL
+            .run(move |conn| {
+                let mut ids = get_top_ids(&conn);
+                if restricted {
+                    let mature = get_mature_game_ids(&conn);
+                    ids.retain(|id| !mature.contains(id));
+                }
+                Ok(ids)
+            })
+            .await
     }
-    fn favorites(context: &Context) -> FieldResult<Vec<i32>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_favorites(&conn, context.user_id))
+    async fn recommended_games(
+        context: &Context,
+        first: Option<i32>,
+    ) -> FieldResult<Vec<ScRecommendation>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let mut list = get_recommended_games(&conn, context.user_id, first)?;
+                if context.is_restricted(ScRestriction::MatureGames) {
+                    let mature = get_mature_game_ids(&conn);
+                    list.retain(|recommendation| !mature.contains(&recommendation.game_id));
+                }
+                Ok(list)
+            })
+            .await
+    }
+    async fn states(context: &Context, game_id: i32) -> FieldResult<Vec<ScSaveState>> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| get_save_states(&conn, user_id, game_id))
+            .await
+    }
+    // base64 of the state
+    async fn state_data(context: &Context, game_id: i32, slot: i32) -> FieldResult<String> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| get_save_state_data(&conn, user_id, game_id, slot))
+            .await
+    }
+    // progress of a chunked upload to resume
+    async fn state_upload(context: &Context, id: String) -> FieldResult<ScStateUpload> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| get_state_upload_progress(&conn, user_id, &id))
+            .await
+    }
+    async fn favorites(context: &Context) -> FieldResult<Vec<i32>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_favorites(&conn, context.user_id)))
+            .await
+    }
+    async fn collections(context: &Context) -> FieldResult<Vec<ScCollection>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_collections(&conn, context.user_id))
+            .await
+    }
+    async fn user_collections(context: &Context, user_id: i32) -> FieldResult<Vec<ScCollection>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_user_collections(&conn, context.user_id, user_id))
+            .await
     }
     #[deprecated]
-    fn comments(_context: &Context, input: ScCommentsReq) -> FieldResult<Vec<ScComment>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_comments(&conn, input.game_id))
+    async fn comments(context: &Context, input: ScCommentsReq) -> FieldResult<Vec<ScComment>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_comments(&conn, input.game_id, Some(context.user_id))))
+            .await
+    }
+    async fn record(context: &Context, input: ScRecordReq) -> FieldResult<Option<ScRecord>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_record(&conn, context.user_id, input.game_id)))
+            .await
     }
-    fn record(context: &Context, input: ScRecordReq) -> FieldResult<Option<ScRecord>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_record(&conn, context.user_id, input.game_id))
+    async fn game_stats(_context: &Context, game_id: i32) -> FieldResult<ScGameStats> {
+        DB_POOL
+            .run(move |conn| get_game_stats(&conn, game_id))
+            .await
+    }
+    async fn game_achievements(
+        _context: &Context,
+        game_id: i32,
+    ) -> FieldResult<Vec<ScAchievement>> {
+        DB_POOL
+            .run(move |conn| get_game_achievements(&conn, game_id))
+            .await
+    }
+    // unlocked by the user, newest first
+    async fn achievements(
+        _context: &Context,
+        user_id: i32,
+        game_id: Option<i32>,
+    ) -> FieldResult<Vec<ScUserAchievement>> {
+        DB_POOL
+            .run(move |conn| get_user_achievements(&conn, user_id, game_id))
+            .await
+    }
+    async fn music_tracks(_context: &Context, game_id: i32) -> FieldResult<Vec<ScMusicTrack>> {
+        DB_POOL
+            .run(move |conn| Ok(get_music_tracks(&conn, game_id)))
+            .await
+    }
+    async fn leaderboard(
+        context: &Context,
+        input: ScLeaderboardReq,
+    ) -> FieldResult<Vec<ScLeaderboardItem>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_leaderboard(&conn, context.user_id, &input))
+            .await
+    }
+    async fn friends_leaderboard(
+        context: &Context,
+        input: ScLeaderboardReq,
+    ) -> FieldResult<Vec<ScLeaderboardItem>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_friends_leaderboard(&conn, context.user_id, &input))
+            .await
     }
-    fn account(context: &Context) -> FieldResult<ScUser> {
-        let conn = DB_POOL.get().unwrap();
-        get_account(&conn, context.user_id)
+    fn usage(context: &Context) -> FieldResult<ScUsage> {
+        Ok(get_usage(context.user_id, Utc::now()))
     }
-    fn messages(context: &Context, input: ScMessagesReq) -> FieldResult<Vec<ScMessage>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_messages(&conn, context.user_id, input.target_id))
+    async fn account(context: &Context) -> FieldResult<ScUser> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_account(&conn, context.user_id))
+            .await
     }
-    fn friends(context: &Context) -> FieldResult<Vec<ScFriend>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_friends(&conn, context.user_id))
+    async fn messages(context: &Context, input: ScMessagesReq) -> FieldResult<Vec<ScMessage>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_messages(&conn, context.user_id, &input)))
+            .await
+    }
+    async fn friends(context: &Context, input: Option<ScFriendsReq>) -> FieldResult<Vec<ScFriend>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| match input.and_then(|input| input.search) {
+                Some(search) => Ok(search_friends(&conn, context.user_id, &search)),
+                None => Ok(get_friends(&conn, context.user_id)),
+            })
+            .await
     }
     #[deprecated]
-    fn rooms(_context: &Context) -> FieldResult<Vec<ScRoom>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_rooms(&conn))
+    async fn rooms(
+        context: &Context,
+        game_id: Option<i32>,
+        mode: Option<ScPlayMode>,
+    ) -> FieldResult<Vec<ScRoom>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_rooms(&conn, context.tenant_id, game_id, mode)))
+            .await
+    }
+    fn error_codes(_context: &Context) -> FieldResult<Vec<ErrorCode>> {
+        Ok(ErrorCode::all())
+    }
+    fn notify_routes(_context: &Context) -> FieldResult<Vec<ScNotifyRoute>> {
+        Ok(get_notify_routes())
+    }
+    fn feature_flags(context: &Context) -> FieldResult<Vec<ScFeatureFlag>> {
+        Ok(context
+            .features
+            .iter()
+            .map(|(name, enabled)| ScFeatureFlag {
+                name: name.clone(),
+                enabled: *enabled,
+            })
+            .collect())
+    }
+    async fn feature_flag_configs(context: &Context) -> FieldResult<Vec<ScFeatureFlagConfig>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_feature_flag_configs(&conn)
+            })
+            .await
+    }
+    async fn compatibility(_context: &Context) -> FieldResult<Vec<ScCompatibility>> {
+        DB_POOL.run(move |conn| Ok(get_compatibility(&conn))).await
+    }
+    async fn client_versions(context: &Context) -> FieldResult<Vec<ScClientVersion>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_client_versions(&conn)
+            })
+            .await
+    }
+    async fn retention_status(context: &Context) -> FieldResult<Vec<ScRetentionStatus>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_retention_status(&conn)
+            })
+            .await
+    }
+    async fn audit_logs(context: &Context, input: ScAuditLogsReq) -> FieldResult<Vec<ScAuditLog>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_audit_logs(&conn, &input)
+            })
+            .await
+    }
+    async fn flagged_scores(
+        context: &Context,
+        input: ScFlaggedScoresReq,
+    ) -> FieldResult<Vec<ScScore>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_flagged_scores(&conn, &input)
+            })
+            .await
+    }
+    async fn connections(context: &Context) -> FieldResult<Vec<ScConnection>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_connections())
+            })
+            .await
     }
-    fn invites(context: &Context) -> FieldResult<Vec<ScInvite>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_invites(&conn, context.user_id))
+    async fn federation_status(context: &Context) -> FieldResult<ScFederationStatus> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_federation_status(&conn)?)
+            })
+            .await
+    }
+    async fn trash_games(context: &Context) -> FieldResult<Vec<ScTrashedGame>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_trashed_games(&conn)
+            })
+            .await
+    }
+    async fn trash_comments(context: &Context) -> FieldResult<Vec<ScHiddenComment>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_hidden_comments(&conn)
+            })
+            .await
+    }
+    async fn trash_users(context: &Context) -> FieldResult<Vec<ScBannedUser>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_banned_users(&conn)
+            })
+            .await
+    }
+    async fn slow_resolvers(
+        context: &Context,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<ScSlowResolver>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_slow_resolvers(
+                    limit.unwrap_or(20).clamp(1, 100) as usize
+                ))
+            })
+            .await
+    }
+    async fn moderation_reports(
+        context: &Context,
+        limit: Option<i32>,
+    ) -> FieldResult<Vec<ScModerationReport>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_moderation_reports(&conn, limit.unwrap_or(50).clamp(1, 200) as i64)
+            })
+            .await
+    }
+    async fn tenants(context: &Context) -> FieldResult<Vec<ScTenant>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) || context.tenant_id.is_some() {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_tenants(&conn))
+            })
+            .await
+    }
+    async fn webhooks(context: &Context) -> FieldResult<Vec<ScWebhook>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_webhooks(&conn))
+            })
+            .await
+    }
+    async fn webhook_deliveries(
+        context: &Context,
+        input: ScWebhookDeliveriesReq,
+    ) -> FieldResult<Vec<ScWebhookDelivery>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(get_webhook_deliveries(&conn, &input))
+            })
+            .await
+    }
+    async fn github_deliveries(
+        context: &Context,
+        first: Option<i32>,
+    ) -> FieldResult<Vec<ScGithubDelivery>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                get_github_deliveries(&conn, first)
+            })
+            .await
+    }
+    async fn room_join_log(
+        context: &Context,
+        input: ScRoomJoinLogReq,
+    ) -> FieldResult<Vec<ScRoomJoinAttempt>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let room = get_room(&conn, input.room_id)?;
+                if room.host != context.user_id && !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("host only", Error::permission_denied()));
+                }
+                Ok(get_join_log(room.id))
+            })
+            .await
+    }
+    async fn spectators(
+        context: &Context,
+        input: ScSpectatorsReq,
+    ) -> FieldResult<Vec<ScUserBasic>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let room = get_room(&conn, input.room_id)?;
+                if room.host != context.user_id {
+                    return Err(FieldError::new("host only", Error::permission_denied()));
+                }
+                Ok(get_spectator_ids(room.id)
+                    .into_iter()
+                    .filter_map(|uid| get_user_basic(&conn, uid).ok())
+                    .collect())
+            })
+            .await
+    }
+    // Members and spectators resync after a gap in the room's seq
+    async fn room_snapshot(context: &Context, room_id: i32) -> FieldResult<ScRoomSnapshot> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let allowed = get_room_user_ids(&conn, room_id).contains(&context.user_id)
+                    || get_spectator_ids(room_id).contains(&context.user_id)
+                    || is_admin(&conn, context.user_id);
+                if !allowed {
+                    return Err(FieldError::new(
+                        format!("{} not in room {}", context.user_id, room_id),
+                        Error::permission_denied(),
+                    ));
+                }
+                get_room_snapshot(&conn, room_id)
+            })
+            .await
+    }
+    async fn my_room(context: &Context) -> FieldResult<Option<ScRoomBasic>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_playing(&conn, context.user_id)))
+            .await
+    }
+    async fn invites(context: &Context) -> FieldResult<Vec<ScInvite>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_invites(&conn, context.user_id)))
+            .await
+    }
+    async fn notifications(
+        context: &Context,
+        input: ScNotificationsReq,
+    ) -> FieldResult<Vec<ScNotification>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_notifications(&conn, context.user_id, &input)))
+            .await
+    }
+    async fn unread_count(
+        context: &Context,
+        input: Option<ScUnreadNotificationReq>,
+    ) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                Ok(get_unread_notification_count(
+                    &conn,
+                    context.user_id,
+                    input.and_then(|input| input.kind),
+                ))
+            })
+            .await
     }
 }
 
@@ -84,9 +622,19 @@ impl MutationRoot {
         leave_lobby(context.user_id);
         Ok("Ok".into())
     }
-    fn lobby_msg(context: &Context, input: ScNewLobbyMessage) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        let user = get_user_basic(&conn, context.user_id)?;
+    async fn lobby_msg(context: &Context, input: ScNewLobbyMessage) -> FieldResult<String> {
+        context.check_restriction(ScRestriction::LobbyReadOnly)?;
+        let user_id = context.user_id;
+        let text = input.text.clone();
+        let user = DB_POOL
+            .run(move |conn| {
+                let user = get_user_basic(&conn, user_id)?;
+                if let Some(filter) = check_text(ScTextKind::Lobby, &text)? {
+                    flag_text(&conn, user_id, ScTextKind::Lobby, &text, filter, None, None);
+                }
+                Ok(user)
+            })
+            .await?;
         notify_ids(
             get_lobby_other_ids(context.user_id),
             ScNotifyMessageBuilder::default()
@@ -102,193 +650,709 @@ impl MutationRoot {
         );
         Ok("Ok".into())
     }
-    fn voice_msg(context: &Context, input: ScVoiceMsgReq) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        let user_id = context.user_id;
-        if let Some(room_id) = get_playing(&conn, user_id).map(|room| room.id) {
-            tokio::spawn(async move {
-                handle_msg(user_id, room_id, input, move |json| {
-                    notify(
-                        user_id,
-                        ScNotifyMessageBuilder::default()
-                            .voice_signal(ScVoiceSignal { json, room_id })
-                            .build()
-                            .unwrap(),
-                    );
-                })
-                .await;
-            });
-        }
-        Ok("ok".into())
+    async fn voice_msg(context: &Context, input: ScVoiceMsgReq) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let user_id = context.user_id;
+                if let Some(room_id) = get_playing(&conn, user_id).map(|room| room.id) {
+                    tokio::spawn(async move {
+                        handle_msg(user_id, room_id, input, move |json| {
+                            notify(
+                                user_id,
+                                ScNotifyMessageBuilder::default()
+                                    .voice_signal(ScVoiceSignal { json, room_id })
+                                    .build()
+                                    .unwrap(),
+                            );
+                        })
+                        .await;
+                    });
+                }
+                Ok("ok".into())
+            })
+            .await
     }
-    fn signaling(context: &Context, input: ScNewSignal) -> FieldResult<String> {
+    async fn get_relay_credentials(
+        context: &Context,
+        input: ScRelayCredentialsReq,
+    ) -> FieldResult<ScRelayCredentials> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_relay_credentials(&conn, context.user_id, &input))
+            .await
+    }
+    fn update_interests(
+        context: &Context,
+        categories: Vec<NotifyCategory>,
+        connection_id: Option<i32>,
+    ) -> FieldResult<Vec<ScConnection>> {
+        update_interests(
+            context.user_id,
+            context.connection.as_ref(),
+            connection_id,
+            categories,
+        )
+    }
+    async fn report_connection(
+        context: &Context,
+        input: ScReportConnectionReq,
+    ) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                report_connection(&conn, context.user_id, &input)?;
+                Ok("Ok".into())
+            })
+            .await
+    }
+    // SDP offers, answers and ICE candidates between peers of a room
+    async fn signaling(context: &Context, input: ScNewSignal) -> FieldResult<String> {
+        let user_id = context.user_id;
+        let target_id = input.target_id;
+        DB_POOL
+            .run(move |conn| check_room_peer(&conn, user_id, target_id))
+            .await?;
         notify(
-            input.target_id,
+            target_id,
             ScNotifyMessageBuilder::default()
                 .send_signal(ScSignal {
                     json: input.json,
-                    user_id: context.user_id,
+                    user_id,
                 })
                 .build()
                 .unwrap(),
         );
         Ok("Ok".into())
     }
-    fn update_account(context: &Context, input: ScUpdateUser) -> FieldResult<ScUser> {
-        let conn = DB_POOL.get().unwrap();
-        update_user(&conn, context.user_id, &input)
-    }
-    fn update_password(context: &Context, input: ScUpdatePassword) -> FieldResult<ScUser> {
-        let conn = DB_POOL.get().unwrap();
-        update_password(&conn, context.user_id, &input)
-    }
-    fn create_game(_context: &Context, input: ScNewGame) -> FieldResult<ScGame> {
-        let conn = DB_POOL.get().unwrap();
-        let game = create_game(&conn, &input)?;
-        notify_all(
-            ScNotifyMessageBuilder::default()
-                .new_game(game.clone())
-                .build()
-                .unwrap(),
-        );
-        Ok(game)
-    }
-    fn create_comment(context: &Context, input: ScNewComment) -> FieldResult<ScComment> {
-        let conn = DB_POOL.get().unwrap();
-        create_comment(&conn, context.user_id, &input)
+    async fn set_status(context: &Context, input: ScSetStatusReq) -> FieldResult<ScUserBasic> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                set_presence(context.user_id, input.status);
+                let user = get_user_basic(&conn, context.user_id)?;
+                notify_ids(
+                    get_friend_ids(&conn, context.user_id),
+                    ScNotifyMessageBuilder::default()
+                        .update_user(user.clone())
+                        .build()
+                        .unwrap(),
+                );
+                Ok(user)
+            })
+            .await
     }
-    fn create_message(context: &Context, input: ScNewMessage) -> FieldResult<ScMessage> {
-        let conn = DB_POOL.get().unwrap();
-        let message = create_message(&conn, context.user_id, &input)?;
-        notify(
-            message.target_id,
-            ScNotifyMessageBuilder::default()
-                .new_message(message.clone())
-                .build()
-                .unwrap(),
-        );
-        Ok(message)
-    }
-    fn read_message(context: &Context, input: ScReadMessage) -> FieldResult<ScFriend> {
-        let conn = DB_POOL.get().unwrap();
-        read_message(&conn, context.user_id, input.target_id)
-    }
-    fn favorite_game(context: &Context, input: ScNewFavorite) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        if input.favorite {
-            create_favorite(&conn, context.user_id, input.game_id).ok();
-            notify(
-                context.user_id,
-                ScNotifyMessageBuilder::default()
-                    .favorite(input.game_id)
-                    .build()
-                    .unwrap(),
-            );
-        } else {
-            delete_favorite(&conn, context.user_id, input.game_id);
-            notify(
-                context.user_id,
+    async fn activity_ping(context: &Context) -> FieldResult<String> {
+        let user_id = context.user_id;
+        if ping_presence(user_id) {
+            let (friend_ids, user) = DB_POOL
+                .run(move |conn| {
+                    Ok::<_, FieldError>((
+                        get_friend_ids(&conn, user_id),
+                        get_user_basic(&conn, user_id)?,
+                    ))
+                })
+                .await?;
+            notify_ids(
+                friend_ids,
                 ScNotifyMessageBuilder::default()
-                    .delete_favorite(input.game_id)
+                    .update_user(user)
                     .build()
                     .unwrap(),
             );
         }
         Ok("Ok".into())
     }
-    fn apply_friend(context: &Context, input: ScNewFriend) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        if let Ok(target_user) = get_user_by_username(&conn, &input.username) {
-            if context.user_id != target_user.id {
-                match apply_friend(&conn, context.user_id, target_user.id) {
-                    Ok(friend) => {
-                        notify(
-                            target_user.id,
-                            ScNotifyMessageBuilder::default()
-                                .apply_friend(friend.clone())
-                                .build()
-                                .unwrap(),
-                        );
-                    }
-                    Err(err) => log::debug!("{:?}", err),
+    async fn update_account(context: &Context, input: ScUpdateUser) -> FieldResult<ScUser> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| update_user(&conn, context.user_id, &input))
+            .await
+    }
+    // Once, the account stays a minor's until it grows up
+    async fn set_birthdate(context: &Context, birthdate: String) -> FieldResult<ScUser> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                set_birthdate(&conn, context.user_id, &birthdate)?;
+                get_account(&conn, context.user_id)
+            })
+            .await
+    }
+    async fn update_password(context: &Context, input: ScUpdatePassword) -> FieldResult<ScUser> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| update_password(&conn, context.user_id, &input))
+            .await
+    }
+    // The token is refused from now on, with its session unless it has none
+    async fn logout(context: &Context, all_sessions: Option<bool>) -> FieldResult<bool> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let all_sessions = all_sessions.unwrap_or_default();
+                let handle = match &context.token {
+                    Some(handle) if context.impersonator_id.is_none() || !all_sessions => handle,
+                    _ => return Err(FieldError::new("not allowed", Error::permission_denied())),
+                };
+                logout(&conn, context.user_id, handle, all_sessions)?;
+                Ok(true)
+            })
+            .await
+    }
+    async fn create_game(_context: &Context, input: ScNewGame) -> FieldResult<ScGame> {
+        DB_POOL
+            .run(move |conn| {
+                let game = conn.transaction(|| {
+                    let game = create_game(&conn, &input, ScGameVersionSource::Admin)?;
+                    enqueue_webhook_event(&conn, ScWebhookEvent::GameCreated, &json!(game))?;
+                    write_outbox_event(&conn, &OutboxEvent::GameCreated { game_id: game.id })?;
+                    Ok::<_, FieldError>(game)
+                })?;
+                wake_outbox();
+                cache_rom(game.id, game.rom.clone(), input.rom_hash);
+                Ok(game)
+            })
+            .await
+    }
+    async fn create_comment(context: &Context, input: ScNewComment) -> FieldResult<ScComment> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| create_comment(&conn, context.user_id, &input))
+            .await
+    }
+    async fn delete_comment(context: &Context, game_id: i32) -> FieldResult<ScComment> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| delete_comment(&conn, context.user_id, game_id))
+            .await
+    }
+    async fn restore_game(context: &Context, id: i32) -> FieldResult<ScGame> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
                 }
-            }
-        }
-        Ok("Ok".into())
+                let game = conn.transaction(|| {
+                    let game = restore_game(&conn, id)?;
+                    enqueue_webhook_event(&conn, ScWebhookEvent::GameCreated, &json!(game))?;
+                    write_outbox_event(&conn, &OutboxEvent::GameCreated { game_id: game.id })?;
+                    Ok::<_, FieldError>(game)
+                })?;
+                wake_outbox();
+                Ok(game)
+            })
+            .await
+    }
+    async fn set_game_mature(context: &Context, game_id: i32, mature: bool) -> FieldResult<ScGame> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                let game = conn.transaction(|| {
+                    let game = set_game_mature(&conn, game_id, mature)?;
+                    enqueue_webhook_event(&conn, ScWebhookEvent::GameUpdated, &json!(game))?;
+                    write_outbox_event(&conn, &OutboxEvent::GameUpdated { game_id })?;
+                    Ok::<_, FieldError>(game)
+                })?;
+                wake_outbox();
+                Ok(game)
+            })
+            .await
+    }
+    async fn hide_comment(context: &Context, game_id: i32, user_id: i32) -> FieldResult<ScComment> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                hide_comment(&conn, user_id, game_id)
+            })
+            .await
+    }
+    async fn restore_comment(
+        context: &Context,
+        game_id: i32,
+        user_id: i32,
+    ) -> FieldResult<ScComment> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                restore_comment(&conn, user_id, game_id)
+            })
+            .await
+    }
+    async fn submit_score(context: &Context, input: ScNewScore) -> FieldResult<ScScore> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| submit_score(&conn, context.user_id, &input))
+            .await
+    }
+    async fn set_achievements(
+        context: &Context,
+        input: ScSetAchievementsReq,
+    ) -> FieldResult<Vec<ScAchievement>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                set_achievements(&conn, &input)
+            })
+            .await
+    }
+    // sent by the running game, friends are notified the first time
+    async fn grant_achievement(
+        context: &Context,
+        input: ScGrantAchievementReq,
+    ) -> FieldResult<ScUserAchievement> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let (unlocked, first) = grant_achievement(&conn, context.user_id, &input)?;
+                if first {
+                    let mut ids = get_friend_ids(&conn, context.user_id);
+                    ids.push(context.user_id);
+                    notify_ids(
+                        ids,
+                        ScNotifyMessageBuilder::default()
+                            .achievement_unlocked(unlocked.clone())
+                            .build()
+                            .unwrap(),
+                    );
+                }
+                Ok(unlocked)
+            })
+            .await
+    }
+    async fn rerender_descriptions(context: &Context) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                Ok(rerender_descriptions(&conn)? as i32)
+            })
+            .await
+    }
+    // upstream image replaced under the same url
+    async fn purge_screenshot(context: &Context, url: String) -> FieldResult<bool> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                purge_screenshot(&url).map_err(|err| FieldError::new(err, Error::internal()))?;
+                Ok(true)
+            })
+            .await
+    }
+    async fn approve_score(context: &Context, id: i32) -> FieldResult<ScScore> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                approve_score(&conn, context.user_id, id)
+            })
+            .await
+    }
+    async fn invalidate_score(
+        context: &Context,
+        input: ScInvalidateScoreReq,
+    ) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                let invalidated = invalidate_score(&conn, context.user_id, &input)?;
+                notify_persisted(
+                    &conn,
+                    invalidated.user_id,
+                    ScNotifyMessageBuilder::default()
+                        .score_invalidated(invalidated.clone())
+                        .build()
+                        .unwrap(),
+                    &json!(invalidated),
+                );
+                Ok("Ok".into())
+            })
+            .await
     }
-    fn accept_friend(context: &Context, input: ScUpdateFriend) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        if input.accept {
-            if let Ok(friend) = accept_friend(&conn, context.user_id, input.target_id) {
+    async fn report_game_problem(
+        context: &Context,
+        input: ScReportGameProblem,
+    ) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let description = create_report(&conn, context.user_id, &input)?;
+                if let (Some(description), Some(issue_url)) =
+                    (description, get_issue_url(&conn, input.game_id))
+                {
+                    let count = get_recent_report_count(&conn, input.game_id);
+                    comment_game_report(input.game_id, &issue_url, description, count);
+                }
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn mark_notifications_read(
+        context: &Context,
+        input: ScMarkNotificationsReadReq,
+    ) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                mark_notifications_read_and_notify(&conn, context.user_id, input.up_to_id)
+            })
+            .await
+    }
+    async fn mark_notification_read(
+        context: &Context,
+        input: ScMarkNotificationReadReq,
+    ) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| mark_notification_read_and_notify(&conn, context.user_id, input.id))
+            .await
+    }
+    async fn create_message(context: &Context, input: ScNewMessage) -> FieldResult<ScMessage> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let message = create_message(&conn, context.user_id, &input)?;
                 notify(
-                    input.target_id,
+                    message.target_id,
                     ScNotifyMessageBuilder::default()
-                        .accept_friend(friend.clone())
+                        .new_message(message.clone())
                         .build()
                         .unwrap(),
                 );
-            }
-        } else {
-            delete_friend(&conn, context.user_id, input.target_id);
-            notify(
-                input.target_id,
-                ScNotifyMessageBuilder::default()
-                    .delete_friend(context.user_id)
-                    .build()
-                    .unwrap(),
-            );
-        }
-        Ok("Ok".into())
+                Ok(message)
+            })
+            .await
     }
-    fn create_invite(context: &Context, input: ScNewInvite) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        let room_id = get_playing(&conn, input.target_id).map(|room| room.id);
-        if Some(input.room_id) != room_id {
-            match create_invite(&conn, context.user_id, &input) {
-                Ok((deleted_invite, invite)) => {
-                    if let Some(deleted_id) = deleted_invite {
-                        notify(
-                            input.target_id,
-                            ScNotifyMessageBuilder::default()
-                                .delete_invite(deleted_id)
-                                .build()
-                                .unwrap(),
-                        );
-                    }
+    async fn update_friend(context: &Context, input: ScUpdateFriendInfo) -> FieldResult<ScFriend> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| update_friend_info(&conn, context.user_id, &input))
+            .await
+    }
+    async fn read_message(context: &Context, input: ScReadMessage) -> FieldResult<ScFriend> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| read_message(&conn, context.user_id, input.target_id))
+            .await
+    }
+    async fn upload_state(context: &Context, input: ScNewSaveState) -> FieldResult<ScSaveState> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| upload_save_state(&conn, user_id, &input))
+            .await
+    }
+    // for states too large for one request
+    async fn begin_state_upload(
+        context: &Context,
+        input: ScBeginStateUpload,
+    ) -> FieldResult<ScStateUpload> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| begin_state_upload(&conn, user_id, &input))
+            .await
+    }
+    async fn finish_state_upload(context: &Context, id: String) -> FieldResult<ScSaveState> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| finish_state_upload(&conn, user_id, &id))
+            .await
+    }
+    async fn delete_state(context: &Context, game_id: i32, slot: i32) -> FieldResult<bool> {
+        let user_id = context.user_id;
+        DB_POOL
+            .run(move |conn| delete_save_state(&conn, user_id, game_id, slot))
+            .await
+    }
+    async fn favorite_game(context: &Context, input: ScNewFavorite) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if input.favorite {
+                    create_favorite(&conn, context.user_id, input.game_id).ok();
+                    notify(
+                        context.user_id,
+                        ScNotifyMessageBuilder::default()
+                            .favorite(input.game_id)
+                            .build()
+                            .unwrap(),
+                    );
+                } else {
+                    delete_favorite(&conn, context.user_id, input.game_id);
                     notify(
-                        invite.target_id,
+                        context.user_id,
                         ScNotifyMessageBuilder::default()
-                            .new_invite(invite.clone())
+                            .delete_favorite(input.game_id)
                             .build()
                             .unwrap(),
                     );
                 }
-                Err(err) => log::debug!("{:?}", err),
-            }
-        }
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn create_collection(
+        context: &Context,
+        input: ScNewCollection,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| create_collection(&conn, context.user_id, &input))
+            .await
+    }
+    async fn rename_collection(
+        context: &Context,
+        id: i32,
+        name: String,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| rename_collection(&conn, context.user_id, id, &name))
+            .await
+    }
+    async fn set_collection_public(
+        context: &Context,
+        id: i32,
+        public: bool,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| set_collection_public(&conn, context.user_id, id, public))
+            .await
+    }
+    async fn delete_collection(context: &Context, id: i32) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| delete_collection(&conn, context.user_id, id))
+            .await
+    }
+    async fn add_to_collection(
+        context: &Context,
+        id: i32,
+        game_id: i32,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| add_to_collection(&conn, context.user_id, id, game_id))
+            .await
+    }
+    async fn remove_from_collection(
+        context: &Context,
+        id: i32,
+        game_id: i32,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| remove_from_collection(&conn, context.user_id, id, game_id))
+            .await
+    }
+    // `gameIds` lists every game of the collection in the new order
+    async fn reorder_collection(
+        context: &Context,
+        id: i32,
+        game_ids: Vec<i32>,
+    ) -> FieldResult<ScCollection> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| reorder_collection(&conn, context.user_id, id, &game_ids))
+            .await
+    }
+    async fn apply_friend(context: &Context, input: ScNewFriend) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if let Ok(target_user) = get_user_by_username(&conn, &input.username) {
+                    let same_tenant = get_user_tenant(&conn, target_user.id) == context.tenant_id;
+                    if context.user_id != target_user.id && same_tenant {
+                        let applied = conn.transaction(|| {
+                            apply_friend(&conn, context.user_id, target_user.id)?;
+                            persist_notification(
+                                &conn,
+                                target_user.id,
+                                NotifyKind::ApplyFriend,
+                                &json!({ "userId": context.user_id }),
+                            )?;
+                            write_outbox_event(
+                                &conn,
+                                &OutboxEvent::ApplyFriend {
+                                    user_id: context.user_id,
+                                    target_id: target_user.id,
+                                },
+                            )?;
+                            Ok::<_, FieldError>(())
+                        });
+                        match applied {
+                            Ok(()) => wake_outbox(),
+                            Err(err) => log::debug!("{:?}", err),
+                        }
+                    }
+                }
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn accept_friend(context: &Context, input: ScUpdateFriend) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let (user_id, target_id) = (context.user_id, input.target_id);
+                let updated = conn.transaction(|| {
+                    if input.accept {
+                        accept_friend(&conn, user_id, target_id)?;
+                        write_outbox_event(
+                            &conn,
+                            &OutboxEvent::AcceptFriend { user_id, target_id },
+                        )?;
+                    } else {
+                        delete_friend(&conn, user_id, target_id);
+                        write_outbox_event(
+                            &conn,
+                            &OutboxEvent::DeleteFriend { user_id, target_id },
+                        )?;
+                    }
+                    Ok::<_, FieldError>(())
+                });
+                match updated {
+                    Ok(()) => wake_outbox(),
+                    Err(err) => log::debug!("{:?}", err),
+                }
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn create_invite(context: &Context, input: ScNewInvite) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                check_same_tenant(get_user_tenant(&conn, input.target_id), context.tenant_id)?;
+                let room_id = get_playing(&conn, input.target_id).map(|room| room.id);
+                if Some(input.room_id) != room_id {
+                    let created = conn.transaction(|| {
+                        let (deleted_invite, invite) =
+                            create_invite(&conn, context.user_id, &input)?;
+                        if let Some(deleted_id) = deleted_invite {
+                            write_outbox_event(
+                                &conn,
+                                &OutboxEvent::DeleteInvite {
+                                    invite_id: deleted_id,
+                                    target_id: invite.target_id,
+                                },
+                            )?;
+                        }
+                        persist_notification(
+                            &conn,
+                            invite.target_id,
+                            NotifyKind::NewInvite,
+                            &json!({
+                                "inviteId": invite.id,
+                                "roomId": invite.room.id,
+                                "userId": context.user_id,
+                            }),
+                        )?;
+                        write_outbox_event(
+                            &conn,
+                            &OutboxEvent::NewInvite {
+                                invite_id: invite.id,
+                                target_id: invite.target_id,
+                            },
+                        )?;
+                        Ok::<_, FieldError>(())
+                    });
+                    match created {
+                        Ok(()) => wake_outbox(),
+                        Err(err) => log::debug!("{:?}", err),
+                    }
+                }
 
-        Ok("Ok".into())
+                Ok("Ok".into())
+            })
+            .await
     }
-    fn accept_invite(context: &Context, input: ScUpdateInvite) -> FieldResult<String> {
-        let conn = DB_POOL.get().unwrap();
-        let invite = get_invite(&conn, context.user_id, input.invite_id)?;
+    async fn accept_invite(context: &Context, input: ScUpdateInvite) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let invite = get_invite(&conn, context.user_id, input.invite_id)?;
 
-        if input.accept {
-            let (room_id, room_host) = get_playing(&conn, context.user_id)
-                .map(|room| (room.id, room.host))
-                .unwrap_or((0, 0));
+                if input.accept {
+                    let (room_id, room_host) = get_playing(&conn, context.user_id)
+                        .map(|room| (room.id, room.host))
+                        .unwrap_or((0, 0));
 
-            if room_id != invite.room.id {
-                if room_host == context.user_id {
-                    delete_room(&conn, room_id);
-                    notify_all(
-                        ScNotifyMessageBuilder::default()
-                            .delete_room(room_id)
-                            .build()
-                            .unwrap(),
-                    );
+                    if room_id != invite.room.id {
+                        check_room_ban(invite.room.id, context.user_id)?;
+                        if is_mature_game(&conn, invite.room.game_id) {
+                            context.check_restriction(ScRestriction::MatureGames)?;
+                        }
+                        if room_host == context.user_id {
+                            delete_room(&conn, room_id);
+                            if let Err(err) = notify_tenant(
+                                context.tenant_id,
+                                ScNotifyMessageBuilder::default()
+                                    .delete_room(room_id)
+                                    .build()
+                                    .unwrap(),
+                            ) {
+                                log::error!("Notify delete room: {:?}", err);
+                            }
+                        }
+                        enter_room(&conn, context.user_id, invite.room.id);
+                        record_join_success(invite.room.id, context.user_id);
+                        log_join_attempt(
+                            invite.room.id,
+                            context.user_id,
+                            context.ip.clone(),
+                            true,
+                            Utc::now(),
+                        );
+                        notify_ids(
+                            get_friend_ids(&conn, context.user_id),
+                            ScNotifyMessageBuilder::default()
+                                .update_user(get_user_basic(&conn, context.user_id)?)
+                                .build()
+                                .unwrap(),
+                        );
+                    }
+                } else {
+                    delete_invite_by_id(&conn, context.user_id, input.invite_id);
                 }
-                enter_room(&conn, context.user_id, invite.room.id);
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn create_room(context: &Context, input: ScNewRoom) -> FieldResult<ScRoomBasic> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !input.private {
+                    context.check_restriction(ScRestriction::NightHosting)?;
+                }
+                if is_mature_game(&conn, input.game_id) {
+                    context.check_restriction(ScRestriction::MatureGames)?;
+                }
+                let room = create_room(&conn, context.user_id, context.tenant_id, &input)?;
                 notify_ids(
                     get_friend_ids(&conn, context.user_id),
                     ScNotifyMessageBuilder::default()
@@ -296,88 +1360,532 @@ impl MutationRoot {
                         .build()
                         .unwrap(),
                 );
-            }
-        } else {
-            delete_invite_by_id(&conn, context.user_id, input.invite_id);
-        }
-        Ok("Ok".into())
+                Ok(room)
+            })
+            .await
     }
-    fn create_room(context: &Context, input: ScNewRoom) -> FieldResult<ScRoomBasic> {
-        let conn = DB_POOL.get().unwrap();
-        let room = create_room(&conn, context.user_id, &input)?;
-        notify_ids(
-            get_friend_ids(&conn, context.user_id),
-            ScNotifyMessageBuilder::default()
-                .update_user(get_user_basic(&conn, context.user_id)?)
-                .build()
-                .unwrap(),
-        );
-        Ok(room)
-    }
-    fn update_room(context: &Context, input: ScUpdateRoom) -> FieldResult<ScRoomBasic> {
-        let conn = DB_POOL.get().unwrap();
-        let room = update_room(&conn, context.user_id, &input)?;
-        notify_ids(
-            get_friend_ids(&conn, context.user_id),
-            ScNotifyMessageBuilder::default()
-                .update_user(get_user_basic(&conn, context.user_id)?)
-                .build()
-                .unwrap(),
-        );
-        notify_ids(
-            get_room_user_ids(&conn, input.id),
-            ScNotifyMessageBuilder::default()
-                .update_room(get_room(&conn, input.id)?)
-                .build()
-                .unwrap(),
-        );
+    async fn update_room(context: &Context, input: ScUpdateRoom) -> FieldResult<ScRoomBasic> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !input.private {
+                    context.check_restriction(ScRestriction::NightHosting)?;
+                }
+                if is_mature_game(&conn, input.game_id) {
+                    context.check_restriction(ScRestriction::MatureGames)?;
+                }
+                let room = update_room(&conn, context.user_id, &input)?;
+                if input.spectator_policy == Some(ScSpectatorPolicy::Closed) {
+                    evict_spectators(room.id);
+                }
+                notify_ids(
+                    get_friend_ids(&conn, context.user_id),
+                    ScNotifyMessageBuilder::default()
+                        .update_user(get_user_basic(&conn, context.user_id)?)
+                        .build()
+                        .unwrap(),
+                );
+                notify_room_update(&conn, input.id)?;
 
-        Ok(room)
+                Ok(room)
+            })
+            .await
     }
-    fn update_room_screenshot(
+    async fn update_room_screenshot(
         context: &Context,
         input: ScUpdateRoomScreenshot,
     ) -> FieldResult<ScRoomBasic> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(update_room_screenshot(&conn, context.user_id, &input)?)
-    }
-    fn enter_pub_room(context: &Context, input: ScUpdatePlaying) -> FieldResult<ScRoomBasic> {
-        let conn = DB_POOL.get().unwrap();
-        let room = get_room(&conn, input.room_id)?;
-        if room.private {
-            return Err("private room".into());
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(update_room_screenshot(&conn, context.user_id, &input)?))
+            .await
+    }
+    async fn enter_pub_room(context: &Context, input: ScUpdatePlaying) -> FieldResult<ScRoomBasic> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let room = get_room(&conn, input.room_id)?;
+                // Rejoin after crash, peers re-signal after update room
+                if get_playing(&conn, context.user_id).map(|room| room.id) == Some(room.id) {
+                    notify_room_update(&conn, room.id)?;
+                    return Ok(room);
+                }
+                check_same_tenant(get_room_tenant(&conn, room.id), context.tenant_id)?;
+                if is_mature_game(&conn, room.game_id) {
+                    context.check_restriction(ScRestriction::MatureGames)?;
+                }
+                if room.private && !room.has_password {
+                    return Err(FieldError::new("private room", Error::permission_denied()));
+                }
+                let now = Utc::now();
+                if room.private {
+                    check_room_join_lock(room.id, context.user_id, now)?;
+                    let password = input.password.unwrap_or_default();
+                    if !check_room_password(&conn, room.id, &password) {
+                        log_join_attempt(room.id, context.user_id, context.ip.clone(), false, now);
+                        if let Some(alert) = record_join_failure(room.id, context.user_id, now) {
+                            notify(
+                                room.host,
+                                ScNotifyMessageBuilder::default()
+                                    .room_join_failures(alert)
+                                    .build()
+                                    .unwrap(),
+                            );
+                        }
+                        return Err(FieldError::new(
+                            "wrong room password",
+                            Error::wrong_room_password(),
+                        ));
+                    }
+                }
+                check_room_ban(room.id, context.user_id)?;
+                if input.spectate.unwrap_or_default() {
+                    check_spectate(&conn, &room, context.user_id)?;
+                    add_spectator(room.id, context.user_id);
+                    return Ok(get_room(&conn, room.id)?);
+                }
+                enter_room(&conn, context.user_id, input.room_id);
+                record_join_success(room.id, context.user_id);
+                log_join_attempt(room.id, context.user_id, context.ip.clone(), true, now);
+                notify_ids(
+                    get_friend_ids(&conn, context.user_id),
+                    ScNotifyMessageBuilder::default()
+                        .update_user(get_user_basic(&conn, context.user_id)?)
+                        .build()
+                        .unwrap(),
+                );
+                Ok(room)
+            })
+            .await
+    }
+    async fn create_room_link(context: &Context, input: ScNewRoomLink) -> FieldResult<ScRoomLink> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| create_room_link(&conn, context.user_id, &context.secret, &input))
+            .await
+    }
+    async fn revoke_room_link(context: &Context, link_id: i32) -> FieldResult<ScRoomLink> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| revoke_room_link(&conn, context.user_id, &context.secret, link_id))
+            .await
+    }
+    async fn join_by_link(context: &Context, token: String) -> FieldResult<ScRoomBasic> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let (playing_id, playing_host) = get_playing(&conn, context.user_id)
+                    .map(|room| (room.id, room.host))
+                    .unwrap_or((0, 0));
+                // The use is only counted along with the join
+                let room_id = conn.transaction(|| {
+                    let room_id = redeem_room_link(
+                        &conn,
+                        context.user_id,
+                        context.tenant_id,
+                        &context.secret,
+                        &token,
+                        |room| {
+                            if is_mature_game(&conn, room.game_id) {
+                                context.check_restriction(ScRestriction::MatureGames)?;
+                            }
+                            Ok(())
+                        },
+                    )?;
+                    if playing_id != room_id {
+                        if playing_host == context.user_id {
+                            delete_room(&conn, playing_id);
+                        }
+                        enter_room(&conn, context.user_id, room_id);
+                    }
+                    Ok::<_, FieldError>(room_id)
+                })?;
+                if playing_id != room_id {
+                    if playing_host == context.user_id {
+                        if let Err(err) = notify_tenant(
+                            context.tenant_id,
+                            ScNotifyMessageBuilder::default()
+                                .delete_room(playing_id)
+                                .build()
+                                .unwrap(),
+                        ) {
+                            log::error!("Notify delete room: {:?}", err);
+                        }
+                    }
+                    record_join_success(room_id, context.user_id);
+                    log_join_attempt(
+                        room_id,
+                        context.user_id,
+                        context.ip.clone(),
+                        true,
+                        Utc::now(),
+                    );
+                    notify_ids(
+                        get_friend_ids(&conn, context.user_id),
+                        ScNotifyMessageBuilder::default()
+                            .update_user(get_user_basic(&conn, context.user_id)?)
+                            .build()
+                            .unwrap(),
+                    );
+                }
+                Ok(get_room(&conn, room_id)?)
+            })
+            .await
+    }
+    async fn update_core_version(
+        context: &Context,
+        input: ScUpdateCoreVersion,
+    ) -> FieldResult<ScCompatibility> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                let compatibility = update_core_version(&conn, &input)?;
+                let announcement = format!(
+                    "{} core updated, minimum supported version is {}",
+                    input.platform, input.minimum
+                );
+                emit_webhook_event(
+                    ScWebhookEvent::Announcement,
+                    json!({ "message": announcement }),
+                );
+                if let Err(err) = notify_all(
+                    ScNotifyMessageBuilder::default()
+                        .announcement(announcement)
+                        .build()
+                        .unwrap(),
+                ) {
+                    log::error!("Notify announcement: {:?}", err);
+                }
+                Ok(compatibility)
+            })
+            .await
+    }
+    // Connected clients keep their socket, the minimum applies at the next connect
+    async fn update_client_version(
+        context: &Context,
+        input: ScUpdateClientVersion,
+    ) -> FieldResult<Vec<ScClientVersion>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                update_client_version(&conn, &input)
+            })
+            .await
+    }
+    async fn update_retention_policy(
+        context: &Context,
+        input: ScUpdateRetentionPolicy,
+    ) -> FieldResult<Vec<ScRetentionStatus>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                update_retention_policy(&conn, &input)
+            })
+            .await
+    }
+    // Returns the number of entries
+    async fn reload_blocked_words(context: &Context) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                reload_blocked_words().map_err(|err| FieldError::new(err, Error::internal()))
+            })
+            .await
+    }
+    // Old keys stay accepted for `SECRET_GRACE_SECONDS`
+    async fn rotate_secret(context: &Context) -> FieldResult<Vec<ScSigningKey>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                let keys = rotate_secret(&conn, &context.secret)?;
+                let kid = keys.first().map(|key| key.kid.as_str()).unwrap_or_default();
+                write_audit_log(
+                    &conn,
+                    context.user_id,
+                    context.user_id,
+                    "rotate_secret",
+                    kid,
+                )?;
+                Ok(keys)
+            })
+            .await
+    }
+    async fn update_feature_flag(
+        context: &Context,
+        input: ScUpdateFeatureFlag,
+    ) -> FieldResult<Vec<ScFeatureFlagConfig>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                update_feature_flag(&conn, &input)
+            })
+            .await
+    }
+    async fn create_tenant(context: &Context, input: ScNewTenant) -> FieldResult<ScTenant> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) || context.tenant_id.is_some() {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                create_tenant(&conn, &input)
+            })
+            .await
+    }
+    async fn play_heartbeat(context: &Context, input: ScRecordReq) -> FieldResult<bool> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| play_heartbeat(&conn, context.user_id, input.game_id))
+            .await
+    }
+    async fn repair_playtime(context: &Context, input: ScRepairPlaytimeReq) -> FieldResult<i32> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                repair_playtime(&conn, input.max_hours)
+            })
+            .await
+    }
+    async fn impersonate(context: &Context, input: ScImpersonateReq) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) || context.impersonator_id.is_some() {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                impersonate(&conn, &context.secret, context.user_id, input.user_id)
+            })
+            .await
+    }
+    async fn create_webhook(context: &Context, input: ScNewWebhook) -> FieldResult<ScWebhook> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                create_webhook(&conn, &input)
+            })
+            .await
+    }
+    async fn delete_webhook(context: &Context, input: ScWebhookReq) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if !is_admin(&conn, context.user_id) {
+                    return Err(FieldError::new("admin only", Error::permission_denied()));
+                }
+                delete_webhook(&conn, input.id)?;
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn kick_from_room(context: &Context, input: ScKickFromRoom) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                kick_from_room(&conn, context.user_id, &input)?;
+                notify(
+                    input.user_id,
+                    ScNotifyMessageBuilder::default()
+                        .kicked_room(input.room_id)
+                        .build()
+                        .unwrap(),
+                );
+                notify_room_update(&conn, input.room_id)?;
+                notify_ids(
+                    get_friend_ids(&conn, input.user_id),
+                    ScNotifyMessageBuilder::default()
+                        .update_user(get_user_basic(&conn, input.user_id)?)
+                        .build()
+                        .unwrap(),
+                );
+                tokio::spawn(close_rtc(input.user_id, input.room_id));
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn leave_room(context: &Context) -> FieldResult<String> {
+        if remove_spectator(context.user_id).is_some() {
+            return Ok("Ok".into());
         }
-        enter_room(&conn, context.user_id, input.room_id);
-        notify_ids(
-            get_friend_ids(&conn, context.user_id),
-            ScNotifyMessageBuilder::default()
-                .update_user(get_user_basic(&conn, context.user_id)?)
-                .build()
-                .unwrap(),
-        );
-        Ok(room)
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| leave_room_and_notify(&conn, context.user_id))
+            .await
+    }
+    async fn send_room_command(context: &Context, input: ScRoomCommandReq) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let command = check_room_command(&conn, context.user_id, &input)?;
+                let host = get_room(&conn, input.room_id)?.host;
+                notify(
+                    host,
+                    ScNotifyMessageBuilder::default()
+                        .room_command(command)
+                        .build()
+                        .unwrap(),
+                );
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn report_room_stats(context: &Context, input: ScRoomStatsReq) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                if report_room_stats(&conn, context.user_id, &input)? {
+                    notify_room_update(&conn, input.room_id)?;
+                }
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn set_authority(context: &Context, room_id: i32, user_id: i32) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let changed = set_authority(&conn, context.user_id, room_id, user_id)?;
+                notify_ids(
+                    get_room_user_ids(&conn, room_id),
+                    ScNotifyMessageBuilder::default()
+                        .authority_changed(changed)
+                        .build()
+                        .unwrap(),
+                );
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn mute_room_user(context: &Context, input: ScMuteRoomUser) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                mute_room_user(&conn, context.user_id, &input)?;
+                Ok("Ok".into())
+            })
+            .await
+    }
+    async fn start_trial_play(context: &Context, game_id: i32) -> FieldResult<ScTrialPlay> {
+        let anon_id = context.anon_id.clone().ok_or(FieldError::new(
+            "trial is for anonymous sessions",
+            Error::permission_denied(),
+        ))?;
+        let ip = context.ip.clone();
+        DB_POOL
+            .run(move |conn| start_trial_play(&conn, &anon_id, ip.as_deref(), game_id))
+            .await
+    }
+    fn end_trial_play(context: &Context) -> FieldResult<String> {
+        if let Some(anon_id) = &context.anon_id {
+            end_trial(anon_id, Utc::now());
+        }
+        Ok("Ok".into())
+    }
+    async fn set_room_moderator(
+        context: &Context,
+        input: ScSetRoomModerator,
+    ) -> FieldResult<String> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                update_room_moderator(&conn, context.user_id, &input)?;
+                notify_room_update(&conn, input.room_id)?;
+                Ok("Ok".into())
+            })
+            .await
     }
-    fn leave_room(context: &Context) -> FieldResult<String> {
-        leave_room_and_notify(context.user_id)
+}
+
+/// Return unread count
+fn notify_unread_changed(conn: &PgConnection, user_id: i32) -> i32 {
+    let count = get_unread_notification_count(conn, user_id, None);
+    notify(
+        user_id,
+        ScNotifyMessageBuilder::default()
+            .unread_changed(count)
+            .build()
+            .unwrap(),
+    );
+    count
+}
+
+fn mark_notifications_read_and_notify(
+    conn: &PgConnection,
+    user_id: i32,
+    up_to_id: i32,
+) -> FieldResult<i32> {
+    mark_notifications_read(conn, user_id, up_to_id)?;
+    Ok(notify_unread_changed(conn, user_id))
+}
+
+fn mark_notification_read_and_notify(
+    conn: &PgConnection,
+    user_id: i32,
+    id: i32,
+) -> FieldResult<i32> {
+    mark_notification_read(conn, user_id, id)?;
+    Ok(notify_unread_changed(conn, user_id))
+}
+
+/// Free seat of user who disconnected abruptly
+pub fn free_seat_and_notify(conn: &PgConnection, user_id: i32) -> FieldResult<String> {
+    let room_id = get_playing(conn, user_id).map(|room| room.id);
+    leave_room_and_notify(conn, user_id)?;
+    if let Some(room) = room_id.and_then(|room_id| get_room(conn, room_id).ok()) {
+        notify_room_update(conn, room.id)?;
     }
+    Ok("Ok".into())
 }
 
-pub fn leave_room_and_notify(user_id: i32) -> FieldResult<String> {
-    let conn = DB_POOL.get().unwrap();
-    let room = get_playing(&conn, user_id).ok_or(FieldError::new(
+pub fn leave_room_and_notify(conn: &PgConnection, user_id: i32) -> FieldResult<String> {
+    let room = get_playing(conn, user_id).ok_or(FieldError::new(
         format!("{} not playing", user_id),
         Error::username_not_playing(),
     ))?;
-    let invites = get_invites_with(&conn, user_id);
-    leave_room(&conn, user_id, room.id);
-    if user_id == room.host {
-        delete_room(&conn, room.id);
-        notify_all(
+    let invites = get_invites_with(conn, user_id);
+    leave_room(conn, user_id, room.id);
+    let next_host = if user_id == room.host {
+        pick_next_host(&get_room_members(conn, room.id), has_user)
+    } else {
+        None
+    };
+    if let Some(next_host) = next_host {
+        transfer_room_host(conn, room.id, next_host)?;
+        notify_room_update(conn, room.id)?;
+    } else if user_id == room.host {
+        delete_room(conn, room.id);
+        if let Err(err) = notify_tenant(
+            get_user_tenant(conn, room.host),
             ScNotifyMessageBuilder::default()
                 .delete_room(room.id)
                 .build()
                 .unwrap(),
-        )
+        ) {
+            log::error!("Notify delete room: {:?}", err);
+        }
     }
     for invite in invites {
         notify(
@@ -389,9 +1897,9 @@ pub fn leave_room_and_notify(user_id: i32) -> FieldResult<String> {
         );
     }
     notify_ids(
-        get_friend_ids(&conn, user_id),
+        get_friend_ids(conn, user_id),
         ScNotifyMessageBuilder::default()
-            .update_user(get_user_basic(&conn, user_id)?)
+            .update_user(get_user_basic(conn, user_id)?)
             .build()
             .unwrap(),
     );
@@ -406,30 +1914,141 @@ type FriendSysStream = Pin<Box<dyn Stream<Item = Result<ScNotifyMessage, FieldEr
 impl Subscription {
     async fn event(context: &Context) -> FriendSysStream {
         let mut rx = get_receiver(context.user_id);
+        set_notify_tenant(context.user_id, context.tenant_id);
+        let hint = ScNotifyMessageBuilder::default()
+            .reconnect_hint(get_reconnect_hint())
+            .build()
+            .unwrap();
+        let connection = context.connection.clone();
+        // Kinds the client didn't declare in `clientInfo` become `refreshHint`
+        let downgrade = |connection: &Option<Arc<SubscriptionConnection>>, msg: ScNotifyMessage| {
+            match connection {
+                Some(connection) => connection.downgrade(msg),
+                None => msg,
+            }
+        };
+        let mut compact = connection
+            .as_ref()
+            .filter(|connection| connection.is_compact())
+            .map(|_| CompactRenderer::default());
         let stream = async_stream::stream! {
+            yield Ok(downgrade(&connection, hint));
             loop {
-                let result = rx.0.recv().await.unwrap();
-                yield Ok(result)
+                match rx.recv().await {
+                    Ok(result) => {
+                        let sid = connection.as_ref().and_then(|connection| connection.session_id());
+                        match result.ends_subscription(sid.as_deref()) {
+                            Some(false) => continue,
+                            // last event, a revoked token can't subscribe again
+                            // and a kicked user comes back outside the room
+                            Some(true) => {
+                                yield Ok(downgrade(&connection, result));
+                                break;
+                            }
+                            None => (),
+                        }
+                        if let Some(connection) = &connection {
+                            if !connection.accepts(&result) {
+                                continue;
+                            }
+                        }
+                        let result = match compact.as_mut() {
+                            Some(renderer) => match renderer.render(result) {
+                                Some(result) => result,
+                                None => continue,
+                            },
+                            None => result,
+                        };
+                        if let Some(connection) = &connection {
+                            connection.add_event();
+                        }
+                        yield Ok(downgrade(&connection, result))
+                    }
+                    // overflow, counted per kind by the receiver
+                    Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => break,
+                }
             }
         };
 
-        let conn = DB_POOL.get().unwrap();
-        if let Ok(user) = get_user_basic(&conn, context.user_id) {
-            notify_ids(
-                get_friend_ids(&conn, context.user_id),
-                ScNotifyMessageBuilder::default()
-                    .update_user(user)
-                    .build()
-                    .unwrap(),
-            );
+        let user_id = context.user_id;
+        let online = DB_POOL
+            .run(move |conn| {
+                if let Ok(user) = get_user_basic(&conn, user_id) {
+                    notify_ids(
+                        get_friend_ids(&conn, user_id),
+                        ScNotifyMessageBuilder::default()
+                            .update_user(user)
+                            .build()
+                            .unwrap(),
+                    );
+                }
+                Ok::<_, String>(())
+            })
+            .await;
+        if let Err(err) = online {
+            log::error!("Notify online {}: {}", user_id, err);
         }
 
         Box::pin(stream)
     }
 }
 
+#[derive(Clone)]
 pub struct Context {
     pub user_id: i32,
+    // `None` is the default public tenant
+    pub tenant_id: Option<i32>,
+    // Admin acting as `user_id`
+    pub impersonator_id: Option<i32>,
+    // Signs impersonation tokens
+    pub secret: String,
+    // Unregistered session, `user_id` is 0 and only the public catalog is readable
+    pub anon_id: Option<String>,
+    // Client address from a trusted proxy, for audit trails and trial limits
+    pub ip: Option<String>,
+    // Subscription socket, `None` for plain requests
+    pub connection: Option<Arc<SubscriptionConnection>>,
+    // Every feature flag resolved for the caller when the request started
+    pub features: BTreeMap<String, bool>,
+    // Derived from the birthdate, which never leaves the server
+    pub is_minor: bool,
+    // Token of the request, `None` for anonymous ones
+    pub token: Option<TokenHandle>,
+}
+
+impl Context {
+    pub fn is_anonymous(&self) -> bool {
+        self.anon_id.is_some()
+    }
+
+    /// Guard of resolvers behind a flag, unknown flags are disabled
+    pub fn check_feature(&self, name: &str) -> FieldResult<()> {
+        if self.features.get(name) == Some(&true) {
+            Ok(())
+        } else {
+            Err(FieldError::new(
+                format!("{} is not available yet", name),
+                Error::feature_disabled(name),
+            ))
+        }
+    }
+
+    pub fn is_restricted(&self, restriction: ScRestriction) -> bool {
+        RESTRICTION_CONFIG.applies(restriction, self.is_minor, Utc::now())
+    }
+
+    /// Guard of resolvers limited for minors, see `MINOR_RESTRICTIONS`
+    pub fn check_restriction(&self, restriction: ScRestriction) -> FieldResult<()> {
+        if self.is_restricted(restriction) {
+            Err(FieldError::new(
+                "not available for minors",
+                Error::restricted(&restriction.to_string()),
+            ))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl juniper::Context for Context {}
@@ -440,8 +2059,12 @@ pub fn create_schema() -> Schema {
     Schema::new(QueryRoot {}, MutationRoot {}, Subscription {})
 }
 
+#[derive(Clone)]
 pub struct GuestContext {
     pub secret: String,
+    pub tenant_id: Option<i32>,
+    // Bearer of the request, revoked once it registers
+    pub anon_token: Option<String>,
 }
 
 pub struct GuestQueryRoot;
@@ -452,24 +2075,60 @@ impl GuestQueryRoot {
         Ok("guest".to_owned())
     }
 
-    fn games(_context: &GuestContext) -> FieldResult<Vec<ScGame>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_games(&conn))
+    async fn games(
+        _context: &GuestContext,
+        kinds: Option<Vec<ScGameKind>>,
+        match_all: Option<bool>,
+    ) -> FieldResult<Vec<ScGame>> {
+        DB_POOL
+            .run(move |conn| Ok(get_games(&conn, kinds, match_all.unwrap_or_default())))
+            .await
+    }
+    // landing page of a shared link, before login
+    async fn room_link_info(context: &GuestContext, token: String) -> FieldResult<ScRoomLinkInfo> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| get_room_link_info(&conn, &context.secret, &token))
+            .await
+    }
+    // polled by federated instances
+    async fn games_delta(
+        _context: &GuestContext,
+        input: ScGamesDeltaReq,
+    ) -> FieldResult<ScGamesDelta> {
+        DB_POOL
+            .run(move |conn| get_games_delta(&conn, input.since_version))
+            .await
+    }
+    fn error_codes(_context: &GuestContext) -> FieldResult<Vec<ErrorCode>> {
+        Ok(ErrorCode::all())
+    }
+    async fn kinds(_context: &GuestContext) -> FieldResult<Vec<ScGameKindCount>> {
+        DB_POOL.run(move |conn| Ok(get_kind_counts(&conn))).await
     }
 
-    fn top_games(_context: &GuestContext) -> FieldResult<Vec<i32>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_top_ids(&conn))
+    async fn top_games(_context: &GuestContext) -> FieldResult<Vec<i32>> {
+        DB_POOL.run(move |conn| Ok(get_top_ids(&conn))).await
     }
 
-    fn comments(_context: &GuestContext, input: ScCommentsReq) -> FieldResult<Vec<ScComment>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_comments(&conn, input.game_id))
+    async fn comments(
+        _context: &GuestContext,
+        input: ScCommentsReq,
+    ) -> FieldResult<Vec<ScComment>> {
+        DB_POOL
+            .run(move |conn| Ok(get_comments(&conn, input.game_id, None)))
+            .await
     }
 
-    fn rooms(_context: &GuestContext) -> FieldResult<Vec<ScRoom>> {
-        let conn = DB_POOL.get().unwrap();
-        Ok(get_rooms(&conn))
+    async fn rooms(
+        context: &GuestContext,
+        game_id: Option<i32>,
+        mode: Option<ScPlayMode>,
+    ) -> FieldResult<Vec<ScRoom>> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| Ok(get_rooms(&conn, context.tenant_id, game_id, mode)))
+            .await
     }
 }
 
@@ -479,26 +2138,61 @@ impl juniper::Context for GuestContext {}
 
 #[juniper::graphql_object(Context = GuestContext)]
 impl GuestMutationRoot {
-    fn register(context: &GuestContext, input: ScRegisterReq) -> FieldResult<ScLoginResp> {
-        let conn = DB_POOL.get().unwrap();
-        register(&conn, input, &context.secret)
-    }
-
-    fn login(context: &GuestContext, input: ScLoginReq) -> FieldResult<ScLoginResp> {
-        let conn = DB_POOL.get().unwrap();
-        let disable_sso = input.disable_sso.unwrap_or_default();
-        let resp = login(&conn, input, &context.secret)?;
-        if !disable_sso {
-            notify(
-                resp.user.id,
-                ScNotifyMessageBuilder::default()
-                    .login(true)
-                    .build()
-                    .unwrap(),
-            );
+    async fn register(context: &GuestContext, input: ScRegisterReq) -> FieldResult<ScLoginResp> {
+        check_register(&input).await?;
+        let (tenant_id, secret) = (context.tenant_id, context.secret.clone());
+        let resp = DB_POOL
+            .run(move |conn| register(&conn, input, tenant_id, &secret))
+            .await?;
+        if let Some(token) = &context.anon_token {
+            AnonToken::revoke(&context.secret, token);
         }
         Ok(resp)
     }
+    fn anonymous_session(context: &GuestContext) -> FieldResult<String> {
+        Ok(AnonToken::generate_token(&context.secret))
+    }
+    // Rotates the refresh token, the one sent stops working
+    async fn refresh_token(
+        context: &GuestContext,
+        refresh_token: String,
+    ) -> FieldResult<ScRefreshResp> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                refresh_access_token(&conn, &refresh_token, context.tenant_id, &context.secret)
+            })
+            .await
+    }
+    // Signs the session out, its access tokens are refused from then on
+    async fn revoke_refresh_token(
+        _context: &GuestContext,
+        refresh_token: String,
+    ) -> FieldResult<bool> {
+        DB_POOL
+            .run(move |conn| Ok(revoke_refresh_token(&conn, &refresh_token)?))
+            .await
+    }
+
+    async fn login(context: &GuestContext, input: ScLoginReq) -> FieldResult<ScLoginResp> {
+        let context = context.clone();
+        DB_POOL
+            .run(move |conn| {
+                let disable_sso = input.disable_sso.unwrap_or_default();
+                let resp = login(&conn, input, context.tenant_id, &context.secret)?;
+                if !disable_sso {
+                    notify(
+                        resp.user.id,
+                        ScNotifyMessageBuilder::default()
+                            .login(true)
+                            .build()
+                            .unwrap(),
+                    );
+                }
+                Ok(resp)
+            })
+            .await
+    }
 }
 
 pub type GuestSchema =
@@ -511,3 +2205,147 @@ pub fn create_guest_schema() -> GuestSchema {
         EmptySubscription::new(),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::root::*;
+    use juniper::{introspect, IntrospectionFormat};
+    use serde_json::Value;
+
+    // Deprecated snake_case aliases still consumed by released clients
+    const SNAKE_CASE_ALIASES: &[&str] = &[];
+
+    /// `Type.field`, `Type.field(arg)` and `Input.field` names containing `_`
+    fn get_snake_case_names(schema: &Value) -> Vec<String> {
+        let mut names = Vec::new();
+        let types = schema["__schema"]["types"].as_array().unwrap();
+        for t in types {
+            let type_name = t["name"].as_str().unwrap_or_default();
+            if type_name.starts_with("__") {
+                continue;
+            }
+            let fields = t["fields"].as_array().into_iter().flatten();
+            let input_fields = t["inputFields"].as_array().into_iter().flatten();
+            for field in fields.chain(input_fields) {
+                let field_name = field["name"].as_str().unwrap_or_default();
+                let path = format!("{}.{}", type_name, field_name);
+                if field_name.contains('_') && !SNAKE_CASE_ALIASES.contains(&path.as_str()) {
+                    names.push(path.clone());
+                }
+                for arg in field["args"].as_array().into_iter().flatten() {
+                    let arg_name = arg["name"].as_str().unwrap_or_default();
+                    if arg_name.contains('_') {
+                        names.push(format!("{}({})", path, arg_name));
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    #[test]
+    fn schema_fields_are_camel_case() {
+        let (schema, _) = introspect(
+            &create_schema(),
+            &Context {
+                user_id: 0,
+                tenant_id: None,
+                impersonator_id: None,
+                secret: String::new(),
+                anon_id: None,
+                ip: None,
+                connection: None,
+                features: BTreeMap::new(),
+                is_minor: false,
+                token: None,
+            },
+            IntrospectionFormat::default(),
+        )
+        .unwrap();
+        let schema = serde_json::to_value(&schema).unwrap();
+        assert_eq!(get_snake_case_names(&schema), Vec::<String>::new());
+
+        let (schema, _) = introspect(
+            &create_guest_schema(),
+            &GuestContext {
+                secret: String::new(),
+                tenant_id: None,
+                anon_token: None,
+            },
+            IntrospectionFormat::default(),
+        )
+        .unwrap();
+        let schema = serde_json::to_value(&schema).unwrap();
+        assert_eq!(get_snake_case_names(&schema), Vec::<String>::new());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn mark_read_emits_unread_changed() {
+        use crate::db::fixtures::*;
+
+        let conn = test_conn();
+        let uid = insert_user(&conn, "unread-changed");
+        let create = || {
+            create_notification(&conn, uid, ScNotificationKind::Announcement, &json!({}))
+                .unwrap()
+                .id
+        };
+        let (first, second, third) = (create(), create(), create());
+        let mut receiver = get_receiver(uid);
+        let mut unread = || {
+            let msg = receiver.try_recv().unwrap();
+            assert_eq!(msg.kind(), "unread_changed");
+            format!("{:?}", msg)
+        };
+
+        assert_eq!(
+            mark_notification_read_and_notify(&conn, uid, second).unwrap(),
+            2
+        );
+        assert!(unread().contains("unread_changed: Some(2)"));
+        assert_eq!(
+            mark_notifications_read_and_notify(&conn, uid, first).unwrap(),
+            1
+        );
+        assert!(unread().contains("unread_changed: Some(1)"));
+        // Already read, the count is sent again unchanged
+        assert_eq!(
+            mark_notification_read_and_notify(&conn, uid, first).unwrap(),
+            1
+        );
+        assert!(unread().contains("unread_changed: Some(1)"));
+        assert_eq!(
+            mark_notifications_read_and_notify(&conn, uid, third).unwrap(),
+            0
+        );
+        assert!(unread().contains("unread_changed: Some(0)"));
+
+        receiver.detach();
+    }
+
+    #[test]
+    fn minor_guards() {
+        let context = |is_minor| Context {
+            user_id: 1,
+            tenant_id: None,
+            impersonator_id: None,
+            secret: String::new(),
+            anon_id: None,
+            ip: None,
+            connection: None,
+            features: BTreeMap::new(),
+            is_minor,
+            token: None,
+        };
+        // Restrictions that don't depend on the hour, all on by default
+        for restriction in [ScRestriction::LobbyReadOnly, ScRestriction::MatureGames] {
+            assert!(context(false).check_restriction(restriction).is_ok());
+            let err = context(true).check_restriction(restriction).unwrap_err();
+            assert_eq!(
+                err.extensions().to_owned(),
+                Error::restricted(&restriction.to_string())
+            );
+        }
+    }
+}
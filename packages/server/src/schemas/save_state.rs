@@ -0,0 +1,841 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use data_encoding::{BASE64, HEXLOWER};
+use diesel::dsl::sql;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::env;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::db::models::{NewSaveState, NewStateUpload, NewStateUploadChunk, StateUpload};
+use crate::db::schema::{
+    games, save_states, state_upload_chunks, state_uploads, storage_deletions,
+};
+use crate::error::Error;
+use crate::storage::{get_storage, read_all, Storage};
+
+use super::compatibility::{check_core_version, get_core_version};
+use super::game::ScGamePlatform;
+use super::visibility::visible_games;
+
+// States stored under a higher `MAX_SAVE_STATE_BYTES` still load
+const MAX_STORED_STATE_BYTES: usize = 64 * 1024 * 1024;
+
+// Incomplete chunked uploads are purged after
+const STATE_UPLOAD_HOURS: i64 = 24;
+
+// Blobs removed from storage per cleanup
+const STORAGE_DELETIONS_BATCH: i64 = 100;
+
+lazy_static! {
+    // Uncompressed, the base64 of it must also fit the request body limit of `/graphql`
+    static ref MAX_SAVE_STATE_BYTES: usize = env::var("MAX_SAVE_STATE_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(128 * 1024);
+    // Slots `0` to `SAVE_SLOTS - 1` of each game
+    static ref SAVE_SLOTS: i32 = env::var("SAVE_SLOTS")
+        .ok()
+        .and_then(|slots| slots.parse().ok())
+        .filter(|slots: &i32| *slots > 0)
+        .unwrap_or(10);
+    // Body limit of `/upload/state/{id}`
+    pub static ref MAX_STATE_CHUNK_BYTES: usize = env::var("MAX_STATE_CHUNK_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(1024 * 1024);
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScSaveState {
+    game_id: i32,
+    slot: i32,
+    // uncompressed bytes
+    size: i32,
+    // hex sha256 of the state, a device skips downloading one it has
+    checksum: String,
+    // emulator core that wrote the state, null for states from before versions
+    core_version: Option<i32>,
+    // written by a core below the platform's minimum supported version
+    incompatible: bool,
+    created_at: f64,
+    updated_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewSaveState {
+    pub game_id: i32,
+    pub slot: i32,
+    // base64 of the emulator state
+    pub data: String,
+    // emulator core writing the state, beyond the latest known version is rejected
+    pub core_version: Option<i32>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScBeginStateUpload {
+    pub game_id: i32,
+    pub slot: i32,
+    // uncompressed bytes of the whole state
+    pub total_size: i32,
+    // lowercase hex sha256 of the whole state
+    pub hash: String,
+    pub core_version: Option<i32>,
+}
+
+/// Chunks go to `PUT /upload/state/{id}` with a `Content-Range` header
+#[derive(GraphQLObject, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScStateUpload {
+    id: String,
+    total_size: i32,
+    // bytes received from the start, resume from here
+    received: i32,
+}
+
+type SaveStateRow = (
+    i32,
+    i32,
+    i32,
+    String,
+    Option<i32>,
+    NaiveDateTime,
+    NaiveDateTime,
+);
+
+fn convert_to_sc_save_state(row: SaveStateRow, incompatible: bool) -> ScSaveState {
+    let (gid, slot_value, size_value, checksum_value, version, created, updated) = row;
+    ScSaveState {
+        game_id: gid,
+        slot: slot_value,
+        size: size_value,
+        checksum: checksum_value,
+        core_version: version,
+        incompatible,
+        created_at: created.timestamp_millis() as f64,
+        updated_at: updated.timestamp_millis() as f64,
+    }
+}
+
+fn compress_state(state: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(state).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Refuses anything inflating beyond `max_size`
+fn decompress_state(compressed: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    let mut state = Vec::new();
+    ZlibDecoder::new(compressed)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut state)
+        .map_err(|err| err.to_string())?;
+    if state.len() > max_size {
+        return Err("state too large".into());
+    }
+    Ok(state)
+}
+
+/// Slot and size of a state coming from an import
+pub fn check_save_state(slot_value: i32, state: &[u8]) -> FieldResult<()> {
+    check_slot(slot_value)?;
+    check_size(state.len())
+}
+
+fn check_slot(slot: i32) -> FieldResult<()> {
+    if (0..*SAVE_SLOTS).contains(&slot) {
+        Ok(())
+    } else {
+        Err(FieldError::new(
+            format!("slot is 0 to {}", *SAVE_SLOTS - 1),
+            Error::validation(),
+        ))
+    }
+}
+
+/// States without a version predate versions and are never flagged
+fn is_incompatible(version: Option<i32>, minimum: Option<i32>) -> bool {
+    matches!((version, minimum), (Some(version), Some(minimum)) if version < minimum)
+}
+
+/// `None` for games without a platform or platforms without configured versions
+fn get_minimum_core_version(conn: &PgConnection, gid: i32) -> QueryResult<Option<i32>> {
+    let platform = games::table
+        .select(games::platform)
+        .filter(games::id.eq(gid))
+        .get_result::<Option<String>>(conn)
+        .optional()?
+        .flatten()
+        .and_then(|p| ScGamePlatform::from_str(&p).ok());
+    Ok(match platform {
+        Some(p) => get_core_version(conn, &p)?.map(|version| version.minimum),
+        None => None,
+    })
+}
+
+pub fn get_save_states(conn: &PgConnection, uid: i32, gid: i32) -> FieldResult<Vec<ScSaveState>> {
+    use self::save_states::dsl::*;
+
+    let minimum = get_minimum_core_version(conn, gid)?;
+    Ok(save_states
+        .select((
+            game_id,
+            slot,
+            size,
+            checksum,
+            core_version,
+            created_at,
+            updated_at,
+        ))
+        .filter(user_id.eq(uid))
+        .filter(game_id.eq(gid))
+        .order(slot.asc())
+        .load::<SaveStateRow>(conn)?
+        .into_iter()
+        .map(|row| {
+            let incompatible = is_incompatible(row.4, minimum);
+            convert_to_sc_save_state(row, incompatible)
+        })
+        .collect())
+}
+
+/// Keyed by the checksum, saving the same state again keeps the blob
+fn get_save_state_key(uid: i32, gid: i32, slot_value: i32, checksum: &str) -> String {
+    format!(
+        "states/{}/{}/{}/{}.zz",
+        uid,
+        gid,
+        slot_value,
+        &checksum[..16]
+    )
+}
+
+/// Base64 of the state in the slot
+pub fn get_save_state_data(
+    conn: &PgConnection,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+) -> FieldResult<String> {
+    let state = load_save_state(conn, get_storage(), uid, gid, slot_value)?;
+    Ok(BASE64.encode(&state))
+}
+
+/// Uncompressed state in the slot, for an export
+pub fn read_save_state(
+    conn: &PgConnection,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+) -> FieldResult<Vec<u8>> {
+    load_save_state(conn, get_storage(), uid, gid, slot_value)
+}
+
+fn load_save_state(
+    conn: &PgConnection,
+    storage: Option<&dyn Storage>,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+) -> FieldResult<Vec<u8>> {
+    use self::save_states::dsl::*;
+
+    let (stored, key) = save_states
+        .select((data, data_key))
+        .filter(user_id.eq(uid))
+        .filter(game_id.eq(gid))
+        .filter(slot.eq(slot_value))
+        .get_result::<(Option<Vec<u8>>, Option<String>)>(conn)
+        .optional()?
+        .ok_or_else(|| FieldError::new("empty slot", Error::not_found()))?;
+    let compressed = match (stored, key, storage) {
+        (Some(stored), _, _) => stored,
+        (None, Some(key), Some(storage)) => read_all(storage, &key)
+            .map_err(|err| FieldError::new(err.to_string(), Error::internal()))?,
+        _ => {
+            return Err(FieldError::new(
+                "state is in storage, which is not configured",
+                Error::internal(),
+            ))
+        }
+    };
+    decompress_state(&compressed, MAX_STORED_STATE_BYTES)
+        .map_err(|err| FieldError::new(err, Error::internal()))
+}
+
+fn check_size(size: usize) -> FieldResult<()> {
+    if size == 0 || size > *MAX_SAVE_STATE_BYTES {
+        return Err(FieldError::new(
+            format!("state is 1 to {} bytes", *MAX_SAVE_STATE_BYTES),
+            Error::validation(),
+        ));
+    }
+    Ok(())
+}
+
+/// Return `true` for a core below the platform's minimum, error for a missing
+/// game or a core newer than the server knows about
+fn check_game_core_version(
+    conn: &PgConnection,
+    gid: i32,
+    version: Option<i32>,
+) -> FieldResult<bool> {
+    let platform = visible_games()
+        .select(games::platform)
+        .filter(games::id.eq(gid))
+        .get_result::<Option<String>>(conn)
+        .optional()?
+        .ok_or_else(|| FieldError::new("game not found", Error::not_found()))?
+        .and_then(|p| ScGamePlatform::from_str(&p).ok());
+    match (platform, version) {
+        (Some(p), Some(version)) => check_core_version(conn, &p, version),
+        _ => Ok(false),
+    }
+}
+
+pub fn get_checksum(state: &[u8]) -> String {
+    HEXLOWER.encode(digest(&SHA256, state).as_ref())
+}
+
+/// Replaces what the slot held
+pub fn upload_save_state(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScNewSaveState,
+) -> FieldResult<ScSaveState> {
+    let state = BASE64
+        .decode(req.data.as_bytes())
+        .map_err(|_| FieldError::new("data is not base64", Error::validation()))?;
+    store_save_state(
+        conn,
+        get_storage(),
+        uid,
+        req.game_id,
+        req.slot,
+        req.core_version,
+        &state,
+    )
+}
+
+/// Replaces what the slot held with a state from an import
+pub fn import_save_state(
+    conn: &PgConnection,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+    version: Option<i32>,
+    state: &[u8],
+) -> FieldResult<()> {
+    store_save_state(conn, get_storage(), uid, gid, slot_value, version, state)?;
+    Ok(())
+}
+
+/// Without a storage backend the state is kept in the row, the blob a
+/// replaced state leaves behind is queued for `release_state_blobs`
+fn store_save_state(
+    conn: &PgConnection,
+    storage: Option<&dyn Storage>,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+    version: Option<i32>,
+    state: &[u8],
+) -> FieldResult<ScSaveState> {
+    use self::save_states::dsl::*;
+
+    check_slot(slot_value)?;
+    check_size(state.len())?;
+    let outdated = check_game_core_version(conn, gid, version)?;
+
+    let compressed = compress_state(state);
+    let hash = get_checksum(state);
+    let key = match storage {
+        Some(storage) => {
+            let key = get_save_state_key(uid, gid, slot_value, &hash);
+            storage
+                .put(&key, &compressed, "application/zlib")
+                .map_err(|err| FieldError::new(err.to_string(), Error::internal()))?;
+            Some(key)
+        }
+        None => None,
+    };
+
+    let now = Utc::now().naive_utc();
+    let row = diesel::insert_into(save_states)
+        .values(&NewSaveState {
+            user_id: uid,
+            game_id: gid,
+            slot: slot_value,
+            data: key.is_none().then(|| compressed.as_slice()),
+            size: state.len() as i32,
+            checksum: &hash,
+            created_at: now,
+            updated_at: now,
+            core_version: version,
+            data_key: key.as_deref(),
+        })
+        .on_conflict((user_id, game_id, slot))
+        .do_update()
+        .set((
+            data.eq(excluded(data)),
+            data_key.eq(excluded(data_key)),
+            size.eq(excluded(size)),
+            checksum.eq(excluded(checksum)),
+            core_version.eq(excluded(core_version)),
+            updated_at.eq(excluded(updated_at)),
+        ))
+        .returning((
+            game_id,
+            slot,
+            size,
+            checksum,
+            core_version,
+            created_at,
+            updated_at,
+        ))
+        .get_result::<SaveStateRow>(conn)?;
+
+    Ok(convert_to_sc_save_state(row, outdated))
+}
+
+/// Returns whether the slot held a state
+pub fn delete_save_state(
+    conn: &PgConnection,
+    uid: i32,
+    gid: i32,
+    slot_value: i32,
+) -> FieldResult<bool> {
+    use self::save_states::dsl::*;
+
+    let count = diesel::delete(
+        save_states
+            .filter(user_id.eq(uid))
+            .filter(game_id.eq(gid))
+            .filter(slot.eq(slot_value)),
+    )
+    .execute(conn)?;
+    Ok(count > 0)
+}
+
+/// Bytes received from the start of the upload, chunks sorted by start
+fn get_received(chunks: &[(i32, i32)]) -> i32 {
+    chunks.iter().fold(0, |end, (start, len)| {
+        if *start <= end {
+            end.max(start + len)
+        } else {
+            end
+        }
+    })
+}
+
+fn check_upload_hash(hash: &str) -> FieldResult<()> {
+    match HEXLOWER.decode(hash.as_bytes()) {
+        Ok(bytes) if bytes.len() == 32 => Ok(()),
+        _ => Err(FieldError::new(
+            "hash is the lowercase hex sha256 of the state",
+            Error::validation(),
+        )),
+    }
+}
+
+fn get_state_upload(conn: &PgConnection, uid: i32, upload_id: &str) -> FieldResult<StateUpload> {
+    use self::state_uploads::dsl::*;
+
+    state_uploads
+        .filter(id.eq(upload_id))
+        .filter(user_id.eq(uid))
+        .filter(created_at.gt(get_upload_expired_before()))
+        .get_result::<StateUpload>(conn)
+        .optional()?
+        .ok_or_else(|| FieldError::new("upload not found", Error::not_found()))
+}
+
+fn get_upload_expired_before() -> NaiveDateTime {
+    Utc::now().naive_utc() - Duration::hours(STATE_UPLOAD_HOURS)
+}
+
+fn convert_to_sc_state_upload(
+    conn: &PgConnection,
+    upload: &StateUpload,
+) -> FieldResult<ScStateUpload> {
+    use self::state_upload_chunks::dsl::*;
+
+    let chunks = state_upload_chunks
+        .select((start, sql::<Integer>("octet_length(data)")))
+        .filter(upload_id.eq(&upload.id))
+        .order(start.asc())
+        .load::<(i32, i32)>(conn)?;
+    Ok(ScStateUpload {
+        id: upload.id.clone(),
+        total_size: upload.total_size,
+        received: get_received(&chunks),
+    })
+}
+
+pub fn begin_state_upload(
+    conn: &PgConnection,
+    uid: i32,
+    req: &ScBeginStateUpload,
+) -> FieldResult<ScStateUpload> {
+    check_slot(req.slot)?;
+    check_size(req.total_size.max(0) as usize)?;
+    check_upload_hash(&req.hash)?;
+    check_game_core_version(conn, req.game_id, req.core_version)?;
+
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    let upload = diesel::insert_into(state_uploads::table)
+        .values(&NewStateUpload {
+            id: &HEXLOWER.encode(&bytes),
+            user_id: uid,
+            game_id: req.game_id,
+            slot: req.slot,
+            total_size: req.total_size,
+            checksum: &req.hash,
+            core_version: req.core_version,
+            created_at: Utc::now().naive_utc(),
+        })
+        .get_result::<StateUpload>(conn)?;
+    convert_to_sc_state_upload(conn, &upload)
+}
+
+pub fn get_state_upload_progress(
+    conn: &PgConnection,
+    uid: i32,
+    upload_id: &str,
+) -> FieldResult<ScStateUpload> {
+    convert_to_sc_state_upload(conn, &get_state_upload(conn, uid, upload_id)?)
+}
+
+/// Store the chunk at `offset`, a chunk sent again replaces the earlier copy
+pub fn put_state_chunk(
+    conn: &PgConnection,
+    uid: i32,
+    upload_id_value: &str,
+    offset: u64,
+    total: u64,
+    chunk: &[u8],
+) -> FieldResult<ScStateUpload> {
+    use self::state_upload_chunks::dsl::*;
+
+    let upload = get_state_upload(conn, uid, upload_id_value)?;
+    if total != upload.total_size as u64 {
+        return Err(FieldError::new(
+            format!("total size is {}", upload.total_size),
+            Error::validation(),
+        ));
+    }
+    diesel::insert_into(state_upload_chunks)
+        .values(&NewStateUploadChunk {
+            upload_id: &upload.id,
+            start: offset as i32,
+            data: chunk,
+        })
+        .on_conflict((upload_id, start))
+        .do_update()
+        .set(data.eq(excluded(data)))
+        .execute(conn)?;
+    convert_to_sc_state_upload(conn, &upload)
+}
+
+/// Verify the assembled chunks against the declared hash and store them in the slot
+pub fn finish_state_upload(
+    conn: &PgConnection,
+    uid: i32,
+    upload_id_value: &str,
+) -> FieldResult<ScSaveState> {
+    use self::state_upload_chunks::dsl::*;
+
+    conn.transaction(|| {
+        let upload = get_state_upload(conn, uid, upload_id_value)?;
+        let chunks = state_upload_chunks
+            .select((start, data))
+            .filter(upload_id.eq(&upload.id))
+            .order(start.asc())
+            .load::<(i32, Vec<u8>)>(conn)?;
+        let ranges: Vec<_> = chunks
+            .iter()
+            .map(|(offset, chunk)| (*offset, chunk.len() as i32))
+            .collect();
+        let received = get_received(&ranges);
+        if received < upload.total_size {
+            return Err(FieldError::new(
+                format!("received {} of {} bytes", received, upload.total_size),
+                Error::validation(),
+            ));
+        }
+        let mut state = vec![0u8; upload.total_size as usize];
+        for (offset, chunk) in chunks.iter() {
+            state[*offset as usize..*offset as usize + chunk.len()].copy_from_slice(chunk);
+        }
+        if get_checksum(&state) != upload.checksum {
+            return Err(FieldError::new(
+                "state doesn't match the hash, send the chunks again",
+                Error::validation(),
+            ));
+        }
+
+        let saved = store_save_state(
+            conn,
+            get_storage(),
+            uid,
+            upload.game_id,
+            upload.slot,
+            upload.core_version,
+            &state,
+        )?;
+        diesel::delete(state_uploads::table.filter(state_uploads::id.eq(&upload.id)))
+            .execute(conn)?;
+        Ok(saved)
+    })
+}
+
+/// Incomplete uploads older than a day
+pub fn prune_state_uploads(conn: &PgConnection) -> QueryResult<usize> {
+    use self::state_uploads::dsl::*;
+
+    diesel::delete(state_uploads.filter(created_at.lt(get_upload_expired_before()))).execute(conn)
+}
+
+/// Delete blobs of replaced and deleted states from storage, returns the number
+/// of queued keys handled
+pub fn release_state_blobs(conn: &PgConnection, storage: &dyn Storage) -> QueryResult<usize> {
+    use self::storage_deletions::dsl::*;
+
+    let keys = storage_deletions
+        .select(key)
+        .order(created_at.asc())
+        .limit(STORAGE_DELETIONS_BATCH)
+        .load::<String>(conn)?;
+    // Saved again in the same slot since
+    let kept = save_states::table
+        .select(save_states::data_key)
+        .filter(save_states::data_key.eq_any(&keys))
+        .load::<Option<String>>(conn)?;
+    let mut count = 0;
+    for value in keys {
+        if !kept.contains(&Some(value.clone())) {
+            if let Err(err) = storage.delete(&value) {
+                log::error!("Delete state {}: {:?}", value, err);
+                continue;
+            }
+        }
+        diesel::delete(storage_deletions.filter(key.eq(&value))).execute(conn)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::save_state::*;
+
+    #[test]
+    fn compressed_states() {
+        // RAM of a running game is mostly zeros
+        let state: Vec<u8> = (0..64 * 1024).map(|i| (i % 7 == 0) as u8).collect();
+        let compressed = compress_state(&state);
+        assert!(compressed.len() < state.len() / 10);
+        assert_eq!(
+            decompress_state(&compressed, state.len()),
+            Ok(state.clone())
+        );
+        // A bomb stops at the limit
+        assert!(decompress_state(&compressed, state.len() - 1).is_err());
+        assert!(decompress_state(b"not zlib", 1024).is_err());
+
+        assert!(check_slot(0).is_ok());
+        assert!(check_slot(*SAVE_SLOTS - 1).is_ok());
+        assert!(check_slot(-1).is_err());
+        assert!(check_slot(*SAVE_SLOTS).is_err());
+    }
+
+    #[test]
+    fn incompatible_states() {
+        assert!(is_incompatible(Some(2), Some(3)));
+        assert!(!is_incompatible(Some(3), Some(3)));
+        assert!(!is_incompatible(Some(2), None));
+        assert!(!is_incompatible(None, Some(3)));
+    }
+
+    #[test]
+    fn upload_progress() {
+        assert_eq!(get_received(&[]), 0);
+        assert_eq!(get_received(&[(0, 10), (10, 10), (30, 10)]), 20);
+        // Overlapping re-sends
+        assert_eq!(get_received(&[(0, 10), (5, 10), (15, 5)]), 20);
+        assert_eq!(get_received(&[(10, 10)]), 0);
+
+        assert!(check_upload_hash(&get_checksum(b"state")).is_ok());
+        assert!(check_upload_hash(&get_checksum(b"state").to_uppercase()).is_err());
+        assert!(check_upload_hash("abc").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn chunked_upload() {
+        use crate::db::fixtures::{insert_game, insert_user, test_conn};
+
+        let conn = test_conn();
+        let uid = insert_user(&conn, "chunk_user");
+        let gid = insert_game(&conn, "chunk_game");
+        let state: Vec<u8> = (0..30u8).collect();
+        let upload = begin_state_upload(
+            &conn,
+            uid,
+            &ScBeginStateUpload {
+                game_id: gid,
+                slot: 1,
+                total_size: 30,
+                hash: get_checksum(&state),
+                core_version: None,
+            },
+        )
+        .unwrap();
+        let put = |start: usize, chunk: &[u8]| {
+            put_state_chunk(&conn, uid, &upload.id, start as u64, 30, chunk)
+                .map(|upload| upload.received)
+        };
+
+        assert_eq!(put(20, &state[20..]).unwrap(), 0);
+        assert_eq!(put(0, &[0; 10]).unwrap(), 10);
+        assert!(finish_state_upload(&conn, uid, &upload.id).is_err());
+        // A corrupted chunk fails the hash, sending it again fixes it
+        assert_eq!(put(10, &state[10..20]).unwrap(), 30);
+        assert!(finish_state_upload(&conn, uid, &upload.id).is_err());
+        assert_eq!(put(0, &state[..10]).unwrap(), 30);
+        // Other users and sizes
+        assert!(put_state_chunk(&conn, uid + 1, &upload.id, 0, 30, &state[..10]).is_err());
+        assert!(put_state_chunk(&conn, uid, &upload.id, 0, 40, &state[..10]).is_err());
+
+        let saved = finish_state_upload(&conn, uid, &upload.id).unwrap();
+        assert_eq!((saved.slot, saved.size), (1, 30));
+        assert_eq!(saved.checksum, get_checksum(&state));
+        assert_eq!(
+            get_save_state_data(&conn, uid, gid, 1).unwrap(),
+            BASE64.encode(&state)
+        );
+        // Finished uploads are gone
+        assert!(get_state_upload_progress(&conn, uid, &upload.id).is_err());
+
+        let stale = begin_state_upload(
+            &conn,
+            uid,
+            &ScBeginStateUpload {
+                game_id: gid,
+                slot: 2,
+                total_size: 30,
+                hash: get_checksum(&state),
+                core_version: None,
+            },
+        )
+        .unwrap();
+        diesel::update(state_uploads::table.filter(state_uploads::id.eq(&stale.id)))
+            .set(state_uploads::created_at.eq(get_upload_expired_before()))
+            .execute(&conn)
+            .unwrap();
+        assert!(get_state_upload_progress(&conn, uid, &stale.id).is_err());
+        assert_eq!(prune_state_uploads(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn stored_states() {
+        use crate::db::fixtures::{insert_game, insert_user, test_conn};
+        use crate::storage::LocalStorage;
+
+        let conn = test_conn();
+        let dir = env::temp_dir().join(format!("nesbox-states-{}", std::process::id()));
+        let storage = LocalStorage::new(&dir);
+        let uid = insert_user(&conn, "stored_state_user");
+        let gid = insert_game(&conn, "stored_state_game");
+        let store = |state: &[u8]| {
+            store_save_state(&conn, Some(&storage), uid, gid, 0, None, state).unwrap()
+        };
+        let key = |state: &[u8]| get_save_state_key(uid, gid, 0, &get_checksum(state));
+
+        store(b"first");
+        assert!(storage.exists(&key(b"first")).unwrap());
+        assert_eq!(
+            load_save_state(&conn, Some(&storage), uid, gid, 0).unwrap(),
+            b"first"
+        );
+        assert!(load_save_state(&conn, None, uid, gid, 0).is_err());
+
+        // Replaced, then saved again before the cleanup ran
+        store(b"second");
+        store(b"first");
+        store(b"second");
+        assert!(release_state_blobs(&conn, &storage).unwrap() >= 1);
+        assert!(!storage.exists(&key(b"first")).unwrap());
+        assert!(storage.exists(&key(b"second")).unwrap());
+
+        // Cascades from the game
+        diesel::delete(games::table.filter(games::id.eq(gid)))
+            .execute(&conn)
+            .unwrap();
+        release_state_blobs(&conn, &storage).unwrap();
+        assert!(!storage.exists(&key(b"second")).unwrap());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn core_version_gate() {
+        use crate::db::fixtures::{insert_game, insert_user, test_conn};
+        use crate::schemas::compatibility::{update_core_version, ScUpdateCoreVersion};
+
+        let conn = test_conn();
+        let uid = insert_user(&conn, "state_user");
+        let gid = insert_game(&conn, "state_game");
+        diesel::update(games::table.filter(games::id.eq(gid)))
+            .set(games::platform.eq("nes"))
+            .execute(&conn)
+            .unwrap();
+        update_core_version(
+            &conn,
+            &ScUpdateCoreVersion {
+                platform: ScGamePlatform::Nes,
+                minimum: 2,
+                latest: 4,
+            },
+        )
+        .unwrap();
+        let upload = |slot: i32, core_version: Option<i32>| {
+            upload_save_state(
+                &conn,
+                uid,
+                &ScNewSaveState {
+                    game_id: gid,
+                    slot,
+                    data: BASE64.encode(b"state"),
+                    core_version,
+                },
+            )
+        };
+
+        // A core newer than the server knows about
+        assert!(upload(0, Some(5)).is_err());
+        assert!(upload(0, Some(1)).unwrap().incompatible);
+        assert!(!upload(1, Some(4)).unwrap().incompatible);
+        assert!(!upload(2, None).unwrap().incompatible);
+
+        let flags: Vec<_> = get_save_states(&conn, uid, gid)
+            .unwrap()
+            .into_iter()
+            .map(|state| (state.slot, state.core_version, state.incompatible))
+            .collect();
+        assert_eq!(
+            flags,
+            vec![(0, Some(1), true), (1, Some(4), false), (2, None, false)]
+        );
+    }
+}
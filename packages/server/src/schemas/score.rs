@@ -0,0 +1,247 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use serde_json::json;
+
+use crate::db::models::{NewScore, Score};
+use crate::db::schema::{games, scores};
+use crate::error::Error;
+
+use super::audit::write_audit_log;
+use super::game::normalize_rom_url;
+use super::visibility::visible_games;
+
+const MAX_REASON_LEN: usize = 500;
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewScore {
+    pub game_id: i32,
+    pub score: f64,
+    // screenshot url, e.g. uploaded to the game issue
+    pub proof_url: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScFlaggedScoresReq {
+    pub game_id: Option<i32>,
+    pub first: Option<i32>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScInvalidateScoreReq {
+    pub id: i32,
+    pub reason: String,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScScore {
+    pub id: i32,
+    game_id: i32,
+    user_id: i32,
+    score: f64,
+    proof_url: Option<String>,
+    // above the game's plausibility ceiling, hidden until reviewed
+    flagged: bool,
+    created_at: f64,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScScoreInvalidated {
+    pub id: i32,
+    pub user_id: i32,
+    game_id: i32,
+    score: f64,
+    reason: String,
+}
+
+fn convert_to_sc_score(s: &Score) -> ScScore {
+    ScScore {
+        id: s.id,
+        game_id: s.game_id,
+        user_id: s.user_id,
+        score: s.score as f64,
+        proof_url: s.proof_url.clone(),
+        flagged: s.flagged,
+        created_at: s.created_at.timestamp_millis() as f64,
+    }
+}
+
+/// No ceiling means every score is shown right away
+pub fn is_plausible(value: i64, ceiling: Option<i64>) -> bool {
+    ceiling.map_or(true, |ceiling| value <= ceiling)
+}
+
+fn validate_proof_url(url: &str) -> FieldResult<String> {
+    let url = url.trim();
+    if !url.starts_with("https://") {
+        return Err(FieldError::new(
+            "proof must be an https url",
+            Error::validation(),
+        ));
+    }
+    normalize_rom_url(url, &[]).map_err(|err| FieldError::new(err, Error::validation()))
+}
+
+fn validate_reason(reason: &str) -> FieldResult<String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(FieldError::new("reason is required", Error::validation()));
+    }
+    Ok(reason.chars().take(MAX_REASON_LEN).collect())
+}
+
+pub fn submit_score(conn: &PgConnection, uid: i32, req: &ScNewScore) -> FieldResult<ScScore> {
+    if !req.score.is_finite() || req.score < 0.0 || req.score.fract() != 0.0 {
+        return Err(FieldError::new(
+            "score must be a non negative integer",
+            Error::validation(),
+        ));
+    }
+    let proof = req
+        .proof_url
+        .as_deref()
+        .map(validate_proof_url)
+        .transpose()?;
+    let ceiling = visible_games()
+        .select(games::score_ceiling)
+        .filter(games::id.eq(req.game_id))
+        .get_result::<Option<i64>>(conn)?;
+
+    let value = req.score as i64;
+    let new_score = NewScore {
+        game_id: req.game_id,
+        user_id: uid,
+        score: value,
+        proof_url: proof.as_deref(),
+        flagged: !is_plausible(value, ceiling),
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let s = diesel::insert_into(scores::table)
+        .values(&new_score)
+        .get_result::<Score>(conn)?;
+    Ok(convert_to_sc_score(&s))
+}
+
+/// Review queue, oldest first
+pub fn get_flagged_scores(
+    conn: &PgConnection,
+    req: &ScFlaggedScoresReq,
+) -> FieldResult<Vec<ScScore>> {
+    use self::scores::dsl::*;
+
+    let mut query = scores
+        .filter(flagged.eq(true))
+        .order(id.asc())
+        .limit(req.first.unwrap_or(50).clamp(1, 500).into())
+        .into_boxed();
+    if let Some(gid) = req.game_id {
+        query = query.filter(game_id.eq(gid));
+    }
+
+    Ok(query
+        .load::<Score>(conn)?
+        .iter()
+        .map(|s| convert_to_sc_score(s))
+        .collect())
+}
+
+/// Show a flagged score on the leaderboard
+pub fn approve_score(conn: &PgConnection, actor: i32, sid: i32) -> FieldResult<ScScore> {
+    use self::scores::dsl::*;
+
+    let s = conn.transaction(|| {
+        let s = diesel::update(scores.filter(id.eq(sid)))
+            .set(flagged.eq(false))
+            .get_result::<Score>(conn)?;
+        write_audit_log(
+            conn,
+            actor,
+            s.user_id,
+            "approve_score",
+            &json!({ "scoreId": s.id, "gameId": s.game_id, "score": s.score }).to_string(),
+        )?;
+        Ok::<_, diesel::result::Error>(s)
+    })?;
+    Ok(convert_to_sc_score(&s))
+}
+
+fn convert_to_sc_score_invalidated(s: &Score, reason: String) -> ScScoreInvalidated {
+    ScScoreInvalidated {
+        id: s.id,
+        user_id: s.user_id,
+        game_id: s.game_id,
+        score: s.score as f64,
+        reason,
+    }
+}
+
+/// Remove the entry, the caller tells the submitter
+pub fn invalidate_score(
+    conn: &PgConnection,
+    actor: i32,
+    req: &ScInvalidateScoreReq,
+) -> FieldResult<ScScoreInvalidated> {
+    use self::scores::dsl::*;
+
+    let reason = validate_reason(&req.reason)?;
+    let invalidated = conn.transaction(|| {
+        let s = diesel::delete(scores.filter(id.eq(req.id))).get_result::<Score>(conn)?;
+        let invalidated = convert_to_sc_score_invalidated(&s, reason);
+        write_audit_log(
+            conn,
+            actor,
+            s.user_id,
+            "invalidate_score",
+            &serde_json::to_string(&invalidated).unwrap(),
+        )?;
+        Ok::<_, diesel::result::Error>(invalidated)
+    })?;
+    Ok(invalidated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::score::*;
+
+    #[test]
+    fn flagging_threshold() {
+        assert!(is_plausible(999_999_999, None));
+        assert!(is_plausible(9_999, Some(10_000)));
+        assert!(is_plausible(10_000, Some(10_000)));
+        assert!(!is_plausible(10_001, Some(10_000)));
+        assert!(!is_plausible(1, Some(0)));
+
+        assert!(validate_proof_url(" https://user-images.githubusercontent.com/1/a.png").is_ok());
+        assert!(validate_proof_url("http://example.com/a.png").is_err());
+        assert!(validate_proof_url("https://localhost/a.png").is_err());
+    }
+
+    #[test]
+    fn invalidation() {
+        assert!(validate_reason("  ").is_err());
+        assert_eq!(
+            validate_reason(&"x".repeat(600)).unwrap().len(),
+            MAX_REASON_LEN
+        );
+
+        let s = Score {
+            id: 7,
+            game_id: 3,
+            user_id: 5,
+            score: 120_000,
+            proof_url: None,
+            flagged: true,
+            created_at: Utc::now().naive_utc(),
+        };
+        let invalidated = convert_to_sc_score_invalidated(&s, validate_reason(" edited ").unwrap());
+        assert_eq!(invalidated.user_id, 5);
+        // Audit detail and notification payload
+        assert_eq!(
+            serde_json::to_value(&invalidated).unwrap(),
+            json!({ "id": 7, "userId": 5, "gameId": 3, "score": 120000.0, "reason": "edited" })
+        );
+    }
+}
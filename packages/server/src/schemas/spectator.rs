@@ -0,0 +1,265 @@
+use diesel::pg::PgConnection;
+use juniper::{FieldError, FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use strum::{Display, EnumString};
+
+use crate::db::root::DB_POOL;
+use crate::error::Error;
+
+use super::coalesce::{Batch, Coalescer};
+use super::friend::is_friend;
+use super::notify::*;
+use super::playing::{get_playing_room_id, get_room_user_ids};
+use super::room::ScRoomBasic;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq, Display, EnumString, Serialize)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ScSpectatorPolicy {
+    Open,
+    // friends of the host
+    FriendsOnly,
+    Closed,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScSpectatorsReq {
+    pub room_id: i32,
+}
+
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScSpectatorCount {
+    pub room_id: i32,
+    pub count: i32,
+}
+
+lazy_static! {
+    // room_id -> watching users, spectators hold no seat
+    static ref SPECTATORS: Mutex<HashMap<i32, HashSet<i32>>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // rooms whose count changed since the window opened
+    static ref SPECTATOR_COUNTS: Coalescer<HashSet<i32>> =
+        Coalescer::new(NotifyCoalesce::SpectatorWindow, flush_spectator_counts);
+}
+
+pub fn get_spectator_count(rid: i32) -> i32 {
+    SPECTATORS
+        .lock()
+        .unwrap()
+        .get(&rid)
+        .map_or(0, |ids| ids.len() as i32)
+}
+
+pub fn get_spectator_ids(rid: i32) -> Vec<i32> {
+    SPECTATORS
+        .lock()
+        .unwrap()
+        .get(&rid)
+        .map(|ids| ids.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+pub fn check_spectate(conn: &PgConnection, room: &ScRoomBasic, uid: i32) -> FieldResult<()> {
+    match room.spectator_policy {
+        ScSpectatorPolicy::Open => Ok(()),
+        ScSpectatorPolicy::FriendsOnly if is_friend(conn, room.host, uid) => Ok(()),
+        ScSpectatorPolicy::FriendsOnly => Err(FieldError::new(
+            "spectating is for friends of the host",
+            Error::spectate_denied(),
+        )),
+        ScSpectatorPolicy::Closed => Err(FieldError::new(
+            "spectating is closed",
+            Error::spectate_denied(),
+        )),
+    }
+}
+
+pub fn get_spectating_room_id(uid: i32) -> Option<i32> {
+    SPECTATORS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, ids)| ids.contains(&uid))
+        .map(|(rid, _)| *rid)
+}
+
+/// Seated in or watching the same room, peers that may signal each other
+pub fn is_room_peer(conn: &PgConnection, uid: i32, other_id: i32) -> bool {
+    let room_of = |id| get_playing_room_id(conn, id).or_else(|| get_spectating_room_id(id));
+    match room_of(uid) {
+        Some(rid) => room_of(other_id) == Some(rid),
+        None => false,
+    }
+}
+
+/// Guard of signaling, only peers exchange SDP and ICE
+pub fn check_room_peer(conn: &PgConnection, uid: i32, other_id: i32) -> FieldResult<()> {
+    if !is_room_peer(conn, uid, other_id) {
+        return Err(FieldError::new(
+            "not in the same room",
+            Error::permission_denied(),
+        ));
+    }
+    Ok(())
+}
+
+/// Return the room the user was watching before
+fn take_spectator(uid: i32) -> Option<i32> {
+    let mut map = SPECTATORS.lock().unwrap();
+    let rid = map
+        .iter()
+        .find(|(_, ids)| ids.contains(&uid))
+        .map(|(rid, _)| *rid)?;
+    if let Some(ids) = map.get_mut(&rid) {
+        ids.remove(&uid);
+        if ids.is_empty() {
+            map.remove(&rid);
+        }
+    }
+    Some(rid)
+}
+
+/// Watch one room at a time, return the room left
+fn put_spectator(rid: i32, uid: i32) -> Option<i32> {
+    let previous = take_spectator(uid);
+    SPECTATORS
+        .lock()
+        .unwrap()
+        .entry(rid)
+        .or_default()
+        .insert(uid);
+    previous
+}
+
+pub fn add_spectator(rid: i32, uid: i32) {
+    let previous = put_spectator(rid, uid);
+    for changed in previous.into_iter().chain([rid]) {
+        notify_spectator_count(changed);
+    }
+}
+
+/// Stop watching, e.g. went offline or took a seat
+pub fn remove_spectator(uid: i32) -> Option<i32> {
+    let rid = take_spectator(uid)?;
+    notify_spectator_count(rid);
+    Some(rid)
+}
+
+/// Drop every spectator of the room, return who was watching
+pub fn close_spectators(rid: i32) -> Vec<i32> {
+    let ids = SPECTATORS.lock().unwrap().remove(&rid).unwrap_or_default();
+    ids.into_iter().collect()
+}
+
+/// Evict current spectators with a targeted event
+pub fn evict_spectators(rid: i32) {
+    for uid in close_spectators(rid) {
+        notify(
+            uid,
+            ScNotifyMessageBuilder::default()
+                .spectate_closed(rid)
+                .build()
+                .unwrap(),
+        );
+    }
+    notify_spectator_count(rid);
+}
+
+fn send_spectator_count(rid: i32) {
+    let conn = DB_POOL.get().unwrap();
+    let mut ids = get_room_user_ids(&conn, rid);
+    ids.extend(get_spectator_ids(rid));
+    notify_ids(
+        ids,
+        ScNotifyMessageBuilder::default()
+            .spectator_count(ScSpectatorCount {
+                room_id: rid,
+                count: get_spectator_count(rid),
+            })
+            .build()
+            .unwrap(),
+    );
+}
+
+impl Batch for HashSet<i32> {
+    type Change = i32;
+
+    fn push(&mut self, rid: &i32) {
+        self.insert(*rid);
+    }
+
+    fn is_empty(&self) -> bool {
+        HashSet::is_empty(self)
+    }
+}
+
+/// First change in a window is sent right away, the rest once per window
+fn notify_spectator_count(rid: i32) {
+    if SPECTATOR_COUNTS.offer(&rid).unwrap_or(true) {
+        send_spectator_count(rid);
+    }
+}
+
+fn flush_spectator_counts(rooms: HashSet<i32>) {
+    rooms.into_iter().for_each(send_spectator_count);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::spectator::*;
+
+    #[test]
+    fn spectators_watch_one_room() {
+        assert_eq!(put_spectator(-1, -10), None);
+        assert_eq!(put_spectator(-1, -11), None);
+        assert_eq!(put_spectator(-2, -10), Some(-1));
+        assert_eq!(get_spectator_count(-1), 1);
+        assert_eq!(get_spectator_ids(-2), vec![-10]);
+        assert_eq!(get_spectating_room_id(-11), Some(-1));
+
+        assert_eq!(take_spectator(-10), Some(-2));
+        assert_eq!(get_spectator_count(-2), 0);
+        assert_eq!(close_spectators(-1), vec![-11]);
+        assert_eq!(get_spectator_count(-1), 0);
+        assert_eq!(get_spectating_room_id(-11), None);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn signaling_peers() {
+        use crate::db::fixtures::*;
+        use crate::schemas::playing::create_playing;
+
+        let conn = test_conn();
+        let host = insert_user(&conn, "signal-host");
+        let other_host = insert_user(&conn, "signal-other-host");
+        let watcher = insert_user(&conn, "signal-watcher");
+        let outsider = insert_user(&conn, "signal-outsider");
+        let gid = insert_game(&conn, "signaling");
+        let rid = insert_room(&conn, gid, host);
+        let other_rid = insert_room(&conn, gid, other_host);
+        create_playing(&conn, host, rid).unwrap();
+        create_playing(&conn, other_host, other_rid).unwrap();
+        put_spectator(rid, watcher);
+
+        let denied = |uid, other_id| {
+            check_room_peer(&conn, uid, other_id)
+                .unwrap_err()
+                .extensions()
+                .to_owned()
+                == Error::permission_denied()
+        };
+        assert!(denied(host, other_host));
+        assert!(denied(host, outsider));
+        assert!(denied(outsider, host));
+        assert!(denied(outsider, outsider));
+        assert!(check_room_peer(&conn, host, watcher).is_ok());
+        assert!(check_room_peer(&conn, watcher, host).is_ok());
+
+        take_spectator(watcher);
+        assert!(denied(watcher, host));
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject};
+use std::collections::HashMap;
+use std::env;
+use std::sync::RwLock;
+
+use crate::db::models::{NewTenant, Tenant};
+use crate::db::schema::{rooms, tenants, users};
+use crate::error::Error;
+
+lazy_static! {
+    // e.g. `nesbox.example.com`, `school.nesbox.example.com` resolves tenant `school`
+    static ref TENANT_BASE_DOMAIN: Option<String> = env::var("TENANT_BASE_DOMAIN")
+        .ok()
+        .map(|domain| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty());
+    // Neither changes after creation
+    static ref SLUG_CACHE: RwLock<HashMap<String, i32>> = {
+        let m = HashMap::new();
+        RwLock::new(m)
+    };
+    static ref USER_TENANT_CACHE: RwLock<HashMap<i32, Option<i32>>> = {
+        let m = HashMap::new();
+        RwLock::new(m)
+    };
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScTenant {
+    id: i32,
+    slug: String,
+    name: String,
+    created_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewTenant {
+    pub slug: String,
+    pub name: String,
+}
+
+fn convert_to_sc_tenant(tenant: &Tenant) -> ScTenant {
+    ScTenant {
+        id: tenant.id,
+        slug: tenant.slug.clone(),
+        name: tenant.name.clone(),
+        created_at: tenant.created_at.timestamp_millis() as f64,
+    }
+}
+
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 40
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// `X-Tenant` header first, then subdomain of the base domain,
+/// `None` is the default public tenant
+pub fn parse_tenant_slug(
+    header: Option<&str>,
+    host: Option<&str>,
+    base_domain: Option<&str>,
+) -> Option<String> {
+    if let Some(slug) = header.map(|h| h.trim().to_lowercase()) {
+        return Some(slug).filter(|slug| !slug.is_empty());
+    }
+    let host = host?.split(':').next()?.to_lowercase();
+    let sub = host.strip_suffix(base_domain?)?.strip_suffix('.')?;
+    Some(sub.to_owned()).filter(|sub| !sub.is_empty() && sub != "www")
+}
+
+pub fn get_tenant_slug(header: Option<&str>, host: Option<&str>) -> Option<String> {
+    parse_tenant_slug(header, host, TENANT_BASE_DOMAIN.as_deref())
+}
+
+/// `None` when the slug is unknown
+pub fn resolve_tenant(conn: &PgConnection, slug: Option<&str>) -> Option<Option<i32>> {
+    use self::tenants::dsl::*;
+
+    let s = match slug {
+        Some(s) => s,
+        None => return Some(None),
+    };
+    if let Some(tid) = SLUG_CACHE.read().unwrap().get(s) {
+        return Some(Some(*tid));
+    }
+    let tid = tenants
+        .select(id)
+        .filter(slug.eq(s))
+        .get_result::<i32>(conn)
+        .ok()?;
+    SLUG_CACHE.write().unwrap().insert(s.to_owned(), tid);
+    Some(Some(tid))
+}
+
+pub fn get_user_tenant(conn: &PgConnection, uid: i32) -> Option<i32> {
+    use self::users::dsl::*;
+
+    if let Some(tid) = USER_TENANT_CACHE.read().unwrap().get(&uid) {
+        return *tid;
+    }
+    let tid = users
+        .select(tenant_id)
+        .filter(id.eq(uid))
+        .get_result::<Option<i32>>(conn)
+        .unwrap_or_default();
+    USER_TENANT_CACHE.write().unwrap().insert(uid, tid);
+    tid
+}
+
+pub fn get_room_tenant(conn: &PgConnection, rid: i32) -> Option<i32> {
+    use self::rooms::dsl::*;
+
+    rooms
+        .select(tenant_id)
+        .filter(id.eq(rid))
+        .get_result::<Option<i32>>(conn)
+        .unwrap_or_default()
+}
+
+pub fn check_same_tenant(tenant: Option<i32>, other: Option<i32>) -> FieldResult<()> {
+    if tenant != other {
+        return Err(FieldError::new(
+            "not in the same space",
+            Error::permission_denied(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_tenants(conn: &PgConnection) -> Vec<ScTenant> {
+    use self::tenants::dsl::*;
+
+    tenants
+        .order(id.asc())
+        .load::<Tenant>(conn)
+        .unwrap()
+        .iter()
+        .map(|tenant| convert_to_sc_tenant(tenant))
+        .collect()
+}
+
+pub fn create_tenant(conn: &PgConnection, req: &ScNewTenant) -> FieldResult<ScTenant> {
+    let slug = req.slug.trim().to_lowercase();
+    if !is_valid_slug(&slug) {
+        return Err(FieldError::new("invalid tenant slug", Error::validation()));
+    }
+
+    let new_tenant = NewTenant {
+        slug: &slug,
+        name: req.name.trim(),
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let tenant = diesel::insert_into(tenants::table)
+        .values(&new_tenant)
+        .get_result::<Tenant>(conn)?;
+
+    Ok(convert_to_sc_tenant(&tenant))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::tenant::*;
+
+    #[test]
+    fn tenant_slug() {
+        let base = Some("nesbox.example.com");
+        assert_eq!(
+            parse_tenant_slug(Some("School"), Some("nesbox.example.com"), base),
+            Some("school".into())
+        );
+        assert_eq!(
+            parse_tenant_slug(None, Some("school.nesbox.example.com:8080"), base),
+            Some("school".into())
+        );
+        assert_eq!(
+            parse_tenant_slug(None, Some("nesbox.example.com"), base),
+            None
+        );
+        assert_eq!(
+            parse_tenant_slug(None, Some("www.nesbox.example.com"), base),
+            None
+        );
+        assert_eq!(
+            parse_tenant_slug(None, Some("evil-nesbox.example.com"), base),
+            None
+        );
+        assert_eq!(
+            parse_tenant_slug(None, Some("school.nesbox.example.com"), None),
+            None
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use diesel::pg::PgConnection;
+use juniper::{FieldError, FieldResult, GraphQLObject};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use super::game::is_trial_allowed;
+use crate::error::Error;
+use crate::quota::get_reset_at;
+
+lazy_static! {
+    // Per anonymous session and per client address, each UTC day
+    pub static ref TRIAL_MINUTES: i32 = env::var("TRIAL_MINUTES")
+        .ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or(10);
+    // key -> seconds granted on the day, a new anon id doesn't reset the address
+    static ref GRANTED: Mutex<HashMap<TrialKey, (NaiveDate, i64)>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+    // anon_id -> running trial
+    static ref SESSIONS: Mutex<HashMap<String, TrialSession>> = {
+        let map = HashMap::new();
+        Mutex::new(map)
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum TrialKey {
+    Anon(String),
+    Ip(String),
+}
+
+#[derive(Debug, Clone)]
+struct TrialSession {
+    game_id: i32,
+    ip: Option<String>,
+    expires_at: DateTime<Utc>,
+}
+
+/// Play without saves, rooms or records until `expires_at`
+#[derive(GraphQLObject, Debug, Clone, PartialEq)]
+pub struct ScTrialPlay {
+    pub game_id: i32,
+    expires_at: f64,
+}
+
+fn get_keys(anon_id: &str, ip: Option<&str>) -> Vec<TrialKey> {
+    let mut keys = vec![TrialKey::Anon(anon_id.to_owned())];
+    keys.extend(ip.map(|ip| TrialKey::Ip(ip.to_owned())));
+    keys
+}
+
+fn get_granted(
+    granted: &HashMap<TrialKey, (NaiveDate, i64)>,
+    key: &TrialKey,
+    day: NaiveDate,
+) -> i64 {
+    granted
+        .get(key)
+        .filter(|(date, _)| *date == day)
+        .map_or(0, |(_, seconds)| *seconds)
+}
+
+fn add_granted(
+    granted: &mut HashMap<TrialKey, (NaiveDate, i64)>,
+    key: TrialKey,
+    day: NaiveDate,
+    seconds: i64,
+) {
+    let entry = granted.entry(key).or_insert((day, 0));
+    if entry.0 != day {
+        *entry = (day, 0);
+    }
+    entry.1 = (entry.1 + seconds).max(0);
+}
+
+fn convert_to_sc_trial_play(session: &TrialSession) -> ScTrialPlay {
+    ScTrialPlay {
+        game_id: session.game_id,
+        expires_at: session.expires_at.timestamp_millis() as f64,
+    }
+}
+
+/// Give back the unplayed part of the running trial
+pub fn end_trial(anon_id: &str, now: DateTime<Utc>) {
+    let session = match SESSIONS.lock().unwrap().remove(anon_id) {
+        Some(session) => session,
+        None => return,
+    };
+    let unused = (session.expires_at - now).num_seconds();
+    if unused <= 0 {
+        return;
+    }
+    let mut granted = GRANTED.lock().unwrap();
+    for key in get_keys(anon_id, session.ip.as_deref()) {
+        add_granted(&mut granted, key, now.date_naive(), -unused);
+    }
+}
+
+/// The whole remaining allowance is charged up front, `Err` is when it resets
+pub fn start_trial(
+    anon_id: &str,
+    ip: Option<&str>,
+    gid: i32,
+    now: DateTime<Utc>,
+    allowance: Duration,
+) -> Result<ScTrialPlay, DateTime<Utc>> {
+    let running = SESSIONS.lock().unwrap().get(anon_id).cloned();
+    match running {
+        Some(session) if session.game_id == gid && session.expires_at > now => {
+            return Ok(convert_to_sc_trial_play(&session));
+        }
+        Some(_) => end_trial(anon_id, now),
+        None => (),
+    }
+
+    let day = now.date_naive();
+    let keys = get_keys(anon_id, ip);
+    let mut granted = GRANTED.lock().unwrap();
+    granted.retain(|_, (date, _)| *date == day);
+    let used = keys
+        .iter()
+        .map(|key| get_granted(&granted, key, day))
+        .max()
+        .unwrap_or_default();
+    let remaining = allowance.num_seconds() - used;
+    if remaining <= 0 {
+        return Err(get_reset_at(now));
+    }
+    for key in keys {
+        add_granted(&mut granted, key, day, remaining);
+    }
+
+    let session = TrialSession {
+        game_id: gid,
+        ip: ip.map(|ip| ip.to_owned()),
+        expires_at: now + Duration::seconds(remaining),
+    };
+    let trial = convert_to_sc_trial_play(&session);
+    SESSIONS.lock().unwrap().insert(anon_id.to_owned(), session);
+    Ok(trial)
+}
+
+pub fn start_trial_play(
+    conn: &PgConnection,
+    anon_id: &str,
+    ip: Option<&str>,
+    gid: i32,
+) -> FieldResult<ScTrialPlay> {
+    if !is_trial_allowed(conn, gid) {
+        return Err(FieldError::new(
+            format!("game {} has no trial", gid),
+            Error::permission_denied(),
+        ));
+    }
+    start_trial(
+        anon_id,
+        ip,
+        gid,
+        Utc::now(),
+        Duration::minutes((*TRIAL_MINUTES).into()),
+    )
+    .map_err(|reset_at| {
+        FieldError::new(
+            "trial exhausted, register to keep playing",
+            Error::trial_exhausted(reset_at.timestamp_millis() as f64, *TRIAL_MINUTES),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::trial::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn trial_allowance() {
+        let allowance = Duration::minutes(10);
+        let now = Utc.with_ymd_and_hms(2022, 11, 27, 12, 0, 0).unwrap();
+        let ip = Some("198.51.100.20");
+
+        let trial = start_trial("trial-a", ip, 1, now, allowance).unwrap();
+        assert_eq!(
+            trial.expires_at,
+            (now + allowance).timestamp_millis() as f64
+        );
+        // Same game resumes the running trial
+        let later = now + Duration::minutes(4);
+        assert_eq!(start_trial("trial-a", ip, 1, later, allowance), Ok(trial));
+
+        // Switching games keeps what's left
+        let other = start_trial("trial-a", ip, 2, later, allowance).unwrap();
+        assert_eq!(
+            other.expires_at,
+            (now + allowance).timestamp_millis() as f64
+        );
+
+        // A fresh anon id from the same address gets nothing more
+        let reset_at = Utc.with_ymd_and_hms(2022, 11, 28, 0, 0, 0).unwrap();
+        let done = now + Duration::minutes(10);
+        assert_eq!(
+            start_trial("trial-b", ip, 1, done, allowance),
+            Err(reset_at)
+        );
+        assert_eq!(
+            start_trial("trial-a", ip, 2, done, allowance),
+            Err(reset_at)
+        );
+
+        // Another address with the same anon id is still capped by the id
+        assert_eq!(
+            start_trial("trial-a", Some("198.51.100.21"), 1, done, allowance),
+            Err(reset_at)
+        );
+
+        // Next day
+        let tomorrow = done + Duration::days(1);
+        assert!(start_trial("trial-b", ip, 1, tomorrow, allowance).is_ok());
+        end_trial("trial-b", tomorrow + Duration::minutes(3));
+        let resumed = start_trial("trial-c", ip, 1, tomorrow + Duration::minutes(3), allowance);
+        assert_eq!(
+            resumed.unwrap().expires_at,
+            (tomorrow + Duration::minutes(10)).timestamp_millis() as f64
+        );
+    }
+}
@@ -5,29 +5,54 @@ use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use juniper::{FieldError, FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
 use ring::{digest, pbkdf2};
+use serde_json::{json, Value};
 use std::num::NonZeroU32;
+use std::str::FromStr;
+use strum::{Display, EnumString};
 
+use super::login_session::{refresh_login_session, start_login_session, ScSessionPolicy};
 use super::notify::*;
 use super::playing::*;
+use super::presence::*;
 use super::room::*;
-use crate::auth::UserToken;
+use super::visibility::{banned_users, visible_users};
+use crate::auth::{Secret, UserToken, ACCESS_TOKEN_TTL_SECONDS};
 use crate::db::models::{NewUser, User};
 use crate::db::schema::users;
 use crate::error::Error;
+use crate::moderation::{check_text, ScTextKind};
 
-#[derive(GraphQLEnum, Debug, Clone)]
+use super::report::flag_text;
+use super::restriction::{parse_birthdate, RESTRICTION_CONFIG};
+
+#[derive(GraphQLEnum, Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ScUserStatus {
     Online,
+    Idle,
+    Dnd,
     Offline,
 }
 
+#[derive(GraphQLEnum, Debug, Clone, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScUserRole {
+    Normal,
+    Admin,
+}
+
 #[derive(GraphQLObject)]
 pub struct ScUser {
     pub id: i32,
     pub username: String,
     pub nickname: String,
     pub playing: Option<ScRoomBasic>,
+    // uploaded to `/upload/avatar`
+    avatar: Option<String>,
     settings: Option<String>,
+    session_policy: ScSessionPolicy,
+    // the birthdate itself is never returned
+    is_minor: bool,
     created_at: f64,
     updated_at: f64,
 }
@@ -36,21 +61,42 @@ pub struct ScUser {
 pub struct ScUpdateUser {
     nickname: String,
     settings: Option<String>,
+    // unchanged when null
+    session_policy: Option<ScSessionPolicy>,
 }
 
-#[derive(GraphQLObject, Debug, Clone)]
+#[derive(GraphQLObject, Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ScUserBasic {
     pub id: i32,
     pub username: String,
     pub nickname: String,
     pub status: ScUserStatus,
     pub playing: Option<ScRoomBasic>,
+    pub avatar: Option<String>,
+}
+
+/// Deleted or banned account in the admin trash
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScBannedUser {
+    pub id: i32,
+    pub username: String,
+    pub nickname: String,
+    pub deleted_at: f64,
 }
 
 #[derive(GraphQLInputObject)]
 pub struct ScRegisterReq {
-    username: String,
-    password: String,
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+    pub captcha_token: Option<String>,
+    // honeypot, hidden in the form and must be empty
+    pub website: Option<String>,
+    // timestamp when the form was shown
+    pub form_started_at: Option<f64>,
+    // `YYYY-MM-DD`, can be set later with `setBirthdate`
+    pub birthdate: Option<String>,
 }
 
 #[derive(GraphQLInputObject)]
@@ -70,17 +116,48 @@ pub struct ScUpdatePassword {
 pub struct ScLoginResp {
     pub user: ScUser,
     token: String,
+    // active before this login, revoked with the `SINGLE` policy
+    other_sessions: i32,
+    // for `refreshToken`, works once
+    refresh_token: String,
+    // seconds until `token` expires
+    expires_in: i32,
+}
+
+impl ScLoginResp {
+    /// Body of the OAuth callbacks, the tokens of `login` without the user
+    pub fn to_json(&self) -> Value {
+        json!({
+            "userId": self.user.id,
+            "token": self.token,
+            "otherSessions": self.other_sessions,
+            "refreshToken": self.refresh_token,
+            "expiresIn": self.expires_in,
+        })
+    }
+}
+
+#[derive(GraphQLObject)]
+pub struct ScRefreshResp {
+    token: String,
+    // replaces the one sent, which no longer works
+    refresh_token: String,
+    expires_in: i32,
 }
 
 pub fn get_user_status(uid: i32) -> ScUserStatus {
     if has_user(uid) {
-        ScUserStatus::Online
+        match get_presence(uid) {
+            ScPresenceStatus::Online => ScUserStatus::Online,
+            ScPresenceStatus::Idle => ScUserStatus::Idle,
+            ScPresenceStatus::Dnd => ScUserStatus::Dnd,
+        }
     } else {
         ScUserStatus::Offline
     }
 }
 
-fn hash_password(password: &str) -> String {
+pub fn hash_password(password: &str) -> String {
     let mut pbkdf2_hash = [0u8; digest::SHA512_OUTPUT_LEN];
     pbkdf2::derive(
         pbkdf2::PBKDF2_HMAC_SHA512,
@@ -92,12 +169,24 @@ fn hash_password(password: &str) -> String {
     HEXUPPER.encode(&pbkdf2_hash)
 }
 
+/// Constant time, always hash even if there is no stored password
+pub fn verify_password(stored: Option<&str>, input: &str) -> bool {
+    let input_hash = hash_password(input);
+    let dummy_hash = "0".repeat(input_hash.len());
+    let matched = Secret::new(stored.unwrap_or(&dummy_hash)).ct_eq(&Secret::new(&input_hash));
+    matched && stored.is_some()
+}
+
 fn convert_to_sc_user(conn: &PgConnection, user: &User) -> ScUser {
     ScUser {
         id: user.id,
         username: user.username.clone(),
         nickname: user.nickname.clone(),
+        avatar: user.avatar.clone(),
         settings: user.settings.clone().map(|v| v.to_string()),
+        session_policy: ScSessionPolicy::from_str(&user.session_policy)
+            .unwrap_or(ScSessionPolicy::Multi),
+        is_minor: RESTRICTION_CONFIG.is_minor(user.birthdate, Utc::now()),
         created_at: user.created_at.timestamp_millis() as f64,
         updated_at: user.updated_at.timestamp_millis() as f64,
         playing: get_playing(conn, user.id),
@@ -107,8 +196,7 @@ fn convert_to_sc_user(conn: &PgConnection, user: &User) -> ScUser {
 pub fn get_account(conn: &PgConnection, uid: i32) -> FieldResult<ScUser> {
     use self::users::dsl::*;
 
-    let user = users
-        .filter(deleted_at.is_null())
+    let user = visible_users()
         .filter(id.eq(uid))
         .get_result::<User>(conn)?;
 
@@ -118,6 +206,7 @@ pub fn get_account(conn: &PgConnection, uid: i32) -> FieldResult<ScUser> {
 pub fn update_user(conn: &PgConnection, uid: i32, req: &ScUpdateUser) -> FieldResult<ScUser> {
     use self::users::dsl::*;
 
+    let filter = check_text(ScTextKind::Username, &req.nickname)?;
     let user = diesel::update(users.filter(deleted_at.is_null()).filter(id.eq(uid)))
         .set((
             nickname.eq(req.nickname.clone()),
@@ -125,13 +214,45 @@ pub fn update_user(conn: &PgConnection, uid: i32, req: &ScUpdateUser) -> FieldRe
                 .settings
                 .as_ref()
                 .map(|s| serde_json::from_str::<serde_json::Value>(&s).unwrap_or_default())),
+            req.session_policy
+                .map(|policy| session_policy.eq(policy.to_string())),
             updated_at.eq(Utc::now().naive_utc()),
         ))
         .get_result::<User>(conn)?;
 
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            uid,
+            ScTextKind::Username,
+            &user.nickname,
+            filter,
+            None,
+            Some(uid),
+        );
+    }
+
     Ok(convert_to_sc_user(conn, &user))
 }
 
+/// Returns the replaced avatar url
+pub fn update_avatar(conn: &PgConnection, uid: i32, url: &str) -> QueryResult<Option<String>> {
+    use self::users::dsl::*;
+
+    conn.transaction(|| {
+        let previous = users
+            .select(avatar)
+            .filter(deleted_at.is_null())
+            .filter(id.eq(uid))
+            .for_update()
+            .get_result::<Option<String>>(conn)?;
+        diesel::update(users.filter(id.eq(uid)))
+            .set((avatar.eq(url), updated_at.eq(Utc::now().naive_utc())))
+            .execute(conn)?;
+        Ok(previous)
+    })
+}
+
 pub fn update_password(
     conn: &PgConnection,
     uid: i32,
@@ -139,17 +260,25 @@ pub fn update_password(
 ) -> FieldResult<ScUser> {
     use self::users::dsl::*;
 
-    let user = diesel::update(
-        users
-            .filter(deleted_at.is_null())
-            .filter(id.eq(uid))
-            .filter(password.eq(hash_password(&req.oldpassword))),
-    )
-    .set((
-        password.eq(hash_password(&req.password)),
-        updated_at.eq(Utc::now().naive_utc()),
-    ))
-    .get_result::<User>(conn)?;
+    let stored = users
+        .select(password)
+        .filter(deleted_at.is_null())
+        .filter(id.eq(uid))
+        .get_result::<String>(conn)
+        .optional()?;
+    if !verify_password(stored.as_deref(), &req.oldpassword) {
+        return Err(FieldError::new(
+            "password error",
+            Error::username_or_password_error(),
+        ));
+    }
+
+    let user = diesel::update(users.filter(deleted_at.is_null()).filter(id.eq(uid)))
+        .set((
+            password.eq(hash_password(&req.password)),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .get_result::<User>(conn)?;
 
     Ok(convert_to_sc_user(conn, &user))
 }
@@ -157,8 +286,7 @@ pub fn update_password(
 pub fn get_user_basic(conn: &PgConnection, uid: i32) -> FieldResult<ScUserBasic> {
     use self::users::dsl::*;
 
-    let user = users
-        .filter(deleted_at.is_null())
+    let user = visible_users()
         .filter(id.eq(uid))
         .get_result::<User>(conn)?;
 
@@ -168,9 +296,46 @@ pub fn get_user_basic(conn: &PgConnection, uid: i32) -> FieldResult<ScUserBasic>
         nickname: user.nickname.clone(),
         status: get_user_status(uid),
         playing: get_playing(conn, user.id),
+        avatar: user.avatar.clone(),
     })
 }
 
+/// Most recently deleted first
+pub fn get_banned_users(conn: &PgConnection) -> FieldResult<Vec<ScBannedUser>> {
+    use self::users::dsl::*;
+
+    Ok(banned_users()
+        .order(deleted_at.desc())
+        .load::<User>(conn)?
+        .iter()
+        .map(|user| ScBannedUser {
+            id: user.id,
+            username: user.username.clone(),
+            nickname: user.nickname.clone(),
+            deleted_at: user
+                .deleted_at
+                .map_or(0.0, |at| at.timestamp_millis() as f64),
+        })
+        .collect())
+}
+
+pub fn get_user_role(conn: &PgConnection, uid: i32) -> ScUserRole {
+    use self::users::dsl::*;
+
+    users
+        .select(role)
+        .filter(deleted_at.is_null())
+        .filter(id.eq(uid))
+        .get_result::<String>(conn)
+        .ok()
+        .and_then(|s| ScUserRole::from_str(&s).ok())
+        .unwrap_or(ScUserRole::Normal)
+}
+
+pub fn is_admin(conn: &PgConnection, uid: i32) -> bool {
+    get_user_role(conn, uid) == ScUserRole::Admin
+}
+
 pub fn get_user_by_username(conn: &PgConnection, u: &str) -> FieldResult<ScUser> {
     use self::users::dsl::*;
 
@@ -179,24 +344,101 @@ pub fn get_user_by_username(conn: &PgConnection, u: &str) -> FieldResult<ScUser>
     Ok(convert_to_sc_user(conn, &user))
 }
 
-pub fn login(conn: &PgConnection, req: ScLoginReq, secret: &str) -> FieldResult<ScLoginResp> {
+pub fn login(
+    conn: &PgConnection,
+    req: ScLoginReq,
+    tenant: Option<i32>,
+    secret: &str,
+) -> FieldResult<ScLoginResp> {
     use self::users::dsl::*;
 
+    // Same work whether the username exists or not
     let user = users
         .filter(deleted_at.is_null())
         .filter(username.eq(&req.username))
-        .filter(password.eq(&hash_password(&req.password)))
         .get_result::<User>(conn)
-        .map_err(|error| FieldError::new(error, Error::username_or_password_error()))?;
+        .optional()?
+        .filter(|user| user.tenant_id == tenant);
+    if !verify_password(
+        user.as_ref().map(|user| user.password.as_str()),
+        &req.password,
+    ) {
+        return Err(FieldError::new(
+            "username or password error",
+            Error::username_or_password_error(),
+        ));
+    }
+    issue_login(conn, &user.unwrap(), secret)
+}
 
-    let user = convert_to_sc_user(conn, &user);
+/// Login, token refresh and subscription count as activity for retention
+pub fn touch_last_seen(conn: &PgConnection, uid: i32) -> QueryResult<usize> {
+    use self::users::dsl::*;
 
-    let token = UserToken::generate_token(secret, &user);
+    diesel::update(users.filter(id.eq(uid)))
+        .set(last_seen_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)
+}
 
-    Ok(ScLoginResp { user, token })
+/// Session and tokens of a user who proved who they are
+pub fn issue_login(conn: &PgConnection, user: &User, secret: &str) -> FieldResult<ScLoginResp> {
+    touch_last_seen(conn, user.id)?;
+    let user = convert_to_sc_user(conn, user);
+
+    let (sid, refresh_token, other_sessions) =
+        start_login_session(conn, user.id, user.session_policy)?;
+    let token = UserToken::generate_token(secret, &user, &sid);
+
+    Ok(ScLoginResp {
+        user,
+        token,
+        other_sessions,
+        refresh_token,
+        expires_in: *ACCESS_TOKEN_TTL_SECONDS as i32,
+    })
 }
 
-pub fn register(conn: &PgConnection, req: ScRegisterReq, secret: &str) -> FieldResult<ScLoginResp> {
+/// New access token of the session, banned users can't refresh
+pub fn refresh_access_token(
+    conn: &PgConnection,
+    token: &str,
+    tenant: Option<i32>,
+    secret: &str,
+) -> FieldResult<ScRefreshResp> {
+    use self::users::dsl::*;
+
+    let (session, refresh_token) = refresh_login_session(conn, token)?;
+    let user = users
+        .filter(deleted_at.is_null())
+        .filter(id.eq(session.user_id))
+        .get_result::<User>(conn)
+        .optional()?
+        .filter(|user| user.tenant_id == tenant)
+        .ok_or_else(|| FieldError::new("invalid refresh token", Error::refresh_token_invalid()))?;
+    touch_last_seen(conn, user.id)?;
+    let user = convert_to_sc_user(conn, &user);
+
+    Ok(ScRefreshResp {
+        token: UserToken::generate_token(secret, &user, &session.id),
+        refresh_token,
+        expires_in: *ACCESS_TOKEN_TTL_SECONDS as i32,
+    })
+}
+
+pub fn register(
+    conn: &PgConnection,
+    req: ScRegisterReq,
+    tenant: Option<i32>,
+    secret: &str,
+) -> FieldResult<ScLoginResp> {
+    let filter = check_text(ScTextKind::Username, &req.username)?;
+    let birth = match &req.birthdate {
+        Some(value) => Some(parse_birthdate(
+            value,
+            RESTRICTION_CONFIG.today(Utc::now()),
+        )?),
+        None => None,
+    };
     let new_user = NewUser {
         username: &req.username,
         password: &hash_password(&req.password),
@@ -210,16 +452,52 @@ pub fn register(conn: &PgConnection, req: ScRegisterReq, secret: &str) -> FieldR
         deleted_at: None,
         created_at: Utc::now().naive_utc(),
         updated_at: Utc::now().naive_utc(),
+        tenant_id: tenant,
+        birthdate: birth,
     };
 
     let user = diesel::insert_into(users::table)
         .values(&new_user)
         .get_result::<User>(conn)
         .map_err(|error| FieldError::new(error, Error::register_username_exist()))?;
+    touch_last_seen(conn, user.id)?;
+
+    if let Some(filter) = filter {
+        flag_text(
+            conn,
+            user.id,
+            ScTextKind::Username,
+            &user.username,
+            filter,
+            None,
+            Some(user.id),
+        );
+    }
 
     let user = convert_to_sc_user(conn, &user);
 
-    let token = UserToken::generate_token(secret, &user);
+    let (sid, refresh_token, other_sessions) =
+        start_login_session(conn, user.id, ScSessionPolicy::Multi)?;
+    let token = UserToken::generate_token(secret, &user, &sid);
 
-    Ok(ScLoginResp { user, token })
+    Ok(ScLoginResp {
+        user,
+        token,
+        other_sessions,
+        refresh_token,
+        expires_in: *ACCESS_TOKEN_TTL_SECONDS as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::user::*;
+
+    #[test]
+    fn verify_password_unchanged() {
+        let stored = hash_password("password");
+        assert!(verify_password(Some(&stored), "password"));
+        assert!(!verify_password(Some(&stored), "passw0rd"));
+        assert!(!verify_password(None, "password"));
+    }
 }
@@ -0,0 +1,86 @@
+use diesel::pg::Pg;
+use diesel::prelude::*;
+
+use crate::db::schema::{comments, games, messages, users};
+
+// Every public query starts from one of these, so a soft-deleted row
+// disappears everywhere at once and the admin trash is the exact complement
+
+/// Deprecated games included, each query decides about those
+pub fn visible_games<'a>() -> games::BoxedQuery<'a, Pg> {
+    games::table
+        .filter(games::deleted_at.is_null())
+        .into_boxed()
+}
+
+pub fn trashed_games<'a>() -> games::BoxedQuery<'a, Pg> {
+    games::table
+        .filter(games::deleted_at.is_not_null())
+        .into_boxed()
+}
+
+pub fn visible_users<'a>() -> users::BoxedQuery<'a, Pg> {
+    users::table
+        .filter(users::deleted_at.is_null())
+        .into_boxed()
+}
+
+/// Deleted or banned accounts, anonymized ones stay visible as "deleted user"
+pub fn banned_users<'a>() -> users::BoxedQuery<'a, Pg> {
+    users::table
+        .filter(users::deleted_at.is_not_null())
+        .into_boxed()
+}
+
+/// Hidden comments are still shown to their author,
+/// comments of banned accounts to nobody
+pub fn visible_comments<'a>(viewer: Option<i32>) -> comments::BoxedQuery<'a, Pg> {
+    let query = comments::table
+        .filter(comments::user_id.eq_any(visible_users().select(users::id)))
+        .into_boxed();
+    match viewer {
+        Some(uid) => query.filter(comments::deleted_at.is_null().or(comments::user_id.eq(uid))),
+        None => query.filter(comments::deleted_at.is_null()),
+    }
+}
+
+pub fn hidden_comments<'a>() -> comments::BoxedQuery<'a, Pg> {
+    comments::table
+        .filter(comments::deleted_at.is_not_null())
+        .into_boxed()
+}
+
+/// Tombstoned messages are gone for both sides
+pub fn visible_messages<'a>() -> messages::BoxedQuery<'a, Pg> {
+    messages::table
+        .filter(messages::deleted_at.is_null())
+        .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::schemas::visibility::*;
+    use diesel::debug_query;
+
+    fn sql<T: diesel::query_builder::QueryFragment<Pg>>(query: T) -> String {
+        debug_query::<Pg, _>(&query).to_string()
+    }
+
+    #[test]
+    fn visibility_rules() {
+        // A soft-deleted game is in exactly one of the two
+        assert!(sql(visible_games()).contains(r#""games"."deleted_at" IS NULL"#));
+        assert!(sql(trashed_games()).contains(r#""games"."deleted_at" IS NOT NULL"#));
+        assert!(!sql(trashed_games()).contains("IS NULL"));
+
+        let guest = sql(visible_comments(None));
+        assert!(guest.contains(r#""comments"."deleted_at" IS NULL"#));
+        assert!(guest.contains(r#""users"."deleted_at" IS NULL"#));
+        let author = sql(visible_comments(Some(7)));
+        assert!(author.contains(r#"OR "comments"."user_id" = $1"#));
+        assert!(author.contains("binds: [7]"));
+
+        assert!(sql(visible_messages()).contains(r#""messages"."deleted_at" IS NULL"#));
+        assert!(sql(banned_users()).contains(r#""users"."deleted_at" IS NOT NULL"#));
+    }
+}
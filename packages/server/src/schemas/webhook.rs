@@ -0,0 +1,324 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::{FieldError, FieldResult, GraphQLEnum, GraphQLInputObject, GraphQLObject};
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::string::ToString;
+use strum::{Display, EnumString};
+use url::Url;
+
+use crate::db::models::{
+    NewGithubDeadLetter, NewWebhook, NewWebhookDelivery, Webhook, WebhookDelivery,
+};
+use crate::db::root::DB_POOL;
+use crate::db::schema::{github_dead_letters, webhook_deliveries, webhooks};
+use crate::error::Error;
+
+const MAX_ATTEMPTS: i32 = 6;
+const FAILING_THRESHOLD: i32 = 10;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScWebhookEvent {
+    GameCreated,
+    GameUpdated,
+    Announcement,
+}
+
+#[derive(GraphQLEnum, Debug, Clone, Display, EnumString, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScWebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScWebhook {
+    id: i32,
+    url: String,
+    events: Vec<ScWebhookEvent>,
+    fail_count: i32,
+    failing: bool,
+    created_at: f64,
+    updated_at: f64,
+}
+
+#[derive(GraphQLObject, Debug, Clone)]
+pub struct ScWebhookDelivery {
+    id: i32,
+    webhook_id: i32,
+    event: ScWebhookEvent,
+    status: ScWebhookDeliveryStatus,
+    attempts: i32,
+    response_status: Option<i32>,
+    error: Option<String>,
+    created_at: f64,
+    updated_at: f64,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScNewWebhook {
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<ScWebhookEvent>,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScWebhookReq {
+    pub id: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct ScWebhookDeliveriesReq {
+    pub webhook_id: i32,
+    pub first: Option<i32>,
+    pub offset: Option<i32>,
+}
+
+fn convert_to_sc_webhook(webhook: &Webhook) -> ScWebhook {
+    ScWebhook {
+        id: webhook.id,
+        url: webhook.url.clone(),
+        events: webhook
+            .events
+            .iter()
+            .filter_map(|event| ScWebhookEvent::from_str(event).ok())
+            .collect(),
+        fail_count: webhook.fail_count,
+        failing: webhook.failing,
+        created_at: webhook.created_at.timestamp_millis() as f64,
+        updated_at: webhook.updated_at.timestamp_millis() as f64,
+    }
+}
+
+fn convert_to_sc_webhook_delivery(delivery: &WebhookDelivery) -> ScWebhookDelivery {
+    ScWebhookDelivery {
+        id: delivery.id,
+        webhook_id: delivery.webhook_id,
+        event: ScWebhookEvent::from_str(&delivery.event).unwrap(),
+        status: ScWebhookDeliveryStatus::from_str(&delivery.status).unwrap(),
+        attempts: delivery.attempts,
+        response_status: delivery.response_status,
+        error: delivery.error.clone(),
+        created_at: delivery.created_at.timestamp_millis() as f64,
+        updated_at: delivery.updated_at.timestamp_millis() as f64,
+    }
+}
+
+pub fn get_webhooks(conn: &PgConnection) -> Vec<ScWebhook> {
+    use self::webhooks::dsl::*;
+
+    webhooks
+        .filter(deleted_at.is_null())
+        .order(id.asc())
+        .load::<Webhook>(conn)
+        .unwrap()
+        .iter()
+        .map(|webhook| convert_to_sc_webhook(webhook))
+        .collect()
+}
+
+pub fn create_webhook(conn: &PgConnection, req: &ScNewWebhook) -> FieldResult<ScWebhook> {
+    let valid = Url::parse(req.url.trim())
+        .map(|url| url.scheme() == "https" || url.scheme() == "http")
+        .unwrap_or(false);
+    if !valid {
+        return Err(FieldError::new(
+            "webhook url must be http(s)",
+            Error::invalid_webhook_url(),
+        ));
+    }
+
+    let new_webhook = NewWebhook {
+        url: req.url.trim(),
+        secret: &req.secret,
+        events: req.events.iter().map(|event| event.to_string()).collect(),
+        deleted_at: None,
+        created_at: Utc::now().naive_utc(),
+        updated_at: Utc::now().naive_utc(),
+    };
+
+    let webhook = diesel::insert_into(webhooks::table)
+        .values(&new_webhook)
+        .get_result::<Webhook>(conn)?;
+
+    Ok(convert_to_sc_webhook(&webhook))
+}
+
+pub fn delete_webhook(conn: &PgConnection, wid: i32) -> FieldResult<()> {
+    use self::webhooks::dsl::*;
+
+    diesel::update(webhooks.filter(id.eq(wid)))
+        .set(deleted_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+pub fn get_webhook_deliveries(
+    conn: &PgConnection,
+    req: &ScWebhookDeliveriesReq,
+) -> Vec<ScWebhookDelivery> {
+    use self::webhook_deliveries::dsl::*;
+
+    webhook_deliveries
+        .filter(webhook_id.eq(req.webhook_id))
+        .order(id.desc())
+        .limit(req.first.unwrap_or(20).clamp(1, 100).into())
+        .offset(req.offset.unwrap_or(0).max(0).into())
+        .load::<WebhookDelivery>(conn)
+        .unwrap()
+        .iter()
+        .map(|delivery| convert_to_sc_webhook_delivery(delivery))
+        .collect()
+}
+
+/// Part of the caller's transaction, unlike `emit_webhook_event`
+pub fn enqueue_webhook_event(
+    conn: &PgConnection,
+    event: ScWebhookEvent,
+    data: &Value,
+) -> QueryResult<usize> {
+    let ids = {
+        use self::webhooks::dsl::*;
+
+        webhooks
+            .select(id)
+            .filter(deleted_at.is_null())
+            .filter(events.contains(vec![event.to_string()]))
+            .load::<i32>(conn)?
+    };
+
+    let payload = json!({
+        "event": event.to_string(),
+        "data": data,
+        "timestamp": Utc::now().timestamp_millis(),
+    });
+    let event = event.to_string();
+    let status = ScWebhookDeliveryStatus::Pending.to_string();
+    let new_deliveries: Vec<_> = ids
+        .into_iter()
+        .map(|wid| NewWebhookDelivery {
+            webhook_id: wid,
+            event: &event,
+            payload: &payload,
+            status: &status,
+            next_attempt_at: Utc::now().naive_utc(),
+            created_at: Utc::now().naive_utc(),
+            updated_at: Utc::now().naive_utc(),
+        })
+        .collect();
+
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&new_deliveries)
+        .execute(conn)
+}
+
+/// Queue deliveries off the request path, never fail the caller
+pub fn emit_webhook_event(event: ScWebhookEvent, data: Value) {
+    tokio::task::spawn_blocking(move || {
+        let conn = DB_POOL.get().unwrap();
+        if let Err(err) = enqueue_webhook_event(&conn, event, &data) {
+            log::error!("Enqueue webhook event {}: {:?}", event, err);
+        }
+    });
+}
+
+/// Incoming issue event that failed every attempt
+pub fn write_github_dead_letter(
+    conn: &PgConnection,
+    issue: i32,
+    act: &str,
+    data: &Value,
+    err: &str,
+    attempt_count: i32,
+) -> QueryResult<()> {
+    let dead_letter = NewGithubDeadLetter {
+        issue_number: issue,
+        action: act,
+        payload: data,
+        error: err,
+        attempts: attempt_count,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    diesel::insert_into(github_dead_letters::table)
+        .values(&dead_letter)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_due_deliveries(conn: &PgConnection, limit: i64) -> Vec<(WebhookDelivery, Webhook)> {
+    use self::webhook_deliveries::dsl::*;
+
+    webhook_deliveries
+        .inner_join(webhooks::table)
+        .filter(status.eq(ScWebhookDeliveryStatus::Pending.to_string()))
+        .filter(next_attempt_at.le(Utc::now().naive_utc()))
+        .filter(webhooks::deleted_at.is_null())
+        .order(id.asc())
+        .limit(limit)
+        .load::<(WebhookDelivery, Webhook)>(conn)
+        .unwrap()
+}
+
+/// Exponential backoff from 30 seconds
+fn get_next_attempt_at(now: NaiveDateTime, attempts: i32) -> NaiveDateTime {
+    now + Duration::seconds(30 << attempts.clamp(0, 10))
+}
+
+pub fn set_delivery_result(
+    conn: &PgConnection,
+    delivery: &WebhookDelivery,
+    result: Result<i32, (Option<i32>, String)>,
+) -> QueryResult<()> {
+    let now = Utc::now().naive_utc();
+    let attempt_count = delivery.attempts + 1;
+
+    conn.transaction(|| {
+        use self::webhook_deliveries::dsl::*;
+
+        match &result {
+            Ok(code) => {
+                diesel::update(webhook_deliveries.filter(id.eq(delivery.id)))
+                    .set((
+                        status.eq(ScWebhookDeliveryStatus::Delivered.to_string()),
+                        attempts.eq(attempt_count),
+                        response_status.eq(Some(*code)),
+                        error.eq(None::<String>),
+                        updated_at.eq(now),
+                    ))
+                    .execute(conn)?;
+                diesel::update(webhooks::table.filter(webhooks::id.eq(delivery.webhook_id)))
+                    .set((webhooks::fail_count.eq(0), webhooks::failing.eq(false)))
+                    .execute(conn)?;
+            }
+            Err((code, err)) => {
+                let next_status = if attempt_count >= MAX_ATTEMPTS {
+                    ScWebhookDeliveryStatus::Failed
+                } else {
+                    ScWebhookDeliveryStatus::Pending
+                };
+                diesel::update(webhook_deliveries.filter(id.eq(delivery.id)))
+                    .set((
+                        status.eq(next_status.to_string()),
+                        attempts.eq(attempt_count),
+                        response_status.eq(*code),
+                        error.eq(Some(err.clone())),
+                        next_attempt_at.eq(get_next_attempt_at(now, attempt_count)),
+                        updated_at.eq(now),
+                    ))
+                    .execute(conn)?;
+                diesel::update(webhooks::table.filter(webhooks::id.eq(delivery.webhook_id)))
+                    .set((
+                        webhooks::fail_count.eq(webhooks::fail_count + 1),
+                        webhooks::failing.eq((webhooks::fail_count + 1).ge(FAILING_THRESHOLD)),
+                    ))
+                    .execute(conn)?;
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,464 @@
+use data_encoding::BASE64;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{ColorType, DynamicImage, ImageFormat, ImageOutputFormat};
+use std::env;
+use std::io::{self, Cursor};
+use std::str::FromStr;
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator};
+
+use crate::db::root::DB_POOL;
+use crate::net::{fetch_public, read_body};
+use crate::rom::sha256_hex;
+use crate::schemas::game::get_game_screenshots;
+use crate::storage::{get_storage, HashedBlobs, Storage};
+
+// Decoded size of a room screenshot sent by `updateRoomScreenshot`
+const MAX_LIVE_SCREENSHOT_BYTES: usize = 1024 * 1024;
+// Larger room screenshots are refused before decoding
+const MAX_LIVE_SCREENSHOT_SIDE: u32 = 2048;
+// Larger catalog screenshots are refused before decoding
+const MAX_SCREENSHOT_SIDE: u32 = 4096;
+
+lazy_static! {
+    static ref SCREENSHOT_MAX_SIZE: u64 = env::var("SCREENSHOT_MAX_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(10 * 1024 * 1024);
+}
+
+#[derive(Debug, Clone, Copy, Display, EnumString, EnumIter, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ScreenshotSize {
+    // catalog grid
+    Thumb,
+    // game page
+    Card,
+    // original dimensions
+    Full,
+}
+
+impl ScreenshotSize {
+    /// `None` keeps the width, smaller images are never upscaled
+    pub fn width(self) -> Option<u32> {
+        match self {
+            ScreenshotSize::Thumb => Some(160),
+            ScreenshotSize::Card => Some(320),
+            ScreenshotSize::Full => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Display, EnumIter, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum RenditionFormat {
+    // sent when `Accept` allows it
+    Webp,
+    // fallbacks, jpeg sources stay jpeg
+    Jpeg,
+    Png,
+}
+
+impl RenditionFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            RenditionFormat::Webp => "image/webp",
+            RenditionFormat::Jpeg => "image/jpeg",
+            RenditionFormat::Png => "image/png",
+        }
+    }
+
+    fn fallback(source: ImageFormat) -> Self {
+        match source {
+            ImageFormat::Jpeg => RenditionFormat::Jpeg,
+            _ => RenditionFormat::Png,
+        }
+    }
+}
+
+pub struct Rendition {
+    pub size: ScreenshotSize,
+    pub format: RenditionFormat,
+    pub data: Vec<u8>,
+}
+
+/// Renditions of an upstream url share the prefix, the url stays out of the key
+pub fn get_screenshot_hash(url: &str) -> String {
+    sha256_hex(url.as_bytes())[..16].to_owned()
+}
+
+pub fn get_rendition_key(hash: &str, size: ScreenshotSize, format: RenditionFormat) -> String {
+    format!("screenshots/{}/{}.{}", hash, size, format)
+}
+
+/// Served by `/screenshot/{gid}/{hash}/{size}`, `None` when the url can't be cached
+pub fn get_rendition_url(gid: i32, url: &str, size: ScreenshotSize) -> Option<String> {
+    get_storage()?;
+    if !url.starts_with("http") {
+        return None;
+    }
+    Some(format!(
+        "/screenshot/{}/{}/{}",
+        gid,
+        get_screenshot_hash(url),
+        size
+    ))
+}
+
+pub fn encode(image: &DynamicImage, format: RenditionFormat) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    let result = match format {
+        RenditionFormat::Webp => {
+            let rgba = image.to_rgba8();
+            WebPEncoder::new_lossless(&mut data).encode(
+                rgba.as_raw(),
+                rgba.width(),
+                rgba.height(),
+                ColorType::Rgba8,
+            )
+        }
+        // No alpha in jpeg
+        RenditionFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(85)),
+        RenditionFormat::Png => image.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png),
+    };
+    result.map_err(|err| err.to_string())?;
+    Ok(data)
+}
+
+fn resize_to_width(image: &DynamicImage, size: ScreenshotSize) -> DynamicImage {
+    match size.width() {
+        Some(width) if width < image.width() => {
+            let height = (image.height() as u64 * width as u64 / image.width() as u64).max(1);
+            image.resize_exact(width, height as u32, FilterType::Triangle)
+        }
+        _ => image.clone(),
+    }
+}
+
+/// Refuses images wider or higher than `max_side` before allocating them
+fn decode_limited(source: &[u8], max_side: u32) -> Result<(DynamicImage, ImageFormat), String> {
+    let mut reader = Reader::new(Cursor::new(source))
+        .with_guessed_format()
+        .map_err(|err| err.to_string())?;
+    let format = reader.format().ok_or("unknown image format")?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_side);
+    limits.max_image_height = Some(max_side);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|err| err.to_string())?;
+    Ok((image, format))
+}
+
+/// Every size as webp and as the fallback format of the source
+pub fn render_screenshot(source: &[u8]) -> Result<Vec<Rendition>, String> {
+    let (image, source_format) = decode_limited(source, MAX_SCREENSHOT_SIDE)?;
+    let fallback = RenditionFormat::fallback(source_format);
+
+    let mut renditions = Vec::new();
+    for size in ScreenshotSize::iter() {
+        let resized = resize_to_width(&image, size);
+        for format in [RenditionFormat::Webp, fallback] {
+            // Nothing to gain from re-encoding the original
+            let data = if size == ScreenshotSize::Full
+                && source_format == ImageFormat::Png
+                && format == RenditionFormat::Png
+            {
+                source.to_vec()
+            } else {
+                encode(&resized, format)?
+            };
+            renditions.push(Rendition { size, format, data });
+        }
+    }
+    Ok(renditions)
+}
+
+/// The url comes from a game, internal hosts are refused on every redirect
+fn fetch_screenshot(url: &str) -> Result<Vec<u8>, String> {
+    let resp = fetch_public(url).map_err(|err| err.to_string())?;
+    read_body(resp, *SCREENSHOT_MAX_SIZE)
+}
+
+fn store_renditions(storage: &dyn Storage, url: &str) -> Result<Vec<Rendition>, String> {
+    let renditions = render_screenshot(&fetch_screenshot(url)?)?;
+    let hash = get_screenshot_hash(url);
+    for rendition in &renditions {
+        storage
+            .put(
+                &get_rendition_key(&hash, rendition.size, rendition.format),
+                &rendition.data,
+                rendition.format.content_type(),
+            )
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(renditions)
+}
+
+/// Stored key and content type of a rendition, games ingested before
+/// renditions existed are rendered on first access.
+/// Blocking, call from `web::block` or `spawn_blocking`
+pub fn load_screenshot(
+    gid: i32,
+    hash: &str,
+    size: &str,
+    webp: bool,
+) -> Option<(String, &'static str)> {
+    let size = ScreenshotSize::from_str(size).ok()?;
+    let storage = get_storage()?;
+    let formats: &[RenditionFormat] = if webp {
+        &[RenditionFormat::Webp]
+    } else {
+        &[RenditionFormat::Jpeg, RenditionFormat::Png]
+    };
+    let stored = formats.iter().find(|format| {
+        storage
+            .exists(&get_rendition_key(hash, size, **format))
+            .unwrap_or_default()
+    });
+    if let Some(format) = stored {
+        return Some((
+            get_rendition_key(hash, size, *format),
+            format.content_type(),
+        ));
+    }
+
+    // Only screenshots of a visible game, this is not an open proxy
+    let conn = DB_POOL.get().ok()?;
+    let url = get_game_screenshots(&conn, gid)
+        .into_iter()
+        .find(|url| url.starts_with("http") && get_screenshot_hash(url) == hash)?;
+    match store_renditions(storage, &url) {
+        Ok(renditions) => renditions
+            .iter()
+            .find(|rendition| rendition.size == size && formats.contains(&rendition.format))
+            .map(|rendition| {
+                (
+                    get_rendition_key(hash, size, rendition.format),
+                    rendition.format.content_type(),
+                )
+            }),
+        Err(err) => {
+            log::error!("Render screenshot {} failed: {}, {}", gid, url, err);
+            None
+        }
+    }
+}
+
+/// Render at ingest time so the catalog never waits, already stored urls are skipped
+pub fn cache_screenshots(urls: Vec<String>) {
+    let storage = match get_storage() {
+        Some(storage) => storage,
+        None => return,
+    };
+    let urls: Vec<String> = urls
+        .into_iter()
+        .filter(|url| url.starts_with("http"))
+        .collect();
+    if urls.is_empty() {
+        return;
+    }
+
+    tokio::task::spawn_blocking(move || {
+        for url in urls {
+            let key = get_rendition_key(
+                &get_screenshot_hash(&url),
+                ScreenshotSize::Full,
+                RenditionFormat::Webp,
+            );
+            if storage.exists(&key).unwrap_or_default() {
+                continue;
+            }
+            match store_renditions(storage, &url) {
+                Ok(_) => log::info!("Cached screenshot: {}", url),
+                Err(err) => log::error!("Cache screenshot failed: {}, {}", url, err),
+            }
+        }
+    });
+}
+
+/// Delete every rendition of an upstream image, rendered again on next access
+pub fn purge_screenshot(url: &str) -> io::Result<()> {
+    let storage = match get_storage() {
+        Some(storage) => storage,
+        None => return Ok(()),
+    };
+    let hash = get_screenshot_hash(url);
+    for size in ScreenshotSize::iter() {
+        for format in RenditionFormat::iter() {
+            storage.delete(&get_rendition_key(&hash, size, format))?;
+        }
+    }
+    Ok(())
+}
+
+/// Served by `/room-screenshot/{rid}/{hash}`, the hash changes with every frame.
+/// Values stored before storage was configured aren't urls of it
+pub static LIVE_SCREENSHOTS: HashedBlobs = HashedBlobs {
+    dir: "rooms",
+    route: "/room-screenshot",
+    extension: "webp",
+    content_type: "image/webp",
+};
+
+/// Bare base64 or a `data:image/...;base64,` url, as sent by `canvas.toDataURL`
+pub fn decode_data_url(value: &str) -> Result<Vec<u8>, String> {
+    let encoded = match value.strip_prefix("data:") {
+        Some(url) => {
+            let (media, data) = url.split_once(',').ok_or("invalid data url")?;
+            if !media.starts_with("image/") || !media.ends_with(";base64") {
+                return Err("not a base64 image".into());
+            }
+            data
+        }
+        None => value,
+    };
+    if encoded.len() / 4 * 3 > MAX_LIVE_SCREENSHOT_BYTES {
+        return Err(format!("size exceeds {}", MAX_LIVE_SCREENSHOT_BYTES));
+    }
+    BASE64
+        .decode(encoded.trim().as_bytes())
+        .map_err(|_| "not base64".into())
+}
+
+/// Card sized webp of a frame, small enough to refresh the lobby often
+pub fn render_live_screenshot(source: &[u8]) -> Result<Vec<u8>, String> {
+    let (image, _) = decode_limited(source, MAX_LIVE_SCREENSHOT_SIDE)?;
+
+    encode(
+        &resize_to_width(&image, ScreenshotSize::Card),
+        RenditionFormat::Webp,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::screenshot::*;
+    use crate::storage::get_blob_hash;
+
+    // 480x300 png
+    const FIXTURE: &[u8] = include_bytes!("screenshot_fixture.png");
+
+    #[test]
+    fn screenshot_renditions() {
+        let renditions = render_screenshot(FIXTURE).unwrap();
+        let found: Vec<_> = renditions
+            .iter()
+            .map(|rendition| {
+                let format = image::guess_format(&rendition.data).unwrap();
+                let image = image::load_from_memory_with_format(&rendition.data, format).unwrap();
+                (
+                    rendition.size,
+                    rendition.format.content_type(),
+                    format,
+                    (image.width(), image.height()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    ScreenshotSize::Thumb,
+                    "image/webp",
+                    ImageFormat::WebP,
+                    (160, 100)
+                ),
+                (
+                    ScreenshotSize::Thumb,
+                    "image/png",
+                    ImageFormat::Png,
+                    (160, 100)
+                ),
+                (
+                    ScreenshotSize::Card,
+                    "image/webp",
+                    ImageFormat::WebP,
+                    (320, 200)
+                ),
+                (
+                    ScreenshotSize::Card,
+                    "image/png",
+                    ImageFormat::Png,
+                    (320, 200)
+                ),
+                (
+                    ScreenshotSize::Full,
+                    "image/webp",
+                    ImageFormat::WebP,
+                    (480, 300)
+                ),
+                (
+                    ScreenshotSize::Full,
+                    "image/png",
+                    ImageFormat::Png,
+                    (480, 300)
+                ),
+            ]
+        );
+        assert_eq!(renditions[5].data, FIXTURE);
+
+        // Jpeg sources fall back to jpeg
+        let mut jpeg = Vec::new();
+        image::load_from_memory(FIXTURE)
+            .unwrap()
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let renditions = render_screenshot(&jpeg).unwrap();
+        assert_eq!(renditions[1].format, RenditionFormat::Jpeg);
+        assert_eq!(
+            image::guess_format(&renditions[1].data).unwrap(),
+            ImageFormat::Jpeg
+        );
+
+        assert!(render_screenshot(b"<svg></svg>").is_err());
+
+        // Refused before decoding
+        let mut wide = Vec::new();
+        DynamicImage::new_rgb8(MAX_SCREENSHOT_SIDE + 1, 1)
+            .write_to(&mut Cursor::new(&mut wide), ImageOutputFormat::Png)
+            .unwrap();
+        assert!(render_screenshot(&wide).is_err());
+    }
+
+    #[test]
+    fn screenshot_keys() {
+        let url = "https://user-images.githubusercontent.com/1/shot.png";
+        let hash = get_screenshot_hash(url);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(
+            get_rendition_key(&hash, ScreenshotSize::Thumb, RenditionFormat::Webp),
+            format!("screenshots/{}/thumb.webp", hash)
+        );
+        assert_ne!(
+            get_rendition_key(&hash, ScreenshotSize::Thumb, RenditionFormat::Png),
+            get_rendition_key(&hash, ScreenshotSize::Card, RenditionFormat::Png)
+        );
+        assert_eq!(ScreenshotSize::from_str("card"), Ok(ScreenshotSize::Card));
+        assert!(ScreenshotSize::from_str("huge").is_err());
+    }
+
+    #[test]
+    fn live_screenshots() {
+        let url = format!("data:image/png;base64,{}", BASE64.encode(FIXTURE));
+        assert_eq!(decode_data_url(&url).unwrap(), FIXTURE);
+        assert_eq!(decode_data_url(&BASE64.encode(FIXTURE)).unwrap(), FIXTURE);
+        assert!(decode_data_url("data:text/plain;base64,aGk=").is_err());
+        assert!(decode_data_url("data:image/png,raw").is_err());
+
+        let data = render_live_screenshot(FIXTURE).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), ImageFormat::WebP);
+        let live = image::load_from_memory(&data).unwrap();
+        assert_eq!((live.width(), live.height()), (320, 200));
+        assert!(render_live_screenshot(b"<svg></svg>").is_err());
+
+        let hash = get_blob_hash(&data);
+        assert_eq!(
+            LIVE_SCREENSHOTS.parse_url(&LIVE_SCREENSHOTS.get_url(3, &hash)),
+            Some(format!("rooms/3/{}.webp", hash))
+        );
+        // Kept as sent without storage
+        assert_eq!(LIVE_SCREENSHOTS.parse_url(&url), None);
+    }
+}
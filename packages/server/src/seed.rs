@@ -0,0 +1,374 @@
+use chrono::{Duration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use juniper::FieldError;
+use std::env;
+use std::fmt;
+use std::time::Instant;
+
+use crate::db::schema::{comments, records, users};
+use crate::schemas::{
+    achievement::{
+        get_game_achievements, set_achievements, ScNewAchievement, ScSetAchievementsReq,
+    },
+    comment::{create_comment, ScNewComment},
+    favorite::{create_favorite, get_favorites},
+    friend::{accept_friend, apply_friend, get_friend},
+    game::{
+        create_game, get_game_from_name, GameLookupError, ScGameKind, ScGamePlatform, ScNewGame,
+        ScPlayMode,
+    },
+    game_version::ScGameVersionSource,
+    playing::get_playing,
+    record::add_past_session,
+    room::{create_room, ScNewRoom},
+    user::{get_user_by_username, register, ScRegisterReq},
+};
+
+lazy_static! {
+    // Run at startup, `SEED_FORCE=true` also seeds an instance with real users
+    pub static ref SEED_DEMO_DATA: bool = is_enabled("SEED_DEMO_DATA");
+    pub static ref SEED_FORCE: bool = is_enabled("SEED_FORCE");
+}
+
+fn is_enabled(name: &str) -> bool {
+    env::var(name).map_or(false, |value| value == "true" || value == "1")
+}
+
+pub const DEMO_PASSWORD: &str = "nesbox-demo";
+pub const DEMO_USERS: [&str; 4] = ["alice", "bob", "carol", "dave"];
+
+// Served by the webapp, ROMs aren't bundled so the games don't start
+const DEMO_COVER: &str = "/demo/cover.svg";
+const DEMO_SCREENSHOT: &str = "/demo/screenshot.svg";
+
+// name, kinds, max player
+const DEMO_GAMES: [(&str, &[ScGameKind], i32); 12] = [
+    ("Pixel Knight", &[ScGameKind::Act], 1),
+    ("Star Lancer", &[ScGameKind::Stg], 2),
+    ("Dungeon Tales", &[ScGameKind::Rpg], 1),
+    ("Turbo Lane", &[ScGameKind::Rcg], 2),
+    ("Block Drop", &[ScGameKind::Pzg], 2),
+    ("Kick Off 8-bit", &[ScGameKind::Spg], 4),
+    ("Iron Fist Arena", &[ScGameKind::Ftg], 2),
+    ("Castle Siege", &[ScGameKind::Rts, ScGameKind::Slg], 2),
+    ("Mahjong Night", &[ScGameKind::Tbg], 1),
+    ("Tactics Isle", &[ScGameKind::Tbs], 1),
+    ("Jungle Run", &[ScGameKind::Act, ScGameKind::Pzg], 2),
+    ("Sky Patrol", &[ScGameKind::Stg, ScGameKind::Act], 2),
+];
+
+// user indexes, the first applies and the second accepts
+const DEMO_FRIENDS: [(usize, usize); 3] = [(0, 1), (0, 2), (1, 3)];
+
+// user, game, body, like, stars
+const DEMO_COMMENTS: [(usize, usize, &str, bool, i32); 6] = [
+    (0, 0, "Tight controls, great first level", true, 5),
+    (1, 0, "Gets hard after the castle", true, 4),
+    (2, 1, "Best with a friend", true, 5),
+    (3, 4, "Simple and addictive", true, 4),
+    (1, 6, "Too many button mashers online", false, 2),
+    (0, 7, "Takes a while to learn", true, 3),
+];
+
+const DEMO_FAVORITES: [(usize, usize); 5] = [(0, 0), (0, 1), (1, 6), (2, 4), (3, 1)];
+
+// user, game, minutes, days ago
+const DEMO_RECORDS: [(usize, usize, i64, i64); 8] = [
+    (0, 0, 95, 1),
+    (0, 1, 30, 3),
+    (1, 0, 45, 2),
+    (1, 6, 120, 1),
+    (2, 1, 60, 5),
+    (2, 4, 20, 0),
+    (3, 4, 75, 4),
+    (3, 1, 15, 6),
+];
+
+// game, key, name, description
+const DEMO_ACHIEVEMENTS: [(usize, &str, &str, &str); 4] = [
+    (0, "first_clear", "First Clear", "Finish the first level"),
+    (
+        0,
+        "no_damage",
+        "Untouched",
+        "Finish a level without taking damage",
+    ),
+    (1, "co_op", "Wingman", "Clear a stage with a friend"),
+    (
+        4,
+        "combo_10",
+        "Chain Reaction",
+        "Clear ten lines in one combo",
+    ),
+];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SeedSummary {
+    pub users: usize,
+    pub games: usize,
+    pub friendships: usize,
+    pub comments: usize,
+    pub favorites: usize,
+    pub rooms: usize,
+    pub records: usize,
+    pub achievements: usize,
+}
+
+impl fmt::Display for SeedSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "users={} games={} friendships={} comments={} favorites={} rooms={} records={} achievements={}",
+            self.users,
+            self.games,
+            self.friendships,
+            self.comments,
+            self.favorites,
+            self.rooms,
+            self.records,
+            self.achievements
+        )
+    }
+}
+
+/// Only an empty or demo-only instance is seeded unless forced
+pub fn check_seed_target(usernames: &[String], force: bool) -> Result<(), String> {
+    let others = usernames
+        .iter()
+        .filter(|username| !DEMO_USERS.contains(&username.as_str()))
+        .count();
+    if others > 0 && !force {
+        return Err(format!(
+            "{} users exist, set SEED_FORCE=true or pass --force",
+            others
+        ));
+    }
+    Ok(())
+}
+
+fn get_demo_game(index: usize) -> ScNewGame {
+    let (name, kinds, max_player) = DEMO_GAMES[index];
+    ScNewGame {
+        name: name.into(),
+        description: format!(
+            "![{}]({})\n\nDemo game, the ROM isn't included.",
+            name, DEMO_COVER
+        ),
+        preview: DEMO_COVER.into(),
+        rom: format!(
+            "/roms/demo/{}.nes.zip",
+            name.to_lowercase().replace(' ', "-")
+        ),
+        screenshots: vec![DEMO_SCREENSHOT.into()],
+        platform: Some(ScGamePlatform::Nes),
+        series: None,
+        kind: kinds.first().cloned(),
+        kinds: Some(kinds.to_vec()),
+        max_player: Some(max_player),
+        deprecation_reason: None,
+        rom_hash: None,
+        issue_number: None,
+        issue_url: None,
+        attachments: None,
+        score_ceiling: None,
+        trial_allowed: Some(index < 3),
+        play_modes: None,
+    }
+}
+
+fn seed(conn: &PgConnection, secret: &str) -> Result<SeedSummary, FieldError> {
+    let mut summary = SeedSummary::default();
+
+    let mut user_ids = Vec::new();
+    for username in DEMO_USERS {
+        let uid = match get_user_by_username(conn, username) {
+            Ok(user) => user.id,
+            Err(_) => {
+                summary.users += 1;
+                let req = ScRegisterReq {
+                    username: username.into(),
+                    password: DEMO_PASSWORD.into(),
+                    email: None,
+                    captcha_token: None,
+                    website: None,
+                    form_started_at: None,
+                    birthdate: None,
+                };
+                register(conn, req, None, secret)?.user.id
+            }
+        };
+        user_ids.push(uid);
+    }
+
+    let mut game_ids = Vec::new();
+    for index in 0..DEMO_GAMES.len() {
+        let req = get_demo_game(index);
+        let gid = match get_game_from_name(conn, &req.name, None) {
+            Ok(game) => game.id,
+            Err(GameLookupError::NotFound) => {
+                summary.games += 1;
+                create_game(conn, &req, ScGameVersionSource::Seed)?.id
+            }
+            Err(err) => return Err(FieldError::from(format!("{}: {:?}", req.name, err))),
+        };
+        game_ids.push(gid);
+    }
+
+    for (from, to) in DEMO_FRIENDS {
+        let (uid, tid) = (user_ids[from], user_ids[to]);
+        if get_friend(conn, uid, tid).is_ok() || get_friend(conn, tid, uid).is_ok() {
+            continue;
+        }
+        summary.friendships += 1;
+        apply_friend(conn, uid, tid)?;
+        accept_friend(conn, tid, uid)?;
+    }
+
+    for (user, game, body, like, stars) in DEMO_COMMENTS {
+        let (uid, gid) = (user_ids[user], game_ids[game]);
+        let exists = comments::table
+            .filter(comments::user_id.eq(uid))
+            .filter(comments::game_id.eq(gid))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0;
+        if exists {
+            continue;
+        }
+        summary.comments += 1;
+        let req = ScNewComment {
+            game_id: gid,
+            body: body.into(),
+            like,
+            rating: Some(stars),
+        };
+        create_comment(conn, uid, &req)?;
+    }
+
+    for (user, game) in DEMO_FAVORITES {
+        let (uid, gid) = (user_ids[user], game_ids[game]);
+        if get_favorites(conn, uid).contains(&gid) {
+            continue;
+        }
+        summary.favorites += 1;
+        create_favorite(conn, uid, gid)?;
+    }
+
+    let now = Utc::now().naive_utc();
+    for (user, game, minutes, days_ago) in DEMO_RECORDS {
+        let (uid, gid) = (user_ids[user], game_ids[game]);
+        let exists = records::table
+            .filter(records::user_id.eq(uid))
+            .filter(records::game_id.eq(gid))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0;
+        if exists {
+            continue;
+        }
+        summary.records += 1;
+        let start = now - Duration::days(days_ago) - Duration::hours(2);
+        add_past_session(conn, uid, gid, start, start + Duration::minutes(minutes))?;
+    }
+
+    for (game, key, name, description) in DEMO_ACHIEVEMENTS {
+        let gid = game_ids[game];
+        if get_game_achievements(conn, gid)?
+            .iter()
+            .any(|achievement| achievement.key == key)
+        {
+            continue;
+        }
+        summary.achievements += 1;
+        let req = ScSetAchievementsReq {
+            game_id: gid,
+            achievements: vec![ScNewAchievement {
+                key: key.into(),
+                name: name.into(),
+                description: Some(description.into()),
+            }],
+        };
+        set_achievements(conn, &req)?;
+    }
+
+    // A public room to join, hosted by the first user
+    if get_playing(conn, user_ids[0]).is_none() {
+        summary.rooms += 1;
+        let req = ScNewRoom {
+            game_id: game_ids[1],
+            private: false,
+            mode: ScPlayMode::CoOp,
+            allow_commands: None,
+            password: None,
+        };
+        create_room(conn, user_ids[0], None, &req)?;
+    }
+
+    Ok(summary)
+}
+
+/// Creates what is missing through the regular creation functions, in one transaction
+pub fn seed_demo_data(
+    conn: &PgConnection,
+    secret: &str,
+    force: bool,
+) -> Result<SeedSummary, String> {
+    let start = Instant::now();
+    let usernames = users::table
+        .select(users::username)
+        .filter(users::deleted_at.is_null())
+        .load::<String>(conn)
+        .map_err(|err| err.to_string())?;
+    check_seed_target(&usernames, force)?;
+
+    let summary = conn
+        .transaction(|| seed(conn, secret))
+        .map_err(|err| err.message().to_owned())?;
+    log::info!(
+        "Seeded demo data in {}ms: {}, password of {:?} is {}",
+        start.elapsed().as_millis(),
+        summary,
+        DEMO_USERS,
+        DEMO_PASSWORD
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::seed::*;
+
+    #[test]
+    fn seed_target() {
+        assert!(check_seed_target(&[], false).is_ok());
+        // Seeding twice is fine
+        let demo: Vec<String> = DEMO_USERS.iter().map(|name| name.to_string()).collect();
+        assert!(check_seed_target(&demo, false).is_ok());
+
+        let mut real = demo.clone();
+        real.push("mantou".into());
+        assert!(check_seed_target(&real, false).is_err());
+        assert!(check_seed_target(&real, true).is_ok());
+
+        // Every reference points at seeded data, names are unique per platform
+        let mut names: Vec<_> = (0..DEMO_GAMES.len())
+            .map(|index| get_demo_game(index).name)
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), DEMO_GAMES.len());
+        assert!(DEMO_COMMENTS
+            .iter()
+            .all(|(user, game, _, _, stars)| *user < DEMO_USERS.len()
+                && *game < DEMO_GAMES.len()
+                && (1..=5).contains(stars)));
+        assert!(DEMO_RECORDS
+            .iter()
+            .all(|(user, game, _, _)| *user < DEMO_USERS.len() && *game < DEMO_GAMES.len()));
+        assert!(DEMO_ACHIEVEMENTS
+            .iter()
+            .all(|(game, _, _, _)| *game < DEMO_GAMES.len()));
+        assert!(get_demo_game(3).rom.starts_with("/roms/demo/turbo-lane"));
+    }
+}
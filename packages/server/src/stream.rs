@@ -0,0 +1,110 @@
+use actix_web::web::{self, Bytes};
+use async_stream::stream;
+use futures::Stream;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Last element key when a streamed array is truncated by an error
+pub const ERROR_SENTINEL: &str = "$error";
+
+fn get_error_chunk(first: bool, err: String) -> Bytes {
+    let mut sentinel = Map::new();
+    sentinel.insert(ERROR_SENTINEL.to_owned(), Value::String(err));
+    let prefix = if first { "" } else { "," };
+    Bytes::from(format!("{}{}", prefix, Value::Object(sentinel)))
+}
+
+/// Stream json array batch by batch, `load` returns rows after the cursor,
+/// empty batch ends the array. When failed, the array is left unclosed
+/// and ends with `{"$error": <message>}`.
+pub fn stream_json_array<T, L, K>(
+    load: L,
+    key: K,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    T: Serialize + Send + 'static,
+    L: Fn(Option<i32>) -> Result<Vec<T>, String> + Send + Clone + 'static,
+    K: Fn(&T) -> i32 + 'static,
+{
+    stream! {
+        yield Ok(Bytes::from_static(b"["));
+
+        let mut cursor = None;
+        let mut first = true;
+        loop {
+            let load = load.clone();
+            let rows = match web::block(move || load(cursor)).await {
+                Ok(Ok(rows)) => rows,
+                Ok(Err(err)) => {
+                    yield Ok(get_error_chunk(first, err));
+                    return;
+                }
+                Err(err) => {
+                    yield Ok(get_error_chunk(first, err.to_string()));
+                    return;
+                }
+            };
+            if rows.is_empty() {
+                break;
+            }
+            cursor = rows.last().map(&key);
+
+            let mut chunk = Vec::new();
+            for row in rows.iter() {
+                if !first {
+                    chunk.push(b',');
+                }
+                first = false;
+                if let Err(err) = serde_json::to_writer(&mut chunk, row) {
+                    yield Ok(Bytes::from(chunk));
+                    yield Ok(get_error_chunk(false, err.to_string()));
+                    return;
+                }
+            }
+            yield Ok(Bytes::from(chunk));
+        }
+
+        yield Ok(Bytes::from_static(b"]"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::*;
+    use futures::StreamExt;
+
+    fn load_synthetic(
+        cursor: Option<i32>,
+        total: i32,
+        fail_at: Option<i32>,
+    ) -> Result<Vec<i32>, String> {
+        let start = cursor.map_or(0, |id| id + 1);
+        if fail_at.map_or(false, |id| start >= id) {
+            return Err("db gone".into());
+        }
+        Ok((start..total).take(100).collect())
+    }
+
+    #[actix_web::test]
+    async fn streams_batches_incrementally() {
+        let stream = stream_json_array(|cursor| load_synthetic(cursor, 3000, None), |id| *id);
+        let chunks: Vec<Bytes> = stream.map(|chunk| chunk.unwrap()).collect().await;
+
+        // `[`, 30 batches, `]`
+        assert_eq!(chunks.len(), 32);
+        assert!(chunks.iter().all(|chunk| chunk.len() < 1024));
+        let body: Vec<u8> = chunks.concat();
+        let rows: Vec<i32> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(rows, (0..3000).collect::<Vec<i32>>());
+    }
+
+    #[actix_web::test]
+    async fn error_truncates_with_sentinel() {
+        let stream = stream_json_array(|cursor| load_synthetic(cursor, 3000, Some(200)), |id| *id);
+        let chunks: Vec<Bytes> = stream.map(|chunk| chunk.unwrap()).collect().await;
+
+        let body = String::from_utf8(chunks.concat()).unwrap();
+        assert!(!body.ends_with(']'));
+        assert!(body.ends_with(r#",{"$error":"db gone"}"#));
+    }
+}
@@ -0,0 +1,779 @@
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE64;
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Integer, Text, Timestamp, Varchar};
+use juniper::FieldResult;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+use crate::db::models::{NewFavorite, NewRecord, User};
+use crate::db::schema::{favorites, games, records, save_states, users};
+use crate::schemas::save_state::{
+    check_save_state, get_checksum, import_save_state, read_save_state,
+};
+
+pub const EXPORT_VERSION: i32 = 1;
+// Settings the client keys by game id, remapped by name and platform
+const PER_GAME_SETTINGS: [&str; 2] = ["cheat", "combo"];
+
+lazy_static! {
+    pub static ref MAX_IMPORT_BYTES: usize = env::var("MAX_IMPORT_BYTES")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(32 * 1024 * 1024);
+}
+
+/// Ids differ across instances, games are referenced by name and platform
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GameRef {
+    pub name: String,
+    pub platform: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedGameSetting {
+    #[serde(flatten)]
+    pub game: GameRef,
+    // `cheat` or `combo`
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedFavorite {
+    #[serde(flatten)]
+    pub game: GameRef,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedRecord {
+    #[serde(flatten)]
+    pub game: GameRef,
+    pub play_total: i64,
+    pub last_play_start_at: i64,
+    pub last_play_end_at: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedSaveState {
+    #[serde(flatten)]
+    pub game: GameRef,
+    pub slot: i32,
+    // base64 of the emulator state
+    pub data: String,
+    pub core_version: Option<i32>,
+    pub updated_at: i64,
+}
+
+/// Conversations can't move to another instance, exported but not imported
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedMessage {
+    // username of the other side
+    pub peer: String,
+    pub outgoing: bool,
+    pub body: String,
+    pub created_at: i64,
+    // pruned from the conversation by retention
+    pub archived: bool,
+}
+
+/// Body of `GET /export` and `POST /import`, timestamps in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonalData {
+    pub version: i32,
+    pub exported_at: i64,
+    // keybinding, shortcuts, video, ... without the per game entries
+    pub settings: Map<String, Value>,
+    pub settings_updated_at: i64,
+    pub game_settings: Vec<ExportedGameSetting>,
+    pub favorites: Vec<ExportedFavorite>,
+    pub records: Vec<ExportedRecord>,
+    #[serde(default)]
+    pub save_states: Vec<ExportedSaveState>,
+    #[serde(default)]
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordData {
+    pub play_total: i64,
+    pub last_play_start_at: i64,
+    pub last_play_end_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateData {
+    pub checksum: String,
+    pub core_version: Option<i32>,
+    pub updated_at: i64,
+    // read for an export, decoded from an import
+    pub data: Option<Vec<u8>>,
+}
+
+/// What an account owns that moves between instances
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountData {
+    pub settings: Map<String, Value>,
+    pub settings_updated_at: i64,
+    // game id -> created at
+    pub favorites: BTreeMap<i32, i64>,
+    pub records: BTreeMap<i32, RecordData>,
+    // (game id, slot) -> state
+    pub save_states: BTreeMap<(i32, i32), StateData>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportOutcome {
+    Imported,
+    Unchanged,
+    SkippedNoMatchingGame,
+    ConflictKeptNewer,
+    // not base64, an unknown slot or too large
+    Rejected,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportItem {
+    // setting, cheat, combo, favorite, record or state
+    pub kind: String,
+    // setting key or game name
+    pub name: String,
+    pub platform: Option<String>,
+    pub outcome: ImportOutcome,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub items: Vec<ImportItem>,
+}
+
+/// Checked before deserializing so older exports get a clear message
+pub fn parse_personal_data(body: &[u8]) -> Result<PersonalData, String> {
+    let value: Value = serde_json::from_slice(body).map_err(|err| err.to_string())?;
+    match value.get("version").and_then(|version| version.as_i64()) {
+        Some(version) if version == EXPORT_VERSION as i64 => (),
+        Some(version) => return Err(format!("unsupported export version {}", version)),
+        None => return Err("missing export version".into()),
+    }
+    serde_json::from_value(value).map_err(|err| err.to_string())
+}
+
+/// Lowest id wins when several games share a name and platform
+pub fn index_games(games: &HashMap<i32, GameRef>) -> HashMap<GameRef, i32> {
+    let mut index = HashMap::new();
+    for (gid, game) in games {
+        let entry = index.entry(game.clone()).or_insert(*gid);
+        *entry = (*entry).min(*gid);
+    }
+    index
+}
+
+pub fn build_export(
+    account: &AccountData,
+    games: &HashMap<i32, GameRef>,
+    now: i64,
+) -> PersonalData {
+    let mut settings = account.settings.clone();
+    let mut game_settings = vec![];
+    for key in PER_GAME_SETTINGS {
+        let entries = match settings.remove(key) {
+            Some(Value::Object(entries)) => entries,
+            _ => continue,
+        };
+        for (gid, value) in entries {
+            let game = gid.parse::<i32>().ok().and_then(|gid| games.get(&gid));
+            if let (Some(game), false) = (game, value.is_null()) {
+                game_settings.push(ExportedGameSetting {
+                    game: game.clone(),
+                    key: key.to_owned(),
+                    value,
+                });
+            }
+        }
+    }
+    game_settings.sort_by(|a, b| (&a.key, &a.game).cmp(&(&b.key, &b.game)));
+
+    let mut favorites: Vec<_> = account
+        .favorites
+        .iter()
+        .filter_map(|(gid, created_at)| {
+            Some(ExportedFavorite {
+                game: games.get(gid)?.clone(),
+                created_at: *created_at,
+            })
+        })
+        .collect();
+    favorites.sort_by(|a, b| a.game.cmp(&b.game));
+
+    let mut records: Vec<_> = account
+        .records
+        .iter()
+        .filter_map(|(gid, record)| {
+            Some(ExportedRecord {
+                game: games.get(gid)?.clone(),
+                play_total: record.play_total,
+                last_play_start_at: record.last_play_start_at,
+                last_play_end_at: record.last_play_end_at,
+            })
+        })
+        .collect();
+    records.sort_by(|a, b| a.game.cmp(&b.game));
+
+    let mut save_states: Vec<_> = account
+        .save_states
+        .iter()
+        .filter_map(|((gid, slot), state)| {
+            Some(ExportedSaveState {
+                game: games.get(gid)?.clone(),
+                slot: *slot,
+                data: BASE64.encode(state.data.as_ref()?),
+                core_version: state.core_version,
+                updated_at: state.updated_at,
+            })
+        })
+        .collect();
+    save_states.sort_by(|a, b| (&a.game, a.slot).cmp(&(&b.game, b.slot)));
+
+    PersonalData {
+        version: EXPORT_VERSION,
+        exported_at: now,
+        settings,
+        settings_updated_at: account.settings_updated_at,
+        game_settings,
+        favorites,
+        records,
+        save_states,
+        messages: vec![],
+    }
+}
+
+fn item(kind: &str, name: &str, platform: Option<&str>, outcome: ImportOutcome) -> ImportItem {
+    ImportItem {
+        kind: kind.to_owned(),
+        name: name.to_owned(),
+        platform: platform.map(|platform| platform.to_owned()),
+        outcome,
+    }
+}
+
+/// A value both sides changed is kept from whichever settings were saved last
+fn merge_value(current: Option<&Value>, imported: &Value, imported_newer: bool) -> ImportOutcome {
+    match current {
+        None | Some(Value::Null) => ImportOutcome::Imported,
+        Some(current) if current == imported => ImportOutcome::Unchanged,
+        Some(_) if imported_newer => ImportOutcome::Imported,
+        Some(_) => ImportOutcome::ConflictKeptNewer,
+    }
+}
+
+/// The account after the import and what happened to every item.
+/// Playtime adds up, favorites already there are left alone, a slot keeps
+/// whichever state was saved last
+pub fn merge_import(
+    current: &AccountData,
+    data: &PersonalData,
+    games: &HashMap<GameRef, i32>,
+    skip_records: bool,
+) -> (AccountData, Vec<ImportItem>) {
+    let mut merged = current.clone();
+    let mut items = vec![];
+    let imported_newer = data.settings_updated_at > current.settings_updated_at;
+    let mut settings_changed = false;
+
+    for (key, value) in &data.settings {
+        if PER_GAME_SETTINGS.contains(&key.as_str()) {
+            continue;
+        }
+        let outcome = merge_value(current.settings.get(key), value, imported_newer);
+        if outcome == ImportOutcome::Imported {
+            merged.settings.insert(key.clone(), value.clone());
+            settings_changed = true;
+        }
+        items.push(item("setting", key, None, outcome));
+    }
+
+    for setting in &data.game_settings {
+        let GameRef { name, platform } = &setting.game;
+        let gid = match games.get(&setting.game) {
+            Some(gid) => gid.to_string(),
+            None => {
+                items.push(item(
+                    &setting.key,
+                    name,
+                    platform.as_deref(),
+                    ImportOutcome::SkippedNoMatchingGame,
+                ));
+                continue;
+            }
+        };
+        let entries = merged
+            .settings
+            .entry(setting.key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !entries.is_object() {
+            *entries = Value::Object(Map::new());
+        }
+        let entries = entries.as_object_mut().unwrap();
+        let outcome = merge_value(entries.get(&gid), &setting.value, imported_newer);
+        if outcome == ImportOutcome::Imported {
+            entries.insert(gid, setting.value.clone());
+            settings_changed = true;
+        }
+        items.push(item(&setting.key, name, platform.as_deref(), outcome));
+    }
+    if settings_changed {
+        merged.settings_updated_at = merged.settings_updated_at.max(data.settings_updated_at);
+    }
+
+    for favorite in &data.favorites {
+        let GameRef { name, platform } = &favorite.game;
+        let outcome = match games.get(&favorite.game) {
+            None => ImportOutcome::SkippedNoMatchingGame,
+            Some(gid) if merged.favorites.contains_key(gid) => ImportOutcome::Unchanged,
+            Some(gid) => {
+                merged.favorites.insert(*gid, favorite.created_at);
+                ImportOutcome::Imported
+            }
+        };
+        items.push(item("favorite", name, platform.as_deref(), outcome));
+    }
+
+    for state in &data.save_states {
+        let GameRef { name, platform } = &state.game;
+        let decoded = BASE64
+            .decode(state.data.as_bytes())
+            .ok()
+            .filter(|decoded| check_save_state(state.slot, decoded).is_ok());
+        let outcome = match (games.get(&state.game), decoded) {
+            (None, _) => ImportOutcome::SkippedNoMatchingGame,
+            (Some(_), None) => ImportOutcome::Rejected,
+            (Some(gid), Some(decoded)) => {
+                let checksum = get_checksum(&decoded);
+                match merged.save_states.get(&(*gid, state.slot)) {
+                    Some(current) if current.checksum == checksum => ImportOutcome::Unchanged,
+                    Some(current) if current.updated_at >= state.updated_at => {
+                        ImportOutcome::ConflictKeptNewer
+                    }
+                    _ => {
+                        let imported = StateData {
+                            checksum,
+                            core_version: state.core_version,
+                            updated_at: state.updated_at,
+                            data: Some(decoded),
+                        };
+                        merged.save_states.insert((*gid, state.slot), imported);
+                        ImportOutcome::Imported
+                    }
+                }
+            }
+        };
+        items.push(item("state", name, platform.as_deref(), outcome));
+    }
+
+    if skip_records {
+        return (merged, items);
+    }
+    for record in &data.records {
+        let GameRef { name, platform } = &record.game;
+        let outcome = match games.get(&record.game) {
+            None => ImportOutcome::SkippedNoMatchingGame,
+            Some(gid) => {
+                let entry = merged.records.entry(*gid).or_default();
+                entry.play_total += record.play_total.max(0);
+                entry.last_play_start_at = entry.last_play_start_at.max(record.last_play_start_at);
+                entry.last_play_end_at = entry.last_play_end_at.max(record.last_play_end_at);
+                ImportOutcome::Imported
+            }
+        };
+        items.push(item("record", name, platform.as_deref(), outcome));
+    }
+    (merged, items)
+}
+
+fn to_millis(time: NaiveDateTime) -> i64 {
+    time.timestamp_millis()
+}
+
+fn from_millis(millis: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp_millis(millis).unwrap_or_else(|| Utc::now().naive_utc())
+}
+
+/// Every game of the instance, deleted ones can't be played anyway
+pub fn load_game_refs(conn: &PgConnection) -> QueryResult<HashMap<i32, GameRef>> {
+    Ok(games::table
+        .select((games::id, games::name, games::platform))
+        .filter(games::deleted_at.is_null())
+        .load::<(i32, String, Option<String>)>(conn)?
+        .into_iter()
+        .map(|(gid, name, platform)| (gid, GameRef { name, platform }))
+        .collect())
+}
+
+pub fn load_account_data(conn: &PgConnection, uid: i32) -> QueryResult<AccountData> {
+    let user = users::table
+        .filter(users::deleted_at.is_null())
+        .filter(users::id.eq(uid))
+        .get_result::<User>(conn)?;
+    let settings = match user.settings {
+        Some(Value::Object(settings)) => settings,
+        _ => Map::new(),
+    };
+    let favorites = favorites::table
+        .select((favorites::game_id, favorites::created_at))
+        .filter(favorites::user_id.eq(uid))
+        .load::<(i32, NaiveDateTime)>(conn)?
+        .into_iter()
+        .map(|(gid, created_at)| (gid, to_millis(created_at)))
+        .collect();
+    let records = records::table
+        .select((
+            records::game_id,
+            records::play_total,
+            records::last_play_start_at,
+            records::last_play_end_at,
+        ))
+        .filter(records::user_id.eq(uid))
+        .load::<(i32, i64, NaiveDateTime, Option<NaiveDateTime>)>(conn)?
+        .into_iter()
+        .map(|(gid, total, start, end)| {
+            let record = RecordData {
+                play_total: total,
+                last_play_start_at: to_millis(start),
+                last_play_end_at: end.map(to_millis),
+            };
+            (gid, record)
+        })
+        .collect();
+    let save_states = save_states::table
+        .select((
+            save_states::game_id,
+            save_states::slot,
+            save_states::checksum,
+            save_states::core_version,
+            save_states::updated_at,
+        ))
+        .filter(save_states::user_id.eq(uid))
+        .load::<(i32, i32, String, Option<i32>, NaiveDateTime)>(conn)?
+        .into_iter()
+        .map(|(gid, slot, checksum, core_version, updated_at)| {
+            let state = StateData {
+                checksum,
+                core_version,
+                updated_at: to_millis(updated_at),
+                data: None,
+            };
+            ((gid, slot), state)
+        })
+        .collect();
+    Ok(AccountData {
+        settings,
+        settings_updated_at: to_millis(user.updated_at),
+        favorites,
+        records,
+        save_states,
+    })
+}
+
+#[derive(QueryableByName)]
+struct MessageRow {
+    #[sql_type = "Varchar"]
+    peer: String,
+    #[sql_type = "Bool"]
+    outgoing: bool,
+    #[sql_type = "Text"]
+    body: String,
+    #[sql_type = "Timestamp"]
+    created_at: NaiveDateTime,
+    #[sql_type = "Bool"]
+    archived: bool,
+}
+
+// Sent and received, live and archived, without the ones the author deleted
+const LOAD_MESSAGES_SQL: &str = r#"
+SELECT users.username AS peer, m.user_id = $1 AS outgoing, m.body, m.created_at, m.archived
+FROM (
+    SELECT user_id, target_id, body, created_at, FALSE AS archived
+    FROM messages WHERE deleted_at IS NULL AND (user_id = $1 OR target_id = $1)
+    UNION ALL
+    SELECT user_id, target_id, body, created_at, TRUE AS archived
+    FROM message_archives WHERE user_id = $1 OR target_id = $1
+) AS m
+INNER JOIN users ON users."id" = CASE WHEN m.user_id = $1 THEN m.target_id ELSE m.user_id END
+ORDER BY m.created_at
+"#;
+
+pub fn load_messages(conn: &PgConnection, uid: i32) -> QueryResult<Vec<ExportedMessage>> {
+    Ok(diesel::sql_query(LOAD_MESSAGES_SQL)
+        .bind::<Integer, _>(uid)
+        .load::<MessageRow>(conn)?
+        .into_iter()
+        .map(|row| ExportedMessage {
+            peer: row.peer,
+            outgoing: row.outgoing,
+            body: row.body,
+            created_at: to_millis(row.created_at),
+            archived: row.archived,
+        })
+        .collect())
+}
+
+/// Writes what `merge_import` changed, the caller wraps it in a transaction
+fn save_account_data(
+    conn: &PgConnection,
+    uid: i32,
+    current: &AccountData,
+    merged: &AccountData,
+) -> FieldResult<()> {
+    if merged.settings != current.settings {
+        diesel::update(users::table.filter(users::id.eq(uid)))
+            .set((
+                users::settings.eq(Some(Value::Object(merged.settings.clone()))),
+                users::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+    }
+
+    let new_favorites: Vec<_> = merged
+        .favorites
+        .iter()
+        .filter(|(gid, _)| !current.favorites.contains_key(gid))
+        .map(|(gid, created_at)| NewFavorite {
+            user_id: uid,
+            game_id: *gid,
+            created_at: from_millis(*created_at),
+        })
+        .collect();
+    if !new_favorites.is_empty() {
+        diesel::insert_into(favorites::table)
+            .values(&new_favorites)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+
+    let changed_records: Vec<_> = merged
+        .records
+        .iter()
+        .filter(|(gid, record)| current.records.get(gid) != Some(record))
+        .map(|(gid, record)| NewRecord {
+            user_id: uid,
+            game_id: *gid,
+            last_play_start_at: from_millis(record.last_play_start_at),
+            last_play_end_at: record.last_play_end_at.map(from_millis),
+            play_total: record.play_total,
+            last_heartbeat_at: None,
+        })
+        .collect();
+    if !changed_records.is_empty() {
+        diesel::insert_into(records::table)
+            .values(&changed_records)
+            .on_conflict((records::user_id, records::game_id))
+            .do_update()
+            .set((
+                records::play_total.eq(excluded(records::play_total)),
+                records::last_play_start_at.eq(excluded(records::last_play_start_at)),
+                records::last_play_end_at.eq(excluded(records::last_play_end_at)),
+            ))
+            .execute(conn)?;
+    }
+
+    // Stored like an upload, the storage backend or the row
+    for ((gid, slot), state) in &merged.save_states {
+        let data = match &state.data {
+            Some(data) if current.save_states.get(&(*gid, *slot)) != Some(state) => data,
+            _ => continue,
+        };
+        import_save_state(conn, uid, *gid, *slot, state.core_version, data)?;
+    }
+    Ok(())
+}
+
+pub fn export_personal_data(conn: &PgConnection, uid: i32) -> FieldResult<PersonalData> {
+    let mut account = load_account_data(conn, uid)?;
+    for ((gid, slot), state) in account.save_states.iter_mut() {
+        state.data = Some(read_save_state(conn, uid, *gid, *slot)?);
+    }
+    let games = load_game_refs(conn)?;
+    let mut data = build_export(&account, &games, Utc::now().timestamp_millis());
+    data.messages = load_messages(conn, uid)?;
+    Ok(data)
+}
+
+pub fn import_personal_data(
+    conn: &PgConnection,
+    uid: i32,
+    data: &PersonalData,
+    skip_records: bool,
+    dry_run: bool,
+) -> FieldResult<ImportReport> {
+    conn.transaction(|| {
+        let current = load_account_data(conn, uid)?;
+        let games = index_games(&load_game_refs(conn)?);
+        let (merged, items) = merge_import(&current, data, &games, skip_records);
+        if !dry_run {
+            save_account_data(conn, uid, &current, &merged)?;
+        }
+        Ok(ImportReport { dry_run, items })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transfer::*;
+    use serde_json::json;
+
+    fn game(name: &str, platform: &str) -> GameRef {
+        GameRef {
+            name: name.into(),
+            platform: Some(platform.into()),
+        }
+    }
+
+    fn state(data: &[u8], updated_at: i64) -> StateData {
+        StateData {
+            checksum: get_checksum(data),
+            core_version: Some(1),
+            updated_at,
+            data: Some(data.to_vec()),
+        }
+    }
+
+    fn outcomes(items: &[ImportItem]) -> Vec<(&str, &str, ImportOutcome)> {
+        items
+            .iter()
+            .map(|item| (item.kind.as_str(), item.name.as_str(), item.outcome))
+            .collect()
+    }
+
+    #[test]
+    fn transfer_round_trip() {
+        use ImportOutcome::*;
+
+        let source_games = HashMap::from([
+            (1, game("Contra", "nes")),
+            (2, game("Tetris", "nes")),
+            (3, game("Snake", "wasm4")),
+        ]);
+        let account = AccountData {
+            settings: json!({
+                "keybinding": { "a": "KeyJ" },
+                "volume": { "master": 0.5 },
+                "cheat": { "1": [{ "code": "AAAA" }], "2": null },
+                "combo": { "3": [{ "name": "up" }] },
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+            settings_updated_at: 1_000,
+            favorites: BTreeMap::from([(1, 100), (3, 300)]),
+            records: BTreeMap::from([(
+                1,
+                RecordData {
+                    play_total: 60_000,
+                    last_play_start_at: 500,
+                    last_play_end_at: Some(560),
+                },
+            )]),
+            save_states: BTreeMap::from([
+                ((1, 2), state(b"contra", 700)),
+                ((3, 0), state(b"snake", 800)),
+            ]),
+        };
+        let exported = build_export(&account, &source_games, 2_000);
+        let body = serde_json::to_vec(&exported).unwrap();
+
+        // Another instance, different ids and no Snake
+        let target_games =
+            HashMap::from([(10, game("Tetris", "nes")), (11, game("Contra", "nes"))]);
+        let index = index_games(&target_games);
+        let parsed = parse_personal_data(&body).unwrap();
+        let (imported, items) = merge_import(&AccountData::default(), &parsed, &index, false);
+        assert_eq!(
+            outcomes(&items),
+            vec![
+                ("setting", "keybinding", Imported),
+                ("setting", "volume", Imported),
+                ("cheat", "Contra", Imported),
+                ("combo", "Snake", SkippedNoMatchingGame),
+                ("favorite", "Contra", Imported),
+                ("favorite", "Snake", SkippedNoMatchingGame),
+                ("state", "Contra", Imported),
+                ("state", "Snake", SkippedNoMatchingGame),
+                ("record", "Contra", Imported),
+            ]
+        );
+
+        // Everything with a matching game comes back
+        let round_trip = build_export(&imported, &target_games, 2_000);
+        let mut expected = exported.clone();
+        expected
+            .game_settings
+            .retain(|setting| setting.key != "combo");
+        expected
+            .favorites
+            .retain(|favorite| favorite.game.name != "Snake");
+        expected
+            .save_states
+            .retain(|state| state.game.name != "Snake");
+        assert_eq!(round_trip, expected);
+
+        // Importing again only adds playtime, a newer local change wins
+        let mut edited = imported.clone();
+        edited.settings["volume"] = json!({ "master": 1.0 });
+        edited.settings_updated_at = 3_000;
+        let (again, items) = merge_import(&edited, &parsed, &index, false);
+        assert_eq!(
+            outcomes(&items)[..3],
+            [
+                ("setting", "keybinding", Unchanged),
+                ("setting", "volume", ConflictKeptNewer),
+                ("cheat", "Contra", Unchanged),
+            ]
+        );
+        assert_eq!(again.settings["volume"], json!({ "master": 1.0 }));
+        assert_eq!(again.records[&11].play_total, 120_000);
+        assert_eq!(again.save_states, edited.save_states);
+        let (skipped, items) = merge_import(&edited, &parsed, &index, true);
+        assert_eq!(skipped.records, edited.records);
+        assert!(items.iter().all(|item| item.kind != "record"));
+
+        // A slot saved later here is kept, a broken state is refused
+        let mut states = parsed.clone();
+        states.save_states[0].data = BASE64.encode(b"older");
+        states.save_states[1].data = "not base64".into();
+        states.save_states[1].game = game("Tetris", "nes");
+        let mut saved = AccountData::default();
+        saved.save_states.insert((11, 2), state(b"newer", 900));
+        let (kept, items) = merge_import(&saved, &states, &index, true);
+        assert_eq!(
+            outcomes(&items)[6..],
+            [
+                ("state", "Contra", ConflictKeptNewer),
+                ("state", "Tetris", Rejected),
+            ]
+        );
+        assert_eq!(kept.save_states, saved.save_states);
+    }
+
+    #[test]
+    fn transfer_version() {
+        assert!(parse_personal_data(br#"{"version":99}"#)
+            .unwrap_err()
+            .contains("99"));
+        assert!(parse_personal_data(b"{}").unwrap_err().contains("version"));
+    }
+}
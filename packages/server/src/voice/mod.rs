@@ -91,6 +91,19 @@ pub async fn upgrade_rtc(user_id: i32, room_id: i32, sdp: String) {
     }
 }
 
+pub async fn close_rtc(user_id: i32, room_id: i32) {
+    let state = ROOM_USER_CONN_STATE_MAP
+        .lock()
+        .await
+        .get_mut(&room_id)
+        .and_then(|map| map.remove(&user_id));
+
+    if let Some(state) = state {
+        // Peer connection state change handler notifies other members
+        state.conn.close().await.ok();
+    }
+}
+
 async fn get_senders_ids(peer_connection: &Arc<RTCPeerConnection>) -> Vec<String> {
     let mut ids = Vec::new();
     for sender in peer_connection.get_senders().await.iter() {